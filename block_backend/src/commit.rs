@@ -0,0 +1,182 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+
+use crate::dirty_bitmap::{DirtyBitmap, DEFAULT_GRANULARITY};
+use crate::job::{complete_func, BlockJobOps};
+use crate::{create_block_backend, BlockDriverOps, BlockProperty};
+use util::aio::{Aio, AioEngine, Iovec};
+
+/// Callback to switch the device to the base image after the commit is done.
+pub type CommitPivotFn = Box<dyn FnOnce() -> Result<()> + Send>;
+/// Callback to release the base image if the commit is cancelled or failed.
+pub type CommitReleaseFn = Box<dyn FnOnce() + Send>;
+
+/// Job to commit the data of the active image to its backing file. The areas
+/// allocated in the active image are copied first, then the areas written by
+/// guest during the commit are recorded in a dirty bitmap and copied again.
+pub struct CommitJob<T: Clone + 'static> {
+    top: Arc<Mutex<dyn BlockDriverOps<T>>>,
+    base: Arc<Mutex<dyn BlockDriverOps<()>>>,
+    bitmap: Arc<Mutex<DirtyBitmap>>,
+    /// Offset of disk to look for the next dirty area.
+    cursor: u64,
+    /// Bytes copied to the base image.
+    copied: u64,
+    pivot: Option<CommitPivotFn>,
+    release: Option<CommitReleaseFn>,
+}
+
+impl<T: Clone + 'static> CommitJob<T> {
+    /// Create the job to commit `top` to the image `base_file`.
+    ///
+    /// # Arguments
+    ///
+    /// * `top` - Backend of the active image used by device.
+    /// * `base_file` - The opened backing file of active image.
+    /// * `base_prop` - Properties of the backing file.
+    /// * `pivot` - Called to switch to the base image when the commit is done.
+    /// * `release` - Called to release the base image if the job is cancelled or failed.
+    pub fn new(
+        top: Arc<Mutex<dyn BlockDriverOps<T>>>,
+        base_file: File,
+        base_prop: BlockProperty,
+        pivot: CommitPivotFn,
+        release: CommitReleaseFn,
+    ) -> Result<Self> {
+        let aio = Aio::new(Arc::new(complete_func), AioEngine::Off)?;
+        let base = create_block_backend(base_file, aio, base_prop)?;
+        let base_size = base.lock().unwrap().disk_size()?;
+
+        let mut locked_top = top.lock().unwrap();
+        let disk_size = locked_top.disk_size()?;
+        if base_size != disk_size {
+            bail!(
+                "Size {} of base image is different from the size {} of active image",
+                base_size,
+                disk_size
+            );
+        }
+        let mut bitmap = DirtyBitmap::new(disk_size, DEFAULT_GRANULARITY)?;
+        let mut offset = 0;
+        while offset < disk_size {
+            let (allocated, len) = locked_top.is_allocated(offset, disk_size - offset)?;
+            if len == 0 {
+                break;
+            }
+            if allocated {
+                bitmap.set_dirty(offset, len)?;
+            }
+            offset += len;
+        }
+        let bitmap = Arc::new(Mutex::new(bitmap));
+        locked_top.add_dirty_bitmap(bitmap.clone())?;
+        drop(locked_top);
+
+        Ok(Self {
+            top,
+            base,
+            bitmap,
+            cursor: 0,
+            copied: 0,
+            pivot: Some(pivot),
+            release: Some(release),
+        })
+    }
+
+    fn write_base(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let iov = vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)];
+        self.base
+            .lock()
+            .unwrap()
+            .write_vectored(iov, offset as usize, ())
+            .with_context(|| format!("Failed to write base image at offset {}", offset))
+    }
+}
+
+impl<T: Clone + 'static> BlockJobOps for CommitJob<T> {
+    fn run_step(&mut self, max_len: u64) -> Result<(u64, bool)> {
+        let mut locked_top = self.top.lock().unwrap();
+        // The data of in-flight writes must be in the active image before read.
+        locked_top.drain_request()?;
+        let area = {
+            let locked_bitmap = self.bitmap.lock().unwrap();
+            match locked_bitmap.next_dirty_area(self.cursor, max_len)? {
+                Some(area) => Some(area),
+                None => locked_bitmap.next_dirty_area(0, max_len)?,
+            }
+        };
+        let (offset, len) = match area {
+            Some(area) => area,
+            None => return Ok((0, true)),
+        };
+
+        let mut buf = vec![0_u8; len as usize];
+        locked_top.read_sync(offset, &mut buf)?;
+        // Areas written by guest from now on will be marked dirty again.
+        self.bitmap.lock().unwrap().clear_dirty(offset, len)?;
+        drop(locked_top);
+
+        self.write_base(offset, &buf)?;
+        self.cursor = offset + len;
+        self.copied += len;
+        Ok((len, false))
+    }
+
+    fn progress(&self) -> (u64, u64) {
+        let remaining = self.bitmap.lock().unwrap().dirty_count().unwrap_or(0);
+        (self.copied, self.copied + remaining)
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.base.lock().unwrap().datasync(())?;
+        self.top.lock().unwrap().remove_dirty_bitmap(&self.bitmap);
+        if let Some(pivot) = self.pivot.take() {
+            pivot()?;
+        }
+        // The base image is used by device now.
+        self.release = None;
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        self.top.lock().unwrap().remove_dirty_bitmap(&self.bitmap);
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::{check_top_to_base_job, prop};
+    use machine_manager::config::DiskFormat;
+
+    #[test]
+    fn test_commit_job() {
+        check_top_to_base_job("test_commit_job", |top, base_file, pivot| {
+            let job = CommitJob::new(
+                top,
+                base_file,
+                prop(DiskFormat::Raw),
+                pivot,
+                Box::new(|| {}),
+            );
+            Box::new(job.unwrap())
+        });
+    }
+}
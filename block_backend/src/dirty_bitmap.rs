@@ -0,0 +1,443 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{remove_file, File};
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+
+use crate::BlockDriverOps;
+use util::bitmap::Bitmap;
+
+/// Min granularity of dirty bitmap, the size of a sector.
+const MIN_GRANULARITY: u64 = 512;
+/// Default granularity of dirty bitmap.
+pub const DEFAULT_GRANULARITY: u64 = 1 << 16;
+/// Magic of the file storing persistent dirty bitmaps.
+const BITMAPS_FILE_MAGIC: &[u8; 8] = b"SVBITMAP";
+const BITMAPS_FILE_VERSION: u32 = 1;
+/// The bitmaps are in use by a running VM, they may miss the latest writes.
+const BITMAPS_FLAG_IN_USE: u32 = 1 << 0;
+
+/// Bitmap which records the areas of disk written, each bit covers
+/// `granularity` bytes of disk.
+pub struct DirtyBitmap {
+    /// Name of the bitmap managed by user, the bitmaps used by block jobs have
+    /// no name.
+    name: Option<String>,
+    /// The bitmap is stored alongside the image when VM exits.
+    persistent: bool,
+    granularity: u64,
+    disk_size: u64,
+    bitmap: Bitmap<u64>,
+}
+
+impl DirtyBitmap {
+    pub fn new(disk_size: u64, granularity: u64) -> Result<Self> {
+        if !granularity.is_power_of_two() || granularity < MIN_GRANULARITY {
+            bail!("Invalid granularity {} of dirty bitmap", granularity);
+        }
+        let nr_bits = disk_size.div_ceil(granularity);
+        Ok(Self {
+            name: None,
+            persistent: false,
+            granularity,
+            disk_size,
+            bitmap: Bitmap::new(nr_bits.div_ceil(u64::BITS as u64) as usize),
+        })
+    }
+
+    /// Set the name of bitmap managed by user.
+    pub fn with_name(mut self, name: &str, persistent: bool) -> Self {
+        self.name = Some(name.to_string());
+        self.persistent = persistent;
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn persistent(&self) -> bool {
+        self.persistent
+    }
+
+    pub fn granularity(&self) -> u64 {
+        self.granularity
+    }
+
+    /// Get the bits covering [offset, offset + len) of disk.
+    fn bit_range(&self, offset: u64, len: u64) -> Option<(usize, usize)> {
+        let end = offset.saturating_add(len).min(self.disk_size);
+        if offset >= end {
+            return None;
+        }
+        let start_bit = offset / self.granularity;
+        let end_bit = end.div_ceil(self.granularity);
+        Some((start_bit as usize, (end_bit - start_bit) as usize))
+    }
+
+    pub fn set_dirty(&mut self, offset: u64, len: u64) -> Result<()> {
+        if let Some((start, nr)) = self.bit_range(offset, len) {
+            self.bitmap.set_range(start, nr)?;
+        }
+        Ok(())
+    }
+
+    /// Clear the bits of the area, the bits partly covered by the area are
+    /// cleared too.
+    pub fn clear_dirty(&mut self, offset: u64, len: u64) -> Result<()> {
+        if let Some((start, nr)) = self.bit_range(offset, len) {
+            self.bitmap.clear_range(start, nr)?;
+        }
+        Ok(())
+    }
+
+    pub fn clear_all(&mut self) {
+        self.bitmap.clear_all();
+    }
+
+    /// Find the first dirty area at or after `offset`, which is at most `max_len`
+    /// bytes. The offset and length of the area are returned.
+    pub fn next_dirty_area(&self, offset: u64, max_len: u64) -> Result<Option<(u64, u64)>> {
+        let nr_bits = self.disk_size.div_ceil(self.granularity) as usize;
+        let start_bit = (offset / self.granularity) as usize;
+        if start_bit >= nr_bits {
+            return Ok(None);
+        }
+        let start = self.bitmap.find_next_bit(start_bit)?;
+        if start >= nr_bits {
+            return Ok(None);
+        }
+        let max_bits = (max_len / self.granularity).max(1) as usize;
+        let end = self
+            .bitmap
+            .find_next_zero(start)?
+            .min(nr_bits)
+            .min(start + max_bits);
+        let area_start = start as u64 * self.granularity;
+        let area_end = (end as u64 * self.granularity).min(self.disk_size);
+        Ok(Some((area_start, area_end - area_start)))
+    }
+
+    /// Mark the dirty areas of `other` as dirty in this bitmap.
+    pub fn merge(&mut self, other: &DirtyBitmap) -> Result<()> {
+        let mut pos = 0;
+        while let Some((offset, len)) = other.next_dirty_area(pos, other.disk_size)? {
+            self.set_dirty(offset, len)?;
+            pos = offset + len;
+            if pos >= other.disk_size {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the count of dirty bytes.
+    pub fn dirty_count(&self) -> Result<u64> {
+        let mut count = 0;
+        let mut pos = 0;
+        while let Some((offset, len)) = self.next_dirty_area(pos, self.disk_size)? {
+            count += len;
+            pos = offset + len;
+            // The last granule may be partly covered by disk.
+            if pos >= self.disk_size {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Resize the bitmap for the disk of `disk_size`. The area added to disk
+    /// is dirty, as it is not in the copies of the old disk.
+    pub fn resize(&mut self, disk_size: u64) -> Result<()> {
+        let mut resized = DirtyBitmap::new(disk_size, self.granularity)?;
+        resized.merge(self)?;
+        if disk_size > self.disk_size {
+            resized.set_dirty(self.disk_size, disk_size - self.disk_size)?;
+        }
+        self.disk_size = disk_size;
+        self.bitmap = resized.bitmap;
+        Ok(())
+    }
+}
+
+/// Get the path of the file storing the persistent dirty bitmaps of image,
+/// which is placed alongside the image.
+pub fn bitmaps_file_path(image: &str) -> String {
+    format!("{}.bitmaps", image)
+}
+
+/// Store the persistent bitmaps of image, the file is removed if there is none.
+/// The file is marked in use while the image is opened by VM, so that the
+/// bitmaps not stored at exit are known to be stale.
+pub fn store_persistent_bitmaps(
+    image: &str,
+    bitmaps: &[Arc<Mutex<DirtyBitmap>>],
+    in_use: bool,
+) -> Result<()> {
+    let path = bitmaps_file_path(image);
+    let bitmaps: Vec<&Arc<Mutex<DirtyBitmap>>> = bitmaps
+        .iter()
+        .filter(|b| b.lock().unwrap().persistent)
+        .collect();
+    if bitmaps.is_empty() {
+        if Path::new(&path).exists() {
+            remove_file(&path).with_context(|| format!("Failed to remove {}", path))?;
+        }
+        return Ok(());
+    }
+
+    let mut buf = BITMAPS_FILE_MAGIC.to_vec();
+    buf.write_u32::<LittleEndian>(BITMAPS_FILE_VERSION)?;
+    buf.write_u32::<LittleEndian>(if in_use { BITMAPS_FLAG_IN_USE } else { 0 })?;
+    buf.write_u32::<LittleEndian>(bitmaps.len() as u32)?;
+    for bitmap in bitmaps {
+        let locked_bitmap = bitmap.lock().unwrap();
+        let name = locked_bitmap.name.as_deref().unwrap_or_default();
+        buf.write_u32::<LittleEndian>(name.len() as u32)?;
+        buf.extend_from_slice(name.as_bytes());
+        buf.write_u64::<LittleEndian>(locked_bitmap.granularity)?;
+        buf.write_u64::<LittleEndian>(locked_bitmap.disk_size)?;
+        let mut extents = Vec::new();
+        let mut pos = 0;
+        while let Some((offset, len)) =
+            locked_bitmap.next_dirty_area(pos, locked_bitmap.disk_size)?
+        {
+            extents.push((offset, len));
+            pos = offset + len;
+            if pos >= locked_bitmap.disk_size {
+                break;
+            }
+        }
+        buf.write_u64::<LittleEndian>(extents.len() as u64)?;
+        for (offset, len) in extents {
+            buf.write_u64::<LittleEndian>(offset)?;
+            buf.write_u64::<LittleEndian>(len)?;
+        }
+    }
+
+    let mut file = File::create(&path).with_context(|| format!("Failed to create {}", path))?;
+    file.write_all(&buf)
+        .and_then(|_| file.sync_data())
+        .with_context(|| format!("Failed to write {}", path))
+}
+
+/// Load the persistent bitmaps of image with the virtual size `disk_size`. The
+/// whole disk is dirty in the stale bitmaps, as the writes missed are unknown.
+pub fn load_persistent_bitmaps(image: &str, disk_size: u64) -> Result<Vec<DirtyBitmap>> {
+    let path = bitmaps_file_path(image);
+    if !Path::new(&path).exists() {
+        return Ok(Vec::new());
+    }
+    let mut buf = Vec::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .with_context(|| format!("Failed to read {}", path))?;
+    parse_bitmaps(&buf, disk_size).with_context(|| format!("Invalid dirty bitmaps file {}", path))
+}
+
+fn parse_bitmaps(buf: &[u8], disk_size: u64) -> Result<Vec<DirtyBitmap>> {
+    let mut cursor = Cursor::new(buf);
+    let mut magic = [0_u8; 8];
+    cursor.read_exact(&mut magic)?;
+    if &magic != BITMAPS_FILE_MAGIC {
+        bail!("Invalid magic");
+    }
+    let version = cursor.read_u32::<LittleEndian>()?;
+    if version != BITMAPS_FILE_VERSION {
+        bail!("Unsupported version {}", version);
+    }
+    let in_use = cursor.read_u32::<LittleEndian>()? & BITMAPS_FLAG_IN_USE != 0;
+    let count = cursor.read_u32::<LittleEndian>()?;
+
+    let mut bitmaps = Vec::new();
+    for _ in 0..count {
+        let name_len = cursor.read_u32::<LittleEndian>()? as usize;
+        if name_len > buf.len() {
+            bail!("Invalid length {} of bitmap name", name_len);
+        }
+        let mut name = vec![0_u8; name_len];
+        cursor.read_exact(&mut name)?;
+        let name = String::from_utf8(name).with_context(|| "Invalid bitmap name")?;
+        let granularity = cursor.read_u64::<LittleEndian>()?;
+        let stored_size = cursor.read_u64::<LittleEndian>()?;
+        let mut bitmap = DirtyBitmap::new(disk_size, granularity)?.with_name(&name, true);
+        let nr_extents = cursor.read_u64::<LittleEndian>()?;
+        for _ in 0..nr_extents {
+            let offset = cursor.read_u64::<LittleEndian>()?;
+            let len = cursor.read_u64::<LittleEndian>()?;
+            bitmap.set_dirty(offset, len)?;
+        }
+        if in_use || stored_size != disk_size {
+            warn!(
+                "Dirty bitmap {} is stale, the whole disk is marked dirty",
+                name
+            );
+            bitmap.set_dirty(0, disk_size)?;
+        }
+        bitmaps.push(bitmap);
+    }
+    Ok(bitmaps)
+}
+
+/// Add the persistent bitmaps of image to its backend. The bitmaps file is
+/// marked in use until the bitmaps are stored when VM exits.
+pub fn restore_persistent_bitmaps<T: Clone>(
+    backend: &mut dyn BlockDriverOps<T>,
+    image: &str,
+) -> Result<()> {
+    let disk_size = backend.disk_size()?;
+    for bitmap in load_persistent_bitmaps(image, disk_size)? {
+        backend.add_dirty_bitmap(Arc::new(Mutex::new(bitmap)))?;
+    }
+    store_persistent_bitmaps(image, &backend.named_dirty_bitmaps(), true)
+}
+
+/// Add the dirty bitmap managed by user to the backend of image.
+pub fn add_named_bitmap<T: Clone>(
+    backend: &mut dyn BlockDriverOps<T>,
+    image: &str,
+    name: &str,
+    granularity: Option<u64>,
+    persistent: bool,
+) -> Result<()> {
+    let disk_size = backend.disk_size()?;
+    let granularity = granularity.unwrap_or(DEFAULT_GRANULARITY);
+    let bitmap = DirtyBitmap::new(disk_size, granularity)?.with_name(name, persistent);
+    let bitmap = Arc::new(Mutex::new(bitmap));
+    backend.add_dirty_bitmap(bitmap.clone())?;
+    if persistent {
+        if let Err(e) = store_persistent_bitmaps(image, &backend.named_dirty_bitmaps(), true) {
+            backend.remove_dirty_bitmap(&bitmap);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Remove the dirty bitmap managed by user from the backend of image.
+pub fn remove_named_bitmap<T: Clone>(
+    backend: &mut dyn BlockDriverOps<T>,
+    image: &str,
+    name: &str,
+) -> Result<()> {
+    let bitmap = backend
+        .get_dirty_bitmap(name)
+        .with_context(|| format!("Dirty bitmap {} not found", name))?;
+    backend.remove_dirty_bitmap(&bitmap);
+    if bitmap.lock().unwrap().persistent {
+        store_persistent_bitmaps(image, &backend.named_dirty_bitmaps(), true)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_bitmap() {
+        assert!(DirtyBitmap::new(1 << 20, 100).is_err());
+        assert!(DirtyBitmap::new(1 << 20, 256).is_err());
+
+        // The last granule is partial.
+        let disk_size = (1 << 20) + 512;
+        let mut bitmap = DirtyBitmap::new(disk_size, 1 << 16).unwrap();
+        assert_eq!(bitmap.next_dirty_area(0, disk_size).unwrap(), None);
+        assert_eq!(bitmap.dirty_count().unwrap(), 0);
+
+        // Unaligned area sets all the granules it touches.
+        bitmap.set_dirty(1000, 1 << 16).unwrap();
+        assert_eq!(
+            bitmap.next_dirty_area(0, disk_size).unwrap(),
+            Some((0, 2 << 16))
+        );
+        assert_eq!(
+            bitmap.next_dirty_area(0, 1 << 16).unwrap(),
+            Some((0, 1 << 16))
+        );
+        assert_eq!(bitmap.next_dirty_area(1 << 17, disk_size).unwrap(), None);
+
+        bitmap.set_dirty(1 << 20, 4096).unwrap();
+        assert_eq!(
+            bitmap.next_dirty_area(1 << 17, disk_size).unwrap(),
+            Some((1 << 20, 512))
+        );
+        assert_eq!(bitmap.dirty_count().unwrap(), (2 << 16) + 512);
+
+        bitmap.clear_dirty(0, 1 << 16).unwrap();
+        assert_eq!(
+            bitmap.next_dirty_area(0, disk_size).unwrap(),
+            Some((1 << 16, 1 << 16))
+        );
+        bitmap.clear_all();
+        assert_eq!(bitmap.dirty_count().unwrap(), 0);
+
+        // The dirty bits are kept and the area added is dirty after resized.
+        bitmap.set_dirty(0, 4096).unwrap();
+        bitmap.resize(2 << 20).unwrap();
+        assert_eq!(
+            bitmap.next_dirty_area(1 << 16, 2 << 20).unwrap(),
+            Some((1 << 20, 1 << 20))
+        );
+        assert_eq!(bitmap.dirty_count().unwrap(), (1 << 16) + (1 << 20));
+    }
+
+    #[test]
+    fn test_persistent_bitmaps() {
+        let image = "/tmp/test_persistent_bitmaps.img";
+        let disk_size = 1 << 20;
+        let named = DirtyBitmap::new(disk_size, 4096)
+            .unwrap()
+            .with_name("bitmap0", true);
+        let bitmaps = vec![
+            Arc::new(Mutex::new(named)),
+            Arc::new(Mutex::new(
+                DirtyBitmap::new(disk_size, 4096)
+                    .unwrap()
+                    .with_name("bitmap1", false),
+            )),
+        ];
+        bitmaps[0].lock().unwrap().set_dirty(8192, 100).unwrap();
+        bitmaps[0].lock().unwrap().set_dirty(65536, 8192).unwrap();
+
+        // Only the persistent bitmaps are stored.
+        store_persistent_bitmaps(image, &bitmaps, false).unwrap();
+        let loaded = load_persistent_bitmaps(image, disk_size).unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].name(), Some("bitmap0"));
+        assert!(loaded[0].persistent());
+        assert_eq!(loaded[0].granularity(), 4096);
+        assert_eq!(loaded[0].dirty_count().unwrap(), 3 * 4096);
+        assert_eq!(
+            loaded[0].next_dirty_area(0, disk_size).unwrap(),
+            Some((8192, 4096))
+        );
+
+        // The bitmaps not stored at exit are stale.
+        store_persistent_bitmaps(image, &bitmaps, true).unwrap();
+        let loaded = load_persistent_bitmaps(image, disk_size).unwrap();
+        assert_eq!(loaded[0].dirty_count().unwrap(), disk_size);
+
+        // The file is removed without persistent bitmaps.
+        store_persistent_bitmaps(image, &bitmaps[1..], false).unwrap();
+        assert!(!Path::new(&bitmaps_file_path(image)).exists());
+        assert!(load_persistent_bitmaps(image, disk_size)
+            .unwrap()
+            .is_empty());
+    }
+}
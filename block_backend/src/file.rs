@@ -0,0 +1,356 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use log::error;
+use vmm_sys_util::epoll::EventSet;
+
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::{BlockIoErrorCallback, BlockProperty};
+use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use util::aio::{raw_datasync, Aio, AioCb, AioEngine, Iovec, OpCode};
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+
+/// A piece of request which is continuous in the host file.
+pub struct CombineRequest {
+    pub iov: Vec<Iovec>,
+    pub offset: u64,
+    pub nbytes: u64,
+    /// The file to access, the image file of the driver if none.
+    pub file_fd: Option<RawFd>,
+}
+
+impl CombineRequest {
+    pub fn new(iov: Vec<Iovec>, offset: u64, nbytes: u64) -> Self {
+        Self {
+            iov,
+            offset,
+            nbytes,
+            file_fd: None,
+        }
+    }
+}
+
+/// The driver to access the image file by aio.
+pub struct FileDriver<T: Clone + 'static> {
+    /// The opened image file.
+    pub file: File,
+    aio: Arc<Mutex<Aio<T>>>,
+    pub block_prop: BlockProperty,
+    /// Registered fds of aio event.
+    delete_evts: Vec<RawFd>,
+    /// Bitmaps which record the areas of disk written.
+    dirty_bitmaps: Vec<Arc<Mutex<DirtyBitmap>>>,
+}
+
+impl<T: Clone + 'static> FileDriver<T> {
+    pub fn new(file: File, aio: Aio<T>, block_prop: BlockProperty) -> Self {
+        Self {
+            file,
+            aio: Arc::new(Mutex::new(aio)),
+            block_prop,
+            delete_evts: Vec::new(),
+            dirty_bitmaps: Vec::new(),
+        }
+    }
+
+    pub fn add_dirty_bitmap(&mut self, bitmap: Arc<Mutex<DirtyBitmap>>) -> Result<()> {
+        if let Some(name) = bitmap.lock().unwrap().name() {
+            if self.get_dirty_bitmap(name).is_some() {
+                bail!("Dirty bitmap {} already exists", name);
+            }
+        }
+        self.dirty_bitmaps.push(bitmap);
+        Ok(())
+    }
+
+    pub fn get_dirty_bitmap(&self, name: &str) -> Option<Arc<Mutex<DirtyBitmap>>> {
+        self.dirty_bitmaps
+            .iter()
+            .find(|b| b.lock().unwrap().name() == Some(name))
+            .cloned()
+    }
+
+    pub fn named_dirty_bitmaps(&self) -> Vec<Arc<Mutex<DirtyBitmap>>> {
+        self.dirty_bitmaps
+            .iter()
+            .filter(|b| b.lock().unwrap().name().is_some())
+            .cloned()
+            .collect()
+    }
+
+    pub fn remove_dirty_bitmap(&mut self, bitmap: &Arc<Mutex<DirtyBitmap>>) {
+        self.dirty_bitmaps.retain(|b| !Arc::ptr_eq(b, bitmap));
+    }
+
+    /// Mark the area of virtual disk as dirty in all the bitmaps.
+    pub fn set_dirty(&self, offset: u64, len: u64) -> Result<()> {
+        for bitmap in self.dirty_bitmaps.iter() {
+            bitmap.lock().unwrap().set_dirty(offset, len)?;
+        }
+        Ok(())
+    }
+
+    /// Flush data of the image file to the storage synchronously.
+    pub fn flush_sync(&self) -> Result<()> {
+        if raw_datasync(self.file.as_raw_fd()) < 0 {
+            bail!("Failed to flush image file of drive {}", self.block_prop.id);
+        }
+        Ok(())
+    }
+
+    /// Resize all the bitmaps for the virtual disk of `disk_size`.
+    pub fn resize_dirty_bitmaps(&self, disk_size: u64) -> Result<()> {
+        for bitmap in self.dirty_bitmaps.iter() {
+            bitmap.lock().unwrap().resize(disk_size)?;
+        }
+        Ok(())
+    }
+
+    fn package_aiocb(
+        &self,
+        opcode: OpCode,
+        iovec: Vec<Iovec>,
+        offset: usize,
+        nbytes: u64,
+        iocompletecb: T,
+    ) -> AioCb<T> {
+        AioCb {
+            direct: self.block_prop.direct,
+            req_align: self.block_prop.req_align,
+            buf_align: self.block_prop.buf_align,
+            file_fd: self.file.as_raw_fd(),
+            opcode,
+            iovec,
+            offset,
+            nbytes,
+            user_data: 0,
+            iocompletecb,
+            combine_req: None,
+        }
+    }
+
+    fn process_request(
+        &mut self,
+        opcode: OpCode,
+        req_list: Vec<CombineRequest>,
+        completecb: T,
+    ) -> Result<()> {
+        if req_list.is_empty() {
+            // Nothing to do with the host file, e.g. all the data is zero.
+            let aiocb = self.package_aiocb(opcode, Vec::new(), 0, 0, completecb);
+            return self.aio.lock().unwrap().complete_cb(&aiocb, 0);
+        }
+
+        let combine_req = if req_list.len() > 1 {
+            Some((
+                Arc::new(AtomicU32::new(req_list.len() as u32)),
+                Arc::new(AtomicI64::new(0)),
+            ))
+        } else {
+            None
+        };
+        for req in req_list {
+            let mut aiocb = self.package_aiocb(
+                opcode,
+                req.iov,
+                req.offset as usize,
+                req.nbytes,
+                completecb.clone(),
+            );
+            if let Some(fd) = req.file_fd {
+                aiocb.file_fd = fd;
+            }
+            aiocb.combine_req = combine_req.clone();
+            self.aio.lock().unwrap().submit_request(aiocb)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_vectored(&mut self, req_list: Vec<CombineRequest>, completecb: T) -> Result<()> {
+        self.process_request(OpCode::Preadv, req_list, completecb)
+    }
+
+    pub fn write_vectored(&mut self, req_list: Vec<CombineRequest>, completecb: T) -> Result<()> {
+        self.process_request(OpCode::Pwritev, req_list, completecb)
+    }
+
+    pub fn discard(&mut self, req_list: Vec<CombineRequest>, completecb: T) -> Result<()> {
+        self.process_request(OpCode::Discard, req_list, completecb)
+    }
+
+    pub fn write_zeroes(
+        &mut self,
+        req_list: Vec<CombineRequest>,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        let opcode = if unmap {
+            OpCode::WriteZeroesUnmap
+        } else {
+            OpCode::WriteZeroes
+        };
+        self.process_request(opcode, req_list, completecb)
+    }
+
+    pub fn datasync(&mut self, completecb: T) -> Result<()> {
+        let aiocb = self.package_aiocb(OpCode::Fdsync, Vec::new(), 0, 0, completecb);
+        self.aio.lock().unwrap().submit_request(aiocb)
+    }
+
+    pub fn flush_request(&mut self) -> Result<()> {
+        self.aio.lock().unwrap().flush_request()
+    }
+
+    pub fn drain_request(&mut self) -> Result<()> {
+        self.aio.lock().unwrap().drain_request()
+    }
+
+    pub fn disk_size(&mut self) -> Result<u64> {
+        let disk_size = self
+            .file
+            .seek(SeekFrom::End(0))
+            .with_context(|| "Failed to seek the end for file")?;
+        Ok(disk_size)
+    }
+
+    /// Change the length of image file, the area extended reads as zeros.
+    pub fn truncate(&mut self, size: u64) -> Result<()> {
+        self.file
+            .set_len(size)
+            .with_context(|| format!("Failed to set the length of file to {}", size))
+    }
+
+    pub fn register_io_event(
+        &mut self,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        let handler = FileIoHandler::new(self.aio.clone(), broken, error_cb);
+        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        register_event_helper(
+            notifiers,
+            self.block_prop.iothread.as_ref(),
+            &mut self.delete_evts,
+        )
+    }
+
+    pub fn unregister_io_event(&mut self) -> Result<()> {
+        unregister_event_helper(self.block_prop.iothread.as_ref(), &mut self.delete_evts)
+    }
+}
+
+// SAFETY: The aio context is shared with the aio event handler only, and the
+// access to it is serialized by its lock.
+unsafe impl<T: Clone + 'static> Send for FileDriver<T> {}
+
+struct FileIoHandler<T: Clone + 'static> {
+    aio: Arc<Mutex<Aio<T>>>,
+    broken: Arc<AtomicBool>,
+    error_cb: BlockIoErrorCallback,
+}
+
+impl<T: Clone + 'static> FileIoHandler<T> {
+    fn new(
+        aio: Arc<Mutex<Aio<T>>>,
+        broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Self {
+        Self {
+            aio,
+            broken,
+            error_cb,
+        }
+    }
+
+    fn aio_complete_handler(&mut self) -> Result<bool> {
+        let error_cb = self.error_cb.clone();
+        self.aio
+            .lock()
+            .unwrap()
+            .handle_complete()
+            .inspect_err(|_| error_cb())
+    }
+}
+
+fn build_event_notifier(
+    fd: RawFd,
+    handlers: Vec<Rc<NotifierCallback>>,
+    handler_poll: Option<Box<NotifierCallback>>,
+) -> EventNotifier {
+    let mut notifier = EventNotifier::new(
+        NotifierOperation::AddShared,
+        fd,
+        None,
+        EventSet::IN,
+        handlers,
+    );
+    notifier.handler_poll = handler_poll;
+    notifier
+}
+
+impl<T: Clone + 'static> EventNotifierHelper for FileIoHandler<T> {
+    fn internal_notifiers(handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let handler_raw = handler.lock().unwrap();
+        let mut notifiers = Vec::new();
+
+        // Register event notifier for aio.
+        let h_clone = handler.clone();
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Err(ref e) = h_lock.aio_complete_handler() {
+                error!("Failed to handle aio {:?}", e);
+            }
+            None
+        });
+        let h_clone = handler.clone();
+        let handler_iopoll: Box<NotifierCallback> = Box::new(move |_, _fd: RawFd| {
+            let mut h_lock = h_clone.lock().unwrap();
+            if h_lock.broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            if h_lock.aio.lock().unwrap().get_engine() == AioEngine::Off {
+                return None;
+            }
+            match h_lock.aio_complete_handler() {
+                Ok(done) => {
+                    if done {
+                        Some(Vec::new())
+                    } else {
+                        None
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to handle aio {:?}", e);
+                    None
+                }
+            }
+        });
+        let aio_fd = handler_raw.aio.lock().unwrap().fd.as_raw_fd();
+        notifiers.push(build_event_notifier(aio_fd, vec![h], Some(handler_iopoll)));
+
+        notifiers
+    }
+}
@@ -0,0 +1,475 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::{anyhow, bail, Result};
+use log::error;
+use once_cell::sync::Lazy;
+
+use machine_manager::event;
+use machine_manager::event_loop::EventLoop;
+use machine_manager::qmp::qmp_schema::{
+    BlockJobCancelled, BlockJobCompleted, BlockJobInfo, BlockJobReady,
+};
+use machine_manager::qmp::QmpChannel;
+use util::aio::AioCb;
+use util::time::NANOSECONDS_PER_SECOND;
+
+/// Max bytes processed by each step of block job.
+const JOB_STEP_LEN: u64 = 1 << 20;
+/// Interval in nanoseconds of the steps of job which is ready to complete.
+const JOB_READY_INTERVAL: u64 = NANOSECONDS_PER_SECOND / 10;
+
+/// The block jobs not concluded, the key is the job id.
+static BLOCK_JOBS: Lazy<Mutex<HashMap<String, Arc<Mutex<BlockJob>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockJobStatus {
+    Running,
+    Paused,
+    Concluded,
+}
+
+impl fmt::Display for BlockJobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                BlockJobStatus::Running => "running",
+                BlockJobStatus::Paused => "paused",
+                BlockJobStatus::Concluded => "concluded",
+            }
+        )
+    }
+}
+
+/// Callback of the io to the images opened by block jobs, which is done
+/// synchronously.
+pub fn complete_func(_aio: &AioCb<()>, ret: i64) -> Result<()> {
+    if ret < 0 {
+        bail!("Failed to complete block job io, ret {}", ret);
+    }
+    Ok(())
+}
+
+/// Operations of a kind of block job, they are called in the iothread of job.
+pub trait BlockJobOps: Send {
+    /// Process at most `max_len` bytes of the job. The length processed and
+    /// whether all the work is done are returned.
+    fn run_step(&mut self, max_len: u64) -> Result<(u64, bool)>;
+
+    /// Get the bytes processed and the estimated total bytes of the job.
+    fn progress(&self) -> (u64, u64);
+
+    /// Finish the job after all the work is done.
+    fn complete(&mut self) -> Result<()>;
+
+    /// Release the resources of the job when it is cancelled or failed.
+    fn abort(&mut self);
+}
+
+/// A long running operation of block device, which is processed step by step
+/// in the iothread of device so that the guest keeps running.
+pub struct BlockJob {
+    id: String,
+    /// Id of the block device.
+    device: String,
+    job_type: String,
+    iothread: Option<String>,
+    /// Max speed in bytes per second, 0 means unlimited.
+    speed: u64,
+    status: BlockJobStatus,
+    cancelled: bool,
+    /// The job waits for `block-job-complete` after all the work is done,
+    /// rather than completing by itself.
+    manual_complete: bool,
+    /// All the work is done and the job waits to be completed by user.
+    ready: bool,
+    /// The job is requested to complete by user.
+    completing: bool,
+    /// The next step of job has been scheduled.
+    scheduled: bool,
+    ops: Box<dyn BlockJobOps>,
+}
+
+impl BlockJob {
+    pub fn new(
+        id: &str,
+        device: &str,
+        job_type: &str,
+        iothread: Option<String>,
+        speed: u64,
+        ops: Box<dyn BlockJobOps>,
+    ) -> Self {
+        Self {
+            id: id.to_string(),
+            device: device.to_string(),
+            job_type: job_type.to_string(),
+            iothread,
+            speed,
+            status: BlockJobStatus::Running,
+            cancelled: false,
+            manual_complete: false,
+            ready: false,
+            completing: false,
+            scheduled: false,
+            ops,
+        }
+    }
+
+    /// Set whether the job waits for `block-job-complete` after all the work
+    /// is done. The job keeps working until it's completed, e.g. mirror copies
+    /// the areas written by guest to target.
+    pub fn set_manual_complete(&mut self, manual_complete: bool) {
+        self.manual_complete = manual_complete;
+    }
+
+    fn info(&self) -> BlockJobInfo {
+        let (offset, len) = self.ops.progress();
+        BlockJobInfo {
+            job_type: self.job_type.clone(),
+            id: self.id.clone(),
+            device: self.device.clone(),
+            len,
+            offset,
+            busy: self.status == BlockJobStatus::Running,
+            paused: self.status == BlockJobStatus::Paused,
+            speed: self.speed,
+            io_status: "ok".to_string(),
+            ready: self.ready,
+            status: self.status.to_string(),
+        }
+    }
+}
+
+/// Schedule the next step of job after `delay` nanoseconds.
+fn schedule_step(job: &Arc<Mutex<BlockJob>>, locked_job: &mut BlockJob, delay: u64) -> Result<()> {
+    let ctx = EventLoop::get_ctx(locked_job.iothread.as_ref())
+        .ok_or_else(|| anyhow!("Iothread {:?} not found", locked_job.iothread))?;
+    let job_clone = job.clone();
+    ctx.delay_call(Box::new(move || run_step(&job_clone)), delay);
+    locked_job.scheduled = true;
+    Ok(())
+}
+
+fn run_step(job: &Arc<Mutex<BlockJob>>) {
+    let mut locked_job = job.lock().unwrap();
+    locked_job.scheduled = false;
+    if locked_job.status != BlockJobStatus::Running {
+        return;
+    }
+    if locked_job.cancelled {
+        locked_job.ops.abort();
+        conclude(locked_job, None, true);
+        return;
+    }
+
+    let result = locked_job
+        .ops
+        .run_step(JOB_STEP_LEN)
+        .and_then(|(len, done)| {
+            if done && (!locked_job.manual_complete || locked_job.completing) {
+                return locked_job.ops.complete().map(|_| true);
+            }
+            let delay = if done {
+                if !locked_job.ready {
+                    locked_job.ready = true;
+                    report_ready(&locked_job);
+                }
+                JOB_READY_INTERVAL
+            } else {
+                match locked_job.speed {
+                    0 => 0,
+                    speed => len * NANOSECONDS_PER_SECOND / speed,
+                }
+            };
+            schedule_step(job, &mut locked_job, delay).map(|_| false)
+        });
+    match result {
+        Ok(true) => conclude(locked_job, None, false),
+        Ok(false) => {}
+        Err(e) => {
+            error!("Block job {} failed: {:?}", locked_job.id, e);
+            locked_job.ops.abort();
+            conclude(locked_job, Some(format!("{:?}", e)), false);
+        }
+    }
+}
+
+/// Report that the job is ready to complete by event.
+fn report_ready(locked_job: &BlockJob) {
+    let (offset, len) = locked_job.ops.progress();
+    let ready_event = BlockJobReady {
+        job_type: locked_job.job_type.clone(),
+        device: locked_job.id.clone(),
+        len,
+        offset,
+        speed: locked_job.speed,
+    };
+    event!(BlockJobReady; ready_event);
+}
+
+/// Remove the job and report its end by event.
+fn conclude(mut locked_job: MutexGuard<BlockJob>, error: Option<String>, cancelled: bool) {
+    locked_job.status = BlockJobStatus::Concluded;
+    let (offset, len) = locked_job.ops.progress();
+    let id = locked_job.id.clone();
+    let job_type = locked_job.job_type.clone();
+    let speed = locked_job.speed;
+    // The job list is locked before the job elsewhere.
+    drop(locked_job);
+    BLOCK_JOBS.lock().unwrap().remove(&id);
+
+    if cancelled {
+        let cancelled_event = BlockJobCancelled {
+            job_type,
+            device: id,
+            len,
+            offset,
+            speed,
+        };
+        event!(BlockJobCancelled; cancelled_event);
+    } else {
+        let completed_event = BlockJobCompleted {
+            job_type,
+            device: id,
+            len,
+            offset,
+            speed,
+            error,
+        };
+        event!(BlockJobCompleted; completed_event);
+    }
+}
+
+fn find_job(id: &str) -> Result<Arc<Mutex<BlockJob>>> {
+    BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| anyhow!("Block job {} not found", id))
+}
+
+/// Check whether the job exists.
+pub fn has_block_job(id: &str) -> bool {
+    BLOCK_JOBS.lock().unwrap().contains_key(id)
+}
+
+/// Check whether any job runs on the block device.
+pub fn device_has_block_job(device: &str) -> bool {
+    BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .any(|job| job.lock().unwrap().device == device)
+}
+
+/// Start the job in its iothread. The job is aborted if it fails to start.
+pub fn start_block_job(job: BlockJob) -> Result<()> {
+    let mut jobs = BLOCK_JOBS.lock().unwrap();
+    let id = job.id.clone();
+    let job = Arc::new(Mutex::new(job));
+    let mut locked_job = job.lock().unwrap();
+    if jobs.contains_key(&id) {
+        locked_job.ops.abort();
+        bail!("Block job {} already exists", id);
+    }
+    if let Err(e) = schedule_step(&job, &mut locked_job, 0) {
+        locked_job.ops.abort();
+        return Err(e);
+    }
+    drop(locked_job);
+    jobs.insert(id, job);
+    Ok(())
+}
+
+pub fn query_block_jobs() -> Vec<BlockJobInfo> {
+    let jobs = BLOCK_JOBS.lock().unwrap();
+    let mut infos: Vec<BlockJobInfo> = jobs
+        .values()
+        .map(|job| job.lock().unwrap().info())
+        .collect();
+    infos.sort_by(|a, b| a.id.cmp(&b.id));
+    infos
+}
+
+/// Cancel the job, it is stopped in its iothread. A paused job is cancelled
+/// once it is resumed, unless `force` is set.
+pub fn cancel_block_job(id: &str, force: bool) -> Result<()> {
+    let job = find_job(id)?;
+    let mut locked_job = job.lock().unwrap();
+    locked_job.cancelled = true;
+    if locked_job.status == BlockJobStatus::Paused {
+        if !force {
+            return Ok(());
+        }
+        locked_job.status = BlockJobStatus::Running;
+    }
+    if !locked_job.scheduled {
+        schedule_step(&job, &mut locked_job, 0)?;
+    }
+    Ok(())
+}
+
+/// Complete the job which is ready. The work left, e.g. the areas written by
+/// guest since the last step of mirror, is done before completing.
+pub fn complete_block_job(id: &str) -> Result<()> {
+    let job = find_job(id)?;
+    let mut locked_job = job.lock().unwrap();
+    if !locked_job.ready {
+        bail!("Block job {} is not ready to complete", id);
+    }
+    locked_job.completing = true;
+    Ok(())
+}
+
+pub fn pause_block_job(id: &str) -> Result<()> {
+    let job = find_job(id)?;
+    let mut locked_job = job.lock().unwrap();
+    if locked_job.status == BlockJobStatus::Running {
+        locked_job.status = BlockJobStatus::Paused;
+    }
+    Ok(())
+}
+
+pub fn resume_block_job(id: &str) -> Result<()> {
+    let job = find_job(id)?;
+    let mut locked_job = job.lock().unwrap();
+    if locked_job.status != BlockJobStatus::Paused {
+        bail!("Block job {} is not paused", id);
+    }
+    locked_job.status = BlockJobStatus::Running;
+    if !locked_job.scheduled {
+        schedule_step(&job, &mut locked_job, 0)?;
+    }
+    Ok(())
+}
+
+pub fn set_block_job_speed(id: &str, speed: u64) -> Result<()> {
+    let job = find_job(id)?;
+    job.lock().unwrap().speed = speed;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use super::*;
+
+    struct TestJobOps {
+        steps: u64,
+        done: u64,
+        completed: Arc<AtomicBool>,
+        aborted: Arc<AtomicBool>,
+    }
+
+    impl BlockJobOps for TestJobOps {
+        fn run_step(&mut self, _max_len: u64) -> Result<(u64, bool)> {
+            if self.done == self.steps {
+                return Ok((0, true));
+            }
+            self.done += 1;
+            Ok((1, false))
+        }
+
+        fn progress(&self) -> (u64, u64) {
+            (self.done, self.steps)
+        }
+
+        fn complete(&mut self) -> Result<()> {
+            self.completed.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn abort(&mut self) {
+            self.aborted.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn create_job(id: &str, steps: u64) -> (BlockJob, Arc<AtomicBool>, Arc<AtomicBool>) {
+        let completed = Arc::new(AtomicBool::new(false));
+        let aborted = Arc::new(AtomicBool::new(false));
+        let ops = TestJobOps {
+            steps,
+            done: 0,
+            completed: completed.clone(),
+            aborted: aborted.clone(),
+        };
+        let job = BlockJob::new(id, "drive0", "test", None, 0, Box::new(ops));
+        (job, completed, aborted)
+    }
+
+    /// Run the steps of jobs in main loop until `cond` is met.
+    fn run_steps_until(cond: impl Fn() -> bool) {
+        for _ in 0..100 {
+            EventLoop::get_ctx(None).unwrap().run_timers();
+            if cond() {
+                return;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        panic!("Block job does not reach the expected state");
+    }
+
+    fn job_ready(id: &str) -> bool {
+        query_block_jobs()
+            .iter()
+            .any(|info| info.id == id && info.ready)
+    }
+
+    #[test]
+    fn test_block_job() {
+        QmpChannel::object_init();
+        EventLoop::object_init(&None).unwrap();
+
+        // The job waits for block-job-complete after all the work is done.
+        let (mut job, completed, _) = create_job("job0", 3);
+        job.set_manual_complete(true);
+        start_block_job(job).unwrap();
+        assert!(complete_block_job("job0").is_err());
+        run_steps_until(|| job_ready("job0"));
+        let info = query_block_jobs();
+        assert_eq!(info[0].device, "drive0");
+        assert_eq!((info[0].offset, info[0].len), (3, 3));
+        sleep(Duration::from_millis(200));
+        run_steps_until(|| true);
+        assert!(has_block_job("job0"));
+        assert!(!completed.load(Ordering::SeqCst));
+        complete_block_job("job0").unwrap();
+        run_steps_until(|| !has_block_job("job0"));
+        assert!(completed.load(Ordering::SeqCst));
+
+        // A paused job is cancelled without resuming only if forced.
+        let (job, completed, aborted) = create_job("job1", 1000);
+        start_block_job(job).unwrap();
+        pause_block_job("job1").unwrap();
+        cancel_block_job("job1", false).unwrap();
+        run_steps_until(|| true);
+        sleep(Duration::from_millis(20));
+        run_steps_until(|| true);
+        assert!(has_block_job("job1"));
+        assert!(!aborted.load(Ordering::SeqCst));
+        cancel_block_job("job1", true).unwrap();
+        run_steps_until(|| !has_block_job("job1"));
+        assert!(aborted.load(Ordering::SeqCst));
+        assert!(!completed.load(Ordering::SeqCst));
+    }
+}
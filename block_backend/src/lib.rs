@@ -0,0 +1,218 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+pub mod commit;
+pub mod dirty_bitmap;
+pub mod file;
+pub mod job;
+pub mod mirror;
+pub mod nbd;
+pub mod qcow2;
+pub mod raw;
+pub mod stats;
+pub mod stream;
+pub mod throttle;
+pub mod zoned;
+
+#[cfg(test)]
+mod test_helper;
+
+use std::fs::File;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use dirty_bitmap::DirtyBitmap;
+use machine_manager::config::{DiskFormat, NbdUri};
+use nbd::client::NbdDriver;
+use qcow2::Qcow2Driver;
+use raw::RawDriver;
+use util::aio::{Aio, Iovec};
+
+/// Callback to report the failure of the backend to the device.
+pub type BlockIoErrorCallback = Arc<dyn Fn() + Send + Sync>;
+
+/// Properties of the image file used by block backends.
+#[derive(Debug, Clone)]
+pub struct BlockProperty {
+    /// Id of the drive.
+    pub id: String,
+    /// Format of the image file.
+    pub format: DiskFormat,
+    /// Iothread to handle the aio events.
+    pub iothread: Option<String>,
+    /// If use direct access io.
+    pub direct: bool,
+    /// The align requirement of request(offset/len).
+    pub req_align: u32,
+    /// The align requirement of buffer(iova_base).
+    pub buf_align: u32,
+    /// Copy the data read from backing file into the image.
+    pub copy_on_read: bool,
+}
+
+/// Information of an internal snapshot of the image.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// Unique id of the snapshot in the image.
+    pub id: String,
+    pub name: String,
+    /// Size of the VM state saved with the snapshot.
+    pub vm_state_size: u64,
+    /// Host time when the snapshot was taken.
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    /// Guest time when the snapshot was taken.
+    pub vm_clock_nsec: u64,
+}
+
+/// Operations of block backend. Offsets and lengths are in bytes of the
+/// virtual disk, the driver maps them to the image file.
+pub trait BlockDriverOps<T: Clone>: Send {
+    /// Get the virtual size of disk.
+    fn disk_size(&mut self) -> Result<u64>;
+
+    /// Grow the virtual disk to `size` bytes, the area added reads as zeros.
+    /// The submitted requests should be drained first.
+    fn resize(&mut self, size: u64) -> Result<()>;
+
+    /// Read data from disk to iovec, `completecb` is called when finished.
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()>;
+
+    /// Write data of iovec to disk, `completecb` is called when finished.
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()>;
+
+    /// Discard the data of disk, the area reads as either zeros or the old data
+    /// afterwards. `completecb` is called when finished.
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()>;
+
+    /// Write zeros to disk, the area may be deallocated if `unmap` is allowed.
+    /// `completecb` is called when finished.
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()>;
+
+    /// Flush data of disk to the storage.
+    fn datasync(&mut self, completecb: T) -> Result<()>;
+
+    /// Submit the pending requests to the host.
+    fn flush_request(&mut self) -> Result<()>;
+
+    /// Wait until all the submitted requests are completed.
+    fn drain_request(&mut self) -> Result<()>;
+
+    /// Read data of disk synchronously, the submitted requests should be
+    /// drained first.
+    fn read_sync(&mut self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Write data to disk synchronously, the submitted requests should be
+    /// drained first.
+    fn write_sync(&mut self, offset: u64, buf: &[u8]) -> Result<()>;
+
+    /// Flush data of disk to the storage synchronously.
+    fn flush_sync(&mut self) -> Result<()>;
+
+    /// Get whether the data at `offset` is allocated in the image itself rather
+    /// than its backing file, and the length of the area with the same status
+    /// which is at most `len`.
+    fn is_allocated(&mut self, offset: u64, len: u64) -> Result<(bool, u64)>;
+
+    /// Copy the data of [offset, offset + len) which is not allocated in the
+    /// image from its backing file, the data read from disk is not changed.
+    /// The submitted requests should be drained first.
+    fn copy_backing_data(&mut self, offset: u64, len: u64) -> Result<()>;
+
+    /// Detach the image from its backing file, all the data of disk should
+    /// have been copied into the image.
+    fn drop_backing_file(&mut self) -> Result<()>;
+
+    /// Get the path and format of the backing file.
+    fn backing_file(&self) -> Option<(String, DiskFormat)>;
+
+    /// Record the areas of disk written from now on in `bitmap`, the name of
+    /// bitmap must be unique if it has.
+    fn add_dirty_bitmap(&mut self, bitmap: Arc<Mutex<DirtyBitmap>>) -> Result<()>;
+
+    /// Get the dirty bitmap with the name.
+    fn get_dirty_bitmap(&self, name: &str) -> Option<Arc<Mutex<DirtyBitmap>>>;
+
+    /// Get all the dirty bitmaps which have names.
+    fn named_dirty_bitmaps(&self) -> Vec<Arc<Mutex<DirtyBitmap>>>;
+
+    /// Stop recording the areas written in `bitmap`.
+    fn remove_dirty_bitmap(&mut self, bitmap: &Arc<Mutex<DirtyBitmap>>);
+
+    /// Create an internal snapshot of the disk with the unique `name`.
+    fn create_snapshot(&mut self, name: &str) -> Result<()>;
+
+    /// Delete the internal snapshot which matches both `id` and `name`
+    /// if they are specified, the information of the deleted one is returned.
+    fn delete_snapshot(&mut self, id: Option<&str>, name: Option<&str>) -> Result<SnapshotInfo>;
+
+    /// Register the aio event to the iothread of backend.
+    fn register_io_event(
+        &mut self,
+        device_broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()>;
+
+    /// Unregister the aio event of backend.
+    fn unregister_io_event(&mut self) -> Result<()>;
+}
+
+/// Create the block backend of the image file according to its format.
+///
+/// # Arguments
+///
+/// * `file` - The opened image file.
+/// * `aio` - Aio context used to process the data of requests.
+/// * `prop` - Properties of the image file.
+pub fn create_block_backend<T: Clone + 'static>(
+    file: File,
+    aio: Aio<T>,
+    prop: BlockProperty,
+) -> Result<Arc<Mutex<dyn BlockDriverOps<T>>>> {
+    match prop.format {
+        DiskFormat::Raw => {
+            let raw_file = RawDriver::new(file, aio, prop)?;
+            Ok(Arc::new(Mutex::new(raw_file)))
+        }
+        DiskFormat::Qcow2 => {
+            let qcow2 = Qcow2Driver::new(file, aio, prop)?;
+            Ok(Arc::new(Mutex::new(qcow2)))
+        }
+    }
+}
+
+/// Create the block backend of the export of NBD server, which is accessed as
+/// raw image.
+///
+/// # Arguments
+///
+/// * `uri` - The export of NBD server.
+/// * `read_only` - If the drive is read-only.
+/// * `aio` - Aio context used to complete the requests.
+/// * `prop` - Properties of the drive.
+pub fn create_nbd_backend<T: Clone + 'static>(
+    uri: &NbdUri,
+    read_only: bool,
+    aio: Aio<T>,
+    prop: BlockProperty,
+) -> Result<Arc<Mutex<dyn BlockDriverOps<T>>>> {
+    let nbd = NbdDriver::new(uri, read_only, aio, prop)?;
+    Ok(Arc::new(Mutex::new(nbd)))
+}
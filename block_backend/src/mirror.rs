@@ -0,0 +1,366 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::dirty_bitmap::{DirtyBitmap, DEFAULT_GRANULARITY};
+use crate::job::{complete_func, BlockJobOps};
+use crate::{create_block_backend, BlockDriverOps, BlockProperty};
+use util::aio::{Aio, AioEngine, Iovec};
+
+/// Callback to switch the device to the target image after the data is synchronized.
+pub type MirrorPivotFn = Box<dyn FnOnce() -> Result<()> + Send>;
+/// Callback to release the target image if the job is cancelled or failed.
+pub type MirrorReleaseFn = Box<dyn FnOnce() + Send>;
+
+/// The areas of source copied to target at the start of job.
+#[derive(Clone)]
+pub enum MirrorSync {
+    /// The whole disk.
+    Full,
+    /// The areas allocated in the image of source rather than its backing file.
+    Top,
+    /// The areas marked dirty in the bitmap of source. When the job completes,
+    /// the bitmap keeps only the areas written after the data is synchronized.
+    Incremental(Arc<Mutex<DirtyBitmap>>),
+}
+
+impl FromStr for MirrorSync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "full" => Ok(MirrorSync::Full),
+            "top" => Ok(MirrorSync::Top),
+            _ => Err(anyhow!("Unsupported sync mode {}", s)),
+        }
+    }
+}
+
+/// Job to copy the data of the image used by device to the target image while
+/// the guest keeps writing. The areas to copy are recorded in a dirty bitmap,
+/// and the areas written by guest during the job are marked dirty and copied
+/// again. Once no area is dirty, the job keeps the target synchronized until
+/// it is completed by user, then the device switches to the target image.
+///
+/// Backup is a mirror without switching the image, the target holds the data
+/// of disk at the time the job completes.
+pub struct MirrorJob<T: Clone + 'static> {
+    source: Arc<Mutex<dyn BlockDriverOps<T>>>,
+    target: Arc<Mutex<dyn BlockDriverOps<()>>>,
+    bitmap: Arc<Mutex<DirtyBitmap>>,
+    /// Offset of disk to look for the next dirty area.
+    cursor: u64,
+    /// Bytes copied to the target image.
+    copied: u64,
+    /// The target image reads as zeros, so the zero areas are not written in
+    /// the first pass over the disk.
+    target_zeroed: bool,
+    /// The bitmap of source synchronized in incremental mode.
+    sync_bitmap: Option<Arc<Mutex<DirtyBitmap>>>,
+    pivot: Option<MirrorPivotFn>,
+    release: Option<MirrorReleaseFn>,
+}
+
+impl<T: Clone + 'static> MirrorJob<T> {
+    /// Create the job to mirror `source` to the image `target_file`.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - Backend of the image used by device.
+    /// * `target_file` - The opened target image.
+    /// * `target_prop` - Properties of the target image.
+    /// * `sync` - The areas to copy at the start of job.
+    /// * `target_zeroed` - Whether the target image reads as zeros, e.g. it is newly created.
+    /// * `pivot` - Called to switch to the target image when the job completes.
+    /// * `release` - Called to release the target image if the job is cancelled or failed.
+    pub fn new(
+        source: Arc<Mutex<dyn BlockDriverOps<T>>>,
+        target_file: File,
+        target_prop: BlockProperty,
+        sync: MirrorSync,
+        target_zeroed: bool,
+        pivot: MirrorPivotFn,
+        release: MirrorReleaseFn,
+    ) -> Result<Self> {
+        let aio = Aio::new(Arc::new(complete_func), AioEngine::Off)?;
+        let target = create_block_backend(target_file, aio, target_prop)?;
+        let target_size = target.lock().unwrap().disk_size()?;
+
+        let mut locked_source = source.lock().unwrap();
+        let disk_size = locked_source.disk_size()?;
+        if target_size != disk_size {
+            bail!(
+                "Size {} of target image is different from the size {} of source image",
+                target_size,
+                disk_size
+            );
+        }
+        let granularity = match &sync {
+            MirrorSync::Incremental(sync_bitmap) => sync_bitmap.lock().unwrap().granularity(),
+            _ => DEFAULT_GRANULARITY,
+        };
+        let mut bitmap = DirtyBitmap::new(disk_size, granularity)?;
+        let mut sync_bitmap = None;
+        match sync {
+            MirrorSync::Full => bitmap.set_dirty(0, disk_size)?,
+            MirrorSync::Top => {
+                let mut offset = 0;
+                while offset < disk_size {
+                    let (allocated, len) =
+                        locked_source.is_allocated(offset, disk_size - offset)?;
+                    if len == 0 {
+                        break;
+                    }
+                    if allocated {
+                        bitmap.set_dirty(offset, len)?;
+                    }
+                    offset += len;
+                }
+            }
+            MirrorSync::Incremental(sync) => {
+                bitmap.merge(&sync.lock().unwrap())?;
+                sync_bitmap = Some(sync);
+            }
+        }
+        let bitmap = Arc::new(Mutex::new(bitmap));
+        locked_source.add_dirty_bitmap(bitmap.clone())?;
+        drop(locked_source);
+
+        Ok(Self {
+            source,
+            target,
+            bitmap,
+            cursor: 0,
+            copied: 0,
+            target_zeroed,
+            sync_bitmap,
+            pivot: Some(pivot),
+            release: Some(release),
+        })
+    }
+
+    fn write_target(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let iov = vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)];
+        self.target
+            .lock()
+            .unwrap()
+            .write_vectored(iov, offset as usize, ())
+            .with_context(|| format!("Failed to write target image at offset {}", offset))
+    }
+}
+
+impl<T: Clone + 'static> BlockJobOps for MirrorJob<T> {
+    fn run_step(&mut self, max_len: u64) -> Result<(u64, bool)> {
+        let mut locked_source = self.source.lock().unwrap();
+        // The data of in-flight writes must be in the source image before read.
+        locked_source.drain_request()?;
+        let area = {
+            let locked_bitmap = self.bitmap.lock().unwrap();
+            match locked_bitmap.next_dirty_area(self.cursor, max_len)? {
+                Some(area) => Some(area),
+                None => {
+                    // Areas copied in the first pass may be written by guest later.
+                    self.target_zeroed = false;
+                    locked_bitmap.next_dirty_area(0, max_len)?
+                }
+            }
+        };
+        let (offset, len) = match area {
+            Some(area) => area,
+            None => return Ok((0, true)),
+        };
+
+        let mut buf = vec![0_u8; len as usize];
+        locked_source.read_sync(offset, &mut buf)?;
+        // Areas written by guest from now on will be marked dirty again.
+        self.bitmap.lock().unwrap().clear_dirty(offset, len)?;
+        drop(locked_source);
+
+        if !self.target_zeroed || buf.iter().any(|b| *b != 0) {
+            self.write_target(offset, &buf)?;
+        }
+        self.cursor = offset + len;
+        self.copied += len;
+        Ok((len, false))
+    }
+
+    fn progress(&self) -> (u64, u64) {
+        let remaining = self.bitmap.lock().unwrap().dirty_count().unwrap_or(0);
+        (self.copied, self.copied + remaining)
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.target.lock().unwrap().datasync(())?;
+        let mut locked_source = self.source.lock().unwrap();
+        if let Some(sync_bitmap) = self.sync_bitmap.take() {
+            // Only the areas written since the data is synchronized are dirty.
+            let mut locked_sync = sync_bitmap.lock().unwrap();
+            locked_sync.clear_all();
+            locked_sync.merge(&self.bitmap.lock().unwrap())?;
+        }
+        locked_source.remove_dirty_bitmap(&self.bitmap);
+        drop(locked_source);
+        if let Some(pivot) = self.pivot.take() {
+            pivot()?;
+        }
+        // The target image is used by device now.
+        self.release = None;
+        Ok(())
+    }
+
+    fn abort(&mut self) {
+        self.source
+            .lock()
+            .unwrap()
+            .remove_dirty_bitmap(&self.bitmap);
+        if let Some(release) = self.release.take() {
+            release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use super::*;
+    use crate::qcow2::{create_qcow2_image, CreateOptions};
+    use crate::test_helper::{check_top_to_base_job, open_backend, prop, write_data};
+    use machine_manager::config::DiskFormat;
+    use util::file::open_file;
+
+    #[test]
+    fn test_mirror_job_top() {
+        // Mirror the data allocated in the active image to its backing file.
+        check_top_to_base_job("test_mirror_job_top", |top, base_file, pivot| {
+            let job = MirrorJob::new(
+                top,
+                base_file,
+                prop(DiskFormat::Raw),
+                MirrorSync::Top,
+                false,
+                pivot,
+                Box::new(|| {}),
+            );
+            Box::new(job.unwrap())
+        });
+    }
+
+    #[test]
+    fn test_mirror_job_full() {
+        let source_path = "/tmp/test_mirror_job_full_source.qcow2";
+        let target_path = "/tmp/test_mirror_job_full_target.qcow2";
+        let disk_size = 4 << 20;
+        create_qcow2_image(&CreateOptions::new(source_path, disk_size)).unwrap();
+        create_qcow2_image(&CreateOptions::new(target_path, disk_size)).unwrap();
+        let source = open_backend(source_path, DiskFormat::Qcow2);
+        write_data(&source, 1 << 20, &[0x55_u8; 4096]);
+
+        let mut job = MirrorJob::new(
+            source.clone(),
+            open_file(target_path, false, false).unwrap(),
+            prop(DiskFormat::Qcow2),
+            MirrorSync::Full,
+            true,
+            Box::new(|| Ok(())),
+            Box::new(|| {}),
+        )
+        .unwrap();
+        assert_eq!(job.progress(), (0, disk_size));
+        assert_eq!(job.run_step(1 << 20).unwrap(), (1 << 20, false));
+        // Guest zeroes the area copied, it must be written to target.
+        write_data(&source, 4096, &[0x66_u8; 4096]);
+        write_data(&source, 4096, &[0_u8; 4096]);
+        while !job.run_step(1 << 20).unwrap().1 {}
+        job.complete().unwrap();
+
+        let target = open_backend(target_path, DiskFormat::Qcow2);
+        let mut locked_target = target.lock().unwrap();
+        let mut buf = vec![0xff_u8; disk_size as usize];
+        locked_target.read_sync(0, &mut buf).unwrap();
+        assert_eq!(&buf[..1 << 20], &vec![0_u8; 1 << 20][..]);
+        assert_eq!(&buf[1 << 20..(1 << 20) + 4096], &[0x55_u8; 4096]);
+        assert_eq!(&buf[(1 << 20) + 4096..], &vec![0_u8; (3 << 20) - 4096][..]);
+        // Zero areas of the first pass are not allocated in target.
+        assert_eq!(
+            locked_target.is_allocated(0, disk_size).unwrap(),
+            (true, DEFAULT_GRANULARITY)
+        );
+        assert_eq!(
+            locked_target.is_allocated(2 << 20, 2 << 20).unwrap(),
+            (false, 2 << 20)
+        );
+        drop(locked_target);
+
+        remove_file(source_path).unwrap();
+        remove_file(target_path).unwrap();
+    }
+
+    #[test]
+    fn test_mirror_job_incremental() {
+        let source_path = "/tmp/test_mirror_job_incremental_source.qcow2";
+        let target_path = "/tmp/test_mirror_job_incremental_target.qcow2";
+        let disk_size = 4 << 20;
+        create_qcow2_image(&CreateOptions::new(source_path, disk_size)).unwrap();
+        create_qcow2_image(&CreateOptions::new(target_path, disk_size)).unwrap();
+        let source = open_backend(source_path, DiskFormat::Qcow2);
+        write_data(&source, 0, &[0x11_u8; 4096]);
+        let named = DirtyBitmap::new(disk_size, 4096)
+            .unwrap()
+            .with_name("bitmap0", false);
+        let named = Arc::new(Mutex::new(named));
+        source
+            .lock()
+            .unwrap()
+            .add_dirty_bitmap(named.clone())
+            .unwrap();
+        write_data(&source, 1 << 20, &[0x22_u8; 4096]);
+
+        let mut job = MirrorJob::new(
+            source.clone(),
+            open_file(target_path, false, false).unwrap(),
+            prop(DiskFormat::Qcow2),
+            MirrorSync::Incremental(named.clone()),
+            true,
+            Box::new(|| Ok(())),
+            Box::new(|| {}),
+        )
+        .unwrap();
+        // Only the areas written since the bitmap is added are copied.
+        assert_eq!(job.progress(), (0, 4096));
+        while !job.run_step(1 << 20).unwrap().1 {}
+        write_data(&source, 2 << 20, &[0x33_u8; 512]);
+        job.complete().unwrap();
+        // The write after synchronization is kept in bitmap for the next backup.
+        let locked_named = named.lock().unwrap();
+        assert_eq!(locked_named.dirty_count().unwrap(), 4096);
+        assert_eq!(
+            locked_named.next_dirty_area(0, disk_size).unwrap(),
+            Some((2 << 20, 4096))
+        );
+        drop(locked_named);
+
+        let target = open_backend(target_path, DiskFormat::Qcow2);
+        let mut buf = vec![0_u8; disk_size as usize];
+        target.lock().unwrap().read_sync(0, &mut buf).unwrap();
+        assert_eq!(&buf[..4096], &[0_u8; 4096]);
+        assert_eq!(&buf[1 << 20..(1 << 20) + 4096], &[0x22_u8; 4096]);
+
+        remove_file(source_path).unwrap();
+        remove_file(target_path).unwrap();
+    }
+}
@@ -0,0 +1,1254 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{error, info, warn};
+use vmm_sys_util::epoll::EventSet;
+
+use super::*;
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::{BlockDriverOps, BlockIoErrorCallback, BlockProperty, SnapshotInfo};
+use machine_manager::config::{DiskFormat, NbdUri};
+use machine_manager::event_loop::EventLoop;
+use util::aio::{
+    get_iov_size, iovec_write_zero, iovecs_split, Aio, AioCb, AioEngine, Iovec, OpCode,
+};
+use util::loop_context::{
+    gen_delete_notifiers, EventNotifier, NotifierCallback, NotifierOperation,
+};
+
+/// Max number of the commands sent to server and not replied.
+const NBD_MAX_INFLIGHT: usize = 16;
+/// The requests are held while reconnecting the server, and fail if the
+/// server is not back in time.
+const NBD_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Interval of the attempts to reconnect the server.
+const NBD_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Information of the export negotiated in handshake.
+#[derive(Debug, Default, Clone, Copy)]
+struct NbdExportInfo {
+    size: u64,
+    flags: u16,
+    /// Structured replies are negotiated.
+    structured: bool,
+}
+
+fn send_option(stream: &mut NbdStream, opt: u32, data: &[u8]) -> Result<()> {
+    let mut buf = Vec::with_capacity(NBD_OPT_HEADER_SIZE + data.len());
+    buf.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&opt.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+/// Receive the reply of option, returns the type and data of reply.
+fn recv_option_reply(stream: &mut NbdStream, opt: u32) -> Result<(u32, Vec<u8>)> {
+    let magic = stream.read_u64()?;
+    let reply_opt = stream.read_u32()?;
+    let reply_type = stream.read_u32()?;
+    let len = stream.read_u32()? as usize;
+    if magic != NBD_REP_MAGIC || reply_opt != opt {
+        bail!("Invalid reply of nbd option {}", opt);
+    }
+    if len > NBD_MAX_OPTION_SIZE {
+        bail!("Too long reply {} of nbd option {}", len, opt);
+    }
+    let mut data = vec![0_u8; len];
+    stream.read_exact(&mut data)?;
+    Ok((reply_type, data))
+}
+
+/// Negotiate with the server in fixed newstyle, and enter transmission phase.
+fn handshake(stream: &mut NbdStream, export: &str) -> Result<NbdExportInfo> {
+    if stream.read_u64()? != NBD_MAGIC {
+        bail!("Invalid magic of nbd server");
+    }
+    let opts_magic = stream.read_u64()?;
+    let server_flags = stream.read_u16()?;
+    if opts_magic != NBD_OPTS_MAGIC || server_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        bail!("Only fixed newstyle negotiation of nbd is supported");
+    }
+    let no_zeroes = server_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    stream.write_all(&client_flags.to_be_bytes())?;
+
+    let mut info = NbdExportInfo::default();
+    send_option(stream, NBD_OPT_STRUCTURED_REPLY, &[])?;
+    let (reply, _) = recv_option_reply(stream, NBD_OPT_STRUCTURED_REPLY)?;
+    info.structured = reply == NBD_REP_ACK;
+
+    let mut data = Vec::with_capacity(6 + export.len());
+    data.extend_from_slice(&(export.len() as u32).to_be_bytes());
+    data.extend_from_slice(export.as_bytes());
+    // No information is requested, NBD_INFO_EXPORT is always sent by server.
+    data.extend_from_slice(&0_u16.to_be_bytes());
+    send_option(stream, NBD_OPT_GO, &data)?;
+    loop {
+        let (reply, data) = recv_option_reply(stream, NBD_OPT_GO)?;
+        match reply {
+            NBD_REP_ACK => return Ok(info),
+            NBD_REP_INFO
+                if data.len() >= 12 && BigEndian::read_u16(&data[0..2]) == NBD_INFO_EXPORT =>
+            {
+                info.size = BigEndian::read_u64(&data[2..10]);
+                info.flags = BigEndian::read_u16(&data[10..12]);
+            }
+            NBD_REP_ERR_UNSUP => break,
+            _ if reply & NBD_REP_FLAG_ERROR != 0 => {
+                bail!(
+                    "Failed to open nbd export \"{}\": {}",
+                    export,
+                    String::from_utf8_lossy(&data)
+                );
+            }
+            // Other information is not used.
+            _ => {}
+        }
+    }
+
+    // The server doesn't support NBD_OPT_GO.
+    send_option(stream, NBD_OPT_EXPORT_NAME, export.as_bytes())?;
+    info.size = stream.read_u64()?;
+    info.flags = stream.read_u16()?;
+    if !no_zeroes {
+        stream.skip(124)?;
+    }
+    Ok(info)
+}
+
+/// Whether the fd has data to read, or is hung up.
+fn fd_readable(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: pollfd is valid and only one fd is polled.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    ret > 0 && pollfd.revents != 0
+}
+
+/// Request of the device, which may be split into several commands.
+struct NbdRequest<T> {
+    opcode: OpCode,
+    offset: u64,
+    nbytes: u64,
+    /// Callback of the device, none for the synchronous request of driver.
+    completecb: Option<T>,
+    /// Number of the commands not completed.
+    pending: usize,
+    /// The first error met.
+    ret: i64,
+}
+
+/// Command of the transmission.
+struct NbdCommand {
+    /// Id of the request which the command belongs to.
+    req_id: u64,
+    header: NbdRequestHeader,
+    /// Data of the read and write command, zeros are written if it's empty.
+    iovec: Vec<Iovec>,
+    /// The first error in the reply.
+    ret: i64,
+}
+
+/// The requests completed, the callbacks are called without the lock of client.
+type NbdCompletion<T> = (AioCb<T>, i64);
+
+fn complete_requests<T: Clone + 'static>(aio: &Arc<Mutex<Aio<T>>>, done: Vec<NbdCompletion<T>>) {
+    if done.is_empty() {
+        return;
+    }
+    let locked_aio = aio.lock().unwrap();
+    for (aiocb, ret) in done {
+        if let Err(e) = locked_aio.complete_cb(&aiocb, ret) {
+            error!("Failed to complete nbd request: {:?}", e);
+        }
+    }
+}
+
+struct NbdClient<T: Clone + 'static> {
+    uri: NbdUri,
+    info: NbdExportInfo,
+    stream: Option<NbdStream>,
+    /// Replies are received in the event loop, otherwise when submitting.
+    async_io: bool,
+    iothread: Option<String>,
+    /// The device has registered the io event.
+    io_registered: bool,
+    /// Fd of the stream registered to the event loop.
+    registered_fd: Option<RawFd>,
+    device_broken: Arc<AtomicBool>,
+    /// Time when the connection is lost.
+    lost_time: Option<Instant>,
+    reconnect_scheduled: bool,
+    requests: HashMap<u64, NbdRequest<T>>,
+    next_req_id: u64,
+    /// Commands sent to server, the key is the handle.
+    inflight: HashMap<u64, NbdCommand>,
+    /// Commands waiting to be sent.
+    queued: VecDeque<NbdCommand>,
+    next_handle: u64,
+    /// Results of the synchronous requests.
+    sync_rets: HashMap<u64, i64>,
+    done: Vec<NbdCompletion<T>>,
+    aio: Arc<Mutex<Aio<T>>>,
+    weak_self: Weak<Mutex<Self>>,
+}
+
+impl<T: Clone + 'static> NbdClient<T> {
+    fn connect(uri: &NbdUri) -> Result<(NbdStream, NbdExportInfo)> {
+        let mut stream = NbdStream::connect(&uri.addr)?;
+        let mut info = handshake(&mut stream, &uri.export)
+            .with_context(|| format!("Failed to negotiate with nbd server {}", uri.addr))?;
+        if info.flags & NBD_FLAG_HAS_FLAGS == 0 {
+            info.flags = 0;
+        }
+        Ok((stream, info))
+    }
+
+    fn take_done(&mut self) -> Vec<NbdCompletion<T>> {
+        std::mem::take(&mut self.done)
+    }
+
+    fn submit(&mut self, req: NbdRequest<T>, cmds: Vec<NbdCommand>) -> u64 {
+        let req_id = self.next_req_id;
+        self.next_req_id = self.next_req_id.wrapping_add(1);
+        self.requests.insert(
+            req_id,
+            NbdRequest {
+                pending: cmds.len(),
+                ..req
+            },
+        );
+        if cmds.is_empty() {
+            // Nothing to do with the server, e.g. flush is not supported.
+            self.finish_request(req_id);
+            return req_id;
+        }
+
+        if self.lost_time.is_some() && !self.async_io {
+            self.reconnect();
+        }
+        let expired = self.reconnect_expired();
+        for mut cmd in cmds {
+            cmd.req_id = req_id;
+            if expired {
+                self.complete_command(cmd, -i64::from(libc::EIO));
+            } else {
+                self.queued.push_back(cmd);
+            }
+        }
+        self.send_queued();
+        req_id
+    }
+
+    fn complete_command(&mut self, cmd: NbdCommand, ret: i64) {
+        if let Some(req) = self.requests.get_mut(&cmd.req_id) {
+            if ret < 0 && req.ret == 0 {
+                req.ret = ret;
+            }
+            req.pending -= 1;
+            if req.pending == 0 {
+                self.finish_request(cmd.req_id);
+            }
+        }
+    }
+
+    fn finish_request(&mut self, req_id: u64) {
+        let req = self.requests.remove(&req_id).unwrap();
+        match req.completecb {
+            Some(completecb) => {
+                let aiocb = AioCb {
+                    direct: false,
+                    req_align: 0,
+                    buf_align: 0,
+                    // There is no host file to access.
+                    file_fd: -1,
+                    opcode: req.opcode,
+                    iovec: Vec::new(),
+                    offset: req.offset as usize,
+                    nbytes: req.nbytes,
+                    user_data: 0,
+                    iocompletecb: completecb,
+                    combine_req: None,
+                };
+                self.done.push((aiocb, req.ret));
+            }
+            None => {
+                self.sync_rets.insert(req_id, req.ret);
+            }
+        }
+    }
+
+    fn send_command(&mut self, cmd: &NbdCommand) -> Result<()> {
+        let stream = self.stream.as_mut().unwrap();
+        stream.write_all(&cmd.header.to_bytes())?;
+        if cmd.header.cmd == NBD_CMD_WRITE {
+            if cmd.iovec.is_empty() {
+                stream.write_zeroes(u64::from(cmd.header.len))?;
+            } else {
+                stream.write_iovec(&cmd.iovec)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_queued(&mut self) {
+        while self.lost_time.is_none() && self.inflight.len() < NBD_MAX_INFLIGHT {
+            let mut cmd = match self.queued.pop_front() {
+                Some(cmd) => cmd,
+                None => break,
+            };
+            cmd.header.handle = self.next_handle;
+            self.next_handle = self.next_handle.wrapping_add(1);
+            if let Err(e) = self.send_command(&cmd) {
+                error!("Failed to send nbd command: {:?}", e);
+                self.queued.push_front(cmd);
+                self.connection_lost();
+                break;
+            }
+            self.inflight.insert(cmd.header.handle, cmd);
+        }
+    }
+
+    /// Receive the payload of structured reply chunk, returns the error it carries.
+    fn recv_chunk(
+        stream: &mut NbdStream,
+        cmd: &NbdCommand,
+        reply_type: u16,
+        len: u64,
+    ) -> Result<i64> {
+        match reply_type {
+            NBD_REPLY_TYPE_NONE => {
+                stream.skip(len)?;
+                Ok(0)
+            }
+            NBD_REPLY_TYPE_OFFSET_DATA | NBD_REPLY_TYPE_OFFSET_HOLE => {
+                let hole = reply_type == NBD_REPLY_TYPE_OFFSET_HOLE;
+                if cmd.header.cmd != NBD_CMD_READ || len < 8 || (hole && len != 12) {
+                    bail!("Invalid data chunk of nbd reply, length {}", len);
+                }
+                let offset = stream.read_u64()?;
+                let size = if hole {
+                    u64::from(stream.read_u32()?)
+                } else {
+                    len - 8
+                };
+                let start = offset.wrapping_sub(cmd.header.offset);
+                if offset < cmd.header.offset
+                    || start + size > u64::from(cmd.header.len)
+                    || cmd.iovec.is_empty()
+                {
+                    bail!("Data chunk of nbd reply is out of range, offset {}", offset);
+                }
+                let (_, iovec) = iovecs_split(cmd.iovec.clone(), start);
+                let (iovec, _) = iovecs_split(iovec, size);
+                if hole {
+                    iovec_write_zero(&iovec);
+                } else {
+                    stream.read_iovec(&iovec)?;
+                }
+                Ok(0)
+            }
+            _ if reply_type & NBD_REPLY_TYPE_ERROR_BIT != 0 => {
+                if len < 6 {
+                    bail!("Invalid error chunk of nbd reply, length {}", len);
+                }
+                let err = stream.read_u32()?;
+                let msg_len = u64::from(stream.read_u16()?);
+                if msg_len + 6 > len {
+                    bail!("Invalid message length {} of nbd reply", msg_len);
+                }
+                let mut msg = vec![0_u8; msg_len as usize];
+                stream.read_exact(&mut msg)?;
+                // The offset of NBD_REPLY_TYPE_ERROR_OFFSET is not used.
+                stream.skip(len - 6 - msg_len)?;
+                warn!(
+                    "Nbd server replies error {}: {}",
+                    err,
+                    String::from_utf8_lossy(&msg)
+                );
+                Ok(-i64::from(nbd_err_to_errno(err)))
+            }
+            _ => bail!("Unknown type {} of nbd reply chunk", reply_type),
+        }
+    }
+
+    /// Receive a reply (chunk) from server.
+    fn recv_reply(&mut self) -> Result<()> {
+        let stream = self
+            .stream
+            .as_mut()
+            .with_context(|| "Nbd server is not connected")?;
+        let magic = stream.read_u32()?;
+        let (handle, done, ret) = match magic {
+            NBD_SIMPLE_REPLY_MAGIC => {
+                let err = stream.read_u32()?;
+                let handle = stream.read_u64()?;
+                let cmd = self
+                    .inflight
+                    .get(&handle)
+                    .with_context(|| format!("Unknown handle {:#x} of nbd reply", handle))?;
+                if err != 0 {
+                    (handle, true, -i64::from(nbd_err_to_errno(err)))
+                } else {
+                    if cmd.header.cmd == NBD_CMD_READ {
+                        stream.read_iovec(&cmd.iovec)?;
+                    }
+                    (handle, true, 0)
+                }
+            }
+            NBD_STRUCTURED_REPLY_MAGIC if self.info.structured => {
+                let flags = stream.read_u16()?;
+                let reply_type = stream.read_u16()?;
+                let handle = stream.read_u64()?;
+                let len = u64::from(stream.read_u32()?);
+                let cmd = self
+                    .inflight
+                    .get(&handle)
+                    .with_context(|| format!("Unknown handle {:#x} of nbd reply", handle))?;
+                let ret = Self::recv_chunk(stream, cmd, reply_type, len)?;
+                (handle, flags & NBD_REPLY_FLAG_DONE != 0, ret)
+            }
+            _ => bail!("Invalid magic {:#x} of nbd reply", magic),
+        };
+
+        let cmd = self.inflight.get_mut(&handle).unwrap();
+        if ret < 0 && cmd.ret == 0 {
+            cmd.ret = ret;
+        }
+        if done {
+            let cmd = self.inflight.remove(&handle).unwrap();
+            let ret = cmd.ret;
+            self.complete_command(cmd, ret);
+            self.send_queued();
+        }
+        Ok(())
+    }
+
+    /// Handle the event of stream in the event loop.
+    fn stream_event_handler(&mut self, fd: RawFd) -> Option<Vec<EventNotifier>> {
+        if self.registered_fd != Some(fd) {
+            return None;
+        }
+        if self.lost_time.is_none() && !self.device_broken.load(Ordering::SeqCst) {
+            // The reply may have been received when draining requests.
+            if !fd_readable(fd) {
+                return None;
+            }
+            match self.recv_reply() {
+                Ok(()) => return None,
+                Err(e) => {
+                    error!("Failed to receive nbd reply: {:?}", e);
+                    self.connection_lost();
+                }
+            }
+        }
+        // Stop watching the stream, it's registered again when reconnected or
+        // the device is activated.
+        self.registered_fd = None;
+        Some(gen_delete_notifiers(&[fd]))
+    }
+
+    fn register_stream(&mut self) -> Result<()> {
+        if !self.async_io || !self.io_registered || self.registered_fd.is_some() {
+            return Ok(());
+        }
+        let fd = match self.stream.as_ref() {
+            Some(stream) if self.lost_time.is_none() => stream.as_raw_fd(),
+            _ => return Ok(()),
+        };
+        let weak = self.weak_self.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            let client = weak.upgrade()?;
+            let mut locked_client = client.lock().unwrap();
+            let notifiers = locked_client.stream_event_handler(fd);
+            let done = locked_client.take_done();
+            let aio = locked_client.aio.clone();
+            drop(locked_client);
+            complete_requests(&aio, done);
+            notifiers
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            fd,
+            None,
+            EventSet::IN,
+            vec![handler],
+        );
+        EventLoop::update_event(vec![notifier], self.iothread.as_ref())?;
+        self.registered_fd = Some(fd);
+        Ok(())
+    }
+
+    fn unregister_stream(&mut self) {
+        if let Some(fd) = self.registered_fd.take() {
+            if let Err(e) =
+                EventLoop::update_event(gen_delete_notifiers(&[fd]), self.iothread.as_ref())
+            {
+                error!("Failed to unregister nbd stream: {:?}", e);
+            }
+        }
+    }
+
+    fn connection_lost(&mut self) {
+        if self.lost_time.is_some() {
+            return;
+        }
+        warn!("Connection to nbd server {} is lost", self.uri.addr);
+        self.lost_time = Some(Instant::now());
+        if let Some(stream) = self.stream.as_ref() {
+            stream.shutdown();
+        }
+        // The commands not replied are sent again after reconnecting.
+        let mut cmds: Vec<NbdCommand> = self.inflight.drain().map(|(_, cmd)| cmd).collect();
+        cmds.sort_by_key(|cmd| cmd.header.handle);
+        for mut cmd in cmds.into_iter().rev() {
+            cmd.ret = 0;
+            self.queued.push_front(cmd);
+        }
+        self.schedule_reconnect(Duration::ZERO);
+    }
+
+    fn reconnect_expired(&self) -> bool {
+        matches!(self.lost_time, Some(time) if time.elapsed() >= NBD_RECONNECT_DELAY)
+    }
+
+    /// Try to reconnect the server once, returns whether it's connected.
+    fn reconnect(&mut self) -> bool {
+        if self.lost_time.is_none() {
+            return true;
+        }
+        self.unregister_stream();
+        self.stream = None;
+        let res = Self::connect(&self.uri).and_then(|(stream, info)| {
+            if info.size != self.info.size {
+                bail!(
+                    "Size of nbd export changed from {} to {}",
+                    self.info.size,
+                    info.size
+                );
+            }
+            Ok((stream, info))
+        });
+        match res {
+            Ok((stream, info)) => {
+                info!("Reconnected to nbd server {}", self.uri.addr);
+                self.stream = Some(stream);
+                self.info = info;
+                self.lost_time = None;
+                if let Err(e) = self.register_stream() {
+                    error!("Failed to register nbd stream: {:?}", e);
+                }
+                self.send_queued();
+                true
+            }
+            Err(e) => {
+                warn!("Failed to reconnect nbd server: {:?}", e);
+                false
+            }
+        }
+    }
+
+    /// Fail the commands which are waiting for reconnection.
+    fn fail_queued(&mut self) {
+        while let Some(cmd) = self.queued.pop_front() {
+            self.complete_command(cmd, -i64::from(libc::EIO));
+        }
+    }
+
+    /// Reconnect the server in the event loop.
+    fn schedule_reconnect(&mut self, delay: Duration) {
+        if !self.async_io || self.reconnect_scheduled {
+            return;
+        }
+        let ctx = match EventLoop::get_ctx(self.iothread.as_ref()) {
+            Some(ctx) => ctx,
+            None => return,
+        };
+        self.reconnect_scheduled = true;
+        let weak = self.weak_self.clone();
+        let func = Box::new(move || {
+            let client = match weak.upgrade() {
+                Some(client) => client,
+                None => return,
+            };
+            let mut locked_client = client.lock().unwrap();
+            locked_client.reconnect_scheduled = false;
+            if !locked_client.reconnect() {
+                if locked_client.reconnect_expired() {
+                    locked_client.fail_queued();
+                }
+                locked_client.schedule_reconnect(NBD_RECONNECT_INTERVAL);
+            }
+            let done = locked_client.take_done();
+            let aio = locked_client.aio.clone();
+            drop(locked_client);
+            complete_requests(&aio, done);
+        });
+        ctx.delay_call(func, delay.as_nanos() as u64);
+    }
+
+    /// Receive the replies in place until the request is completed, or all the
+    /// requests are completed if `req_id` is none.
+    fn wait_requests(&mut self, req_id: Option<u64>) {
+        loop {
+            let pending = match req_id {
+                Some(id) => self.requests.contains_key(&id),
+                None => !self.requests.is_empty(),
+            };
+            if !pending {
+                break;
+            }
+            if self.lost_time.is_some() {
+                if !self.reconnect() {
+                    if self.reconnect_expired() {
+                        self.fail_queued();
+                    } else {
+                        std::thread::sleep(NBD_RECONNECT_INTERVAL);
+                    }
+                }
+                continue;
+            }
+            if let Err(e) = self.recv_reply() {
+                error!("Failed to receive nbd reply: {:?}", e);
+                self.connection_lost();
+            }
+        }
+    }
+}
+
+impl<T: Clone + 'static> Drop for NbdClient<T> {
+    fn drop(&mut self) {
+        if let (Some(stream), None) = (self.stream.as_mut(), self.lost_time) {
+            let header = NbdRequestHeader {
+                cmd: NBD_CMD_DISC,
+                ..Default::default()
+            };
+            let _ = stream.write_all(&header.to_bytes());
+        }
+    }
+}
+
+/// Driver of the export of NBD server, which is accessed as raw image.
+pub struct NbdDriver<T: Clone + 'static> {
+    client: Arc<Mutex<NbdClient<T>>>,
+    aio: Arc<Mutex<Aio<T>>>,
+    /// Flags of the export.
+    flags: u16,
+    disk_size: u64,
+    /// Bitmaps which record the areas of disk written.
+    dirty_bitmaps: Vec<Arc<Mutex<DirtyBitmap>>>,
+}
+
+impl<T: Clone + 'static> NbdDriver<T> {
+    /// Connect to the export of NBD server. The replies are received in the
+    /// event loop if the aio engine is not `off`, otherwise the requests are
+    /// completed synchronously.
+    pub fn new(uri: &NbdUri, read_only: bool, aio: Aio<T>, prop: BlockProperty) -> Result<Self> {
+        if prop.format != DiskFormat::Raw {
+            bail!("Only raw format is supported by nbd drive {}", uri);
+        }
+        let (stream, info) = NbdClient::<T>::connect(uri)?;
+        if !read_only && info.flags & NBD_FLAG_READ_ONLY != 0 {
+            bail!("Nbd export {} is read-only", uri);
+        }
+
+        let async_io = aio.get_engine() != AioEngine::Off;
+        let aio = Arc::new(Mutex::new(aio));
+        let client = Arc::new_cyclic(|weak_self| {
+            Mutex::new(NbdClient {
+                uri: uri.clone(),
+                info,
+                stream: Some(stream),
+                async_io,
+                iothread: prop.iothread.clone(),
+                io_registered: false,
+                registered_fd: None,
+                device_broken: Arc::new(AtomicBool::new(false)),
+                lost_time: None,
+                reconnect_scheduled: false,
+                requests: HashMap::new(),
+                next_req_id: 0,
+                inflight: HashMap::new(),
+                queued: VecDeque::new(),
+                next_handle: 0,
+                sync_rets: HashMap::new(),
+                done: Vec::new(),
+                aio: aio.clone(),
+                weak_self: weak_self.clone(),
+            })
+        });
+        Ok(Self {
+            client,
+            aio,
+            flags: info.flags,
+            disk_size: info.size,
+            dirty_bitmaps: Vec::new(),
+        })
+    }
+
+    /// Split the area into commands within the max payload.
+    fn build_commands(
+        cmd: u16,
+        flags: u16,
+        offset: u64,
+        nbytes: u64,
+        mut iovec: Vec<Iovec>,
+    ) -> Vec<NbdCommand> {
+        let mut cmds = Vec::new();
+        let mut pos = 0;
+        while pos < nbytes {
+            let len = (nbytes - pos).min(NBD_MAX_PAYLOAD);
+            let cmd_iovec = if iovec.is_empty() {
+                Vec::new()
+            } else {
+                let (head, rest) = iovecs_split(iovec, len);
+                iovec = rest;
+                head
+            };
+            cmds.push(NbdCommand {
+                req_id: 0,
+                header: NbdRequestHeader {
+                    flags,
+                    cmd,
+                    handle: 0,
+                    offset: offset + pos,
+                    len: len as u32,
+                },
+                iovec: cmd_iovec,
+                ret: 0,
+            });
+            pos += len;
+        }
+        cmds
+    }
+
+    fn submit(
+        &mut self,
+        opcode: OpCode,
+        offset: u64,
+        nbytes: u64,
+        completecb: Option<T>,
+        cmds: Vec<NbdCommand>,
+    ) -> u64 {
+        let req = NbdRequest {
+            opcode,
+            offset,
+            nbytes,
+            completecb,
+            pending: 0,
+            ret: 0,
+        };
+        let mut locked_client = self.client.lock().unwrap();
+        let req_id = locked_client.submit(req, cmds);
+        if !locked_client.async_io {
+            locked_client.wait_requests(Some(req_id));
+        }
+        let done = locked_client.take_done();
+        drop(locked_client);
+        complete_requests(&self.aio, done);
+        req_id
+    }
+
+    /// Submit the request without callback and wait for its completion.
+    fn submit_sync(
+        &mut self,
+        opcode: OpCode,
+        offset: u64,
+        nbytes: u64,
+        cmds: Vec<NbdCommand>,
+    ) -> Result<()> {
+        let req_id = self.submit(opcode, offset, nbytes, None, cmds);
+        let mut locked_client = self.client.lock().unwrap();
+        locked_client.wait_requests(Some(req_id));
+        let ret = locked_client.sync_rets.remove(&req_id).unwrap_or(0);
+        let done = locked_client.take_done();
+        drop(locked_client);
+        complete_requests(&self.aio, done);
+        if ret < 0 {
+            bail!(
+                "Failed to access nbd export at offset {}: {}",
+                offset,
+                std::io::Error::from_raw_os_error(-ret as i32)
+            );
+        }
+        Ok(())
+    }
+
+    fn flush_commands(&self) -> Vec<NbdCommand> {
+        if self.flags & NBD_FLAG_SEND_FLUSH == 0 {
+            return Vec::new();
+        }
+        vec![NbdCommand {
+            req_id: 0,
+            header: NbdRequestHeader {
+                cmd: NBD_CMD_FLUSH,
+                ..Default::default()
+            },
+            iovec: Vec::new(),
+            ret: 0,
+        }]
+    }
+
+    fn set_dirty(&self, offset: u64, len: u64) -> Result<()> {
+        for bitmap in self.dirty_bitmaps.iter() {
+            bitmap.lock().unwrap().set_dirty(offset, len)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Clone + 'static> BlockDriverOps<T> for NbdDriver<T> {
+    fn disk_size(&mut self) -> Result<u64> {
+        Ok(self.disk_size)
+    }
+
+    fn resize(&mut self, _size: u64) -> Result<()> {
+        bail!("Resizing nbd export is not supported");
+    }
+
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        let cmds = Self::build_commands(NBD_CMD_READ, 0, offset as u64, nbytes, iovec);
+        self.submit(
+            OpCode::Preadv,
+            offset as u64,
+            nbytes,
+            Some(completecb),
+            cmds,
+        );
+        Ok(())
+    }
+
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.set_dirty(offset as u64, nbytes)?;
+        let cmds = Self::build_commands(NBD_CMD_WRITE, 0, offset as u64, nbytes, iovec);
+        self.submit(
+            OpCode::Pwritev,
+            offset as u64,
+            nbytes,
+            Some(completecb),
+            cmds,
+        );
+        Ok(())
+    }
+
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()> {
+        self.set_dirty(offset as u64, nbytes)?;
+        // Discard is only a hint, it's ignored if the server doesn't support.
+        let cmds = if self.flags & NBD_FLAG_SEND_TRIM != 0 {
+            Self::build_commands(NBD_CMD_TRIM, 0, offset as u64, nbytes, Vec::new())
+        } else {
+            Vec::new()
+        };
+        self.submit(
+            OpCode::Discard,
+            offset as u64,
+            nbytes,
+            Some(completecb),
+            cmds,
+        );
+        Ok(())
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        self.set_dirty(offset as u64, nbytes)?;
+        let (cmd, flags) = if self.flags & NBD_FLAG_SEND_WRITE_ZEROES == 0 {
+            (NBD_CMD_WRITE, 0)
+        } else if unmap {
+            (NBD_CMD_WRITE_ZEROES, 0)
+        } else {
+            (NBD_CMD_WRITE_ZEROES, NBD_CMD_FLAG_NO_HOLE)
+        };
+        let cmds = Self::build_commands(cmd, flags, offset as u64, nbytes, Vec::new());
+        let opcode = if unmap {
+            OpCode::WriteZeroesUnmap
+        } else {
+            OpCode::WriteZeroes
+        };
+        self.submit(opcode, offset as u64, nbytes, Some(completecb), cmds);
+        Ok(())
+    }
+
+    fn datasync(&mut self, completecb: T) -> Result<()> {
+        let cmds = self.flush_commands();
+        self.submit(OpCode::Fdsync, 0, 0, Some(completecb), cmds);
+        Ok(())
+    }
+
+    fn flush_request(&mut self) -> Result<()> {
+        // The commands are sent when submitted.
+        Ok(())
+    }
+
+    fn drain_request(&mut self) -> Result<()> {
+        let mut locked_client = self.client.lock().unwrap();
+        locked_client.wait_requests(None);
+        let done = locked_client.take_done();
+        drop(locked_client);
+        complete_requests(&self.aio, done);
+        Ok(())
+    }
+
+    fn read_sync(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let iovec = vec![Iovec::new(buf.as_mut_ptr() as u64, buf.len() as u64)];
+        let cmds = Self::build_commands(NBD_CMD_READ, 0, offset, buf.len() as u64, iovec);
+        self.submit_sync(OpCode::Preadv, offset, buf.len() as u64, cmds)
+    }
+
+    fn write_sync(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.set_dirty(offset, buf.len() as u64)?;
+        let iovec = vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)];
+        let cmds = Self::build_commands(NBD_CMD_WRITE, 0, offset, buf.len() as u64, iovec);
+        self.submit_sync(OpCode::Pwritev, offset, buf.len() as u64, cmds)
+    }
+
+    fn flush_sync(&mut self) -> Result<()> {
+        let cmds = self.flush_commands();
+        self.submit_sync(OpCode::Fdsync, 0, 0, cmds)
+    }
+
+    fn is_allocated(&mut self, offset: u64, len: u64) -> Result<(bool, u64)> {
+        Ok((true, self.disk_size.saturating_sub(offset).min(len)))
+    }
+
+    fn copy_backing_data(&mut self, _offset: u64, _len: u64) -> Result<()> {
+        // All the data is in the image itself.
+        Ok(())
+    }
+
+    fn drop_backing_file(&mut self) -> Result<()> {
+        bail!(
+            "Nbd drive {} has no backing file",
+            self.client.lock().unwrap().uri
+        )
+    }
+
+    fn backing_file(&self) -> Option<(String, DiskFormat)> {
+        None
+    }
+
+    fn add_dirty_bitmap(&mut self, bitmap: Arc<Mutex<DirtyBitmap>>) -> Result<()> {
+        if let Some(name) = bitmap.lock().unwrap().name() {
+            if self.get_dirty_bitmap(name).is_some() {
+                bail!("Dirty bitmap {} already exists", name);
+            }
+        }
+        self.dirty_bitmaps.push(bitmap);
+        Ok(())
+    }
+
+    fn get_dirty_bitmap(&self, name: &str) -> Option<Arc<Mutex<DirtyBitmap>>> {
+        self.dirty_bitmaps
+            .iter()
+            .find(|b| b.lock().unwrap().name() == Some(name))
+            .cloned()
+    }
+
+    fn named_dirty_bitmaps(&self) -> Vec<Arc<Mutex<DirtyBitmap>>> {
+        self.dirty_bitmaps
+            .iter()
+            .filter(|b| b.lock().unwrap().name().is_some())
+            .cloned()
+            .collect()
+    }
+
+    fn remove_dirty_bitmap(&mut self, bitmap: &Arc<Mutex<DirtyBitmap>>) {
+        self.dirty_bitmaps.retain(|b| !Arc::ptr_eq(b, bitmap));
+    }
+
+    fn create_snapshot(&mut self, _name: &str) -> Result<()> {
+        bail!("Internal snapshot is not supported by nbd drive");
+    }
+
+    fn delete_snapshot(&mut self, _id: Option<&str>, _name: Option<&str>) -> Result<SnapshotInfo> {
+        bail!("Internal snapshot is not supported by nbd drive");
+    }
+
+    fn register_io_event(
+        &mut self,
+        device_broken: Arc<AtomicBool>,
+        _error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        let mut locked_client = self.client.lock().unwrap();
+        locked_client.device_broken = device_broken;
+        locked_client.io_registered = true;
+        locked_client.register_stream()
+    }
+
+    fn unregister_io_event(&mut self) -> Result<()> {
+        let mut locked_client = self.client.lock().unwrap();
+        locked_client.io_registered = false;
+        locked_client.unregister_stream();
+        Ok(())
+    }
+}
+
+// SAFETY: The client is shared with the event handler of its stream only, and
+// the access to it is serialized by its lock.
+unsafe impl<T: Clone + 'static> Send for NbdDriver<T> {}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::atomic::AtomicI64;
+    use std::thread;
+
+    use super::*;
+    use machine_manager::config::NbdAddr;
+
+    const TEST_DISK_SIZE: usize = 1 << 20;
+
+    struct TestServer {
+        disk: Arc<Mutex<Vec<u8>>>,
+        /// Close the connection when the next command is received.
+        drop_next: Arc<AtomicBool>,
+    }
+
+    fn send_rep(stream: &mut UnixStream, opt: u32, reply: u32, data: &[u8]) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_REP_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&opt.to_be_bytes());
+        buf.extend_from_slice(&reply.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).unwrap();
+    }
+
+    fn serve(mut stream: UnixStream, disk: &Mutex<Vec<u8>>, drop_next: &AtomicBool) {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&buf).unwrap();
+        let mut flags = [0_u8; 4];
+        stream.read_exact(&mut flags).unwrap();
+
+        let mut structured = false;
+        loop {
+            let mut header = [0_u8; NBD_OPT_HEADER_SIZE];
+            stream.read_exact(&mut header).unwrap();
+            let opt = BigEndian::read_u32(&header[8..12]);
+            let mut data = vec![0_u8; BigEndian::read_u32(&header[12..16]) as usize];
+            stream.read_exact(&mut data).unwrap();
+            if opt == NBD_OPT_STRUCTURED_REPLY {
+                structured = true;
+                send_rep(&mut stream, opt, NBD_REP_ACK, &[]);
+            } else if opt == NBD_OPT_GO {
+                let mut info = Vec::new();
+                info.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+                info.extend_from_slice(&(TEST_DISK_SIZE as u64).to_be_bytes());
+                let flags = NBD_FLAG_HAS_FLAGS
+                    | NBD_FLAG_SEND_FLUSH
+                    | NBD_FLAG_SEND_TRIM
+                    | NBD_FLAG_SEND_WRITE_ZEROES;
+                info.extend_from_slice(&flags.to_be_bytes());
+                send_rep(&mut stream, opt, NBD_REP_INFO, &info);
+                send_rep(&mut stream, opt, NBD_REP_ACK, &[]);
+                break;
+            } else {
+                send_rep(&mut stream, opt, NBD_REP_ERR_UNSUP, &[]);
+            }
+        }
+
+        loop {
+            let mut buf = [0_u8; NBD_REQUEST_SIZE];
+            if stream.read_exact(&mut buf).is_err() {
+                return;
+            }
+            if drop_next.swap(false, Ordering::SeqCst) {
+                return;
+            }
+            let req = NbdRequestHeader::from_bytes(&buf).unwrap();
+            let start = req.offset as usize;
+            let end = start + req.len as usize;
+            let in_range = end <= TEST_DISK_SIZE;
+            let mut data = vec![0_u8; req.len as usize];
+            if req.cmd == NBD_CMD_WRITE {
+                stream.read_exact(&mut data).unwrap();
+            }
+            let mut disk = disk.lock().unwrap();
+            match req.cmd {
+                NBD_CMD_DISC => return,
+                NBD_CMD_READ if in_range && structured => {
+                    let mut reply = Vec::new();
+                    reply.extend_from_slice(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes());
+                    reply.extend_from_slice(&NBD_REPLY_FLAG_DONE.to_be_bytes());
+                    reply.extend_from_slice(&NBD_REPLY_TYPE_OFFSET_DATA.to_be_bytes());
+                    reply.extend_from_slice(&req.handle.to_be_bytes());
+                    reply.extend_from_slice(&(req.len + 8).to_be_bytes());
+                    reply.extend_from_slice(&req.offset.to_be_bytes());
+                    reply.extend_from_slice(&disk[start..end]);
+                    stream.write_all(&reply).unwrap();
+                    continue;
+                }
+                NBD_CMD_READ if !in_range && structured => {
+                    let msg = b"out of range";
+                    let mut reply = Vec::new();
+                    reply.extend_from_slice(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes());
+                    reply.extend_from_slice(&NBD_REPLY_FLAG_DONE.to_be_bytes());
+                    reply.extend_from_slice(&NBD_REPLY_TYPE_ERROR.to_be_bytes());
+                    reply.extend_from_slice(&req.handle.to_be_bytes());
+                    reply.extend_from_slice(&(6 + msg.len() as u32).to_be_bytes());
+                    reply.extend_from_slice(&NBD_EINVAL.to_be_bytes());
+                    reply.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+                    reply.extend_from_slice(msg);
+                    stream.write_all(&reply).unwrap();
+                    continue;
+                }
+                NBD_CMD_WRITE if in_range => disk[start..end].copy_from_slice(&data),
+                NBD_CMD_TRIM | NBD_CMD_WRITE_ZEROES if in_range => disk[start..end].fill(0),
+                _ => {}
+            }
+            let err = if in_range { 0 } else { NBD_EINVAL };
+            let mut reply = Vec::new();
+            reply.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+            reply.extend_from_slice(&err.to_be_bytes());
+            reply.extend_from_slice(&req.handle.to_be_bytes());
+            stream.write_all(&reply).unwrap();
+        }
+    }
+
+    impl TestServer {
+        fn start(path: &str) -> Self {
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path).unwrap();
+            let disk = Arc::new(Mutex::new(vec![0_u8; TEST_DISK_SIZE]));
+            let drop_next = Arc::new(AtomicBool::new(false));
+            let server_disk = disk.clone();
+            let server_drop = drop_next.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    serve(stream.unwrap(), &server_disk, &server_drop);
+                }
+            });
+            TestServer { disk, drop_next }
+        }
+    }
+
+    fn test_complete_func(aiocb: &AioCb<Arc<AtomicI64>>, ret: i64) -> Result<()> {
+        aiocb.iocompletecb.store(ret, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn create_driver(path: &str) -> NbdDriver<Arc<AtomicI64>> {
+        let uri = NbdUri {
+            addr: NbdAddr::Unix(path.to_string()),
+            export: "disk0".to_string(),
+        };
+        let aio = Aio::new(Arc::new(test_complete_func), AioEngine::Off).unwrap();
+        let prop = BlockProperty {
+            id: "nbd0".to_string(),
+            format: DiskFormat::Raw,
+            iothread: None,
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            copy_on_read: false,
+        };
+        NbdDriver::new(&uri, false, aio, prop).unwrap()
+    }
+
+    #[test]
+    fn test_nbd_client_rw() {
+        let path = "/tmp/test_nbd_client_rw.sock";
+        let server = TestServer::start(path);
+        let mut driver = create_driver(path);
+        assert_eq!(driver.disk_size().unwrap(), TEST_DISK_SIZE as u64);
+
+        let ret = Arc::new(AtomicI64::new(1));
+        let mut wbuf = vec![0x5a_u8; 8192];
+        let iovec = vec![Iovec::new(wbuf.as_mut_ptr() as u64, wbuf.len() as u64)];
+        driver.write_vectored(iovec, 4096, ret.clone()).unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), 0);
+        assert_eq!(server.disk.lock().unwrap()[4096..12288], wbuf[..]);
+
+        let mut rbuf = vec![0_u8; 8192];
+        let iovec = vec![
+            Iovec::new(rbuf.as_mut_ptr() as u64, 4096),
+            Iovec::new(rbuf.as_mut_ptr() as u64 + 4096, 4096),
+        ];
+        ret.store(1, Ordering::SeqCst);
+        driver.read_vectored(iovec, 4096, ret.clone()).unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), 0);
+        assert_eq!(rbuf, wbuf);
+
+        ret.store(1, Ordering::SeqCst);
+        driver.write_zeroes(4096, 4096, ret.clone(), false).unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), 0);
+        let mut buf = vec![1_u8; 8192];
+        driver.read_sync(4096, &mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|b| *b == 0));
+        assert!(buf[4096..].iter().all(|b| *b == 0x5a));
+
+        ret.store(1, Ordering::SeqCst);
+        driver.datasync(ret.clone()).unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), 0);
+
+        // Errors of both simple and structured replies are reported.
+        ret.store(1, Ordering::SeqCst);
+        let iovec = vec![Iovec::new(rbuf.as_mut_ptr() as u64, 4096)];
+        driver
+            .read_vectored(iovec, TEST_DISK_SIZE, ret.clone())
+            .unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), -i64::from(libc::EINVAL));
+        ret.store(1, Ordering::SeqCst);
+        driver.discard(TEST_DISK_SIZE, 4096, ret.clone()).unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), -i64::from(libc::EINVAL));
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_nbd_client_reconnect() {
+        let path = "/tmp/test_nbd_client_reconnect.sock";
+        let server = TestServer::start(path);
+        let mut driver = create_driver(path);
+
+        // The write is sent again after the server drops the connection.
+        server.drop_next.store(true, Ordering::SeqCst);
+        let ret = Arc::new(AtomicI64::new(1));
+        let mut wbuf = vec![0xa5_u8; 4096];
+        let iovec = vec![Iovec::new(wbuf.as_mut_ptr() as u64, wbuf.len() as u64)];
+        driver.write_vectored(iovec, 0, ret.clone()).unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), 0);
+        assert_eq!(server.disk.lock().unwrap()[..4096], wbuf[..]);
+
+        let mut buf = vec![0_u8; 4096];
+        driver.read_sync(0, &mut buf).unwrap();
+        assert_eq!(buf, wbuf);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,349 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Network block device protocol, see
+//! <https://github.com/NetworkBlockDevice/nbd/blob/master/doc/proto.md>.
+
+pub mod client;
+pub mod server;
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+use anyhow::{Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+use machine_manager::config::NbdAddr;
+use util::aio::Iovec;
+
+/// Magic numbers of the handshake.
+pub const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+pub const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+pub const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+/// Magic numbers of the transmission.
+pub const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+pub const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+/// Handshake flags of server.
+pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+/// Handshake flags of client.
+pub const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+pub const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+/// Transmission flags of export.
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+pub const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+/// Options of the handshake.
+pub const NBD_OPT_EXPORT_NAME: u32 = 1;
+pub const NBD_OPT_ABORT: u32 = 2;
+pub const NBD_OPT_LIST: u32 = 3;
+pub const NBD_OPT_INFO: u32 = 6;
+pub const NBD_OPT_GO: u32 = 7;
+pub const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+pub const NBD_OPT_LIST_META_CONTEXT: u32 = 9;
+pub const NBD_OPT_SET_META_CONTEXT: u32 = 10;
+
+/// Replies of the options.
+pub const NBD_REP_ACK: u32 = 1;
+pub const NBD_REP_SERVER: u32 = 2;
+pub const NBD_REP_INFO: u32 = 3;
+pub const NBD_REP_META_CONTEXT: u32 = 4;
+pub const NBD_REP_FLAG_ERROR: u32 = 1 << 31;
+pub const NBD_REP_ERR_UNSUP: u32 = NBD_REP_FLAG_ERROR | 1;
+pub const NBD_REP_ERR_INVALID: u32 = NBD_REP_FLAG_ERROR | 3;
+pub const NBD_REP_ERR_UNKNOWN: u32 = NBD_REP_FLAG_ERROR | 6;
+
+/// Information types of NBD_OPT_INFO and NBD_OPT_GO.
+pub const NBD_INFO_EXPORT: u16 = 0;
+
+/// Commands of the transmission.
+pub const NBD_CMD_READ: u16 = 0;
+pub const NBD_CMD_WRITE: u16 = 1;
+pub const NBD_CMD_DISC: u16 = 2;
+pub const NBD_CMD_FLUSH: u16 = 3;
+pub const NBD_CMD_TRIM: u16 = 4;
+pub const NBD_CMD_WRITE_ZEROES: u16 = 6;
+pub const NBD_CMD_BLOCK_STATUS: u16 = 7;
+/// Flags of the commands.
+pub const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+pub const NBD_CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+
+/// Flags and types of the structured reply chunks.
+pub const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+pub const NBD_REPLY_TYPE_NONE: u16 = 0;
+pub const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+pub const NBD_REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+pub const NBD_REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+pub const NBD_REPLY_TYPE_ERROR_BIT: u16 = 1 << 15;
+pub const NBD_REPLY_TYPE_ERROR: u16 = NBD_REPLY_TYPE_ERROR_BIT | 1;
+pub const NBD_REPLY_TYPE_ERROR_OFFSET: u16 = NBD_REPLY_TYPE_ERROR_BIT | 2;
+
+/// Error values of the replies.
+pub const NBD_EPERM: u32 = 1;
+pub const NBD_EIO: u32 = 5;
+pub const NBD_ENOMEM: u32 = 12;
+pub const NBD_EINVAL: u32 = 22;
+pub const NBD_ENOSPC: u32 = 28;
+pub const NBD_EOVERFLOW: u32 = 75;
+pub const NBD_ENOTSUP: u32 = 95;
+pub const NBD_ESHUTDOWN: u32 = 108;
+
+/// Prefix of the meta context which reports the dirty areas in a bitmap.
+pub const NBD_META_DIRTY_BITMAP_PREFIX: &str = "qemu:dirty-bitmap:";
+/// Flag of the extent in the dirty bitmap context which is dirty.
+pub const NBD_STATE_DIRTY: u32 = 1 << 0;
+
+/// Size of the header of option request.
+pub const NBD_OPT_HEADER_SIZE: usize = 16;
+/// Size of the header of option reply.
+pub const NBD_OPT_REPLY_HEADER_SIZE: usize = 20;
+/// Size of the header of transmission request.
+pub const NBD_REQUEST_SIZE: usize = 28;
+/// Size of the simple reply.
+pub const NBD_SIMPLE_REPLY_SIZE: usize = 16;
+/// Size of the header of structured reply chunk.
+pub const NBD_STRUCTURED_REPLY_SIZE: usize = 20;
+/// Max length of the data carried by one request or reply.
+pub const NBD_MAX_PAYLOAD: u64 = 32 << 20;
+/// Max length of the data of option reply.
+pub const NBD_MAX_OPTION_SIZE: usize = 64 << 10;
+
+/// Convert the error value of the reply to errno of host.
+pub fn nbd_err_to_errno(err: u32) -> i32 {
+    match err {
+        NBD_EPERM => libc::EPERM,
+        NBD_EIO => libc::EIO,
+        NBD_ENOMEM => libc::ENOMEM,
+        NBD_ENOSPC => libc::ENOSPC,
+        NBD_EOVERFLOW => libc::EOVERFLOW,
+        NBD_ENOTSUP => libc::ENOTSUP,
+        NBD_ESHUTDOWN => libc::ESHUTDOWN,
+        // Unknown errors are treated as EINVAL as the protocol requires.
+        _ => libc::EINVAL,
+    }
+}
+
+/// Connection between NBD client and server.
+pub enum NbdStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl NbdStream {
+    /// Connect to the NBD server at `addr`.
+    pub fn connect(addr: &NbdAddr) -> Result<Self> {
+        let stream = match addr {
+            NbdAddr::Unix(path) => NbdStream::Unix(
+                UnixStream::connect(path)
+                    .with_context(|| format!("Failed to connect nbd server {}", addr))?,
+            ),
+            NbdAddr::Inet { host, port } => {
+                let stream = TcpStream::connect((host.as_str(), *port))
+                    .with_context(|| format!("Failed to connect nbd server {}", addr))?;
+                stream.set_nodelay(true)?;
+                NbdStream::Tcp(stream)
+            }
+        };
+        Ok(stream)
+    }
+
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(match self {
+            NbdStream::Unix(s) => NbdStream::Unix(s.try_clone()?),
+            NbdStream::Tcp(s) => NbdStream::Tcp(s.try_clone()?),
+        })
+    }
+
+    pub fn shutdown(&self) {
+        let _ = match self {
+            NbdStream::Unix(s) => s.shutdown(Shutdown::Both),
+            NbdStream::Tcp(s) => s.shutdown(Shutdown::Both),
+        };
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        let mut buf = [0_u8; 2];
+        self.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u16(&buf))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let mut buf = [0_u8; 4];
+        self.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u32(&buf))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let mut buf = [0_u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(BigEndian::read_u64(&buf))
+    }
+
+    /// Read and drop `len` bytes.
+    pub fn skip(&mut self, len: u64) -> Result<()> {
+        let copied = std::io::copy(&mut Read::by_ref(self).take(len), &mut std::io::sink())?;
+        if copied != len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        Ok(())
+    }
+
+    /// Read the data to the guest memory described by `iovec`.
+    pub fn read_iovec(&mut self, iovec: &[Iovec]) -> Result<()> {
+        for iov in iovec {
+            // SAFETY: the iovec is the host address of guest memory checked by device.
+            let buf = unsafe {
+                std::slice::from_raw_parts_mut(iov.iov_base as *mut u8, iov.iov_len as usize)
+            };
+            self.read_exact(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Write the data of the guest memory described by `iovec`.
+    pub fn write_iovec(&mut self, iovec: &[Iovec]) -> Result<()> {
+        for iov in iovec {
+            // SAFETY: the iovec is the host address of guest memory checked by device.
+            let buf = unsafe {
+                std::slice::from_raw_parts(iov.iov_base as *const u8, iov.iov_len as usize)
+            };
+            self.write_all(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Write `len` bytes of zeros.
+    pub fn write_zeroes(&mut self, mut len: u64) -> Result<()> {
+        let zeroes = [0_u8; 4096];
+        while len > 0 {
+            let size = len.min(zeroes.len() as u64);
+            self.write_all(&zeroes[..size as usize])?;
+            len -= size;
+        }
+        Ok(())
+    }
+}
+
+impl Read for NbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Unix(s) => s.read(buf),
+            NbdStream::Tcp(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for NbdStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Unix(s) => s.write(buf),
+            NbdStream::Tcp(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            NbdStream::Unix(s) => s.flush(),
+            NbdStream::Tcp(s) => s.flush(),
+        }
+    }
+}
+
+impl AsRawFd for NbdStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            NbdStream::Unix(s) => s.as_raw_fd(),
+            NbdStream::Tcp(s) => s.as_raw_fd(),
+        }
+    }
+}
+
+/// Header of transmission request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NbdRequestHeader {
+    pub flags: u16,
+    pub cmd: u16,
+    pub handle: u64,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl NbdRequestHeader {
+    pub fn to_bytes(&self) -> [u8; NBD_REQUEST_SIZE] {
+        let mut buf = [0_u8; NBD_REQUEST_SIZE];
+        BigEndian::write_u32(&mut buf[0..4], NBD_REQUEST_MAGIC);
+        BigEndian::write_u16(&mut buf[4..6], self.flags);
+        BigEndian::write_u16(&mut buf[6..8], self.cmd);
+        BigEndian::write_u64(&mut buf[8..16], self.handle);
+        BigEndian::write_u64(&mut buf[16..24], self.offset);
+        BigEndian::write_u32(&mut buf[24..28], self.len);
+        buf
+    }
+
+    /// Parse the header, returns None if the magic is wrong.
+    pub fn from_bytes(buf: &[u8; NBD_REQUEST_SIZE]) -> Option<Self> {
+        if BigEndian::read_u32(&buf[0..4]) != NBD_REQUEST_MAGIC {
+            return None;
+        }
+        Some(NbdRequestHeader {
+            flags: BigEndian::read_u16(&buf[4..6]),
+            cmd: BigEndian::read_u16(&buf[6..8]),
+            handle: BigEndian::read_u64(&buf[8..16]),
+            offset: BigEndian::read_u64(&buf[16..24]),
+            len: BigEndian::read_u32(&buf[24..28]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nbd_request_header() {
+        let header = NbdRequestHeader {
+            flags: NBD_CMD_FLAG_NO_HOLE,
+            cmd: NBD_CMD_WRITE,
+            handle: 0x1234,
+            offset: 4096,
+            len: 512,
+        };
+        let buf = header.to_bytes();
+        assert_eq!(&buf[0..4], &[0x25, 0x60, 0x95, 0x13]);
+        let parsed = NbdRequestHeader::from_bytes(&buf).unwrap();
+        assert_eq!(parsed.flags, NBD_CMD_FLAG_NO_HOLE);
+        assert_eq!(parsed.cmd, NBD_CMD_WRITE);
+        assert_eq!(parsed.handle, 0x1234);
+        assert_eq!(parsed.offset, 4096);
+        assert_eq!(parsed.len, 512);
+
+        let mut bad = buf;
+        bad[0] = 0;
+        assert!(NbdRequestHeader::from_bytes(&bad).is_none());
+    }
+
+    #[test]
+    fn test_nbd_errno() {
+        assert_eq!(nbd_err_to_errno(NBD_ENOSPC), libc::ENOSPC);
+        assert_eq!(nbd_err_to_errno(NBD_EIO), libc::EIO);
+        assert_eq!(nbd_err_to_errno(1000), libc::EINVAL);
+    }
+}
@@ -0,0 +1,1171 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
+use vmm_sys_util::epoll::EventSet;
+
+use super::*;
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::BlockDriverOps;
+use machine_manager::config::NbdAddr;
+use machine_manager::event_loop::EventLoop;
+use util::loop_context::{
+    gen_delete_notifiers, EventNotifier, NotifierCallback, NotifierOperation,
+};
+
+/// Id of the only meta context supported, the dirty bitmap of export.
+const NBD_META_CONTEXT_ID: u32 = 1;
+
+/// The running NBD server, there is at most one server in VM.
+static NBD_SERVER: Lazy<Mutex<Option<NbdServer>>> = Lazy::new(|| Mutex::new(None));
+
+/// Access to the disk of the exported device.
+trait NbdExportOps: Send + Sync {
+    fn disk_size(&self) -> Result<u64>;
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<()>;
+
+    fn flush(&self) -> Result<()>;
+}
+
+/// The block backend of device, the requests of guest in flight are drained
+/// before the export accesses the disk.
+struct BackendExport<T: Clone + 'static> {
+    backend: Arc<Mutex<dyn BlockDriverOps<T>>>,
+}
+
+impl<T: Clone + 'static> NbdExportOps for BackendExport<T> {
+    fn disk_size(&self) -> Result<u64> {
+        self.backend.lock().unwrap().disk_size()
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut locked_backend = self.backend.lock().unwrap();
+        locked_backend.drain_request()?;
+        locked_backend.read_sync(offset, buf)
+    }
+
+    fn write(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut locked_backend = self.backend.lock().unwrap();
+        locked_backend.drain_request()?;
+        locked_backend.write_sync(offset, buf)
+    }
+
+    fn flush(&self) -> Result<()> {
+        let mut locked_backend = self.backend.lock().unwrap();
+        locked_backend.drain_request()?;
+        locked_backend.flush_sync()
+    }
+}
+
+/// Block device exported by the NBD server.
+pub struct NbdExport {
+    /// Name of the export which clients open.
+    pub name: String,
+    /// Id of the exported device.
+    pub device: String,
+    pub writable: bool,
+    /// Dirty bitmap reported in the meta context `qemu:dirty-bitmap:<name>`.
+    bitmap: Option<(String, Arc<Mutex<DirtyBitmap>>)>,
+    ops: Box<dyn NbdExportOps>,
+}
+
+impl NbdExport {
+    /// Export the disk of device. The requests of clients are processed on
+    /// `backend` of device, while the guest keeps using it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the export.
+    /// * `device` - Id of the device.
+    /// * `backend` - Block backend of the device.
+    /// * `writable` - Whether clients can write the disk.
+    /// * `bitmap` - The dirty bitmap of device and its name to report to clients.
+    pub fn new<T: Clone + 'static>(
+        name: &str,
+        device: &str,
+        backend: Arc<Mutex<dyn BlockDriverOps<T>>>,
+        writable: bool,
+        bitmap: Option<(String, Arc<Mutex<DirtyBitmap>>)>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            device: device.to_string(),
+            writable,
+            bitmap,
+            ops: Box::new(BackendExport { backend }),
+        }
+    }
+
+    fn meta_context(&self) -> Option<String> {
+        self.bitmap
+            .as_ref()
+            .map(|(name, _)| format!("{}{}", NBD_META_DIRTY_BITMAP_PREFIX, name))
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        let mut flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_CAN_MULTI_CONN;
+        if !self.writable {
+            flags |= NBD_FLAG_READ_ONLY;
+        }
+        flags
+    }
+
+    /// Get the extents of [offset, offset + len) in the dirty bitmap.
+    fn dirty_extents(&self, offset: u64, len: u64, one: bool) -> Result<Vec<(u32, u32)>> {
+        let bitmap = match &self.bitmap {
+            Some((_, bitmap)) => bitmap.lock().unwrap(),
+            None => bail!("No dirty bitmap is exported"),
+        };
+        let end = offset + len;
+        let mut extents = Vec::new();
+        let mut pos = offset;
+        while pos < end {
+            let (start, dirty_len) = bitmap.next_dirty_area(pos, end - pos)?.unwrap_or((end, 0));
+            // The dirty area may start in the middle of granule before `pos`.
+            let start = start.max(pos).min(end);
+            if start > pos {
+                extents.push(((start - pos) as u32, 0));
+                pos = start;
+            } else {
+                let dirty_end = (start + dirty_len).min(end).max(pos + 1);
+                extents.push(((dirty_end - pos) as u32, NBD_STATE_DIRTY));
+                pos = dirty_end;
+            }
+            if one {
+                break;
+            }
+        }
+        Ok(extents)
+    }
+}
+
+type NbdExports = Arc<Mutex<HashMap<String, Arc<NbdExport>>>>;
+
+/// Connection of client, which is shut down when its export is removed.
+struct NbdConnection {
+    stream: NbdStream,
+    /// Name of the export opened by client.
+    export: Arc<Mutex<Option<String>>>,
+}
+
+type NbdConnections = Arc<Mutex<HashMap<u64, NbdConnection>>>;
+
+enum NbdListener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl NbdListener {
+    fn bind(addr: &NbdAddr) -> Result<Self> {
+        Ok(match addr {
+            NbdAddr::Unix(path) => NbdListener::Unix(
+                UnixListener::bind(path)
+                    .with_context(|| format!("Failed to bind nbd server to {}", addr))?,
+            ),
+            NbdAddr::Inet { host, port } => NbdListener::Tcp(
+                TcpListener::bind((host.as_str(), *port))
+                    .with_context(|| format!("Failed to bind nbd server to {}", addr))?,
+            ),
+        })
+    }
+
+    fn accept(&self) -> Result<NbdStream> {
+        Ok(match self {
+            NbdListener::Unix(l) => NbdStream::Unix(l.accept()?.0),
+            NbdListener::Tcp(l) => {
+                let stream = l.accept()?.0;
+                stream.set_nodelay(true)?;
+                NbdStream::Tcp(stream)
+            }
+        })
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            NbdListener::Unix(l) => l.as_raw_fd(),
+            NbdListener::Tcp(l) => l.as_raw_fd(),
+        }
+    }
+}
+
+struct NbdServer {
+    addr: NbdAddr,
+    listener: Arc<NbdListener>,
+    /// Max number of clients connected at the same time, 0 means unlimited.
+    max_connections: u32,
+    exports: NbdExports,
+    connections: NbdConnections,
+    next_conn_id: u64,
+}
+
+impl NbdServer {
+    fn accept(&mut self) {
+        let stream = match self.listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Failed to accept nbd client: {:?}", e);
+                return;
+            }
+        };
+        let nr_conns = self.connections.lock().unwrap().len();
+        if self.max_connections != 0 && nr_conns >= self.max_connections as usize {
+            warn!("Too many nbd clients, the connection is refused");
+            return;
+        }
+        let cloned_stream = match stream.try_clone() {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to clone stream of nbd client: {:?}", e);
+                return;
+            }
+        };
+
+        let id = self.next_conn_id;
+        self.next_conn_id += 1;
+        let export = Arc::new(Mutex::new(None));
+        self.connections.lock().unwrap().insert(
+            id,
+            NbdConnection {
+                stream: cloned_stream,
+                export: export.clone(),
+            },
+        );
+        let exports = self.exports.clone();
+        let connections = self.connections.clone();
+        let res = thread::Builder::new()
+            .name("nbd-client".to_string())
+            .spawn(move || {
+                let mut session = NbdSession::new(stream, exports, export);
+                if let Err(e) = session.run() {
+                    warn!("Nbd client is disconnected: {:?}", e);
+                }
+                connections.lock().unwrap().remove(&id);
+            });
+        if let Err(e) = res {
+            error!("Failed to create thread for nbd client: {:?}", e);
+            self.connections.lock().unwrap().remove(&id);
+        }
+    }
+
+    /// Shut down the connections of clients which open the export, or all the
+    /// connections if `name` is none.
+    fn disconnect(&self, name: Option<&str>) {
+        for conn in self.connections.lock().unwrap().values() {
+            let export = conn.export.lock().unwrap();
+            if name.is_none() || export.as_deref() == name {
+                conn.stream.shutdown();
+            }
+        }
+    }
+}
+
+/// Session of a client, from the negotiation to the disconnection.
+struct NbdSession {
+    stream: NbdStream,
+    exports: NbdExports,
+    /// Name of the export opened, shared with the connection in server.
+    export_name: Arc<Mutex<Option<String>>>,
+    no_zeroes: bool,
+    structured: bool,
+    /// The dirty bitmap context is selected by client.
+    meta_selected: bool,
+}
+
+impl NbdSession {
+    fn new(
+        stream: NbdStream,
+        exports: NbdExports,
+        export_name: Arc<Mutex<Option<String>>>,
+    ) -> Self {
+        Self {
+            stream,
+            exports,
+            export_name,
+            no_zeroes: false,
+            structured: false,
+            meta_selected: false,
+        }
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let export = match self.negotiate()? {
+            Some(export) => export,
+            None => return Ok(()),
+        };
+        *self.export_name.lock().unwrap() = Some(export.name.clone());
+        self.transmit(&export)
+    }
+
+    fn find_export(&self, name: &str) -> Option<Arc<NbdExport>> {
+        self.exports.lock().unwrap().get(name).cloned()
+    }
+
+    fn send_rep(&mut self, opt: u32, reply: u32, data: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(NBD_OPT_REPLY_HEADER_SIZE + data.len());
+        buf.extend_from_slice(&NBD_REP_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&opt.to_be_bytes());
+        buf.extend_from_slice(&reply.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn send_rep_err(&mut self, opt: u32, reply: u32, msg: &str) -> Result<()> {
+        self.send_rep(opt, reply, msg.as_bytes())
+    }
+
+    /// Negotiate in fixed newstyle, returns the export opened by client, or
+    /// none if the client aborts.
+    fn negotiate(&mut self) -> Result<Option<Arc<NbdExport>>> {
+        let mut buf = Vec::with_capacity(18);
+        buf.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        self.stream.write_all(&buf)?;
+        let client_flags = self.stream.read_u32()?;
+        if client_flags & NBD_FLAG_C_FIXED_NEWSTYLE == 0 {
+            bail!("Nbd client doesn't support fixed newstyle negotiation");
+        }
+        self.no_zeroes = client_flags & NBD_FLAG_C_NO_ZEROES != 0;
+
+        loop {
+            let mut header = [0_u8; NBD_OPT_HEADER_SIZE];
+            self.stream.read_exact(&mut header)?;
+            if BigEndian::read_u64(&header[0..8]) != NBD_OPTS_MAGIC {
+                bail!("Invalid magic of nbd option");
+            }
+            let opt = BigEndian::read_u32(&header[8..12]);
+            let len = BigEndian::read_u32(&header[12..16]) as usize;
+            if len > NBD_MAX_OPTION_SIZE {
+                bail!("Too long data {} of nbd option {}", len, opt);
+            }
+            let mut data = vec![0_u8; len];
+            self.stream.read_exact(&mut data)?;
+
+            match opt {
+                NBD_OPT_EXPORT_NAME => {
+                    let name = String::from_utf8_lossy(&data).to_string();
+                    let export = self
+                        .find_export(&name)
+                        .with_context(|| format!("Nbd export {} not found", name))?;
+                    let mut buf = Vec::with_capacity(10 + 124);
+                    buf.extend_from_slice(&export.ops.disk_size()?.to_be_bytes());
+                    buf.extend_from_slice(&export.transmission_flags().to_be_bytes());
+                    if !self.no_zeroes {
+                        buf.resize(buf.len() + 124, 0);
+                    }
+                    self.stream.write_all(&buf)?;
+                    return Ok(Some(export));
+                }
+                NBD_OPT_ABORT => {
+                    self.send_rep(opt, NBD_REP_ACK, &[])?;
+                    return Ok(None);
+                }
+                NBD_OPT_LIST => {
+                    if !data.is_empty() {
+                        self.send_rep_err(opt, NBD_REP_ERR_INVALID, "Invalid data of list")?;
+                        continue;
+                    }
+                    let mut names: Vec<String> =
+                        self.exports.lock().unwrap().keys().cloned().collect();
+                    names.sort();
+                    for name in names {
+                        let mut buf = Vec::with_capacity(4 + name.len());
+                        buf.extend_from_slice(&(name.len() as u32).to_be_bytes());
+                        buf.extend_from_slice(name.as_bytes());
+                        self.send_rep(opt, NBD_REP_SERVER, &buf)?;
+                    }
+                    self.send_rep(opt, NBD_REP_ACK, &[])?;
+                }
+                NBD_OPT_STRUCTURED_REPLY => {
+                    if !data.is_empty() {
+                        self.send_rep_err(opt, NBD_REP_ERR_INVALID, "Invalid data")?;
+                        continue;
+                    }
+                    self.structured = true;
+                    self.send_rep(opt, NBD_REP_ACK, &[])?;
+                }
+                NBD_OPT_INFO | NBD_OPT_GO => {
+                    if let Some(export) = self.handle_info(opt, &data)? {
+                        if opt == NBD_OPT_GO {
+                            return Ok(Some(export));
+                        }
+                    }
+                }
+                NBD_OPT_LIST_META_CONTEXT | NBD_OPT_SET_META_CONTEXT => {
+                    self.handle_meta_context(opt, &data)?;
+                }
+                _ => {
+                    self.send_rep_err(opt, NBD_REP_ERR_UNSUP, "Unsupported option")?;
+                }
+            }
+        }
+    }
+
+    /// Parse the export name and the queries of the option data.
+    fn parse_name_and_queries(data: &[u8], query_len: usize) -> Option<(String, Vec<&[u8]>)> {
+        if data.len() < 4 {
+            return None;
+        }
+        let name_len = BigEndian::read_u32(&data[0..4]) as usize;
+        let rest = data.get(4..)?;
+        let name = String::from_utf8_lossy(rest.get(..name_len)?).to_string();
+        let rest = rest.get(name_len..)?;
+        let count = match query_len {
+            // The count of queries is u16 for info, u32 for meta context.
+            2 => usize::from(BigEndian::read_u16(rest.get(..2)?)),
+            _ => BigEndian::read_u32(rest.get(..4)?) as usize,
+        };
+        let mut rest = rest.get(query_len..)?;
+        let mut queries = Vec::new();
+        for _ in 0..count {
+            if query_len == 2 {
+                queries.push(rest.get(..2)?);
+                rest = rest.get(2..)?;
+            } else {
+                let len = BigEndian::read_u32(rest.get(..4)?) as usize;
+                queries.push(rest.get(4..4 + len)?);
+                rest = rest.get(4 + len..)?;
+            }
+        }
+        if !rest.is_empty() {
+            return None;
+        }
+        Some((name, queries))
+    }
+
+    fn handle_info(&mut self, opt: u32, data: &[u8]) -> Result<Option<Arc<NbdExport>>> {
+        let name = match Self::parse_name_and_queries(data, 2) {
+            Some((name, _)) => name,
+            None => {
+                self.send_rep_err(opt, NBD_REP_ERR_INVALID, "Invalid data of info")?;
+                return Ok(None);
+            }
+        };
+        let export = match self.find_export(&name) {
+            Some(export) => export,
+            None => {
+                let msg = format!("Export {} not found", name);
+                self.send_rep_err(opt, NBD_REP_ERR_UNKNOWN, &msg)?;
+                return Ok(None);
+            }
+        };
+        // Only NBD_INFO_EXPORT is replied, the other information is optional.
+        let mut buf = Vec::with_capacity(12);
+        buf.extend_from_slice(&NBD_INFO_EXPORT.to_be_bytes());
+        buf.extend_from_slice(&export.ops.disk_size()?.to_be_bytes());
+        buf.extend_from_slice(&export.transmission_flags().to_be_bytes());
+        self.send_rep(opt, NBD_REP_INFO, &buf)?;
+        self.send_rep(opt, NBD_REP_ACK, &[])?;
+        Ok(Some(export))
+    }
+
+    fn handle_meta_context(&mut self, opt: u32, data: &[u8]) -> Result<()> {
+        if opt == NBD_OPT_SET_META_CONTEXT && !self.structured {
+            return self.send_rep_err(
+                opt,
+                NBD_REP_ERR_INVALID,
+                "Structured reply is not negotiated",
+            );
+        }
+        let (name, queries) = match Self::parse_name_and_queries(data, 4) {
+            Some(parsed) => parsed,
+            None => {
+                return self.send_rep_err(opt, NBD_REP_ERR_INVALID, "Invalid data of meta context")
+            }
+        };
+        let export = match self.find_export(&name) {
+            Some(export) => export,
+            None => {
+                let msg = format!("Export {} not found", name);
+                return self.send_rep_err(opt, NBD_REP_ERR_UNKNOWN, &msg);
+            }
+        };
+
+        let context = export.meta_context();
+        let matched = context.as_ref().filter(|context| {
+            // All the contexts are listed if there is no query.
+            (opt == NBD_OPT_LIST_META_CONTEXT && queries.is_empty())
+                || queries.iter().any(|query| {
+                    let query = String::from_utf8_lossy(query);
+                    query == context.as_str()
+                        || (opt == NBD_OPT_LIST_META_CONTEXT
+                            && query == NBD_META_DIRTY_BITMAP_PREFIX)
+                })
+        });
+        if opt == NBD_OPT_SET_META_CONTEXT {
+            self.meta_selected = matched.is_some();
+        }
+        if let Some(context) = matched {
+            let mut buf = Vec::with_capacity(4 + context.len());
+            buf.extend_from_slice(&NBD_META_CONTEXT_ID.to_be_bytes());
+            buf.extend_from_slice(context.as_bytes());
+            self.send_rep(opt, NBD_REP_META_CONTEXT, &buf)?;
+        }
+        self.send_rep(opt, NBD_REP_ACK, &[])
+    }
+
+    fn send_simple_reply(&mut self, handle: u64, err: u32, data: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(NBD_SIMPLE_REPLY_SIZE + data.len());
+        buf.extend_from_slice(&NBD_SIMPLE_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&err.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)?;
+        Ok(())
+    }
+
+    fn send_chunk(&mut self, handle: u64, reply_type: u16, payload: &[u8]) -> Result<()> {
+        let mut buf = Vec::with_capacity(NBD_STRUCTURED_REPLY_SIZE + payload.len());
+        buf.extend_from_slice(&NBD_STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&NBD_REPLY_FLAG_DONE.to_be_bytes());
+        buf.extend_from_slice(&reply_type.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        self.stream.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Reply the error, the structured reply is required for read and block
+    /// status if it's negotiated.
+    fn send_error(&mut self, header: &NbdRequestHeader, err: u32) -> Result<()> {
+        if self.structured && (header.cmd == NBD_CMD_READ || header.cmd == NBD_CMD_BLOCK_STATUS) {
+            let mut payload = Vec::with_capacity(6);
+            payload.extend_from_slice(&err.to_be_bytes());
+            payload.extend_from_slice(&0_u16.to_be_bytes());
+            return self.send_chunk(header.handle, NBD_REPLY_TYPE_ERROR, &payload);
+        }
+        self.send_simple_reply(header.handle, err, &[])
+    }
+
+    fn transmit(&mut self, export: &NbdExport) -> Result<()> {
+        let disk_size = export.ops.disk_size()?;
+        loop {
+            let mut buf = [0_u8; NBD_REQUEST_SIZE];
+            self.stream.read_exact(&mut buf)?;
+            let header = NbdRequestHeader::from_bytes(&buf)
+                .with_context(|| "Invalid magic of nbd request")?;
+            let len = u64::from(header.len);
+            if header.cmd == NBD_CMD_WRITE && len > NBD_MAX_PAYLOAD {
+                bail!("Too long data {} of nbd write request", len);
+            }
+            let in_range = matches!(header.offset.checked_add(len), Some(end) if end <= disk_size);
+
+            match header.cmd {
+                NBD_CMD_DISC => return Ok(()),
+                NBD_CMD_READ => {
+                    if !in_range || len > NBD_MAX_PAYLOAD {
+                        self.send_error(&header, NBD_EINVAL)?;
+                        continue;
+                    }
+                    let mut data = vec![0_u8; len as usize];
+                    if let Err(e) = export.ops.read(header.offset, &mut data) {
+                        error!("Failed to read nbd export {}: {:?}", export.name, e);
+                        self.send_error(&header, NBD_EIO)?;
+                        continue;
+                    }
+                    if self.structured {
+                        let mut payload = Vec::with_capacity(8 + data.len());
+                        payload.extend_from_slice(&header.offset.to_be_bytes());
+                        payload.extend_from_slice(&data);
+                        self.send_chunk(header.handle, NBD_REPLY_TYPE_OFFSET_DATA, &payload)?;
+                    } else {
+                        self.send_simple_reply(header.handle, 0, &data)?;
+                    }
+                }
+                NBD_CMD_WRITE => {
+                    let mut data = vec![0_u8; len as usize];
+                    self.stream.read_exact(&mut data)?;
+                    let err = if !export.writable {
+                        NBD_EPERM
+                    } else if !in_range {
+                        NBD_ENOSPC
+                    } else if let Err(e) = export.ops.write(header.offset, &data) {
+                        error!("Failed to write nbd export {}: {:?}", export.name, e);
+                        NBD_EIO
+                    } else {
+                        0
+                    };
+                    self.send_simple_reply(header.handle, err, &[])?;
+                }
+                NBD_CMD_FLUSH => {
+                    let err = match export.ops.flush() {
+                        Ok(()) => 0,
+                        Err(e) => {
+                            error!("Failed to flush nbd export {}: {:?}", export.name, e);
+                            NBD_EIO
+                        }
+                    };
+                    self.send_simple_reply(header.handle, err, &[])?;
+                }
+                NBD_CMD_BLOCK_STATUS => {
+                    if !self.structured || !self.meta_selected || !in_range || len == 0 {
+                        self.send_error(&header, NBD_EINVAL)?;
+                        continue;
+                    }
+                    let one = header.flags & NBD_CMD_FLAG_REQ_ONE != 0;
+                    let extents = export.dirty_extents(header.offset, len, one)?;
+                    let mut payload = Vec::with_capacity(4 + extents.len() * 8);
+                    payload.extend_from_slice(&NBD_META_CONTEXT_ID.to_be_bytes());
+                    for (length, flags) in extents {
+                        payload.extend_from_slice(&length.to_be_bytes());
+                        payload.extend_from_slice(&flags.to_be_bytes());
+                    }
+                    self.send_chunk(header.handle, NBD_REPLY_TYPE_BLOCK_STATUS, &payload)?;
+                }
+                _ => self.send_error(&header, NBD_EINVAL)?,
+            }
+        }
+    }
+}
+
+/// Start the NBD server listening at `addr`.
+///
+/// # Arguments
+///
+/// * `addr` - Address of the server.
+/// * `max_connections` - Max number of clients connected at the same time, 0
+///   means unlimited.
+pub fn nbd_server_start(addr: &NbdAddr, max_connections: u32) -> Result<()> {
+    let mut server = NBD_SERVER.lock().unwrap();
+    if server.is_some() {
+        bail!("Nbd server is already running");
+    }
+    let listener = Arc::new(NbdListener::bind(addr)?);
+    let fd = listener.as_raw_fd();
+    let handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+        if let Some(server) = NBD_SERVER.lock().unwrap().as_mut() {
+            server.accept();
+        }
+        None
+    });
+    let notifier = EventNotifier::new(
+        NotifierOperation::AddShared,
+        fd,
+        None,
+        EventSet::IN,
+        vec![handler],
+    );
+    EventLoop::update_event(vec![notifier], None)?;
+
+    *server = Some(NbdServer {
+        addr: addr.clone(),
+        listener,
+        max_connections,
+        exports: Arc::new(Mutex::new(HashMap::new())),
+        connections: Arc::new(Mutex::new(HashMap::new())),
+        next_conn_id: 0,
+    });
+    info!("Nbd server is listening at {}", addr);
+    Ok(())
+}
+
+/// Add the export to the running NBD server.
+pub fn nbd_server_add(export: NbdExport) -> Result<()> {
+    let server = NBD_SERVER.lock().unwrap();
+    let server = server
+        .as_ref()
+        .with_context(|| "Nbd server is not running")?;
+    let mut exports = server.exports.lock().unwrap();
+    if exports.contains_key(&export.name) {
+        bail!("Nbd export {} already exists", export.name);
+    }
+    exports.insert(export.name.clone(), Arc::new(export));
+    Ok(())
+}
+
+/// Remove the export, the clients using it are disconnected.
+pub fn nbd_server_remove(name: &str) -> Result<()> {
+    let server = NBD_SERVER.lock().unwrap();
+    let server = server
+        .as_ref()
+        .with_context(|| "Nbd server is not running")?;
+    server
+        .exports
+        .lock()
+        .unwrap()
+        .remove(name)
+        .ok_or_else(|| anyhow!("Nbd export {} not found", name))?;
+    server.disconnect(Some(name));
+    Ok(())
+}
+
+/// Remove the exports of device, e.g. it is unplugged.
+pub fn nbd_server_remove_device(device: &str) {
+    let server = NBD_SERVER.lock().unwrap();
+    if let Some(server) = server.as_ref() {
+        let mut removed = Vec::new();
+        server.exports.lock().unwrap().retain(|name, export| {
+            if export.device == device {
+                removed.push(name.clone());
+                return false;
+            }
+            true
+        });
+        for name in removed {
+            server.disconnect(Some(&name));
+        }
+    }
+}
+
+/// Stop the NBD server, all the exports are removed and the clients are
+/// disconnected.
+pub fn nbd_server_stop() -> Result<()> {
+    let server = NBD_SERVER
+        .lock()
+        .unwrap()
+        .take()
+        .with_context(|| "Nbd server is not running")?;
+    EventLoop::update_event(gen_delete_notifiers(&[server.listener.as_raw_fd()]), None)?;
+    server.exports.lock().unwrap().clear();
+    server.disconnect(None);
+    if let NbdAddr::Unix(path) = &server.addr {
+        let _ = std::fs::remove_file(path);
+    }
+    info!("Nbd server at {} is stopped", server.addr);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::os::unix::net::UnixListener;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    use super::*;
+    use crate::create_block_backend;
+    use crate::nbd::client::NbdDriver;
+    use crate::BlockProperty;
+    use machine_manager::config::{DiskFormat, NbdUri};
+    use util::aio::{Aio, AioCb, AioEngine, Iovec};
+
+    const TEST_DISK_SIZE: u64 = 1 << 20;
+
+    fn test_complete_func(aiocb: &AioCb<Arc<AtomicI64>>, ret: i64) -> Result<()> {
+        aiocb.iocompletecb.store(ret, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn prop(id: &str) -> BlockProperty {
+        BlockProperty {
+            id: id.to_string(),
+            format: DiskFormat::Raw,
+            iothread: None,
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            copy_on_read: false,
+        }
+    }
+
+    /// Serve the exports on the socket without the event loop.
+    fn start_test_server(path: &str, exports: NbdExports) {
+        let _ = remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = NbdStream::Unix(stream.unwrap());
+                let exports = exports.clone();
+                thread::spawn(move || {
+                    let mut session = NbdSession::new(stream, exports, Arc::new(Mutex::new(None)));
+                    let _ = session.run();
+                });
+            }
+        });
+    }
+
+    fn connect(path: &str, export: &str, read_only: bool) -> Result<NbdDriver<Arc<AtomicI64>>> {
+        let uri = NbdUri {
+            addr: NbdAddr::Unix(path.to_string()),
+            export: export.to_string(),
+        };
+        let aio = Aio::new(Arc::new(test_complete_func), AioEngine::Off)?;
+        NbdDriver::new(&uri, read_only, aio, prop("nbd0"))
+    }
+
+    #[test]
+    fn test_nbd_server_export() {
+        let image = "/tmp/test_nbd_server_export.img";
+        let path = "/tmp/test_nbd_server_export.sock";
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image)
+            .unwrap();
+        file.set_len(TEST_DISK_SIZE).unwrap();
+        let aio = Aio::new(Arc::new(|_: &AioCb<()>, _| Ok(())), AioEngine::Off).unwrap();
+        let backend = create_block_backend(file, aio, prop("drive0")).unwrap();
+        let bitmap = Arc::new(Mutex::new(
+            DirtyBitmap::new(TEST_DISK_SIZE, 4096)
+                .unwrap()
+                .with_name("bitmap0", false),
+        ));
+        backend
+            .lock()
+            .unwrap()
+            .add_dirty_bitmap(bitmap.clone())
+            .unwrap();
+
+        let exports: NbdExports = Arc::new(Mutex::new(HashMap::new()));
+        exports.lock().unwrap().insert(
+            "rw".to_string(),
+            Arc::new(NbdExport::new(
+                "rw",
+                "blk0",
+                backend.clone(),
+                true,
+                Some(("bitmap0".to_string(), bitmap.clone())),
+            )),
+        );
+        exports.lock().unwrap().insert(
+            "ro".to_string(),
+            Arc::new(NbdExport::new("ro", "blk0", backend.clone(), false, None)),
+        );
+        start_test_server(path, exports.clone());
+
+        // Unknown export and writing the read-only export are refused.
+        assert!(connect(path, "none", true).is_err());
+        assert!(connect(path, "ro", false).is_err());
+
+        let mut driver = connect(path, "rw", false).unwrap();
+        assert_eq!(driver.disk_size().unwrap(), TEST_DISK_SIZE);
+        let ret = Arc::new(AtomicI64::new(1));
+        let mut wbuf = vec![0x3c_u8; 8192];
+        let iovec = vec![Iovec::new(wbuf.as_mut_ptr() as u64, wbuf.len() as u64)];
+        driver.write_vectored(iovec, 8192, ret.clone()).unwrap();
+        assert_eq!(ret.load(Ordering::SeqCst), 0);
+        driver.flush_sync().unwrap();
+
+        // The data is written to the backend of device, and the area is dirty.
+        let mut buf = vec![0_u8; 8192];
+        backend.lock().unwrap().read_sync(8192, &mut buf).unwrap();
+        assert_eq!(buf, wbuf);
+        assert_eq!(
+            bitmap
+                .lock()
+                .unwrap()
+                .next_dirty_area(0, TEST_DISK_SIZE)
+                .unwrap(),
+            Some((8192, 8192))
+        );
+        let export = exports.lock().unwrap().get("rw").cloned().unwrap();
+        assert_eq!(
+            export.dirty_extents(0, 65536, false).unwrap(),
+            vec![(8192, 0), (8192, NBD_STATE_DIRTY), (49152, 0)]
+        );
+        assert_eq!(
+            export.dirty_extents(12288, 65536, true).unwrap(),
+            vec![(4096, NBD_STATE_DIRTY)]
+        );
+
+        let mut reader = connect(path, "ro", true).unwrap();
+        let mut buf = vec![0_u8; 16384];
+        reader.read_sync(4096, &mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|b| *b == 0));
+        assert_eq!(buf[4096..12288], wbuf[..]);
+        assert!(buf[12288..].iter().all(|b| *b == 0));
+        // Reading out of disk fails.
+        assert!(reader.read_sync(TEST_DISK_SIZE, &mut buf).is_err());
+
+        drop(driver);
+        drop(reader);
+        let _ = remove_file(path);
+        let _ = remove_file(image);
+    }
+
+    /// Create the raw image of `TEST_DISK_SIZE` and its backend.
+    fn test_backend(image: &str) -> Arc<Mutex<dyn BlockDriverOps<()>>> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image)
+            .unwrap();
+        file.set_len(TEST_DISK_SIZE).unwrap();
+        let aio = Aio::new(Arc::new(|_: &AioCb<()>, _| Ok(())), AioEngine::Off).unwrap();
+        create_block_backend(file, aio, prop("drive0")).unwrap()
+    }
+
+    /// Get the exports `rw` and `ro` of the backend.
+    fn test_exports(backend: &Arc<Mutex<dyn BlockDriverOps<()>>>) -> NbdExports {
+        let exports: NbdExports = Arc::new(Mutex::new(HashMap::new()));
+        for (name, writable) in [("rw", true), ("ro", false)] {
+            exports.lock().unwrap().insert(
+                name.to_string(),
+                Arc::new(NbdExport::new(
+                    name,
+                    "blk0",
+                    backend.clone(),
+                    writable,
+                    None,
+                )),
+            );
+        }
+        exports
+    }
+
+    /// Finish the handshake of fixed newstyle negotiation before the options.
+    fn handshake(stream: &mut NbdStream) {
+        assert_eq!(stream.read_u64().unwrap(), NBD_MAGIC);
+        assert_eq!(stream.read_u64().unwrap(), NBD_OPTS_MAGIC);
+        assert_eq!(
+            stream.read_u16().unwrap(),
+            NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES
+        );
+        let flags = NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES;
+        stream.write_all(&flags.to_be_bytes()).unwrap();
+    }
+
+    fn raw_connect(path: &str) -> NbdStream {
+        let mut stream = NbdStream::connect(&NbdAddr::Unix(path.to_string())).unwrap();
+        handshake(&mut stream);
+        stream
+    }
+
+    fn send_opt(stream: &mut NbdStream, opt: u32, data: &[u8]) {
+        let mut buf = Vec::with_capacity(NBD_OPT_HEADER_SIZE + data.len());
+        buf.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&opt.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).unwrap();
+    }
+
+    /// Receive the reply of option `opt`, returns the reply type and data.
+    fn recv_rep(stream: &mut NbdStream, opt: u32) -> (u32, Vec<u8>) {
+        assert_eq!(stream.read_u64().unwrap(), NBD_REP_MAGIC);
+        assert_eq!(stream.read_u32().unwrap(), opt);
+        let reply = stream.read_u32().unwrap();
+        let mut data = vec![0_u8; stream.read_u32().unwrap() as usize];
+        stream.read_exact(&mut data).unwrap();
+        (reply, data)
+    }
+
+    /// Open the export with NBD_OPT_GO, returns the transmission flags.
+    fn go(stream: &mut NbdStream, name: &str) -> Result<u16, u32> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&0_u16.to_be_bytes());
+        send_opt(stream, NBD_OPT_GO, &data);
+        let (reply, data) = recv_rep(stream, NBD_OPT_GO);
+        if reply != NBD_REP_INFO {
+            return Err(reply);
+        }
+        assert_eq!(BigEndian::read_u16(&data[0..2]), NBD_INFO_EXPORT);
+        assert_eq!(BigEndian::read_u64(&data[2..10]), TEST_DISK_SIZE);
+        assert_eq!(recv_rep(stream, NBD_OPT_GO), (NBD_REP_ACK, vec![]));
+        Ok(BigEndian::read_u16(&data[10..12]))
+    }
+
+    /// Send the request and receive its simple reply, returns the error.
+    fn request(stream: &mut NbdStream, cmd: u16, offset: u64, data: &mut [u8]) -> Result<u32> {
+        let header = NbdRequestHeader {
+            flags: 0,
+            cmd,
+            handle: 0x1234,
+            offset,
+            len: data.len() as u32,
+        };
+        stream.write_all(&header.to_bytes())?;
+        if cmd == NBD_CMD_WRITE {
+            stream.write_all(data)?;
+        }
+        if stream.read_u32()? != NBD_SIMPLE_REPLY_MAGIC {
+            bail!("Invalid magic of nbd reply");
+        }
+        let err = stream.read_u32()?;
+        assert_eq!(stream.read_u64()?, 0x1234);
+        if cmd == NBD_CMD_READ && err == 0 {
+            stream.read_exact(data)?;
+        }
+        Ok(err)
+    }
+
+    #[test]
+    fn test_nbd_server_negotiate() {
+        let image = "/tmp/test_nbd_server_negotiate.img";
+        let path = "/tmp/test_nbd_server_negotiate.sock";
+        let backend = test_backend(image);
+        start_test_server(path, test_exports(&backend));
+
+        // The exports are listed in order of name.
+        let mut stream = raw_connect(path);
+        send_opt(&mut stream, NBD_OPT_LIST, &[]);
+        for name in ["ro", "rw"] {
+            let (reply, data) = recv_rep(&mut stream, NBD_OPT_LIST);
+            assert_eq!(reply, NBD_REP_SERVER);
+            assert_eq!(BigEndian::read_u32(&data[0..4]) as usize, name.len());
+            assert_eq!(&data[4..], name.as_bytes());
+        }
+        assert_eq!(recv_rep(&mut stream, NBD_OPT_LIST), (NBD_REP_ACK, vec![]));
+        send_opt(&mut stream, NBD_OPT_LIST, b"rw");
+        assert_eq!(recv_rep(&mut stream, NBD_OPT_LIST).0, NBD_REP_ERR_INVALID);
+
+        // The unknown option and export are refused, and the negotiation goes on.
+        send_opt(&mut stream, 100, &[]);
+        assert_eq!(recv_rep(&mut stream, 100).0, NBD_REP_ERR_UNSUP);
+        assert_eq!(go(&mut stream, "none"), Err(NBD_REP_ERR_UNKNOWN));
+
+        // The export is opened by NBD_OPT_GO and the transmission starts.
+        let flags = go(&mut stream, "rw").unwrap();
+        assert_eq!(flags & NBD_FLAG_READ_ONLY, 0);
+        assert_ne!(flags & NBD_FLAG_SEND_FLUSH, 0);
+        let mut buf = vec![0xff_u8; 512];
+        assert_eq!(request(&mut stream, NBD_CMD_READ, 0, &mut buf).unwrap(), 0);
+        assert!(buf.iter().all(|b| *b == 0));
+        let mut buf = vec![0_u8; 512];
+        assert_eq!(
+            request(&mut stream, NBD_CMD_READ, TEST_DISK_SIZE, &mut buf).unwrap(),
+            NBD_EINVAL
+        );
+
+        // The server closes the connection after NBD_OPT_ABORT is acked.
+        let mut stream = raw_connect(path);
+        send_opt(&mut stream, NBD_OPT_ABORT, &[]);
+        assert_eq!(recv_rep(&mut stream, NBD_OPT_ABORT), (NBD_REP_ACK, vec![]));
+        assert!(stream.read_u32().is_err());
+
+        // The client without fixed newstyle is disconnected.
+        let mut stream = NbdStream::connect(&NbdAddr::Unix(path.to_string())).unwrap();
+        let mut greeting = [0_u8; 18];
+        stream.read_exact(&mut greeting).unwrap();
+        stream.write_all(&0_u32.to_be_bytes()).unwrap();
+        assert!(stream.read_u32().is_err());
+
+        let _ = remove_file(path);
+        let _ = remove_file(image);
+    }
+
+    #[test]
+    fn test_nbd_server_read_only() {
+        let image = "/tmp/test_nbd_server_read_only.img";
+        let path = "/tmp/test_nbd_server_read_only.sock";
+        let backend = test_backend(image);
+        start_test_server(path, test_exports(&backend));
+
+        let mut stream = raw_connect(path);
+        let flags = go(&mut stream, "ro").unwrap();
+        assert_ne!(flags & NBD_FLAG_READ_ONLY, 0);
+
+        // The write is refused with EPERM and the disk is not changed, while the
+        // connection can still be used.
+        let mut wbuf = vec![0x5a_u8; 4096];
+        assert_eq!(
+            request(&mut stream, NBD_CMD_WRITE, 0, &mut wbuf).unwrap(),
+            NBD_EPERM
+        );
+        let mut buf = vec![0xff_u8; 4096];
+        backend.lock().unwrap().read_sync(0, &mut buf).unwrap();
+        assert!(buf.iter().all(|b| *b == 0));
+        let mut buf = vec![0xff_u8; 4096];
+        assert_eq!(request(&mut stream, NBD_CMD_READ, 0, &mut buf).unwrap(), 0);
+        assert!(buf.iter().all(|b| *b == 0));
+        assert_eq!(request(&mut stream, NBD_CMD_FLUSH, 0, &mut []).unwrap(), 0);
+
+        // The writable export of the same device accepts the write.
+        let mut stream = raw_connect(path);
+        go(&mut stream, "rw").unwrap();
+        assert_eq!(
+            request(&mut stream, NBD_CMD_WRITE, 0, &mut wbuf).unwrap(),
+            0
+        );
+        backend.lock().unwrap().read_sync(0, &mut buf).unwrap();
+        assert_eq!(buf, wbuf);
+
+        let _ = remove_file(path);
+        let _ = remove_file(image);
+    }
+
+    #[test]
+    fn test_nbd_server_remove_export() {
+        let image = "/tmp/test_nbd_server_remove_export.img";
+        let path = "/tmp/test_nbd_server_remove_export.sock";
+        let backend = test_backend(image);
+        let _ = remove_file(path);
+        let addr = NbdAddr::Unix(path.to_string());
+        let connections: NbdConnections = Arc::new(Mutex::new(HashMap::new()));
+        // The server is installed without the event loop, clients are accepted manually.
+        *NBD_SERVER.lock().unwrap() = Some(NbdServer {
+            addr: addr.clone(),
+            listener: Arc::new(NbdListener::bind(&addr).unwrap()),
+            max_connections: 0,
+            exports: test_exports(&backend),
+            connections: connections.clone(),
+            next_conn_id: 0,
+        });
+        let connect = |name: &str| {
+            let mut stream = NbdStream::connect(&addr).unwrap();
+            NBD_SERVER.lock().unwrap().as_mut().unwrap().accept();
+            handshake(&mut stream);
+            let res = go(&mut stream, name);
+            // The export is recorded in the connection once the transmission starts.
+            if res.is_ok() {
+                assert_eq!(request(&mut stream, NBD_CMD_FLUSH, 0, &mut []).unwrap(), 0);
+            }
+            (stream, res)
+        };
+        let (mut rw_stream, res) = connect("rw");
+        assert!(res.is_ok());
+        let (mut ro_stream, res) = connect("ro");
+        assert!(res.is_ok());
+        assert_eq!(connections.lock().unwrap().len(), 2);
+
+        // The clients of the removed export are disconnected, the others are not.
+        nbd_server_remove("rw").unwrap();
+        assert!(request(&mut rw_stream, NBD_CMD_FLUSH, 0, &mut []).is_err());
+        assert_eq!(
+            request(&mut ro_stream, NBD_CMD_FLUSH, 0, &mut []).unwrap(),
+            0
+        );
+        let mut wait = 0;
+        while connections.lock().unwrap().len() != 1 {
+            wait += 1;
+            assert!(wait < 100);
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        // The removed export can not be opened or removed again.
+        let (_stream, res) = connect("rw");
+        assert_eq!(res, Err(NBD_REP_ERR_UNKNOWN));
+        assert!(nbd_server_remove("rw").is_err());
+
+        // The clients of the device are disconnected when its exports are removed.
+        nbd_server_remove_device("blk0");
+        assert!(request(&mut ro_stream, NBD_CMD_FLUSH, 0, &mut []).is_err());
+
+        let server = NBD_SERVER.lock().unwrap().take().unwrap();
+        server.disconnect(None);
+        let _ = remove_file(path);
+        let _ = remove_file(image);
+    }
+}
@@ -0,0 +1,95 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Cache of metadata tables, the least recently used table is evicted when full.
+/// Tables are written through to the image, so eviction needs no write back.
+pub struct Qcow2Cache {
+    /// Max number of tables in cache.
+    max_size: usize,
+    /// Tables indexed by their offset in image file.
+    tables: HashMap<u64, Vec<u64>>,
+    /// Offsets of tables, the most recently used one is at the back.
+    lru: VecDeque<u64>,
+}
+
+impl Qcow2Cache {
+    pub fn new(max_size: usize) -> Self {
+        Qcow2Cache {
+            max_size,
+            tables: HashMap::new(),
+            lru: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, offset: u64) {
+        if let Some(pos) = self.lru.iter().position(|&o| o == offset) {
+            self.lru.remove(pos);
+        }
+        self.lru.push_back(offset);
+    }
+
+    pub fn get(&mut self, offset: u64) -> Option<&mut Vec<u64>> {
+        if !self.tables.contains_key(&offset) {
+            return None;
+        }
+        self.touch(offset);
+        self.tables.get_mut(&offset)
+    }
+
+    pub fn insert(&mut self, offset: u64, table: Vec<u64>) {
+        if !self.tables.contains_key(&offset) && self.tables.len() >= self.max_size {
+            if let Some(evicted) = self.lru.pop_front() {
+                self.tables.remove(&evicted);
+            }
+        }
+        self.tables.insert(offset, table);
+        self.touch(offset);
+    }
+
+    pub fn remove(&mut self, offset: u64) {
+        if self.tables.remove(&offset).is_some() {
+            self.lru.retain(|&o| o != offset);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tables.clear();
+        self.lru.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_lru() {
+        let mut cache = Qcow2Cache::new(2);
+        cache.insert(0x1000, vec![1]);
+        cache.insert(0x2000, vec![2]);
+        // Touch 0x1000, so 0x2000 is evicted by the next insertion.
+        assert_eq!(cache.get(0x1000).unwrap()[0], 1);
+        cache.insert(0x3000, vec![3]);
+        assert!(cache.get(0x2000).is_none());
+        assert_eq!(cache.get(0x1000).unwrap()[0], 1);
+        assert_eq!(cache.get(0x3000).unwrap()[0], 3);
+
+        cache.get(0x3000).unwrap()[0] = 4;
+        assert_eq!(cache.get(0x3000).unwrap()[0], 4);
+        cache.remove(0x3000);
+        assert!(cache.get(0x3000).is_none());
+        cache.clear();
+        assert!(cache.get(0x1000).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+pub const QCOW_MAGIC: u32 = 0x5146_49fb;
+pub const QCOW_VERSION_2_MIN_LEN: usize = 72;
+pub const QCOW_VERSION_3_MIN_LEN: usize = 104;
+const MIN_CLUSTER_BIT: u32 = 9;
+const MAX_CLUSTER_BIT: u32 = 21;
+/// The image was not closed correctly, refcounts may be stale.
+pub const QCOW2_INCOMPAT_DIRTY: u64 = 1 << 0;
+/// The image is corrupt, it should not be written any more.
+pub const QCOW2_INCOMPAT_CORRUPT: u64 = 1 << 1;
+
+/// Header of qcow2 image, stored in big endian at the start of image file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QcowHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub backing_file_offset: u64,
+    pub backing_file_size: u32,
+    pub cluster_bits: u32,
+    pub size: u64,
+    pub crypt_method: u32,
+    pub l1_size: u32,
+    pub l1_table_offset: u64,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    pub nb_snapshots: u32,
+    pub snapshots_offset: u64,
+    // Fields below are valid for version 3.
+    pub incompatible_features: u64,
+    pub compatible_features: u64,
+    pub autoclear_features: u64,
+    pub refcount_order: u32,
+    pub header_length: u32,
+}
+
+impl QcowHeader {
+    pub fn from_vec(buf: &[u8]) -> Result<QcowHeader> {
+        if buf.len() < QCOW_VERSION_2_MIN_LEN {
+            bail!(
+                "Invalid header len {}, the min len {}",
+                buf.len(),
+                QCOW_VERSION_2_MIN_LEN
+            );
+        }
+        let mut header = QcowHeader {
+            magic: BigEndian::read_u32(&buf[0..4]),
+            version: BigEndian::read_u32(&buf[4..8]),
+            backing_file_offset: BigEndian::read_u64(&buf[8..16]),
+            backing_file_size: BigEndian::read_u32(&buf[16..20]),
+            cluster_bits: BigEndian::read_u32(&buf[20..24]),
+            size: BigEndian::read_u64(&buf[24..32]),
+            crypt_method: BigEndian::read_u32(&buf[32..36]),
+            l1_size: BigEndian::read_u32(&buf[36..40]),
+            l1_table_offset: BigEndian::read_u64(&buf[40..48]),
+            refcount_table_offset: BigEndian::read_u64(&buf[48..56]),
+            refcount_table_clusters: BigEndian::read_u32(&buf[56..60]),
+            nb_snapshots: BigEndian::read_u32(&buf[60..64]),
+            snapshots_offset: BigEndian::read_u64(&buf[64..72]),
+            // Default value of version 2.
+            incompatible_features: 0,
+            compatible_features: 0,
+            autoclear_features: 0,
+            refcount_order: 4,
+            header_length: QCOW_VERSION_2_MIN_LEN as u32,
+        };
+        if header.magic != QCOW_MAGIC {
+            bail!("Invalid format {:#x}", header.magic);
+        }
+        if header.version == 3 {
+            if buf.len() < QCOW_VERSION_3_MIN_LEN {
+                bail!(
+                    "Invalid header len {} for version 3, the min len {}",
+                    buf.len(),
+                    QCOW_VERSION_3_MIN_LEN
+                );
+            }
+            header.incompatible_features = BigEndian::read_u64(&buf[72..80]);
+            header.compatible_features = BigEndian::read_u64(&buf[80..88]);
+            header.autoclear_features = BigEndian::read_u64(&buf[88..96]);
+            header.refcount_order = BigEndian::read_u32(&buf[96..100]);
+            header.header_length = BigEndian::read_u32(&buf[100..104]);
+        }
+        header.check()?;
+        Ok(header)
+    }
+
+    /// Serialize the header fields, the length is 72 bytes for version 2
+    /// and 104 bytes for version 3.
+    pub fn to_vec(&self) -> Vec<u8> {
+        let len = if self.version == 2 {
+            QCOW_VERSION_2_MIN_LEN
+        } else {
+            QCOW_VERSION_3_MIN_LEN
+        };
+        let mut buf = vec![0; len];
+        BigEndian::write_u32(&mut buf[0..4], self.magic);
+        BigEndian::write_u32(&mut buf[4..8], self.version);
+        BigEndian::write_u64(&mut buf[8..16], self.backing_file_offset);
+        BigEndian::write_u32(&mut buf[16..20], self.backing_file_size);
+        BigEndian::write_u32(&mut buf[20..24], self.cluster_bits);
+        BigEndian::write_u64(&mut buf[24..32], self.size);
+        BigEndian::write_u32(&mut buf[32..36], self.crypt_method);
+        BigEndian::write_u32(&mut buf[36..40], self.l1_size);
+        BigEndian::write_u64(&mut buf[40..48], self.l1_table_offset);
+        BigEndian::write_u64(&mut buf[48..56], self.refcount_table_offset);
+        BigEndian::write_u32(&mut buf[56..60], self.refcount_table_clusters);
+        BigEndian::write_u32(&mut buf[60..64], self.nb_snapshots);
+        BigEndian::write_u64(&mut buf[64..72], self.snapshots_offset);
+        if self.version == 3 {
+            BigEndian::write_u64(&mut buf[72..80], self.incompatible_features);
+            BigEndian::write_u64(&mut buf[80..88], self.compatible_features);
+            BigEndian::write_u64(&mut buf[88..96], self.autoclear_features);
+            BigEndian::write_u32(&mut buf[96..100], self.refcount_order);
+            BigEndian::write_u32(&mut buf[100..104], self.header_length);
+        }
+        buf
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.version != 2 && self.version != 3 {
+            bail!("Invalid version {}", self.version);
+        }
+        if self.cluster_bits < MIN_CLUSTER_BIT || self.cluster_bits > MAX_CLUSTER_BIT {
+            bail!("Invalid cluster bits {}", self.cluster_bits);
+        }
+        if self.header_length as u64 > self.cluster_size() {
+            bail!(
+                "Header length {} over cluster size {}",
+                self.header_length,
+                self.cluster_size()
+            );
+        }
+        if self.crypt_method != 0 {
+            bail!("Encrypted image is not supported");
+        }
+        // Refcount of 8/16/32/64 bits is supported.
+        if self.refcount_order < 3 || self.refcount_order > 6 {
+            bail!("Refcount order {} is not supported", self.refcount_order);
+        }
+        if self.incompatible_features & QCOW2_INCOMPAT_CORRUPT != 0 {
+            bail!("Image is marked corrupt, repair it first");
+        }
+        if self.incompatible_features & QCOW2_INCOMPAT_DIRTY != 0 {
+            bail!("Image is dirty, its refcounts need to be repaired first");
+        }
+        if self.incompatible_features & !(QCOW2_INCOMPAT_DIRTY | QCOW2_INCOMPAT_CORRUPT) != 0 {
+            bail!(
+                "Unsupported incompatible features {:#x}",
+                self.incompatible_features
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_convert() {
+        let header = QcowHeader {
+            magic: QCOW_MAGIC,
+            version: 3,
+            cluster_bits: 16,
+            size: 1 << 30,
+            l1_size: 2,
+            l1_table_offset: 0x30000,
+            refcount_table_offset: 0x10000,
+            refcount_table_clusters: 1,
+            refcount_order: 4,
+            header_length: QCOW_VERSION_3_MIN_LEN as u32,
+            ..Default::default()
+        };
+        let buf = header.to_vec();
+        assert_eq!(buf.len(), QCOW_VERSION_3_MIN_LEN);
+        assert_eq!(QcowHeader::from_vec(&buf).unwrap(), header);
+
+        // Invalid magic.
+        let mut invalid = buf.clone();
+        invalid[0] = 0;
+        assert!(QcowHeader::from_vec(&invalid).is_err());
+        // Encrypted image.
+        let mut invalid = header.clone();
+        invalid.crypt_method = 1;
+        assert!(QcowHeader::from_vec(&invalid.to_vec()).is_err());
+        // Unknown incompatible features.
+        let mut invalid = header.clone();
+        invalid.incompatible_features = 1 << 4;
+        assert!(QcowHeader::from_vec(&invalid.to_vec()).is_err());
+        // Too short for version 3.
+        assert!(QcowHeader::from_vec(&buf[..QCOW_VERSION_2_MIN_LEN]).is_err());
+    }
+}
@@ -0,0 +1,1518 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+pub mod cache;
+pub mod header;
+pub mod refcount;
+pub mod snapshot;
+pub mod table;
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+use self::header::{QcowHeader, QCOW_MAGIC, QCOW_VERSION_2_MIN_LEN, QCOW_VERSION_3_MIN_LEN};
+use self::refcount::RefCount;
+use self::snapshot::{InternalSnapshot, QcowSnapshot, QCOW2_MAX_SNAPSHOTS};
+use self::table::Qcow2Table;
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::file::{CombineRequest, FileDriver};
+use crate::{BlockDriverOps, BlockIoErrorCallback, BlockProperty, SnapshotInfo};
+use machine_manager::config::DiskFormat;
+use util::aio::{
+    get_iov_size, iovec_write_zero, iovecs_split, Aio, AioCb, AioEngine, Iovec, OpCode,
+};
+use util::file::open_file;
+use util::num_ops::round_up;
+
+/// Size of the entry of L1/L2/refcount table.
+pub const ENTRY_SIZE: u64 = 8;
+/// The cluster is used only once, it can be written in place.
+pub const QCOW2_OFLAG_COPIED: u64 = 1 << 63;
+/// The cluster is compressed.
+pub const QCOW2_OFLAG_COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as all zeros, valid for version 3.
+pub const QCOW2_OFLAG_ZERO: u64 = 1 << 0;
+pub const L1_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+pub const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+pub const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+/// Header extension of the format of backing file.
+const QCOW2_EXT_MAGIC_BACKING_FORMAT: u32 = 0xE279_2ACA;
+const QCOW2_EXT_MAGIC_END: u32 = 0;
+/// Max length of the backing file name.
+const MAX_BACKING_FILE_NAME_LEN: u32 = 1023;
+/// Max depth of the backing chain.
+const MAX_BACKING_CHAIN_DEPTH: u32 = 16;
+const DEFAULT_CLUSTER_SIZE: u64 = 1 << 16;
+const DEFAULT_REFCOUNT_ORDER: u32 = 4;
+/// Offsets of the fields in header.
+const HEADER_BACKING_FILE_OFFSET: u64 = 8;
+const HEADER_SIZE_OFFSET: u64 = 24;
+const HEADER_L1_SIZE_OFFSET: u64 = 36;
+
+/// Synchronous access to the image file, used for the metadata.
+pub struct SyncAioInfo {
+    aio: Aio<()>,
+    fd: RawFd,
+    prop: BlockProperty,
+}
+
+impl SyncAioInfo {
+    fn complete_func(_aio: &AioCb<()>, ret: i64) -> Result<()> {
+        if ret < 0 {
+            bail!("Failed to complete sync io, ret {}", ret);
+        }
+        Ok(())
+    }
+
+    pub fn new(fd: RawFd, prop: BlockProperty) -> Result<Self> {
+        Ok(Self {
+            aio: Aio::new(Arc::new(SyncAioInfo::complete_func), AioEngine::Off)?,
+            fd,
+            prop,
+        })
+    }
+
+    fn rw_sync(&mut self, fd: RawFd, opcode: OpCode, offset: u64, buf: &[u8]) -> Result<()> {
+        let aiocb = AioCb {
+            direct: self.prop.direct,
+            req_align: self.prop.req_align,
+            buf_align: self.prop.buf_align,
+            file_fd: fd,
+            opcode,
+            iovec: vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)],
+            offset: offset as usize,
+            nbytes: buf.len() as u64,
+            user_data: 0,
+            iocompletecb: (),
+            combine_req: None,
+        };
+        self.aio.submit_request(aiocb)
+    }
+
+    /// Read data of file `fd` at `offset` to `buf`.
+    pub fn read_fd(&mut self, fd: RawFd, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.rw_sync(fd, OpCode::Preadv, offset, buf)
+            .with_context(|| format!("Failed to read {} bytes at {:#x}", buf.len(), offset))
+    }
+
+    pub fn read_buffer(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.read_fd(self.fd, offset, buf)
+    }
+
+    pub fn write_buffer(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.rw_sync(self.fd, OpCode::Pwritev, offset, buf)
+            .with_context(|| format!("Failed to write {} bytes at {:#x}", buf.len(), offset))
+    }
+
+    /// Read `nb_entries` big endian entries of metadata table.
+    pub fn read_ctrl_cluster(&mut self, offset: u64, nb_entries: u64) -> Result<Vec<u64>> {
+        let mut buf = vec![0_u8; (nb_entries * ENTRY_SIZE) as usize];
+        self.read_buffer(offset, &mut buf)?;
+        Ok(buf
+            .chunks(ENTRY_SIZE as usize)
+            .map(BigEndian::read_u64)
+            .collect())
+    }
+
+    pub fn write_ctrl_cluster(&mut self, offset: u64, table: &[u64]) -> Result<()> {
+        let mut buf = vec![0_u8; table.len() * ENTRY_SIZE as usize];
+        BigEndian::write_u64_into(table, &mut buf);
+        self.write_buffer(offset, &buf)
+    }
+}
+
+/// Location of a piece of the data in virtual disk.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HostRange {
+    /// The data is in file `fd` at `offset`.
+    DataOnFile { fd: RawFd, offset: u64, nbytes: u64 },
+    /// The data reads as zeros.
+    Zero(u64),
+}
+
+impl HostRange {
+    fn len(&self) -> u64 {
+        match self {
+            HostRange::DataOnFile { nbytes, .. } => *nbytes,
+            HostRange::Zero(len) => *len,
+        }
+    }
+}
+
+/// Append the range, merge it to the last one if they are contiguous.
+fn push_host_range(ranges: &mut Vec<HostRange>, range: HostRange) {
+    match (ranges.last_mut(), &range) {
+        (Some(HostRange::Zero(last)), HostRange::Zero(len)) => *last += len,
+        (
+            Some(HostRange::DataOnFile {
+                fd: last_fd,
+                offset: last_offset,
+                nbytes: last_nbytes,
+            }),
+            HostRange::DataOnFile { fd, offset, nbytes },
+        ) if last_fd == fd && *last_offset + *last_nbytes == *offset => *last_nbytes += nbytes,
+        _ => ranges.push(range),
+    }
+}
+
+/// The image which provides the unallocated data of qcow2 image.
+enum BackingImage {
+    Raw {
+        file: File,
+        size: u64,
+        sync_aio: SyncAioInfo,
+    },
+    Qcow2 {
+        // Keep the file opened while the image is in use.
+        _file: File,
+        image: Box<Qcow2Image>,
+    },
+}
+
+impl BackingImage {
+    fn open(
+        path: &str,
+        format: Option<DiskFormat>,
+        prop: &BlockProperty,
+        depth: u32,
+    ) -> Result<Self> {
+        if depth >= MAX_BACKING_CHAIN_DEPTH {
+            bail!(
+                "Backing chain is too deep, the max depth {}",
+                MAX_BACKING_CHAIN_DEPTH
+            );
+        }
+        let mut file = open_file(path, true, prop.direct)?;
+        let format = match format {
+            Some(format) => format,
+            None => probe_format(&file, prop)?,
+        };
+        match format {
+            DiskFormat::Raw => {
+                let size = std::io::Seek::seek(&mut file, std::io::SeekFrom::End(0))
+                    .with_context(|| format!("Failed to get size of backing file {}", path))?;
+                let sync_aio = SyncAioInfo::new(file.as_raw_fd(), prop.clone())?;
+                Ok(BackingImage::Raw {
+                    file,
+                    size,
+                    sync_aio,
+                })
+            }
+            DiskFormat::Qcow2 => {
+                let image = Qcow2Image::open(&file, prop, depth + 1)
+                    .with_context(|| format!("Failed to open backing file {}", path))?;
+                Ok(BackingImage::Qcow2 {
+                    _file: file,
+                    image: Box::new(image),
+                })
+            }
+        }
+    }
+
+    fn host_ranges(&mut self, offset: u64, len: u64, ranges: &mut Vec<HostRange>) -> Result<()> {
+        match self {
+            BackingImage::Raw { file, size, .. } => {
+                let data_len = size.saturating_sub(offset).min(len);
+                if data_len != 0 {
+                    push_host_range(
+                        ranges,
+                        HostRange::DataOnFile {
+                            fd: file.as_raw_fd(),
+                            offset,
+                            nbytes: data_len,
+                        },
+                    );
+                }
+                if len > data_len {
+                    push_host_range(ranges, HostRange::Zero(len - data_len));
+                }
+                Ok(())
+            }
+            BackingImage::Qcow2 { image, .. } => {
+                let data_len = image.header.size.saturating_sub(offset).min(len);
+                if data_len != 0 {
+                    image.get_host_ranges(offset, data_len, ranges)?;
+                }
+                if len > data_len {
+                    push_host_range(ranges, HostRange::Zero(len - data_len));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn format(&self) -> DiskFormat {
+        match self {
+            BackingImage::Raw { .. } => DiskFormat::Raw,
+            BackingImage::Qcow2 { .. } => DiskFormat::Qcow2,
+        }
+    }
+
+    fn read_sync(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        match self {
+            BackingImage::Raw { size, sync_aio, .. } => {
+                buf.fill(0);
+                let data_len = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+                if data_len != 0 {
+                    sync_aio.read_buffer(offset, &mut buf[..data_len])?;
+                }
+                Ok(())
+            }
+            BackingImage::Qcow2 { image, .. } => {
+                buf.fill(0);
+                let data_len = image
+                    .header
+                    .size
+                    .saturating_sub(offset)
+                    .min(buf.len() as u64) as usize;
+                if data_len != 0 {
+                    image.read_sync(offset, &mut buf[..data_len])?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Probe the format of image by its magic.
+fn probe_format(file: &File, prop: &BlockProperty) -> Result<DiskFormat> {
+    let mut sync_aio = SyncAioInfo::new(file.as_raw_fd(), prop.clone())?;
+    let mut buf = vec![0_u8; 4];
+    if sync_aio.read_buffer(0, &mut buf).is_ok() && BigEndian::read_u32(&buf) == QCOW_MAGIC {
+        return Ok(DiskFormat::Qcow2);
+    }
+    Ok(DiskFormat::Raw)
+}
+
+/// Get the path of the opened file.
+fn file_path(file: &File) -> Result<PathBuf> {
+    std::fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))
+        .with_context(|| "Failed to get the path of image file")
+}
+
+/// Metadata of qcow2 image, which maps the virtual disk to the image file.
+pub struct Qcow2Image {
+    sync_aio: Rc<RefCell<SyncAioInfo>>,
+    /// The fd of image file.
+    fd: RawFd,
+    pub header: QcowHeader,
+    pub table: Qcow2Table,
+    pub refcount: RefCount,
+    pub snapshot: InternalSnapshot,
+    /// The backing file and its format recorded in the header.
+    pub backing_file: Option<(String, Option<DiskFormat>)>,
+    /// Path of the opened backing file.
+    backing_path: Option<String>,
+    backing: Option<BackingImage>,
+}
+
+impl Qcow2Image {
+    /// Load the metadata of qcow2 image and open its backing file.
+    pub fn open(file: &File, prop: &BlockProperty, depth: u32) -> Result<Self> {
+        let fd = file.as_raw_fd();
+        let sync_aio = Rc::new(RefCell::new(SyncAioInfo::new(fd, prop.clone())?));
+        let mut buf = vec![0_u8; QCOW_VERSION_3_MIN_LEN];
+        sync_aio
+            .borrow_mut()
+            .read_buffer(0, &mut buf)
+            .with_context(|| "Failed to read header of qcow2 image")?;
+        let header = QcowHeader::from_vec(&buf)?;
+
+        let mut image = Qcow2Image {
+            sync_aio: sync_aio.clone(),
+            fd,
+            header: header.clone(),
+            table: Qcow2Table::new(sync_aio.clone()),
+            refcount: RefCount::new(sync_aio.clone()),
+            snapshot: InternalSnapshot::new(sync_aio),
+            backing_file: None,
+            backing_path: None,
+            backing: None,
+        };
+        image.table.init_table(&header)?;
+        let file_size = file
+            .metadata()
+            .with_context(|| "Failed to get size of qcow2 image")?
+            .len();
+        image.refcount.init_refcount_info(&header, file_size)?;
+        image.snapshot.load_snapshot_table(
+            header.snapshots_offset,
+            header.nb_snapshots,
+            header.cluster_size(),
+        )?;
+
+        image.backing_file = image.load_backing_file_info()?;
+        if let Some((name, format)) = image.backing_file.clone() {
+            let mut path = PathBuf::from(&name);
+            if path.is_relative() {
+                if let Some(dir) = file_path(file)?.parent() {
+                    path = dir.join(path);
+                }
+            }
+            let path = path
+                .to_str()
+                .with_context(|| format!("Invalid backing file {}", name))?;
+            image.backing = Some(BackingImage::open(path, format, prop, depth)?);
+            image.backing_path = Some(path.to_string());
+        }
+        Ok(image)
+    }
+
+    fn load_backing_file_info(&mut self) -> Result<Option<(String, Option<DiskFormat>)>> {
+        if self.header.backing_file_offset == 0 {
+            return Ok(None);
+        }
+        if self.header.backing_file_size > MAX_BACKING_FILE_NAME_LEN {
+            bail!(
+                "Backing file name is too long {}",
+                self.header.backing_file_size
+            );
+        }
+        let mut buf = vec![0_u8; self.header.backing_file_size as usize];
+        self.sync_aio
+            .borrow_mut()
+            .read_buffer(self.header.backing_file_offset, &mut buf)?;
+        let name = String::from_utf8(buf).with_context(|| "Invalid backing file name")?;
+        let format = match self.read_header_extension(QCOW2_EXT_MAGIC_BACKING_FORMAT)? {
+            Some(data) => {
+                let format =
+                    String::from_utf8(data).with_context(|| "Invalid backing file format")?;
+                match format.parse::<DiskFormat>() {
+                    Ok(format) => Some(format),
+                    Err(_) => bail!("Unsupported backing file format {}", format),
+                }
+            }
+            None => None,
+        };
+        Ok(Some((name, format)))
+    }
+
+    /// Get the data of header extension with the magic.
+    fn read_header_extension(&mut self, magic: u32) -> Result<Option<Vec<u8>>> {
+        let mut offset = if self.header.version == 2 {
+            QCOW_VERSION_2_MIN_LEN as u64
+        } else {
+            self.header.header_length as u64
+        };
+        let mut end = self.header.cluster_size();
+        if self.header.backing_file_offset != 0 {
+            end = end.min(self.header.backing_file_offset);
+        }
+        while offset + 8 <= end {
+            let mut buf = [0_u8; 8];
+            self.sync_aio.borrow_mut().read_buffer(offset, &mut buf)?;
+            let ext_magic = BigEndian::read_u32(&buf[0..4]);
+            let ext_len = BigEndian::read_u32(&buf[4..8]) as u64;
+            if ext_magic == QCOW2_EXT_MAGIC_END {
+                break;
+            }
+            offset += 8;
+            if offset + ext_len > end {
+                bail!(
+                    "Invalid header extension {:#x} of len {}",
+                    ext_magic,
+                    ext_len
+                );
+            }
+            if ext_magic == magic {
+                let mut data = vec![0_u8; ext_len as usize];
+                self.sync_aio.borrow_mut().read_buffer(offset, &mut data)?;
+                return Ok(Some(data));
+            }
+            offset += round_up(ext_len, 8).with_context(|| "Invalid header extension")?;
+        }
+        Ok(None)
+    }
+
+    fn check_request(&self, offset: u64, len: u64) -> Result<()> {
+        if offset
+            .checked_add(len)
+            .filter(|end| *end <= self.header.size)
+            .is_none()
+        {
+            bail!(
+                "Request offset {} len {} over disk size {}",
+                offset,
+                len,
+                self.header.size
+            );
+        }
+        Ok(())
+    }
+
+    /// Get where the data of virtual disk in [offset, offset + len) is.
+    pub fn get_host_ranges(
+        &mut self,
+        offset: u64,
+        len: u64,
+        ranges: &mut Vec<HostRange>,
+    ) -> Result<()> {
+        let cluster_size = self.header.cluster_size();
+        let mut pos = offset;
+        let end = offset + len;
+        while pos < end {
+            let offset_in_cluster = pos & (cluster_size - 1);
+            let piece = (cluster_size - offset_in_cluster).min(end - pos);
+            let entry = self.table.get_entry(pos)?;
+            if entry & QCOW2_OFLAG_COMPRESSED != 0 {
+                bail!("Compressed cluster is not supported");
+            }
+            let host_offset = entry & L2_TABLE_OFFSET_MASK;
+            if entry & QCOW2_OFLAG_ZERO != 0 {
+                push_host_range(ranges, HostRange::Zero(piece));
+            } else if host_offset != 0 {
+                push_host_range(
+                    ranges,
+                    HostRange::DataOnFile {
+                        fd: self.fd,
+                        offset: host_offset + offset_in_cluster,
+                        nbytes: piece,
+                    },
+                );
+            } else if let Some(backing) = self.backing.as_mut() {
+                backing.host_ranges(pos, piece, ranges)?;
+            } else {
+                push_host_range(ranges, HostRange::Zero(piece));
+            }
+            pos += piece;
+        }
+        Ok(())
+    }
+
+    /// Read data of virtual disk synchronously.
+    pub fn read_sync(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut ranges = Vec::new();
+        self.get_host_ranges(offset, buf.len() as u64, &mut ranges)?;
+        let mut pos = 0_usize;
+        for range in ranges {
+            let len = range.len() as usize;
+            match range {
+                HostRange::DataOnFile { fd, offset, .. } => {
+                    self.sync_aio
+                        .borrow_mut()
+                        .read_fd(fd, offset, &mut buf[pos..pos + len])?;
+                }
+                HostRange::Zero(_) => buf[pos..pos + len].fill(0),
+            }
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Write the data of virtual disk at `offset` synchronously, the clusters are
+    /// allocated if needed.
+    pub fn write_sync(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let ranges = self.get_write_ranges(offset, buf.len() as u64)?;
+        let mut pos = 0_usize;
+        for (host_offset, len) in ranges {
+            let len = len as usize;
+            self.sync_aio
+                .borrow_mut()
+                .write_buffer(host_offset, &buf[pos..pos + len])?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Get whether the data at `offset` is allocated in this image, and the length
+    /// of the area with the same status which is at most `len`. The clusters read
+    /// as zeros are allocated, as they don't read from the backing file.
+    pub fn is_allocated(&mut self, offset: u64, len: u64) -> Result<(bool, u64)> {
+        let len = self.header.size.saturating_sub(offset).min(len);
+        let cluster_size = self.header.cluster_size();
+        let mut status = None;
+        let mut pos = offset;
+        let end = offset + len;
+        while pos < end {
+            let entry = self.table.get_entry(pos)?;
+            let allocated = entry & L2_TABLE_OFFSET_MASK != 0 || entry & QCOW2_OFLAG_ZERO != 0;
+            if *status.get_or_insert(allocated) != allocated {
+                break;
+            }
+            pos = (pos - (pos & (cluster_size - 1)) + cluster_size).min(end);
+        }
+        Ok((status.unwrap_or(false), pos - offset))
+    }
+
+    /// Copy the data of the clusters in [offset, offset + len) which are not
+    /// allocated in this image from the backing file.
+    pub fn copy_backing_data(&mut self, offset: u64, len: u64) -> Result<()> {
+        if self.backing.is_none() {
+            return Ok(());
+        }
+        let cluster_size = self.header.cluster_size();
+        let end = offset.saturating_add(len).min(self.header.size);
+        let mut pos = offset - (offset & (cluster_size - 1));
+        while pos < end {
+            let (allocated, area_len) = self.is_allocated(pos, end - pos)?;
+            if area_len == 0 {
+                break;
+            }
+            if allocated {
+                pos += area_len;
+                continue;
+            }
+            // The whole clusters are copied, as they are allocated by the write.
+            let area_end = round_up(pos + area_len, cluster_size)
+                .with_context(|| "Invalid area to copy")?
+                .min(self.header.size);
+            let mut buf = vec![0_u8; (area_end - pos) as usize];
+            self.read_sync(pos, &mut buf)?;
+            self.write_sync(pos, &buf)?;
+            pos = area_end;
+        }
+        Ok(())
+    }
+
+    /// Remove the backing file from the header, the data of backing file should
+    /// have been copied into this image.
+    pub fn drop_backing_file(&mut self) -> Result<()> {
+        if self.backing.is_none() {
+            bail!("Image has no backing file");
+        }
+        // Both the offset and the size of backing file name are cleared.
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(HEADER_BACKING_FILE_OFFSET, &[0_u8; 12])?;
+        self.header.backing_file_offset = 0;
+        self.header.backing_file_size = 0;
+        self.backing_file = None;
+        self.backing_path = None;
+        self.backing = None;
+        Ok(())
+    }
+
+    /// Get the L2 table of the L1 index which can be written in place, it is
+    /// allocated or copied if needed.
+    fn get_l2_table_for_write(&mut self, l1_index: u64) -> Result<u64> {
+        let l1_entry = self.table.get_l1_entry(l1_index)?;
+        let l2_table_offset = l1_entry & L1_TABLE_OFFSET_MASK;
+        if l2_table_offset != 0 && l1_entry & QCOW2_OFLAG_COPIED != 0 {
+            return Ok(l2_table_offset);
+        }
+
+        let new_offset = self.refcount.alloc_cluster(self.header.cluster_size())?;
+        if l2_table_offset == 0 {
+            self.table
+                .write_l2_table(new_offset, vec![0; self.table.l2_size() as usize])?;
+        } else {
+            // The L2 table is shared. The refcounts of data clusters count the
+            // references of each L1 table, so they are not changed by the copy.
+            let mut l2_table = self.table.get_l2_table(l2_table_offset)?;
+            for entry in l2_table.iter_mut() {
+                *entry &= !QCOW2_OFLAG_COPIED;
+            }
+            self.table.write_l2_table(new_offset, l2_table)?;
+            self.refcount.update_refcount(l2_table_offset, 1, -1)?;
+            self.table.drop_l2_cache(l2_table_offset);
+        }
+        self.table
+            .set_l1_entry(l1_index, new_offset | QCOW2_OFLAG_COPIED)?;
+        Ok(new_offset)
+    }
+
+    /// Get the host clusters to write the data of virtual disk in [offset, offset + len).
+    /// Clusters are allocated if needed, and the data out of the request in the new
+    /// clusters is copied from the old ones.
+    pub fn get_write_ranges(&mut self, offset: u64, len: u64) -> Result<Vec<(u64, u64)>> {
+        let cluster_size = self.header.cluster_size();
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        let mut pos = offset;
+        let end = offset + len;
+        while pos < end {
+            let offset_in_cluster = pos & (cluster_size - 1);
+            let piece = (cluster_size - offset_in_cluster).min(end - pos);
+            let (l1_index, l2_index) = self.table.get_table_index(pos);
+            let l2_table_offset = self.get_l2_table_for_write(l1_index)?;
+            let entry = self.table.get_l2_entry(l2_table_offset, l2_index)?;
+            if entry & QCOW2_OFLAG_COMPRESSED != 0 {
+                bail!("Compressed cluster is not supported");
+            }
+            let mut host_offset = entry & L2_TABLE_OFFSET_MASK;
+            if host_offset == 0 || entry & QCOW2_OFLAG_COPIED == 0 || entry & QCOW2_OFLAG_ZERO != 0
+            {
+                let new_offset = self.refcount.alloc_cluster(cluster_size)?;
+                if piece != cluster_size {
+                    let mut buf = vec![0_u8; cluster_size as usize];
+                    let cluster_start = pos - offset_in_cluster;
+                    if entry & QCOW2_OFLAG_ZERO != 0 {
+                        // The cluster reads as zeros.
+                    } else if host_offset != 0 {
+                        self.sync_aio
+                            .borrow_mut()
+                            .read_buffer(host_offset, &mut buf)?;
+                    } else if let Some(backing) = self.backing.as_mut() {
+                        backing.read_sync(cluster_start, &mut buf)?;
+                    }
+                    self.sync_aio.borrow_mut().write_buffer(new_offset, &buf)?;
+                }
+                self.table.set_l2_entry(
+                    l2_table_offset,
+                    l2_index,
+                    new_offset | QCOW2_OFLAG_COPIED,
+                )?;
+                if host_offset != 0 {
+                    self.refcount.update_refcount(host_offset, 1, -1)?;
+                }
+                host_offset = new_offset;
+            }
+
+            let host_pos = host_offset + offset_in_cluster;
+            match ranges.last_mut() {
+                Some((last_pos, last_len)) if *last_pos + *last_len == host_pos => {
+                    *last_len += piece
+                }
+                _ => ranges.push((host_pos, piece)),
+            }
+            pos += piece;
+        }
+        Ok(ranges)
+    }
+
+    /// Add `added` to the refcounts of the L2 tables and data clusters referenced
+    /// by `l1_table`, and update the COPIED flags of the entries by their refcounts.
+    /// The entries of `l1_table` are updated in place, the caller saves them.
+    fn update_snapshot_refcount(&mut self, l1_table: &mut [u64], added: i64) -> Result<()> {
+        for l1_entry in l1_table.iter_mut() {
+            let l2_table_offset = *l1_entry & L1_TABLE_OFFSET_MASK;
+            if l2_table_offset == 0 {
+                continue;
+            }
+            let mut l2_table = self.table.get_l2_table(l2_table_offset)?;
+            let mut l2_changed = false;
+            for entry in l2_table.iter_mut() {
+                if *entry & QCOW2_OFLAG_COMPRESSED != 0 {
+                    bail!("Compressed cluster is not supported");
+                }
+                let host_offset = *entry & L2_TABLE_OFFSET_MASK;
+                if host_offset == 0 {
+                    continue;
+                }
+                if added != 0 {
+                    self.refcount.update_refcount(host_offset, 1, added)?;
+                }
+                let new_entry = if self.refcount.get_refcount(host_offset)? == 1 {
+                    *entry | QCOW2_OFLAG_COPIED
+                } else {
+                    *entry & !QCOW2_OFLAG_COPIED
+                };
+                if new_entry != *entry {
+                    *entry = new_entry;
+                    l2_changed = true;
+                }
+            }
+
+            if added != 0 {
+                self.refcount.update_refcount(l2_table_offset, 1, added)?;
+            }
+            let l2_refcount = self.refcount.get_refcount(l2_table_offset)?;
+            if l2_refcount == 0 {
+                self.table.drop_l2_cache(l2_table_offset);
+            } else if l2_changed {
+                self.table.write_l2_table(l2_table_offset, l2_table)?;
+            }
+            *l1_entry = if l2_refcount == 1 {
+                *l1_entry | QCOW2_OFLAG_COPIED
+            } else {
+                *l1_entry & !QCOW2_OFLAG_COPIED
+            };
+        }
+        Ok(())
+    }
+
+    /// Write the changed entries of active L1 table.
+    fn save_l1_table(&mut self, l1_table: &[u64]) -> Result<()> {
+        for (index, entry) in l1_table.iter().enumerate() {
+            if *entry != self.table.l1_table[index] {
+                self.table.set_l1_entry(index as u64, *entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_snapshot_table(&mut self) -> Result<()> {
+        self.snapshot.save_snapshot_table(&mut self.refcount)?;
+        self.header.nb_snapshots = self.snapshot.snapshots.len() as u32;
+        self.header.snapshots_offset = self.snapshot.snapshot_table_offset;
+        Ok(())
+    }
+
+    /// Take an internal snapshot of the disk. The clusters in use are shared
+    /// with the snapshot, and copied when written later.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            bail!("Invalid snapshot name length {}", name.len());
+        }
+        if self.snapshot.find_snapshot(None, Some(name)).is_some() {
+            bail!("Snapshot {} already exists", name);
+        }
+        if self.snapshot.snapshots.len() >= QCOW2_MAX_SNAPSHOTS {
+            bail!("Too many snapshots, the max number {}", QCOW2_MAX_SNAPSHOTS);
+        }
+
+        let mut l1_table = self.table.l1_table.clone();
+        self.update_snapshot_refcount(&mut l1_table, 1)?;
+        self.save_l1_table(&l1_table)?;
+        let l1_size = self.table.l1_size;
+        let l1_table_offset = if l1_size == 0 {
+            0
+        } else {
+            let offset = self.refcount.alloc_cluster(l1_size as u64 * ENTRY_SIZE)?;
+            self.sync_aio
+                .borrow_mut()
+                .write_ctrl_cluster(offset, &l1_table)?;
+            offset
+        };
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let snapshot = QcowSnapshot {
+            l1_table_offset,
+            l1_size,
+            id: self.snapshot.new_snapshot_id(),
+            name: name.to_string(),
+            date_sec: now.as_secs() as u32,
+            date_nsec: now.subsec_nanos(),
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            disk_size: self.header.size,
+            extra_data: Vec::new(),
+        };
+        self.snapshot.snapshots.push(snapshot);
+        self.save_snapshot_table()
+    }
+
+    /// Delete the internal snapshot, the clusters only used by it are freed.
+    pub fn delete_snapshot(
+        &mut self,
+        id: Option<&str>,
+        name: Option<&str>,
+    ) -> Result<SnapshotInfo> {
+        let index = self
+            .snapshot
+            .find_snapshot(id, name)
+            .with_context(|| format!("Snapshot with id {:?} name {:?} not found", id, name))?;
+        // Remove the snapshot from table first, so that its clusters are leaked
+        // rather than referenced after freed if failed.
+        let snapshot = self.snapshot.snapshots.remove(index);
+        self.save_snapshot_table()?;
+
+        let mut l1_table = self
+            .sync_aio
+            .borrow_mut()
+            .read_ctrl_cluster(snapshot.l1_table_offset, snapshot.l1_size as u64)?;
+        self.update_snapshot_refcount(&mut l1_table, -1)?;
+        if snapshot.l1_size != 0 {
+            let l1_clusters = round_up(
+                snapshot.l1_size as u64 * ENTRY_SIZE,
+                self.header.cluster_size(),
+            )
+            .with_context(|| "Invalid size of snapshot L1 table")?
+                >> self.header.cluster_bits;
+            self.refcount
+                .update_refcount(snapshot.l1_table_offset, l1_clusters, -1)?;
+        }
+
+        // Clusters may be used only by the disk now.
+        let mut l1_table = self.table.l1_table.clone();
+        self.update_snapshot_refcount(&mut l1_table, 0)?;
+        self.save_l1_table(&l1_table)?;
+        Ok(snapshot.get_info())
+    }
+
+    /// Grow the virtual disk to `size` bytes. The L1 table is moved to a bigger
+    /// one if it does not cover the new size, the snapshots are not changed.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if size < self.header.size {
+            bail!(
+                "Shrinking qcow2 image from {} to {} is not supported",
+                self.header.size,
+                size
+            );
+        }
+        let l2_coverage = self.header.cluster_size() * self.table.l2_size();
+        let l1_size = round_up(size, l2_coverage)
+            .with_context(|| format!("Invalid disk size {}", size))?
+            / l2_coverage;
+        if l1_size > u32::MAX as u64 {
+            bail!("Disk size {} is too large", size);
+        }
+        if l1_size > self.table.l1_size as u64 {
+            let mut l1_table = self.table.l1_table.clone();
+            l1_table.resize(l1_size as usize, 0);
+            let new_offset = self.refcount.alloc_cluster(l1_size * ENTRY_SIZE)?;
+            self.sync_aio
+                .borrow_mut()
+                .write_ctrl_cluster(new_offset, &l1_table)?;
+
+            let mut buf = [0_u8; 12];
+            BigEndian::write_u32(&mut buf[0..4], l1_size as u32);
+            BigEndian::write_u64(&mut buf[4..12], new_offset);
+            self.sync_aio
+                .borrow_mut()
+                .write_buffer(HEADER_L1_SIZE_OFFSET, &buf)?;
+
+            let old_offset = self.table.l1_table_offset;
+            let old_size = self.table.l1_size as u64;
+            self.table.load_l1_table(new_offset, l1_size as u32)?;
+            self.header.l1_size = l1_size as u32;
+            self.header.l1_table_offset = new_offset;
+            if old_size != 0 {
+                let old_clusters = round_up(old_size * ENTRY_SIZE, self.header.cluster_size())
+                    .with_context(|| "Invalid size of L1 table")?
+                    >> self.header.cluster_bits;
+                self.refcount
+                    .update_refcount(old_offset, old_clusters, -1)?;
+            }
+        }
+
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(HEADER_SIZE_OFFSET, &size.to_be_bytes())?;
+        self.header.size = size;
+        Ok(())
+    }
+}
+
+/// Driver of qcow2 image.
+pub struct Qcow2Driver<T: Clone + 'static> {
+    driver: FileDriver<T>,
+    image: Qcow2Image,
+}
+
+impl<T: Clone + 'static> Qcow2Driver<T> {
+    pub fn new(file: File, aio: Aio<T>, prop: BlockProperty) -> Result<Self> {
+        let image = Qcow2Image::open(&file, &prop, 0)
+            .with_context(|| format!("Failed to open qcow2 image of drive {}", prop.id))?;
+        Ok(Self {
+            driver: FileDriver::new(file, aio, prop),
+            image,
+        })
+    }
+}
+
+impl<T: Clone + 'static> BlockDriverOps<T> for Qcow2Driver<T> {
+    fn disk_size(&mut self) -> Result<u64> {
+        Ok(self.image.header.size)
+    }
+
+    fn resize(&mut self, size: u64) -> Result<()> {
+        self.image.resize(size)?;
+        self.driver.resize_dirty_bitmaps(size)
+    }
+
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.image.check_request(offset as u64, nbytes)?;
+        if self.driver.block_prop.copy_on_read {
+            // The data is not changed, so the dirty bitmaps are not set.
+            self.image.copy_backing_data(offset as u64, nbytes)?;
+        }
+        let mut ranges = Vec::new();
+        self.image
+            .get_host_ranges(offset as u64, nbytes, &mut ranges)?;
+
+        let mut left = iovec;
+        let mut req_list = Vec::new();
+        for range in ranges {
+            let (iov, rest) = iovecs_split(left, range.len());
+            left = rest;
+            match range {
+                HostRange::DataOnFile { fd, offset, nbytes } => {
+                    let mut req = CombineRequest::new(iov, offset, nbytes);
+                    req.file_fd = Some(fd);
+                    req_list.push(req);
+                }
+                HostRange::Zero(_) => iovec_write_zero(&iov),
+            }
+        }
+        self.driver.read_vectored(req_list, completecb)
+    }
+
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.image.check_request(offset as u64, nbytes)?;
+        let ranges = self.image.get_write_ranges(offset as u64, nbytes)?;
+        self.driver.set_dirty(offset as u64, nbytes)?;
+
+        let mut left = iovec;
+        let mut req_list = Vec::new();
+        for (host_offset, len) in ranges {
+            let (iov, rest) = iovecs_split(left, len);
+            left = rest;
+            req_list.push(CombineRequest::new(iov, host_offset, len));
+        }
+        self.driver.write_vectored(req_list, completecb)
+    }
+
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()> {
+        self.image.check_request(offset as u64, nbytes)?;
+        // The clusters are kept, as discard is only a hint.
+        self.driver.discard(Vec::new(), completecb)
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        self.image.check_request(offset as u64, nbytes)?;
+        let ranges = self.image.get_write_ranges(offset as u64, nbytes)?;
+        self.driver.set_dirty(offset as u64, nbytes)?;
+
+        let req_list = ranges
+            .into_iter()
+            .map(|(host_offset, len)| CombineRequest::new(Vec::new(), host_offset, len))
+            .collect();
+        // The holes punched in the allocated clusters read as zeros.
+        self.driver.write_zeroes(req_list, completecb, unmap)
+    }
+
+    fn datasync(&mut self, completecb: T) -> Result<()> {
+        self.driver.datasync(completecb)
+    }
+
+    fn flush_request(&mut self) -> Result<()> {
+        self.driver.flush_request()
+    }
+
+    fn drain_request(&mut self) -> Result<()> {
+        self.driver.drain_request()
+    }
+
+    fn read_sync(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.image.check_request(offset, buf.len() as u64)?;
+        self.image.read_sync(offset, buf)
+    }
+
+    fn write_sync(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.image.check_request(offset, buf.len() as u64)?;
+        self.driver.set_dirty(offset, buf.len() as u64)?;
+        self.image.write_sync(offset, buf)
+    }
+
+    fn flush_sync(&mut self) -> Result<()> {
+        self.driver.flush_sync()
+    }
+
+    fn is_allocated(&mut self, offset: u64, len: u64) -> Result<(bool, u64)> {
+        self.image.is_allocated(offset, len)
+    }
+
+    fn copy_backing_data(&mut self, offset: u64, len: u64) -> Result<()> {
+        self.image.check_request(offset, len)?;
+        self.image.copy_backing_data(offset, len)
+    }
+
+    fn drop_backing_file(&mut self) -> Result<()> {
+        self.image.drop_backing_file().with_context(|| {
+            format!(
+                "Failed to drop backing file of drive {}",
+                self.driver.block_prop.id
+            )
+        })
+    }
+
+    fn backing_file(&self) -> Option<(String, DiskFormat)> {
+        let path = self.image.backing_path.clone()?;
+        let format = self.image.backing.as_ref()?.format();
+        Some((path, format))
+    }
+
+    fn add_dirty_bitmap(&mut self, bitmap: Arc<Mutex<DirtyBitmap>>) -> Result<()> {
+        self.driver.add_dirty_bitmap(bitmap)
+    }
+
+    fn get_dirty_bitmap(&self, name: &str) -> Option<Arc<Mutex<DirtyBitmap>>> {
+        self.driver.get_dirty_bitmap(name)
+    }
+
+    fn named_dirty_bitmaps(&self) -> Vec<Arc<Mutex<DirtyBitmap>>> {
+        self.driver.named_dirty_bitmaps()
+    }
+
+    fn remove_dirty_bitmap(&mut self, bitmap: &Arc<Mutex<DirtyBitmap>>) {
+        self.driver.remove_dirty_bitmap(bitmap);
+    }
+
+    fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        self.image.create_snapshot(name).with_context(|| {
+            format!(
+                "Failed to create snapshot of drive {}",
+                self.driver.block_prop.id
+            )
+        })
+    }
+
+    fn delete_snapshot(&mut self, id: Option<&str>, name: Option<&str>) -> Result<SnapshotInfo> {
+        self.image.delete_snapshot(id, name).with_context(|| {
+            format!(
+                "Failed to delete snapshot of drive {}",
+                self.driver.block_prop.id
+            )
+        })
+    }
+
+    fn register_io_event(
+        &mut self,
+        device_broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        self.driver.register_io_event(device_broken, error_cb)
+    }
+
+    fn unregister_io_event(&mut self) -> Result<()> {
+        self.driver.unregister_io_event()
+    }
+}
+
+// SAFETY: The metadata of image is only accessed in the iothread of the block backend.
+unsafe impl<T: Clone + 'static> Send for Qcow2Driver<T> {}
+
+/// Options to create a qcow2 image.
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    pub path: String,
+    /// Virtual size of disk in bytes.
+    pub size: u64,
+    pub cluster_size: u64,
+    pub backing_file: Option<String>,
+    pub backing_fmt: Option<DiskFormat>,
+}
+
+impl CreateOptions {
+    pub fn new(path: &str, size: u64) -> Self {
+        Self {
+            path: path.to_string(),
+            size,
+            cluster_size: DEFAULT_CLUSTER_SIZE,
+            backing_file: None,
+            backing_fmt: None,
+        }
+    }
+}
+
+/// Create an empty qcow2 image of version 3. The image is laid out as: header,
+/// refcount table, refcount blocks and L1 table.
+pub fn create_qcow2_image(opts: &CreateOptions) -> Result<()> {
+    let cluster_size = opts.cluster_size;
+    if !cluster_size.is_power_of_two() {
+        bail!("Cluster size {} is not power of 2", cluster_size);
+    }
+    let cluster_bits = cluster_size.trailing_zeros();
+    let l2_entries = cluster_size / ENTRY_SIZE;
+    let l1_size = round_up(opts.size, cluster_size * l2_entries)
+        .with_context(|| format!("Invalid disk size {}", opts.size))?
+        / (cluster_size * l2_entries);
+    let l1_clusters = round_up((l1_size * ENTRY_SIZE).max(1), cluster_size).unwrap() / cluster_size;
+    // Entries of refcount block with 16 bits refcount.
+    let refblock_entries = cluster_size * 8 / (1 << DEFAULT_REFCOUNT_ORDER);
+    let mut refblocks = 1;
+    loop {
+        let total = 2 + refblocks + l1_clusters;
+        let needed = round_up(total, refblock_entries).unwrap() / refblock_entries;
+        if needed <= refblocks {
+            break;
+        }
+        refblocks = needed;
+    }
+    if refblocks > cluster_size / ENTRY_SIZE {
+        bail!(
+            "Disk size {} is too large for cluster size {}",
+            opts.size,
+            cluster_size
+        );
+    }
+    let total_clusters = 2 + refblocks + l1_clusters;
+    let l1_table_offset = (2 + refblocks) * cluster_size;
+
+    let mut header = QcowHeader {
+        magic: QCOW_MAGIC,
+        version: 3,
+        cluster_bits,
+        size: opts.size,
+        l1_size: l1_size as u32,
+        l1_table_offset,
+        refcount_table_offset: cluster_size,
+        refcount_table_clusters: 1,
+        refcount_order: DEFAULT_REFCOUNT_ORDER,
+        header_length: QCOW_VERSION_3_MIN_LEN as u32,
+        ..Default::default()
+    };
+    header.check()?;
+
+    let mut cluster0 = Vec::new();
+    let mut ext = Vec::new();
+    if let Some(format) = opts.backing_fmt {
+        let name = match format {
+            DiskFormat::Raw => "raw",
+            DiskFormat::Qcow2 => "qcow2",
+        };
+        let mut buf = [0_u8; 8];
+        BigEndian::write_u32(&mut buf[0..4], QCOW2_EXT_MAGIC_BACKING_FORMAT);
+        BigEndian::write_u32(&mut buf[4..8], name.len() as u32);
+        ext.extend_from_slice(&buf);
+        ext.extend_from_slice(name.as_bytes());
+        ext.resize(round_up(ext.len() as u64, 8).unwrap() as usize, 0);
+    }
+    // End of header extensions.
+    ext.extend_from_slice(&[0_u8; 8]);
+    if let Some(backing) = opts.backing_file.as_ref() {
+        if backing.len() as u32 > MAX_BACKING_FILE_NAME_LEN {
+            bail!("Backing file name is too long {}", backing.len());
+        }
+        header.backing_file_offset = (QCOW_VERSION_3_MIN_LEN + ext.len()) as u64;
+        header.backing_file_size = backing.len() as u32;
+    }
+    cluster0.extend_from_slice(&header.to_vec());
+    cluster0.extend_from_slice(&ext);
+    if let Some(backing) = opts.backing_file.as_ref() {
+        cluster0.extend_from_slice(backing.as_bytes());
+    }
+    if cluster0.len() as u64 > cluster_size {
+        bail!("Header is too large for cluster size {}", cluster_size);
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&opts.path)
+        .with_context(|| format!("Failed to create image {}", opts.path))?;
+    file.set_len(total_clusters * cluster_size)?;
+    file.write_all_at(&cluster0, 0)?;
+
+    let mut table = vec![0_u8; (refblocks * ENTRY_SIZE) as usize];
+    for i in 0..refblocks {
+        BigEndian::write_u64(
+            &mut table[(i * ENTRY_SIZE) as usize..],
+            (2 + i) * cluster_size,
+        );
+    }
+    file.write_all_at(&table, cluster_size)?;
+    let mut blocks = vec![0_u8; (refblocks * cluster_size) as usize];
+    for i in 0..total_clusters as usize {
+        BigEndian::write_u16(&mut blocks[i * 2..], 1);
+    }
+    file.write_all_at(&blocks, 2 * cluster_size)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::io::Write;
+
+    use super::*;
+
+    fn complete_func(_aio: &AioCb<()>, ret: i64) -> Result<()> {
+        if ret < 0 {
+            bail!("Failed to complete io, ret {}", ret);
+        }
+        Ok(())
+    }
+
+    fn open_driver(path: &str) -> Qcow2Driver<()> {
+        let file = open_file(path, false, false).unwrap();
+        let aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        let prop = BlockProperty {
+            id: "drive0".to_string(),
+            format: DiskFormat::Qcow2,
+            iothread: None,
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            copy_on_read: false,
+        };
+        Qcow2Driver::new(file, aio, prop).unwrap()
+    }
+
+    fn write_data(driver: &mut Qcow2Driver<()>, offset: usize, buf: &[u8]) {
+        let iov = vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)];
+        driver.write_vectored(iov, offset, ()).unwrap();
+    }
+
+    fn read_data(driver: &mut Qcow2Driver<()>, offset: usize, len: usize) -> Vec<u8> {
+        let buf = vec![0xff_u8; len];
+        // Split the buffer to test iovecs crossing clusters.
+        let half = len / 2;
+        let iov = vec![
+            Iovec::new(buf.as_ptr() as u64, half as u64),
+            Iovec::new(buf.as_ptr() as u64 + half as u64, (len - half) as u64),
+        ];
+        driver.read_vectored(iov, offset, ()).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_qcow2_read_write() {
+        let path = "/tmp/test_qcow2_read_write.qcow2";
+        create_qcow2_image(&CreateOptions::new(path, 1 << 30)).unwrap();
+        let mut driver = open_driver(path);
+        assert_eq!(driver.disk_size().unwrap(), 1 << 30);
+        // Unallocated data reads as zero.
+        assert_eq!(read_data(&mut driver, 0, 4096), vec![0_u8; 4096]);
+
+        // Write crossing the boundary of cluster and L2 table.
+        let offset = (1 << 29) - 1000;
+        write_data(&mut driver, offset, &[0x5a_u8; 3000]);
+        write_data(&mut driver, 512, &[0xa5_u8; 512]);
+        let buf = read_data(&mut driver, offset - 1000, 5000);
+        assert_eq!(&buf[..1000], &[0_u8; 1000]);
+        assert_eq!(&buf[1000..4000], &[0x5a_u8; 3000]);
+        assert_eq!(&buf[4000..], &[0_u8; 1000]);
+        // Out of the disk.
+        let buf = [0_u8; 512];
+        let iov = vec![Iovec::new(buf.as_ptr() as u64, 512)];
+        assert!(driver.write_vectored(iov, 1 << 30, ()).is_err());
+        drop(driver);
+
+        // The data persists after reopen.
+        let mut driver = open_driver(path);
+        let buf = read_data(&mut driver, 0, 2048);
+        assert_eq!(&buf[..512], &[0_u8; 512]);
+        assert_eq!(&buf[512..1024], &[0xa5_u8; 512]);
+        assert_eq!(&buf[1024..], &[0_u8; 1024]);
+        assert_eq!(read_data(&mut driver, offset, 3000), vec![0x5a_u8; 3000]);
+        // Clusters written are referenced once.
+        let host = driver.image.table.get_entry(0).unwrap();
+        assert_ne!(host & QCOW2_OFLAG_COPIED, 0);
+        assert_eq!(
+            driver
+                .image
+                .refcount
+                .get_refcount(host & L2_TABLE_OFFSET_MASK)
+                .unwrap(),
+            1
+        );
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_write_zeroes_discard() {
+        let path = "/tmp/test_qcow2_write_zeroes_discard.qcow2";
+        create_qcow2_image(&CreateOptions::new(path, 1 << 30)).unwrap();
+        let mut driver = open_driver(path);
+        write_data(&mut driver, 0, &[0x5a_u8; 4096]);
+        write_data(&mut driver, 1 << 20, &[0xa5_u8; 4096]);
+
+        // Zeros are written both with and without unmap.
+        driver.write_zeroes(1024, 1024, (), false).unwrap();
+        driver.write_zeroes(3072, 512, (), true).unwrap();
+        let buf = read_data(&mut driver, 0, 4096);
+        assert_eq!(&buf[..1024], &[0x5a_u8; 1024]);
+        assert_eq!(&buf[1024..2048], &[0_u8; 1024]);
+        assert_eq!(&buf[2048..3072], &[0x5a_u8; 1024]);
+        assert_eq!(&buf[3072..3584], &[0_u8; 512]);
+        assert_eq!(&buf[3584..], &[0x5a_u8; 512]);
+        // Out of the disk.
+        assert!(driver.write_zeroes(1 << 30, 512, (), false).is_err());
+        assert!(driver.discard(1 << 30, 512, ()).is_err());
+
+        // Discard is a hint, the data is kept.
+        driver.discard(1 << 20, 4096, ()).unwrap();
+        let buf = read_data(&mut driver, 1 << 20, 4096);
+        assert!(buf == vec![0xa5_u8; 4096] || buf == vec![0_u8; 4096]);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_backing_file() {
+        let base = "/tmp/test_qcow2_backing_file.raw";
+        let path = "/tmp/test_qcow2_backing_file.qcow2";
+        let mut file = File::create(base).unwrap();
+        file.write_all(&[0x11_u8; 1 << 16]).unwrap();
+        // The backing file is smaller than the disk.
+        file.write_all(&[0x22_u8; 1000]).unwrap();
+        drop(file);
+        let mut opts = CreateOptions::new(path, 1 << 20);
+        opts.backing_file = Some("test_qcow2_backing_file.raw".to_string());
+        opts.backing_fmt = Some(DiskFormat::Raw);
+        create_qcow2_image(&opts).unwrap();
+
+        let mut driver = open_driver(path);
+        let buf = read_data(&mut driver, (1 << 16) - 100, 1200);
+        assert_eq!(&buf[..100], &[0x11_u8; 100]);
+        assert_eq!(&buf[100..1100], &[0x22_u8; 1000]);
+        assert_eq!(&buf[1100..], &[0_u8; 100]);
+
+        // Partial write copies the rest of cluster from backing file.
+        write_data(&mut driver, 4096, &[0x33_u8; 512]);
+        let buf = read_data(&mut driver, 0, 1 << 16);
+        assert_eq!(&buf[..4096], &[0x11_u8; 4096]);
+        assert_eq!(&buf[4096..4608], &[0x33_u8; 512]);
+        assert_eq!(&buf[4608..], &[0x11_u8; (1 << 16) - 4608]);
+        drop(driver);
+
+        // Backing file is not modified.
+        let data = std::fs::read(base).unwrap();
+        assert_eq!(&data[4096..4608], &[0x11_u8; 512]);
+        remove_file(path).unwrap();
+        remove_file(base).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_internal_snapshot() {
+        let path = "/tmp/test_qcow2_internal_snapshot.qcow2";
+        create_qcow2_image(&CreateOptions::new(path, 1 << 20)).unwrap();
+        let mut driver = open_driver(path);
+        let cluster_size = 1 << 16;
+        write_data(&mut driver, 0, &vec![0x11_u8; cluster_size * 2]);
+        driver.create_snapshot("snap0").unwrap();
+        assert!(driver.create_snapshot("snap0").is_err());
+
+        // The clusters are shared by the snapshot.
+        let old_host = driver.image.table.get_entry(0).unwrap();
+        assert_eq!(old_host & QCOW2_OFLAG_COPIED, 0);
+        let old_host = old_host & L2_TABLE_OFFSET_MASK;
+        assert_eq!(driver.image.refcount.get_refcount(old_host).unwrap(), 2);
+        assert_eq!(driver.image.table.l1_table[0] & QCOW2_OFLAG_COPIED, 0);
+
+        // Write after snapshot copies the cluster.
+        write_data(&mut driver, 0, &[0x22_u8; 4096]);
+        let buf = read_data(&mut driver, 0, cluster_size);
+        assert_eq!(&buf[..4096], &[0x22_u8; 4096]);
+        assert_eq!(&buf[4096..], &vec![0x11_u8; cluster_size - 4096][..]);
+        let mut buf = vec![0_u8; cluster_size];
+        driver
+            .image
+            .sync_aio
+            .borrow_mut()
+            .read_buffer(old_host, &mut buf)
+            .unwrap();
+        assert_eq!(buf, vec![0x11_u8; cluster_size]);
+        drop(driver);
+
+        let mut driver = open_driver(path);
+        assert_eq!(driver.image.header.nb_snapshots, 1);
+        assert_eq!(driver.image.snapshot.snapshots[0].id, "1");
+        assert_eq!(driver.image.snapshot.snapshots[0].disk_size, 1 << 20);
+        assert!(driver.delete_snapshot(Some("2"), Some("snap0")).is_err());
+        let info = driver.delete_snapshot(None, Some("snap0")).unwrap();
+        assert_eq!(info.id, "1");
+        assert_eq!(info.name, "snap0");
+
+        // The clusters only used by the snapshot are freed, and the rest can
+        // be written in place again.
+        assert_eq!(driver.image.refcount.get_refcount(old_host).unwrap(), 0);
+        let host = driver.image.table.get_entry(cluster_size as u64).unwrap();
+        assert_ne!(host & QCOW2_OFLAG_COPIED, 0);
+        assert_eq!(
+            driver
+                .image
+                .refcount
+                .get_refcount(host & L2_TABLE_OFFSET_MASK)
+                .unwrap(),
+            1
+        );
+        assert_ne!(driver.image.table.l1_table[0] & QCOW2_OFLAG_COPIED, 0);
+        drop(driver);
+
+        let mut driver = open_driver(path);
+        assert_eq!(driver.image.header.nb_snapshots, 0);
+        assert!(driver.image.snapshot.snapshots.is_empty());
+        let buf = read_data(&mut driver, 0, cluster_size * 2);
+        assert_eq!(&buf[..4096], &[0x22_u8; 4096]);
+        assert_eq!(&buf[4096..], &vec![0x11_u8; cluster_size * 2 - 4096][..]);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_refcount_table_grow() {
+        let path = "/tmp/test_qcow2_refcount_table_grow.qcow2";
+        let size = 1 << 24;
+        let mut opts = CreateOptions::new(path, size as u64);
+        opts.cluster_size = 512;
+        create_qcow2_image(&opts).unwrap();
+        let mut driver = open_driver(path);
+        let old_table_offset = driver.image.refcount.refcount_table_offset;
+
+        // A refcount block covers 256 clusters, and the table holds 64 blocks.
+        // Write the whole disk to use more than 64 refcount blocks.
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        for offset in (0..size).step_by(1 << 16) {
+            write_data(&mut driver, offset, &data[offset..offset + (1 << 16)]);
+        }
+        assert_ne!(
+            driver.image.refcount.refcount_table_offset,
+            old_table_offset
+        );
+        assert_eq!(
+            driver
+                .image
+                .refcount
+                .get_refcount(old_table_offset)
+                .unwrap(),
+            0
+        );
+        drop(driver);
+
+        let mut driver = open_driver(path);
+        assert_eq!(read_data(&mut driver, 0, size), data);
+        let table_offset = driver.image.refcount.refcount_table_offset;
+        assert_eq!(driver.image.refcount.get_refcount(table_offset).unwrap(), 1);
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_qcow2_resize() {
+        let path = "/tmp/test_qcow2_resize.qcow2";
+        // A L2 table covers 32KiB of disk with the cluster of 512 bytes.
+        let mut opts = CreateOptions::new(path, 1 << 16);
+        opts.cluster_size = 512;
+        create_qcow2_image(&opts).unwrap();
+        let mut driver = open_driver(path);
+        write_data(&mut driver, (1 << 16) - 512, &[0x5a_u8; 512]);
+        let old_l1_offset = driver.image.table.l1_table_offset;
+
+        assert!(driver.resize(1 << 15).is_err());
+        driver.resize(1 << 20).unwrap();
+        assert_eq!(driver.disk_size().unwrap(), 1 << 20);
+        assert_eq!(driver.image.table.l1_size, 32);
+        assert_ne!(driver.image.table.l1_table_offset, old_l1_offset);
+        assert_eq!(
+            driver.image.refcount.get_refcount(old_l1_offset).unwrap(),
+            0
+        );
+        // The area added reads as zeros and is writable.
+        assert_eq!(read_data(&mut driver, 1 << 16, 4096), vec![0_u8; 4096]);
+        write_data(&mut driver, (1 << 20) - 512, &[0xa5_u8; 512]);
+        drop(driver);
+
+        let mut driver = open_driver(path);
+        assert_eq!(driver.disk_size().unwrap(), 1 << 20);
+        assert_eq!(
+            read_data(&mut driver, (1 << 16) - 512, 512),
+            vec![0x5a_u8; 512]
+        );
+        assert_eq!(
+            read_data(&mut driver, (1 << 20) - 512, 512),
+            vec![0xa5_u8; 512]
+        );
+        remove_file(path).unwrap();
+    }
+}
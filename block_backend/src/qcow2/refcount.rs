@@ -0,0 +1,242 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+use super::cache::Qcow2Cache;
+use super::header::QcowHeader;
+use super::{SyncAioInfo, ENTRY_SIZE, REFCOUNT_TABLE_OFFSET_MASK};
+use util::num_ops::round_up;
+
+/// Max number of refcount blocks in cache.
+const MAX_REFCOUNT_CACHE_SIZE: usize = 16;
+/// Offset of the refcount table fields in header.
+const REFCOUNT_TABLE_HEADER_OFFSET: u64 = 48;
+
+/// Reference counts of the clusters in image. Clusters are allocated at the end
+/// of image, freed clusters are not reused.
+pub struct RefCount {
+    sync_aio: Rc<RefCell<SyncAioInfo>>,
+    pub refcount_table: Vec<u64>,
+    pub refcount_table_offset: u64,
+    pub refcount_table_clusters: u32,
+    cluster_bits: u64,
+    cluster_size: u64,
+    /// Bytes of each refcount entry.
+    refcount_bytes: u64,
+    /// Max value of the refcount.
+    refcount_max: u64,
+    /// Number of bits of the entry index in refcount block.
+    refcount_block_bits: u64,
+    /// Number of entries in refcount block.
+    refcount_block_size: u64,
+    /// Decoded refcount blocks indexed by their offset in image file.
+    block_cache: Qcow2Cache,
+    /// Offset of the next cluster to allocate.
+    free_cluster_offset: u64,
+}
+
+impl RefCount {
+    pub fn new(sync_aio: Rc<RefCell<SyncAioInfo>>) -> Self {
+        RefCount {
+            sync_aio,
+            refcount_table: Vec::new(),
+            refcount_table_offset: 0,
+            refcount_table_clusters: 0,
+            cluster_bits: 0,
+            cluster_size: 0,
+            refcount_bytes: 0,
+            refcount_max: 0,
+            refcount_block_bits: 0,
+            refcount_block_size: 0,
+            block_cache: Qcow2Cache::new(MAX_REFCOUNT_CACHE_SIZE),
+            free_cluster_offset: 0,
+        }
+    }
+
+    pub fn init_refcount_info(&mut self, header: &QcowHeader, file_size: u64) -> Result<()> {
+        self.cluster_bits = header.cluster_bits as u64;
+        self.cluster_size = header.cluster_size();
+        self.refcount_bytes = 1 << (header.refcount_order - 3);
+        self.refcount_max = if header.refcount_order == 6 {
+            u64::MAX
+        } else {
+            (1 << (1 << header.refcount_order)) - 1
+        };
+        self.refcount_block_bits = self.cluster_bits + 3 - header.refcount_order as u64;
+        self.refcount_block_size = 1 << self.refcount_block_bits;
+        if header.refcount_table_offset & (self.cluster_size - 1) != 0 {
+            bail!(
+                "Refcount table offset {:#x} is not aligned to cluster",
+                header.refcount_table_offset
+            );
+        }
+        self.refcount_table_offset = header.refcount_table_offset;
+        self.refcount_table_clusters = header.refcount_table_clusters;
+        let entries = header.refcount_table_clusters as u64 * self.cluster_size / ENTRY_SIZE;
+        self.refcount_table = self
+            .sync_aio
+            .borrow_mut()
+            .read_ctrl_cluster(self.refcount_table_offset, entries)
+            .with_context(|| "Failed to read refcount table")?;
+        self.free_cluster_offset =
+            round_up(file_size, self.cluster_size).with_context(|| "Invalid image size")?;
+        self.block_cache.clear();
+        Ok(())
+    }
+
+    /// Get the index in refcount table and refcount block of the cluster.
+    fn get_refcount_index(&self, offset: u64) -> (u64, u64) {
+        let cluster_index = offset >> self.cluster_bits;
+        (
+            cluster_index >> self.refcount_block_bits,
+            cluster_index & (self.refcount_block_size - 1),
+        )
+    }
+
+    fn load_refcount_block(&mut self, block_offset: u64) -> Result<&mut Vec<u64>> {
+        if self.block_cache.get(block_offset).is_none() {
+            let mut buf = vec![0_u8; self.cluster_size as usize];
+            self.sync_aio
+                .borrow_mut()
+                .read_buffer(block_offset, &mut buf)?;
+            let bytes = self.refcount_bytes as usize;
+            let block = buf
+                .chunks(bytes)
+                .map(|entry| BigEndian::read_uint(entry, bytes))
+                .collect();
+            self.block_cache.insert(block_offset, block);
+        }
+        Ok(self.block_cache.get(block_offset).unwrap())
+    }
+
+    pub fn get_refcount(&mut self, offset: u64) -> Result<u64> {
+        let (table_index, block_index) = self.get_refcount_index(offset);
+        if table_index >= self.refcount_table.len() as u64 {
+            return Ok(0);
+        }
+        let block_offset = self.refcount_table[table_index as usize] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.load_refcount_block(block_offset)?[block_index as usize])
+    }
+
+    fn set_refcount(&mut self, block_offset: u64, block_index: u64, value: u64) -> Result<()> {
+        self.load_refcount_block(block_offset)?[block_index as usize] = value;
+        let bytes = self.refcount_bytes as usize;
+        let mut buf = vec![0_u8; bytes];
+        BigEndian::write_uint(&mut buf, value, bytes);
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(block_offset + block_index * bytes as u64, &buf)
+    }
+
+    /// Add `added` to the refcount of `nb_clusters` clusters from `offset`.
+    pub fn update_refcount(&mut self, offset: u64, nb_clusters: u64, added: i64) -> Result<()> {
+        for i in 0..nb_clusters {
+            let cluster_offset = offset + (i << self.cluster_bits);
+            let (table_index, block_index) = self.get_refcount_index(cluster_offset);
+            let block_offset = self.get_or_alloc_refcount_block(table_index)?;
+            let old = self.load_refcount_block(block_offset)?[block_index as usize];
+            let new = if added >= 0 {
+                old.checked_add(added as u64)
+                    .filter(|v| *v <= self.refcount_max)
+            } else {
+                old.checked_sub(added.unsigned_abs())
+            };
+            let new = new.with_context(|| {
+                format!(
+                    "Invalid refcount update {} for cluster {:#x} with refcount {}",
+                    added, cluster_offset, old
+                )
+            })?;
+            self.set_refcount(block_offset, block_index, new)?;
+        }
+        Ok(())
+    }
+
+    /// Reserve clusters at the end of image without setting their refcount.
+    pub fn alloc_cluster_noref(&mut self, nb_clusters: u64) -> u64 {
+        let offset = self.free_cluster_offset;
+        self.free_cluster_offset += nb_clusters << self.cluster_bits;
+        offset
+    }
+
+    /// Allocate clusters of `size` bytes with refcount 1, the offset is returned.
+    pub fn alloc_cluster(&mut self, size: u64) -> Result<u64> {
+        let nb_clusters = round_up(size, self.cluster_size)
+            .with_context(|| format!("Invalid allocation size {}", size))?
+            >> self.cluster_bits;
+        let offset = self.alloc_cluster_noref(nb_clusters);
+        self.update_refcount(offset, nb_clusters, 1)?;
+        Ok(offset)
+    }
+
+    fn get_or_alloc_refcount_block(&mut self, table_index: u64) -> Result<u64> {
+        if table_index >= self.refcount_table.len() as u64 {
+            self.extend_refcount_table(table_index + 1)?;
+        }
+        let block_offset = self.refcount_table[table_index as usize] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset != 0 {
+            return Ok(block_offset);
+        }
+
+        let block_offset = self.alloc_cluster_noref(1);
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(block_offset, &vec![0_u8; self.cluster_size as usize])?;
+        self.block_cache
+            .insert(block_offset, vec![0; self.refcount_block_size as usize]);
+        self.refcount_table[table_index as usize] = block_offset;
+        self.sync_aio.borrow_mut().write_buffer(
+            self.refcount_table_offset + table_index * ENTRY_SIZE,
+            &block_offset.to_be_bytes(),
+        )?;
+        // The refcount block may describe itself, so set its refcount at last.
+        self.update_refcount(block_offset, 1, 1)?;
+        Ok(block_offset)
+    }
+
+    /// Move the refcount table to a bigger one at the end of image.
+    fn extend_refcount_table(&mut self, min_entries: u64) -> Result<()> {
+        let entries = std::cmp::max(min_entries, self.refcount_table.len() as u64) * 2;
+        let new_clusters = round_up(entries * ENTRY_SIZE, self.cluster_size)
+            .with_context(|| "Invalid size of refcount table")?
+            >> self.cluster_bits;
+        let new_offset = self.alloc_cluster_noref(new_clusters);
+        let mut new_table = self.refcount_table.clone();
+        new_table.resize((new_clusters * self.cluster_size / ENTRY_SIZE) as usize, 0);
+        self.sync_aio
+            .borrow_mut()
+            .write_ctrl_cluster(new_offset, &new_table)?;
+
+        let mut buf = [0_u8; 12];
+        BigEndian::write_u64(&mut buf[0..8], new_offset);
+        BigEndian::write_u32(&mut buf[8..12], new_clusters as u32);
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(REFCOUNT_TABLE_HEADER_OFFSET, &buf)?;
+
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = self.refcount_table_clusters as u64;
+        self.refcount_table = new_table;
+        self.refcount_table_offset = new_offset;
+        self.refcount_table_clusters = new_clusters as u32;
+        self.update_refcount(new_offset, new_clusters, 1)?;
+        self.update_refcount(old_offset, old_clusters, -1)
+    }
+}
@@ -0,0 +1,277 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder};
+
+use super::refcount::RefCount;
+use super::SyncAioInfo;
+use crate::SnapshotInfo;
+use util::num_ops::round_up;
+
+/// Max number of snapshots in image.
+pub const QCOW2_MAX_SNAPSHOTS: usize = 65536;
+/// Length of the fixed part of snapshot table entry.
+const SNAPSHOT_ENTRY_FIXED_LEN: usize = 40;
+/// Length of the extra data written for new snapshot: the large size of
+/// vm state and the size of disk.
+const SNAPSHOT_EXTRA_DATA_LEN: usize = 16;
+/// Max length of the extra data of snapshot table entry.
+const SNAPSHOT_MAX_EXTRA_DATA_LEN: u32 = 1024;
+/// Offset of the snapshot fields in header.
+const SNAPSHOT_HEADER_OFFSET: u64 = 60;
+
+/// Entry of snapshot table, which records the L1 table of disk at the time
+/// the snapshot was taken.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QcowSnapshot {
+    pub l1_table_offset: u64,
+    pub l1_size: u32,
+    pub id: String,
+    pub name: String,
+    pub date_sec: u32,
+    pub date_nsec: u32,
+    pub vm_clock_nsec: u64,
+    pub vm_state_size: u64,
+    pub disk_size: u64,
+    /// Extra data of the entry, the fields known are updated when saved.
+    pub extra_data: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    /// Get the length of the entry which is padded to 8 bytes.
+    fn entry_len(&self) -> usize {
+        let len =
+            SNAPSHOT_ENTRY_FIXED_LEN + self.extra_data.len() + self.id.len() + self.name.len();
+        round_up(len as u64, 8).unwrap() as usize
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        let mut extra_data = self.extra_data.clone();
+        if extra_data.len() < SNAPSHOT_EXTRA_DATA_LEN {
+            extra_data.resize(SNAPSHOT_EXTRA_DATA_LEN, 0);
+        }
+        BigEndian::write_u64(&mut extra_data[0..8], self.vm_state_size);
+        BigEndian::write_u64(&mut extra_data[8..16], self.disk_size);
+
+        let mut buf = vec![0_u8; SNAPSHOT_ENTRY_FIXED_LEN];
+        BigEndian::write_u64(&mut buf[0..8], self.l1_table_offset);
+        BigEndian::write_u32(&mut buf[8..12], self.l1_size);
+        BigEndian::write_u16(&mut buf[12..14], self.id.len() as u16);
+        BigEndian::write_u16(&mut buf[14..16], self.name.len() as u16);
+        BigEndian::write_u32(&mut buf[16..20], self.date_sec);
+        BigEndian::write_u32(&mut buf[20..24], self.date_nsec);
+        BigEndian::write_u64(&mut buf[24..32], self.vm_clock_nsec);
+        // The 32 bits vm state size is obsoleted by the one in extra data.
+        BigEndian::write_u32(
+            &mut buf[32..36],
+            self.vm_state_size.min(u32::MAX as u64) as u32,
+        );
+        BigEndian::write_u32(&mut buf[36..40], extra_data.len() as u32);
+        buf.extend_from_slice(&extra_data);
+        buf.extend_from_slice(self.id.as_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.resize(round_up(buf.len() as u64, 8).unwrap() as usize, 0);
+        buf
+    }
+
+    pub fn get_info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            vm_state_size: self.vm_state_size,
+            date_sec: self.date_sec,
+            date_nsec: self.date_nsec,
+            vm_clock_nsec: self.vm_clock_nsec,
+        }
+    }
+}
+
+/// The snapshot table of image.
+pub struct InternalSnapshot {
+    sync_aio: Rc<RefCell<SyncAioInfo>>,
+    pub snapshots: Vec<QcowSnapshot>,
+    pub snapshot_table_offset: u64,
+    /// Length of the snapshot table in bytes.
+    snapshot_table_len: u64,
+    cluster_size: u64,
+}
+
+impl InternalSnapshot {
+    pub fn new(sync_aio: Rc<RefCell<SyncAioInfo>>) -> Self {
+        InternalSnapshot {
+            sync_aio,
+            snapshots: Vec::new(),
+            snapshot_table_offset: 0,
+            snapshot_table_len: 0,
+            cluster_size: 0,
+        }
+    }
+
+    pub fn load_snapshot_table(
+        &mut self,
+        offset: u64,
+        nb_snapshots: u32,
+        cluster_size: u64,
+    ) -> Result<()> {
+        self.cluster_size = cluster_size;
+        self.snapshots.clear();
+        self.snapshot_table_offset = offset;
+        self.snapshot_table_len = 0;
+        if nb_snapshots as usize > QCOW2_MAX_SNAPSHOTS {
+            bail!("Too many snapshots {}", nb_snapshots);
+        }
+        if nb_snapshots == 0 {
+            return Ok(());
+        }
+        if offset & (cluster_size - 1) != 0 {
+            bail!(
+                "Snapshot table offset {:#x} is not aligned to cluster",
+                offset
+            );
+        }
+
+        let mut pos = offset;
+        for _ in 0..nb_snapshots {
+            let mut buf = vec![0_u8; SNAPSHOT_ENTRY_FIXED_LEN];
+            self.sync_aio.borrow_mut().read_buffer(pos, &mut buf)?;
+            let id_len = BigEndian::read_u16(&buf[12..14]) as usize;
+            let name_len = BigEndian::read_u16(&buf[14..16]) as usize;
+            let extra_len = BigEndian::read_u32(&buf[36..40]);
+            if extra_len > SNAPSHOT_MAX_EXTRA_DATA_LEN {
+                bail!("Invalid extra data len {} of snapshot", extra_len);
+            }
+            let mut snapshot = QcowSnapshot {
+                l1_table_offset: BigEndian::read_u64(&buf[0..8]),
+                l1_size: BigEndian::read_u32(&buf[8..12]),
+                date_sec: BigEndian::read_u32(&buf[16..20]),
+                date_nsec: BigEndian::read_u32(&buf[20..24]),
+                vm_clock_nsec: BigEndian::read_u64(&buf[24..32]),
+                vm_state_size: BigEndian::read_u32(&buf[32..36]) as u64,
+                ..Default::default()
+            };
+
+            let mut data = vec![0_u8; extra_len as usize + id_len + name_len];
+            self.sync_aio
+                .borrow_mut()
+                .read_buffer(pos + SNAPSHOT_ENTRY_FIXED_LEN as u64, &mut data)?;
+            let (extra_data, strings) = data.split_at(extra_len as usize);
+            if extra_data.len() >= 8 {
+                snapshot.vm_state_size = BigEndian::read_u64(&extra_data[0..8]);
+            }
+            if extra_data.len() >= 16 {
+                snapshot.disk_size = BigEndian::read_u64(&extra_data[8..16]);
+            }
+            snapshot.extra_data = extra_data.to_vec();
+            snapshot.id = String::from_utf8(strings[..id_len].to_vec())
+                .with_context(|| "Invalid snapshot id")?;
+            snapshot.name = String::from_utf8(strings[id_len..].to_vec())
+                .with_context(|| "Invalid snapshot name")?;
+            pos += snapshot.entry_len() as u64;
+            self.snapshots.push(snapshot);
+        }
+        self.snapshot_table_len = pos - offset;
+        Ok(())
+    }
+
+    /// Find the snapshot which matches both `id` and `name` if they are specified.
+    pub fn find_snapshot(&self, id: Option<&str>, name: Option<&str>) -> Option<usize> {
+        if id.is_none() && name.is_none() {
+            return None;
+        }
+        self.snapshots.iter().position(|s| {
+            (id.is_none() || id == Some(s.id.as_str()))
+                && (name.is_none() || name == Some(s.name.as_str()))
+        })
+    }
+
+    /// Get an unused id for the new snapshot.
+    pub fn new_snapshot_id(&self) -> String {
+        let max_id = self
+            .snapshots
+            .iter()
+            .filter_map(|s| s.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0);
+        (max_id + 1).to_string()
+    }
+
+    /// Write the snapshot table to new clusters and update the header, the
+    /// clusters of the old table are freed.
+    pub fn save_snapshot_table(&mut self, refcount: &mut RefCount) -> Result<()> {
+        let mut buf = Vec::new();
+        for snapshot in self.snapshots.iter() {
+            buf.extend_from_slice(&snapshot.to_vec());
+        }
+        let new_offset = if buf.is_empty() {
+            0
+        } else {
+            let offset = refcount.alloc_cluster(buf.len() as u64)?;
+            self.sync_aio.borrow_mut().write_buffer(offset, &buf)?;
+            offset
+        };
+
+        let mut header_buf = [0_u8; 12];
+        BigEndian::write_u32(&mut header_buf[0..4], self.snapshots.len() as u32);
+        BigEndian::write_u64(&mut header_buf[4..12], new_offset);
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(SNAPSHOT_HEADER_OFFSET, &header_buf)?;
+
+        let old_offset = self.snapshot_table_offset;
+        let old_clusters = round_up(self.snapshot_table_len, self.cluster_size)
+            .with_context(|| "Invalid size of snapshot table")?
+            / self.cluster_size;
+        self.snapshot_table_offset = new_offset;
+        self.snapshot_table_len = buf.len() as u64;
+        if old_offset != 0 && old_clusters != 0 {
+            refcount.update_refcount(old_offset, old_clusters, -1)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_entry_convert() {
+        let snapshot = QcowSnapshot {
+            l1_table_offset: 0x30000,
+            l1_size: 2,
+            id: "1".to_string(),
+            name: "snap0".to_string(),
+            date_sec: 100,
+            date_nsec: 200,
+            vm_clock_nsec: 300,
+            vm_state_size: 0,
+            disk_size: 1 << 30,
+            extra_data: Vec::new(),
+        };
+        let buf = snapshot.to_vec();
+        assert_eq!(buf.len() % 8, 0);
+        assert_eq!(BigEndian::read_u64(&buf[0..8]), 0x30000);
+        assert_eq!(BigEndian::read_u16(&buf[12..14]), 1);
+        assert_eq!(BigEndian::read_u16(&buf[14..16]), 5);
+        assert_eq!(
+            BigEndian::read_u32(&buf[36..40]),
+            SNAPSHOT_EXTRA_DATA_LEN as u32
+        );
+        assert_eq!(BigEndian::read_u64(&buf[48..56]), 1 << 30);
+        let strings = SNAPSHOT_ENTRY_FIXED_LEN + SNAPSHOT_EXTRA_DATA_LEN;
+        assert_eq!(&buf[strings..strings + 6], b"1snap0");
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use anyhow::{bail, Context, Result};
+
+use super::cache::Qcow2Cache;
+use super::header::QcowHeader;
+use super::{SyncAioInfo, ENTRY_SIZE, L1_TABLE_OFFSET_MASK};
+
+/// Max number of L2 tables in cache.
+const MAX_L2_CACHE_SIZE: usize = 32;
+
+/// The two-level table which maps the guest offset to the host cluster.
+pub struct Qcow2Table {
+    sync_aio: Rc<RefCell<SyncAioInfo>>,
+    cluster_bits: u64,
+    /// Number of bits of the entry index in L2 table.
+    l2_bits: u64,
+    /// Number of entries in L2 table.
+    l2_size: u64,
+    pub l1_table: Vec<u64>,
+    pub l1_table_offset: u64,
+    pub l1_size: u32,
+    l2_cache: Qcow2Cache,
+}
+
+impl Qcow2Table {
+    pub fn new(sync_aio: Rc<RefCell<SyncAioInfo>>) -> Self {
+        Qcow2Table {
+            sync_aio,
+            cluster_bits: 0,
+            l2_bits: 0,
+            l2_size: 0,
+            l1_table: Vec::new(),
+            l1_table_offset: 0,
+            l1_size: 0,
+            l2_cache: Qcow2Cache::new(MAX_L2_CACHE_SIZE),
+        }
+    }
+
+    pub fn init_table(&mut self, header: &QcowHeader) -> Result<()> {
+        self.cluster_bits = header.cluster_bits as u64;
+        self.l2_bits = header.cluster_bits as u64 - ENTRY_SIZE.trailing_zeros() as u64;
+        self.l2_size = 1 << self.l2_bits;
+        let max_l1_size = header
+            .size
+            .checked_add((1 << (self.cluster_bits + self.l2_bits)) - 1)
+            .with_context(|| format!("Invalid disk size {}", header.size))?
+            >> (self.cluster_bits + self.l2_bits);
+        if (header.l1_size as u64) < max_l1_size {
+            bail!(
+                "L1 table size {} is too small for disk size {}",
+                header.l1_size,
+                header.size
+            );
+        }
+        self.load_l1_table(header.l1_table_offset, header.l1_size)
+    }
+
+    /// Read the L1 table from image, the cached L2 tables are dropped.
+    pub fn load_l1_table(&mut self, l1_table_offset: u64, l1_size: u32) -> Result<()> {
+        self.l1_table = self
+            .sync_aio
+            .borrow_mut()
+            .read_ctrl_cluster(l1_table_offset, l1_size as u64)?;
+        self.l1_table_offset = l1_table_offset;
+        self.l1_size = l1_size;
+        self.l2_cache.clear();
+        Ok(())
+    }
+
+    pub fn l2_size(&self) -> u64 {
+        self.l2_size
+    }
+
+    /// Get the index of L1 table and L2 table for the guest offset.
+    pub fn get_table_index(&self, guest_offset: u64) -> (u64, u64) {
+        let l1_index = guest_offset >> (self.cluster_bits + self.l2_bits);
+        let l2_index = (guest_offset >> self.cluster_bits) & (self.l2_size - 1);
+        (l1_index, l2_index)
+    }
+
+    pub fn get_l1_entry(&self, l1_index: u64) -> Result<u64> {
+        if l1_index >= self.l1_size as u64 {
+            bail!(
+                "L1 index {} over the size of L1 table {}",
+                l1_index,
+                self.l1_size
+            );
+        }
+        Ok(self.l1_table[l1_index as usize])
+    }
+
+    pub fn set_l1_entry(&mut self, l1_index: u64, entry: u64) -> Result<()> {
+        self.get_l1_entry(l1_index)?;
+        self.l1_table[l1_index as usize] = entry;
+        let offset = self.l1_table_offset + l1_index * ENTRY_SIZE;
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(offset, &entry.to_be_bytes())
+    }
+
+    fn load_l2_table(&mut self, l2_table_offset: u64) -> Result<&mut Vec<u64>> {
+        if self.l2_cache.get(l2_table_offset).is_none() {
+            let table = self
+                .sync_aio
+                .borrow_mut()
+                .read_ctrl_cluster(l2_table_offset, self.l2_size)?;
+            self.l2_cache.insert(l2_table_offset, table);
+        }
+        Ok(self.l2_cache.get(l2_table_offset).unwrap())
+    }
+
+    pub fn get_l2_table(&mut self, l2_table_offset: u64) -> Result<Vec<u64>> {
+        Ok(self.load_l2_table(l2_table_offset)?.clone())
+    }
+
+    pub fn get_l2_entry(&mut self, l2_table_offset: u64, l2_index: u64) -> Result<u64> {
+        Ok(self.load_l2_table(l2_table_offset)?[l2_index as usize])
+    }
+
+    pub fn set_l2_entry(&mut self, l2_table_offset: u64, l2_index: u64, entry: u64) -> Result<()> {
+        self.load_l2_table(l2_table_offset)?[l2_index as usize] = entry;
+        let offset = l2_table_offset + l2_index * ENTRY_SIZE;
+        self.sync_aio
+            .borrow_mut()
+            .write_buffer(offset, &entry.to_be_bytes())
+    }
+
+    /// Write a whole L2 table to image.
+    pub fn write_l2_table(&mut self, l2_table_offset: u64, table: Vec<u64>) -> Result<()> {
+        self.sync_aio
+            .borrow_mut()
+            .write_ctrl_cluster(l2_table_offset, &table)?;
+        self.l2_cache.insert(l2_table_offset, table);
+        Ok(())
+    }
+
+    /// Get the L2 entry of the guest offset, zero is returned if unallocated.
+    pub fn get_entry(&mut self, guest_offset: u64) -> Result<u64> {
+        let (l1_index, l2_index) = self.get_table_index(guest_offset);
+        let l2_table_offset = self.get_l1_entry(l1_index)? & L1_TABLE_OFFSET_MASK;
+        if l2_table_offset == 0 {
+            return Ok(0);
+        }
+        self.get_l2_entry(l2_table_offset, l2_index)
+    }
+
+    pub fn drop_l2_cache(&mut self, l2_table_offset: u64) {
+        self.l2_cache.remove(l2_table_offset);
+    }
+}
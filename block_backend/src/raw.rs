@@ -0,0 +1,182 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+
+use crate::dirty_bitmap::DirtyBitmap;
+use crate::file::{CombineRequest, FileDriver};
+use crate::qcow2::SyncAioInfo;
+use crate::{BlockDriverOps, BlockIoErrorCallback, BlockProperty, SnapshotInfo};
+use machine_manager::config::DiskFormat;
+use util::aio::{get_iov_size, Aio, Iovec};
+
+/// Driver of raw image, the data of disk is the same as the image file.
+pub struct RawDriver<T: Clone + 'static> {
+    driver: FileDriver<T>,
+    sync_aio: SyncAioInfo,
+}
+
+impl<T: Clone + 'static> RawDriver<T> {
+    pub fn new(file: File, aio: Aio<T>, prop: BlockProperty) -> Result<Self> {
+        let sync_aio = SyncAioInfo::new(file.as_raw_fd(), prop.clone())?;
+        Ok(Self {
+            driver: FileDriver::new(file, aio, prop),
+            sync_aio,
+        })
+    }
+}
+
+impl<T: Clone + 'static> BlockDriverOps<T> for RawDriver<T> {
+    fn disk_size(&mut self) -> Result<u64> {
+        self.driver.disk_size()
+    }
+
+    fn resize(&mut self, size: u64) -> Result<()> {
+        let disk_size = self.driver.disk_size()?;
+        if size < disk_size {
+            bail!(
+                "Shrinking raw image from {} to {} is not supported",
+                disk_size,
+                size
+            );
+        }
+        self.driver.truncate(size)?;
+        self.driver.resize_dirty_bitmaps(size)
+    }
+
+    fn read_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.driver.read_vectored(
+            vec![CombineRequest::new(iovec, offset as u64, nbytes)],
+            completecb,
+        )
+    }
+
+    fn write_vectored(&mut self, iovec: Vec<Iovec>, offset: usize, completecb: T) -> Result<()> {
+        let nbytes = get_iov_size(&iovec);
+        self.driver.set_dirty(offset as u64, nbytes)?;
+        self.driver.write_vectored(
+            vec![CombineRequest::new(iovec, offset as u64, nbytes)],
+            completecb,
+        )
+    }
+
+    fn discard(&mut self, offset: usize, nbytes: u64, completecb: T) -> Result<()> {
+        self.driver.set_dirty(offset as u64, nbytes)?;
+        self.driver.discard(
+            vec![CombineRequest::new(Vec::new(), offset as u64, nbytes)],
+            completecb,
+        )
+    }
+
+    fn write_zeroes(
+        &mut self,
+        offset: usize,
+        nbytes: u64,
+        completecb: T,
+        unmap: bool,
+    ) -> Result<()> {
+        self.driver.set_dirty(offset as u64, nbytes)?;
+        self.driver.write_zeroes(
+            vec![CombineRequest::new(Vec::new(), offset as u64, nbytes)],
+            completecb,
+            unmap,
+        )
+    }
+
+    fn datasync(&mut self, completecb: T) -> Result<()> {
+        self.driver.datasync(completecb)
+    }
+
+    fn flush_request(&mut self) -> Result<()> {
+        self.driver.flush_request()
+    }
+
+    fn drain_request(&mut self) -> Result<()> {
+        self.driver.drain_request()
+    }
+
+    fn read_sync(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.sync_aio.read_buffer(offset, buf)
+    }
+
+    fn write_sync(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.driver.set_dirty(offset, buf.len() as u64)?;
+        self.sync_aio.write_buffer(offset, buf)
+    }
+
+    fn flush_sync(&mut self) -> Result<()> {
+        self.driver.flush_sync()
+    }
+
+    fn is_allocated(&mut self, offset: u64, len: u64) -> Result<(bool, u64)> {
+        let disk_size = self.driver.disk_size()?;
+        Ok((true, disk_size.saturating_sub(offset).min(len)))
+    }
+
+    fn copy_backing_data(&mut self, _offset: u64, _len: u64) -> Result<()> {
+        // All the data is in the image itself.
+        Ok(())
+    }
+
+    fn drop_backing_file(&mut self) -> Result<()> {
+        bail!("Drive {} has no backing file", self.driver.block_prop.id)
+    }
+
+    fn backing_file(&self) -> Option<(String, DiskFormat)> {
+        None
+    }
+
+    fn add_dirty_bitmap(&mut self, bitmap: Arc<Mutex<DirtyBitmap>>) -> Result<()> {
+        self.driver.add_dirty_bitmap(bitmap)
+    }
+
+    fn get_dirty_bitmap(&self, name: &str) -> Option<Arc<Mutex<DirtyBitmap>>> {
+        self.driver.get_dirty_bitmap(name)
+    }
+
+    fn named_dirty_bitmaps(&self) -> Vec<Arc<Mutex<DirtyBitmap>>> {
+        self.driver.named_dirty_bitmaps()
+    }
+
+    fn remove_dirty_bitmap(&mut self, bitmap: &Arc<Mutex<DirtyBitmap>>) {
+        self.driver.remove_dirty_bitmap(bitmap);
+    }
+
+    fn create_snapshot(&mut self, _name: &str) -> Result<()> {
+        bail!("Internal snapshot is not supported by raw image");
+    }
+
+    fn delete_snapshot(&mut self, _id: Option<&str>, _name: Option<&str>) -> Result<SnapshotInfo> {
+        bail!("Internal snapshot is not supported by raw image");
+    }
+
+    fn register_io_event(
+        &mut self,
+        device_broken: Arc<AtomicBool>,
+        error_cb: BlockIoErrorCallback,
+    ) -> Result<()> {
+        self.driver.register_io_event(device_broken, error_cb)
+    }
+
+    fn unregister_io_event(&mut self) -> Result<()> {
+        self.driver.unregister_io_event()
+    }
+}
+
+// SAFETY: The sync aio context is only used with the lock of driver held.
+unsafe impl<T: Clone + 'static> Send for RawDriver<T> {}
@@ -0,0 +1,145 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use machine_manager::qmp::qmp_schema::BlockDeviceStats;
+
+/// Kinds of the IO accounted by block device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockAcctType {
+    Read = 0,
+    Write = 1,
+    Flush = 2,
+    Unmap = 3,
+}
+
+const BLOCK_ACCT_TYPE_NUM: usize = 4;
+
+#[derive(Default)]
+struct BlockAcctCounters {
+    bytes: [u64; BLOCK_ACCT_TYPE_NUM],
+    ops: [u64; BLOCK_ACCT_TYPE_NUM],
+    failed_ops: [u64; BLOCK_ACCT_TYPE_NUM],
+    total_time_ns: [u64; BLOCK_ACCT_TYPE_NUM],
+    /// The time that the last IO completed.
+    last_access: Option<Instant>,
+}
+
+/// Statistics of the IO of block device, which are shared by the IO handlers
+/// of device and queried by QMP.
+#[derive(Default)]
+pub struct BlockAcctStats {
+    counters: Mutex<BlockAcctCounters>,
+}
+
+impl BlockAcctStats {
+    fn account(&self, cookie: &BlockAcctCookie, failed: bool) {
+        let now = Instant::now();
+        let idx = cookie.acct_type as usize;
+        let mut counters = self.counters.lock().unwrap();
+        if failed {
+            counters.failed_ops[idx] += 1;
+        } else {
+            counters.bytes[idx] += cookie.bytes;
+            counters.ops[idx] += 1;
+            counters.total_time_ns[idx] +=
+                now.saturating_duration_since(cookie.start).as_nanos() as u64;
+        }
+        counters.last_access = Some(now);
+    }
+
+    /// Get the statistics for QMP.
+    pub fn query(&self) -> BlockDeviceStats {
+        let counters = self.counters.lock().unwrap();
+        let read = BlockAcctType::Read as usize;
+        let write = BlockAcctType::Write as usize;
+        let flush = BlockAcctType::Flush as usize;
+        let unmap = BlockAcctType::Unmap as usize;
+        BlockDeviceStats {
+            rd_bytes: counters.bytes[read],
+            wr_bytes: counters.bytes[write],
+            unmap_bytes: counters.bytes[unmap],
+            rd_operations: counters.ops[read],
+            wr_operations: counters.ops[write],
+            flush_operations: counters.ops[flush],
+            unmap_operations: counters.ops[unmap],
+            rd_total_time_ns: counters.total_time_ns[read],
+            wr_total_time_ns: counters.total_time_ns[write],
+            flush_total_time_ns: counters.total_time_ns[flush],
+            unmap_total_time_ns: counters.total_time_ns[unmap],
+            failed_rd_operations: counters.failed_ops[read],
+            failed_wr_operations: counters.failed_ops[write],
+            failed_flush_operations: counters.failed_ops[flush],
+            failed_unmap_operations: counters.failed_ops[unmap],
+            idle_time_ns: counters
+                .last_access
+                .map(|last| Instant::now().saturating_duration_since(last).as_nanos() as u64),
+        }
+    }
+}
+
+/// Record of the IO in flight, it is accounted to the statistics when the IO
+/// is done.
+#[derive(Clone)]
+pub struct BlockAcctCookie {
+    stats: Arc<BlockAcctStats>,
+    acct_type: BlockAcctType,
+    bytes: u64,
+    start: Instant,
+}
+
+impl BlockAcctCookie {
+    pub fn new(stats: &Arc<BlockAcctStats>, acct_type: BlockAcctType, bytes: u64) -> Self {
+        BlockAcctCookie {
+            stats: stats.clone(),
+            acct_type,
+            bytes,
+            start: Instant::now(),
+        }
+    }
+
+    /// Account the IO which is done.
+    pub fn done(&self, failed: bool) {
+        self.stats.account(self, failed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_acct_stats() {
+        let stats = Arc::new(BlockAcctStats::default());
+        assert_eq!(stats.query(), BlockDeviceStats::default());
+
+        BlockAcctCookie::new(&stats, BlockAcctType::Read, 4096).done(false);
+        BlockAcctCookie::new(&stats, BlockAcctType::Read, 512).done(false);
+        BlockAcctCookie::new(&stats, BlockAcctType::Write, 1024).done(true);
+        BlockAcctCookie::new(&stats, BlockAcctType::Flush, 0).done(false);
+        BlockAcctCookie::new(&stats, BlockAcctType::Unmap, 1 << 20).done(false);
+
+        let result = stats.query();
+        assert_eq!(result.rd_bytes, 4608);
+        assert_eq!(result.rd_operations, 2);
+        // The bytes of failed IO are not accounted.
+        assert_eq!(result.wr_bytes, 0);
+        assert_eq!(result.wr_operations, 0);
+        assert_eq!(result.failed_wr_operations, 1);
+        assert_eq!(result.flush_operations, 1);
+        assert_eq!(result.unmap_bytes, 1 << 20);
+        assert_eq!(result.unmap_operations, 1);
+        assert!(result.idle_time_ns.is_some());
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+
+use crate::job::BlockJobOps;
+use crate::BlockDriverOps;
+
+/// Job to copy the data of backing chain into the image used by device, the
+/// image is detached from its backing file when all the data is copied. The
+/// areas written by guest during the job are allocated in the image, so they
+/// are skipped.
+pub struct StreamJob<T: Clone + 'static> {
+    image: Arc<Mutex<dyn BlockDriverOps<T>>>,
+    disk_size: u64,
+    /// Offset of disk to look for the next area not allocated.
+    cursor: u64,
+}
+
+impl<T: Clone + 'static> StreamJob<T> {
+    /// Create the job to stream the backing file into `image`.
+    pub fn new(image: Arc<Mutex<dyn BlockDriverOps<T>>>) -> Result<Self> {
+        let mut locked_image = image.lock().unwrap();
+        if locked_image.backing_file().is_none() {
+            bail!("Image has no backing file to stream");
+        }
+        let disk_size = locked_image.disk_size()?;
+        drop(locked_image);
+        Ok(Self {
+            image,
+            disk_size,
+            cursor: 0,
+        })
+    }
+}
+
+impl<T: Clone + 'static> BlockJobOps for StreamJob<T> {
+    fn run_step(&mut self, max_len: u64) -> Result<(u64, bool)> {
+        let mut locked_image = self.image.lock().unwrap();
+        // The disk may be grown during the job.
+        self.disk_size = locked_image.disk_size()?;
+        if self.cursor >= self.disk_size {
+            return Ok((0, true));
+        }
+        // The clusters allocated by in-flight writes must not be overwritten.
+        locked_image.drain_request()?;
+        let len = (self.disk_size - self.cursor).min(max_len);
+        let (allocated, len) = locked_image.is_allocated(self.cursor, len)?;
+        if !allocated {
+            locked_image.copy_backing_data(self.cursor, len)?;
+        }
+        self.cursor += len;
+        // Only the copied data counts for the speed of job.
+        Ok((if allocated { 0 } else { len }, false))
+    }
+
+    fn progress(&self) -> (u64, u64) {
+        (self.cursor.min(self.disk_size), self.disk_size)
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        let mut locked_image = self.image.lock().unwrap();
+        locked_image.flush_sync()?;
+        locked_image.drop_backing_file()?;
+        locked_image.flush_sync()
+    }
+
+    fn abort(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use super::*;
+    use crate::test_helper::{
+        create_backing_chain, open_backend, open_backend_with_prop, prop, write_data,
+    };
+    use machine_manager::config::DiskFormat;
+    use util::aio::Iovec;
+
+    fn create_images(base_path: &str, top_path: &str, disk_size: u64) {
+        create_backing_chain(base_path, top_path, disk_size);
+        let base = open_backend(base_path, DiskFormat::Raw);
+        write_data(&base, 2 << 20, &[0x22_u8; 4096]);
+    }
+
+    #[test]
+    fn test_stream_job() {
+        let base_path = "/tmp/test_stream_job_base.raw";
+        let top_path = "/tmp/test_stream_job.qcow2";
+        let disk_size = 4 << 20;
+        create_images(base_path, top_path, disk_size);
+        let top = open_backend(top_path, DiskFormat::Qcow2);
+        write_data(&top, 4096, &[0x33_u8; 512]);
+
+        let mut job = StreamJob::new(top.clone()).unwrap();
+        assert_eq!(job.progress(), (0, disk_size));
+        // The cluster written by guest is skipped.
+        let cluster_size = 1 << 16;
+        assert_eq!(job.run_step(1 << 20).unwrap(), (0, false));
+        assert_eq!(job.progress(), (cluster_size, disk_size));
+        while !job.run_step(1 << 20).unwrap().1 {}
+        job.complete().unwrap();
+        assert!(top.lock().unwrap().backing_file().is_none());
+        drop(top);
+
+        // The image reads the same data without the backing file.
+        remove_file(base_path).unwrap();
+        let top = open_backend(top_path, DiskFormat::Qcow2);
+        let mut locked_top = top.lock().unwrap();
+        assert!(locked_top.backing_file().is_none());
+        assert_eq!(
+            locked_top.is_allocated(0, disk_size).unwrap(),
+            (true, disk_size)
+        );
+        let mut buf = vec![0_u8; disk_size as usize];
+        locked_top.read_sync(0, &mut buf).unwrap();
+        assert_eq!(&buf[..4096], &[0x11_u8; 4096]);
+        assert_eq!(&buf[4096..4608], &[0x33_u8; 512]);
+        assert_eq!(&buf[4608..8192], &[0x11_u8; 3584]);
+        assert_eq!(&buf[2 << 20..(2 << 20) + 4096], &[0x22_u8; 4096]);
+        assert!(buf[(2 << 20) + 4096..].iter().all(|b| *b == 0));
+        drop(locked_top);
+
+        remove_file(top_path).unwrap();
+    }
+
+    #[test]
+    fn test_copy_on_read() {
+        let base_path = "/tmp/test_copy_on_read_base.raw";
+        let top_path = "/tmp/test_copy_on_read.qcow2";
+        let disk_size = 4 << 20;
+        create_images(base_path, top_path, disk_size);
+        let mut top_prop = prop(DiskFormat::Qcow2);
+        top_prop.copy_on_read = true;
+        let top = open_backend_with_prop(top_path, top_prop);
+
+        let mut buf = vec![0_u8; 512];
+        let iov = vec![Iovec::new(buf.as_mut_ptr() as u64, buf.len() as u64)];
+        top.lock()
+            .unwrap()
+            .read_vectored(iov, (2 << 20) + 512, ())
+            .unwrap();
+        assert_eq!(buf, vec![0x22_u8; 512]);
+
+        // The whole cluster read is copied into the image.
+        let mut locked_top = top.lock().unwrap();
+        assert_eq!(
+            locked_top.is_allocated(0, disk_size).unwrap(),
+            (false, 2 << 20)
+        );
+        assert_eq!(
+            locked_top.is_allocated(2 << 20, 2 << 20).unwrap(),
+            (true, 1 << 16)
+        );
+        let mut buf = vec![0_u8; 8192];
+        locked_top.read_sync(2 << 20, &mut buf).unwrap();
+        assert_eq!(&buf[..4096], &[0x22_u8; 4096]);
+        assert_eq!(&buf[4096..], &[0_u8; 4096]);
+        drop(locked_top);
+
+        remove_file(base_path).unwrap();
+        remove_file(top_path).unwrap();
+    }
+}
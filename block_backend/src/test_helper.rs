@@ -0,0 +1,132 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{remove_file, File};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::dirty_bitmap::DEFAULT_GRANULARITY;
+use crate::job::{complete_func, BlockJobOps};
+use crate::qcow2::{create_qcow2_image, CreateOptions};
+use crate::{create_block_backend, BlockDriverOps, BlockProperty};
+use machine_manager::config::DiskFormat;
+use util::aio::{Aio, AioEngine, Iovec};
+use util::file::open_file;
+
+pub type TestBackend = Arc<Mutex<dyn BlockDriverOps<()>>>;
+
+pub fn prop(format: DiskFormat) -> BlockProperty {
+    BlockProperty {
+        id: "drive0".to_string(),
+        format,
+        iothread: None,
+        direct: false,
+        req_align: 1,
+        buf_align: 1,
+        copy_on_read: false,
+    }
+}
+
+pub fn open_backend_with_prop(path: &str, prop: BlockProperty) -> TestBackend {
+    let file = open_file(path, false, false).unwrap();
+    let aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+    create_block_backend(file, aio, prop).unwrap()
+}
+
+pub fn open_backend(path: &str, format: DiskFormat) -> TestBackend {
+    open_backend_with_prop(path, prop(format))
+}
+
+pub fn write_data(backend: &TestBackend, offset: u64, buf: &[u8]) {
+    let iov = vec![Iovec::new(buf.as_ptr() as u64, buf.len() as u64)];
+    backend
+        .lock()
+        .unwrap()
+        .write_vectored(iov, offset as usize, ())
+        .unwrap();
+}
+
+/// Create the raw image `base_path` whose first 8K bytes are 0x11, and the
+/// qcow2 image `top_path` which uses it as backing file.
+pub fn create_backing_chain(base_path: &str, top_path: &str, disk_size: u64) {
+    let base_file = File::create(base_path).unwrap();
+    base_file.set_len(disk_size).unwrap();
+    drop(base_file);
+    let base = open_backend(base_path, DiskFormat::Raw);
+    write_data(&base, 0, &[0x11_u8; 8192]);
+    drop(base);
+
+    let mut opts = CreateOptions::new(top_path, disk_size);
+    opts.backing_file = Some(base_path.to_string());
+    opts.backing_fmt = Some(DiskFormat::Raw);
+    create_qcow2_image(&opts).unwrap();
+}
+
+/// Check the job which copies the data allocated in the active image to its
+/// backing file, `create_job` creates the job with the active image, the
+/// opened backing file and the callback to pivot.
+pub fn check_top_to_base_job<F>(name: &str, create_job: F)
+where
+    F: FnOnce(TestBackend, File, Box<dyn FnOnce() -> Result<()> + Send>) -> Box<dyn BlockJobOps>,
+{
+    let base_path = format!("/tmp/{}_base.raw", name);
+    let top_path = format!("/tmp/{}_top.qcow2", name);
+    let disk_size = 4 << 20;
+    create_backing_chain(&base_path, &top_path, disk_size);
+    let top = open_backend(&top_path, DiskFormat::Qcow2);
+    write_data(&top, 4096, &[0x22_u8; 4096]);
+    write_data(&top, 3 << 20, &[0x33_u8; 1 << 20]);
+
+    let pivoted = Arc::new(AtomicBool::new(false));
+    let pivoted_clone = pivoted.clone();
+    let mut job = create_job(
+        top.clone(),
+        open_file(&base_path, false, false).unwrap(),
+        Box::new(move || {
+            pivoted_clone.store(true, Ordering::SeqCst);
+            Ok(())
+        }),
+    );
+    // Only the clusters allocated in the active image are copied.
+    assert_eq!(job.progress(), (0, DEFAULT_GRANULARITY + (1 << 20)));
+
+    assert_eq!(job.run_step(1 << 20).unwrap(), (DEFAULT_GRANULARITY, false));
+    // Write of guest during the job is copied too.
+    write_data(&top, 0, &[0x44_u8; 512]);
+    while !job.run_step(1 << 20).unwrap().1 {}
+    assert_eq!(
+        job.progress(),
+        (
+            2 * DEFAULT_GRANULARITY + (1 << 20),
+            2 * DEFAULT_GRANULARITY + (1 << 20)
+        )
+    );
+    job.complete().unwrap();
+    assert!(pivoted.load(Ordering::SeqCst));
+
+    let mut base = vec![0_u8; disk_size as usize];
+    open_backend(&base_path, DiskFormat::Raw)
+        .lock()
+        .unwrap()
+        .read_sync(0, &mut base)
+        .unwrap();
+    assert_eq!(&base[..512], &[0x44_u8; 512]);
+    assert_eq!(&base[512..4096], &[0x11_u8; 3584]);
+    assert_eq!(&base[4096..8192], &[0x22_u8; 4096]);
+    assert_eq!(&base[8192..3 << 20], &vec![0_u8; (3 << 20) - 8192][..]);
+    assert_eq!(&base[3 << 20..], &vec![0x33_u8; 1 << 20][..]);
+
+    remove_file(base_path).unwrap();
+    remove_file(top_path).unwrap();
+}
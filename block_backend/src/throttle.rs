@@ -0,0 +1,376 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! IO throttling of block devices. The limits of bandwidth and iops are
+//! implemented by leaky buckets, which are shared by the block devices in
+//! the same throttle group.
+
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use anyhow::Result;
+use log::error;
+use once_cell::sync::Lazy;
+use vmm_sys_util::eventfd::EventFd;
+
+use machine_manager::config::{ThrottleConfig, ThrottleLimit};
+use util::loop_context::{get_current_time, EventLoopContext};
+use util::time::NANOSECONDS_PER_SECOND;
+
+/// The throttle groups registered by name.
+static THROTTLE_GROUPS: Lazy<Mutex<HashMap<String, Weak<Mutex<ThrottleGroup>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Index of the buckets, in the same order as `ThrottleConfig::limits()`.
+const BPS_TOTAL: usize = 0;
+const BPS_READ: usize = 1;
+const BPS_WRITE: usize = 2;
+const IOPS_TOTAL: usize = 3;
+const IOPS_READ: usize = 4;
+const IOPS_WRITE: usize = 5;
+
+/// Leaky bucket of one kind of limit. The bucket leaks at the average rate,
+/// and the IO is allowed only if the bucket is not full. With burst limit,
+/// the bucket holds the units of `max * max_length`, and the rate of burst is
+/// limited by another bucket leaking at `max`.
+#[derive(Default)]
+struct LeakyBucket {
+    limit: ThrottleLimit,
+    /// Units in the bucket.
+    level: f64,
+    /// Units in the bucket of burst.
+    burst_level: f64,
+}
+
+impl LeakyBucket {
+    fn leak(&mut self, delta_ns: u64) {
+        let delta = delta_ns as f64 / NANOSECONDS_PER_SECOND as f64;
+        self.level = (self.level - self.limit.avg as f64 * delta).max(0.0);
+        self.burst_level = (self.burst_level - self.limit.max as f64 * delta).max(0.0);
+    }
+
+    fn has_burst_bucket(&self) -> bool {
+        self.limit.max != 0 && self.limit.max_length > 1
+    }
+
+    /// Get the nanoseconds to wait until the bucket allows IO.
+    fn wait_time(&self) -> u64 {
+        if self.limit.avg == 0 {
+            return 0;
+        }
+        // Without burst, the units of 1/10 second can be accumulated.
+        let size = match self.limit.max {
+            0 => self.limit.avg as f64 / 10.0,
+            max => (max * self.limit.max_length) as f64,
+        };
+        let extra = self.level - size;
+        if extra > 0.0 {
+            return (extra * NANOSECONDS_PER_SECOND as f64 / self.limit.avg as f64) as u64;
+        }
+        if self.has_burst_bucket() {
+            let extra = self.burst_level - self.limit.max as f64 / 10.0;
+            if extra > 0.0 {
+                return (extra * NANOSECONDS_PER_SECOND as f64 / self.limit.max as f64) as u64;
+            }
+        }
+        0
+    }
+
+    fn account(&mut self, units: u64) {
+        if self.limit.avg == 0 {
+            return;
+        }
+        self.level += units as f64;
+        if self.has_burst_bucket() {
+            self.burst_level += units as f64;
+        }
+    }
+}
+
+/// The IO limits shared by the block devices in the group.
+pub struct ThrottleGroup {
+    name: String,
+    config: ThrottleConfig,
+    buckets: Vec<LeakyBucket>,
+    /// The last time that the buckets leaked.
+    prev_time: Instant,
+}
+
+impl ThrottleGroup {
+    /// Create a throttle group which is not registered, it is used by the
+    /// device alone.
+    pub fn new(name: &str, config: ThrottleConfig) -> Self {
+        let mut group = ThrottleGroup {
+            name: name.to_string(),
+            config: ThrottleConfig::default(),
+            buckets: Vec::new(),
+            prev_time: get_current_time(),
+        };
+        group.set_config(config);
+        group
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> ThrottleConfig {
+        self.config
+    }
+
+    /// Set the limits of group, the IO accounted before is forgotten.
+    pub fn set_config(&mut self, config: ThrottleConfig) {
+        self.config = config;
+        self.buckets = config
+            .limits()
+            .iter()
+            .map(|limit| LeakyBucket {
+                limit: **limit,
+                ..Default::default()
+            })
+            .collect();
+        self.prev_time = get_current_time();
+    }
+
+    fn bucket_indexes(is_write: bool) -> [usize; 4] {
+        match is_write {
+            true => [BPS_TOTAL, BPS_WRITE, IOPS_TOTAL, IOPS_WRITE],
+            false => [BPS_TOTAL, BPS_READ, IOPS_TOTAL, IOPS_READ],
+        }
+    }
+
+    /// Get the nanoseconds to wait until the read or write IO is allowed.
+    fn wait_time(&mut self, is_write: bool) -> u64 {
+        let now = get_current_time();
+        let delta = now.saturating_duration_since(self.prev_time).as_nanos() as u64;
+        self.prev_time = now;
+        for bucket in self.buckets.iter_mut() {
+            bucket.leak(delta);
+        }
+        Self::bucket_indexes(is_write)
+            .iter()
+            .map(|idx| self.buckets[*idx].wait_time())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn account(&mut self, is_write: bool, bytes: u64) {
+        let indexes = Self::bucket_indexes(is_write);
+        for idx in indexes.iter() {
+            let units = match *idx {
+                BPS_TOTAL | BPS_READ | BPS_WRITE => bytes,
+                _ => 1,
+            };
+            self.buckets[*idx].account(units);
+        }
+    }
+}
+
+/// Get the registered throttle group with the name, it is created if not
+/// found. The limits of group are updated if `config` is specified.
+pub fn get_throttle_group(name: &str, config: Option<ThrottleConfig>) -> Arc<Mutex<ThrottleGroup>> {
+    let mut groups = THROTTLE_GROUPS.lock().unwrap();
+    groups.retain(|_, group| group.strong_count() > 0);
+    if let Some(group) = groups.get(name).and_then(|group| group.upgrade()) {
+        if let Some(config) = config {
+            group.lock().unwrap().set_config(config);
+        }
+        return group;
+    }
+    let group = Arc::new(Mutex::new(ThrottleGroup::new(
+        name,
+        config.unwrap_or_default(),
+    )));
+    groups.insert(name.to_string(), Arc::downgrade(&group));
+    group
+}
+
+/// Throttle of the IO handler, which uses the limits of the throttle group.
+/// The handler is woken up by the timer event when the IO is allowed again.
+pub struct Throttle {
+    group: Arc<Mutex<ThrottleGroup>>,
+    /// Indicate whether the timer started.
+    timer_started: bool,
+    /// Written when the IO is allowed again, it should be listened by the IO thread.
+    timer_wakeup: Arc<EventFd>,
+}
+
+impl Throttle {
+    pub fn new(group: Arc<Mutex<ThrottleGroup>>) -> Result<Self> {
+        Ok(Throttle {
+            group,
+            timer_started: false,
+            timer_wakeup: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+        })
+    }
+
+    pub fn set_group(&mut self, group: Arc<Mutex<ThrottleGroup>>) {
+        self.group = group;
+    }
+
+    /// Return true if the IO should wait, and the timer is started to wake up
+    /// the handler. Otherwise the IO of `bytes` is accounted.
+    ///
+    /// # Arguments
+    ///
+    /// * `loop_context` - Used for delay function call.
+    /// * `is_write` - Whether it is write IO.
+    /// * `bytes` - Length of the IO.
+    pub fn throttled(
+        &mut self,
+        loop_context: &mut EventLoopContext,
+        is_write: bool,
+        bytes: u64,
+    ) -> bool {
+        if self.wait(loop_context, is_write) {
+            return true;
+        }
+        self.account(is_write, bytes);
+        false
+    }
+
+    /// Return true if the IO should wait, and the timer is started to wake up
+    /// the handler. The IO is not accounted, it is used with `account()` when
+    /// the length of IO is not known until it is done.
+    pub fn wait(&mut self, loop_context: &mut EventLoopContext, is_write: bool) -> bool {
+        if self.timer_started {
+            return true;
+        }
+        let mut group = self.group.lock().unwrap();
+        if !group.config.is_enabled() {
+            return false;
+        }
+
+        let wait = group.wait_time(is_write);
+        if wait > 0 {
+            let wakeup_clone = self.timer_wakeup.clone();
+            let func = Box::new(move || {
+                wakeup_clone
+                    .write(1)
+                    .unwrap_or_else(|e| error!("Throttle send event to device failed {:?}", e));
+            });
+            loop_context.delay_call(func, wait);
+            self.timer_started = true;
+            return true;
+        }
+        false
+    }
+
+    /// Account the IO of `bytes` which has been allowed by `wait()`.
+    pub fn account(&mut self, is_write: bool, bytes: u64) {
+        let mut group = self.group.lock().unwrap();
+        if group.config.is_enabled() {
+            group.account(is_write, bytes);
+        }
+    }
+
+    /// Whether the handler is waiting for the timer.
+    pub fn timer_started(&self) -> bool {
+        self.timer_started
+    }
+
+    /// Clear the timer state.
+    pub fn clear_timer(&mut self) {
+        self.timer_started = false;
+    }
+}
+
+impl AsRawFd for Throttle {
+    fn as_raw_fd(&self) -> RawFd {
+        self.timer_wakeup.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NS: u64 = NANOSECONDS_PER_SECOND;
+
+    #[test]
+    fn test_leaky_bucket() {
+        // Average limit only.
+        let mut bucket = LeakyBucket {
+            limit: ThrottleLimit {
+                avg: 100,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(bucket.wait_time(), 0);
+        bucket.account(10);
+        assert_eq!(bucket.wait_time(), 0);
+        bucket.account(40);
+        // 40 units over the bucket of 10 units.
+        assert_eq!(bucket.wait_time(), NS * 4 / 10);
+        bucket.leak(NS * 4 / 10);
+        assert_eq!(bucket.wait_time(), 0);
+        bucket.leak(NS);
+        assert_eq!(bucket.level, 0.0);
+
+        // Burst of 1000 units for 2 seconds.
+        let mut bucket = LeakyBucket {
+            limit: ThrottleLimit {
+                avg: 100,
+                max: 1000,
+                max_length: 2,
+            },
+            ..Default::default()
+        };
+        bucket.account(90);
+        assert_eq!(bucket.wait_time(), 0);
+        // The rate of burst is limited.
+        bucket.account(20);
+        assert_eq!(bucket.wait_time(), NS / 100);
+        bucket.leak(NS / 100);
+        assert_eq!(bucket.wait_time(), 0);
+        // The bucket of burst is full, the IO waits for the average rate.
+        bucket.account(2100);
+        assert!(bucket.wait_time() > NS * 2);
+        bucket.leak(NS * 3);
+        assert_eq!(bucket.burst_level, 0.0);
+        assert_eq!(bucket.wait_time(), 0);
+    }
+
+    #[test]
+    fn test_throttle_group() {
+        let mut config = ThrottleConfig::default();
+        config.bps_read.avg = 1 << 20;
+        config.iops_write.avg = 10;
+        let group = get_throttle_group("test_group", Some(config));
+        let group2 = get_throttle_group("test_group", None);
+        assert!(Arc::ptr_eq(&group, &group2));
+        assert_eq!(group2.lock().unwrap().config(), config);
+
+        let mut locked_group = group.lock().unwrap();
+        assert_eq!(locked_group.wait_time(false), 0);
+        locked_group.account(false, 1 << 20);
+        assert!(locked_group.wait_time(false) > 0);
+        // Write is limited by iops only.
+        assert_eq!(locked_group.wait_time(true), 0);
+        locked_group.account(true, 1 << 20);
+        locked_group.account(true, 1 << 20);
+        assert!(locked_group.wait_time(true) > 0);
+        // The IO accounted is forgotten after the limits change.
+        locked_group.set_config(ThrottleConfig::default());
+        assert_eq!(locked_group.wait_time(false), 0);
+        assert_eq!(locked_group.wait_time(true), 0);
+        drop(locked_group);
+
+        drop(group);
+        drop(group2);
+        let group = get_throttle_group("test_group", None);
+        assert_eq!(group.lock().unwrap().config(), ThrottleConfig::default());
+    }
+}
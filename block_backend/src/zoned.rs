@@ -0,0 +1,837 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Zone model of zoned block devices. The zones are either those of a host
+//! zoned block device managed by the zone ioctls, or emulated over a regular
+//! image file with the zone map stored in the file alongside the image.
+//! Sectors are of 512 bytes, as used by the zone ioctls.
+
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{error, warn};
+use vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr, ioctl_iowr_nr};
+
+/// Type of zone, refer to Virtio Spec.
+/// Conventional zone, which can be written randomly.
+pub const ZONE_TYPE_CNV: u8 = 1;
+/// Sequential write required zone.
+pub const ZONE_TYPE_SWR: u8 = 2;
+/// Sequential write preferred zone.
+pub const ZONE_TYPE_SWP: u8 = 3;
+
+/// Condition of zone, refer to Virtio Spec.
+pub const ZONE_COND_NOT_WP: u8 = 0;
+pub const ZONE_COND_EMPTY: u8 = 1;
+pub const ZONE_COND_IMP_OPEN: u8 = 2;
+pub const ZONE_COND_EXP_OPEN: u8 = 3;
+pub const ZONE_COND_CLOSED: u8 = 4;
+pub const ZONE_COND_READONLY: u8 = 0xd;
+pub const ZONE_COND_FULL: u8 = 0xe;
+pub const ZONE_COND_OFFLINE: u8 = 0xf;
+
+/// Zoned model of device, refer to Virtio Spec.
+/// Host-managed model, the sequential write required zones must be written at
+/// the write pointer.
+pub const ZONED_MODEL_HM: u8 = 1;
+/// Host-aware model, the sequential write preferred zones can be written randomly.
+pub const ZONED_MODEL_HA: u8 = 2;
+
+/// Magic of the file storing the zone map of emulated zoned device.
+const ZONES_FILE_MAGIC: &[u8; 8] = b"SVZONES\0";
+const ZONES_FILE_VERSION: u32 = 1;
+/// Min size of emulated zone.
+pub const MIN_ZONE_SIZE: u64 = 4096;
+
+/// The ioctls of host zoned block devices, refer to linux/blkzoned.h.
+const BLK_IOCTL_TYPE: u32 = 0x12;
+/// Capacity of zone is reported.
+const BLK_ZONE_REP_CAPACITY: u32 = 1 << 0;
+/// Max number of zones reported by one ioctl.
+const HOST_REPORT_ZONES: usize = 256;
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct BlkZoneReport {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct BlkZone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    zone_type: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct BlkZoneRange {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+ioctl_iowr_nr!(BLKREPORTZONE, BLK_IOCTL_TYPE, 130, BlkZoneReport);
+ioctl_iow_nr!(BLKRESETZONE, BLK_IOCTL_TYPE, 131, BlkZoneRange);
+ioctl_ior_nr!(BLKGETZONESZ, BLK_IOCTL_TYPE, 132, u32);
+ioctl_iow_nr!(BLKOPENZONE, BLK_IOCTL_TYPE, 134, BlkZoneRange);
+ioctl_iow_nr!(BLKCLOSEZONE, BLK_IOCTL_TYPE, 135, BlkZoneRange);
+ioctl_iow_nr!(BLKFINISHZONE, BLK_IOCTL_TYPE, 136, BlkZoneRange);
+
+/// A zone of device, the positions are in sectors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Zone {
+    pub start: u64,
+    pub len: u64,
+    /// Writable sectors of zone from its start.
+    pub cap: u64,
+    /// Write pointer.
+    pub wp: u64,
+    pub zone_type: u8,
+    pub cond: u8,
+}
+
+impl Zone {
+    fn new(start: u64, len: u64, zone_type: u8) -> Self {
+        Zone {
+            start,
+            len,
+            cap: len,
+            wp: start,
+            zone_type,
+            cond: match zone_type {
+                ZONE_TYPE_CNV => ZONE_COND_NOT_WP,
+                _ => ZONE_COND_EMPTY,
+            },
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.cond == ZONE_COND_IMP_OPEN || self.cond == ZONE_COND_EXP_OPEN
+    }
+
+    fn is_active(&self) -> bool {
+        self.is_open() || self.cond == ZONE_COND_CLOSED
+    }
+
+    /// Close the open zone, it becomes empty if nothing is written.
+    fn close(&mut self) {
+        self.cond = match self.wp == self.start {
+            true => ZONE_COND_EMPTY,
+            false => ZONE_COND_CLOSED,
+        };
+    }
+}
+
+/// Errors of zone commands, reported to guest by the zone status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneError {
+    /// The command is invalid, or the zone is in invalid condition.
+    InvalidCmd,
+    /// The write is not at the write pointer of zone.
+    UnalignedWp,
+    /// The limit of open zones is reached.
+    OpenResource,
+    /// The limit of active zones is reached.
+    ActiveResource,
+    /// The zone command failed on host device.
+    Io,
+}
+
+/// Zone management operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneOp {
+    Open,
+    Close,
+    Finish,
+    Reset,
+    ResetAll,
+}
+
+enum ZoneBackend {
+    /// Zones emulated over the image, the zone map is stored in the file at path.
+    Emulated(String),
+    /// Zones of host zoned block device.
+    Host(File),
+}
+
+/// The zones of device, which track the write pointers of zones for the
+/// checks of writes.
+pub struct ZoneMap {
+    zones: Vec<Zone>,
+    zone_sectors: u64,
+    /// Limits of the open and active zones, zero means no limit.
+    max_open: u32,
+    max_active: u32,
+    max_append_sectors: u32,
+    model: u8,
+    backend: ZoneBackend,
+    /// The zone map is changed since it was stored.
+    dirty: bool,
+}
+
+/// Get the path of the file storing the zone map of emulated zoned device,
+/// which is placed alongside the image.
+pub fn zones_file_path(image: &str) -> String {
+    format!("{}.zones", image)
+}
+
+/// Read the attribute of request queue of host block device from sysfs.
+fn host_queue_attr(file: &File, attr: &str) -> Result<Option<String>> {
+    let meta = file.metadata()?;
+    if !meta.file_type().is_block_device() {
+        return Ok(None);
+    }
+    let rdev = meta.rdev();
+    // SAFETY: Just calculate the device numbers.
+    let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+    let path = format!("/sys/dev/block/{}:{}/queue/{}", major, minor, attr);
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let value =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?;
+    Ok(Some(value.trim().to_string()))
+}
+
+fn host_queue_attr_u64(file: &File, attr: &str) -> Result<u64> {
+    match host_queue_attr(file, attr)? {
+        Some(value) => value
+            .parse::<u64>()
+            .with_context(|| format!("Invalid value {} of {}", value, attr)),
+        None => Ok(0),
+    }
+}
+
+/// Get the zoned model of host block device, None if it is not zoned.
+pub fn host_zoned_model(file: &File) -> Result<Option<u8>> {
+    Ok(match host_queue_attr(file, "zoned")?.as_deref() {
+        Some("host-managed") => Some(ZONED_MODEL_HM),
+        Some("host-aware") => Some(ZONED_MODEL_HA),
+        _ => None,
+    })
+}
+
+impl ZoneMap {
+    /// Emulate the zones of `zone_size` bytes over the image of `disk_size` bytes,
+    /// the first `conventional` zones are conventional. The zone map stored is
+    /// restored if it matches the geometry, otherwise all the zones are empty.
+    pub fn new_emulated(
+        image: &str,
+        disk_size: u64,
+        zone_size: u64,
+        conventional: u32,
+        max_open: u32,
+        max_active: u32,
+    ) -> Result<Self> {
+        if zone_size < MIN_ZONE_SIZE || !zone_size.is_power_of_two() {
+            bail!("Invalid zone size {}", zone_size);
+        }
+        let disk_sectors = disk_size >> 9;
+        let zone_sectors = zone_size >> 9;
+        let nr_zones = disk_sectors.div_ceil(zone_sectors);
+        if nr_zones <= u64::from(conventional) {
+            bail!(
+                "No sequential zone in disk of {} bytes with {} conventional zones",
+                disk_size,
+                conventional
+            );
+        }
+        let zones: Vec<Zone> = (0..nr_zones)
+            .map(|i| {
+                let start = i * zone_sectors;
+                let zone_type = match i < u64::from(conventional) {
+                    true => ZONE_TYPE_CNV,
+                    false => ZONE_TYPE_SWR,
+                };
+                Zone::new(start, zone_sectors.min(disk_sectors - start), zone_type)
+            })
+            .collect();
+
+        let mut zone_map = ZoneMap {
+            zones,
+            zone_sectors,
+            max_open,
+            max_active,
+            max_append_sectors: zone_sectors.min(u64::from(u32::MAX)) as u32,
+            model: ZONED_MODEL_HM,
+            backend: ZoneBackend::Emulated(zones_file_path(image)),
+            dirty: true,
+        };
+        zone_map.load()?;
+        Ok(zone_map)
+    }
+
+    /// Get the zones of host zoned block device, None if it is not zoned.
+    pub fn new_host(file: File) -> Result<Option<Self>> {
+        let model = match host_zoned_model(&file)? {
+            Some(model) => model,
+            None => return Ok(None),
+        };
+        let mut zone_sectors = 0_u32;
+        // SAFETY: The file is valid and zone_sectors is a valid u32.
+        let ret = unsafe { ioctl_with_mut_ref(&file, BLKGETZONESZ(), &mut zone_sectors) };
+        if ret < 0 || zone_sectors == 0 {
+            bail!(
+                "Failed to get zone size of host device: {:?}",
+                std::io::Error::last_os_error()
+            );
+        }
+        let append_bytes = host_queue_attr_u64(&file, "zone_append_max_bytes")?;
+        let max_append_sectors = match append_bytes >> 9 {
+            0 => u64::from(zone_sectors),
+            sectors => sectors,
+        };
+        let mut zone_map = ZoneMap {
+            zones: Vec::new(),
+            zone_sectors: u64::from(zone_sectors),
+            max_open: host_queue_attr_u64(&file, "max_open_zones")? as u32,
+            max_active: host_queue_attr_u64(&file, "max_active_zones")? as u32,
+            max_append_sectors: max_append_sectors.min(u64::from(zone_sectors)) as u32,
+            model,
+            backend: ZoneBackend::Host(file),
+            dirty: false,
+        };
+        zone_map.zones = zone_map.host_report_zones(0, usize::MAX)?;
+        if zone_map.zones.is_empty() {
+            bail!("No zone reported by host device");
+        }
+        Ok(Some(zone_map))
+    }
+
+    pub fn zone_sectors(&self) -> u64 {
+        self.zone_sectors
+    }
+
+    pub fn nr_zones(&self) -> usize {
+        self.zones.len()
+    }
+
+    pub fn max_open(&self) -> u32 {
+        self.max_open
+    }
+
+    pub fn max_active(&self) -> u32 {
+        self.max_active
+    }
+
+    pub fn max_append_sectors(&self) -> u32 {
+        self.max_append_sectors
+    }
+
+    pub fn model(&self) -> u8 {
+        self.model
+    }
+
+    /// Check whether the zones are emulated over image.
+    pub fn is_emulated(&self) -> bool {
+        matches!(self.backend, ZoneBackend::Emulated(_))
+    }
+
+    fn zone_index(&self, sector: u64) -> Option<usize> {
+        let index = (sector / self.zone_sectors) as usize;
+        (index < self.zones.len()).then_some(index)
+    }
+
+    /// Report at most `max_zones` zones from the zone containing `sector`.
+    pub fn report(&mut self, sector: u64, max_zones: usize) -> Result<Vec<Zone>, ZoneError> {
+        let index = match self.zone_index(sector) {
+            Some(index) => index,
+            None => return Ok(Vec::new()),
+        };
+        let count = max_zones.min(self.zones.len() - index);
+        if !self.is_emulated() {
+            // The host device may change the condition of zones, e.g. close the
+            // implicitly open zones.
+            self.host_refresh(index, count)?;
+        }
+        Ok(self.zones[index..index + count].to_vec())
+    }
+
+    /// Check the write of `nr_sectors` at `sector` and advance the write pointer
+    /// of zone. The zone append writes at the write pointer of the zone starting
+    /// at `sector`. Returns the sector where the data is written.
+    pub fn write(&mut self, sector: u64, nr_sectors: u64, append: bool) -> Result<u64, ZoneError> {
+        let index = self.zone_index(sector).ok_or(ZoneError::InvalidCmd)?;
+        let end = sector
+            .checked_add(nr_sectors)
+            .ok_or(ZoneError::InvalidCmd)?;
+        let zone = self.zones[index];
+        if zone.zone_type == ZONE_TYPE_CNV {
+            // Conventional zones can be written across, but not into the
+            // sequential zones.
+            let last = self.zone_index(end.saturating_sub(1).max(sector));
+            if append || !matches!(last, Some(i) if self.zones[i].zone_type == ZONE_TYPE_CNV) {
+                return Err(ZoneError::InvalidCmd);
+            }
+            return Ok(sector);
+        }
+        if append && (sector != zone.start || nr_sectors > u64::from(self.max_append_sectors)) {
+            return Err(ZoneError::InvalidCmd);
+        }
+        if !append && end > zone.start + zone.len {
+            return Err(ZoneError::InvalidCmd);
+        }
+        if zone.zone_type == ZONE_TYPE_SWP && !append {
+            // Sequential write preferred zones can be written randomly.
+            if sector == zone.wp {
+                self.advance_wp(index, nr_sectors);
+            }
+            return Ok(sector);
+        }
+
+        let mut zone = zone;
+        if !self.is_emulated() && !append && sector != zone.wp {
+            // The write pointer may be changed by the failed writes on host.
+            self.host_refresh(index, 1)?;
+            zone = self.zones[index];
+        }
+        match zone.cond {
+            ZONE_COND_EMPTY | ZONE_COND_CLOSED | ZONE_COND_IMP_OPEN | ZONE_COND_EXP_OPEN => (),
+            _ => return Err(ZoneError::InvalidCmd),
+        }
+        let pos = if append { zone.wp } else { sector };
+        if pos != zone.wp {
+            return Err(ZoneError::UnalignedWp);
+        }
+        if pos + nr_sectors > zone.start + zone.cap {
+            return Err(ZoneError::InvalidCmd);
+        }
+        if !zone.is_open() {
+            self.check_open_resource(index)?;
+            self.zones[index].cond = ZONE_COND_IMP_OPEN;
+        }
+        self.advance_wp(index, nr_sectors);
+        Ok(pos)
+    }
+
+    fn advance_wp(&mut self, index: usize, nr_sectors: u64) {
+        let zone = &mut self.zones[index];
+        zone.wp += nr_sectors;
+        if zone.wp >= zone.start + zone.cap {
+            zone.wp = zone.start + zone.cap;
+            zone.cond = ZONE_COND_FULL;
+        }
+        self.dirty = true;
+    }
+
+    /// Check the resources to open the zone which is not open. The limits are
+    /// enforced by host device for its zones. An implicitly open zone is closed
+    /// if the limit of open zones is reached.
+    fn check_open_resource(&mut self, index: usize) -> Result<(), ZoneError> {
+        if !self.is_emulated() {
+            return Ok(());
+        }
+        let active = self.zones.iter().filter(|z| z.is_active()).count() as u32;
+        if self.zones[index].cond == ZONE_COND_EMPTY
+            && self.max_active != 0
+            && active >= self.max_active
+        {
+            return Err(ZoneError::ActiveResource);
+        }
+        let open = self.zones.iter().filter(|z| z.is_open()).count() as u32;
+        if self.max_open != 0 && open >= self.max_open {
+            match self.zones.iter().position(|z| z.cond == ZONE_COND_IMP_OPEN) {
+                Some(i) => self.zones[i].close(),
+                None => return Err(ZoneError::OpenResource),
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute the zone management operation on the zone starting at `sector`.
+    /// Returns the range of sectors to be zeroed, as the data of emulated zones
+    /// reset must read as zeros.
+    pub fn manage(&mut self, op: ZoneOp, sector: u64) -> Result<Option<(u64, u64)>, ZoneError> {
+        if op == ZoneOp::ResetAll {
+            return self.reset_all();
+        }
+        let index = self.zone_index(sector).ok_or(ZoneError::InvalidCmd)?;
+        let zone = self.zones[index];
+        if sector != zone.start || zone.zone_type == ZONE_TYPE_CNV {
+            return Err(ZoneError::InvalidCmd);
+        }
+        if zone.cond == ZONE_COND_READONLY || zone.cond == ZONE_COND_OFFLINE {
+            return Err(ZoneError::InvalidCmd);
+        }
+        if !self.is_emulated() {
+            self.host_manage(op, zone.start, zone.len)?;
+            self.host_refresh(index, 1)?;
+            return Ok(None);
+        }
+
+        let mut zero_range = None;
+        match op {
+            ZoneOp::Open => match zone.cond {
+                ZONE_COND_EMPTY | ZONE_COND_CLOSED => {
+                    self.check_open_resource(index)?;
+                    self.zones[index].cond = ZONE_COND_EXP_OPEN;
+                }
+                ZONE_COND_IMP_OPEN => self.zones[index].cond = ZONE_COND_EXP_OPEN,
+                _ => (),
+            },
+            ZoneOp::Close => {
+                if zone.is_open() {
+                    self.zones[index].close();
+                }
+            }
+            ZoneOp::Finish => {
+                if zone.cond != ZONE_COND_FULL {
+                    let zone = &mut self.zones[index];
+                    zone.wp = zone.start + zone.cap;
+                    zone.cond = ZONE_COND_FULL;
+                }
+            }
+            ZoneOp::Reset => {
+                let zone = &mut self.zones[index];
+                zone.wp = zone.start;
+                zone.cond = ZONE_COND_EMPTY;
+                zero_range = Some((zone.start, zone.len));
+            }
+            ZoneOp::ResetAll => unreachable!(),
+        }
+        self.dirty = true;
+        Ok(zero_range)
+    }
+
+    fn reset_all(&mut self) -> Result<Option<(u64, u64)>, ZoneError> {
+        let first = self
+            .zones
+            .iter()
+            .position(|z| z.zone_type != ZONE_TYPE_CNV)
+            .ok_or(ZoneError::InvalidCmd)?;
+        let last = self.zones.last().unwrap();
+        let end = last.start + last.len;
+        if !self.is_emulated() {
+            // The conventional zones are skipped by host when all zones are reset.
+            self.host_manage(ZoneOp::Reset, 0, end)?;
+            self.host_refresh(0, self.zones.len())?;
+            return Ok(None);
+        }
+        for zone in self.zones[first..].iter_mut() {
+            zone.wp = zone.start;
+            zone.cond = ZONE_COND_EMPTY;
+        }
+        self.dirty = true;
+        let start = self.zones[first].start;
+        Ok(Some((start, end - start)))
+    }
+
+    /// Store the zone map of emulated zones if it is changed.
+    pub fn store(&mut self) -> Result<()> {
+        let path = match &self.backend {
+            ZoneBackend::Emulated(path) if self.dirty => path.clone(),
+            _ => return Ok(()),
+        };
+        let mut buf = ZONES_FILE_MAGIC.to_vec();
+        buf.write_u32::<LittleEndian>(ZONES_FILE_VERSION)?;
+        buf.write_u64::<LittleEndian>(self.zone_sectors)?;
+        buf.write_u32::<LittleEndian>(self.zones.len() as u32)?;
+        for zone in self.zones.iter() {
+            buf.write_u8(zone.zone_type)?;
+            buf.write_u8(zone.cond)?;
+            buf.write_u64::<LittleEndian>(zone.wp)?;
+        }
+        let mut file = File::create(&path).with_context(|| format!("Failed to create {}", path))?;
+        file.write_all(&buf)
+            .and_then(|_| file.sync_data())
+            .with_context(|| format!("Failed to write {}", path))?;
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Restore the stored zone map of emulated zones. The zones open are closed,
+    /// as they are not kept open across the restart of device.
+    fn load(&mut self) -> Result<()> {
+        let path = match &self.backend {
+            ZoneBackend::Emulated(path) => path.clone(),
+            ZoneBackend::Host(_) => return Ok(()),
+        };
+        if !Path::new(&path).exists() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        File::open(&path)
+            .and_then(|mut file| file.read_to_end(&mut buf))
+            .with_context(|| format!("Failed to read {}", path))?;
+        match self.parse_zones(&buf) {
+            Ok(zones) => {
+                self.zones = zones;
+                self.dirty = false;
+            }
+            Err(e) => warn!(
+                "Zone map in {} is discarded, all zones are empty: {:?}",
+                path, e
+            ),
+        }
+        Ok(())
+    }
+
+    fn parse_zones(&self, buf: &[u8]) -> Result<Vec<Zone>> {
+        let mut cursor = Cursor::new(buf);
+        let mut magic = [0_u8; 8];
+        cursor.read_exact(&mut magic)?;
+        if &magic != ZONES_FILE_MAGIC {
+            bail!("Invalid magic");
+        }
+        let version = cursor.read_u32::<LittleEndian>()?;
+        if version != ZONES_FILE_VERSION {
+            bail!("Unsupported version {}", version);
+        }
+        let zone_sectors = cursor.read_u64::<LittleEndian>()?;
+        let nr_zones = cursor.read_u32::<LittleEndian>()? as usize;
+        if zone_sectors != self.zone_sectors || nr_zones != self.zones.len() {
+            bail!(
+                "Geometry of {} zones of {} sectors is changed",
+                nr_zones,
+                zone_sectors
+            );
+        }
+
+        let mut zones = self.zones.clone();
+        for zone in zones.iter_mut() {
+            let zone_type = cursor.read_u8()?;
+            let cond = cursor.read_u8()?;
+            let wp = cursor.read_u64::<LittleEndian>()?;
+            if zone_type != zone.zone_type {
+                bail!("Type of zone at sector {} is changed", zone.start);
+            }
+            if zone_type == ZONE_TYPE_CNV {
+                continue;
+            }
+            if wp < zone.start || wp > zone.start + zone.cap {
+                bail!("Invalid write pointer {} of zone", wp);
+            }
+            zone.wp = wp;
+            zone.cond = cond;
+            match cond {
+                ZONE_COND_IMP_OPEN | ZONE_COND_EXP_OPEN => zone.close(),
+                ZONE_COND_EMPTY | ZONE_COND_CLOSED | ZONE_COND_FULL => (),
+                _ => bail!("Invalid condition {} of zone", cond),
+            }
+        }
+        Ok(zones)
+    }
+
+    fn host_file(&self) -> &File {
+        match &self.backend {
+            ZoneBackend::Host(file) => file,
+            ZoneBackend::Emulated(_) => unreachable!(),
+        }
+    }
+
+    fn host_manage(&self, op: ZoneOp, sector: u64, nr_sectors: u64) -> Result<(), ZoneError> {
+        let range = BlkZoneRange { sector, nr_sectors };
+        let req = match op {
+            ZoneOp::Open => BLKOPENZONE(),
+            ZoneOp::Close => BLKCLOSEZONE(),
+            ZoneOp::Finish => BLKFINISHZONE(),
+            ZoneOp::Reset | ZoneOp::ResetAll => BLKRESETZONE(),
+        };
+        // SAFETY: The file is valid and range is a valid BlkZoneRange.
+        let ret = unsafe { ioctl_with_ref(self.host_file(), req, &range) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            error!(
+                "Failed to {:?} zone at sector {} of host device: {:?}",
+                op, sector, err
+            );
+            return Err(match err.raw_os_error() {
+                Some(libc::ETOOMANYREFS) => ZoneError::OpenResource,
+                Some(libc::EOVERFLOW) => ZoneError::ActiveResource,
+                _ => ZoneError::Io,
+            });
+        }
+        Ok(())
+    }
+
+    /// Update `count` zones from the zone at `index` with those of host device.
+    fn host_refresh(&mut self, index: usize, count: usize) -> Result<(), ZoneError> {
+        let zones = self
+            .host_report_zones(self.zones[index].start, count)
+            .map_err(|e| {
+                error!("{:?}", e);
+                ZoneError::Io
+            })?;
+        for (i, zone) in zones.into_iter().enumerate() {
+            if let Some(z) = self.zones.get_mut(index + i) {
+                *z = zone;
+            }
+        }
+        Ok(())
+    }
+
+    fn host_report_zones(&self, mut sector: u64, max_zones: usize) -> Result<Vec<Zone>> {
+        let fd = self.host_file().as_raw_fd();
+        let header_words = std::mem::size_of::<BlkZoneReport>() / 8;
+        let zone_words = std::mem::size_of::<BlkZone>() / 8;
+        let mut buf = vec![0_u64; header_words + HOST_REPORT_ZONES * zone_words];
+        let mut zones = Vec::new();
+        while zones.len() < max_zones {
+            buf.iter_mut().for_each(|w| *w = 0);
+            let report = buf.as_mut_ptr() as *mut BlkZoneReport;
+            let nr_zones = HOST_REPORT_ZONES.min(max_zones - zones.len()) as u32;
+            // SAFETY: The buffer is large enough for the header and zones, and
+            // is aligned to u64.
+            unsafe {
+                (*report).sector = sector;
+                (*report).nr_zones = nr_zones;
+            }
+            // SAFETY: The file is valid and the buffer is allocated above.
+            let ret = unsafe { ioctl_with_mut_ptr(self.host_file(), BLKREPORTZONE(), report) };
+            if ret < 0 {
+                bail!(
+                    "Failed to report zones of host device {}: {:?}",
+                    fd,
+                    std::io::Error::last_os_error()
+                );
+            }
+            // SAFETY: The header is filled by host.
+            let (reported, flags) = unsafe { ((*report).nr_zones as usize, (*report).flags) };
+            if reported == 0 {
+                break;
+            }
+            for i in 0..reported.min(HOST_REPORT_ZONES) {
+                // SAFETY: The zones are within the buffer.
+                let blk_zone =
+                    unsafe { *(buf.as_ptr().add(header_words + i * zone_words) as *const BlkZone) };
+                let cap = match flags & BLK_ZONE_REP_CAPACITY {
+                    0 => blk_zone.len,
+                    _ => blk_zone.capacity,
+                };
+                zones.push(Zone {
+                    start: blk_zone.start,
+                    len: blk_zone.len,
+                    cap,
+                    wp: blk_zone.wp,
+                    zone_type: blk_zone.zone_type,
+                    cond: blk_zone.cond,
+                });
+                sector = blk_zone.start + blk_zone.len;
+            }
+        }
+        Ok(zones)
+    }
+}
+
+impl Drop for ZoneMap {
+    fn drop(&mut self) {
+        if let Err(e) = self.store() {
+            error!("Failed to store zone map: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_sectors(zone_map: &mut ZoneMap, sector: u64, nr: u64) -> Result<u64, ZoneError> {
+        zone_map.write(sector, nr, false)
+    }
+
+    #[test]
+    fn test_emulated_zones() {
+        let image = "/tmp/test_emulated_zones.img";
+        let _ = std::fs::remove_file(zones_file_path(image));
+        // 8 zones of 64KiB, the first one is conventional.
+        let mut zone_map = ZoneMap::new_emulated(image, 8 << 16, 1 << 16, 1, 2, 3).unwrap();
+        assert_eq!(zone_map.nr_zones(), 8);
+        assert_eq!(zone_map.zone_sectors(), 128);
+
+        // Conventional zone is written randomly, but not across sequential zone.
+        assert_eq!(write_sectors(&mut zone_map, 8, 8), Ok(8));
+        assert_eq!(
+            write_sectors(&mut zone_map, 120, 16),
+            Err(ZoneError::InvalidCmd)
+        );
+        assert_eq!(
+            zone_map.manage(ZoneOp::Reset, 0),
+            Err(ZoneError::InvalidCmd)
+        );
+
+        // Sequential zone is written at the write pointer.
+        assert_eq!(
+            write_sectors(&mut zone_map, 136, 8),
+            Err(ZoneError::UnalignedWp)
+        );
+        assert_eq!(write_sectors(&mut zone_map, 128, 8), Ok(128));
+        assert_eq!(zone_map.write(128, 8, true), Ok(136));
+        let zones = zone_map.report(128, 2).unwrap();
+        assert_eq!(zones[0].wp, 144);
+        assert_eq!(zones[0].cond, ZONE_COND_IMP_OPEN);
+        assert_eq!(zones[1].cond, ZONE_COND_EMPTY);
+
+        // The implicitly open zone is closed for the third open zone, and the
+        // limit of active zones is reached then.
+        assert_eq!(zone_map.manage(ZoneOp::Open, 256), Ok(None));
+        assert_eq!(write_sectors(&mut zone_map, 384, 8), Ok(384));
+        assert_eq!(zone_map.report(128, 1).unwrap()[0].cond, ZONE_COND_CLOSED);
+        assert_eq!(
+            write_sectors(&mut zone_map, 512, 8),
+            Err(ZoneError::ActiveResource)
+        );
+        assert_eq!(zone_map.manage(ZoneOp::Finish, 384), Ok(None));
+        assert_eq!(zone_map.manage(ZoneOp::Open, 512), Ok(None));
+        assert_eq!(
+            zone_map.manage(ZoneOp::Open, 128),
+            Err(ZoneError::OpenResource)
+        );
+        assert_eq!(
+            write_sectors(&mut zone_map, 384, 8),
+            Err(ZoneError::InvalidCmd)
+        );
+
+        // The zone map is restored with open zones closed.
+        zone_map.store().unwrap();
+        drop(zone_map);
+        let mut zone_map = ZoneMap::new_emulated(image, 8 << 16, 1 << 16, 1, 2, 3).unwrap();
+        let zones = zone_map.report(0, 8).unwrap();
+        assert_eq!(zones.len(), 8);
+        assert_eq!(zones[0].cond, ZONE_COND_NOT_WP);
+        assert_eq!(zones[1].cond, ZONE_COND_CLOSED);
+        assert_eq!(zones[2].cond, ZONE_COND_EMPTY);
+        assert_eq!(zones[3].cond, ZONE_COND_FULL);
+        assert_eq!(zones[3].wp, 512);
+
+        assert_eq!(zone_map.manage(ZoneOp::Reset, 128), Ok(Some((128, 128))));
+        assert_eq!(zone_map.manage(ZoneOp::ResetAll, 0), Ok(Some((128, 896))));
+        assert!(zone_map
+            .report(0, 8)
+            .unwrap()
+            .iter()
+            .skip(1)
+            .all(|z| z.cond == ZONE_COND_EMPTY && z.wp == z.start));
+
+        // The zone map is discarded if the geometry is changed.
+        drop(zone_map);
+        let zone_map = ZoneMap::new_emulated(image, 8 << 16, 1 << 15, 1, 0, 0).unwrap();
+        assert_eq!(zone_map.nr_zones(), 16);
+        drop(zone_map);
+        std::fs::remove_file(zones_file_path(image)).unwrap();
+    }
+}
@@ -46,19 +46,19 @@ use devices::InterruptController;
 
 use hypervisor::kvm::KVM_FDS;
 use machine_manager::config::{
-    complete_numa_node, get_multi_function, get_pci_bdf, parse_balloon, parse_blk, parse_demo_dev,
-    parse_device_id, parse_fs, parse_net, parse_numa_distance, parse_numa_mem, parse_rng_dev,
-    parse_root_port, parse_scsi_controller, parse_scsi_device, parse_vfio,
-    parse_vhost_user_blk_pci, parse_virtconsole, parse_virtio_serial, parse_vsock, BootIndexInfo,
-    DriveFile, Incoming, MachineMemConfig, MigrateMode, NumaConfig, NumaDistance, NumaNode,
-    NumaNodes, PFlashConfig, PciBdf, SerialConfig, VfioConfig, VmConfig, FAST_UNPLUG_ON,
-    MAX_VIRTIO_QUEUE,
+    complete_numa_node, get_multi_function, get_pci_bdf, is_nbd_path, parse_balloon, parse_blk,
+    parse_demo_dev, parse_device_id, parse_fs, parse_net, parse_numa_distance, parse_numa_mem,
+    parse_nvme, parse_nvme_ns, parse_rng_dev, parse_root_port, parse_scsi_controller,
+    parse_scsi_device, parse_vfio, parse_vhost_user_blk_pci, parse_virtconsole,
+    parse_virtio_serial, parse_vsock, BootIndexInfo, DriveFile, FilterDumpConfig, Incoming,
+    MachineMemConfig, MigrateMode, NumaConfig, NumaDistance, NumaNode, NumaNodes, PFlashConfig,
+    PciBdf, SerialConfig, VfioConfig, VmConfig, FAST_UNPLUG_ON, MAX_VIRTIO_QUEUE,
 };
 #[cfg(not(target_env = "musl"))]
 use machine_manager::config::{parse_gpu, parse_usb_keyboard, parse_usb_tablet, parse_xhci};
 use machine_manager::machine::{KvmVmState, MachineInterface};
 use migration::MigrationManager;
-use pci::{demo_dev::DemoDev, PciBus, PciDevOps, PciHost, RootPort};
+use pci::{demo_dev::DemoDev, nvme::NvmePciDevice, PciBus, PciDevOps, PciHost, RootPort};
 use standard_vm::Result as StdResult;
 pub use standard_vm::StdMachine;
 use sysbus::{SysBus, SysBusDevOps};
@@ -74,12 +74,12 @@ use vfio::{VfioDevice, VfioPciDevice};
 #[cfg(not(target_env = "musl"))]
 use virtio::Gpu;
 use virtio::{
-    balloon_allow_list, vhost, Balloon, Block, BlockState, Console, Rng, RngState, ScsiBus,
-    ScsiCntlr, ScsiDisk, VhostKern, VhostUser, VirtioConsoleState, VirtioDevice, VirtioMmioDevice,
-    VirtioMmioState, VirtioNetState, VirtioPciDevice,
+    balloon_allow_list, vhost, Balloon, Block, BlockMap, BlockState, Console, NetMap, Rng,
+    RngState, ScsiBus, ScsiCntlr, ScsiDisk, VhostKern, VhostUser, VirtioConsoleState, VirtioDevice,
+    VirtioMmioDevice, VirtioMmioState, VirtioNetState, VirtioPciDevice,
 };
 use ScsiCntlr::ScsiCntlrMap;
-use ScsiDisk::{SCSI_TYPE_DISK, SCSI_TYPE_ROM};
+use ScsiDisk::{ScsiDevKind, SCSI_TYPE_DISK, SCSI_TYPE_NOT_PRESENT, SCSI_TYPE_ROM};
 
 pub trait MachineOps {
     /// Calculate the ranges of memory according to architecture.
@@ -309,10 +309,46 @@ pub trait MachineOps {
     fn get_migrate_info(&self) -> Incoming;
 
     /// Get the Scsi Controller list. The map stores the mapping between scsi bus name and scsi controller.
-    fn get_scsi_cntlr_list(&mut self) -> Option<&ScsiCntlrMap> {
+    fn get_scsi_cntlr_list(&self) -> Option<&ScsiCntlrMap> {
         None
     }
 
+    /// Get the virtio block device list. The map stores the mapping between device id and block device.
+    fn get_blk_dev_list(&self) -> Option<&BlockMap> {
+        None
+    }
+
+    /// Get the virtio net device list. The map stores the mapping between device id and net device.
+    fn get_net_dev_list(&self) -> Option<&NetMap> {
+        None
+    }
+
+    /// Start capturing the packets of the net device using the netdev of `filter-dump`.
+    fn add_filter_dump(&self, config: &FilterDumpConfig) -> Result<()> {
+        let net_dev_list = if let Some(list) = self.get_net_dev_list() {
+            list
+        } else {
+            bail!("filter-dump is not supported by this machine");
+        };
+        let locked_list = net_dev_list.lock().unwrap();
+        for net in locked_list.values() {
+            if net.lock().unwrap().has_dump(&config.id) {
+                bail!("filter-dump {} has been added", config.id);
+            }
+        }
+        for net in locked_list.values() {
+            let locked_net = net.lock().unwrap();
+            if locked_net.netdev_id() == config.netdev {
+                return locked_net.add_dump(config);
+            }
+        }
+        bail!(
+            "No virtio-net device uses netdev {} for filter-dump {}",
+            config.netdev,
+            config.id
+        );
+    }
+
     /// Add net device.
     ///
     /// # Arguments
@@ -653,6 +689,12 @@ pub trait MachineOps {
                 self.add_bootindex_devices(bootindex, &dev_path, &device_cfg.id);
             }
         }
+        if let Some(blk_dev_list) = self.get_blk_dev_list() {
+            blk_dev_list
+                .lock()
+                .unwrap()
+                .insert(device_cfg.id.clone(), device.clone());
+        }
         MigrationManager::register_device_instance(
             BlockState::descriptor(),
             device,
@@ -695,17 +737,24 @@ pub trait MachineOps {
         vm_config: &mut VmConfig,
         cfg_args: &str,
         scsi_type: u32,
+        kind: ScsiDevKind,
     ) -> Result<()> {
         let device_cfg = parse_scsi_device(vm_config, cfg_args)?;
         if let Some(bootindex) = device_cfg.boot_index {
             self.check_bootindex(bootindex)
                 .with_context(|| "Failed to add scsi device for invalid bootindex")?;
         }
-        let device = Arc::new(Mutex::new(ScsiDisk::ScsiDevice::new(
-            device_cfg.clone(),
-            scsi_type,
-            self.get_drive_files(),
-        )));
+        let device = match kind {
+            ScsiDevKind::Emulated => {
+                ScsiDisk::ScsiDevice::new(device_cfg.clone(), scsi_type, self.get_drive_files())
+            }
+            _ => ScsiDisk::ScsiDevice::new_passthrough(
+                device_cfg.clone(),
+                kind,
+                self.get_drive_files(),
+            ),
+        };
+        let device = Arc::new(Mutex::new(device));
 
         let cntlr_list = self
             .get_scsi_cntlr_list()
@@ -754,6 +803,51 @@ pub trait MachineOps {
         Ok(())
     }
 
+    fn add_nvme(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let bdf = get_pci_bdf(cfg_args)?;
+        let device_cfg = parse_nvme(vm_config, cfg_args)?;
+        let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
+
+        let drive_files = self.get_drive_files();
+        let pcidev = NvmePciDevice::new(
+            &device_cfg,
+            devfn,
+            parent_bus,
+            self.get_sys_mem(),
+            drive_files,
+        );
+        pcidev
+            .realize()
+            .with_context(|| "Failed to realize nvme device")?;
+        Ok(())
+    }
+
+    /// Add the namespace to the nvme controller whose id is the `bus` of it.
+    fn add_nvme_ns(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        let ns_cfg = parse_nvme_ns(vm_config, cfg_args)?;
+        let ctrl_args = vm_config
+            .devices
+            .iter()
+            .find(|(name, args)| {
+                name == "nvme" && parse_device_id(args).ok().as_deref() == Some(&ns_cfg.bus)
+            })
+            .map(|(_, args)| args.clone())
+            .with_context(|| format!("Nvme controller {} not found", ns_cfg.bus))?;
+        let bdf = get_pci_bdf(&ctrl_args)?;
+        let devfn = (bdf.addr.0 << 3) + bdf.addr.1;
+        let pci_host = self.get_pci_host()?;
+        let root_bus = pci_host.lock().unwrap().root_bus.clone();
+        let pci_dev = PciBus::find_bus_by_name(&root_bus, &bdf.bus)
+            .and_then(|bus| bus.lock().unwrap().get_device(0, devfn))
+            .with_context(|| format!("Nvme controller {} is not realized", ns_cfg.bus))?;
+        let locked_dev = pci_dev.lock().unwrap();
+        let nvme = locked_dev
+            .as_any()
+            .downcast_ref::<NvmePciDevice>()
+            .with_context(|| "PciDevOps can not downcast to NvmePciDevice")?;
+        nvme.attach_namespace(&ns_cfg)
+    }
+
     fn add_virtio_pci_net(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
         let bdf = get_pci_bdf(cfg_args)?;
         let multi_func = get_multi_function(cfg_args)?;
@@ -767,10 +861,16 @@ pub trait MachineOps {
                 )))
             } else {
                 need_irqfd = true;
-                Arc::new(Mutex::new(VhostUser::Net::new(
+                let device = Arc::new(Mutex::new(VhostUser::Net::new(
                     &device_cfg,
                     self.get_sys_mem(),
-                )))
+                )));
+                MigrationManager::register_device_instance(
+                    VirtioNetState::descriptor(),
+                    device.clone(),
+                    &device_cfg.id,
+                );
+                device
             }
         } else {
             let device = Arc::new(Mutex::new(virtio::Net::new(device_cfg.clone())));
@@ -779,6 +879,12 @@ pub trait MachineOps {
                 device.clone(),
                 &device_cfg.id,
             );
+            if let Some(net_dev_list) = self.get_net_dev_list() {
+                net_dev_list
+                    .lock()
+                    .unwrap()
+                    .insert(device_cfg.id.clone(), device.clone());
+            }
             device
         };
         self.add_virtio_pci_device(&device_cfg.id, &bdf, device, multi_func, need_irqfd)?;
@@ -1207,10 +1313,42 @@ pub trait MachineOps {
                     self.add_virtio_pci_scsi(vm_config, cfg_args)?;
                 }
                 "scsi-hd" => {
-                    self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_DISK)?;
+                    self.add_scsi_device(
+                        vm_config,
+                        cfg_args,
+                        SCSI_TYPE_DISK,
+                        ScsiDevKind::Emulated,
+                    )?;
                 }
                 "scsi-cd" => {
-                    self.add_scsi_device(vm_config, cfg_args, SCSI_TYPE_ROM)?;
+                    self.add_scsi_device(
+                        vm_config,
+                        cfg_args,
+                        SCSI_TYPE_ROM,
+                        ScsiDevKind::Emulated,
+                    )?;
+                }
+                "scsi-generic" => {
+                    self.add_scsi_device(
+                        vm_config,
+                        cfg_args,
+                        SCSI_TYPE_NOT_PRESENT,
+                        ScsiDevKind::Generic,
+                    )?;
+                }
+                "scsi-block" => {
+                    self.add_scsi_device(
+                        vm_config,
+                        cfg_args,
+                        SCSI_TYPE_NOT_PRESENT,
+                        ScsiDevKind::Block,
+                    )?;
+                }
+                "nvme" => {
+                    self.add_nvme(vm_config, cfg_args)?;
+                }
+                "nvme-ns" => {
+                    self.add_nvme_ns(vm_config, cfg_args)?;
                 }
                 "virtio-net-device" => {
                     self.add_virtio_mmio_net(vm_config, cfg_args)?;
@@ -1274,6 +1412,11 @@ pub trait MachineOps {
             }
         }
 
+        for dump_cfg in cloned_vm_config.object.filter_dump.values() {
+            self.add_filter_dump(dump_cfg)
+                .with_context(|| format!("Failed to add filter-dump {}", dump_cfg.id))?;
+        }
+
         Ok(())
     }
 
@@ -1339,6 +1482,10 @@ pub trait MachineOps {
         let files = self.get_drive_files();
         let mut drive_files = files.lock().unwrap();
         VmConfig::add_drive_file(&mut drive_files, path, read_only, direct)?;
+        // The export of NBD server is not a host file.
+        if is_nbd_path(path) {
+            return Ok(());
+        }
 
         // Lock the added file if VM is running.
         let drive_file = drive_files.get_mut(path).unwrap();
@@ -1366,7 +1513,11 @@ pub trait MachineOps {
             if drive_file.locked {
                 continue;
             }
-            lock_file(&drive_file.file, &drive_file.path, drive_file.read_only)?;
+            lock_file(
+                &drive_file.file,
+                &drive_file.path,
+                drive_file.read_only || drive_file.shared,
+            )?;
             drive_file.locked = true;
         }
         Ok(())
@@ -1479,6 +1630,13 @@ pub trait MachineOps {
             }
         }
 
+        // Retry the block requests kept by the `stop` error action.
+        if let Some(blk_dev_list) = self.get_blk_dev_list() {
+            for blk in blk_dev_list.lock().unwrap().values() {
+                blk.lock().unwrap().retry_failed_requests();
+            }
+        }
+
         *vm_state = KvmVmState::Running;
 
         Ok(())
@@ -67,9 +67,9 @@ use hypervisor::kvm::KVM_FDS;
 use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
 use machine_manager::{
     config::{
-        parse_blk, parse_incoming_uri, parse_net, BlkDevConfig, BootSource, ConfigCheck, DriveFile,
-        Incoming, MigrateMode, NetworkInterfaceConfig, SerialConfig, VmConfig,
-        DEFAULT_VIRTQUEUE_SIZE,
+        parse_blk, parse_incoming_uri, parse_net, BlkDevConfig, BlockErrorAction, BootSource,
+        ConfigCheck, DiskFormat, DriveFile, Incoming, MigrateMode, NetRateLimit,
+        NetworkInterfaceConfig, SerialConfig, ThrottleConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
     },
     event,
     machine::{
@@ -88,6 +88,7 @@ use syscall::syscall_whitelist;
 use util::device_tree::{self, CompileFDT, FdtBuilder};
 use util::{
     loop_context::EventLoopManager, num_ops::str_to_usize, seccomp::BpfRule, set_termi_canon_mode,
+    usernet::UserNetConfig,
 };
 use virtio::{
     create_tap, qmp_balloon, qmp_query_balloon, Block, BlockState, Net, VhostKern, VirtioDevice,
@@ -1157,15 +1158,33 @@ impl DeviceInterface for LightMachine {
         } else {
             true
         };
+        let format = match args
+            .driver
+            .as_deref()
+            .unwrap_or("raw")
+            .parse::<DiskFormat>()
+        {
+            Ok(format) => format,
+            Err(_) => {
+                let err_str = format!("Unsupported block driver {:?}", args.driver);
+                error!("{}", err_str);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(err_str),
+                    None,
+                );
+            }
+        };
 
         let config = BlkDevConfig {
             id: args.node_name.clone(),
             path_on_host: args.file.filename.clone(),
             read_only,
             direct,
+            format,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            throttle_group: None,
             queues: 1,
             boot_index: None,
             chardev: None,
@@ -1177,6 +1196,13 @@ impl DeviceInterface for LightMachine {
                 AioEngine::Off
             },
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            werror: BlockErrorAction::Report,
+            rerror: BlockErrorAction::Report,
+            copy_on_read: false,
+            zone_size: None,
+            conventional_zones: 0,
+            max_open_zones: 0,
+            max_active_zones: 0,
         };
         if let Err(e) = config.check() {
             error!("{:?}", e);
@@ -1227,6 +1253,10 @@ impl DeviceInterface for LightMachine {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            netdev: args.id.clone(),
+            rate_limit: NetRateLimit::default(),
         };
 
         if let Some(fds) = args.fds {
@@ -1268,6 +1298,8 @@ impl DeviceInterface for LightMachine {
                     None,
                 );
             }
+        } else if args.net_type.as_deref() == Some("user") {
+            config.user = Some(UserNetConfig::default());
         }
 
         match self.add_replaceable_config(&args.id, Arc::new(config)) {
@@ -75,9 +75,21 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_eventfd2),
         BpfRule::new(libc::SYS_epoll_ctl),
         BpfRule::new(libc::SYS_fdatasync),
+        BpfRule::new(libc::SYS_fallocate),
         BpfRule::new(libc::SYS_recvmsg),
         BpfRule::new(libc::SYS_sendmsg),
         BpfRule::new(libc::SYS_recvfrom),
+        BpfRule::new(libc::SYS_sendto),
+        BpfRule::new(libc::SYS_socket),
+        BpfRule::new(libc::SYS_socketpair),
+        BpfRule::new(libc::SYS_bind),
+        BpfRule::new(libc::SYS_listen),
+        BpfRule::new(libc::SYS_connect),
+        BpfRule::new(libc::SYS_getsockopt),
+        BpfRule::new(libc::SYS_setsockopt),
+        BpfRule::new(libc::SYS_shutdown),
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
         BpfRule::new(libc::SYS_mremap),
         BpfRule::new(libc::SYS_io_setup),
         BpfRule::new(libc::SYS_brk),
@@ -76,6 +76,7 @@ use super::{AcpiBuilder, Result as StdResult, StdMachineOps};
 use crate::MachineOps;
 use anyhow::{anyhow, bail, Context, Result};
 use virtio::ScsiCntlr::ScsiCntlrMap;
+use virtio::{BlockMap, NetMap};
 
 /// The type of memory layout entry on aarch64
 pub enum LayoutEntryType {
@@ -140,6 +141,8 @@ pub struct StdMachine {
     vm_config: Arc<Mutex<VmConfig>>,
     /// Reset request, handle VM `Reset` event.
     reset_req: Arc<EventFd>,
+    /// Stop request from devices, e.g. on block IO error.
+    stop_req: Arc<EventFd>,
     /// Device Tree Blob.
     dtb_vec: Vec<u8>,
     /// List of guest NUMA nodes information.
@@ -150,6 +153,10 @@ pub struct StdMachine {
     fwcfg_dev: Option<Arc<Mutex<FwCfgMem>>>,
     /// Scsi Controller List.
     scsi_cntlr_list: ScsiCntlrMap,
+    /// Virtio Block Device List.
+    blk_dev_list: BlockMap,
+    /// Virtio Net Device List.
+    net_dev_list: NetMap,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
 }
@@ -201,11 +208,18 @@ impl StdMachine {
                     anyhow!(MachineError::InitEventFdErr("reset_req".to_string()))
                 })?,
             ),
+            stop_req: Arc::new(
+                EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+                    anyhow!(MachineError::InitEventFdErr("stop_req".to_string()))
+                })?,
+            ),
             dtb_vec: Vec::new(),
             numa_nodes: None,
             boot_order_list: Arc::new(Mutex::new(Vec::new())),
             fwcfg_dev: None,
             scsi_cntlr_list: Arc::new(Mutex::new(HashMap::new())),
+            blk_dev_list: Arc::new(Mutex::new(HashMap::new())),
+            net_dev_list: Arc::new(Mutex::new(HashMap::new())),
             drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
         })
     }
@@ -501,8 +515,11 @@ impl MachineOps for StdMachine {
         let mut locked_vm = vm.lock().unwrap();
         locked_vm.init_global_config(vm_config)?;
         locked_vm
-            .register_reset_event(locked_vm.reset_req.clone(), clone_vm)
+            .register_reset_event(locked_vm.reset_req.clone(), clone_vm.clone())
             .with_context(|| "Fail to register reset event")?;
+        locked_vm
+            .register_stop_event(locked_vm.stop_req.clone(), clone_vm)
+            .with_context(|| "Fail to register stop event")?;
         locked_vm.numa_nodes = locked_vm.add_numa_nodes(vm_config)?;
         locked_vm.init_memory(
             &vm_config.machine_config.mem_config,
@@ -669,9 +686,17 @@ impl MachineOps for StdMachine {
         Some(self.boot_order_list.clone())
     }
 
-    fn get_scsi_cntlr_list(&mut self) -> Option<&ScsiCntlrMap> {
+    fn get_scsi_cntlr_list(&self) -> Option<&ScsiCntlrMap> {
         Some(&self.scsi_cntlr_list)
     }
+
+    fn get_blk_dev_list(&self) -> Option<&BlockMap> {
+        Some(&self.blk_dev_list)
+    }
+
+    fn get_net_dev_list(&self) -> Option<&NetMap> {
+        Some(&self.net_dev_list)
+    }
 }
 
 impl AcpiBuilder for StdMachine {
@@ -1081,6 +1106,7 @@ impl EventLoopManager for StdMachine {
     }
 
     fn loop_cleanup(&self) -> util::Result<()> {
+        self.store_dirty_bitmaps();
         set_termi_canon_mode().with_context(|| "Failed to set terminal to canonical mode")?;
         Ok(())
     }
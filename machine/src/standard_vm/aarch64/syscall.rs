@@ -80,6 +80,7 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_ppoll),
         BpfRule::new(libc::SYS_fdatasync),
+        BpfRule::new(libc::SYS_fallocate),
         BpfRule::new(libc::SYS_recvmsg),
         BpfRule::new(libc::SYS_sendmsg),
         #[cfg(target_env = "gnu")]
@@ -121,6 +122,7 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_statx),
         BpfRule::new(libc::SYS_mkdirat),
         BpfRule::new(libc::SYS_unlinkat),
+        BpfRule::new(libc::SYS_renameat),
         madvise_rule(),
         BpfRule::new(libc::SYS_msync),
         BpfRule::new(libc::SYS_readlinkat),
@@ -128,6 +130,10 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_bind),
         BpfRule::new(libc::SYS_connect),
+        BpfRule::new(libc::SYS_listen),
+        BpfRule::new(libc::SYS_socketpair),
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
         BpfRule::new(libc::SYS_getcwd),
         BpfRule::new(libc::SYS_clone),
         BpfRule::new(libc::SYS_prctl),
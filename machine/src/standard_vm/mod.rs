@@ -39,6 +39,7 @@ use std::mem::size_of;
 use std::ops::Deref;
 use std::os::unix::io::RawFd;
 use std::os::unix::prelude::AsRawFd;
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
@@ -54,23 +55,38 @@ use address_space::{
     AddressRange, FileBackend, GuestAddress, HostMemMapping, Region, RegionIoEventFd, RegionOps,
 };
 pub use anyhow::Result;
-use anyhow::{bail, Context};
+use anyhow::{anyhow, bail, Context};
+use block_backend::dirty_bitmap::DirtyBitmap;
+use block_backend::job::{
+    cancel_block_job, complete_block_job, device_has_block_job, has_block_job, pause_block_job,
+    query_block_jobs, resume_block_job, set_block_job_speed, start_block_job, BlockJob,
+    BlockJobOps,
+};
+use block_backend::mirror::{MirrorPivotFn, MirrorReleaseFn, MirrorSync};
+use block_backend::nbd::server::{
+    nbd_server_add, nbd_server_remove, nbd_server_remove_device, nbd_server_start, nbd_server_stop,
+    NbdExport,
+};
+use block_backend::qcow2::{create_qcow2_image, CreateOptions};
 use cpu::{CpuTopology, CPU};
 use devices::legacy::FwCfgOps;
 use machine_manager::config::{
-    get_chardev_config, get_netdev_config, get_pci_df, BlkDevConfig, ChardevType, ConfigCheck,
-    DriveConfig, NetworkInterfaceConfig, NumaNode, NumaNodes, PciBdf, ScsiCntlrConfig, VmConfig,
-    DEFAULT_VIRTQUEUE_SIZE, MAX_VIRTIO_QUEUE,
+    get_chardev_config, get_filter_dump_config, get_netdev_config, get_pci_df, BlkDevConfig,
+    BlockErrorAction, ChardevType, ConfigCheck, DiskFormat, DriveConfig, NbdAddr, NetRateLimit,
+    NetworkInterfaceConfig, NumaNode, NumaNodes, PciBdf, ScsiCntlrConfig, ThrottleConfig,
+    ThrottleLimit, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_VIRTIO_QUEUE,
+};
+use machine_manager::machine::{
+    register_vm_stop_req, DeviceInterface, KvmVmState, MachineLifecycle,
 };
-use machine_manager::machine::{DeviceInterface, KvmVmState};
 use machine_manager::qmp::{qmp_schema, QmpChannel, Response};
 use migration::MigrationManager;
 use pci::hotplug::{handle_plug, handle_unplug_request};
 use pci::PciBus;
 use util::byte_code::ByteCode;
 use virtio::{
-    qmp_balloon, qmp_query_balloon, Block, BlockState, ScsiBus, ScsiCntlr, VhostKern, VhostUser,
-    VirtioDevice, VirtioNetState, VirtioPciDevice,
+    qmp_balloon, qmp_query_balloon, Block, BlockState, ScsiBus, ScsiCntlr, ScsiDisk, VhostKern,
+    VhostUser, VirtioDevice, VirtioNetState, VirtioPciDevice,
 };
 
 #[cfg(target_arch = "aarch64")]
@@ -221,6 +237,42 @@ trait StdMachineOps: AcpiBuilder {
         Ok(())
     }
 
+    /// Register event notifier for the stop request of devices, the VM is
+    /// paused as `stop` command does.
+    ///
+    /// # Arguments
+    ///
+    /// * `stop_req` - Eventfd of the stop request.
+    /// * `clone_vm` - Reference of the StdMachine.
+    fn register_stop_event(
+        &self,
+        stop_req: Arc<EventFd>,
+        clone_vm: Arc<Mutex<StdMachine>>,
+    ) -> MachineResult<()> {
+        let stop_req_fd = stop_req.as_raw_fd();
+        let stop_req_handler: Rc<NotifierCallback> = Rc::new(move |_, _| {
+            read_fd(stop_req_fd);
+            let locked_vm = clone_vm.lock().unwrap();
+            let vm_state = *locked_vm.get_vm_state().0.lock().unwrap();
+            // The VM may have been stopped by the previous requests.
+            if vm_state == KvmVmState::Running && !locked_vm.pause() {
+                error!("Failed to stop VM on request of devices");
+            }
+            None
+        });
+        let notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            stop_req_fd,
+            None,
+            EventSet::IN,
+            vec![stop_req_handler],
+        );
+        EventLoop::update_event(vec![notifier], None)
+            .with_context(|| "Failed to register event notifier.")?;
+        register_vm_stop_req(stop_req);
+        Ok(())
+    }
+
     #[cfg(target_arch = "x86_64")]
     fn register_acpi_shutdown_event(
         &self,
@@ -735,6 +787,263 @@ fn get_device_bdf(bus: Option<String>, addr: Option<String>) -> Result<PciBdf> {
     Ok(pci_bdf)
 }
 
+/// The target image of mirror or backup job.
+#[derive(Clone)]
+struct MirrorTarget {
+    path: String,
+    format: DiskFormat,
+    /// The image reads as zeros.
+    zeroed: bool,
+    /// Node name of the block backend added by `blockdev-add`, whose image
+    /// file has been registered.
+    node_name: Option<String>,
+}
+
+/// Parse the action on IO error of drive, `report` is used if not specified.
+fn parse_error_action(action: Option<&str>) -> Result<BlockErrorAction> {
+    match action {
+        Some(action) => action
+            .parse::<BlockErrorAction>()
+            .map_err(|_| anyhow!("Invalid error action {}", action)),
+        None => Ok(BlockErrorAction::Report),
+    }
+}
+
+/// Check whether the paths refer to the same file.
+fn is_same_file(path: &str, other: &str) -> bool {
+    match (std::fs::canonicalize(path), std::fs::canonicalize(other)) {
+        (Ok(path), Ok(other)) => path == other,
+        _ => false,
+    }
+}
+
+/// Update the image of drives after the block device switches to the image at
+/// `new_path`. The hotplugged drive follows the image of device, so that it
+/// can be deleted correctly.
+fn update_drive_image(
+    vm_config: &Arc<Mutex<VmConfig>>,
+    old_path: &str,
+    new_path: &str,
+    format: DiskFormat,
+) {
+    let mut locked_config = vm_config.lock().unwrap();
+    for drive in locked_config.drives.values_mut() {
+        if drive.path_on_host == old_path {
+            drive.path_on_host = new_path.to_string();
+            drive.format = format;
+        }
+    }
+}
+
+/// Create the image of mirror or backup target with the virtual size
+/// `disk_size`, and the backing file if specified.
+fn create_target_image(
+    path: &str,
+    format: DiskFormat,
+    disk_size: u64,
+    backing: Option<&(String, DiskFormat)>,
+) -> Result<()> {
+    if Path::new(path).exists() {
+        bail!("Target image {} already exists", path);
+    }
+    match format {
+        DiskFormat::Qcow2 => {
+            let mut opts = CreateOptions::new(path, disk_size);
+            if let Some((backing_path, backing_fmt)) = backing {
+                opts.backing_file = Some(backing_path.clone());
+                opts.backing_fmt = Some(*backing_fmt);
+            }
+            create_qcow2_image(&opts)
+        }
+        DiskFormat::Raw => {
+            if backing.is_some() {
+                bail!("Raw target image can not have a backing file for sync mode top");
+            }
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .and_then(|file| file.set_len(disk_size))
+                .map_err(|e| anyhow!(e))
+        }
+    }
+    .with_context(|| format!("Failed to create target image {}", path))
+}
+
+/// Block device which the dirty bitmaps and backup jobs work on.
+enum BlockNode {
+    Virtio(Arc<Mutex<Block>>),
+    Scsi(Arc<Mutex<ScsiDisk::ScsiDevice>>),
+}
+
+/// Configuration of the block device used by backup and query.
+struct BlockNodeInfo {
+    path: String,
+    format: DiskFormat,
+    read_only: bool,
+    direct: bool,
+    iothread: Option<String>,
+    disk_size: u64,
+    backing: Option<(String, DiskFormat)>,
+    removable: bool,
+    locked: bool,
+    tray_open: bool,
+    throttle: ThrottleConfig,
+    throttle_group: Option<String>,
+}
+
+impl BlockNodeInfo {
+    fn device_info(&self, node_name: &str) -> qmp_schema::BlockDeviceInfo {
+        let backing_file = self.backing.as_ref().map(|(path, _)| path.clone());
+        qmp_schema::BlockDeviceInfo {
+            file: self.path.clone(),
+            node_name: node_name.to_string(),
+            ro: self.read_only,
+            drv: self.format.to_string(),
+            backing_file: backing_file.clone(),
+            encrypted: false,
+            bps: self.throttle.bps_total.avg,
+            bps_rd: self.throttle.bps_read.avg,
+            bps_wr: self.throttle.bps_write.avg,
+            iops: self.throttle.iops_total.avg,
+            iops_rd: self.throttle.iops_read.avg,
+            iops_wr: self.throttle.iops_write.avg,
+            group: self.throttle_group.clone(),
+            iothread: self.iothread.clone(),
+            image: qmp_schema::ImageInfo {
+                filename: self.path.clone(),
+                format: self.format.to_string(),
+                virtual_size: self.disk_size,
+                backing_filename: backing_file,
+            },
+        }
+    }
+}
+
+impl BlockNode {
+    fn info(&self) -> BlockNodeInfo {
+        match self {
+            BlockNode::Virtio(blk) => {
+                let locked_blk = blk.lock().unwrap();
+                let blk_cfg = locked_blk.blk_config();
+                BlockNodeInfo {
+                    path: blk_cfg.path_on_host.clone(),
+                    format: blk_cfg.format,
+                    read_only: blk_cfg.read_only,
+                    direct: blk_cfg.direct,
+                    iothread: blk_cfg.iothread.clone(),
+                    disk_size: locked_blk.disk_size(),
+                    backing: locked_blk.backing_file(),
+                    removable: false,
+                    locked: false,
+                    tray_open: false,
+                    // The limits may be shared from the throttle group.
+                    throttle: locked_blk.io_throttle().1,
+                    throttle_group: blk_cfg.throttle_group.clone(),
+                }
+            }
+            BlockNode::Scsi(dev) => {
+                let locked_dev = dev.lock().unwrap();
+                BlockNodeInfo {
+                    path: locked_dev.config.path_on_host.clone(),
+                    format: locked_dev.config.format,
+                    read_only: locked_dev.config.read_only,
+                    direct: locked_dev.config.direct,
+                    // Requests of scsi device are processed synchronously.
+                    iothread: None,
+                    disk_size: locked_dev.disk_sectors << ScsiDisk::SECTOR_SHIFT,
+                    backing: locked_dev
+                        .block_backend
+                        .as_ref()
+                        .and_then(|backend| backend.lock().unwrap().backing_file()),
+                    removable: locked_dev.scsi_type == ScsiDisk::SCSI_TYPE_ROM
+                        && locked_dev.kind == ScsiDisk::ScsiDevKind::Emulated,
+                    locked: locked_dev.locked,
+                    tray_open: locked_dev.tray_open,
+                    throttle: ThrottleConfig::default(),
+                    throttle_group: None,
+                }
+            }
+        }
+    }
+
+    fn io_stats(&self) -> qmp_schema::BlockDeviceStats {
+        match self {
+            BlockNode::Virtio(blk) => blk.lock().unwrap().io_stats(),
+            BlockNode::Scsi(dev) => dev.lock().unwrap().stats.query(),
+        }
+    }
+
+    fn add_dirty_bitmap(
+        &self,
+        name: &str,
+        granularity: Option<u64>,
+        persistent: bool,
+    ) -> Result<()> {
+        match self {
+            BlockNode::Virtio(blk) => {
+                blk.lock()
+                    .unwrap()
+                    .add_dirty_bitmap(name, granularity, persistent)
+            }
+            BlockNode::Scsi(dev) => {
+                dev.lock()
+                    .unwrap()
+                    .add_dirty_bitmap(name, granularity, persistent)
+            }
+        }
+    }
+
+    fn remove_dirty_bitmap(&self, name: &str) -> Result<()> {
+        match self {
+            BlockNode::Virtio(blk) => blk.lock().unwrap().remove_dirty_bitmap(name),
+            BlockNode::Scsi(dev) => dev.lock().unwrap().remove_dirty_bitmap(name),
+        }
+    }
+
+    fn get_dirty_bitmap(&self, name: &str) -> Result<Arc<Mutex<DirtyBitmap>>> {
+        match self {
+            BlockNode::Virtio(blk) => blk.lock().unwrap().get_dirty_bitmap(name),
+            BlockNode::Scsi(dev) => dev.lock().unwrap().get_dirty_bitmap(name),
+        }
+    }
+
+    fn nbd_export(&self, export: &str, writable: bool, bitmap: Option<&str>) -> Result<NbdExport> {
+        match self {
+            BlockNode::Virtio(blk) => blk.lock().unwrap().nbd_export(export, writable, bitmap),
+            BlockNode::Scsi(dev) => dev.lock().unwrap().nbd_export(export, writable, bitmap),
+        }
+    }
+
+    /// Create the job to copy the data of device to `target`.
+    fn mirror_job(
+        &self,
+        target: &MirrorTarget,
+        sync: MirrorSync,
+        pivot: MirrorPivotFn,
+        release: MirrorReleaseFn,
+    ) -> Result<Box<dyn BlockJobOps>> {
+        Ok(match self {
+            BlockNode::Virtio(blk) => Box::new(blk.lock().unwrap().mirror_job(
+                &target.path,
+                target.format,
+                sync,
+                target.zeroed,
+                pivot,
+                release,
+            )?),
+            BlockNode::Scsi(dev) => Box::new(dev.lock().unwrap().mirror_job(
+                &target.path,
+                target.format,
+                sync,
+                target.zeroed,
+                pivot,
+                release,
+            )?),
+        })
+    }
+}
+
 impl StdMachine {
     fn plug_virtio_pci_blk(
         &mut self,
@@ -757,9 +1066,11 @@ impl StdMachine {
                 path_on_host: conf.path_on_host.clone(),
                 read_only: conf.read_only,
                 direct: conf.direct,
+                format: conf.format,
                 serial_num: args.serial_num.clone(),
                 iothread: args.iothread.clone(),
-                iops: conf.iops,
+                throttle: conf.throttle,
+                throttle_group: conf.throttle_group.clone(),
                 queues: args.queues.unwrap_or_else(|| {
                     VirtioPciDevice::virtio_pci_auto_queues_num(0, nr_cpus, MAX_VIRTIO_QUEUE)
                 }),
@@ -768,6 +1079,13 @@ impl StdMachine {
                 socket_path: None,
                 aio: conf.aio,
                 queue_size,
+                werror: conf.werror,
+                rerror: conf.rerror,
+                copy_on_read: conf.copy_on_read,
+                zone_size: None,
+                conventional_zones: 0,
+                max_open_zones: 0,
+                max_active_zones: 0,
             };
             dev.check()?;
             dev
@@ -794,529 +1112,1784 @@ impl StdMachine {
             }
         }
 
+        if let Some(blk_dev_list) = self.get_blk_dev_list() {
+            blk_dev_list
+                .lock()
+                .unwrap()
+                .insert(blk_id.clone(), blk.clone());
+        }
         MigrationManager::register_device_instance(BlockState::descriptor(), blk, &blk_id);
         Ok(())
     }
 
-    fn plug_virtio_pci_scsi(
-        &mut self,
-        pci_bdf: &PciBdf,
-        args: &qmp_schema::DeviceAddArgument,
-    ) -> Result<()> {
-        let multifunction = args.multifunction.unwrap_or(false);
-        let nr_cpus = self.get_vm_config().lock().unwrap().machine_config.nr_cpus;
-        let queue_size = args.queue_size.unwrap_or(DEFAULT_VIRTQUEUE_SIZE);
-        let dev_cfg = ScsiCntlrConfig {
-            id: args.id.clone(),
-            iothread: args.iothread.clone(),
-            queues: args.queues.unwrap_or_else(|| {
-                VirtioPciDevice::virtio_pci_auto_queues_num(0, nr_cpus, MAX_VIRTIO_QUEUE)
-            }) as u32,
-            boot_prefix: None,
-            queue_size,
-        };
-        dev_cfg.check()?;
-
-        let device = Arc::new(Mutex::new(ScsiCntlr::ScsiCntlr::new(dev_cfg.clone())));
+    fn get_virtio_blk(&self, device: &str) -> Result<Arc<Mutex<Block>>> {
+        self.get_blk_dev_list()
+            .and_then(|list| list.lock().unwrap().get(device).cloned())
+            .with_context(|| format!("Block device {} not found", device))
+    }
 
-        let bus_name = format!("{}.0", dev_cfg.id);
-        ScsiBus::create_scsi_bus(&bus_name, &device)?;
+    /// Get the virtio-blk or scsi-hd device with the id.
+    fn get_block_node(&self, id: &str) -> Result<BlockNode> {
+        if let Ok(blk) = self.get_virtio_blk(id) {
+            return Ok(BlockNode::Virtio(blk));
+        }
         if let Some(cntlr_list) = self.get_scsi_cntlr_list() {
-            let mut lock_cntlr_list = cntlr_list.lock().unwrap();
-            lock_cntlr_list.insert(bus_name.clone(), device.clone());
-        } else {
-            bail!("No scsi controller list found");
+            for cntlr in cntlr_list.lock().unwrap().values() {
+                let bus = match cntlr.lock().unwrap().bus.clone() {
+                    Some(bus) => bus,
+                    None => continue,
+                };
+                for dev in bus.lock().unwrap().devices.values() {
+                    if dev.lock().unwrap().config.id == id {
+                        return Ok(BlockNode::Scsi(dev.clone()));
+                    }
+                }
+            }
         }
+        bail!("Block device {} not found", id)
+    }
 
-        let result =
-            self.add_virtio_pci_device(&args.id, pci_bdf, device.clone(), multifunction, false);
-        let pci_dev = if let Err(ref e) = result {
-            // SAFETY: unwrap is safe because Standard machine always make sure it not return null.
-            self.get_scsi_cntlr_list()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .remove(&bus_name);
-            bail!("Failed to add virtio scsi controller, error is {:?}", e);
-        } else {
-            result.unwrap()
-        };
+    /// Get all the virtio-blk and scsi devices with their ids, sorted by id.
+    fn block_nodes(&self) -> Vec<(String, BlockNode)> {
+        let mut nodes = Vec::new();
+        if let Some(blk_dev_list) = self.get_blk_dev_list() {
+            for (id, blk) in blk_dev_list.lock().unwrap().iter() {
+                nodes.push((id.clone(), BlockNode::Virtio(blk.clone())));
+            }
+        }
+        if let Some(cntlr_list) = self.get_scsi_cntlr_list() {
+            for cntlr in cntlr_list.lock().unwrap().values() {
+                let bus = match cntlr.lock().unwrap().bus.clone() {
+                    Some(bus) => bus,
+                    None => continue,
+                };
+                for dev in bus.lock().unwrap().devices.values() {
+                    let id = dev.lock().unwrap().config.id.clone();
+                    nodes.push((id, BlockNode::Scsi(dev.clone())));
+                }
+            }
+        }
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        nodes
+    }
 
-        device.lock().unwrap().config.boot_prefix = pci_dev.lock().unwrap().get_dev_path();
+    /// Get the id of the drive which uses the image at `path`.
+    fn drive_id_of(&self, path: &str) -> String {
+        self.get_vm_config()
+            .lock()
+            .unwrap()
+            .drives
+            .values()
+            .find(|drive| drive.path_on_host == path)
+            .map(|drive| drive.id.clone())
+            .unwrap_or_default()
+    }
 
-        Ok(())
+    /// Store the persistent dirty bitmaps of all block devices when VM exits.
+    fn store_dirty_bitmaps(&self) {
+        if let Some(blk_dev_list) = self.get_blk_dev_list() {
+            for (id, blk) in blk_dev_list.lock().unwrap().iter() {
+                if let Err(e) = blk.lock().unwrap().store_dirty_bitmaps() {
+                    error!("Failed to store dirty bitmaps of {}: {:?}", id, e);
+                }
+            }
+        }
+        if let Some(cntlr_list) = self.get_scsi_cntlr_list() {
+            for cntlr in cntlr_list.lock().unwrap().values() {
+                let bus = match cntlr.lock().unwrap().bus.clone() {
+                    Some(bus) => bus,
+                    None => continue,
+                };
+                for dev in bus.lock().unwrap().devices.values() {
+                    let locked_dev = dev.lock().unwrap();
+                    if let Err(e) = locked_dev.store_dirty_bitmaps() {
+                        error!(
+                            "Failed to store dirty bitmaps of {}: {:?}",
+                            locked_dev.config.id, e
+                        );
+                    }
+                }
+            }
+        }
     }
 
-    fn plug_vhost_user_blk_pci(
-        &mut self,
-        pci_bdf: &PciBdf,
-        args: &qmp_schema::DeviceAddArgument,
+    fn snapshot_external(
+        &self,
+        device: &str,
+        snapshot_file: &str,
+        format: Option<&str>,
+        mode: Option<&str>,
     ) -> Result<()> {
-        let multifunction = args.multifunction.unwrap_or(false);
-        let vm_config = self.get_vm_config();
-        let locked_vmconfig = vm_config.lock().unwrap();
-        let chardev = if let Some(dev) = &args.chardev {
-            dev
-        } else {
-            bail!("Chardev not set");
-        };
-        let queue_size = args.queue_size.unwrap_or(DEFAULT_VIRTQUEUE_SIZE);
-        let socket_path = self
-            .get_socket_path(&locked_vmconfig, chardev.to_string())
-            .with_context(|| "Failed to get socket path")?;
-        let nr_cpus = locked_vmconfig.machine_config.nr_cpus;
-        let dev = BlkDevConfig {
-            id: args.id.clone(),
-            queues: args.queues.unwrap_or_else(|| {
-                VirtioPciDevice::virtio_pci_auto_queues_num(0, nr_cpus, MAX_VIRTIO_QUEUE)
-            }),
-            boot_index: args.boot_index,
-            chardev: Some(chardev.to_string()),
-            socket_path,
-            queue_size,
-            ..BlkDevConfig::default()
+        let format = format.unwrap_or("qcow2");
+        if format != "qcow2" {
+            bail!("Unsupported snapshot format {}", format);
+        }
+        let create = match mode.unwrap_or("absolute-paths") {
+            "absolute-paths" => true,
+            "existing" => false,
+            mode => bail!("Invalid snapshot mode {}", mode),
         };
 
-        dev.check()?;
-        drop(locked_vmconfig);
-
-        let blk = Arc::new(Mutex::new(VhostUser::Block::new(&dev, self.get_sys_mem())));
-        self.add_virtio_pci_device(&args.id, pci_bdf, blk, multifunction, true)
-            .with_context(|| "Failed to add vhost user blk pci device")?;
+        let blk = self.get_virtio_blk(device)?;
+        let mut locked_blk = blk.lock().unwrap();
+        let blk_cfg = locked_blk.blk_config().clone();
+        if blk_cfg.path_on_host.is_empty() {
+            bail!("No image is opened by block device {}", device);
+        }
+        if blk_cfg.read_only {
+            bail!("Block device {} is read only", device);
+        }
+        if create {
+            if Path::new(snapshot_file).exists() {
+                bail!("Snapshot file {} already exists", snapshot_file);
+            }
+            let backing = std::fs::canonicalize(&blk_cfg.path_on_host)
+                .with_context(|| format!("Invalid image path {}", blk_cfg.path_on_host))?;
+            let mut opts = CreateOptions::new(snapshot_file, locked_blk.disk_size());
+            opts.backing_file = Some(backing.to_string_lossy().to_string());
+            opts.backing_fmt = Some(blk_cfg.format);
+            create_qcow2_image(&opts)
+                .with_context(|| format!("Failed to create snapshot file {}", snapshot_file))?;
+        }
 
+        self.register_drive_file(snapshot_file, false, blk_cfg.direct)?;
+        if let Err(e) = locked_blk.switch_image(snapshot_file, DiskFormat::Qcow2) {
+            self.unregister_drive_file(snapshot_file)?;
+            return Err(e);
+        }
+        drop(locked_blk);
+        self.unregister_drive_file(&blk_cfg.path_on_host)?;
+
+        update_drive_image(
+            &self.get_vm_config(),
+            &blk_cfg.path_on_host,
+            snapshot_file,
+            DiskFormat::Qcow2,
+        );
         Ok(())
     }
 
-    fn get_socket_path(&self, vm_config: &VmConfig, chardev: String) -> Result<Option<String>> {
-        let char_dev = if let Some(char_dev) = vm_config.chardev.get(&chardev) {
-            char_dev
-        } else {
-            bail!("Chardev: {:?} not found for character device", &chardev);
-        };
+    /// Start a job to mirror the image of block device to `target`, the device
+    /// switches to `target` when the job is completed by `block-job-complete`
+    /// after the data is synchronized.
+    fn mirror_image(
+        &self,
+        device: &str,
+        job_id: Option<&str>,
+        target: MirrorTarget,
+        sync: MirrorSync,
+        speed: u64,
+    ) -> Result<()> {
+        let job_id = job_id.unwrap_or(device);
+        if has_block_job(job_id) {
+            bail!("Block job {} already exists", job_id);
+        }
+        if device_has_block_job(device) {
+            bail!("Block device {} is in use by block job", device);
+        }
+        let blk = self.get_virtio_blk(device)?;
+        let locked_blk = blk.lock().unwrap();
+        let blk_cfg = locked_blk.blk_config().clone();
+        if blk_cfg.read_only {
+            bail!("Block device {} is read only", device);
+        }
+        if is_same_file(&target.path, &blk_cfg.path_on_host) {
+            bail!("Target image is the same as the image of {}", device);
+        }
 
-        let socket_path = match &char_dev.backend {
-            ChardevType::Socket {
-                path,
-                server,
-                nowait,
-            } => {
-                if *server || *nowait {
-                    bail!(
-                        "Argument \'server\' or \'nowait\' is not needed for chardev \'{}\'",
-                        path
-                    );
-                }
-                Some(path.clone())
+        if target.node_name.is_none() {
+            self.register_drive_file(&target.path, false, blk_cfg.direct)?;
+        }
+        let blk_clone = blk.clone();
+        let drive_files = self.get_drive_files();
+        let vm_config = self.get_vm_config();
+        let source = blk_cfg.path_on_host.clone();
+        let target_clone = target.clone();
+        let pivot = Box::new(move || {
+            blk_clone
+                .lock()
+                .unwrap()
+                .switch_image(&target_clone.path, target_clone.format)?;
+            VmConfig::remove_drive_file(&mut drive_files.lock().unwrap(), &source)?;
+            update_drive_image(&vm_config, &source, &target_clone.path, target_clone.format);
+            // The image file of block backend is taken over by the drive of device.
+            if let Some(node_name) = target_clone.node_name.as_ref() {
+                vm_config.lock().unwrap().drives.remove(node_name);
             }
-            _ => {
-                bail!("Chardev {:?} backend should be socket type.", &chardev);
+            Ok(())
+        });
+        let drive_files = self.get_drive_files();
+        let target_clone = target.clone();
+        let release = Box::new(move || {
+            if target_clone.node_name.is_some() {
+                return;
+            }
+            let mut locked_files = drive_files.lock().unwrap();
+            if let Err(e) = VmConfig::remove_drive_file(&mut locked_files, &target_clone.path) {
+                error!(
+                    "Failed to release target image {}: {:?}",
+                    target_clone.path, e
+                );
+            }
+        });
+        let mirror = match locked_blk.mirror_job(
+            &target.path,
+            target.format,
+            sync,
+            target.zeroed,
+            pivot,
+            release,
+        ) {
+            Ok(mirror) => mirror,
+            Err(e) => {
+                if target.node_name.is_none() {
+                    self.unregister_drive_file(&target.path)?;
+                }
+                return Err(e);
             }
         };
-
-        Ok(socket_path)
+        let mut job = BlockJob::new(
+            job_id,
+            device,
+            "mirror",
+            blk_cfg.iothread.clone(),
+            speed,
+            Box::new(mirror),
+        );
+        job.set_manual_complete(true);
+        // The job locks the device when pivoting in iothread.
+        drop(locked_blk);
+        start_block_job(job)
     }
 
-    fn plug_virtio_pci_net(
-        &mut self,
-        pci_bdf: &PciBdf,
-        args: &qmp_schema::DeviceAddArgument,
+    fn commit_active_image(
+        &self,
+        device: &str,
+        job_id: Option<&str>,
+        base: Option<&str>,
+        top: Option<&str>,
+        speed: u64,
     ) -> Result<()> {
-        let multifunction = args.multifunction.unwrap_or(false);
-        let netdev = if let Some(dev) = &args.netdev {
-            dev
-        } else {
-            bail!("Netdev not set");
-        };
-        let queue_size = args.queue_size.unwrap_or(DEFAULT_VIRTQUEUE_SIZE);
-        let vm_config = self.get_vm_config();
-        let mut locked_vmconfig = vm_config.lock().unwrap();
-        let dev = if let Some(conf) = locked_vmconfig.netdevs.get(netdev) {
-            let mut socket_path: Option<String> = None;
-            if let Some(chardev) = &conf.chardev {
-                socket_path = self
-                    .get_socket_path(&locked_vmconfig, (&chardev).to_string())
-                    .with_context(|| "Failed to get socket path")?;
+        let job_id = job_id.unwrap_or(device);
+        if has_block_job(job_id) {
+            bail!("Block job {} already exists", job_id);
+        }
+        if device_has_block_job(device) {
+            bail!("Block device {} is in use by block job", device);
+        }
+        let blk = self.get_virtio_blk(device)?;
+        let locked_blk = blk.lock().unwrap();
+        let blk_cfg = locked_blk.blk_config().clone();
+        if blk_cfg.read_only {
+            bail!("Block device {} is read only", device);
+        }
+        let (base_path, base_format) = locked_blk
+            .backing_file()
+            .with_context(|| format!("Image of block device {} has no backing file", device))?;
+        if let Some(top) = top {
+            if !is_same_file(top, &blk_cfg.path_on_host) {
+                bail!(
+                    "Only the active image of block device {} can be committed",
+                    device
+                );
+            }
+        }
+        if let Some(base) = base {
+            if !is_same_file(base, &base_path) {
+                bail!("Only committing to the immediate backing file is supported");
+            }
+        }
+
+        self.register_drive_file(&base_path, false, blk_cfg.direct)?;
+        let blk_clone = blk.clone();
+        let drive_files = self.get_drive_files();
+        let vm_config = self.get_vm_config();
+        let top_path = blk_cfg.path_on_host.clone();
+        let base_clone = base_path.clone();
+        let pivot = Box::new(move || {
+            blk_clone
+                .lock()
+                .unwrap()
+                .switch_image(&base_clone, base_format)?;
+            VmConfig::remove_drive_file(&mut drive_files.lock().unwrap(), &top_path)?;
+            update_drive_image(&vm_config, &top_path, &base_clone, base_format);
+            Ok(())
+        });
+        let drive_files = self.get_drive_files();
+        let base_clone = base_path.clone();
+        let release = Box::new(move || {
+            if let Err(e) =
+                VmConfig::remove_drive_file(&mut drive_files.lock().unwrap(), &base_clone)
+            {
+                error!("Failed to release base image {}: {:?}", base_clone, e);
+            }
+        });
+        let commit = match locked_blk.commit_job(&base_path, base_format, pivot, release) {
+            Ok(commit) => commit,
+            Err(e) => {
+                self.unregister_drive_file(&base_path)?;
+                return Err(e);
             }
-            let dev = NetworkInterfaceConfig {
-                id: args.id.clone(),
-                host_dev_name: conf.ifname.clone(),
-                mac: args.mac.clone(),
-                tap_fds: conf.tap_fds.clone(),
-                vhost_type: conf.vhost_type.clone(),
-                vhost_fds: conf.vhost_fds.clone(),
-                iothread: args.iothread.clone(),
-                queues: conf.queues,
-                mq: conf.queues > 2,
-                socket_path,
-                queue_size,
-            };
-            dev.check()?;
-            dev
-        } else {
-            bail!("Netdev not found");
         };
-        locked_vmconfig.add_net_device_config(args);
-        drop(locked_vmconfig);
+        let job = BlockJob::new(
+            job_id,
+            device,
+            "commit",
+            blk_cfg.iothread.clone(),
+            speed,
+            Box::new(commit),
+        );
+        // The job locks the device when pivoting in iothread.
+        drop(locked_blk);
+        start_block_job(job)
+    }
 
-        if dev.vhost_type.is_some() {
-            let mut need_irqfd = false;
-            let net: Arc<Mutex<dyn VirtioDevice>> =
-                if dev.vhost_type == Some(String::from("vhost-kernel")) {
-                    Arc::new(Mutex::new(VhostKern::Net::new(&dev, self.get_sys_mem())))
-                } else {
-                    need_irqfd = true;
-                    Arc::new(Mutex::new(VhostUser::Net::new(&dev, self.get_sys_mem())))
-                };
-            self.add_virtio_pci_device(&args.id, pci_bdf, net, multifunction, need_irqfd)
-                .with_context(|| "Failed to add vhost-kernel/vhost-user net device")?;
-        } else {
-            let net_id = dev.id.clone();
-            let net = Arc::new(Mutex::new(virtio::Net::new(dev)));
-            self.add_virtio_pci_device(&args.id, pci_bdf, net.clone(), multifunction, false)
-                .with_context(|| "Failed to add virtio net device")?;
-            MigrationManager::register_device_instance(VirtioNetState::descriptor(), net, &net_id);
+    /// Get the scsi cd-rom whose medium is removed or replaced by management.
+    fn get_removable_device(&self, device: &str) -> Result<Arc<Mutex<ScsiDisk::ScsiDevice>>> {
+        if device_has_block_job(device) {
+            bail!("Block device {} is in use by block job", device);
         }
+        match self.get_block_node(device)? {
+            BlockNode::Scsi(dev) => Ok(dev),
+            BlockNode::Virtio(_) => bail!("Block device {} is not removable", device),
+        }
+    }
 
+    fn eject_medium(&self, device: &str, force: bool) -> Result<()> {
+        let dev = self.get_removable_device(device)?;
+        let format = dev.lock().unwrap().config.format;
+        let path = dev.lock().unwrap().eject(force)?;
+        nbd_server_remove_device(device);
+        if !path.is_empty() {
+            self.unregister_drive_file(&path)?;
+            update_drive_image(&self.get_vm_config(), &path, "", format);
+        }
         Ok(())
     }
 
-    fn plug_vfio_pci_device(
-        &mut self,
-        bdf: &PciBdf,
-        args: &qmp_schema::DeviceAddArgument,
+    fn change_medium(
+        &self,
+        device: &str,
+        filename: &str,
+        format: Option<&str>,
+        force: bool,
     ) -> Result<()> {
-        let host = if args.host.is_none() {
-            ""
-        } else {
-            args.host.as_ref().unwrap()
+        let format = match format {
+            Some(format) => format
+                .parse::<DiskFormat>()
+                .map_err(|_| anyhow!("Unsupported image format {}", format))?,
+            None => DiskFormat::Raw,
         };
-
-        let sysfsdev = if args.sysfsdev.is_none() {
-            ""
-        } else {
-            args.sysfsdev.as_ref().unwrap()
+        let dev = self.get_removable_device(device)?;
+        let direct = dev.lock().unwrap().config.direct;
+        self.register_drive_file(filename, true, direct)?;
+        let old_path = match dev.lock().unwrap().change_medium(filename, format, force) {
+            Ok(path) => path,
+            Err(e) => {
+                self.unregister_drive_file(filename)?;
+                return Err(e);
+            }
         };
+        nbd_server_remove_device(device);
+        if !old_path.is_empty() {
+            self.unregister_drive_file(&old_path)?;
+        }
+        update_drive_image(&self.get_vm_config(), &old_path, filename, format);
+        Ok(())
+    }
 
-        if args.host.is_none() && args.sysfsdev.is_none() {
-            bail!("Neither option \"host\" nor \"sysfsdev\" was not provided.");
+    fn stream_image(&self, device: &str, job_id: Option<&str>, speed: u64) -> Result<()> {
+        let job_id = job_id.unwrap_or(device);
+        if has_block_job(job_id) {
+            bail!("Block job {} already exists", job_id);
         }
+        if device_has_block_job(device) {
+            bail!("Block device {} is in use by block job", device);
+        }
+        let blk = self.get_virtio_blk(device)?;
+        let locked_blk = blk.lock().unwrap();
+        let stream = locked_blk.stream_job()?;
+        let job = BlockJob::new(
+            job_id,
+            device,
+            "stream",
+            locked_blk.blk_config().iothread.clone(),
+            speed,
+            Box::new(stream),
+        );
+        drop(locked_blk);
+        start_block_job(job)
+    }
 
-        if args.host.is_some() && args.sysfsdev.is_some() {
-            bail!("Both option \"host\" and \"sysfsdev\" was provided.");
+    fn drive_mirror_image(&self, args: &qmp_schema::drive_mirror) -> Result<()> {
+        let sync = args.sync.parse::<MirrorSync>()?;
+        let create = match args.mode.as_deref().unwrap_or("absolute-paths") {
+            "absolute-paths" => true,
+            "existing" => false,
+            mode => bail!("Invalid mirror mode {}", mode),
+        };
+        let blk = self.get_virtio_blk(&args.device)?;
+        let locked_blk = blk.lock().unwrap();
+        let format = match args.format.as_deref() {
+            Some(format) => format
+                .parse::<DiskFormat>()
+                .map_err(|_| anyhow!("Unsupported image format {}", format))?,
+            None => locked_blk.blk_config().format,
+        };
+        let disk_size = locked_blk.disk_size();
+        let backing = locked_blk.backing_file();
+        drop(locked_blk);
+
+        // The target image needs the same backing file to mirror the data not in it.
+        let backing = backing.filter(|_| matches!(sync, MirrorSync::Top));
+        if create {
+            create_target_image(&args.target, format, disk_size, backing.as_ref())?;
         }
 
-        if let Err(e) = self.create_vfio_pci_device(
-            &args.id,
-            bdf,
-            host,
-            sysfsdev,
-            args.multifunction.map_or(false, |m| m),
-        ) {
-            error!("{:?}", e);
-            bail!("Failed to plug vfio-pci device.");
+        let target = MirrorTarget {
+            path: args.target.clone(),
+            format,
+            zeroed: create && backing.is_none(),
+            node_name: None,
+        };
+        self.mirror_image(
+            &args.device,
+            args.job_id.as_deref(),
+            target,
+            sync,
+            args.speed.unwrap_or(0),
+        )
+    }
+
+    fn set_block_io_throttle(&self, args: &qmp_schema::block_set_io_throttle) -> Result<()> {
+        let limit = |avg: u64, max: Option<u64>, max_length: Option<u64>| ThrottleLimit {
+            avg,
+            max: max.unwrap_or(0),
+            max_length: max_length.unwrap_or(1),
+        };
+        let throttle = ThrottleConfig {
+            bps_total: limit(args.bps, args.bps_max, args.bps_max_length),
+            bps_read: limit(args.bps_rd, args.bps_rd_max, args.bps_rd_max_length),
+            bps_write: limit(args.bps_wr, args.bps_wr_max, args.bps_wr_max_length),
+            iops_total: limit(args.iops, args.iops_max, args.iops_max_length),
+            iops_read: limit(args.iops_rd, args.iops_rd_max, args.iops_rd_max_length),
+            iops_write: limit(args.iops_wr, args.iops_wr_max, args.iops_wr_max_length),
+        };
+        if matches!(args.group.as_deref(), Some("")) {
+            bail!("Name of throttle group should not be empty");
         }
-        Ok(())
+        let blk = self.get_virtio_blk(&args.device)?;
+        let mut locked_blk = blk.lock().unwrap();
+        locked_blk.set_io_throttle(throttle, args.group.clone())
     }
-}
 
-impl DeviceInterface for StdMachine {
-    fn query_status(&self) -> Response {
-        let vm_state = self.get_vm_state();
-        let vmstate = vm_state.deref().0.lock().unwrap();
-        let qmp_state = match *vmstate {
-            KvmVmState::Running => qmp_schema::StatusInfo {
-                singlestep: false,
-                running: true,
-                status: qmp_schema::RunState::running,
-            },
-            KvmVmState::Paused => qmp_schema::StatusInfo {
-                singlestep: false,
-                running: false,
-                status: qmp_schema::RunState::paused,
+    fn set_net_rate_limit(&self, args: &qmp_schema::net_set_rate_limit) -> Result<()> {
+        let limit = |avg: Option<u64>, max: Option<u64>, max_length: Option<u64>| ThrottleLimit {
+            avg: avg.unwrap_or(0),
+            max: max.unwrap_or(0),
+            max_length: max_length.unwrap_or(1),
+        };
+        let rate_limit = NetRateLimit {
+            rx_bps: limit(args.rx_bps, args.rx_bps_max, args.rx_bps_max_length),
+            tx_bps: limit(args.tx_bps, args.tx_bps_max, args.tx_bps_max_length),
+            rx_pps: limit(args.rx_pps, args.rx_pps_max, args.rx_pps_max_length),
+            tx_pps: limit(args.tx_pps, args.tx_pps_max, args.tx_pps_max_length),
+        };
+        let net = self
+            .get_net_dev_list()
+            .and_then(|list| list.lock().unwrap().get(&args.device).cloned())
+            .with_context(|| format!("Net device {} not found", args.device))?;
+        let mut locked_net = net.lock().unwrap();
+        locked_net.set_rate_limit(rate_limit)
+    }
+
+    fn set_net_link(&self, name: &str, up: bool) -> Result<()> {
+        let net = self
+            .get_net_dev_list()
+            .and_then(|list| {
+                let locked_list = list.lock().unwrap();
+                locked_list.get(name).cloned().or_else(|| {
+                    locked_list
+                        .values()
+                        .find(|net| net.lock().unwrap().netdev_id() == name)
+                        .cloned()
+                })
+            })
+            .with_context(|| format!("Net device {} not found", name))?;
+        let mut locked_net = net.lock().unwrap();
+        locked_net.set_link(up)
+    }
+
+    fn resize_block_device(&self, device: &str, size: u64) -> Result<()> {
+        if device_has_block_job(device) {
+            bail!("Block device {} is in use by block job", device);
+        }
+        match self.get_block_node(device)? {
+            BlockNode::Virtio(blk) => blk.lock().unwrap().resize(size),
+            BlockNode::Scsi(dev) => dev.lock().unwrap().resize(size),
+        }
+    }
+
+    fn start_nbd_server(&self, args: &qmp_schema::nbd_server_start) -> Result<()> {
+        let data = &args.addr.addr_data;
+        let addr = match args.addr.addr_type.as_str() {
+            "unix" => NbdAddr::Unix(
+                data.path
+                    .clone()
+                    .with_context(|| "Socket path of nbd server is not set")?,
+            ),
+            "inet" => NbdAddr::Inet {
+                host: data
+                    .host
+                    .clone()
+                    .with_context(|| "Host of nbd server is not set")?,
+                port: data
+                    .port
+                    .as_ref()
+                    .with_context(|| "Port of nbd server is not set")?
+                    .parse::<u16>()
+                    .with_context(|| "Invalid port of nbd server")?,
             },
-            _ => Default::default(),
+            addr_type => bail!("Unsupported address type {} of nbd server", addr_type),
         };
+        nbd_server_start(&addr, args.max_connections.unwrap_or(0))
+    }
 
-        Response::create_response(serde_json::to_value(&qmp_state).unwrap(), None)
+    fn add_nbd_export(&self, args: &qmp_schema::nbd_server_add) -> Result<()> {
+        let name = args.name.as_ref().unwrap_or(&args.device);
+        let export = self.get_block_node(&args.device)?.nbd_export(
+            name,
+            args.writable.unwrap_or(false),
+            args.bitmap.as_deref(),
+        )?;
+        nbd_server_add(export)
     }
 
-    fn query_cpus(&self) -> Response {
-        let mut cpu_vec: Vec<serde_json::Value> = Vec::new();
-        let cpu_topo = self.get_cpu_topo();
-        let cpus = self.get_cpus();
-        for cpu_index in 0..cpu_topo.max_cpus {
-            if cpu_topo.get_mask(cpu_index as usize) == 1 {
-                let thread_id = cpus[cpu_index as usize].tid();
-                let cpu_instance = cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
-                let cpu_common = qmp_schema::CpuInfoCommon {
-                    current: true,
-                    qom_path: String::from("/machine/unattached/device[")
-                        + &cpu_index.to_string()
-                        + "]",
-                    halted: false,
-                    props: Some(cpu_instance),
-                    CPU: cpu_index as isize,
-                    thread_id: thread_id as isize,
-                };
-                #[cfg(target_arch = "x86_64")]
-                {
-                    let cpu_info = qmp_schema::CpuInfo::x86 {
-                        common: cpu_common,
-                        x86: qmp_schema::CpuInfoX86 {},
-                    };
-                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
-                }
-                #[cfg(target_arch = "aarch64")]
-                {
-                    let cpu_info = qmp_schema::CpuInfo::Arm {
-                        common: cpu_common,
-                        arm: qmp_schema::CpuInfoArm {},
-                    };
-                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
+    fn blockdev_mirror_image(&self, args: &qmp_schema::blockdev_mirror) -> Result<()> {
+        let sync = args.sync.parse::<MirrorSync>()?;
+        let drive = self
+            .get_vm_config()
+            .lock()
+            .unwrap()
+            .drives
+            .get(&args.target)
+            .cloned()
+            .with_context(|| format!("Block backend {} not found", args.target))?;
+        if drive.read_only {
+            bail!("Block backend {} is read only", args.target);
+        }
+        if let Some(blk_dev_list) = self.get_blk_dev_list() {
+            for blk in blk_dev_list.lock().unwrap().values() {
+                if blk.lock().unwrap().blk_config().path_on_host == drive.path_on_host {
+                    bail!("Block backend {} is in use", args.target);
                 }
             }
         }
-        Response::create_response(cpu_vec.into(), None)
+
+        let target = MirrorTarget {
+            path: drive.path_on_host,
+            format: drive.format,
+            zeroed: false,
+            node_name: Some(args.target.clone()),
+        };
+        self.mirror_image(
+            &args.device,
+            args.job_id.as_deref(),
+            target,
+            sync,
+            args.speed.unwrap_or(0),
+        )
     }
 
-    fn query_hotpluggable_cpus(&self) -> Response {
-        Response::create_empty_response()
+    /// Start a job to back up the data of block device to `target`. The device
+    /// keeps using its image, and the target holds the data of disk at the time
+    /// the job completes.
+    fn backup_image(
+        &self,
+        device: &str,
+        job_id: Option<&str>,
+        target: MirrorTarget,
+        sync: MirrorSync,
+        speed: u64,
+    ) -> Result<()> {
+        let job_id = job_id.unwrap_or(device);
+        if has_block_job(job_id) {
+            bail!("Block job {} already exists", job_id);
+        }
+        if device_has_block_job(device) {
+            bail!("Block device {} is in use by block job", device);
+        }
+        let node = self.get_block_node(device)?;
+        let info = node.info();
+        if info.path.is_empty() {
+            bail!("No image is opened by block device {}", device);
+        }
+        if is_same_file(&target.path, &info.path) {
+            bail!("Target image is the same as the image of {}", device);
+        }
+
+        if target.node_name.is_none() {
+            self.register_drive_file(&target.path, false, info.direct)?;
+        }
+        let release_target = {
+            let drive_files = self.get_drive_files();
+            let target = target.clone();
+            move || {
+                if target.node_name.is_some() {
+                    return;
+                }
+                let mut locked_files = drive_files.lock().unwrap();
+                if let Err(e) = VmConfig::remove_drive_file(&mut locked_files, &target.path) {
+                    error!("Failed to release target image {}: {:?}", target.path, e);
+                }
+            }
+        };
+        let release_clone = release_target.clone();
+        let pivot = Box::new(move || {
+            release_clone();
+            Ok(())
+        });
+        let job = match node.mirror_job(&target, sync, pivot, Box::new(release_target)) {
+            Ok(job) => job,
+            Err(e) => {
+                if target.node_name.is_none() {
+                    self.unregister_drive_file(&target.path)?;
+                }
+                return Err(e);
+            }
+        };
+        start_block_job(BlockJob::new(
+            job_id,
+            device,
+            "backup",
+            info.iothread,
+            speed,
+            job,
+        ))
     }
 
-    fn balloon(&self, value: u64) -> Response {
-        if qmp_balloon(value) {
-            return Response::create_empty_response();
+    /// Get the areas to back up according to the sync mode.
+    fn backup_sync(
+        &self,
+        node: &BlockNode,
+        sync: &str,
+        bitmap: Option<&str>,
+    ) -> Result<MirrorSync> {
+        match (sync, bitmap) {
+            ("incremental", Some(bitmap)) => {
+                Ok(MirrorSync::Incremental(node.get_dirty_bitmap(bitmap)?))
+            }
+            ("incremental", None) => bail!("Bitmap is required for sync mode incremental"),
+            (sync, None) => sync.parse::<MirrorSync>(),
+            (sync, Some(_)) => bail!("Bitmap is not supported for sync mode {}", sync),
         }
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::DeviceNotActive(
-                "No balloon device has been activated".to_string(),
-            ),
-            None,
-        )
     }
 
-    fn query_balloon(&self) -> Response {
-        if let Some(actual) = qmp_query_balloon() {
-            let ret = qmp_schema::BalloonInfo { actual };
-            return Response::create_response(serde_json::to_value(&ret).unwrap(), None);
+    fn drive_backup_image(&self, args: &qmp_schema::drive_backup) -> Result<()> {
+        let create = match args.mode.as_deref().unwrap_or("absolute-paths") {
+            "absolute-paths" => true,
+            "existing" => false,
+            mode => bail!("Invalid backup mode {}", mode),
+        };
+        let node = self.get_block_node(&args.device)?;
+        let sync = self.backup_sync(&node, &args.sync, args.bitmap.as_deref())?;
+        let info = node.info();
+        let format = match args.format.as_deref() {
+            Some(format) => format
+                .parse::<DiskFormat>()
+                .map_err(|_| anyhow!("Unsupported image format {}", format))?,
+            None => info.format,
+        };
+
+        // The target image needs the same backing file to back up the data not in it.
+        let backing = info.backing.filter(|_| matches!(sync, MirrorSync::Top));
+        if create {
+            create_target_image(&args.target, format, info.disk_size, backing.as_ref())?;
         }
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::DeviceNotActive(
-                "No balloon device has been activated".to_string(),
-            ),
-            None,
+
+        let target = MirrorTarget {
+            path: args.target.clone(),
+            format,
+            zeroed: create && backing.is_none(),
+            node_name: None,
+        };
+        self.backup_image(
+            &args.device,
+            args.job_id.as_deref(),
+            target,
+            sync,
+            args.speed.unwrap_or(0),
         )
     }
 
-    fn query_vnc(&self) -> Response {
-        #[cfg(not(target_env = "musl"))]
-        if let Some(vnc_info) = qmp_query_vnc() {
-            return Response::create_response(serde_json::to_value(&vnc_info).unwrap(), None);
+    fn blockdev_backup_image(&self, args: &qmp_schema::blockdev_backup) -> Result<()> {
+        let node = self.get_block_node(&args.device)?;
+        let sync = self.backup_sync(&node, &args.sync, args.bitmap.as_deref())?;
+        let drive = self
+            .get_vm_config()
+            .lock()
+            .unwrap()
+            .drives
+            .get(&args.target)
+            .cloned()
+            .with_context(|| format!("Block backend {} not found", args.target))?;
+        if drive.read_only {
+            bail!("Block backend {} is read only", args.target);
         }
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::GenericError(
-                "The service of VNC is not supported".to_string(),
-            ),
-            None,
+
+        let target = MirrorTarget {
+            path: drive.path_on_host,
+            format: drive.format,
+            zeroed: false,
+            node_name: Some(args.target.clone()),
+        };
+        self.backup_image(
+            &args.device,
+            args.job_id.as_deref(),
+            target,
+            sync,
+            args.speed.unwrap_or(0),
         )
     }
 
-    fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
-        if let Err(e) = self.check_device_id_existed(&args.id) {
-            return Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-                None,
-            );
+    fn plug_virtio_pci_scsi(
+        &mut self,
+        pci_bdf: &PciBdf,
+        args: &qmp_schema::DeviceAddArgument,
+    ) -> Result<()> {
+        let multifunction = args.multifunction.unwrap_or(false);
+        let nr_cpus = self.get_vm_config().lock().unwrap().machine_config.nr_cpus;
+        let queue_size = args.queue_size.unwrap_or(DEFAULT_VIRTQUEUE_SIZE);
+        let dev_cfg = ScsiCntlrConfig {
+            id: args.id.clone(),
+            iothread: args.iothread.clone(),
+            queues: args.queues.unwrap_or_else(|| {
+                VirtioPciDevice::virtio_pci_auto_queues_num(0, nr_cpus, MAX_VIRTIO_QUEUE)
+            }) as u32,
+            boot_prefix: None,
+            queue_size,
+        };
+        dev_cfg.check()?;
+
+        let device = Arc::new(Mutex::new(ScsiCntlr::ScsiCntlr::new(dev_cfg.clone())));
+
+        let bus_name = format!("{}.0", dev_cfg.id);
+        ScsiBus::create_scsi_bus(&bus_name, &device)?;
+        if let Some(cntlr_list) = self.get_scsi_cntlr_list() {
+            let mut lock_cntlr_list = cntlr_list.lock().unwrap();
+            lock_cntlr_list.insert(bus_name.clone(), device.clone());
+        } else {
+            bail!("No scsi controller list found");
+        }
+
+        let result =
+            self.add_virtio_pci_device(&args.id, pci_bdf, device.clone(), multifunction, false);
+        let pci_dev = if let Err(ref e) = result {
+            // SAFETY: unwrap is safe because Standard machine always make sure it not return null.
+            self.get_scsi_cntlr_list()
+                .unwrap()
+                .lock()
+                .unwrap()
+                .remove(&bus_name);
+            bail!("Failed to add virtio scsi controller, error is {:?}", e);
+        } else {
+            result.unwrap()
+        };
+
+        device.lock().unwrap().config.boot_prefix = pci_dev.lock().unwrap().get_dev_path();
+
+        Ok(())
+    }
+
+    fn plug_vhost_user_blk_pci(
+        &mut self,
+        pci_bdf: &PciBdf,
+        args: &qmp_schema::DeviceAddArgument,
+    ) -> Result<()> {
+        let multifunction = args.multifunction.unwrap_or(false);
+        let vm_config = self.get_vm_config();
+        let locked_vmconfig = vm_config.lock().unwrap();
+        let chardev = if let Some(dev) = &args.chardev {
+            dev
+        } else {
+            bail!("Chardev not set");
+        };
+        let queue_size = args.queue_size.unwrap_or(DEFAULT_VIRTQUEUE_SIZE);
+        let socket_path = self
+            .get_socket_path(&locked_vmconfig, chardev.to_string())
+            .with_context(|| "Failed to get socket path")?;
+        let nr_cpus = locked_vmconfig.machine_config.nr_cpus;
+        let dev = BlkDevConfig {
+            id: args.id.clone(),
+            queues: args.queues.unwrap_or_else(|| {
+                VirtioPciDevice::virtio_pci_auto_queues_num(0, nr_cpus, MAX_VIRTIO_QUEUE)
+            }),
+            boot_index: args.boot_index,
+            chardev: Some(chardev.to_string()),
+            socket_path,
+            queue_size,
+            ..BlkDevConfig::default()
+        };
+
+        dev.check()?;
+        drop(locked_vmconfig);
+
+        let blk = Arc::new(Mutex::new(VhostUser::Block::new(&dev, self.get_sys_mem())));
+        self.add_virtio_pci_device(&args.id, pci_bdf, blk, multifunction, true)
+            .with_context(|| "Failed to add vhost user blk pci device")?;
+
+        Ok(())
+    }
+
+    fn get_socket_path(&self, vm_config: &VmConfig, chardev: String) -> Result<Option<String>> {
+        let char_dev = if let Some(char_dev) = vm_config.chardev.get(&chardev) {
+            char_dev
+        } else {
+            bail!("Chardev: {:?} not found for character device", &chardev);
+        };
+
+        let socket_path = match &char_dev.backend {
+            ChardevType::Socket {
+                path,
+                server,
+                nowait,
+            } => {
+                if *server || *nowait {
+                    bail!(
+                        "Argument \'server\' or \'nowait\' is not needed for chardev \'{}\'",
+                        path
+                    );
+                }
+                Some(path.clone())
+            }
+            _ => {
+                bail!("Chardev {:?} backend should be socket type.", &chardev);
+            }
+        };
+
+        Ok(socket_path)
+    }
+
+    fn plug_virtio_pci_net(
+        &mut self,
+        pci_bdf: &PciBdf,
+        args: &qmp_schema::DeviceAddArgument,
+    ) -> Result<()> {
+        let multifunction = args.multifunction.unwrap_or(false);
+        let netdev = if let Some(dev) = &args.netdev {
+            dev
+        } else {
+            bail!("Netdev not set");
+        };
+        let queue_size = args.queue_size.unwrap_or(DEFAULT_VIRTQUEUE_SIZE);
+        let vm_config = self.get_vm_config();
+        let mut locked_vmconfig = vm_config.lock().unwrap();
+        let dev = if let Some(conf) = locked_vmconfig.netdevs.get(netdev) {
+            let mut socket_path: Option<String> = None;
+            if let Some(chardev) = &conf.chardev {
+                socket_path = self
+                    .get_socket_path(&locked_vmconfig, (&chardev).to_string())
+                    .with_context(|| "Failed to get socket path")?;
+            }
+            let dev = NetworkInterfaceConfig {
+                id: args.id.clone(),
+                host_dev_name: conf.ifname.clone(),
+                mac: args.mac.clone(),
+                tap_fds: conf.tap_fds.clone(),
+                vhost_type: conf.vhost_type.clone(),
+                vhost_fds: conf.vhost_fds.clone(),
+                iothread: args.iothread.clone(),
+                queues: conf.queues,
+                mq: conf.queues > 2,
+                socket_path,
+                queue_size,
+                user: conf.user.clone(),
+                socket: conf.socket.clone(),
+                netdev: netdev.clone(),
+                rate_limit: NetRateLimit::default(),
+            };
+            dev.check()?;
+            dev
+        } else {
+            bail!("Netdev not found");
+        };
+        locked_vmconfig.add_net_device_config(args);
+        drop(locked_vmconfig);
+
+        if dev.vhost_type.is_some() {
+            let mut need_irqfd = false;
+            let net: Arc<Mutex<dyn VirtioDevice>> =
+                if dev.vhost_type == Some(String::from("vhost-kernel")) {
+                    Arc::new(Mutex::new(VhostKern::Net::new(&dev, self.get_sys_mem())))
+                } else {
+                    need_irqfd = true;
+                    let net = Arc::new(Mutex::new(VhostUser::Net::new(&dev, self.get_sys_mem())));
+                    MigrationManager::register_device_instance(
+                        VirtioNetState::descriptor(),
+                        net.clone(),
+                        &args.id,
+                    );
+                    net
+                };
+            self.add_virtio_pci_device(&args.id, pci_bdf, net, multifunction, need_irqfd)
+                .with_context(|| "Failed to add vhost-kernel/vhost-user net device")?;
+        } else {
+            let net_id = dev.id.clone();
+            let net = Arc::new(Mutex::new(virtio::Net::new(dev)));
+            self.add_virtio_pci_device(&args.id, pci_bdf, net.clone(), multifunction, false)
+                .with_context(|| "Failed to add virtio net device")?;
+            if let Some(net_dev_list) = self.get_net_dev_list() {
+                net_dev_list
+                    .lock()
+                    .unwrap()
+                    .insert(net_id.clone(), net.clone());
+            }
+            MigrationManager::register_device_instance(VirtioNetState::descriptor(), net, &net_id);
+        }
+
+        Ok(())
+    }
+
+    fn plug_vfio_pci_device(
+        &mut self,
+        bdf: &PciBdf,
+        args: &qmp_schema::DeviceAddArgument,
+    ) -> Result<()> {
+        let host = if args.host.is_none() {
+            ""
+        } else {
+            args.host.as_ref().unwrap()
+        };
+
+        let sysfsdev = if args.sysfsdev.is_none() {
+            ""
+        } else {
+            args.sysfsdev.as_ref().unwrap()
+        };
+
+        if args.host.is_none() && args.sysfsdev.is_none() {
+            bail!("Neither option \"host\" nor \"sysfsdev\" was not provided.");
+        }
+
+        if args.host.is_some() && args.sysfsdev.is_some() {
+            bail!("Both option \"host\" and \"sysfsdev\" was provided.");
+        }
+
+        if let Err(e) = self.create_vfio_pci_device(
+            &args.id,
+            bdf,
+            host,
+            sysfsdev,
+            args.multifunction.map_or(false, |m| m),
+        ) {
+            error!("{:?}", e);
+            bail!("Failed to plug vfio-pci device.");
+        }
+        Ok(())
+    }
+}
+
+impl DeviceInterface for StdMachine {
+    fn query_status(&self) -> Response {
+        let vm_state = self.get_vm_state();
+        let vmstate = vm_state.deref().0.lock().unwrap();
+        let qmp_state = match *vmstate {
+            KvmVmState::Running => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: true,
+                status: qmp_schema::RunState::running,
+            },
+            KvmVmState::Paused => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: qmp_schema::RunState::paused,
+            },
+            _ => Default::default(),
+        };
+
+        Response::create_response(serde_json::to_value(&qmp_state).unwrap(), None)
+    }
+
+    fn query_cpus(&self) -> Response {
+        let mut cpu_vec: Vec<serde_json::Value> = Vec::new();
+        let cpu_topo = self.get_cpu_topo();
+        let cpus = self.get_cpus();
+        for cpu_index in 0..cpu_topo.max_cpus {
+            if cpu_topo.get_mask(cpu_index as usize) == 1 {
+                let thread_id = cpus[cpu_index as usize].tid();
+                let cpu_instance = cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
+                let cpu_common = qmp_schema::CpuInfoCommon {
+                    current: true,
+                    qom_path: String::from("/machine/unattached/device[")
+                        + &cpu_index.to_string()
+                        + "]",
+                    halted: false,
+                    props: Some(cpu_instance),
+                    CPU: cpu_index as isize,
+                    thread_id: thread_id as isize,
+                };
+                #[cfg(target_arch = "x86_64")]
+                {
+                    let cpu_info = qmp_schema::CpuInfo::x86 {
+                        common: cpu_common,
+                        x86: qmp_schema::CpuInfoX86 {},
+                    };
+                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
+                }
+                #[cfg(target_arch = "aarch64")]
+                {
+                    let cpu_info = qmp_schema::CpuInfo::Arm {
+                        common: cpu_common,
+                        arm: qmp_schema::CpuInfoArm {},
+                    };
+                    cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
+                }
+            }
+        }
+        Response::create_response(cpu_vec.into(), None)
+    }
+
+    fn query_hotpluggable_cpus(&self) -> Response {
+        Response::create_empty_response()
+    }
+
+    fn balloon(&self, value: u64) -> Response {
+        if qmp_balloon(value) {
+            return Response::create_empty_response();
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::DeviceNotActive(
+                "No balloon device has been activated".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn query_balloon(&self) -> Response {
+        if let Some(actual) = qmp_query_balloon() {
+            let ret = qmp_schema::BalloonInfo { actual };
+            return Response::create_response(serde_json::to_value(&ret).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::DeviceNotActive(
+                "No balloon device has been activated".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn query_vnc(&self) -> Response {
+        #[cfg(not(target_env = "musl"))]
+        if let Some(vnc_info) = qmp_query_vnc() {
+            return Response::create_response(serde_json::to_value(&vnc_info).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError(
+                "The service of VNC is not supported".to_string(),
+            ),
+            None,
+        )
+    }
+
+    fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
+        if let Err(e) = self.check_device_id_existed(&args.id) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+
+        // Use args.bus.clone() and args.addr.clone() because args borrowed in the following process.
+        let pci_bdf = match get_device_bdf(args.bus.clone(), args.addr.clone()) {
+            Ok(bdf) => bdf,
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        };
+
+        let driver = args.driver.as_str();
+        match driver {
+            "virtio-blk-pci" => {
+                if let Err(e) = self.plug_virtio_pci_blk(&pci_bdf, args.as_ref()) {
+                    error!("{:?}", e);
+                    let err_str = format!("Failed to add virtio pci blk: {}", e);
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    );
+                }
+            }
+            "virtio-scsi-pci" => {
+                if let Err(e) = self.plug_virtio_pci_scsi(&pci_bdf, args.as_ref()) {
+                    error!("{:?}", e);
+                    let err_str = format!("Failed to add virtio scsi controller: {}", e);
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    );
+                }
+            }
+            "vhost-user-blk-pci" => {
+                if let Err(e) = self.plug_vhost_user_blk_pci(&pci_bdf, args.as_ref()) {
+                    error!("{:?}", e);
+                    let err_str = format!("Failed to add vhost user blk pci: {}", e);
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    );
+                }
+            }
+            "virtio-net-pci" => {
+                if let Err(e) = self.plug_virtio_pci_net(&pci_bdf, args.as_ref()) {
+                    error!("{:?}", e);
+                    let err_str = format!("Failed to add virtio pci net: {}", e);
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    );
+                }
+            }
+            "vfio-pci" => {
+                if let Err(e) = self.plug_vfio_pci_device(&pci_bdf, args.as_ref()) {
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    );
+                }
+            }
+            _ => {
+                let err_str = format!("Failed to add device: Driver {} is not support", driver);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(err_str),
+                    None,
+                );
+            }
+        }
+
+        // It's safe to call get_pci_host().unwrap() because it has been checked before.
+        let locked_pci_host = self.get_pci_host().unwrap().lock().unwrap();
+        if let Some((bus, dev)) = PciBus::find_attached_bus(&locked_pci_host.root_bus, &args.id) {
+            match handle_plug(&bus, &dev) {
+                Ok(()) => Response::create_empty_response(),
+                Err(e) => {
+                    if let Err(e) = PciBus::detach_device(&bus, &dev) {
+                        error!("{:?}", e);
+                        error!("Failed to detach device");
+                    }
+                    let err_str = format!("Failed to plug device: {}", e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    )
+                }
+            }
+        } else {
+            Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(
+                    "Failed to add device: Bus not found".to_string(),
+                ),
+                None,
+            )
+        }
+    }
+
+    fn device_del(&mut self, device_id: String) -> Response {
+        let pci_host = match self.get_pci_host() {
+            Ok(host) => host,
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        };
+
+        let locked_pci_host = pci_host.lock().unwrap();
+        if let Some((bus, dev)) = PciBus::find_attached_bus(&locked_pci_host.root_bus, &device_id) {
+            match handle_unplug_request(&bus, &dev) {
+                Ok(()) => {
+                    let locked_dev = dev.lock().unwrap();
+                    let dev_id = locked_dev.name();
+                    drop(locked_pci_host);
+                    self.del_bootindex_devices(&dev_id);
+                    if let Some(blk_dev_list) = self.get_blk_dev_list() {
+                        blk_dev_list.lock().unwrap().remove(&dev_id);
+                    }
+                    if let Some(net_dev_list) = self.get_net_dev_list() {
+                        net_dev_list.lock().unwrap().remove(&dev_id);
+                    }
+                    nbd_server_remove_device(&dev_id);
+                    let vm_config = self.get_vm_config();
+                    let mut locked_config = vm_config.lock().unwrap();
+                    locked_config.del_device_by_id(device_id);
+                    drop(locked_config);
+                    Response::create_empty_response()
+                }
+                Err(e) => Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                ),
+            }
+        } else {
+            let err_str = format!("Failed to remove device: id {} not found", &device_id);
+            Response::create_error_response(qmp_schema::QmpErrorClass::GenericError(err_str), None)
+        }
+    }
+
+    fn blockdev_add(&self, args: Box<qmp_schema::BlockDevAddArgument>) -> Response {
+        let read_only = args.read_only.unwrap_or(false);
+        let direct = if let Some(cache) = args.cache {
+            cache.direct.unwrap_or(true)
+        } else {
+            true
+        };
+        let format = match args
+            .driver
+            .as_deref()
+            .unwrap_or("raw")
+            .parse::<DiskFormat>()
+        {
+            Ok(format) => format,
+            Err(_) => {
+                let err_str = format!("Unsupported block driver {:?}", args.driver);
+                error!("{}", err_str);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(err_str),
+                    None,
+                );
+            }
+        };
+        let error_actions = parse_error_action(args.werror.as_deref()).and_then(|werror| {
+            parse_error_action(args.rerror.as_deref()).map(|rerror| (werror, rerror))
+        });
+        let (werror, rerror) = match error_actions {
+            Ok(actions) => actions,
+            Err(e) => {
+                error!("{:?}", e);
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                );
+            }
+        };
+        let config = DriveConfig {
+            id: args.node_name,
+            path_on_host: args.file.filename.clone(),
+            read_only,
+            direct,
+            format,
+            throttle: ThrottleConfig {
+                iops_total: ThrottleLimit {
+                    avg: args.iops.unwrap_or(0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            throttle_group: None,
+            // TODO Add aio option by qmp, now we set it based on "direct".
+            aio: if direct {
+                AioEngine::Native
+            } else {
+                AioEngine::Off
+            },
+            werror,
+            rerror,
+            copy_on_read: args.copy_on_read.unwrap_or(false),
+            share_rw: false,
+        };
+
+        if let Err(e) = config.check() {
+            error!("{:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        // Check whether path is valid after configuration check
+        if let Err(e) = config.check_path() {
+            error!("{:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        // Register drive backend file for hotplug drive.
+        if let Err(e) = self.register_drive_file(&args.file.filename, read_only, direct) {
+            error!("{:?}", e);
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        match self
+            .get_vm_config()
+            .lock()
+            .unwrap()
+            .add_drive_with_config(config)
+        {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                // It's safe to unwrap as the path has been registered.
+                self.unregister_drive_file(&args.file.filename).unwrap();
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_del(&self, node_name: String) -> Response {
+        match self
+            .get_vm_config()
+            .lock()
+            .unwrap()
+            .del_drive_by_id(&node_name)
+        {
+            Ok(path) => {
+                // It's safe to unwrap as the path has been registered.
+                self.unregister_drive_file(&path).unwrap();
+                Response::create_empty_response()
+            }
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn blockdev_snapshot_sync(
+        &mut self,
+        device: String,
+        snapshot_file: String,
+        format: Option<String>,
+        mode: Option<String>,
+    ) -> Response {
+        match self.snapshot_external(&device, &snapshot_file, format.as_deref(), mode.as_deref()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_snapshot_internal_sync(&self, device: String, name: String) -> Response {
+        let result = self.get_virtio_blk(&device).and_then(|blk| {
+            let locked_blk = blk.lock().unwrap();
+            if locked_blk.blk_config().read_only {
+                bail!("Block device {} is read only", device);
+            }
+            locked_blk.create_snapshot(&name)
+        });
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_snapshot_delete_internal_sync(
+        &self,
+        device: String,
+        id: Option<String>,
+        name: Option<String>,
+    ) -> Response {
+        let result = self.get_virtio_blk(&device).and_then(|blk| {
+            let locked_blk = blk.lock().unwrap();
+            if locked_blk.blk_config().read_only {
+                bail!("Block device {} is read only", device);
+            }
+            if id.is_none() && name.is_none() {
+                bail!("One of snapshot id and name must be specified");
+            }
+            locked_blk.delete_snapshot(id.as_deref(), name.as_deref())
+        });
+        match result {
+            Ok(info) => {
+                let info = qmp_schema::SnapshotInfo {
+                    id: info.id,
+                    name: info.name,
+                    vm_state_size: info.vm_state_size,
+                    date_sec: info.date_sec as u64,
+                    date_nsec: info.date_nsec as u64,
+                    vm_clock_sec: info.vm_clock_nsec / 1_000_000_000,
+                    vm_clock_nsec: info.vm_clock_nsec % 1_000_000_000,
+                };
+                Response::create_response(serde_json::to_value(info).unwrap(), None)
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_commit(
+        &self,
+        device: String,
+        job_id: Option<String>,
+        base: Option<String>,
+        top: Option<String>,
+        speed: Option<u64>,
+    ) -> Response {
+        match self.commit_active_image(
+            &device,
+            job_id.as_deref(),
+            base.as_deref(),
+            top.as_deref(),
+            speed.unwrap_or(0),
+        ) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn drive_mirror(&self, args: qmp_schema::drive_mirror) -> Response {
+        match self.drive_mirror_image(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_mirror(&self, args: qmp_schema::blockdev_mirror) -> Response {
+        match self.blockdev_mirror_image(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_set_io_throttle(&self, args: qmp_schema::block_set_io_throttle) -> Response {
+        match self.set_block_io_throttle(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn net_set_rate_limit(&self, args: qmp_schema::net_set_rate_limit) -> Response {
+        match self.set_net_rate_limit(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn set_link(&self, name: String, up: bool) -> Response {
+        match self.set_net_link(&name, up) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_resize(&self, device: String, size: u64) -> Response {
+        match self.resize_block_device(&device, size) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn drive_backup(&self, args: qmp_schema::drive_backup) -> Response {
+        match self.drive_backup_image(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn blockdev_backup(&self, args: qmp_schema::blockdev_backup) -> Response {
+        match self.blockdev_backup_image(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_add(
+        &self,
+        node: String,
+        name: String,
+        granularity: Option<u64>,
+        persistent: Option<bool>,
+    ) -> Response {
+        let result = self.get_block_node(&node).and_then(|node| {
+            node.add_dirty_bitmap(&name, granularity, persistent.unwrap_or(false))
+        });
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_remove(&self, node: String, name: String) -> Response {
+        let result = if device_has_block_job(&node) {
+            Err(anyhow!("Block device {} is in use by block job", node))
+        } else {
+            self.get_block_node(&node)
+                .and_then(|node| node.remove_dirty_bitmap(&name))
+        };
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn block_dirty_bitmap_clear(&self, node: String, name: String) -> Response {
+        let result = if device_has_block_job(&node) {
+            Err(anyhow!("Block device {} is in use by block job", node))
+        } else {
+            self.get_block_node(&node)
+                .and_then(|node| node.get_dirty_bitmap(&name))
+                .map(|bitmap| bitmap.lock().unwrap().clear_all())
+        };
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_start(&self, args: qmp_schema::nbd_server_start) -> Response {
+        match self.start_nbd_server(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_add(&self, args: qmp_schema::nbd_server_add) -> Response {
+        match self.add_nbd_export(&args) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_remove(&self, name: String) -> Response {
+        match nbd_server_remove(&name) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn nbd_server_stop(&self) -> Response {
+        match nbd_server_stop() {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
+        }
+    }
+
+    fn eject(&self, device: String, force: Option<bool>) -> Response {
+        match self.eject_medium(&device, force.unwrap_or(false)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
         }
+    }
 
-        // Use args.bus.clone() and args.addr.clone() because args borrowed in the following process.
-        let pci_bdf = match get_device_bdf(args.bus.clone(), args.addr.clone()) {
-            Ok(bdf) => bdf,
+    fn blockdev_change_medium(
+        &self,
+        device: String,
+        filename: String,
+        format: Option<String>,
+        force: Option<bool>,
+    ) -> Response {
+        match self.change_medium(
+            &device,
+            &filename,
+            format.as_deref(),
+            force.unwrap_or(false),
+        ) {
+            Ok(()) => Response::create_empty_response(),
             Err(e) => {
-                return Response::create_error_response(
+                error!("{:?}", e);
+                Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
                 )
             }
-        };
+        }
+    }
 
-        let driver = args.driver.as_str();
-        match driver {
-            "virtio-blk-pci" => {
-                if let Err(e) = self.plug_virtio_pci_blk(&pci_bdf, args.as_ref()) {
-                    error!("{:?}", e);
-                    let err_str = format!("Failed to add virtio pci blk: {}", e);
-                    return Response::create_error_response(
-                        qmp_schema::QmpErrorClass::GenericError(err_str),
-                        None,
-                    );
-                }
-            }
-            "virtio-scsi-pci" => {
-                if let Err(e) = self.plug_virtio_pci_scsi(&pci_bdf, args.as_ref()) {
-                    error!("{:?}", e);
-                    let err_str = format!("Failed to add virtio scsi controller: {}", e);
-                    return Response::create_error_response(
-                        qmp_schema::QmpErrorClass::GenericError(err_str),
-                        None,
-                    );
-                }
-            }
-            "vhost-user-blk-pci" => {
-                if let Err(e) = self.plug_vhost_user_blk_pci(&pci_bdf, args.as_ref()) {
-                    error!("{:?}", e);
-                    let err_str = format!("Failed to add vhost user blk pci: {}", e);
-                    return Response::create_error_response(
-                        qmp_schema::QmpErrorClass::GenericError(err_str),
-                        None,
-                    );
-                }
-            }
-            "virtio-net-pci" => {
-                if let Err(e) = self.plug_virtio_pci_net(&pci_bdf, args.as_ref()) {
-                    error!("{:?}", e);
-                    let err_str = format!("Failed to add virtio pci net: {}", e);
-                    return Response::create_error_response(
-                        qmp_schema::QmpErrorClass::GenericError(err_str),
-                        None,
-                    );
-                }
-            }
-            "vfio-pci" => {
-                if let Err(e) = self.plug_vfio_pci_device(&pci_bdf, args.as_ref()) {
-                    return Response::create_error_response(
-                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-                        None,
-                    );
-                }
-            }
-            _ => {
-                let err_str = format!("Failed to add device: Driver {} is not support", driver);
-                return Response::create_error_response(
-                    qmp_schema::QmpErrorClass::GenericError(err_str),
+    fn block_stream(&self, device: String, job_id: Option<String>, speed: Option<u64>) -> Response {
+        match self.stream_image(&device, job_id.as_deref(), speed.unwrap_or(0)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
-                );
+                )
             }
         }
+    }
 
-        // It's safe to call get_pci_host().unwrap() because it has been checked before.
-        let locked_pci_host = self.get_pci_host().unwrap().lock().unwrap();
-        if let Some((bus, dev)) = PciBus::find_attached_bus(&locked_pci_host.root_bus, &args.id) {
-            match handle_plug(&bus, &dev) {
-                Ok(()) => Response::create_empty_response(),
-                Err(e) => {
-                    if let Err(e) = PciBus::detach_device(&bus, &dev) {
-                        error!("{:?}", e);
-                        error!("Failed to detach device");
-                    }
-                    let err_str = format!("Failed to plug device: {}", e);
-                    Response::create_error_response(
-                        qmp_schema::QmpErrorClass::GenericError(err_str),
-                        None,
-                    )
-                }
+    fn block_job_cancel(&self, device: String, force: Option<bool>) -> Response {
+        match cancel_block_job(&device, force.unwrap_or(false)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
             }
-        } else {
-            Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(
-                    "Failed to add device: Bus not found".to_string(),
-                ),
-                None,
-            )
         }
     }
 
-    fn device_del(&mut self, device_id: String) -> Response {
-        let pci_host = match self.get_pci_host() {
-            Ok(host) => host,
+    fn block_job_complete(&self, device: String) -> Response {
+        match complete_block_job(&device) {
+            Ok(()) => Response::create_empty_response(),
             Err(e) => {
-                return Response::create_error_response(
+                error!("{:?}", e);
+                Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
                 )
             }
-        };
+        }
+    }
 
-        let locked_pci_host = pci_host.lock().unwrap();
-        if let Some((bus, dev)) = PciBus::find_attached_bus(&locked_pci_host.root_bus, &device_id) {
-            match handle_unplug_request(&bus, &dev) {
-                Ok(()) => {
-                    let locked_dev = dev.lock().unwrap();
-                    let dev_id = locked_dev.name();
-                    drop(locked_pci_host);
-                    self.del_bootindex_devices(&dev_id);
-                    let vm_config = self.get_vm_config();
-                    let mut locked_config = vm_config.lock().unwrap();
-                    locked_config.del_device_by_id(device_id);
-                    drop(locked_config);
-                    Response::create_empty_response()
-                }
-                Err(e) => Response::create_error_response(
+    fn block_job_pause(&self, device: String) -> Response {
+        match pause_block_job(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
-                ),
+                )
             }
-        } else {
-            let err_str = format!("Failed to remove device: id {} not found", &device_id);
-            Response::create_error_response(qmp_schema::QmpErrorClass::GenericError(err_str), None)
         }
     }
 
-    fn blockdev_add(&self, args: Box<qmp_schema::BlockDevAddArgument>) -> Response {
-        let read_only = args.read_only.unwrap_or(false);
-        let direct = if let Some(cache) = args.cache {
-            cache.direct.unwrap_or(true)
-        } else {
-            true
-        };
-        let config = DriveConfig {
-            id: args.node_name,
-            path_on_host: args.file.filename.clone(),
-            read_only,
-            direct,
-            iops: args.iops,
-            // TODO Add aio option by qmp, now we set it based on "direct".
-            aio: if direct {
-                AioEngine::Native
-            } else {
-                AioEngine::Off
-            },
-        };
-
-        if let Err(e) = config.check() {
-            error!("{:?}", e);
-            return Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-                None,
-            );
-        }
-        // Check whether path is valid after configuration check
-        if let Err(e) = config.check_path() {
-            error!("{:?}", e);
-            return Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-                None,
-            );
-        }
-        // Register drive backend file for hotplug drive.
-        if let Err(e) = self.register_drive_file(&args.file.filename, read_only, direct) {
-            error!("{:?}", e);
-            return Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-                None,
-            );
+    fn block_job_resume(&self, device: String) -> Response {
+        match resume_block_job(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                error!("{:?}", e);
+                Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                )
+            }
         }
-        match self
-            .get_vm_config()
-            .lock()
-            .unwrap()
-            .add_drive_with_config(config)
-        {
+    }
+
+    fn block_job_set_speed(&self, device: String, speed: u64) -> Response {
+        match set_block_job_speed(&device, speed) {
             Ok(()) => Response::create_empty_response(),
             Err(e) => {
                 error!("{:?}", e);
-                // It's safe to unwrap as the path has been registered.
-                self.unregister_drive_file(&args.file.filename).unwrap();
                 Response::create_error_response(
                     qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                     None,
@@ -1325,23 +2898,54 @@ impl DeviceInterface for StdMachine {
         }
     }
 
-    fn blockdev_del(&self, node_name: String) -> Response {
-        match self
-            .get_vm_config()
-            .lock()
-            .unwrap()
-            .del_drive_by_id(&node_name)
-        {
-            Ok(path) => {
-                // It's safe to unwrap as the path has been registered.
-                self.unregister_drive_file(&path).unwrap();
-                Response::create_empty_response()
+    fn query_block(&self) -> Response {
+        let mut vec_block = Vec::new();
+        for (id, node) in self.block_nodes() {
+            let info = node.info();
+            let drive_id = self.drive_id_of(&info.path);
+            let inserted = match info.path.is_empty() {
+                true => None,
+                false => Some(info.device_info(&drive_id)),
+            };
+            vec_block.push(qmp_schema::BlockInfo {
+                device: drive_id,
+                qdev: id,
+                removable: info.removable,
+                locked: info.locked,
+                tray_open: info.removable.then_some(info.tray_open),
+                inserted,
+            });
+        }
+        Response::create_response(serde_json::to_value(vec_block).unwrap(), None)
+    }
+
+    fn query_named_block_nodes(&self) -> Response {
+        let mut vec_node = Vec::new();
+        for (_, node) in self.block_nodes() {
+            let info = node.info();
+            if !info.path.is_empty() {
+                vec_node.push(info.device_info(&self.drive_id_of(&info.path)));
             }
-            Err(e) => Response::create_error_response(
-                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
-                None,
-            ),
         }
+        Response::create_response(serde_json::to_value(vec_node).unwrap(), None)
+    }
+
+    fn query_blockstats(&self) -> Response {
+        let mut vec_stats = Vec::new();
+        for (id, node) in self.block_nodes() {
+            let drive_id = self.drive_id_of(&node.info().path);
+            vec_stats.push(qmp_schema::BlockStats {
+                device: drive_id.clone(),
+                qdev: id,
+                node_name: drive_id,
+                stats: node.io_stats(),
+            });
+        }
+        Response::create_response(serde_json::to_value(vec_stats).unwrap(), None)
+    }
+
+    fn query_block_jobs(&self) -> Response {
+        Response::create_response(serde_json::to_value(query_block_jobs()).unwrap(), None)
     }
 
     fn chardev_add(&mut self, args: qmp_schema::CharDevAddArgument) -> Response {
@@ -1386,6 +2990,59 @@ impl DeviceInterface for StdMachine {
         }
     }
 
+    fn object_add(&mut self, args: qmp_schema::object_add) -> Response {
+        let config = match get_filter_dump_config(args) {
+            Ok(config) => config,
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                );
+            }
+        };
+
+        match self.add_filter_dump(&config) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn object_del(&mut self, id: String) -> Response {
+        let mut found = false;
+        if let Some(net_dev_list) = self.get_net_dev_list() {
+            for net in net_dev_list.lock().unwrap().values() {
+                if net.lock().unwrap().del_dump(&id) {
+                    found = true;
+                    break;
+                }
+            }
+        }
+        // The capture may have been stopped by the write error.
+        let vm_config = self.get_vm_config();
+        if vm_config
+            .lock()
+            .unwrap()
+            .object
+            .filter_dump
+            .remove(&id)
+            .is_some()
+        {
+            found = true;
+        }
+
+        if found {
+            Response::create_empty_response()
+        } else {
+            Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("Object {} not found", id)),
+                None,
+            )
+        }
+    }
+
     fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
         let config = match get_netdev_config(args) {
             Ok(conf) => conf,
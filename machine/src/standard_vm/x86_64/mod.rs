@@ -65,6 +65,7 @@ use anyhow::{anyhow, bail, Context, Result};
 #[cfg(not(target_env = "musl"))]
 use ui::vnc;
 use virtio::ScsiCntlr::ScsiCntlrMap;
+use virtio::{BlockMap, NetMap};
 
 const VENDOR_ID_INTEL: u16 = 0x8086;
 const HOLE_640K_START: u64 = 0x000A_0000;
@@ -115,6 +116,8 @@ pub struct StdMachine {
     boot_source: Arc<Mutex<BootSource>>,
     /// Reset request, handle VM `Reset` event.
     reset_req: Arc<EventFd>,
+    /// Stop request from devices, e.g. on block IO error.
+    stop_req: Arc<EventFd>,
     /// All configuration information of virtual machine.
     vm_config: Arc<Mutex<VmConfig>>,
     /// List of guest NUMA nodes information.
@@ -125,6 +128,10 @@ pub struct StdMachine {
     fwcfg_dev: Option<Arc<Mutex<FwCfgIO>>>,
     /// Scsi Controller List.
     scsi_cntlr_list: ScsiCntlrMap,
+    /// Virtio Block Device List.
+    blk_dev_list: BlockMap,
+    /// Virtio Net Device List.
+    net_dev_list: NetMap,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
 }
@@ -173,11 +180,16 @@ impl StdMachine {
             reset_req: Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
                 anyhow!(MachineError::InitEventFdErr("reset request".to_string()))
             })?),
+            stop_req: Arc::new(EventFd::new(libc::EFD_NONBLOCK).with_context(|| {
+                anyhow!(MachineError::InitEventFdErr("stop request".to_string()))
+            })?),
             vm_config: Arc::new(Mutex::new(vm_config.clone())),
             numa_nodes: None,
             boot_order_list: Arc::new(Mutex::new(Vec::new())),
             fwcfg_dev: None,
             scsi_cntlr_list: Arc::new(Mutex::new(HashMap::new())),
+            blk_dev_list: Arc::new(Mutex::new(HashMap::new())),
+            net_dev_list: Arc::new(Mutex::new(HashMap::new())),
             drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
         })
     }
@@ -425,6 +437,9 @@ impl MachineOps for StdMachine {
         let clone_vm = vm.clone();
         let mut locked_vm = vm.lock().unwrap();
         locked_vm.init_global_config(vm_config)?;
+        locked_vm
+            .register_stop_event(locked_vm.stop_req.clone(), clone_vm.clone())
+            .with_context(|| "Fail to register stop event")?;
         locked_vm.numa_nodes = locked_vm.add_numa_nodes(vm_config)?;
         locked_vm.init_memory(
             &vm_config.machine_config.mem_config,
@@ -592,9 +607,17 @@ impl MachineOps for StdMachine {
         Some(self.boot_order_list.clone())
     }
 
-    fn get_scsi_cntlr_list(&mut self) -> Option<&ScsiCntlrMap> {
+    fn get_scsi_cntlr_list(&self) -> Option<&ScsiCntlrMap> {
         Some(&self.scsi_cntlr_list)
     }
+
+    fn get_blk_dev_list(&self) -> Option<&BlockMap> {
+        Some(&self.blk_dev_list)
+    }
+
+    fn get_net_dev_list(&self) -> Option<&NetMap> {
+        Some(&self.net_dev_list)
+    }
 }
 
 impl AcpiBuilder for StdMachine {
@@ -921,6 +944,7 @@ impl EventLoopManager for StdMachine {
     }
 
     fn loop_cleanup(&self) -> util::Result<()> {
+        self.store_dirty_bitmaps();
         set_termi_canon_mode().with_context(|| "Failed to set terminal to canonical mode")?;
         Ok(())
     }
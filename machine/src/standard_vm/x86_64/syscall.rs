@@ -82,6 +82,7 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_ppoll),
         BpfRule::new(libc::SYS_fdatasync),
+        BpfRule::new(libc::SYS_fallocate),
         BpfRule::new(libc::SYS_recvmsg),
         BpfRule::new(libc::SYS_sendmsg),
         #[cfg(target_env = "gnu")]
@@ -126,6 +127,8 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_statx),
         BpfRule::new(libc::SYS_mkdir),
         BpfRule::new(libc::SYS_unlink),
+        BpfRule::new(libc::SYS_rename),
+        BpfRule::new(libc::SYS_renameat),
         madvise_rule(),
         BpfRule::new(libc::SYS_msync),
         BpfRule::new(libc::SYS_readlinkat),
@@ -134,6 +137,10 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_bind),
         BpfRule::new(libc::SYS_connect),
+        BpfRule::new(libc::SYS_listen),
+        BpfRule::new(libc::SYS_socketpair),
+        BpfRule::new(libc::SYS_timerfd_create),
+        BpfRule::new(libc::SYS_timerfd_settime),
         BpfRule::new(libc::SYS_getcwd),
         #[cfg(target_env = "musl")]
         BpfRule::new(libc::SYS_clone),
@@ -198,7 +198,7 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .multiple(true)
             .long("drive")
             .value_name("<parameters>")
-            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>]; \
+            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>][,throttling.bps-read=<bytes>][,throttling.bps-read-max=<bytes>][,throttling.group=<name>]; \
                    \n\t\tset pflash drive image: -drive file=<pflash_path>,if=pflash,unit=0|1[,readonly=true|false]; \
                    \n\t\tset scsi drive image: -drive id=<drive-scsi0-0-0-0>,file=<path_on_host>[,readonly=true|false]")
             .takes_values(true),
@@ -208,9 +208,9 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .multiple(true)
             .long("netdev")
             .value_name(
-                "tap,id=<str>,ifname=<tap_name>[,vhost=on|off][,queue=<N>]",
+                "tap,id=<str>,ifname=<tap_name>[,vhost=on|off][,queue=<N>] | user,id=<str>[,net=<addr>[/<prefix>]][,host=<addr>][,dhcpstart=<addr>][,dns=<addr>][,hostfwd=<rule>[;<rule>]] | socket,id=<str>,listen=[host]:port|connect=host:port|udp=host:port,localaddr=host:port | stream,id=<str>[,server=on|off],addr.type=unix,addr.path=<path>|addr.type=inet,addr.host=<host>,addr.port=<port>",
             )
-            .help("configure a host TAP network with ID 'str'")
+            .help("configure a host TAP network, a user mode network or a socket network with ID 'str'")
             .takes_values(true),
         )
         .arg(
@@ -227,10 +227,10 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .long("device")
             .value_name("<parameters>")
             .help("\n\t\tadd virtio mmio block: -device virtio-blk-device,id=<blk_id>,drive=<drive_id>[,iothread=<iothread1>][,serial=<serial_num>]; \
-                   \n\t\tadd virtio pci block: -device virtio-blk-pci,id=<blk_id>,drive=<drive_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,serial=<serial_num>][,num-queues=<N>][,bootindex=<N>]; \
+                   \n\t\tadd virtio pci block: -device virtio-blk-pci,id=<blk_id>,drive=<drive_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,serial=<serial_num>][,num-queues=<N>][,bootindex=<N>][,zone-size=<size>][,conventional-zones=<N>][,max-open-zones=<N>][,max-active-zones=<N>]; \
                    \n\t\tadd vhost user pci block: -device vhost-user-blk-pci,id=<blk_id>,chardev=<chardev_id>,bus=<pcie.0>,addr=<0x3>[,num-queues=<N>][,bootindex=<N>]; \
                    \n\t\tadd virtio mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
-                   \n\t\tadd virtio pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
+                   \n\t\tadd virtio pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off][,rx-bps|tx-bps|rx-pps|tx-pps=<N>][,<limit>-max=<N>][,<limit>-max-length=<seconds>]; \
                    \n\t\tadd vhost mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
                    \n\t\tadd vhost pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
                    \n\t\tadd virtio mmio console: -device virtio-serial-device[,id=<virtio-serial0>] -device virtconsole,id=console_id,chardev=<virtioconsole1>; \
@@ -247,7 +247,8 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
                    \n\t\tadd usb keyboard: -device usb-kbd,id=<kbd>; \
                    \n\t\tadd usb tablet-device usb-tablet,id=<tablet>; \
                    \n\t\tadd scsi controller: -device virtio-scsi-pci,id=<scsi_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,num-queues=<N>]; \
-                   \n\t\tadd scsi hard disk: -device scsi-hd,scsi-id=<0>,bus=<scsi0.0>,lun=<0>,drive=<drive-scsi0-0-0-0>,id=<scsi0-0-0-0>; \
+                   \n\t\tadd scsi hard disk: -device scsi-hd,scsi-id=<0>,bus=<scsi0.0>,lun=<0>,drive=<drive-scsi0-0-0-0>,id=<scsi0-0-0-0>[,pr-initiator=<name>][,pr-manager=<pr_id>]; \
+                   \n\t\tadd scsi passthrough device: -device scsi-generic|scsi-block,scsi-id=<0>,bus=<scsi0.0>,lun=<0>,drive=<drive-scsi0-0-0-0>,id=<scsi0-0-0-0>; \
                    \n\t\tadd vhost user fs: -device vhost-user-fs-pci,id=<device_id>,chardev=<chardev_id>,tag=<mount_tag>")
             .takes_values(true),
         )
@@ -304,10 +305,12 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
             .long("object")
             .value_name("<parameters>")
             .help("\n\t\tadd memory backend ram object: -object memory-backend-ram,id=<memid>,size=<2G>,host-nodes=<0-1>,policy=<bind>; \
-                   \n\t\tadd iothread object: -object iothread,id=<iothread_id>; \
+                   \n\t\tadd iothread object: -object iothread,id=<iothread_id>[,poll-max-ns=<ns>][,poll-grow=<N>][,poll-shrink=<N>]; \
                    \n\t\tadd rng object: -object rng-random,id=<rng_id>,filename=<file_path>; \
                    \n\t\tadd vnc tls object: -object tls-creds-x509,id=<vnc_id>,dir=</etc/pki/vnc>; \
-                   \n\t\tadd authz object: -object authz-simple,id=<authz_id>,identity=<username>")
+                   \n\t\tadd authz object: -object authz-simple,id=<authz_id>,identity=<username>; \
+                   \n\t\tadd pr manager object: -object pr-manager-helper,id=<pr_id>,path=<socket_path>; \
+                   \n\t\tadd packet capture object: -object filter-dump,id=<dump_id>,netdev=<netdev_id>,file=<pcap_file>[,maxlen=<bytes>][,maxsize=<bytes>][,maxfiles=<N>][,queue=all|rx|tx]")
             .takes_values(true),
         )
         .arg(
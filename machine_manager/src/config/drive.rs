@@ -10,9 +10,11 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::fmt;
 use std::fs::{metadata, File};
 use std::os::linux::fs::MetadataExt;
 use std::path::Path;
+use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Result};
 use log::error;
@@ -20,19 +22,23 @@ use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check};
 use crate::config::{
-    get_chardev_socket_path, CmdParser, ConfigCheck, ExBool, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
-    MAX_PATH_LENGTH, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
+    get_chardev_socket_path, is_nbd_path, CmdParser, ConfigCheck, ExBool, NbdUri, VmConfig,
+    DEFAULT_VIRTQUEUE_SIZE, MAX_PATH_LENGTH, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
 };
 use crate::qmp::qmp_schema;
 use util::aio::{aio_probe, AioEngine};
 const MAX_SERIAL_NUM: usize = 20;
 const MAX_IOPS: u64 = 1_000_000;
+const MAX_BPS: u64 = 1 << 50;
 const MAX_UNIT_ID: usize = 2;
 
 // Seg_max = queue_size - 2. So, size of each virtqueue for virtio-blk should be larger than 2.
 const MIN_QUEUE_SIZE_BLK: u16 = 2;
 // Max size of each virtqueue for virtio-blk.
 const MAX_QUEUE_SIZE_BLK: u16 = 1024;
+// Zone size of virtio-blk is in sectors of u32.
+const MIN_ZONE_SIZE: u64 = 4096;
+const MAX_ZONE_SIZE: u64 = 1 << 40;
 
 /// Represent a single drive backend file.
 pub struct DriveFile {
@@ -46,12 +52,235 @@ pub struct DriveFile {
     pub read_only: bool,
     /// File lock status.
     pub locked: bool,
+    /// File can be written by other processes, so it's locked shared.
+    pub shared: bool,
     /// The align requirement of request(offset/len).
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
     pub buf_align: u32,
 }
 
+/// Format of the disk image.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum DiskFormat {
+    Raw,
+    Qcow2,
+}
+
+impl FromStr for DiskFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(DiskFormat::Raw),
+            "qcow2" => Ok(DiskFormat::Qcow2),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                DiskFormat::Raw => "raw",
+                DiskFormat::Qcow2 => "qcow2",
+            }
+        )
+    }
+}
+
+/// Action taken by block device when the IO fails.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum BlockErrorAction {
+    /// Report the error to guest.
+    Report,
+    /// Ignore the error, the request is completed successfully for guest.
+    Ignore,
+    /// Stop the VM, the request is retried after VM resumes.
+    Stop,
+}
+
+impl FromStr for BlockErrorAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(BlockErrorAction::Report),
+            "ignore" => Ok(BlockErrorAction::Ignore),
+            "stop" => Ok(BlockErrorAction::Stop),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for BlockErrorAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                BlockErrorAction::Report => "report",
+                BlockErrorAction::Ignore => "ignore",
+                BlockErrorAction::Stop => "stop",
+            }
+        )
+    }
+}
+
+/// Limit of one kind of IO for throttling.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleLimit {
+    /// Average units per second, 0 means unlimited.
+    pub avg: u64,
+    /// Units per second allowed during burst, 0 means no burst.
+    pub max: u64,
+    /// Seconds that the burst can last.
+    pub max_length: u64,
+}
+
+impl Default for ThrottleLimit {
+    fn default() -> Self {
+        ThrottleLimit {
+            avg: 0,
+            max: 0,
+            max_length: 1,
+        }
+    }
+}
+
+/// Throttling limits of block drive, the bandwidth is in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThrottleConfig {
+    pub bps_total: ThrottleLimit,
+    pub bps_read: ThrottleLimit,
+    pub bps_write: ThrottleLimit,
+    pub iops_total: ThrottleLimit,
+    pub iops_read: ThrottleLimit,
+    pub iops_write: ThrottleLimit,
+}
+
+impl ThrottleConfig {
+    /// Names of the limits in command line, in the same order as `limits()`.
+    pub const LIMIT_NAMES: [&'static str; 6] = [
+        "bps-total",
+        "bps-read",
+        "bps-write",
+        "iops-total",
+        "iops-read",
+        "iops-write",
+    ];
+
+    pub fn limits(&self) -> [&ThrottleLimit; 6] {
+        [
+            &self.bps_total,
+            &self.bps_read,
+            &self.bps_write,
+            &self.iops_total,
+            &self.iops_read,
+            &self.iops_write,
+        ]
+    }
+
+    pub fn limits_mut(&mut self) -> [&mut ThrottleLimit; 6] {
+        [
+            &mut self.bps_total,
+            &mut self.bps_read,
+            &mut self.bps_write,
+            &mut self.iops_total,
+            &mut self.iops_read,
+            &mut self.iops_write,
+        ]
+    }
+
+    /// Whether any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.limits().iter().any(|limit| limit.avg != 0)
+    }
+
+    fn parse(&mut self, cmd_parser: &CmdParser) -> Result<()> {
+        for (name, limit) in Self::LIMIT_NAMES.iter().zip(self.limits_mut()) {
+            let key = format!("throttling.{}", name);
+            limit.avg = cmd_parser.get_value::<u64>(&key)?.unwrap_or(0);
+            limit.max = cmd_parser
+                .get_value::<u64>(&format!("{}-max", key))?
+                .unwrap_or(0);
+            limit.max_length = cmd_parser
+                .get_value::<u64>(&format!("{}-max-length", key))?
+                .unwrap_or(1);
+        }
+        Ok(())
+    }
+
+    fn push_params(cmd_parser: &mut CmdParser) {
+        for name in Self::LIMIT_NAMES.iter() {
+            cmd_parser
+                .push(&format!("throttling.{}", name))
+                .push(&format!("throttling.{}-max", name))
+                .push(&format!("throttling.{}-max-length", name));
+        }
+        cmd_parser.push("throttling.group");
+    }
+}
+
+impl ConfigCheck for ThrottleConfig {
+    fn check(&self) -> Result<()> {
+        for (name, limit) in Self::LIMIT_NAMES.iter().zip(self.limits()) {
+            let max_value = match name.starts_with("iops") {
+                true => MAX_IOPS,
+                false => MAX_BPS,
+            };
+            if limit.avg > max_value || limit.max > max_value {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    format!("throttling.{} of block device", name),
+                    0,
+                    true,
+                    max_value,
+                    true,
+                )));
+            }
+            if limit.max != 0 && limit.max < limit.avg {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    format!("throttling.{}-max", name),
+                    format!("it should be no less than throttling.{}", name),
+                )));
+            }
+            if limit.max != 0 && limit.avg == 0 {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    format!("throttling.{}-max", name),
+                    format!("throttling.{} is required", name),
+                )));
+            }
+            if limit.max_length == 0 || limit.max_length > MAX_IOPS {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    format!("throttling.{}-max-length of block device", name),
+                    1,
+                    true,
+                    MAX_IOPS,
+                    true,
+                )));
+            }
+            if limit.max_length > 1 && limit.max == 0 {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    format!("throttling.{}-max-length", name),
+                    format!("throttling.{}-max is required", name),
+                )));
+            }
+        }
+        if self.bps_total.avg != 0 && (self.bps_read.avg != 0 || self.bps_write.avg != 0) {
+            bail!("bps-total and bps-read/bps-write of throttling cannot be set at the same time");
+        }
+        if self.iops_total.avg != 0 && (self.iops_read.avg != 0 || self.iops_write.avg != 0) {
+            bail!(
+                "iops-total and iops-read/iops-write of throttling cannot be set at the same time"
+            );
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct BlkDevConfig {
@@ -59,15 +288,31 @@ pub struct BlkDevConfig {
     pub path_on_host: String,
     pub read_only: bool,
     pub direct: bool,
+    pub format: DiskFormat,
     pub serial_num: Option<String>,
     pub iothread: Option<String>,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
+    /// Throttle group which the limits are shared with.
+    pub throttle_group: Option<String>,
     pub queues: u16,
     pub boot_index: Option<u8>,
     pub chardev: Option<String>,
     pub socket_path: Option<String>,
     pub aio: AioEngine,
     pub queue_size: u16,
+    /// Action on write error.
+    pub werror: BlockErrorAction,
+    /// Action on read error.
+    pub rerror: BlockErrorAction,
+    /// Copy the data read from backing file into the image.
+    pub copy_on_read: bool,
+    /// Size in bytes of the zones emulated over the image.
+    pub zone_size: Option<u64>,
+    /// Number of the conventional zones at the start of emulated zones.
+    pub conventional_zones: u32,
+    /// Limits of the open and active emulated zones, zero means no limit.
+    pub max_open_zones: u32,
+    pub max_active_zones: u32,
 }
 
 #[derive(Debug, Clone)]
@@ -84,15 +329,24 @@ impl Default for BlkDevConfig {
             path_on_host: "".to_string(),
             read_only: false,
             direct: true,
+            format: DiskFormat::Raw,
             serial_num: None,
             iothread: None,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            throttle_group: None,
             queues: 1,
             boot_index: None,
             chardev: None,
             socket_path: None,
             aio: AioEngine::Native,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            werror: BlockErrorAction::Report,
+            rerror: BlockErrorAction::Report,
+            copy_on_read: false,
+            zone_size: None,
+            conventional_zones: 0,
+            max_open_zones: 0,
+            max_active_zones: 0,
         }
     }
 }
@@ -106,8 +360,19 @@ pub struct DriveConfig {
     pub path_on_host: String,
     pub read_only: bool,
     pub direct: bool,
-    pub iops: Option<u64>,
+    pub throttle: ThrottleConfig,
+    /// Throttle group which the limits are shared with.
+    pub throttle_group: Option<String>,
     pub aio: AioEngine,
+    pub format: DiskFormat,
+    /// Action on write error.
+    pub werror: BlockErrorAction,
+    /// Action on read error.
+    pub rerror: BlockErrorAction,
+    /// Copy the data read from backing file into the image.
+    pub copy_on_read: bool,
+    /// Allow other VMs to write the file at the same time.
+    pub share_rw: bool,
 }
 
 impl Default for DriveConfig {
@@ -117,8 +382,14 @@ impl Default for DriveConfig {
             path_on_host: "".to_string(),
             read_only: false,
             direct: true,
-            iops: None,
+            throttle: ThrottleConfig::default(),
+            throttle_group: None,
             aio: AioEngine::Native,
+            format: DiskFormat::Raw,
+            werror: BlockErrorAction::Report,
+            rerror: BlockErrorAction::Report,
+            copy_on_read: false,
+            share_rw: false,
         }
     }
 }
@@ -126,6 +397,9 @@ impl Default for DriveConfig {
 impl DriveConfig {
     /// Check whether the drive file path on the host is valid.
     pub fn check_path(&self) -> Result<()> {
+        if is_nbd_path(&self.path_on_host) {
+            return self.path_on_host.parse::<NbdUri>().map(|_| ());
+        }
         let blk = Path::new(&self.path_on_host);
         match metadata(blk) {
             Ok(meta) => {
@@ -161,6 +435,19 @@ impl DriveConfig {
         }
         Ok(())
     }
+
+    /// Check that no error policy is set for the drive used by `device`, which
+    /// reports all the IO errors to guest.
+    pub fn check_no_error_policy(&self, device: &str) -> Result<()> {
+        if self.werror != BlockErrorAction::Report || self.rerror != BlockErrorAction::Report {
+            bail!(
+                "werror and rerror of drive {} are not supported by {}",
+                self.id,
+                device
+            );
+        }
+        Ok(())
+    }
 }
 
 impl ConfigCheck for DriveConfig {
@@ -177,15 +464,21 @@ impl ConfigCheck for DriveConfig {
                 MAX_PATH_LENGTH,
             )));
         }
-        if self.iops.is_some() && self.iops.unwrap() > MAX_IOPS {
-            return Err(anyhow!(ConfigError::IllegalValue(
-                "iops of block device".to_string(),
-                0,
-                true,
-                MAX_IOPS,
-                true,
+        self.throttle.check()?;
+        if self.copy_on_read && self.read_only {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                "copy-on-read".to_string(),
+                "copy-on-read should be used with \"readonly\" off".to_string(),
             )));
         }
+        if let Some(group) = self.throttle_group.as_ref() {
+            if group.len() > MAX_STRING_LENGTH {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "throttle group name".to_string(),
+                    MAX_STRING_LENGTH,
+                )));
+            }
+        }
         if self.aio != AioEngine::Off {
             if self.aio == AioEngine::Native && !self.direct {
                 return Err(anyhow!(ConfigError::InvalidParam(
@@ -204,6 +497,47 @@ impl ConfigCheck for DriveConfig {
     }
 }
 
+impl BlkDevConfig {
+    /// Check the options of zones emulated over the image.
+    fn check_zones(&self) -> Result<()> {
+        let zone_size = match self.zone_size {
+            Some(size) => size,
+            None => {
+                if self.conventional_zones != 0
+                    || self.max_open_zones != 0
+                    || self.max_active_zones != 0
+                {
+                    bail!("Options of emulated zones are set without zone-size");
+                }
+                return Ok(());
+            }
+        };
+        if !zone_size.is_power_of_two() || !(MIN_ZONE_SIZE..=MAX_ZONE_SIZE).contains(&zone_size) {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "zone size of block device (power of 2)".to_string(),
+                MIN_ZONE_SIZE,
+                true,
+                MAX_ZONE_SIZE,
+                true,
+            )));
+        }
+        if self.max_open_zones != 0
+            && self.max_active_zones != 0
+            && self.max_open_zones > self.max_active_zones
+        {
+            bail!(
+                "max-open-zones {} is larger than max-active-zones {}",
+                self.max_open_zones,
+                self.max_active_zones
+            );
+        }
+        if self.read_only {
+            bail!("Zones can't be emulated over read only image");
+        }
+        Ok(())
+    }
+}
+
 impl ConfigCheck for BlkDevConfig {
     fn check(&self) -> Result<()> {
         if self.id.len() > MAX_STRING_LENGTH {
@@ -251,10 +585,13 @@ impl ConfigCheck for BlkDevConfig {
             bail!("Queue size should be power of 2!");
         }
 
+        self.check_zones()?;
+
         let fake_drive = DriveConfig {
             path_on_host: self.path_on_host.clone(),
             direct: self.direct,
-            iops: self.iops,
+            throttle: self.throttle,
+            throttle_group: self.throttle_group.clone(),
             aio: self.aio,
             ..Default::default()
         };
@@ -271,10 +608,8 @@ impl ConfigCheck for BlkDevConfig {
 fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     let mut drive = DriveConfig::default();
 
-    if let Some(format) = cmd_parser.get_value::<String>("format")? {
-        if format.ne("raw") {
-            bail!("Only \'raw\' type of block is supported");
-        }
+    if let Some(format) = cmd_parser.get_value::<DiskFormat>("format")? {
+        drive.format = format;
     }
 
     if let Some(id) = cmd_parser.get_value::<String>("id")? {
@@ -295,7 +630,8 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
     if let Some(direct) = cmd_parser.get_value::<ExBool>("direct")? {
         drive.direct = direct.into();
     }
-    drive.iops = cmd_parser.get_value::<u64>("throttling.iops-total")?;
+    drive.throttle.parse(&cmd_parser)?;
+    drive.throttle_group = cmd_parser.get_value::<String>("throttling.group")?;
     drive.aio = cmd_parser.get_value::<AioEngine>("aio")?.unwrap_or({
         if drive.direct {
             AioEngine::Native
@@ -303,6 +639,18 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
             AioEngine::Off
         }
     });
+    if let Some(werror) = cmd_parser.get_value::<BlockErrorAction>("werror")? {
+        drive.werror = werror;
+    }
+    if let Some(rerror) = cmd_parser.get_value::<BlockErrorAction>("rerror")? {
+        drive.rerror = rerror;
+    }
+    if let Some(copy_on_read) = cmd_parser.get_value::<ExBool>("copy-on-read")? {
+        drive.copy_on_read = copy_on_read.into();
+    }
+    if let Some(share_rw) = cmd_parser.get_value::<ExBool>("share-rw")? {
+        drive.share_rw = share_rw.into();
+    }
     drive.check()?;
     #[cfg(not(test))]
     drive.check_path()?;
@@ -326,7 +674,11 @@ pub fn parse_blk(
         .push("serial")
         .push("iothread")
         .push("num-queues")
-        .push("queue-size");
+        .push("queue-size")
+        .push("zone-size")
+        .push("conventional-zones")
+        .push("max-open-zones")
+        .push("max-active-zones");
 
     cmd_parser.parse(drive_config)?;
 
@@ -367,12 +719,28 @@ pub fn parse_blk(
         blkdevcfg.queue_size = queue_size;
     }
 
+    blkdevcfg.zone_size = cmd_parser.get_value::<u64>("zone-size")?;
+    if let Some(zones) = cmd_parser.get_value::<u32>("conventional-zones")? {
+        blkdevcfg.conventional_zones = zones;
+    }
+    if let Some(zones) = cmd_parser.get_value::<u32>("max-open-zones")? {
+        blkdevcfg.max_open_zones = zones;
+    }
+    if let Some(zones) = cmd_parser.get_value::<u32>("max-active-zones")? {
+        blkdevcfg.max_active_zones = zones;
+    }
+
     if let Some(drive_arg) = &vm_config.drives.remove(&blkdrive) {
         blkdevcfg.path_on_host = drive_arg.path_on_host.clone();
         blkdevcfg.read_only = drive_arg.read_only;
         blkdevcfg.direct = drive_arg.direct;
-        blkdevcfg.iops = drive_arg.iops;
+        blkdevcfg.throttle = drive_arg.throttle;
+        blkdevcfg.throttle_group = drive_arg.throttle_group.clone();
         blkdevcfg.aio = drive_arg.aio;
+        blkdevcfg.format = drive_arg.format;
+        blkdevcfg.werror = drive_arg.werror;
+        blkdevcfg.rerror = drive_arg.rerror;
+        blkdevcfg.copy_on_read = drive_arg.copy_on_read;
     } else {
         bail!("No drive configured matched for blk device");
     }
@@ -504,8 +872,12 @@ impl VmConfig {
             .push("direct")
             .push("format")
             .push("if")
-            .push("throttling.iops-total")
-            .push("aio");
+            .push("aio")
+            .push("werror")
+            .push("rerror")
+            .push("copy-on-read")
+            .push("share-rw");
+        ThrottleConfig::push_params(&mut cmd_parser);
 
         cmd_parser.parse(block_config)?;
         let drive_cfg = parse_drive(cmd_parser)?;
@@ -683,6 +1055,21 @@ mod tests {
             None,
         );
         assert!(blk_cfg_res.is_err()); // Can not find drive named "rootfs1".
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=qcow2")
+            .is_ok());
+        let blk_cfg_res = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=rootfs",
+            None,
+        );
+        assert_eq!(blk_cfg_res.unwrap().format, DiskFormat::Qcow2);
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=vmdk")
+            .is_err());
     }
 
     #[test]
@@ -793,34 +1180,178 @@ mod tests {
         assert!(drive_conf.check().is_err());
 
         let mut drive_conf = DriveConfig::default();
-        drive_conf.iops = Some(MAX_IOPS);
+        drive_conf.throttle.iops_total.avg = MAX_IOPS;
         assert!(drive_conf.check().is_ok());
 
         let mut drive_conf = DriveConfig::default();
-        drive_conf.iops = None;
+        drive_conf.throttle.iops_total.avg = 0;
         assert!(drive_conf.check().is_ok());
 
         // Overflow
-        drive_conf.iops = Some(MAX_IOPS + 1);
+        drive_conf.throttle.iops_total.avg = MAX_IOPS + 1;
         assert!(drive_conf.check().is_err());
     }
 
+    #[test]
+    fn test_drive_throttle_config() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive(
+                "id=rootfs,file=/path/to/rootfs,direct=off,throttling.bps-read=1048576,\
+                 throttling.bps-read-max=2097152,throttling.bps-read-max-length=10,\
+                 throttling.iops-total=100,throttling.group=group0"
+            )
+            .is_ok());
+        let drive = vm_config.drives.get("rootfs").unwrap();
+        assert_eq!(drive.throttle.bps_read.avg, 1 << 20);
+        assert_eq!(drive.throttle.bps_read.max, 2 << 20);
+        assert_eq!(drive.throttle.bps_read.max_length, 10);
+        assert_eq!(drive.throttle.iops_total.avg, 100);
+        assert_eq!(drive.throttle.iops_total.max_length, 1);
+        assert_eq!(drive.throttle_group, Some("group0".to_string()));
+        assert!(drive.throttle.is_enabled());
+
+        let mut throttle = ThrottleConfig::default();
+        assert!(!throttle.is_enabled());
+        // Burst limit without average limit.
+        throttle.bps_write.max = 100;
+        assert!(throttle.check().is_err());
+        // Burst limit less than average limit.
+        throttle.bps_write.avg = 200;
+        assert!(throttle.check().is_err());
+        throttle.bps_write.max = 400;
+        assert!(throttle.check().is_ok());
+        // Burst length without burst limit.
+        throttle.iops_read.avg = 100;
+        throttle.iops_read.max_length = 2;
+        assert!(throttle.check().is_err());
+        throttle.iops_read.max_length = 1;
+        assert!(throttle.check().is_ok());
+        // Total limit and read/write limit at the same time.
+        throttle.iops_total.avg = 100;
+        assert!(throttle.check().is_err());
+    }
+
+    #[test]
+    fn test_drive_error_action() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,werror=stop,rerror=ignore")
+            .is_ok());
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=blk1",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.werror, BlockErrorAction::Stop);
+        assert_eq!(blk_cfg.rerror, BlockErrorAction::Ignore);
+
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        let drive = vm_config.drives.get("rootfs").unwrap();
+        assert_eq!(drive.werror, BlockErrorAction::Report);
+        assert_eq!(drive.rerror, BlockErrorAction::Report);
+
+        assert!(vm_config
+            .add_drive("id=drive1,file=/path/to/drive1,werror=enospc")
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_copy_on_read() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,format=qcow2,copy-on-read=on")
+            .is_ok());
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=blk1",
+            None,
+        )
+        .unwrap();
+        assert!(blk_cfg.copy_on_read);
+
+        assert!(vm_config
+            .add_drive("id=drive1,file=/path/to/drive1,readonly=on,copy-on-read=on")
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_share_rw() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=drive0,file=/path/to/drive0,share-rw=on")
+            .is_ok());
+        assert!(vm_config
+            .add_drive("id=drive1,file=/path/to/drive1")
+            .is_ok());
+        assert!(vm_config.drives.get("drive0").unwrap().share_rw);
+        assert!(!vm_config.drives.get("drive1").unwrap().share_rw);
+    }
+
+    #[test]
+    fn test_blk_zoned_config() {
+        let mut vm_config = VmConfig::default();
+        for id in ["drive0", "drive1", "drive2", "drive3"] {
+            let drive = format!("id={},file=/path/to/{}", id, id);
+            assert!(vm_config.add_drive(&drive).is_ok());
+        }
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=drive0,id=blk0,zone-size=1048576,conventional-zones=2,max-open-zones=4,max-active-zones=8",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.zone_size, Some(1 << 20));
+        assert_eq!(blk_cfg.conventional_zones, 2);
+        assert_eq!(blk_cfg.max_open_zones, 4);
+        assert_eq!(blk_cfg.max_active_zones, 8);
+
+        // Zone size is power of 2.
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=drive1,id=blk1,zone-size=1000000",
+            None
+        )
+        .is_err());
+        // Open zones are limited by active zones.
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=drive2,id=blk2,zone-size=1048576,max-open-zones=4,max-active-zones=2",
+            None
+        )
+        .is_err());
+        // Zone limits are for emulated zones.
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=drive3,id=blk3,max-open-zones=4",
+            None
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_add_drive_with_config() {
         let mut vm_config = VmConfig::default();
 
         let drive_list = ["drive-0", "drive-1", "drive-2"];
         for id in drive_list.iter() {
-            let mut drive_conf = DriveConfig::default();
-            drive_conf.id = String::from(*id);
+            let drive_conf = DriveConfig {
+                id: String::from(*id),
+                ..Default::default()
+            };
             assert!(vm_config.add_drive_with_config(drive_conf).is_ok());
 
             let drive = vm_config.drives.get(*id).unwrap();
             assert_eq!(*id, drive.id);
         }
 
-        let mut drive_conf = DriveConfig::default();
-        drive_conf.id = String::from("drive-0");
+        let drive_conf = DriveConfig {
+            id: String::from("drive-0"),
+            ..Default::default()
+        };
         assert!(vm_config.add_drive_with_config(drive_conf).is_err());
     }
 
@@ -832,14 +1363,14 @@ mod tests {
 
         let drive_list = ["drive-0", "drive-1", "drive-2"];
         for id in drive_list.iter() {
-            let mut drive_conf = DriveConfig::default();
-            drive_conf.id = String::from(*id);
+            let drive_conf = DriveConfig {
+                id: String::from(*id),
+                ..Default::default()
+            };
             assert!(vm_config.add_drive_with_config(drive_conf).is_ok());
         }
 
         for id in drive_list.iter() {
-            let mut drive_conf = DriveConfig::default();
-            drive_conf.id = String::from(*id);
             assert!(vm_config.drives.get(*id).is_some());
             assert!(vm_config.del_drive_by_id(*id).is_ok());
             assert!(vm_config.drives.get(*id).is_none());
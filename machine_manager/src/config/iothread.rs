@@ -22,6 +22,12 @@ const MAX_IOTHREAD_NUM: usize = 8;
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct IothreadConfig {
     pub id: String,
+    /// Max time in nanoseconds to busy poll the IO before waiting for events,
+    /// zero means the adaptive polling is disabled.
+    pub poll_max_ns: u32,
+    /// Factors to grow and shrink the polling time.
+    pub poll_grow: u32,
+    pub poll_shrink: u32,
 }
 
 impl ConfigCheck for IothreadConfig {
@@ -41,13 +47,27 @@ impl VmConfig {
     /// Add new iothread device to `VmConfig`.
     pub fn add_iothread(&mut self, iothread_config: &str) -> Result<()> {
         let mut cmd_parser = CmdParser::new("iothread");
-        cmd_parser.push("").push("id");
+        cmd_parser
+            .push("")
+            .push("id")
+            .push("poll-max-ns")
+            .push("poll-grow")
+            .push("poll-shrink");
         cmd_parser.parse(iothread_config)?;
 
         let mut iothread = IothreadConfig::default();
         if let Some(id) = cmd_parser.get_value::<String>("id")? {
             iothread.id = id;
         }
+        if let Some(poll_max_ns) = cmd_parser.get_value::<u32>("poll-max-ns")? {
+            iothread.poll_max_ns = poll_max_ns;
+        }
+        if let Some(poll_grow) = cmd_parser.get_value::<u32>("poll-grow")? {
+            iothread.poll_grow = poll_grow;
+        }
+        if let Some(poll_shrink) = cmd_parser.get_value::<u32>("poll-shrink")? {
+            iothread.poll_shrink = poll_shrink;
+        }
         iothread.check()?;
 
         if self.iothreads.is_some() {
@@ -112,4 +132,23 @@ mod tests {
         assert!(vm_config.add_object("iothread,id=iothread0").is_ok());
         assert!(vm_config.add_object("iothread,id=iothread0").is_err());
     }
+
+    #[test]
+    fn test_iothread_poll_config() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_object("iothread,id=iothread0,poll-max-ns=32768,poll-grow=4,poll-shrink=2")
+            .is_ok());
+        assert!(vm_config.add_object("iothread,id=iothread1").is_ok());
+        let iothreads = vm_config.iothreads.unwrap();
+        assert_eq!(iothreads[0].poll_max_ns, 32768);
+        assert_eq!(iothreads[0].poll_grow, 4);
+        assert_eq!(iothreads[0].poll_shrink, 2);
+        assert_eq!(iothreads[1].poll_max_ns, 0);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_object("iothread,id=iothread0,poll-max-ns=-1")
+            .is_err());
+    }
 }
@@ -22,8 +22,10 @@ pub use gpu::*;
 pub use incoming::*;
 pub use iothread::*;
 pub use machine_config::*;
+pub use nbd::*;
 pub use network::*;
 pub use numa::*;
+pub use nvme::*;
 pub use pci::*;
 pub use rng::*;
 pub use sasl_auth::*;
@@ -45,8 +47,10 @@ mod gpu;
 mod incoming;
 mod iothread;
 mod machine_config;
+mod nbd;
 mod network;
 mod numa;
+mod nvme;
 mod pci;
 mod rng;
 mod sasl_auth;
@@ -59,6 +63,7 @@ pub mod vnc;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::FileTypeExt;
 use std::str::FromStr;
 
 use serde::{Deserialize, Serialize};
@@ -93,6 +98,8 @@ pub struct ObjectConfig {
     pub mem_object: HashMap<String, MemZoneConfig>,
     pub tls_object: HashMap<String, TlsCredObjConfig>,
     pub sasl_object: HashMap<String, SaslAuthObjConfig>,
+    pub pr_manager_object: HashMap<String, PrManagerObjConfig>,
+    pub filter_dump: HashMap<String, FilterDumpConfig>,
 }
 
 /// This main config structure for Vm, contains Vm's basic configuration and devices.
@@ -218,6 +225,22 @@ impl VmConfig {
             "authz-simple" => {
                 self.add_saslauth(object_args)?;
             }
+            "pr-manager-helper" => {
+                let pr_cfg = parse_pr_manager_obj(object_args)?;
+                let id = pr_cfg.id.clone();
+                if self.object.pr_manager_object.contains_key(&id) {
+                    bail!("Object: {} has been added", id);
+                }
+                self.object.pr_manager_object.insert(id, pr_cfg);
+            }
+            "filter-dump" => {
+                let dump_cfg = parse_filter_dump(object_args)?;
+                let id = dump_cfg.id.clone();
+                if self.object.filter_dump.contains_key(&id) {
+                    bail!("Object: {} has been added", id);
+                }
+                self.object.filter_dump.insert(id, dump_cfg);
+            }
             _ => {
                 bail!("Unknow object type: {:?}", &device_type);
             }
@@ -260,6 +283,10 @@ impl VmConfig {
         read_only: bool,
         direct: bool,
     ) -> Result<()> {
+        // The export of NBD server is connected by the block backend.
+        if is_nbd_path(path) {
+            return Ok(());
+        }
         if let Some(drive_file) = drive_files.get_mut(path) {
             if drive_file.read_only && read_only {
                 // File can be shared with read_only.
@@ -273,17 +300,27 @@ impl VmConfig {
                 ));
             }
         }
-        let mut file = open_file(path, read_only, direct)?;
-        let (req_align, buf_align) = get_file_alignment(&file, direct);
+        // The sg device of scsi-generic is a character device, which can't be
+        // opened with O_DIRECT, read or seeked like an image file.
+        let char_dev = std::fs::metadata(path)
+            .map(|meta| meta.file_type().is_char_device())
+            .unwrap_or(false);
+        let mut file = open_file(path, read_only, direct && !char_dev)?;
+        let (req_align, buf_align) = match char_dev {
+            true => (1, 1),
+            false => get_file_alignment(&file, direct),
+        };
         if req_align == 0 || buf_align == 0 {
             bail!(
                 "Failed to detect alignment requirement of drive file {}.",
                 path
             );
         }
-        let file_size = file.seek(SeekFrom::End(0))?;
-        if file_size & (req_align as u64 - 1) != 0 {
-            bail!("The size of file {} is not aligned to {}.", path, req_align);
+        if !char_dev {
+            let file_size = file.seek(SeekFrom::End(0))?;
+            if file_size & (req_align as u64 - 1) != 0 {
+                bail!("The size of file {} is not aligned to {}.", path, req_align);
+            }
         }
         let drive_file = DriveFile {
             file,
@@ -291,6 +328,7 @@ impl VmConfig {
             read_only,
             path: path.to_string(),
             locked: false,
+            shared: false,
             req_align,
             buf_align,
         };
@@ -303,6 +341,9 @@ impl VmConfig {
         drive_files: &mut HashMap<String, DriveFile>,
         path: &str,
     ) -> Result<()> {
+        if is_nbd_path(path) {
+            return Ok(());
+        }
         if let Some(drive_file) = drive_files.get_mut(path) {
             drive_file.count -= 1;
             if drive_file.count == 0 {
@@ -349,6 +390,9 @@ impl VmConfig {
                 drive.read_only,
                 drive.direct,
             )?;
+            if let Some(drive_file) = drive_files.get_mut(&drive.path_on_host) {
+                drive_file.shared = drive.share_rw;
+            }
         }
         if let Some(pflashs) = self.pflashs.as_ref() {
             for pflash in pflashs {
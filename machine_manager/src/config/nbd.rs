@@ -0,0 +1,160 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::error::ConfigError;
+use crate::config::MAX_STRING_LENGTH;
+
+/// Prefix of the drive file which is an export of NBD server.
+const NBD_PATH_PREFIX: &str = "nbd:";
+/// Option of the drive file to specify the export name.
+const NBD_EXPORT_NAME_OPT: &str = ":exportname=";
+
+/// Address of the NBD server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NbdAddr {
+    /// Path of the unix socket.
+    Unix(String),
+    /// Host and port of the TCP socket.
+    Inet { host: String, port: u16 },
+}
+
+impl fmt::Display for NbdAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NbdAddr::Unix(path) => write!(f, "unix:{}", path),
+            NbdAddr::Inet { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// The export of NBD server used as drive file, in the form of
+/// `nbd:unix:<socket_path>[:exportname=<name>]` or
+/// `nbd:<host>:<port>[:exportname=<name>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NbdUri {
+    pub addr: NbdAddr,
+    /// Name of the export, the default export of server is used if empty.
+    pub export: String,
+}
+
+impl FromStr for NbdUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let uri = s
+            .strip_prefix(NBD_PATH_PREFIX)
+            .ok_or_else(|| anyhow!("Invalid nbd drive file {}", s))?;
+        let (addr, export) = match uri.find(NBD_EXPORT_NAME_OPT) {
+            Some(pos) => (&uri[..pos], &uri[pos + NBD_EXPORT_NAME_OPT.len()..]),
+            None => (uri, ""),
+        };
+        if export.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "nbd export name".to_string(),
+                MAX_STRING_LENGTH
+            )));
+        }
+
+        let addr = if let Some(path) = addr.strip_prefix("unix:") {
+            if path.is_empty() {
+                bail!("Socket path of nbd drive file {} is empty", s);
+            }
+            NbdAddr::Unix(path.to_string())
+        } else {
+            let (host, port) = addr
+                .rsplit_once(':')
+                .ok_or_else(|| anyhow!("Port of nbd drive file {} is missing", s))?;
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| anyhow!("Invalid port of nbd drive file {}", s))?;
+            if host.is_empty() {
+                bail!("Host of nbd drive file {} is empty", s);
+            }
+            // IPv6 address may be enclosed in brackets.
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            NbdAddr::Inet {
+                host: host.to_string(),
+                port,
+            }
+        };
+
+        Ok(NbdUri {
+            addr,
+            export: export.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for NbdUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", NBD_PATH_PREFIX, self.addr)?;
+        if !self.export.is_empty() {
+            write!(f, "{}{}", NBD_EXPORT_NAME_OPT, self.export)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether the drive file is an export of NBD server rather than a host file.
+pub fn is_nbd_path(path: &str) -> bool {
+    path.starts_with(NBD_PATH_PREFIX)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nbd_uri() {
+        let uri = "nbd:unix:/tmp/nbd.sock:exportname=disk0"
+            .parse::<NbdUri>()
+            .unwrap();
+        assert_eq!(uri.addr, NbdAddr::Unix("/tmp/nbd.sock".to_string()));
+        assert_eq!(uri.export, "disk0");
+        assert_eq!(uri.to_string(), "nbd:unix:/tmp/nbd.sock:exportname=disk0");
+
+        let uri = "nbd:127.0.0.1:10809".parse::<NbdUri>().unwrap();
+        assert_eq!(
+            uri.addr,
+            NbdAddr::Inet {
+                host: "127.0.0.1".to_string(),
+                port: 10809
+            }
+        );
+        assert!(uri.export.is_empty());
+
+        let uri = "nbd:[::1]:10809:exportname=disk0"
+            .parse::<NbdUri>()
+            .unwrap();
+        assert_eq!(
+            uri.addr,
+            NbdAddr::Inet {
+                host: "::1".to_string(),
+                port: 10809
+            }
+        );
+
+        assert!("nbd:unix:".parse::<NbdUri>().is_err());
+        assert!("nbd:127.0.0.1".parse::<NbdUri>().is_err());
+        assert!("nbd:127.0.0.1:port".parse::<NbdUri>().is_err());
+        assert!("nbd::10809".parse::<NbdUri>().is_err());
+        assert!("/path/to/disk".parse::<NbdUri>().is_err());
+
+        assert!(is_nbd_path("nbd:unix:/tmp/nbd.sock"));
+        assert!(!is_nbd_path("/path/to/disk"));
+    }
+}
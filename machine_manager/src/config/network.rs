@@ -10,7 +10,9 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::io::RawFd;
+use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
@@ -18,10 +20,12 @@ use serde::{Deserialize, Serialize};
 use super::{error::ConfigError, pci_args_check};
 use crate::config::get_chardev_socket_path;
 use crate::config::{
-    CmdParser, ConfigCheck, ExBool, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_PATH_LENGTH,
-    MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
+    CmdParser, ConfigCheck, ExBool, ThrottleConfig, ThrottleLimit, VmConfig,
+    DEFAULT_VIRTQUEUE_SIZE, MAX_PATH_LENGTH, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
 };
 use crate::qmp::{qmp_schema, QmpChannel};
+use util::socknet::{SocketNetAddr, SocketNetConfig};
+use util::usernet::{HostFwd, UserNetConfig};
 
 const MAC_ADDRESS_LENGTH: usize = 17;
 
@@ -30,6 +34,22 @@ pub const MAX_QUEUE_SIZE_NET: u16 = 4096;
 /// Max num of virtqueues.
 const MAX_QUEUE_PAIRS: usize = MAX_VIRTIO_QUEUE / 2;
 
+/// Max bandwidth of rate limit in bytes per second.
+const MAX_RATE_BPS: u64 = 1 << 50;
+/// Max packets per second of rate limit.
+const MAX_RATE_PPS: u64 = 100_000_000;
+/// Max seconds that the burst of rate limit can last.
+const MAX_BURST_LENGTH: u64 = 1_000_000;
+
+/// Options of each type of netdev.
+const TAP_NETDEV_OPTS: [&str; 8] = [
+    "fd", "fds", "vhost", "ifname", "vhostfd", "vhostfds", "queues", "chardev",
+];
+const USER_NETDEV_OPTS: [&str; 5] = ["net", "host", "dhcpstart", "dns", "hostfwd"];
+const SOCKET_NETDEV_OPTS: [&str; 4] = ["listen", "connect", "udp", "localaddr"];
+const STREAM_NETDEV_OPTS: [&str; 5] =
+    ["server", "addr.type", "addr.path", "addr.host", "addr.port"];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetDevcfg {
     pub id: String,
@@ -39,6 +59,10 @@ pub struct NetDevcfg {
     pub ifname: String,
     pub queues: u16,
     pub chardev: Option<String>,
+    /// Configuration of the user-mode network backend.
+    pub user: Option<UserNetConfig>,
+    /// Configuration of the socket or stream network backend.
+    pub socket: Option<SocketNetConfig>,
 }
 
 impl Default for NetDevcfg {
@@ -51,6 +75,8 @@ impl Default for NetDevcfg {
             ifname: "".to_string(),
             queues: 2,
             chardev: None,
+            user: None,
+            socket: None,
         }
     }
 }
@@ -87,6 +113,27 @@ impl ConfigCheck for NetDevcfg {
             )));
         }
 
+        if let Some(user) = self.user.as_ref() {
+            if self.queues != 2 {
+                bail!("User netdev only supports one queue pair");
+            }
+            user.check()?;
+        }
+
+        if let Some(socket) = self.socket.as_ref() {
+            if self.queues != 2 {
+                bail!("Socket netdev only supports one queue pair");
+            }
+            if let SocketNetAddr::Unix(path) = &socket.addr {
+                if path.len() > MAX_PATH_LENGTH {
+                    return Err(anyhow!(ConfigError::StringLengthTooLong(
+                        "socket path".to_string(),
+                        MAX_PATH_LENGTH
+                    )));
+                }
+            }
+        }
+
         Ok(())
     }
 }
@@ -108,6 +155,14 @@ pub struct NetworkInterfaceConfig {
     pub socket_path: Option<String>,
     /// All queues of a net device have the same queue size now.
     pub queue_size: u16,
+    /// Configuration of the user-mode network backend.
+    pub user: Option<UserNetConfig>,
+    /// Configuration of the socket or stream network backend.
+    pub socket: Option<SocketNetConfig>,
+    /// Id of the netdev used by this device.
+    pub netdev: String,
+    /// Rate limits of rx and tx.
+    pub rate_limit: NetRateLimit,
 }
 
 impl Default for NetworkInterfaceConfig {
@@ -124,6 +179,10 @@ impl Default for NetworkInterfaceConfig {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            netdev: "".to_string(),
+            rate_limit: NetRateLimit::default(),
         }
     }
 }
@@ -177,6 +236,125 @@ impl ConfigCheck for NetworkInterfaceConfig {
             bail!("queue size of net device should be power of 2!");
         }
 
+        self.rate_limit.check()?;
+        if self.vhost_type.is_some() && self.rate_limit.is_enabled() {
+            bail!("Rate limit is not supported by vhost net device");
+        }
+
+        Ok(())
+    }
+}
+
+/// Rate limits of net device. The rx packets are received by the guest, and the
+/// tx packets are sent by the guest. The bandwidth is in bytes per second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetRateLimit {
+    pub rx_bps: ThrottleLimit,
+    pub tx_bps: ThrottleLimit,
+    pub rx_pps: ThrottleLimit,
+    pub tx_pps: ThrottleLimit,
+}
+
+impl NetRateLimit {
+    /// Names of the limits in command line, in the same order as `limits()`.
+    pub const LIMIT_NAMES: [&'static str; 4] = ["rx-bps", "tx-bps", "rx-pps", "tx-pps"];
+
+    pub fn limits(&self) -> [&ThrottleLimit; 4] {
+        [&self.rx_bps, &self.tx_bps, &self.rx_pps, &self.tx_pps]
+    }
+
+    fn limits_mut(&mut self) -> [&mut ThrottleLimit; 4] {
+        [
+            &mut self.rx_bps,
+            &mut self.tx_bps,
+            &mut self.rx_pps,
+            &mut self.tx_pps,
+        ]
+    }
+
+    /// Whether any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.limits().iter().any(|limit| limit.avg != 0)
+    }
+
+    /// Limits of the throttle, where rx is read and tx is write, and each packet is one IO.
+    pub fn throttle_config(&self) -> ThrottleConfig {
+        ThrottleConfig {
+            bps_read: self.rx_bps,
+            bps_write: self.tx_bps,
+            iops_read: self.rx_pps,
+            iops_write: self.tx_pps,
+            ..Default::default()
+        }
+    }
+
+    fn parse(&mut self, cmd_parser: &CmdParser) -> Result<()> {
+        for (name, limit) in Self::LIMIT_NAMES.iter().zip(self.limits_mut()) {
+            limit.avg = cmd_parser.get_value::<u64>(name)?.unwrap_or(0);
+            limit.max = cmd_parser
+                .get_value::<u64>(&format!("{}-max", name))?
+                .unwrap_or(0);
+            limit.max_length = cmd_parser
+                .get_value::<u64>(&format!("{}-max-length", name))?
+                .unwrap_or(1);
+        }
+        Ok(())
+    }
+
+    fn push_params(cmd_parser: &mut CmdParser) {
+        for name in Self::LIMIT_NAMES.iter() {
+            cmd_parser
+                .push(name)
+                .push(&format!("{}-max", name))
+                .push(&format!("{}-max-length", name));
+        }
+    }
+}
+
+impl ConfigCheck for NetRateLimit {
+    fn check(&self) -> Result<()> {
+        for (name, limit) in Self::LIMIT_NAMES.iter().zip(self.limits()) {
+            let max_value = match name.ends_with("pps") {
+                true => MAX_RATE_PPS,
+                false => MAX_RATE_BPS,
+            };
+            if limit.avg > max_value || limit.max > max_value {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    format!("{} of net device", name),
+                    0,
+                    true,
+                    max_value,
+                    true,
+                )));
+            }
+            if limit.max != 0 && limit.max < limit.avg {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    format!("{}-max", name),
+                    format!("it should be no less than {}", name),
+                )));
+            }
+            if limit.max != 0 && limit.avg == 0 {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    format!("{}-max", name),
+                    format!("{} is required", name),
+                )));
+            }
+            if limit.max_length == 0 || limit.max_length > MAX_BURST_LENGTH {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    format!("{}-max-length of net device", name),
+                    1,
+                    true,
+                    MAX_BURST_LENGTH,
+                    true,
+                )));
+            }
+            if limit.max_length > 1 && limit.max == 0 {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    format!("{}-max-length", name),
+                    format!("{}-max is required", name),
+                )));
+            }
+        }
         Ok(())
     }
 }
@@ -197,6 +375,161 @@ fn parse_fds(cmd_parser: &CmdParser, name: &str) -> Result<Option<Vec<i32>>> {
     }
 }
 
+fn parse_ipv4_addr(cmd_parser: &CmdParser, name: &str) -> Result<Option<Ipv4Addr>> {
+    if let Some(addr) = cmd_parser.get_value::<String>(name)? {
+        let addr = addr
+            .parse::<Ipv4Addr>()
+            .map_err(|_| anyhow!(ConfigError::ConvertValueFailed(addr, name.to_string())))?;
+        Ok(Some(addr))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parse the options of user netdev, the addresses not set are derived from the
+/// network address as the default network `10.0.2.0/24` does.
+fn parse_user_netdev(cmd_parser: &CmdParser) -> Result<UserNetConfig> {
+    let mut user = UserNetConfig::default();
+    if let Some(net) = cmd_parser.get_value::<String>("net")? {
+        let (addr, prefix_len) = match net.split_once('/') {
+            Some((addr, prefix_len)) => (
+                addr,
+                prefix_len.parse::<u8>().map_err(|_| {
+                    anyhow!(ConfigError::ConvertValueFailed(
+                        prefix_len.to_string(),
+                        "net".to_string()
+                    ))
+                })?,
+            ),
+            None => (net.as_str(), 24),
+        };
+        user.net = addr.parse::<Ipv4Addr>().map_err(|_| {
+            anyhow!(ConfigError::ConvertValueFailed(
+                addr.to_string(),
+                "net".to_string()
+            ))
+        })?;
+        user.prefix_len = prefix_len;
+        let base = u32::from(user.net);
+        user.host = Ipv4Addr::from(base.wrapping_add(2));
+        user.dns = Ipv4Addr::from(base.wrapping_add(3));
+        user.dhcp_start = Ipv4Addr::from(base.wrapping_add(15));
+    }
+    if let Some(host) = parse_ipv4_addr(cmd_parser, "host")? {
+        user.host = host;
+    }
+    if let Some(dns) = parse_ipv4_addr(cmd_parser, "dns")? {
+        user.dns = dns;
+    }
+    if let Some(dhcp_start) = parse_ipv4_addr(cmd_parser, "dhcpstart")? {
+        user.dhcp_start = dhcp_start;
+    }
+    // Multiple rules are separated by ';'.
+    if let Some(hostfwd) = cmd_parser.get_value::<String>("hostfwd")? {
+        for rule in hostfwd.split(';') {
+            user.hostfwd.push(HostFwd::from_str(rule)?);
+        }
+    }
+    user.check()?;
+
+    Ok(user)
+}
+
+/// Parse the socket address, the host is optional for the address to listen on.
+fn parse_socket_addr(cmd_parser: &CmdParser, name: &str) -> Result<Option<SocketAddr>> {
+    if let Some(addr) = cmd_parser.get_value::<String>(name)? {
+        let full_addr = if addr.starts_with(':') {
+            format!("0.0.0.0{}", addr)
+        } else {
+            addr.clone()
+        };
+        let addr = full_addr
+            .parse::<SocketAddr>()
+            .map_err(|_| anyhow!(ConfigError::ConvertValueFailed(addr, name.to_string())))?;
+        Ok(Some(addr))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parse the options of socket netdev, which listens or connects with `listen`
+/// and `connect` for the tcp stream, or exchanges datagrams with `udp`.
+fn parse_socket_netdev(cmd_parser: &CmdParser) -> Result<SocketNetConfig> {
+    let listen = parse_socket_addr(cmd_parser, "listen")?;
+    let connect = parse_socket_addr(cmd_parser, "connect")?;
+    let udp = parse_socket_addr(cmd_parser, "udp")?;
+    let localaddr = parse_socket_addr(cmd_parser, "localaddr")?;
+    if localaddr.is_some() && udp.is_none() {
+        bail!("Argument \'localaddr\' is only supported with \'udp\' by socket netdev");
+    }
+
+    let socket = match (listen, connect, udp) {
+        (Some(addr), None, None) => SocketNetConfig {
+            addr: SocketNetAddr::Inet(addr),
+            server: true,
+        },
+        (None, Some(addr), None) => SocketNetConfig {
+            addr: SocketNetAddr::Inet(addr),
+            server: false,
+        },
+        (None, None, Some(remote)) => SocketNetConfig {
+            addr: SocketNetAddr::Udp {
+                local: localaddr
+                    .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("localaddr", "netdev")))?,
+                remote,
+            },
+            server: false,
+        },
+        _ => bail!("Socket netdev needs one of \'listen\', \'connect\' and \'udp\'"),
+    };
+
+    Ok(socket)
+}
+
+/// Parse the options of stream netdev, whose address is given by `addr.type` with
+/// `addr.path` for the unix socket, or `addr.host` and `addr.port` for the tcp one.
+fn parse_stream_netdev(cmd_parser: &CmdParser) -> Result<SocketNetConfig> {
+    let mut server = false;
+    if let Some(value) = cmd_parser.get_value::<ExBool>("server")? {
+        server = value.inner;
+    }
+    let addr_type = cmd_parser
+        .get_value::<String>("addr.type")?
+        .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("addr.type", "netdev")))?;
+    let path = cmd_parser.get_value::<String>("addr.path")?;
+    let host = cmd_parser.get_value::<String>("addr.host")?;
+    let port = cmd_parser.get_value::<u16>("addr.port")?;
+
+    let addr = match addr_type.as_str() {
+        "unix" => {
+            if host.is_some() || port.is_some() {
+                bail!("Unix stream netdev does not support \'addr.host\' and \'addr.port\'");
+            }
+            SocketNetAddr::Unix(
+                path.ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("addr.path", "netdev")))?,
+            )
+        }
+        "inet" => {
+            if path.is_some() {
+                bail!("Inet stream netdev does not support \'addr.path\'");
+            }
+            let host = host.unwrap_or_else(|| "0.0.0.0".to_string());
+            let ip = host.parse::<IpAddr>().map_err(|_| {
+                anyhow!(ConfigError::ConvertValueFailed(
+                    host,
+                    "addr.host".to_string()
+                ))
+            })?;
+            let port =
+                port.ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("addr.port", "netdev")))?;
+            SocketNetAddr::Inet(SocketAddr::new(ip, port))
+        }
+        _ => bail!("Unsupported addr.type {:?} of stream netdev", addr_type),
+    };
+
+    Ok(SocketNetConfig { addr, server })
+}
+
 fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     let mut net = NetDevcfg::default();
     let netdev_type = if let Some(netdev_type) = cmd_parser.get_value::<String>("")? {
@@ -204,7 +537,7 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     } else {
         "".to_string()
     };
-    if netdev_type.ne("tap") && netdev_type.ne("vhost-user") {
+    if !["tap", "vhost-user", "user", "socket", "stream"].contains(&netdev_type.as_str()) {
         bail!("Unsupported netdev type: {:?}", &netdev_type);
     }
     if let Some(net_id) = cmd_parser.get_value::<String>("id")? {
@@ -212,6 +545,36 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     } else {
         return Err(anyhow!(ConfigError::FieldIsMissing("id", "netdev")));
     }
+    // Each type of netdev only accepts its own options, vhost-user shares the tap ones.
+    for (opts_type, opts) in [
+        ("tap", &TAP_NETDEV_OPTS[..]),
+        ("user", &USER_NETDEV_OPTS[..]),
+        ("socket", &SOCKET_NETDEV_OPTS[..]),
+        ("stream", &STREAM_NETDEV_OPTS[..]),
+    ] {
+        if opts_type == netdev_type || (opts_type == "tap" && netdev_type == "vhost-user") {
+            continue;
+        }
+        for opt in opts {
+            if cmd_parser.get_value::<String>(opt)?.is_some() {
+                bail!(
+                    "Argument \'{}\' is not supported by {} netdev",
+                    opt,
+                    netdev_type
+                );
+            }
+        }
+    }
+    match netdev_type.as_str() {
+        "user" => net.user = Some(parse_user_netdev(&cmd_parser)?),
+        "socket" => net.socket = Some(parse_socket_netdev(&cmd_parser)?),
+        "stream" => net.socket = Some(parse_stream_netdev(&cmd_parser)?),
+        _ => {}
+    }
+    if net.user.is_some() || net.socket.is_some() {
+        net.check()?;
+        return Ok(net);
+    }
     if let Some(ifname) = cmd_parser.get_value::<String>("ifname")? {
         net.ifname = ifname;
     }
@@ -297,6 +660,7 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
         .push("mac")
         .push("iothread")
         .push("queue-size");
+    NetRateLimit::push_params(&mut cmd_parser);
 
     cmd_parser.parse(net_config)?;
     pci_args_check(&cmd_parser)?;
@@ -321,14 +685,18 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
     if let Some(queue_size) = cmd_parser.get_value::<u16>("queue-size")? {
         netdevinterfacecfg.queue_size = queue_size;
     }
+    netdevinterfacecfg.rate_limit.parse(&cmd_parser)?;
 
     if let Some(netcfg) = &vm_config.netdevs.remove(&netdev) {
         netdevinterfacecfg.id = netid;
+        netdevinterfacecfg.netdev = netdev.clone();
         netdevinterfacecfg.host_dev_name = netcfg.ifname.clone();
         netdevinterfacecfg.tap_fds = netcfg.tap_fds.clone();
         netdevinterfacecfg.vhost_fds = netcfg.vhost_fds.clone();
         netdevinterfacecfg.vhost_type = netcfg.vhost_type.clone();
         netdevinterfacecfg.queues = netcfg.queues;
+        netdevinterfacecfg.user = netcfg.user.clone();
+        netdevinterfacecfg.socket = netcfg.socket.clone();
         if let Some(chardev) = &netcfg.chardev {
             netdevinterfacecfg.socket_path = Some(get_chardev_socket_path(chardev, vm_config)?);
         }
@@ -389,8 +757,27 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
         ifname: String::new(),
         queues,
         chardev: args.chardev,
+        user: None,
+        socket: None,
     };
 
+    // Get net device type.
+    let netdev_type = args.net_type.unwrap_or_default();
+    if netdev_type.eq("user") {
+        if args.if_name.is_some()
+            || args.fd.is_some()
+            || args.fds.is_some()
+            || args.vhost.is_some()
+            || args.vhostfd.is_some()
+            || args.vhostfds.is_some()
+            || args.queues.is_some()
+        {
+            bail!("user netdev is conflict with ifname/fd/fds/vhost/vhostfd/vhostfds/queues");
+        }
+        config.user = Some(UserNetConfig::default());
+        return Ok(config);
+    }
+
     if let Some(tap_fd) = args.fd {
         if args.if_name.is_some()
             || args.script.is_some()
@@ -430,8 +817,6 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
         config.ifname = if_name;
     }
 
-    // Get net device type.
-    let netdev_type = args.net_type.unwrap_or_default();
     let vhost = args.vhost.unwrap_or_default();
     if vhost {
         if netdev_type.ne("vhost-user") {
@@ -453,6 +838,179 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
     Ok(config)
 }
 
+/// Default snap length of the packets captured by filter-dump.
+const DEFAULT_DUMP_SNAPLEN: u32 = 65535;
+/// Max number of the rotated capture files kept by filter-dump.
+const MAX_DUMP_FILES: u32 = 100;
+
+/// Config structure of object `filter-dump`, which captures the packets of a
+/// netdev to a pcap file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FilterDumpConfig {
+    pub id: String,
+    /// Id of the netdev whose packets are captured.
+    pub netdev: String,
+    pub file: String,
+    /// Max number of bytes saved for each packet.
+    pub maxlen: u32,
+    /// The file is rotated once it exceeds this size in bytes, 0 means no rotation.
+    pub maxsize: u64,
+    /// Max number of the rotated files kept besides the current one.
+    pub maxfiles: u32,
+    /// Capture the packets received by the guest.
+    pub rx: bool,
+    /// Capture the packets sent by the guest.
+    pub tx: bool,
+}
+
+impl Default for FilterDumpConfig {
+    fn default() -> Self {
+        FilterDumpConfig {
+            id: "".to_string(),
+            netdev: "".to_string(),
+            file: "".to_string(),
+            maxlen: DEFAULT_DUMP_SNAPLEN,
+            maxsize: 0,
+            maxfiles: 1,
+            rx: true,
+            tx: true,
+        }
+    }
+}
+
+impl FilterDumpConfig {
+    fn set_queue(&mut self, queue: &str) -> Result<()> {
+        (self.rx, self.tx) = match queue {
+            "all" => (true, true),
+            "rx" => (true, false),
+            "tx" => (false, true),
+            _ => {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "queue".to_string(),
+                    "filter-dump".to_string()
+                )))
+            }
+        };
+        Ok(())
+    }
+}
+
+impl ConfigCheck for FilterDumpConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "id".to_string(),
+                MAX_STRING_LENGTH
+            )));
+        }
+        if self.netdev.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "netdev".to_string(),
+                MAX_STRING_LENGTH
+            )));
+        }
+        if self.file.len() > MAX_PATH_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "filter-dump file".to_string(),
+                MAX_PATH_LENGTH
+            )));
+        }
+        if self.maxlen == 0 || self.maxlen > DEFAULT_DUMP_SNAPLEN {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "maxlen of filter-dump".to_string(),
+                1,
+                true,
+                DEFAULT_DUMP_SNAPLEN as u64,
+                true
+            )));
+        }
+        if self.maxfiles == 0 || self.maxfiles > MAX_DUMP_FILES {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "maxfiles of filter-dump".to_string(),
+                1,
+                true,
+                MAX_DUMP_FILES as u64,
+                true
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_filter_dump(object_args: &str) -> Result<FilterDumpConfig> {
+    let mut cmd_parser = CmdParser::new("filter-dump");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("netdev")
+        .push("file")
+        .push("maxlen")
+        .push("maxsize")
+        .push("maxfiles")
+        .push("queue");
+    cmd_parser.parse(object_args)?;
+
+    let mut config = FilterDumpConfig {
+        id: cmd_parser
+            .get_value::<String>("id")?
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("id", "filter-dump")))?,
+        netdev: cmd_parser
+            .get_value::<String>("netdev")?
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("netdev", "filter-dump")))?,
+        file: cmd_parser
+            .get_value::<String>("file")?
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("file", "filter-dump")))?,
+        ..Default::default()
+    };
+    if let Some(maxlen) = cmd_parser.get_value::<u32>("maxlen")? {
+        config.maxlen = maxlen;
+    }
+    if let Some(maxsize) = cmd_parser.get_value::<u64>("maxsize")? {
+        config.maxsize = maxsize;
+    }
+    if let Some(maxfiles) = cmd_parser.get_value::<u32>("maxfiles")? {
+        config.maxfiles = maxfiles;
+    }
+    if let Some(queue) = cmd_parser.get_value::<String>("queue")? {
+        config.set_queue(&queue)?;
+    }
+
+    config.check()?;
+    Ok(config)
+}
+
+pub fn get_filter_dump_config(args: qmp_schema::object_add) -> Result<FilterDumpConfig> {
+    if args.qom_type != "filter-dump" {
+        bail!("Unsupported object type: {}", args.qom_type);
+    }
+    let mut config = FilterDumpConfig {
+        id: args.id,
+        netdev: args
+            .netdev
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("netdev", "filter-dump")))?,
+        file: args
+            .file
+            .ok_or_else(|| anyhow!(ConfigError::FieldIsMissing("file", "filter-dump")))?,
+        ..Default::default()
+    };
+    if let Some(maxlen) = args.maxlen {
+        config.maxlen = maxlen;
+    }
+    if let Some(maxsize) = args.maxsize {
+        config.maxsize = maxsize;
+    }
+    if let Some(maxfiles) = args.maxfiles {
+        config.maxfiles = maxfiles;
+    }
+    if let Some(queue) = args.queue {
+        config.set_queue(&queue)?;
+    }
+
+    config.check()?;
+    Ok(config)
+}
+
 impl VmConfig {
     pub fn add_netdev(&mut self, netdev_config: &str) -> Result<()> {
         let mut cmd_parser = CmdParser::new("netdev");
@@ -466,7 +1024,21 @@ impl VmConfig {
             .push("vhostfd")
             .push("vhostfds")
             .push("queues")
-            .push("chardev");
+            .push("chardev")
+            .push("net")
+            .push("host")
+            .push("dhcpstart")
+            .push("dns")
+            .push("hostfwd")
+            .push("listen")
+            .push("connect")
+            .push("udp")
+            .push("localaddr")
+            .push("server")
+            .push("addr.type")
+            .push("addr.path")
+            .push("addr.host")
+            .push("addr.port");
 
         cmd_parser.parse(netdev_config)?;
         let drive_cfg = parse_netdev(cmd_parser)?;
@@ -735,6 +1307,255 @@ mod tests {
         assert!(net_cfg_res.is_err());
     }
 
+    #[test]
+    fn test_user_netdev_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_netdev("user,id=user0").is_ok());
+        let net_cfg_res = parse_net(&mut vm_config, "virtio-net-device,id=net0,netdev=user0");
+        assert!(net_cfg_res.is_ok());
+        let network_configs = net_cfg_res.unwrap();
+        assert!(network_configs.vhost_type.is_none());
+        assert!(network_configs.tap_fds.is_none());
+        assert_eq!(network_configs.user, Some(UserNetConfig::default()));
+
+        // Addresses are derived from the network address if not set.
+        assert!(vm_config
+            .add_netdev(
+                "user,id=user1,net=192.168.76.0/24,dns=192.168.76.53,\
+                 hostfwd=tcp::2222-:22;udp:127.0.0.1:5353-192.168.76.9:53"
+            )
+            .is_ok());
+        let user = vm_config
+            .netdevs
+            .get("user1")
+            .unwrap()
+            .user
+            .clone()
+            .unwrap();
+        assert_eq!(user.net, Ipv4Addr::new(192, 168, 76, 0));
+        assert_eq!(user.prefix_len, 24);
+        assert_eq!(user.host, Ipv4Addr::new(192, 168, 76, 2));
+        assert_eq!(user.dhcp_start, Ipv4Addr::new(192, 168, 76, 15));
+        assert_eq!(user.dns, Ipv4Addr::new(192, 168, 76, 53));
+        assert_eq!(user.hostfwd.len(), 2);
+        assert!(!user.hostfwd[0].udp);
+        assert_eq!(user.hostfwd[0].host_port, 2222);
+        assert!(user.hostfwd[1].udp);
+        assert_eq!(user.hostfwd[1].guest_addr, Ipv4Addr::new(192, 168, 76, 9));
+
+        // Tap options are not supported by user netdev, and vice versa.
+        assert!(vm_config.add_netdev("user,id=user2,ifname=tap0").is_err());
+        assert!(vm_config.add_netdev("user,id=user2,queues=2").is_err());
+        assert!(vm_config.add_netdev("user,id=user2,vhost=on").is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=tap0,ifname=tap0,net=10.0.2.0")
+            .is_err());
+
+        // Invalid addresses.
+        assert!(vm_config
+            .add_netdev("user,id=user2,net=10.0.2.0/33")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("user,id=user2,net=10.0.2.1/24")
+            .is_err());
+        assert!(vm_config.add_netdev("user,id=user2,host=10.0.3.2").is_err());
+        assert!(vm_config.add_netdev("user,id=user2,dns=10.0.2.2").is_err());
+        assert!(vm_config
+            .add_netdev("user,id=user2,dhcpstart=10.0.2.x")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("user,id=user2,hostfwd=tcp::2222-10.0.3.15:22")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("user,id=user2,hostfwd=tcp::2222")
+            .is_err());
+    }
+
+    #[test]
+    fn test_socket_netdev_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("stream,id=stream0,server=on,addr.type=unix,addr.path=/tmp/net0.sock")
+            .is_ok());
+        let net_cfg_res = parse_net(&mut vm_config, "virtio-net-device,id=net0,netdev=stream0");
+        assert!(net_cfg_res.is_ok());
+        let network_configs = net_cfg_res.unwrap();
+        assert!(network_configs.vhost_type.is_none());
+        assert!(network_configs.user.is_none());
+        assert_eq!(
+            network_configs.socket,
+            Some(SocketNetConfig {
+                addr: SocketNetAddr::Unix("/tmp/net0.sock".to_string()),
+                server: true,
+            })
+        );
+
+        let socket_cfg = |vm_config: &VmConfig, id: &str| {
+            vm_config.netdevs.get(id).unwrap().socket.clone().unwrap()
+        };
+        assert!(vm_config
+            .add_netdev("stream,id=stream1,addr.type=inet,addr.host=127.0.0.1,addr.port=5555")
+            .is_ok());
+        let socket = socket_cfg(&vm_config, "stream1");
+        assert_eq!(
+            socket.addr,
+            SocketNetAddr::Inet("127.0.0.1:5555".parse().unwrap())
+        );
+        assert!(!socket.server);
+
+        assert!(vm_config
+            .add_netdev("socket,id=socket0,listen=:5555")
+            .is_ok());
+        let socket = socket_cfg(&vm_config, "socket0");
+        assert_eq!(
+            socket.addr,
+            SocketNetAddr::Inet("0.0.0.0:5555".parse().unwrap())
+        );
+        assert!(socket.server);
+        assert!(vm_config
+            .add_netdev("socket,id=socket1,udp=127.0.0.1:5556,localaddr=127.0.0.1:5555")
+            .is_ok());
+        assert_eq!(
+            socket_cfg(&vm_config, "socket1").addr,
+            SocketNetAddr::Udp {
+                local: "127.0.0.1:5555".parse().unwrap(),
+                remote: "127.0.0.1:5556".parse().unwrap(),
+            }
+        );
+
+        // Options of other types of netdev are not supported.
+        assert!(vm_config
+            .add_netdev("socket,id=socket2,connect=127.0.0.1:5555,queues=2")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("socket,id=socket2,connect=127.0.0.1:5555,addr.type=unix")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=tap0,ifname=tap0,listen=:5555")
+            .is_err());
+
+        // Invalid addresses.
+        assert!(vm_config.add_netdev("socket,id=socket2").is_err());
+        assert!(vm_config
+            .add_netdev("socket,id=socket2,listen=:5555,connect=127.0.0.1:5555")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("socket,id=socket2,udp=127.0.0.1:5556")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("socket,id=socket2,connect=127.0.0.1:5555,localaddr=127.0.0.1:5556")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("socket,id=socket2,connect=localhost")
+            .is_err());
+        assert!(vm_config.add_netdev("stream,id=stream2").is_err());
+        assert!(vm_config
+            .add_netdev("stream,id=stream2,addr.type=unix")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("stream,id=stream2,addr.type=unix,addr.path=/tmp/a.sock,addr.port=5555")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("stream,id=stream2,addr.type=inet,addr.host=127.0.0.1")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("stream,id=stream2,addr.type=vsock,addr.path=/tmp/a.sock")
+            .is_err());
+    }
+
+    #[test]
+    fn test_net_rate_limit_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_netdev("tap,id=eth0,ifname=tap0").is_ok());
+        let net_cfg = parse_net(
+            &mut vm_config,
+            "virtio-net-pci,id=net0,netdev=eth0,bus=pcie.0,addr=0x2,rx-bps=1048576,tx-pps=1000,tx-pps-max=5000,tx-pps-max-length=10",
+        )
+        .unwrap();
+        assert!(net_cfg.rate_limit.is_enabled());
+        assert_eq!(net_cfg.rate_limit.rx_bps.avg, 1048576);
+        assert_eq!(net_cfg.rate_limit.rx_bps.max, 0);
+        assert_eq!(net_cfg.rate_limit.rx_bps.max_length, 1);
+        assert_eq!(net_cfg.rate_limit.tx_pps.avg, 1000);
+        assert_eq!(net_cfg.rate_limit.tx_pps.max, 5000);
+        assert_eq!(net_cfg.rate_limit.tx_pps.max_length, 10);
+        let throttle = net_cfg.rate_limit.throttle_config();
+        assert_eq!(throttle.bps_read.avg, 1048576);
+        assert_eq!(throttle.iops_write.max, 5000);
+        assert_eq!(throttle.bps_total.avg, 0);
+
+        // The burst limit needs the average limit, and it can not be less than it.
+        let invalid_args = [
+            "rx-bps-max=2048",
+            "rx-bps=2048,rx-bps-max=1024",
+            "rx-pps=10,rx-pps-max-length=10",
+            "tx-pps=200000000",
+        ];
+        for args in invalid_args.iter() {
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config.add_netdev("tap,id=eth0,ifname=tap0").is_ok());
+            let net_cfg = format!(
+                "virtio-net-pci,id=net0,netdev=eth0,bus=pcie.0,addr=0x2,{}",
+                args
+            );
+            assert!(parse_net(&mut vm_config, &net_cfg).is_err());
+        }
+
+        // Rate limit is not supported by vhost.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("tap,id=eth0,ifname=tap0,vhost=on")
+            .is_ok());
+        assert!(parse_net(
+            &mut vm_config,
+            "virtio-net-pci,id=net0,netdev=eth0,bus=pcie.0,addr=0x2,tx-bps=1048576",
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_filter_dump_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_object("filter-dump,id=dump0,netdev=eth0,file=/tmp/eth0.pcap")
+            .is_ok());
+        let dump_cfg = vm_config.object.filter_dump.get("dump0").unwrap();
+        assert_eq!(dump_cfg.netdev, "eth0");
+        assert_eq!(dump_cfg.file, "/tmp/eth0.pcap");
+        assert_eq!(dump_cfg.maxlen, 65535);
+        assert_eq!(dump_cfg.maxsize, 0);
+        assert_eq!(dump_cfg.maxfiles, 1);
+        assert!(dump_cfg.rx && dump_cfg.tx);
+        // The id of object is unique.
+        assert!(vm_config
+            .add_object("filter-dump,id=dump0,netdev=eth1,file=/tmp/eth1.pcap")
+            .is_err());
+
+        let dump_cfg = parse_filter_dump(
+            "filter-dump,id=dump1,netdev=eth0,file=/tmp/eth0.pcap,maxlen=128,maxsize=1048576,maxfiles=4,queue=tx",
+        )
+        .unwrap();
+        assert_eq!(dump_cfg.maxlen, 128);
+        assert_eq!(dump_cfg.maxsize, 1048576);
+        assert_eq!(dump_cfg.maxfiles, 4);
+        assert!(!dump_cfg.rx && dump_cfg.tx);
+
+        assert!(parse_filter_dump("filter-dump,id=dump1,file=/tmp/eth0.pcap").is_err());
+        assert!(parse_filter_dump("filter-dump,id=dump1,netdev=eth0").is_err());
+        assert!(
+            parse_filter_dump("filter-dump,id=dump1,netdev=eth0,file=/tmp/a.pcap,maxlen=0")
+                .is_err()
+        );
+        assert!(parse_filter_dump(
+            "filter-dump,id=dump1,netdev=eth0,file=/tmp/a.pcap,maxfiles=101"
+        )
+        .is_err());
+        assert!(
+            parse_filter_dump("filter-dump,id=dump1,netdev=eth0,file=/tmp/a.pcap,queue=ctrl")
+                .is_err()
+        );
+    }
+
     #[test]
     fn test_netdev_config_check() {
         let mut netdev_conf = NetDevcfg::default();
@@ -852,6 +1673,22 @@ mod tests {
         assert_eq!(net_cfg.id, "netdev");
         assert_eq!(net_cfg.ifname, "tap0");
 
+        // Normal test with user netdev.
+        let netdev = Box::new(qmp_schema::NetDevAddArgument {
+            id: "netdev".to_string(),
+            net_type: Some("user".to_string()),
+            ..qmp_schema::NetDevAddArgument::default()
+        });
+        let net_cfg = get_netdev_config(netdev).unwrap();
+        assert_eq!(net_cfg.user, Some(UserNetConfig::default()));
+        let netdev = Box::new(qmp_schema::NetDevAddArgument {
+            id: "netdev".to_string(),
+            net_type: Some("user".to_string()),
+            if_name: Some("tap0".to_string()),
+            ..qmp_schema::NetDevAddArgument::default()
+        });
+        assert!(get_netdev_config(netdev).is_err());
+
         // Set fd_name and fd_value to qmp channel.
         for i in 0..5 {
             let fd_name = "fd-net0".to_string() + &i.to_string();
@@ -0,0 +1,297 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, bail, Result};
+
+use super::{error::ConfigError, pci_args_check};
+use crate::config::{CmdParser, ConfigCheck, DiskFormat, VmConfig, MAX_STRING_LENGTH};
+use util::aio::AioEngine;
+
+/// Default number of IO queue pairs of nvme controller.
+pub const DEFAULT_NVME_QUEUES: u16 = 64;
+/// Max number of IO queue pairs of nvme controller.
+pub const MAX_NVME_QUEUES: u16 = 64;
+/// Max namespace id of nvme controller.
+pub const MAX_NVME_NAMESPACES: u32 = 256;
+/// Length of serial number in the identify controller data.
+const MAX_NVME_SERIAL_LENGTH: usize = 20;
+
+/// Config of the nvme controller.
+#[derive(Debug, Clone)]
+pub struct NvmeConfig {
+    /// Id of the nvme controller.
+    pub id: String,
+    /// Serial number reported to guest.
+    pub serial: String,
+    /// Number of IO queue pairs.
+    pub num_queues: u16,
+    /// Namespace 1 given by the `drive` property of the controller.
+    pub namespace: Option<NvmeNsConfig>,
+}
+
+impl ConfigCheck for NvmeConfig {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "nvme id".to_string(),
+                MAX_STRING_LENGTH
+            )));
+        }
+        if self.serial.len() > MAX_NVME_SERIAL_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "nvme serial".to_string(),
+                MAX_NVME_SERIAL_LENGTH
+            )));
+        }
+        if self.num_queues == 0 || self.num_queues > MAX_NVME_QUEUES {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "num-queues of nvme".to_string(),
+                1,
+                true,
+                MAX_NVME_QUEUES as u64,
+                true,
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Config of the namespace of nvme controller.
+#[derive(Debug, Clone)]
+pub struct NvmeNsConfig {
+    /// Id of the namespace device.
+    pub id: String,
+    /// Id of the nvme controller which the namespace attaches to.
+    pub bus: String,
+    /// Namespace id, the first free one is used if not specified.
+    pub nsid: Option<u32>,
+    /// The image file path.
+    pub path_on_host: String,
+    /// Namespace can not do write operation.
+    pub read_only: bool,
+    /// If true, use direct access io.
+    pub direct: bool,
+    /// Format of the image file.
+    pub format: DiskFormat,
+    /// Async IO type.
+    pub aio: AioEngine,
+}
+
+impl Default for NvmeNsConfig {
+    fn default() -> Self {
+        NvmeNsConfig {
+            id: "".to_string(),
+            bus: "".to_string(),
+            nsid: None,
+            path_on_host: "".to_string(),
+            read_only: false,
+            direct: true,
+            format: DiskFormat::Raw,
+            aio: AioEngine::Native,
+        }
+    }
+}
+
+impl ConfigCheck for NvmeNsConfig {
+    fn check(&self) -> Result<()> {
+        if let Some(nsid) = self.nsid {
+            if nsid == 0 || nsid > MAX_NVME_NAMESPACES {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    "nsid of nvme-ns".to_string(),
+                    1,
+                    true,
+                    MAX_NVME_NAMESPACES as u64,
+                    true,
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fill the namespace config with the properties of drive `drive`.
+fn fetch_nvme_drive(
+    vm_config: &mut VmConfig,
+    drive: &str,
+    ns_cfg: &mut NvmeNsConfig,
+) -> Result<()> {
+    if let Some(drive_arg) = vm_config.drives.get(drive) {
+        drive_arg.check_no_error_policy("nvme namespace")?;
+    }
+    if let Some(drive_arg) = &vm_config.drives.remove(drive) {
+        ns_cfg.path_on_host = drive_arg.path_on_host.clone();
+        ns_cfg.read_only = drive_arg.read_only;
+        ns_cfg.direct = drive_arg.direct;
+        ns_cfg.aio = drive_arg.aio;
+        ns_cfg.format = drive_arg.format;
+        Ok(())
+    } else {
+        bail!("No drive configured matched for nvme namespace");
+    }
+}
+
+pub fn parse_nvme(vm_config: &mut VmConfig, conf: &str) -> Result<NvmeConfig> {
+    let mut cmd_parser = CmdParser::new("nvme");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("addr")
+        .push("multifunction")
+        .push("serial")
+        .push("num-queues")
+        .push("drive");
+    cmd_parser.parse(conf)?;
+    pci_args_check(&cmd_parser)?;
+
+    let id = if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        id
+    } else {
+        return Err(anyhow!(ConfigError::FieldIsMissing("id", "nvme")));
+    };
+    let serial = cmd_parser
+        .get_value::<String>("serial")?
+        .unwrap_or_else(|| id.clone());
+    let num_queues = cmd_parser
+        .get_value::<u16>("num-queues")?
+        .unwrap_or(DEFAULT_NVME_QUEUES);
+
+    let namespace = if let Some(drive) = cmd_parser.get_value::<String>("drive")? {
+        let mut ns_cfg = NvmeNsConfig {
+            id: drive.clone(),
+            bus: id.clone(),
+            nsid: Some(1),
+            ..Default::default()
+        };
+        fetch_nvme_drive(vm_config, &drive, &mut ns_cfg)?;
+        Some(ns_cfg)
+    } else {
+        None
+    };
+
+    let nvme_cfg = NvmeConfig {
+        id,
+        serial,
+        num_queues,
+        namespace,
+    };
+    nvme_cfg.check()?;
+    Ok(nvme_cfg)
+}
+
+pub fn parse_nvme_ns(vm_config: &mut VmConfig, conf: &str) -> Result<NvmeNsConfig> {
+    let mut cmd_parser = CmdParser::new("nvme-ns");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("bus")
+        .push("nsid")
+        .push("drive");
+    cmd_parser.parse(conf)?;
+
+    let mut ns_cfg = NvmeNsConfig::default();
+    if let Some(id) = cmd_parser.get_value::<String>("id")? {
+        ns_cfg.id = id;
+    } else {
+        return Err(anyhow!(ConfigError::FieldIsMissing("id", "nvme-ns")));
+    }
+    if let Some(bus) = cmd_parser.get_value::<String>("bus")? {
+        ns_cfg.bus = bus;
+    } else {
+        return Err(anyhow!(ConfigError::FieldIsMissing("bus", "nvme-ns")));
+    }
+    ns_cfg.nsid = cmd_parser.get_value::<u32>("nsid")?;
+
+    let drive = if let Some(drive) = cmd_parser.get_value::<String>("drive")? {
+        drive
+    } else {
+        return Err(anyhow!(ConfigError::FieldIsMissing("drive", "nvme-ns")));
+    };
+    fetch_nvme_drive(vm_config, &drive, &mut ns_cfg)?;
+
+    ns_cfg.check()?;
+    Ok(ns_cfg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nvme_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=nvme0,file=/path/to/nvme0,format=qcow2,direct=off,aio=off")
+            .is_ok());
+        let nvme_cfg = parse_nvme(
+            &mut vm_config,
+            "nvme,id=nvme-ctrl,bus=pcie.0,addr=0x5,drive=nvme0,num-queues=8",
+        )
+        .unwrap();
+        assert_eq!(nvme_cfg.id, "nvme-ctrl");
+        assert_eq!(nvme_cfg.serial, "nvme-ctrl");
+        assert_eq!(nvme_cfg.num_queues, 8);
+        let ns_cfg = nvme_cfg.namespace.unwrap();
+        assert_eq!(ns_cfg.nsid, Some(1));
+        assert_eq!(ns_cfg.bus, "nvme-ctrl");
+        assert_eq!(ns_cfg.path_on_host, "/path/to/nvme0");
+        assert_eq!(ns_cfg.format, DiskFormat::Qcow2);
+        assert_eq!(ns_cfg.aio, AioEngine::Off);
+
+        let nvme_cfg = parse_nvme(&mut vm_config, "nvme,id=nvme-ctrl,serial=abc").unwrap();
+        assert_eq!(nvme_cfg.serial, "abc");
+        assert_eq!(nvme_cfg.num_queues, DEFAULT_NVME_QUEUES);
+        assert!(nvme_cfg.namespace.is_none());
+
+        assert!(parse_nvme(&mut vm_config, "nvme,id=nvme-ctrl,num-queues=0").is_err());
+        assert!(parse_nvme(&mut vm_config, "nvme,id=nvme-ctrl,num-queues=65").is_err());
+        assert!(parse_nvme(&mut vm_config, "nvme,serial=abc").is_err());
+        // The drive has been taken by the first controller.
+        assert!(parse_nvme(&mut vm_config, "nvme,id=nvme-ctrl,drive=nvme0").is_err());
+
+        // Error policy is only supported by virtio-blk.
+        assert!(vm_config
+            .add_drive("id=nvme1,file=/path/to/nvme1,werror=stop")
+            .is_ok());
+        assert!(parse_nvme(&mut vm_config, "nvme,id=nvme-ctrl,drive=nvme1").is_err());
+        assert!(vm_config.drives.contains_key("nvme1"));
+    }
+
+    #[test]
+    fn test_nvme_ns_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=drv1,file=/path/to/drv1,readonly=on")
+            .is_ok());
+        assert!(vm_config.add_drive("id=drv2,file=/path/to/drv2").is_ok());
+        let ns_cfg =
+            parse_nvme_ns(&mut vm_config, "nvme-ns,id=ns1,bus=nvme-ctrl,drive=drv1").unwrap();
+        assert_eq!(ns_cfg.bus, "nvme-ctrl");
+        assert_eq!(ns_cfg.nsid, None);
+        assert!(ns_cfg.read_only);
+
+        assert!(parse_nvme_ns(&mut vm_config, "nvme-ns,id=ns2,bus=nvme-ctrl,drive=drv1").is_err());
+        assert!(parse_nvme_ns(&mut vm_config, "nvme-ns,id=ns2,drive=drv2").is_err());
+        assert!(parse_nvme_ns(
+            &mut vm_config,
+            "nvme-ns,id=ns2,bus=nvme-ctrl,nsid=0,drive=drv2"
+        )
+        .is_err());
+        assert!(vm_config.add_drive("id=drv2,file=/path/to/drv2").is_ok());
+        let ns_cfg = parse_nvme_ns(
+            &mut vm_config,
+            "nvme-ns,id=ns2,bus=nvme-ctrl,nsid=3,drive=drv2",
+        )
+        .unwrap();
+        assert_eq!(ns_cfg.nsid, Some(3));
+    }
+}
@@ -10,11 +10,13 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check};
 use crate::config::{
-    CmdParser, ConfigCheck, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
+    CmdParser, ConfigCheck, DiskFormat, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_SOCK_PATH_LENGTH,
+    MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
 };
 use util::aio::AioEngine;
 
@@ -167,6 +169,8 @@ pub struct ScsiDevConfig {
     pub read_only: bool,
     /// If true, use direct access io.
     pub direct: bool,
+    /// Format of the image file.
+    pub format: DiskFormat,
     /// Async IO type.
     pub aio_type: AioEngine,
     /// Boot order.
@@ -175,6 +179,11 @@ pub struct ScsiDevConfig {
     pub channel: u8,
     pub target: u8,
     pub lun: u16,
+    /// Socket path of the helper doing persistent reservations for the device.
+    pub pr_manager: Option<String>,
+    /// Name of the initiator emulating persistent reservations, which is
+    /// unique among the VMs sharing the image.
+    pub pr_initiator: Option<String>,
 }
 
 impl Default for ScsiDevConfig {
@@ -186,11 +195,14 @@ impl Default for ScsiDevConfig {
             bus: "".to_string(),
             read_only: false,
             direct: true,
+            format: DiskFormat::Raw,
             aio_type: AioEngine::Native,
             boot_index: None,
             channel: 0,
             target: 0,
             lun: 0,
+            pr_manager: None,
+            pr_initiator: None,
         }
     }
 }
@@ -205,7 +217,9 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         .push("lun")
         .push("serial")
         .push("bootindex")
-        .push("drive");
+        .push("drive")
+        .push("pr-manager")
+        .push("pr-initiator");
 
     cmd_parser.parse(drive_config)?;
 
@@ -266,12 +280,81 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         scsi_dev_cfg.lun = lun;
     }
 
+    if let Some(pr_manager) = cmd_parser.get_value::<String>("pr-manager")? {
+        let pr_obj = vm_config
+            .object
+            .pr_manager_object
+            .get(&pr_manager)
+            .with_context(|| format!("Object for pr-manager {} is not found", pr_manager))?;
+        scsi_dev_cfg.pr_manager = Some(pr_obj.path.clone());
+    }
+
+    if let Some(initiator) = cmd_parser.get_value::<String>("pr-initiator")? {
+        if initiator.is_empty() {
+            bail!("pr-initiator of scsi device can not be empty");
+        }
+        if initiator.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "pr-initiator of scsi device".to_string(),
+                MAX_STRING_LENGTH,
+            )));
+        }
+        if scsi_dev_cfg.pr_manager.is_some() {
+            bail!("pr-initiator and pr-manager of scsi device can not be used together");
+        }
+        scsi_dev_cfg.pr_initiator = Some(initiator);
+    }
+
+    if let Some(drive_arg) = vm_config.drives.get(&scsi_drive) {
+        drive_arg.check_no_error_policy("scsi device")?;
+    }
     if let Some(drive_arg) = &vm_config.drives.remove(&scsi_drive) {
         scsi_dev_cfg.path_on_host = drive_arg.path_on_host.clone();
         scsi_dev_cfg.read_only = drive_arg.read_only;
         scsi_dev_cfg.direct = drive_arg.direct;
         scsi_dev_cfg.aio_type = drive_arg.aio;
+        scsi_dev_cfg.format = drive_arg.format;
     }
 
     Ok(scsi_dev_cfg)
 }
+
+/// Config structure of object `pr-manager-helper`, the helper process doing
+/// persistent reservations on host devices for scsi devices.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrManagerObjConfig {
+    pub id: String,
+    /// Path of the unix socket the helper listens on.
+    pub path: String,
+}
+
+pub fn parse_pr_manager_obj(object_args: &str) -> Result<PrManagerObjConfig> {
+    let mut cmd_params = CmdParser::new("pr-manager-helper");
+    cmd_params.push("").push("id").push("path");
+
+    cmd_params.parse(object_args)?;
+    let id = if let Some(obj_id) = cmd_params.get_value::<String>("id")? {
+        obj_id
+    } else {
+        return Err(anyhow!(ConfigError::FieldIsMissing(
+            "id",
+            "pr-manager-helper"
+        )));
+    };
+    let path = if let Some(path) = cmd_params.get_value::<String>("path")? {
+        path
+    } else {
+        return Err(anyhow!(ConfigError::FieldIsMissing(
+            "path",
+            "pr-manager-helper"
+        )));
+    };
+    if path.len() > MAX_SOCK_PATH_LENGTH {
+        return Err(anyhow!(ConfigError::StringLengthTooLong(
+            "pr-manager-helper path".to_string(),
+            MAX_SOCK_PATH_LENGTH,
+        )));
+    }
+
+    Ok(PrManagerObjConfig { id, path })
+}
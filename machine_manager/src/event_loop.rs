@@ -50,7 +50,13 @@ impl EventLoop {
         let mut io_threads = HashMap::new();
         if let Some(thrs) = iothreads {
             for thr in thrs {
-                io_threads.insert(thr.id.clone(), EventLoopContext::new());
+                let mut ctx = EventLoopContext::new();
+                ctx.set_poll_params(
+                    u64::from(thr.poll_max_ns),
+                    u64::from(thr.poll_grow),
+                    u64::from(thr.poll_shrink),
+                );
+                io_threads.insert(thr.id.clone(), ctx);
             }
         }
 
@@ -66,11 +72,12 @@ impl EventLoop {
                 if let Some(event_loop) = GLOBAL_EVENT_LOOP.as_mut() {
                     for (id, ctx) in &mut event_loop.io_threads {
                         thread::Builder::new().name(id.to_string()).spawn(move || {
+                            let (max, grow, shrink) = ctx.poll_params();
                             let iothread_info = IothreadInfo {
-                                shrink: 0,
+                                shrink: shrink as u32,
                                 pid: process::id(),
-                                grow: 0,
-                                max: 0,
+                                grow: grow as u32,
+                                max: max as u32,
                                 id: id.to_string(),
                             };
                             IOTHREADS.lock().unwrap().push(iothread_info);
@@ -11,16 +11,21 @@
 // See the Mulan PSL v2 for more details.
 
 use std::os::unix::io::RawFd;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use log::error;
 use once_cell::sync::Lazy;
 use strum::VariantNames;
+use vmm_sys_util::eventfd::EventFd;
 
 use crate::config::ShutdownAction;
 use crate::qmp::qmp_schema::{
-    BlockDevAddArgument, CharDevAddArgument, ChardevInfo, Cmd, CmdLine, DeviceAddArgument,
-    DeviceProps, Events, GicCap, IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities,
-    NetDevAddArgument, PropList, QmpCommand, QmpEvent, Target, TypeLists, UpdateRegionArgument,
+    block_set_io_throttle, blockdev_backup, blockdev_mirror, drive_backup, drive_mirror,
+    nbd_server_add, nbd_server_start, net_set_rate_limit, object_add, BlockDevAddArgument,
+    BlockDeviceInfo, BlockInfo, BlockJobInfo, BlockStats, CharDevAddArgument, ChardevInfo, Cmd,
+    CmdLine, DeviceAddArgument, DeviceProps, Events, GicCap, IothreadInfo, KvmInfo, MachineInfo,
+    MigrateCapabilities, NetDevAddArgument, PropList, QmpCommand, QmpErrorClass, QmpEvent, Target,
+    TypeLists, UpdateRegionArgument,
 };
 use crate::qmp::{Response, Version};
 
@@ -169,6 +174,276 @@ pub trait DeviceInterface {
     /// Delete a block device.
     fn blockdev_del(&self, node_name: String) -> Response;
 
+    /// Take an external snapshot of the block device, the device switches to
+    /// the qcow2 overlay at `snapshot_file`.
+    fn blockdev_snapshot_sync(
+        &mut self,
+        _device: String,
+        _snapshot_file: String,
+        _format: Option<String>,
+        _mode: Option<String>,
+    ) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("External snapshot is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Take an internal snapshot of the block device.
+    fn blockdev_snapshot_internal_sync(&self, _device: String, _name: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Internal snapshot is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Delete an internal snapshot of the block device.
+    fn blockdev_snapshot_delete_internal_sync(
+        &self,
+        _device: String,
+        _id: Option<String>,
+        _name: Option<String>,
+    ) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Internal snapshot is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Eject the medium of the removable device.
+    fn eject(&self, _device: String, _force: Option<bool>) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Eject is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Replace the medium of the removable device with a new image file.
+    fn blockdev_change_medium(
+        &self,
+        _device: String,
+        _filename: String,
+        _format: Option<String>,
+        _force: Option<bool>,
+    ) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Blockdev change medium is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Start a job to commit the active image of the block device to its
+    /// backing file.
+    fn block_commit(
+        &self,
+        _device: String,
+        _job_id: Option<String>,
+        _base: Option<String>,
+        _top: Option<String>,
+        _speed: Option<u64>,
+    ) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block commit is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Start a job to copy the data of backing chain into the image of the block
+    /// device.
+    fn block_stream(
+        &self,
+        _device: String,
+        _job_id: Option<String>,
+        _speed: Option<u64>,
+    ) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block stream is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Start a job to mirror the image of the block device to a new image file.
+    fn drive_mirror(&self, _args: drive_mirror) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Drive mirror is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Start a job to mirror the image of the block device to a block backend.
+    fn blockdev_mirror(&self, _args: blockdev_mirror) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Blockdev mirror is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Start a job to back up the data of the block device to an image file.
+    fn drive_backup(&self, _args: drive_backup) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Drive backup is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Start a job to back up the data of the block device to a block backend.
+    fn blockdev_backup(&self, _args: blockdev_backup) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Blockdev backup is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Add a dirty bitmap to the block device.
+    fn block_dirty_bitmap_add(
+        &self,
+        _node: String,
+        _name: String,
+        _granularity: Option<u64>,
+        _persistent: Option<bool>,
+    ) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Dirty bitmap is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Remove the dirty bitmap from the block device.
+    fn block_dirty_bitmap_remove(&self, _node: String, _name: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Dirty bitmap is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Clear the dirty bitmap of the block device.
+    fn block_dirty_bitmap_clear(&self, _node: String, _name: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Dirty bitmap is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Start the NBD server.
+    fn nbd_server_start(&self, _args: nbd_server_start) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Nbd server is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Export the block device by the NBD server.
+    fn nbd_server_add(&self, _args: nbd_server_add) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Nbd server is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Remove the export of the NBD server.
+    fn nbd_server_remove(&self, _name: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Nbd server is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Stop the NBD server.
+    fn nbd_server_stop(&self) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Nbd server is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Change the rate limits of the net device.
+    fn net_set_rate_limit(&self, _args: net_set_rate_limit) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Net rate limit is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Add an object.
+    fn object_add(&mut self, _args: object_add) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("object-add is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Remove the object.
+    fn object_del(&mut self, _id: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("object-del is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Set the link status of the net device.
+    fn set_link(&self, _name: String, _up: bool) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("set_link is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Cancel the block job.
+    fn block_job_cancel(&self, _device: String, _force: Option<bool>) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block job is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Complete the block job which is ready.
+    fn block_job_complete(&self, _device: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block job is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Pause the block job.
+    fn block_job_pause(&self, _device: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block job is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Resume the paused block job.
+    fn block_job_resume(&self, _device: String) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block job is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Set the max speed of the block job.
+    fn block_job_set_speed(&self, _device: String, _speed: u64) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block job is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Change the IO limits of the block device.
+    fn block_set_io_throttle(&self, _args: block_set_io_throttle) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block IO throttling is not supported".to_string()),
+            None,
+        )
+    }
+
+    /// Grow the disk of the block device.
+    fn block_resize(&self, _device: String, _size: u64) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("Block resize is not supported".to_string()),
+            None,
+        )
+    }
+
     /// Create a new network device.
     fn netdev_add(&mut self, args: Box<NetDevAddArgument>) -> Response;
 
@@ -396,23 +671,22 @@ pub trait DeviceInterface {
     }
 
     fn query_block(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_block: Vec<BlockInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_block).unwrap(), None)
     }
 
     fn query_named_block_nodes(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_node: Vec<BlockDeviceInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_node).unwrap(), None)
     }
 
     fn query_blockstats(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_stats: Vec<BlockStats> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_stats).unwrap(), None)
     }
 
     fn query_block_jobs(&self) -> Response {
-        // Fix me: qmp command call, return none temporarily.
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
+        let vec_cmd: Vec<BlockJobInfo> = Vec::new();
         Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
     }
 
@@ -470,3 +744,26 @@ pub trait MachineTestInterface: MachineAddressInterface {}
 
 pub static PTY_PATH: Lazy<Mutex<Vec<PathInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
 pub static IOTHREADS: Lazy<Mutex<Vec<IothreadInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Eventfd of the request to stop VM from devices, e.g. on block IO error. The
+/// request is handled by machine in the main loop.
+static VM_STOP_REQ: Lazy<Mutex<Option<Arc<EventFd>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Set the eventfd which machine listens to for the stop request of devices.
+pub fn register_vm_stop_req(stop_req: Arc<EventFd>) {
+    *VM_STOP_REQ.lock().unwrap() = Some(stop_req);
+}
+
+/// Request the machine to stop VM, false if the machine does not support it.
+pub fn request_vm_stop() -> bool {
+    match VM_STOP_REQ.lock().unwrap().as_ref() {
+        Some(stop_req) => match stop_req.write(1) {
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to request VM stop: {:?}", e);
+                false
+            }
+        },
+        None => false,
+    }
+}
@@ -453,11 +453,51 @@ fn qmp_command_exec(
         (query_balloon, query_balloon),
         (query_vnc, query_vnc),
         (list_type, list_type),
+        (nbd_server_stop, nbd_server_stop),
         (query_hotpluggable_cpus, query_hotpluggable_cpus);
         (input_event, input_event, key, value),
         (device_list_properties, device_list_properties, typename),
         (device_del, device_del, id),
         (blockdev_del, blockdev_del, node_name),
+        (blockdev_snapshot_sync, blockdev_snapshot_sync, device, snapshot_file, format, mode),
+        (blockdev_snapshot_internal_sync, blockdev_snapshot_internal_sync, device, name),
+        (
+            blockdev_snapshot_delete_internal_sync,
+            blockdev_snapshot_delete_internal_sync,
+            device,
+            id,
+            name
+        ),
+        (eject, eject, device, force),
+        (
+            blockdev_change_medium,
+            blockdev_change_medium,
+            device,
+            filename,
+            format,
+            force
+        ),
+        (block_commit, block_commit, device, job_id, base, top, speed),
+        (block_stream, block_stream, device, job_id, speed),
+        (block_job_cancel, block_job_cancel, device, force),
+        (block_job_complete, block_job_complete, device),
+        (block_job_pause, block_job_pause, device),
+        (block_job_resume, block_job_resume, device),
+        (block_job_set_speed, block_job_set_speed, device, speed),
+        (block_resize, block_resize, device, size),
+        (
+            block_dirty_bitmap_add,
+            block_dirty_bitmap_add,
+            node,
+            name,
+            granularity,
+            persistent
+        ),
+        (block_dirty_bitmap_remove, block_dirty_bitmap_remove, node, name),
+        (block_dirty_bitmap_clear, block_dirty_bitmap_clear, node, name),
+        (nbd_server_remove, nbd_server_remove, name),
+        (object_del, object_del, id),
+        (set_link, set_link, name, up),
         (netdev_del, netdev_del, id),
         (chardev_remove, chardev_remove, id),
         (balloon, balloon, value),
@@ -466,6 +506,15 @@ fn qmp_command_exec(
         (blockdev_add, blockdev_add),
         (netdev_add, netdev_add),
         (chardev_add, chardev_add),
+        (drive_mirror, drive_mirror),
+        (blockdev_mirror, blockdev_mirror),
+        (block_set_io_throttle, block_set_io_throttle),
+        (drive_backup, drive_backup),
+        (blockdev_backup, blockdev_backup),
+        (nbd_server_start, nbd_server_start),
+        (nbd_server_add, nbd_server_add),
+        (object_add, object_add),
+        (net_set_rate_limit, net_set_rate_limit),
         (update_region, update_region)
     );
 
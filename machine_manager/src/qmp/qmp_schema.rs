@@ -164,6 +164,27 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "blockdev-snapshot-sync")]
+    #[strum(serialize = "blockdev-snapshot-sync")]
+    blockdev_snapshot_sync {
+        arguments: blockdev_snapshot_sync,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-snapshot-internal-sync")]
+    #[strum(serialize = "blockdev-snapshot-internal-sync")]
+    blockdev_snapshot_internal_sync {
+        arguments: blockdev_snapshot_internal_sync,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-snapshot-delete-internal-sync")]
+    #[strum(serialize = "blockdev-snapshot-delete-internal-sync")]
+    blockdev_snapshot_delete_internal_sync {
+        arguments: blockdev_snapshot_delete_internal_sync,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "balloon")]
     balloon {
         #[serde(default)]
@@ -263,14 +284,184 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "eject")]
+    eject {
+        arguments: eject,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-change-medium")]
+    #[strum(serialize = "blockdev-change-medium")]
+    blockdev_change_medium {
+        arguments: blockdev_change_medium,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "block-commit")]
     #[strum(serialize = "block-commit")]
     block_commit {
-        #[serde(default)]
         arguments: block_commit,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "block-stream")]
+    #[strum(serialize = "block-stream")]
+    block_stream {
+        arguments: block_stream,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-cancel")]
+    #[strum(serialize = "block-job-cancel")]
+    block_job_cancel {
+        arguments: block_job_cancel,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-complete")]
+    #[strum(serialize = "block-job-complete")]
+    block_job_complete {
+        arguments: block_job_complete,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-pause")]
+    #[strum(serialize = "block-job-pause")]
+    block_job_pause {
+        arguments: block_job_pause,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-resume")]
+    #[strum(serialize = "block-job-resume")]
+    block_job_resume {
+        arguments: block_job_resume,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-set-speed")]
+    #[strum(serialize = "block-job-set-speed")]
+    block_job_set_speed {
+        arguments: block_job_set_speed,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    block_set_io_throttle {
+        arguments: block_set_io_throttle,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    block_resize {
+        arguments: block_resize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "drive-mirror")]
+    #[strum(serialize = "drive-mirror")]
+    drive_mirror {
+        arguments: drive_mirror,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-mirror")]
+    #[strum(serialize = "blockdev-mirror")]
+    blockdev_mirror {
+        arguments: blockdev_mirror,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "drive-backup")]
+    #[strum(serialize = "drive-backup")]
+    drive_backup {
+        arguments: drive_backup,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-backup")]
+    #[strum(serialize = "blockdev-backup")]
+    blockdev_backup {
+        arguments: blockdev_backup,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-dirty-bitmap-add")]
+    #[strum(serialize = "block-dirty-bitmap-add")]
+    block_dirty_bitmap_add {
+        arguments: block_dirty_bitmap_add,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-dirty-bitmap-remove")]
+    #[strum(serialize = "block-dirty-bitmap-remove")]
+    block_dirty_bitmap_remove {
+        arguments: block_dirty_bitmap_remove,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-dirty-bitmap-clear")]
+    #[strum(serialize = "block-dirty-bitmap-clear")]
+    block_dirty_bitmap_clear {
+        arguments: block_dirty_bitmap_clear,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "nbd-server-start")]
+    #[strum(serialize = "nbd-server-start")]
+    nbd_server_start {
+        arguments: nbd_server_start,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "nbd-server-add")]
+    #[strum(serialize = "nbd-server-add")]
+    nbd_server_add {
+        arguments: nbd_server_add,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "nbd-server-remove")]
+    #[strum(serialize = "nbd-server-remove")]
+    nbd_server_remove {
+        arguments: nbd_server_remove,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "nbd-server-stop")]
+    #[strum(serialize = "nbd-server-stop")]
+    nbd_server_stop {
+        #[serde(default)]
+        arguments: nbd_server_stop,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "net-set-rate-limit")]
+    #[strum(serialize = "net-set-rate-limit")]
+    net_set_rate_limit {
+        arguments: net_set_rate_limit,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "object-add")]
+    #[strum(serialize = "object-add")]
+    object_add {
+        arguments: object_add,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "object-del")]
+    #[strum(serialize = "object-del")]
+    object_del {
+        arguments: object_del,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "set_link")]
+    #[strum(serialize = "set_link")]
+    set_link {
+        arguments: set_link,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-tpm-models")]
     query_tpm_models {
         #[serde(default)]
@@ -688,6 +879,9 @@ pub struct CacheOptions {
 /// * `file` - the backend file information.
 /// * `cache` - if use direct io.
 /// * `read_only` - if readonly.
+/// * `werror` - action on write error: report, ignore or stop.
+/// * `rerror` - action on read error: report, ignore or stop.
+/// * `copy-on-read` - copy the data read from backing file into the image.
 ///
 /// Additional arguments depend on the type.
 ///
@@ -718,6 +912,10 @@ pub struct blockdev_add {
     pub options: Option<String>,
     #[serde(rename = "throttling.iops-total")]
     pub iops: Option<u64>,
+    pub werror: Option<String>,
+    pub rerror: Option<String>,
+    #[serde(rename = "copy-on-read")]
+    pub copy_on_read: Option<bool>,
 }
 
 pub type BlockDevAddArgument = blockdev_add;
@@ -932,6 +1130,130 @@ impl Command for blockdev_del {
     }
 }
 
+/// blockdev-snapshot-sync
+///
+/// Take an external snapshot of a block device. A qcow2 overlay whose backing
+/// file is the current image is used by the device from now on, the guest
+/// keeps running during the switch.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `snapshot-file` - The path of the overlay image.
+/// * `format` - The format of the overlay image, only `qcow2` is supported.
+/// * `mode` - `absolute-paths` to create the overlay, or `existing` to use
+///   the image prepared by user. Default is `absolute-paths`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-snapshot-sync",
+///      "arguments": { "device": "virtio-blk0",
+///                     "snapshot-file": "/path/to/overlay.qcow2",
+///                     "format": "qcow2" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_snapshot_sync {
+    pub device: String,
+    #[serde(rename = "snapshot-file")]
+    pub snapshot_file: String,
+    pub format: Option<String>,
+    pub mode: Option<String>,
+}
+
+impl Command for blockdev_snapshot_sync {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-snapshot-internal-sync
+///
+/// Take an internal snapshot of a block device whose image is qcow2.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `name` - The name of the snapshot, must be unique in the image.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-snapshot-internal-sync",
+///      "arguments": { "device": "virtio-blk0", "name": "snap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_snapshot_internal_sync {
+    pub device: String,
+    pub name: String,
+}
+
+impl Command for blockdev_snapshot_internal_sync {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-snapshot-delete-internal-sync
+///
+/// Delete an internal snapshot of a block device. The snapshot matches both
+/// `id` and `name` if they are specified, at least one of them is required.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `id` - The id of the snapshot.
+/// * `name` - The name of the snapshot.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-snapshot-delete-internal-sync",
+///      "arguments": { "device": "virtio-blk0", "name": "snap0" } }
+/// <- { "return": { "id": "1", "name": "snap0", "vm-state-size": 0,
+///                  "date-sec": 1000012, "date-nsec": 10,
+///                  "vm-clock-sec": 0, "vm-clock-nsec": 0 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_snapshot_delete_internal_sync {
+    pub device: String,
+    pub id: Option<String>,
+    pub name: Option<String>,
+}
+
+impl Command for blockdev_snapshot_delete_internal_sync {
+    type Res = SnapshotInfo;
+
+    fn back(self) -> SnapshotInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub id: String,
+    pub name: String,
+    #[serde(rename = "vm-state-size")]
+    pub vm_state_size: u64,
+    #[serde(rename = "date-sec")]
+    pub date_sec: u64,
+    #[serde(rename = "date-nsec")]
+    pub date_nsec: u64,
+    #[serde(rename = "vm-clock-sec")]
+    pub vm_clock_sec: u64,
+    #[serde(rename = "vm-clock-nsec")]
+    pub vm_clock_nsec: u64,
+}
+
 /// netdev_del
 ///
 /// Remove a network backend.
@@ -1370,6 +1692,144 @@ pub struct DeviceDeleted {
     pub path: String,
 }
 
+/// BlockJobCompleted
+///
+/// Emitted when a block job has completed, `error` is set if the job failed.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_COMPLETED",
+///      "data": { "type": "commit", "device": "virtio-blk0", "len": 10737418240,
+///                "offset": 10737418240, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCompleted {
+    /// Job type.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// Job id.
+    pub device: String,
+    /// Estimated total bytes of the job.
+    pub len: u64,
+    /// Bytes processed by the job.
+    pub offset: u64,
+    /// Max speed of the job.
+    pub speed: u64,
+    /// Error message if the job failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// BlockJobCancelled
+///
+/// Emitted when a block job has been cancelled.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_CANCELLED",
+///      "data": { "type": "commit", "device": "virtio-blk0", "len": 10737418240,
+///                "offset": 134217728, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCancelled {
+    /// Job type.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// Job id.
+    pub device: String,
+    /// Estimated total bytes of the job.
+    pub len: u64,
+    /// Bytes processed by the job.
+    pub offset: u64,
+    /// Max speed of the job.
+    pub speed: u64,
+}
+
+/// BlockJobReady
+///
+/// Emitted when a block job is ready to complete by `block-job-complete`,
+/// e.g. the data of mirror job is synchronized.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_READY",
+///      "data": { "type": "mirror", "device": "virtio-blk0", "len": 10737418240,
+///                "offset": 10737418240, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobReady {
+    /// Job type.
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// Job id.
+    pub device: String,
+    /// Estimated total bytes of the job.
+    pub len: u64,
+    /// Bytes processed by the job.
+    pub offset: u64,
+    /// Max speed of the job.
+    pub speed: u64,
+}
+
+/// BlockIoError
+///
+/// Emitted when a disk IO error occurs, `action` is the action taken by the
+/// device according to the error policy of drive.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_IO_ERROR",
+///      "data": { "device": "virtio-blk0", "operation": "write", "action": "stop",
+///                "nospace": true, "reason": "No space left on device (os error 28)" },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockIoError {
+    /// Id of the block device.
+    pub device: String,
+    /// The operation which fails, read or write.
+    pub operation: String,
+    /// The action taken: report, ignore or stop.
+    pub action: String,
+    /// The error is caused by no space on the host.
+    pub nospace: bool,
+    /// Human readable description of the error.
+    pub reason: String,
+}
+
+/// DeviceTrayMoved
+///
+/// Emitted when the tray of a removable device is opened or closed, by the
+/// guest or the management.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "DEVICE_TRAY_MOVED",
+///      "data": { "id": "scsi-cd0", "tray-open": true },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceTrayMoved {
+    /// Id of the device.
+    pub id: String,
+    /// The tray is open.
+    #[serde(rename = "tray-open")]
+    pub tray_open: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, EnumIter, EnumVariantNames, EnumString)]
 #[serde(tag = "event")]
 pub enum QmpEvent {
@@ -1408,6 +1868,31 @@ pub enum QmpEvent {
         data: BalloonInfo,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "BLOCK_JOB_COMPLETED")]
+    BlockJobCompleted {
+        data: BlockJobCompleted,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_CANCELLED")]
+    BlockJobCancelled {
+        data: BlockJobCancelled,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_READY")]
+    BlockJobReady {
+        data: BlockJobReady,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_IO_ERROR")]
+    BlockIoError {
+        data: BlockIoError,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "DEVICE_TRAY_MOVED")]
+    DeviceTrayMoved {
+        data: DeviceTrayMoved,
+        timestamp: TimeStamp,
+    },
 }
 
 /// query-balloon:
@@ -1763,27 +2248,986 @@ impl Command for device_list_properties {
     }
 }
 
+/// block-commit
+///
+/// Start a job to commit the active image of a block device to its backing
+/// file. The guest keeps writing to the active image during the job, and the
+/// device switches to the backing file when all the data is committed.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `job-id` - The id of the job, default is the id of device.
+/// * `base` - The backing file of the active image.
+/// * `top` - The active image of the device.
+/// * `speed` - The max speed of the job in bytes per second, 0 means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-commit",
+///      "arguments": { "device": "virtio-blk0", "speed": 10485760 } }
+/// <- { "return": {} }
+/// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct block_commit {}
+#[serde(deny_unknown_fields)]
+pub struct block_commit {
+    pub device: String,
+    #[serde(rename = "job-id")]
+    pub job_id: Option<String>,
+    pub base: Option<String>,
+    pub top: Option<String>,
+    pub speed: Option<u64>,
+}
 
 impl Command for block_commit {
-    type Res = Vec<DeviceProps>;
+    type Res = Empty;
 
-    fn back(self) -> Vec<DeviceProps> {
+    fn back(self) -> Empty {
         Default::default()
     }
 }
 
-/// Query tpm models of StratoVirt.
+/// eject
 ///
-/// # Example
+/// Eject the medium of a removable device and leave the tray open.
+///
+/// # Arguments
+///
+/// * `device` - The id of the device.
+/// * `force` - Eject the medium even if it's locked by guest.
+///
+/// # Notes
+///
+/// If the medium is locked by guest and `force` is not set, the guest is
+/// requested to eject it and the command fails.
+///
+/// # Examples
 ///
 /// ```text
-/// -> { "execute": "query-tpm-models" }
-/// <- {"return":[]}
+/// -> { "execute": "eject", "arguments": { "device": "scsi-cd0" } }
+/// <- { "return": {} }
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_tpm_models {}
+#[serde(deny_unknown_fields)]
+pub struct eject {
+    pub device: String,
+    pub force: Option<bool>,
+}
+
+impl Command for eject {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-change-medium
+///
+/// Replace the medium of a removable device with a new image file, and close
+/// the tray.
+///
+/// # Arguments
+///
+/// * `device` - The id of the device.
+/// * `filename` - The path of the new image file.
+/// * `format` - The format of the image, default is raw.
+/// * `force` - Replace the medium even if it's locked by guest.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-change-medium",
+///      "arguments": { "device": "scsi-cd0", "filename": "/path/to/new.iso" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_change_medium {
+    pub device: String,
+    pub filename: String,
+    pub format: Option<String>,
+    pub force: Option<bool>,
+}
+
+impl Command for blockdev_change_medium {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-stream
+///
+/// Start a job to copy the data of the backing chain into the image of a block
+/// device. The image is detached from its backing file when all the data is
+/// copied, the guest keeps running during the job.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `job-id` - The id of the job, default is the id of device.
+/// * `speed` - The max speed of the job in bytes per second, 0 means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-stream",
+///      "arguments": { "device": "virtio-blk0", "speed": 10485760 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_stream {
+    pub device: String,
+    #[serde(rename = "job-id")]
+    pub job_id: Option<String>,
+    pub speed: Option<u64>,
+}
+
+impl Command for block_stream {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// drive-mirror
+///
+/// Start a job to mirror the image of a block device to a new image file. The
+/// guest keeps writing to the device during the job, and the device switches to
+/// the new image when all the data is synchronized.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `job-id` - The id of the job, default is the id of device.
+/// * `target` - The path of the target image.
+/// * `format` - The format of the target image, default is the format of device.
+/// * `sync` - The data to copy, "full" for the whole disk or "top" for the data
+///   not in the backing file of the device image.
+/// * `mode` - "absolute-paths" to create the target image or "existing" to use
+///   an existing one, default is "absolute-paths".
+/// * `speed` - The max speed of the job in bytes per second, 0 means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "drive-mirror",
+///      "arguments": { "device": "virtio-blk0", "target": "/path/to/new.qcow2",
+///                     "format": "qcow2", "sync": "full" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct drive_mirror {
+    pub device: String,
+    #[serde(rename = "job-id")]
+    pub job_id: Option<String>,
+    pub target: String,
+    pub format: Option<String>,
+    pub sync: String,
+    pub mode: Option<String>,
+    pub speed: Option<u64>,
+}
+
+impl Command for drive_mirror {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-mirror
+///
+/// Start a job to mirror the image of a block device to the image of a block
+/// backend added by `blockdev-add`. The block backend is taken over by the
+/// device when all the data is synchronized.
+///
+/// # Arguments
+///
+/// * `job-id` - The id of the job, default is the id of device.
+/// * `device` - The id of the block device.
+/// * `target` - The node name of the target block backend.
+/// * `sync` - The data to copy, "full" or "top".
+/// * `speed` - The max speed of the job in bytes per second, 0 means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-mirror",
+///      "arguments": { "device": "virtio-blk0", "target": "drive-1", "sync": "full" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_mirror {
+    #[serde(rename = "job-id")]
+    pub job_id: Option<String>,
+    pub device: String,
+    pub target: String,
+    pub sync: String,
+    pub speed: Option<u64>,
+}
+
+impl Command for blockdev_mirror {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// drive-backup
+///
+/// Start a job to back up the data of a block device to an image file. The
+/// guest keeps writing to the device during the job, and the backup holds the
+/// data of disk at the time the job completes.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `job-id` - The id of the job, default is the id of device.
+/// * `target` - The path of the target image.
+/// * `format` - The format of the target image, default is the format of device.
+/// * `sync` - The data to copy, "full" for the whole disk, "top" for the data
+///   not in the backing file of the device image, or "incremental" for the
+///   areas marked dirty in `bitmap`.
+/// * `bitmap` - The name of the dirty bitmap used by "incremental" backup, it
+///   only keeps the areas written after the backup when the job completes.
+/// * `mode` - "absolute-paths" to create the target image or "existing" to use
+///   an existing one, default is "absolute-paths".
+/// * `speed` - The max speed of the job in bytes per second, 0 means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "drive-backup",
+///      "arguments": { "device": "virtio-blk0", "target": "/path/to/inc.qcow2",
+///                     "format": "qcow2", "sync": "incremental", "bitmap": "bitmap0",
+///                     "mode": "existing" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct drive_backup {
+    pub device: String,
+    #[serde(rename = "job-id")]
+    pub job_id: Option<String>,
+    pub target: String,
+    pub format: Option<String>,
+    pub sync: String,
+    pub bitmap: Option<String>,
+    pub mode: Option<String>,
+    pub speed: Option<u64>,
+}
+
+impl Command for drive_backup {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// blockdev-backup
+///
+/// Start a job to back up the data of a block device to the image of a block
+/// backend added by `blockdev-add`.
+///
+/// # Arguments
+///
+/// * `job-id` - The id of the job, default is the id of device.
+/// * `device` - The id of the block device.
+/// * `target` - The node name of the target block backend.
+/// * `sync` - The data to copy, "full", "top" or "incremental".
+/// * `bitmap` - The name of the dirty bitmap used by "incremental" backup.
+/// * `speed` - The max speed of the job in bytes per second, 0 means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "blockdev-backup",
+///      "arguments": { "device": "virtio-blk0", "target": "drive-1",
+///                     "sync": "incremental", "bitmap": "bitmap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct blockdev_backup {
+    #[serde(rename = "job-id")]
+    pub job_id: Option<String>,
+    pub device: String,
+    pub target: String,
+    pub sync: String,
+    pub bitmap: Option<String>,
+    pub speed: Option<u64>,
+}
+
+impl Command for blockdev_backup {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-dirty-bitmap-add
+///
+/// Add a dirty bitmap to a block device to record the areas written by guest.
+///
+/// # Arguments
+///
+/// * `node` - The id of the block device.
+/// * `name` - The name of the bitmap, unique in the device.
+/// * `granularity` - The bytes each bit of bitmap stands for, power of 2 and
+///   at least 512, default is 65536.
+/// * `persistent` - Whether the bitmap is stored alongside the image so that it
+///   survives the restart of VM, default is false.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-dirty-bitmap-add",
+///      "arguments": { "node": "virtio-blk0", "name": "bitmap0", "persistent": true } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_dirty_bitmap_add {
+    pub node: String,
+    pub name: String,
+    pub granularity: Option<u64>,
+    pub persistent: Option<bool>,
+}
+
+impl Command for block_dirty_bitmap_add {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-dirty-bitmap-remove
+///
+/// Remove a dirty bitmap from a block device.
+///
+/// # Arguments
+///
+/// * `node` - The id of the block device.
+/// * `name` - The name of the bitmap.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-dirty-bitmap-remove",
+///      "arguments": { "node": "virtio-blk0", "name": "bitmap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_dirty_bitmap_remove {
+    pub node: String,
+    pub name: String,
+}
+
+impl Command for block_dirty_bitmap_remove {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-dirty-bitmap-clear
+///
+/// Clear all the bits of a dirty bitmap.
+///
+/// # Arguments
+///
+/// * `node` - The id of the block device.
+/// * `name` - The name of the bitmap.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-dirty-bitmap-clear",
+///      "arguments": { "node": "virtio-blk0", "name": "bitmap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_dirty_bitmap_clear {
+    pub node: String,
+    pub name: String,
+}
+
+impl Command for block_dirty_bitmap_clear {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NbdServerAddrData {
+    pub path: Option<String>,
+    pub host: Option<String>,
+    pub port: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NbdServerAddr {
+    #[serde(rename = "type")]
+    pub addr_type: String,
+    #[serde(rename = "data")]
+    pub addr_data: NbdServerAddrData,
+}
+
+/// nbd-server-start
+///
+/// Start the built-in NBD server to export block devices.
+///
+/// # Arguments
+///
+/// * `addr` - The address to listen, `unix` with `path`, or `inet` with
+///   `host` and `port`.
+/// * `max-connections` - The max number of clients connected at the same
+///   time, default is 0 which means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "nbd-server-start",
+///      "arguments": { "addr": { "type": "unix", "data": { "path": "/tmp/nbd.sock" } } } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct nbd_server_start {
+    pub addr: NbdServerAddr,
+    #[serde(rename = "max-connections")]
+    pub max_connections: Option<u32>,
+}
+
+impl Command for nbd_server_start {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// nbd-server-add
+///
+/// Export the disk of a block device by the NBD server, the guest keeps
+/// using the device meanwhile.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `name` - The name of the export, default is the id of device.
+/// * `writable` - Whether clients can write the export, default is false.
+/// * `bitmap` - The dirty bitmap of device exposed to clients in the meta
+///   context `qemu:dirty-bitmap:<bitmap>`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "nbd-server-add",
+///      "arguments": { "device": "virtio-blk0", "name": "disk0", "bitmap": "bitmap0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct nbd_server_add {
+    pub device: String,
+    pub name: Option<String>,
+    pub writable: Option<bool>,
+    pub bitmap: Option<String>,
+}
+
+impl Command for nbd_server_add {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// nbd-server-remove
+///
+/// Remove an export of the NBD server, the clients using it are disconnected.
+///
+/// # Arguments
+///
+/// * `name` - The name of the export.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "nbd-server-remove", "arguments": { "name": "disk0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct nbd_server_remove {
+    pub name: String,
+}
+
+impl Command for nbd_server_remove {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// nbd-server-stop
+///
+/// Stop the NBD server, all the exports are removed.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "nbd-server-stop" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct nbd_server_stop {}
+
+impl Command for nbd_server_stop {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// net-set-rate-limit
+///
+/// Change the rate limits of a virtio-net device at runtime. The rx packets
+/// are received by the guest, and the tx packets are sent by the guest. Rate
+/// limiting is disabled if all the limits are zero or not set.
+///
+/// # Arguments
+///
+/// * `device` - The id of the net device.
+/// * `rx-bps`, `tx-bps` - Bandwidth limits in bytes per second.
+/// * `rx-pps`, `tx-pps` - Packets per second.
+/// * `*-max` - The burst limit of the corresponding limit.
+/// * `*-max-length` - The seconds that the burst limit can last, default is 1.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "net-set-rate-limit",
+///      "arguments": { "device": "net-0", "rx-bps": 1048576, "tx-bps": 1048576,
+///                     "tx-pps": 1000, "tx-pps-max": 5000, "tx-pps-max-length": 10 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct net_set_rate_limit {
+    pub device: String,
+    #[serde(rename = "rx-bps")]
+    pub rx_bps: Option<u64>,
+    #[serde(rename = "rx-bps-max")]
+    pub rx_bps_max: Option<u64>,
+    #[serde(rename = "rx-bps-max-length")]
+    pub rx_bps_max_length: Option<u64>,
+    #[serde(rename = "tx-bps")]
+    pub tx_bps: Option<u64>,
+    #[serde(rename = "tx-bps-max")]
+    pub tx_bps_max: Option<u64>,
+    #[serde(rename = "tx-bps-max-length")]
+    pub tx_bps_max_length: Option<u64>,
+    #[serde(rename = "rx-pps")]
+    pub rx_pps: Option<u64>,
+    #[serde(rename = "rx-pps-max")]
+    pub rx_pps_max: Option<u64>,
+    #[serde(rename = "rx-pps-max-length")]
+    pub rx_pps_max_length: Option<u64>,
+    #[serde(rename = "tx-pps")]
+    pub tx_pps: Option<u64>,
+    #[serde(rename = "tx-pps-max")]
+    pub tx_pps_max: Option<u64>,
+    #[serde(rename = "tx-pps-max-length")]
+    pub tx_pps_max_length: Option<u64>,
+}
+
+impl Command for net_set_rate_limit {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// object-add
+///
+/// Add an object while the VM is running, only `filter-dump` is supported now,
+/// which captures the packets of a net device to a pcap file.
+///
+/// # Arguments
+///
+/// * `qom-type` - The type of the object.
+/// * `id` - The id of the object.
+/// * `netdev` - The netdev whose packets are captured.
+/// * `file` - The pcap file.
+/// * `maxlen` - Max number of bytes saved for each packet, default is 65535.
+/// * `maxsize` - Rotate the file once it exceeds this size in bytes, default
+///   is 0 which means no rotation.
+/// * `maxfiles` - Max number of the rotated files kept, default is 1.
+/// * `queue` - The packets captured, `all`, `rx` or `tx`, default is `all`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "object-add",
+///      "arguments": { "qom-type": "filter-dump", "id": "dump0", "netdev": "net0",
+///                     "file": "/tmp/net0.pcap", "maxlen": 128 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct object_add {
+    #[serde(rename = "qom-type")]
+    pub qom_type: String,
+    pub id: String,
+    pub netdev: Option<String>,
+    pub file: Option<String>,
+    pub maxlen: Option<u32>,
+    pub maxsize: Option<u64>,
+    pub maxfiles: Option<u32>,
+    pub queue: Option<String>,
+}
+
+impl Command for object_add {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// object-del
+///
+/// Remove an object added by `-object` or `object-add`.
+///
+/// # Arguments
+///
+/// * `id` - The id of the object.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "object-del", "arguments": { "id": "dump0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct object_del {
+    pub id: String,
+}
+
+impl Command for object_del {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// set_link
+///
+/// Set the link status of a virtio-net device, as if the cable is plugged or
+/// unplugged. The guest is notified by the config interrupt, and the packets
+/// are dropped while the link is down.
+///
+/// # Arguments
+///
+/// * `name` - The id of the net device, or the id of the netdev it uses.
+/// * `up` - True to set the link up, false to set it down.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "set_link", "arguments": { "name": "net-0", "up": false } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct set_link {
+    pub name: String,
+    pub up: bool,
+}
+
+impl Command for set_link {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-cancel
+///
+/// Cancel a block job, `BLOCK_JOB_CANCELLED` event is emitted when the job
+/// is stopped.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+/// * `force` - Cancel a paused job immediately, otherwise it's cancelled
+///   once resumed.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-cancel", "arguments": { "device": "virtio-blk0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_cancel {
+    pub device: String,
+    pub force: Option<bool>,
+}
+
+impl Command for block_job_cancel {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-complete
+///
+/// Complete a block job which is ready, e.g. switch the device to the target
+/// image of mirror job. `BLOCK_JOB_COMPLETED` event is emitted when the job
+/// is done.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-complete", "arguments": { "device": "virtio-blk0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_complete {
+    pub device: String,
+}
+
+impl Command for block_job_complete {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-pause
+///
+/// Pause a block job.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-pause", "arguments": { "device": "virtio-blk0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_pause {
+    pub device: String,
+}
+
+impl Command for block_job_pause {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-resume
+///
+/// Resume a paused block job.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-resume", "arguments": { "device": "virtio-blk0" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_resume {
+    pub device: String,
+}
+
+impl Command for block_job_resume {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block-job-set-speed
+///
+/// Set the max speed of a block job.
+///
+/// # Arguments
+///
+/// * `device` - The id of the job.
+/// * `speed` - The max speed in bytes per second, 0 means unlimited.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block-job-set-speed",
+///      "arguments": { "device": "virtio-blk0", "speed": 1048576 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_job_set_speed {
+    pub device: String,
+    pub speed: u64,
+}
+
+impl Command for block_job_set_speed {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block_set_io_throttle
+///
+/// Change the IO limits of a block device at runtime. The limits are shared by
+/// all the devices in the same throttle group. Throttling is disabled if all
+/// the limits are zero.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `bps`, `bps_rd`, `bps_wr` - Total, read and write bandwidth limits in
+///   bytes per second.
+/// * `iops`, `iops_rd`, `iops_wr` - Total, read and write IO operations per second.
+/// * `*_max` - The burst limit of the corresponding limit.
+/// * `*_max_length` - The seconds that the burst limit can last, default is 1.
+/// * `group` - The throttle group which the device joins.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_set_io_throttle",
+///      "arguments": { "device": "virtio-blk0", "bps": 0, "bps_rd": 1048576,
+///                     "bps_wr": 0, "iops": 100, "iops_rd": 0, "iops_wr": 0,
+///                     "iops_max": 200, "iops_max_length": 10 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_set_io_throttle {
+    pub device: String,
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    pub bps_max: Option<u64>,
+    pub bps_rd_max: Option<u64>,
+    pub bps_wr_max: Option<u64>,
+    pub iops_max: Option<u64>,
+    pub iops_rd_max: Option<u64>,
+    pub iops_wr_max: Option<u64>,
+    pub bps_max_length: Option<u64>,
+    pub bps_rd_max_length: Option<u64>,
+    pub bps_wr_max_length: Option<u64>,
+    pub iops_max_length: Option<u64>,
+    pub iops_rd_max_length: Option<u64>,
+    pub iops_wr_max_length: Option<u64>,
+    pub group: Option<String>,
+}
+
+impl Command for block_set_io_throttle {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// block_resize
+///
+/// Grow the disk of a block device online, the guest is notified of the new size.
+///
+/// # Arguments
+///
+/// * `device` - The id of the block device.
+/// * `size` - The new size of disk in bytes.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "block_resize",
+///      "arguments": { "device": "virtio-blk0", "size": 21474836480 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct block_resize {
+    pub device: String,
+    pub size: u64,
+}
+
+impl Command for block_resize {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// Query tpm models of StratoVirt.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-tpm-models" }
+/// <- {"return":[]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_tpm_models {}
 
 impl Command for query_tpm_models {
     type Res = Vec<String>;
@@ -1986,34 +3430,94 @@ impl Command for qom_get {
 ///
 /// ```text
 /// -> { "execute": "query-block" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","qdev":"virtio-blk0","removable":false,
+///      "locked":false,"inserted":{"file":"/path/to/disk.qcow2","node-name":"drive-0",
+///      "ro":false,"drv":"qcow2","encrypted":false,"bps":0,"bps_rd":0,"bps_wr":0,
+///      "iops":0,"iops_rd":0,"iops_wr":0,"iothread":"iothread0",
+///      "image":{"filename":"/path/to/disk.qcow2","format":"qcow2",
+///      "virtual-size":10737418240}}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block {}
 
 impl Command for query_block {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockInfo {
+    /// Id of the drive.
+    pub device: String,
+    /// Id of the block device.
+    pub qdev: String,
+    pub removable: bool,
+    pub locked: bool,
+    /// The tray of removable device is open.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tray_open: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inserted: Option<BlockDeviceInfo>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceInfo {
+    pub file: String,
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub ro: bool,
+    pub drv: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backing_file: Option<String>,
+    pub encrypted: bool,
+    pub bps: u64,
+    pub bps_rd: u64,
+    pub bps_wr: u64,
+    pub iops: u64,
+    pub iops_rd: u64,
+    pub iops_wr: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iothread: Option<String>,
+    pub image: ImageInfo,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub filename: String,
+    pub format: String,
+    #[serde(rename = "virtual-size")]
+    pub virtual_size: u64,
+    #[serde(
+        rename = "backing-filename",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub backing_filename: Option<String>,
+}
+
 /// Query named block node.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-named-block-nodes" }
-/// <- {"return":[]}
+/// <- {"return":[{"file":"/path/to/disk.qcow2","node-name":"drive-0","ro":false,
+///      "drv":"qcow2","encrypted":false,"bps":0,"bps_rd":0,"bps_wr":0,"iops":0,
+///      "iops_rd":0,"iops_wr":0,"image":{"filename":"/path/to/disk.qcow2",
+///      "format":"qcow2","virtual-size":10737418240}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_named_block_nodes {}
 
 impl Command for query_named_block_nodes {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockDeviceInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockDeviceInfo> {
         Default::default()
     }
 }
@@ -2024,38 +3528,98 @@ impl Command for query_named_block_nodes {
 ///
 /// ```text
 /// -> { "execute": "query-blockstats" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","qdev":"virtio-blk0","node-name":"drive-0",
+///      "stats":{"rd_bytes":1048576,"wr_bytes":4096,"unmap_bytes":0,
+///      "rd_operations":256,"wr_operations":1,"flush_operations":1,
+///      "unmap_operations":0,"rd_total_time_ns":2000000,"wr_total_time_ns":50000,
+///      "flush_total_time_ns":100000,"unmap_total_time_ns":0,
+///      "failed_rd_operations":0,"failed_wr_operations":0,
+///      "failed_flush_operations":0,"failed_unmap_operations":0,
+///      "idle_time_ns":1000000000}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_blockstats {}
 
 impl Command for query_blockstats {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockStats>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockStats> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockStats {
+    /// Id of the drive.
+    pub device: String,
+    /// Id of the block device.
+    pub qdev: String,
+    #[serde(rename = "node-name")]
+    pub node_name: String,
+    pub stats: BlockDeviceStats,
+}
+
+/// Statistics of the IO of block device, the time is in nanoseconds.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub unmap_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub unmap_operations: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub unmap_total_time_ns: u64,
+    pub failed_rd_operations: u64,
+    pub failed_wr_operations: u64,
+    pub failed_flush_operations: u64,
+    pub failed_unmap_operations: u64,
+    /// Time since the last IO completed, none if there is no IO yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_time_ns: Option<u64>,
+}
+
 /// Query jobs of blocks.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-block_jobs" }
-/// <- {"return":[]}
+/// -> { "execute": "query-block-jobs" }
+/// <- {"return":[{"type":"commit","id":"job0","device":"virtio-blk0","len":10737418240,
+///      "offset":1048576,"busy":false,"paused":false,"speed":0,
+///      "io-status":"ok","ready":false,"status":"running"}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block_jobs {}
 
 impl Command for query_block_jobs {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockJobInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockJobInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockJobInfo {
+    #[serde(rename = "type")]
+    pub job_type: String,
+    pub id: String,
+    pub device: String,
+    pub len: u64,
+    pub offset: u64,
+    pub busy: bool,
+    pub paused: bool,
+    pub speed: u64,
+    #[serde(rename = "io-status")]
+    pub io_status: String,
+    pub ready: bool,
+    pub status: String,
+}
+
 /// Query capabilities of gic.
 ///
 /// # Example
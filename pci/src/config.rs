@@ -294,6 +294,10 @@ pub const PCI_EXP_HP_EV_CCI: u16 = PCI_EXP_SLTCTL_CCIE;
 pub const PCI_DEVICE_ID_REDHAT_XHCI: u16 = 0x000d;
 pub const PCI_CLASS_SERIAL_USB: u16 = 0x0c03;
 
+// NVMe device id
+pub const PCI_DEVICE_ID_REDHAT_NVME: u16 = 0x0010;
+pub const PCI_CLASS_STORAGE_EXPRESS: u16 = 0x0108;
+
 /// Type of bar region.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum RegionType {
@@ -16,6 +16,7 @@ pub mod config;
 pub mod demo_dev;
 pub mod hotplug;
 pub mod msix;
+pub mod nvme;
 
 mod bus;
 pub mod demo_device;
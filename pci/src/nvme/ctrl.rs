@@ -0,0 +1,1022 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Result};
+use log::{error, warn};
+
+use super::namespace::NvmeNamespace;
+use super::queue::{map_prps, CompletionQueue, NvmeCmd, NvmeRequest, SubmissionQueue};
+use super::{
+    NvmeInterruptCb, NVME_DNR, NVME_IDENTIFY_DATA_SIZE, NVME_SC_AER_LIMIT,
+    NVME_SC_DATA_TRANSFER_ERROR, NVME_SC_FEATURE_NOT_SAVEABLE, NVME_SC_INVALID_CQID,
+    NVME_SC_INVALID_FIELD, NVME_SC_INVALID_IRQ_VECTOR, NVME_SC_INVALID_LOG_PAGE,
+    NVME_SC_INVALID_NSID, NVME_SC_INVALID_OPCODE, NVME_SC_INVALID_PRP_OFFSET, NVME_SC_INVALID_QID,
+    NVME_SC_INVALID_QUEUE_DELETION, NVME_SC_MAX_QSIZE,
+};
+use crate::{le_write_u16, le_write_u32};
+use address_space::AddressSpace;
+use machine_manager::config::{DriveFile, NvmeConfig, NvmeNsConfig, MAX_NVME_NAMESPACES};
+use util::aio::iov_from_buf_direct;
+
+/// Controller registers, NVMe spec 1.4 3.1.
+pub const NVME_REG_CAP: u64 = 0x00;
+pub const NVME_REG_VS: u64 = 0x08;
+pub const NVME_REG_INTMS: u64 = 0x0c;
+pub const NVME_REG_INTMC: u64 = 0x10;
+pub const NVME_REG_CC: u64 = 0x14;
+pub const NVME_REG_CSTS: u64 = 0x1c;
+pub const NVME_REG_AQA: u64 = 0x24;
+pub const NVME_REG_ASQ: u64 = 0x28;
+pub const NVME_REG_ACQ: u64 = 0x30;
+
+/// Max queue entries supported, 0's based.
+const NVME_CAP_MQES: u64 = 1023;
+/// Contiguous queues required.
+const NVME_CAP_CQR: u64 = 1 << 16;
+/// Worst case time to wait for CSTS.RDY in 500ms units.
+const NVME_CAP_TO: u64 = 0xf << 24;
+/// NVM command set supported.
+const NVME_CAP_CSS_NVM: u64 = 1 << 37;
+/// Max memory page size is 2 ^ (12 + 4).
+const NVME_CAP_MPSMAX: u64 = 4 << 52;
+/// Version 1.4.0.
+const NVME_VERSION: u32 = 0x0001_0400;
+
+const NVME_CC_EN: u32 = 1;
+const NVME_CC_MPS_SHIFT: u32 = 7;
+const NVME_CC_MPS_MASK: u32 = 0xf;
+const NVME_CC_SHN_SHIFT: u32 = 14;
+const NVME_CC_SHN_MASK: u32 = 0x3;
+const NVME_CC_IOSQES_SHIFT: u32 = 16;
+const NVME_CC_IOCQES_SHIFT: u32 = 20;
+const NVME_CC_QES_MASK: u32 = 0xf;
+/// Size of submission/completion queue entry in power of two.
+const NVME_SQES: u32 = 6;
+const NVME_CQES: u32 = 4;
+
+const NVME_CSTS_RDY: u32 = 1;
+const NVME_CSTS_CFS: u32 = 1 << 1;
+const NVME_CSTS_SHST_COMPLETE: u32 = 2 << 2;
+
+/// Admin command set opcodes, NVMe spec 1.4 5.
+const NVME_ADM_DELETE_SQ: u8 = 0x00;
+const NVME_ADM_CREATE_SQ: u8 = 0x01;
+const NVME_ADM_GET_LOG_PAGE: u8 = 0x02;
+const NVME_ADM_DELETE_CQ: u8 = 0x04;
+const NVME_ADM_CREATE_CQ: u8 = 0x05;
+const NVME_ADM_IDENTIFY: u8 = 0x06;
+const NVME_ADM_ABORT: u8 = 0x08;
+const NVME_ADM_SET_FEATURES: u8 = 0x09;
+const NVME_ADM_GET_FEATURES: u8 = 0x0a;
+const NVME_ADM_ASYNC_EVENT: u8 = 0x0c;
+
+/// Controller or namespace structure (CNS) of Identify command.
+const NVME_ID_CNS_NS: u32 = 0x00;
+const NVME_ID_CNS_CTRL: u32 = 0x01;
+const NVME_ID_CNS_NS_ACTIVE_LIST: u32 = 0x02;
+const NVME_ID_CNS_NS_DESCR_LIST: u32 = 0x03;
+
+const NVME_LOG_ERROR_INFO: u32 = 0x01;
+const NVME_LOG_SMART_INFO: u32 = 0x02;
+const NVME_LOG_FW_SLOT_INFO: u32 = 0x03;
+
+const NVME_FEAT_ARBITRATION: u32 = 0x01;
+const NVME_FEAT_VOLATILE_WC: u32 = 0x06;
+const NVME_FEAT_NUM_QUEUES: u32 = 0x07;
+const NVME_FEAT_IRQ_VECTOR_CONFIG: u32 = 0x09;
+const NVME_FEAT_ASYNC_EVENT_CONFIG: u32 = 0x0b;
+/// Save bit of Set Features command.
+const NVME_FEAT_SAVE: u32 = 1 << 31;
+
+/// Max data transfer size in units of min page size, 2 ^ 7 * 4K.
+const NVME_MDTS: u8 = 7;
+const NVME_MIN_PAGE_SIZE: u64 = 4096;
+/// Max outstanding Asynchronous Event Request commands, 0's based.
+const NVME_AERL: u8 = 3;
+const NVME_FIRMWARE_REVISION: &str = "1.0";
+const NVME_MODEL_NUMBER: &str = "StratoVirt NVMe Ctrl";
+/// Temperature reported in SMART log, in Kelvin.
+const NVME_TEMPERATURE: u16 = 0x0141;
+
+/// Fill `buf` with `s` padded by spaces, the format of ASCII fields.
+fn write_ascii(buf: &mut [u8], s: &str) {
+    buf.fill(b' ');
+    let len = std::cmp::min(buf.len(), s.len());
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+type NvmeResult = std::result::Result<u32, u16>;
+
+/// The NVMe controller, which handles the registers and the admin commands, and
+/// dispatches the IO commands to namespaces.
+pub struct NvmeCtrl {
+    config: NvmeConfig,
+    mem_space: Arc<AddressSpace>,
+    drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    cap: u64,
+    cc: u32,
+    csts: u32,
+    intms: u32,
+    aqa: u32,
+    asq: u64,
+    acq: u64,
+    /// Submission queues indexed by queue id, 0 is the admin queue.
+    sqs: Vec<Option<SubmissionQueue>>,
+    /// Completion queues indexed by queue id, 0 is the admin queue.
+    cqs: Vec<Option<Arc<Mutex<CompletionQueue>>>>,
+    namespaces: BTreeMap<u32, NvmeNamespace>,
+    /// Value of the features set by guest, indexed by feature id.
+    features: [u32; 12],
+    /// Outstanding Asynchronous Event Request commands.
+    aer_reqs: Vec<NvmeRequest>,
+    /// Fatal error met, reported in CSTS.CFS.
+    broken: Arc<AtomicBool>,
+    pub interrupt_cb: Option<NvmeInterruptCb>,
+}
+
+impl NvmeCtrl {
+    pub fn new(
+        config: &NvmeConfig,
+        mem_space: &Arc<AddressSpace>,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    ) -> Self {
+        let nr_queues = config.num_queues as usize + 1;
+        let mut ctrl = Self {
+            config: config.clone(),
+            mem_space: mem_space.clone(),
+            drive_files,
+            cap: NVME_CAP_MQES | NVME_CAP_CQR | NVME_CAP_TO | NVME_CAP_CSS_NVM | NVME_CAP_MPSMAX,
+            cc: 0,
+            csts: 0,
+            intms: 0,
+            aqa: 0,
+            asq: 0,
+            acq: 0,
+            sqs: (0..nr_queues).map(|_| None).collect(),
+            cqs: vec![None; nr_queues],
+            namespaces: BTreeMap::new(),
+            features: [0; 12],
+            aer_reqs: Vec::new(),
+            broken: Arc::new(AtomicBool::new(false)),
+            interrupt_cb: None,
+        };
+        ctrl.reset_features();
+        ctrl
+    }
+
+    /// Attach the namespace backed by the drive in `config`.
+    pub fn attach_namespace(&mut self, config: &NvmeNsConfig) -> Result<()> {
+        let nsid = match config.nsid {
+            Some(nsid) => {
+                if self.namespaces.contains_key(&nsid) {
+                    bail!(
+                        "Namespace {} of nvme {} already exists",
+                        nsid,
+                        self.config.id
+                    );
+                }
+                nsid
+            }
+            None => match (1..=MAX_NVME_NAMESPACES).find(|id| !self.namespaces.contains_key(id)) {
+                Some(nsid) => nsid,
+                None => bail!("No free namespace id in nvme {}", self.config.id),
+            },
+        };
+        let ns = NvmeNamespace::new(nsid, config.clone(), &self.drive_files, self.broken.clone())?;
+        self.namespaces.insert(nsid, ns);
+        Ok(())
+    }
+
+    pub fn detach_namespaces(&mut self) -> Result<()> {
+        for ns in self.namespaces.values() {
+            ns.unregister_io_event()?;
+        }
+        self.namespaces.clear();
+        Ok(())
+    }
+
+    fn reset_features(&mut self) {
+        self.features = [0; 12];
+        self.features[NVME_FEAT_VOLATILE_WC as usize] = 1;
+        let nr_queues = self.config.num_queues as u32 - 1;
+        self.features[NVME_FEAT_NUM_QUEUES as usize] = nr_queues << 16 | nr_queues;
+    }
+
+    /// Drop all the queues after the in-flight requests complete.
+    fn reset_queues(&mut self) {
+        for ns in self.namespaces.values() {
+            if let Err(e) = ns.drain() {
+                error!("Failed to drain nvme namespace {}: {:?}", ns.nsid, e);
+            }
+        }
+        for cq in self.cqs.iter_mut() {
+            if let Some(cq) = cq.take() {
+                cq.lock().unwrap().deleted = true;
+            }
+        }
+        for sq in self.sqs.iter_mut() {
+            *sq = None;
+        }
+        self.aer_reqs.clear();
+        self.broken.store(false, Ordering::SeqCst);
+    }
+
+    /// Reset the controller to the power on state.
+    pub fn reset(&mut self) {
+        self.reset_queues();
+        self.cc = 0;
+        self.csts = 0;
+        self.intms = 0;
+        self.aqa = 0;
+        self.asq = 0;
+        self.acq = 0;
+        self.reset_features();
+    }
+
+    fn page_size(&self) -> u64 {
+        1 << (12 + ((self.cc >> NVME_CC_MPS_SHIFT) & NVME_CC_MPS_MASK))
+    }
+
+    fn enable(&mut self) -> Result<()> {
+        let page_size = self.page_size();
+        if (self.cc >> NVME_CC_MPS_SHIFT) & NVME_CC_MPS_MASK > (self.cap >> 52) as u32 & 0xf {
+            bail!("Unsupported memory page size {}", page_size);
+        }
+        if (self.cc >> NVME_CC_IOSQES_SHIFT) & NVME_CC_QES_MASK != NVME_SQES
+            || (self.cc >> NVME_CC_IOCQES_SHIFT) & NVME_CC_QES_MASK != NVME_CQES
+        {
+            bail!("Unsupported queue entry size, cc {:#x}", self.cc);
+        }
+        if self.asq == 0
+            || self.acq == 0
+            || self.asq & (page_size - 1) != 0
+            || self.acq & (page_size - 1) != 0
+        {
+            bail!(
+                "Invalid admin queue address, asq {:#x} acq {:#x}",
+                self.asq,
+                self.acq
+            );
+        }
+        let sq_size = (self.aqa & 0xfff) as u16 + 1;
+        let cq_size = ((self.aqa >> 16) & 0xfff) as u16 + 1;
+        if sq_size < 2 || cq_size < 2 {
+            bail!("Invalid admin queue size, aqa {:#x}", self.aqa);
+        }
+
+        self.cqs[0] = Some(Arc::new(Mutex::new(CompletionQueue::new(
+            self.mem_space.clone(),
+            self.acq,
+            cq_size,
+            0,
+            true,
+            self.interrupt_cb.clone(),
+        ))));
+        self.sqs[0] = Some(SubmissionQueue::new(0, self.asq, sq_size));
+        self.csts = NVME_CSTS_RDY;
+        Ok(())
+    }
+
+    pub fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            NVME_REG_CAP => self.cap as u32,
+            o if o == NVME_REG_CAP + 4 => (self.cap >> 32) as u32,
+            NVME_REG_VS => NVME_VERSION,
+            NVME_REG_INTMS | NVME_REG_INTMC => self.intms,
+            NVME_REG_CC => self.cc,
+            NVME_REG_CSTS => {
+                let mut csts = self.csts;
+                if self.broken.load(Ordering::SeqCst) {
+                    csts |= NVME_CSTS_CFS;
+                }
+                csts
+            }
+            NVME_REG_AQA => self.aqa,
+            NVME_REG_ASQ => self.asq as u32,
+            o if o == NVME_REG_ASQ + 4 => (self.asq >> 32) as u32,
+            NVME_REG_ACQ => self.acq as u32,
+            o if o == NVME_REG_ACQ + 4 => (self.acq >> 32) as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write_reg(&mut self, offset: u64, value: u32) {
+        match offset {
+            NVME_REG_INTMS => self.intms |= value,
+            NVME_REG_INTMC => self.intms &= !value,
+            NVME_REG_CC => self.write_cc(value),
+            NVME_REG_AQA => self.aqa = value,
+            NVME_REG_ASQ => self.asq = (self.asq & !0xffff_ffff) | value as u64,
+            o if o == NVME_REG_ASQ + 4 => {
+                self.asq = (self.asq & 0xffff_ffff) | (value as u64) << 32
+            }
+            NVME_REG_ACQ => self.acq = (self.acq & !0xffff_ffff) | value as u64,
+            o if o == NVME_REG_ACQ + 4 => {
+                self.acq = (self.acq & 0xffff_ffff) | (value as u64) << 32
+            }
+            _ => warn!("Write to read-only nvme register {:#x}", offset),
+        }
+    }
+
+    fn write_cc(&mut self, value: u32) {
+        let old = self.cc;
+        self.cc = value;
+        if value & NVME_CC_EN != 0 && old & NVME_CC_EN == 0 {
+            if let Err(e) = self.enable() {
+                error!("Failed to enable nvme {}: {:?}", self.config.id, e);
+            }
+        } else if value & NVME_CC_EN == 0 && old & NVME_CC_EN != 0 {
+            self.reset_queues();
+            self.csts &= !NVME_CSTS_RDY;
+        }
+
+        if (value >> NVME_CC_SHN_SHIFT) & NVME_CC_SHN_MASK != 0 {
+            for ns in self.namespaces.values() {
+                if let Err(e) = ns.drain() {
+                    error!("Failed to drain nvme namespace {}: {:?}", ns.nsid, e);
+                }
+            }
+            self.csts |= NVME_CSTS_SHST_COMPLETE;
+        } else {
+            self.csts &= !NVME_CSTS_SHST_COMPLETE;
+        }
+    }
+
+    /// Doorbell registers start from 0x1000 with the stride of 4 bytes. The
+    /// even ones are tails of submission queues and the odd ones are heads of
+    /// completion queues.
+    pub fn write_doorbell(&mut self, offset: u64, value: u32) {
+        if self.csts & NVME_CSTS_RDY == 0 {
+            warn!("Write doorbell {:#x} when nvme is not ready", offset);
+            return;
+        }
+        let qid = (offset >> 3) as usize;
+        if (offset >> 2) & 1 == 1 {
+            let cq = match self.cqs.get(qid) {
+                Some(Some(cq)) => cq.clone(),
+                _ => {
+                    error!("Invalid nvme completion queue doorbell {}", qid);
+                    return;
+                }
+            };
+            let mut locked_cq = cq.lock().unwrap();
+            if value >= locked_cq.size() as u32 {
+                error!("Invalid head {} of nvme completion queue {}", value, qid);
+                return;
+            }
+            locked_cq.set_head(value as u16);
+        } else {
+            match self.sqs.get_mut(qid) {
+                Some(Some(sq)) if value < sq.size() as u32 => sq.set_tail(value as u16),
+                _ => {
+                    error!("Invalid nvme submission queue doorbell {} {}", qid, value);
+                    return;
+                }
+            }
+            self.process_sq(qid as u16);
+        }
+    }
+
+    fn process_sq(&mut self, sqid: u16) {
+        while let Some(sq) = self.sqs[sqid as usize].as_mut() {
+            let cmd = match sq.pop(&self.mem_space) {
+                Some(Ok(cmd)) => cmd,
+                Some(Err(e)) => {
+                    error!("{:?}", e);
+                    self.broken.store(true, Ordering::SeqCst);
+                    break;
+                }
+                None => break,
+            };
+            let cq = match self.cqs[sq.cqid as usize].as_ref() {
+                Some(cq) => cq.clone(),
+                None => break,
+            };
+            let req = NvmeRequest::new(cq, sqid, sq.head, cmd.cid);
+            if sqid == 0 {
+                self.handle_admin_cmd(&cmd, req);
+            } else {
+                self.handle_io_cmd(&cmd, req);
+            }
+        }
+
+        if sqid != 0 {
+            for ns in self.namespaces.values() {
+                if let Err(e) = ns.flush_request() {
+                    error!(
+                        "Failed to submit requests of nvme namespace {}: {:?}",
+                        ns.nsid, e
+                    );
+                }
+            }
+        }
+    }
+
+    fn handle_io_cmd(&self, cmd: &NvmeCmd, req: NvmeRequest) {
+        let res = match self.namespaces.get(&cmd.nsid) {
+            Some(ns) => ns.execute(
+                cmd,
+                &req,
+                &self.mem_space,
+                self.page_size(),
+                NVME_MIN_PAGE_SIZE << NVME_MDTS,
+            ),
+            None => Err(NVME_SC_INVALID_NSID),
+        };
+        match res {
+            Ok(()) => req.finish(0),
+            Err(status) => req.finish(status | NVME_DNR),
+        }
+    }
+
+    fn handle_admin_cmd(&mut self, cmd: &NvmeCmd, req: NvmeRequest) {
+        let res = match cmd.opcode {
+            NVME_ADM_DELETE_SQ => self.delete_sq(cmd),
+            NVME_ADM_CREATE_SQ => self.create_sq(cmd),
+            NVME_ADM_GET_LOG_PAGE => self.get_log_page(cmd),
+            NVME_ADM_DELETE_CQ => self.delete_cq(cmd),
+            NVME_ADM_CREATE_CQ => self.create_cq(cmd),
+            NVME_ADM_IDENTIFY => self.identify(cmd),
+            // The command is not found to abort, as it has been completed.
+            NVME_ADM_ABORT => Ok(1),
+            NVME_ADM_SET_FEATURES => self.set_features(cmd),
+            NVME_ADM_GET_FEATURES => self.get_features(cmd),
+            NVME_ADM_ASYNC_EVENT => {
+                if self.aer_reqs.len() > NVME_AERL as usize {
+                    Err(NVME_SC_AER_LIMIT)
+                } else {
+                    // No event is reported, the command is outstanding until reset.
+                    self.aer_reqs.push(req);
+                    return;
+                }
+            }
+            _ => Err(NVME_SC_INVALID_OPCODE),
+        };
+        match res {
+            Ok(dw0) => req.complete(0, dw0),
+            Err(status) => req.complete(status | NVME_DNR, 0),
+        }
+    }
+
+    /// Get the queue size of 0's based `qsize`, and check the attributes of
+    /// the queue to create.
+    fn check_create_queue(&self, cmd: &NvmeCmd) -> std::result::Result<u16, u16> {
+        let qsize = cmd.cdw10 >> 16;
+        if qsize == 0 || qsize as u64 > NVME_CAP_MQES {
+            return Err(NVME_SC_MAX_QSIZE);
+        }
+        // Physically contiguous queue is required.
+        if cmd.cdw11 & 1 == 0 {
+            return Err(NVME_SC_INVALID_FIELD);
+        }
+        if cmd.prp1 == 0 || cmd.prp1 & (self.page_size() - 1) != 0 {
+            return Err(NVME_SC_INVALID_PRP_OFFSET);
+        }
+        Ok(qsize as u16 + 1)
+    }
+
+    fn create_cq(&mut self, cmd: &NvmeCmd) -> NvmeResult {
+        let cqid = (cmd.cdw10 & 0xffff) as usize;
+        if cqid == 0 || cqid >= self.cqs.len() || self.cqs[cqid].is_some() {
+            return Err(NVME_SC_INVALID_QID);
+        }
+        let size = self.check_create_queue(cmd)?;
+        let vector = (cmd.cdw11 >> 16) as u16;
+        if vector as usize >= self.cqs.len() {
+            return Err(NVME_SC_INVALID_IRQ_VECTOR);
+        }
+        let irq_enabled = cmd.cdw11 & (1 << 1) != 0;
+        self.cqs[cqid] = Some(Arc::new(Mutex::new(CompletionQueue::new(
+            self.mem_space.clone(),
+            cmd.prp1,
+            size,
+            vector,
+            irq_enabled,
+            self.interrupt_cb.clone(),
+        ))));
+        Ok(0)
+    }
+
+    fn create_sq(&mut self, cmd: &NvmeCmd) -> NvmeResult {
+        let sqid = (cmd.cdw10 & 0xffff) as usize;
+        if sqid == 0 || sqid >= self.sqs.len() || self.sqs[sqid].is_some() {
+            return Err(NVME_SC_INVALID_QID);
+        }
+        let cqid = (cmd.cdw11 >> 16) as usize;
+        if cqid == 0 || !matches!(self.cqs.get(cqid), Some(Some(_))) {
+            return Err(NVME_SC_INVALID_CQID);
+        }
+        let size = self.check_create_queue(cmd)?;
+        self.sqs[sqid] = Some(SubmissionQueue::new(cqid as u16, cmd.prp1, size));
+        Ok(0)
+    }
+
+    fn delete_sq(&mut self, cmd: &NvmeCmd) -> NvmeResult {
+        let sqid = (cmd.cdw10 & 0xffff) as usize;
+        match self.sqs.get_mut(sqid) {
+            Some(sq) if sqid != 0 && sq.is_some() => {
+                *sq = None;
+                Ok(0)
+            }
+            _ => Err(NVME_SC_INVALID_QID),
+        }
+    }
+
+    fn delete_cq(&mut self, cmd: &NvmeCmd) -> NvmeResult {
+        let cqid = (cmd.cdw10 & 0xffff) as usize;
+        if cqid == 0 || !matches!(self.cqs.get(cqid), Some(Some(_))) {
+            return Err(NVME_SC_INVALID_QID);
+        }
+        if self.sqs.iter().flatten().any(|sq| sq.cqid as usize == cqid) {
+            return Err(NVME_SC_INVALID_QUEUE_DELETION);
+        }
+        if let Some(cq) = self.cqs[cqid].take() {
+            cq.lock().unwrap().deleted = true;
+        }
+        Ok(0)
+    }
+
+    /// Copy `data` to the buffer of the command.
+    fn write_data(&self, cmd: &NvmeCmd, data: &[u8]) -> NvmeResult {
+        let iovecs = map_prps(
+            &self.mem_space,
+            cmd.prp1,
+            cmd.prp2,
+            data.len() as u64,
+            self.page_size(),
+        )?;
+        iov_from_buf_direct(&iovecs, data).map_err(|_| NVME_SC_DATA_TRANSFER_ERROR)?;
+        Ok(0)
+    }
+
+    fn identify(&self, cmd: &NvmeCmd) -> NvmeResult {
+        let data = match cmd.cdw10 & 0xff {
+            NVME_ID_CNS_NS => {
+                if cmd.nsid == 0 || cmd.nsid > MAX_NVME_NAMESPACES {
+                    return Err(NVME_SC_INVALID_NSID);
+                }
+                // Inactive namespace returns zeros.
+                match self.namespaces.get(&cmd.nsid) {
+                    Some(ns) => ns.identify(),
+                    None => vec![0_u8; NVME_IDENTIFY_DATA_SIZE],
+                }
+            }
+            NVME_ID_CNS_CTRL => self.identify_ctrl(),
+            NVME_ID_CNS_NS_ACTIVE_LIST => {
+                if cmd.nsid >= 0xffff_fffe {
+                    return Err(NVME_SC_INVALID_NSID);
+                }
+                let mut data = vec![0_u8; NVME_IDENTIFY_DATA_SIZE];
+                for (i, nsid) in self
+                    .namespaces
+                    .range(cmd.nsid + 1..)
+                    .map(|(id, _)| id)
+                    .enumerate()
+                {
+                    le_write_u32(&mut data, i * 4, *nsid).unwrap();
+                }
+                data
+            }
+            NVME_ID_CNS_NS_DESCR_LIST => {
+                if !self.namespaces.contains_key(&cmd.nsid) {
+                    return Err(NVME_SC_INVALID_NSID);
+                }
+                // No namespace identification descriptor.
+                vec![0_u8; NVME_IDENTIFY_DATA_SIZE]
+            }
+            _ => return Err(NVME_SC_INVALID_FIELD),
+        };
+        self.write_data(cmd, &data)
+    }
+
+    /// Identify Controller data structure, NVMe spec 1.4 5.15.2.
+    fn identify_ctrl(&self) -> Vec<u8> {
+        let mut data = vec![0_u8; NVME_IDENTIFY_DATA_SIZE];
+        // VID and SSVID.
+        le_write_u16(&mut data, 0, crate::config::PCI_VENDOR_ID_REDHAT).unwrap();
+        le_write_u16(&mut data, 2, crate::config::PCI_VENDOR_ID_REDHAT).unwrap();
+        write_ascii(&mut data[4..24], &self.config.serial);
+        write_ascii(&mut data[24..64], NVME_MODEL_NUMBER);
+        write_ascii(&mut data[64..72], NVME_FIRMWARE_REVISION);
+        data[77] = NVME_MDTS;
+        le_write_u32(&mut data, 80, NVME_VERSION).unwrap();
+        // CNTRLTYPE: I/O controller.
+        data[111] = 1;
+        // ACL and AERL.
+        data[258] = 3;
+        data[259] = NVME_AERL;
+        // FRMW: slot 1 is read only, and there is 1 slot.
+        data[260] = 0x03;
+        // SQES and CQES: required and max entry sizes are the same.
+        data[512] = (NVME_SQES << 4 | NVME_SQES) as u8;
+        data[513] = (NVME_CQES << 4 | NVME_CQES) as u8;
+        le_write_u32(&mut data, 516, MAX_NVME_NAMESPACES).unwrap();
+        // ONCS: Dataset Management and Write Zeroes.
+        le_write_u16(&mut data, 520, 0x0c).unwrap();
+        // VWC: volatile write cache is present, flush with nsid 0xffffffff is
+        // not supported.
+        data[524] = 0x05;
+        let subnqn = format!("nqn.2019-08.org.stratovirt:{}", self.config.serial);
+        data[768..768 + subnqn.len()].copy_from_slice(subnqn.as_bytes());
+        // PSD0: max power 25W in 0.01W units.
+        le_write_u16(&mut data, 2048, 2500).unwrap();
+        data
+    }
+
+    fn get_log_page(&self, cmd: &NvmeCmd) -> NvmeResult {
+        let numd = ((cmd.cdw11 & 0xffff) << 16 | cmd.cdw10 >> 16) as u64 + 1;
+        let offset = (cmd.cdw13 as u64) << 32 | cmd.cdw12 as u64;
+        let mut log = match cmd.cdw10 & 0xff {
+            NVME_LOG_ERROR_INFO => vec![0_u8; 64],
+            NVME_LOG_SMART_INFO => {
+                let mut log = vec![0_u8; 512];
+                le_write_u16(&mut log, 1, NVME_TEMPERATURE).unwrap();
+                // Available spare and its threshold in percent.
+                log[3] = 100;
+                log[4] = 10;
+                log
+            }
+            NVME_LOG_FW_SLOT_INFO => {
+                let mut log = vec![0_u8; 512];
+                // Firmware in slot 1 is active.
+                log[0] = 1;
+                write_ascii(&mut log[8..16], NVME_FIRMWARE_REVISION);
+                log
+            }
+            _ => return Err(NVME_SC_INVALID_LOG_PAGE),
+        };
+        if offset & 0x3 != 0 || offset > log.len() as u64 {
+            return Err(NVME_SC_INVALID_FIELD);
+        }
+        let end = std::cmp::min(log.len() as u64, offset + numd * 4) as usize;
+        self.write_data(
+            cmd,
+            &log.split_off(offset as usize)[..end - offset as usize],
+        )
+    }
+
+    fn get_features(&self, cmd: &NvmeCmd) -> NvmeResult {
+        let fid = cmd.cdw10 & 0xff;
+        match fid {
+            NVME_FEAT_ARBITRATION..=NVME_FEAT_ASYNC_EVENT_CONFIG if fid != 0x03 => {
+                if fid == NVME_FEAT_IRQ_VECTOR_CONFIG {
+                    // Interrupt coalescing is not disabled for the vector.
+                    return Ok(cmd.cdw11 & 0xffff);
+                }
+                Ok(self.features[fid as usize])
+            }
+            _ => Err(NVME_SC_INVALID_FIELD),
+        }
+    }
+
+    fn set_features(&mut self, cmd: &NvmeCmd) -> NvmeResult {
+        if cmd.cdw10 & NVME_FEAT_SAVE != 0 {
+            return Err(NVME_SC_FEATURE_NOT_SAVEABLE);
+        }
+        let fid = cmd.cdw10 & 0xff;
+        match fid {
+            NVME_FEAT_NUM_QUEUES => {
+                if cmd.cdw11 & 0xffff == 0xffff || cmd.cdw11 >> 16 == 0xffff {
+                    return Err(NVME_SC_INVALID_FIELD);
+                }
+                // The number of queues allocated is fixed.
+                Ok(self.features[fid as usize])
+            }
+            NVME_FEAT_ARBITRATION..=NVME_FEAT_ASYNC_EVENT_CONFIG if fid != 0x03 => {
+                self.features[fid as usize] = cmd.cdw11;
+                Ok(0)
+            }
+            _ => Err(NVME_SC_INVALID_FIELD),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvme::namespace::NVME_DSM_AD;
+    use crate::nvme::queue::{NvmeCqe, NVME_CQE_SIZE, NVME_SQE_SIZE};
+    use crate::nvme::{create_mem_space, NVME_SC_LBA_RANGE};
+    use address_space::GuestAddress;
+    use machine_manager::config::VmConfig;
+    use machine_manager::event_loop::EventLoop;
+    use util::aio::AioEngine;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const ASQ: u64 = 0x10000;
+    const ACQ: u64 = 0x11000;
+    const DATA: u64 = 0x20000;
+
+    fn create_ctrl(mem_space: &Arc<AddressSpace>) -> NvmeCtrl {
+        let config = NvmeConfig {
+            id: "nvme0".to_string(),
+            serial: "1234".to_string(),
+            num_queues: 4,
+            namespace: None,
+        };
+        let mut ctrl = NvmeCtrl::new(&config, mem_space, Arc::new(Mutex::new(HashMap::new())));
+        ctrl.write_reg(NVME_REG_AQA, 7 << 16 | 7);
+        ctrl.write_reg(NVME_REG_ASQ, ASQ as u32);
+        ctrl.write_reg(NVME_REG_ACQ, ACQ as u32);
+        ctrl.write_reg(
+            NVME_REG_CC,
+            NVME_CQES << NVME_CC_IOCQES_SHIFT | NVME_SQES << NVME_CC_IOSQES_SHIFT | NVME_CC_EN,
+        );
+        assert_eq!(ctrl.read_reg(NVME_REG_CSTS) & NVME_CSTS_RDY, NVME_CSTS_RDY);
+        ctrl
+    }
+
+    /// Submit the admin command in slot `idx` and get its completion.
+    fn admin_cmd(ctrl: &mut NvmeCtrl, mem_space: &AddressSpace, idx: u64, cmd: NvmeCmd) -> NvmeCqe {
+        mem_space
+            .write_object(&cmd, GuestAddress(ASQ + idx * NVME_SQE_SIZE))
+            .unwrap();
+        ctrl.write_doorbell(0, idx as u32 + 1);
+        mem_space
+            .read_object::<NvmeCqe>(GuestAddress(ACQ + idx * NVME_CQE_SIZE))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_nvme_registers() {
+        let mem_space = create_mem_space();
+        let mut ctrl = create_ctrl(&mem_space);
+        assert_eq!(ctrl.read_reg(NVME_REG_VS), NVME_VERSION);
+        assert_eq!(ctrl.read_reg(NVME_REG_CAP) & 0xffff, NVME_CAP_MQES as u32);
+        assert_eq!(ctrl.read_reg(NVME_REG_CAP + 4) >> 20 & 0xf, 4);
+        assert!(ctrl.cqs[0].is_some());
+
+        // Shutdown and disable.
+        ctrl.write_reg(NVME_REG_CC, ctrl.cc | 1 << NVME_CC_SHN_SHIFT);
+        assert_ne!(ctrl.read_reg(NVME_REG_CSTS) & NVME_CSTS_SHST_COMPLETE, 0);
+        ctrl.write_reg(NVME_REG_CC, 0);
+        assert_eq!(ctrl.read_reg(NVME_REG_CSTS), 0);
+        assert!(ctrl.cqs[0].is_none());
+
+        // Admin queue of 1 entry is invalid.
+        ctrl.write_reg(NVME_REG_AQA, 0);
+        ctrl.write_reg(
+            NVME_REG_CC,
+            NVME_CQES << NVME_CC_IOCQES_SHIFT | NVME_SQES << NVME_CC_IOSQES_SHIFT | NVME_CC_EN,
+        );
+        assert_eq!(ctrl.read_reg(NVME_REG_CSTS) & NVME_CSTS_RDY, 0);
+    }
+
+    #[test]
+    fn test_nvme_admin_cmds() {
+        let mem_space = create_mem_space();
+        let mut ctrl = create_ctrl(&mem_space);
+
+        // Identify controller.
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_IDENTIFY,
+            cid: 1,
+            prp1: DATA,
+            cdw10: NVME_ID_CNS_CTRL,
+            ..Default::default()
+        };
+        let cqe = admin_cmd(&mut ctrl, &mem_space, 0, cmd);
+        assert_eq!(cqe.cid, 1);
+        assert_eq!(cqe.status, 1);
+        let mut sn = [0_u8; 20];
+        mem_space
+            .read(&mut sn.as_mut_slice(), GuestAddress(DATA + 4), 20)
+            .unwrap();
+        assert_eq!(&sn[..6], b"1234  ");
+
+        // Create completion queue 1 and submission queue 1.
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_CREATE_CQ,
+            cid: 2,
+            prp1: 0x30000,
+            cdw10: 63 << 16 | 1,
+            cdw11: 1 << 16 | 0x3,
+            ..Default::default()
+        };
+        assert_eq!(admin_cmd(&mut ctrl, &mem_space, 1, cmd).status, 1);
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_CREATE_SQ,
+            cid: 3,
+            prp1: 0x31000,
+            cdw10: 63 << 16 | 1,
+            cdw11: 1 << 16 | 0x1,
+            ..Default::default()
+        };
+        assert_eq!(admin_cmd(&mut ctrl, &mem_space, 2, cmd).status, 1);
+
+        // The completion queue is in use.
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_DELETE_CQ,
+            cid: 4,
+            cdw10: 1,
+            ..Default::default()
+        };
+        let cqe = admin_cmd(&mut ctrl, &mem_space, 3, cmd);
+        assert_eq!(
+            cqe.status,
+            (NVME_SC_INVALID_QUEUE_DELETION | NVME_DNR) << 1 | 1
+        );
+
+        // Queue id exceeds the number of queues.
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_CREATE_CQ,
+            cid: 5,
+            prp1: 0x32000,
+            cdw10: 63 << 16 | 5,
+            cdw11: 0x3,
+            ..Default::default()
+        };
+        let cqe = admin_cmd(&mut ctrl, &mem_space, 4, cmd);
+        assert_eq!(cqe.status, (NVME_SC_INVALID_QID | NVME_DNR) << 1 | 1);
+
+        // Number of queues allocated.
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_SET_FEATURES,
+            cid: 6,
+            cdw10: NVME_FEAT_NUM_QUEUES,
+            cdw11: 0xff << 16 | 0xff,
+            ..Default::default()
+        };
+        let cqe = admin_cmd(&mut ctrl, &mem_space, 5, cmd);
+        assert_eq!(cqe.status, 1);
+        assert_eq!(cqe.dw0, 3 << 16 | 3);
+
+        // Identify namespace of invalid nsid.
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_IDENTIFY,
+            cid: 7,
+            prp1: DATA,
+            cdw10: NVME_ID_CNS_NS,
+            ..Default::default()
+        };
+        let cqe = admin_cmd(&mut ctrl, &mem_space, 6, cmd);
+        assert_eq!(cqe.status, (NVME_SC_INVALID_NSID | NVME_DNR) << 1 | 1);
+    }
+
+    #[test]
+    fn test_nvme_io_cmds() {
+        EventLoop::object_init(&None).unwrap();
+        let mem_space = create_mem_space();
+        let mut ctrl = create_ctrl(&mem_space);
+
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(0x10_0000).unwrap();
+        let path = file.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(&mut ctrl.drive_files.lock().unwrap(), &path, false, false)
+            .unwrap();
+        let ns_config = NvmeNsConfig {
+            id: "ns1".to_string(),
+            bus: "nvme0".to_string(),
+            path_on_host: path,
+            direct: false,
+            aio: AioEngine::Off,
+            ..Default::default()
+        };
+        ctrl.attach_namespace(&ns_config).unwrap();
+        assert!(ctrl
+            .attach_namespace(&NvmeNsConfig {
+                nsid: Some(1),
+                ..ns_config.clone()
+            })
+            .is_err());
+
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_IDENTIFY,
+            nsid: 1,
+            prp1: DATA,
+            ..Default::default()
+        };
+        assert_eq!(admin_cmd(&mut ctrl, &mem_space, 0, cmd).status, 1);
+        assert_eq!(
+            mem_space.read_object::<u64>(GuestAddress(DATA)).unwrap(),
+            0x10_0000 >> 9
+        );
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_CREATE_CQ,
+            prp1: 0x30000,
+            cdw10: 63 << 16 | 1,
+            cdw11: 1 << 16 | 0x3,
+            ..Default::default()
+        };
+        assert_eq!(admin_cmd(&mut ctrl, &mem_space, 1, cmd).status, 1);
+        let cmd = NvmeCmd {
+            opcode: NVME_ADM_CREATE_SQ,
+            prp1: 0x31000,
+            cdw10: 63 << 16 | 1,
+            cdw11: 1 << 16 | 0x1,
+            ..Default::default()
+        };
+        assert_eq!(admin_cmd(&mut ctrl, &mem_space, 2, cmd).status, 1);
+
+        let io_cmd = |ctrl: &mut NvmeCtrl, idx: u64, cmd: NvmeCmd| -> NvmeCqe {
+            mem_space
+                .write_object(&cmd, GuestAddress(0x31000 + idx * NVME_SQE_SIZE))
+                .unwrap();
+            ctrl.write_doorbell(8, idx as u32 + 1);
+            mem_space
+                .read_object::<NvmeCqe>(GuestAddress(0x30000 + idx * NVME_CQE_SIZE))
+                .unwrap()
+        };
+
+        // Write 2 blocks at lba 8 and read them back.
+        mem_space
+            .write(&mut [0xa5_u8; 1024].as_ref(), GuestAddress(0x40000), 1024)
+            .unwrap();
+        let cmd = NvmeCmd {
+            opcode: 0x01,
+            cid: 1,
+            nsid: 1,
+            prp1: 0x40000,
+            cdw10: 8,
+            cdw12: 1,
+            ..Default::default()
+        };
+        assert_eq!(io_cmd(&mut ctrl, 0, cmd).status, 1);
+        let cmd = NvmeCmd {
+            opcode: 0x02,
+            cid: 2,
+            nsid: 1,
+            prp1: 0x50000,
+            cdw10: 8,
+            cdw12: 1,
+            ..Default::default()
+        };
+        let cqe = io_cmd(&mut ctrl, 1, cmd);
+        assert_eq!(cqe.cid, 2);
+        assert_eq!(cqe.status, 1);
+        let mut buf = [0_u8; 1024];
+        mem_space
+            .read(&mut buf.as_mut_slice(), GuestAddress(0x50000), 1024)
+            .unwrap();
+        assert_eq!(buf, [0xa5_u8; 1024]);
+
+        // Read out of the namespace.
+        let cmd = NvmeCmd {
+            opcode: 0x02,
+            cid: 3,
+            nsid: 1,
+            prp1: 0x50000,
+            cdw10: 0x800,
+            ..Default::default()
+        };
+        let cqe = io_cmd(&mut ctrl, 2, cmd);
+        assert_eq!(cqe.status, (NVME_SC_LBA_RANGE | NVME_DNR) << 1 | 1);
+
+        // Deallocate the blocks written, which read as zeros.
+        mem_space
+            .write_object(&2_u32, GuestAddress(0x60004))
+            .unwrap();
+        mem_space
+            .write_object(&8_u64, GuestAddress(0x60008))
+            .unwrap();
+        let cmd = NvmeCmd {
+            opcode: 0x09,
+            cid: 4,
+            nsid: 1,
+            prp1: 0x60000,
+            cdw11: NVME_DSM_AD,
+            ..Default::default()
+        };
+        assert_eq!(io_cmd(&mut ctrl, 3, cmd).status, 1);
+        let cmd = NvmeCmd {
+            opcode: 0x02,
+            cid: 5,
+            nsid: 1,
+            prp1: 0x50000,
+            cdw10: 8,
+            cdw12: 1,
+            ..Default::default()
+        };
+        assert_eq!(io_cmd(&mut ctrl, 4, cmd).status, 1);
+        mem_space
+            .read(&mut buf.as_mut_slice(), GuestAddress(0x50000), 1024)
+            .unwrap();
+        assert_eq!(buf, [0_u8; 1024]);
+
+        // Invalid namespace.
+        let cmd = NvmeCmd {
+            opcode: 0x00,
+            cid: 6,
+            nsid: 2,
+            ..Default::default()
+        };
+        let cqe = io_cmd(&mut ctrl, 5, cmd);
+        assert_eq!(cqe.status, (NVME_SC_INVALID_NSID | NVME_DNR) << 1 | 1);
+
+        ctrl.reset();
+        ctrl.detach_namespaces().unwrap();
+    }
+}
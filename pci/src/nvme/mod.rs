@@ -0,0 +1,303 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Emulated NVMe controller, which follows NVM Express Base Specification 1.4.
+//!
+//! The controller supports the mandatory admin commands, and the Read, Write,
+//! Flush, Write Zeroes and Dataset Management IO commands. Each namespace is
+//! backed by a drive, the requests are handled by the aio engine of the drive.
+
+mod ctrl;
+mod namespace;
+mod queue;
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use anyhow::{bail, Context, Result};
+use log::warn;
+use machine_manager::config::{DriveFile, NvmeConfig, NvmeNsConfig};
+use util::num_ops::{read_data_u32, write_data_u32};
+
+use crate::config::{
+    PciConfig, RegionType, DEVICE_ID, MINMUM_BAR_SIZE_FOR_MMIO, PCI_CLASS_STORAGE_EXPRESS,
+    PCI_CONFIG_SPACE_SIZE, PCI_DEVICE_ID_REDHAT_NVME, PCI_VENDOR_ID_REDHAT, REVISION_ID,
+    SUB_CLASS_CODE, VENDOR_ID,
+};
+use crate::msix::update_dev_id;
+use crate::{init_msix, le_write_u16, PciBus, PciDevOps};
+use ctrl::NvmeCtrl;
+
+/// Status codes of completion queue entry, the status code type is in bits 8~10.
+const NVME_SC_INVALID_OPCODE: u16 = 0x01;
+const NVME_SC_INVALID_FIELD: u16 = 0x02;
+const NVME_SC_DATA_TRANSFER_ERROR: u16 = 0x04;
+const NVME_SC_INTERNAL: u16 = 0x06;
+const NVME_SC_INVALID_NSID: u16 = 0x0b;
+const NVME_SC_INVALID_PRP_OFFSET: u16 = 0x13;
+const NVME_SC_WRITE_PROTECTED: u16 = 0x20;
+const NVME_SC_LBA_RANGE: u16 = 0x80;
+const NVME_SC_INVALID_CQID: u16 = 0x100;
+const NVME_SC_INVALID_QID: u16 = 0x101;
+const NVME_SC_MAX_QSIZE: u16 = 0x102;
+const NVME_SC_AER_LIMIT: u16 = 0x105;
+const NVME_SC_INVALID_IRQ_VECTOR: u16 = 0x108;
+const NVME_SC_INVALID_LOG_PAGE: u16 = 0x109;
+const NVME_SC_INVALID_QUEUE_DELETION: u16 = 0x10c;
+const NVME_SC_FEATURE_NOT_SAVEABLE: u16 = 0x10d;
+const NVME_SC_WRITE_FAULT: u16 = 0x280;
+const NVME_SC_UNRECOVERED_READ: u16 = 0x281;
+/// Do not retry the command.
+const NVME_DNR: u16 = 0x4000;
+
+/// Size of the data structure returned by Identify command.
+const NVME_IDENTIFY_DATA_SIZE: usize = 4096;
+
+/// Callback to send the interrupt of the vector.
+pub type NvmeInterruptCb = Arc<dyn Fn(u16) + Send + Sync>;
+
+/// Programming interface of NVM Express.
+const PCI_CLASS_PI: usize = 0x09;
+const PCI_PROG_IF_NVME: u8 = 0x02;
+
+/// Registers offset in BAR 0.
+/// 0x0       0x1000      0x2000        0x3000     0x4000
+/// | regs    | doorbell  | MSIX table  | MSIX PBA |
+const NVME_BAR_SIZE: u64 = 0x4000;
+const NVME_REG_SIZE: u64 = 0x2000;
+const NVME_DOORBELL_OFFSET: u64 = 0x1000;
+const NVME_MSIX_TABLE_OFFSET: u32 = 0x2000;
+const NVME_MSIX_PBA_OFFSET: u32 = 0x3000;
+
+/// NVMe controller which can be attached to PCI bus.
+pub struct NvmePciDevice {
+    pci_config: PciConfig,
+    devfn: u8,
+    ctrl: Arc<Mutex<NvmeCtrl>>,
+    config: NvmeConfig,
+    dev_id: Arc<AtomicU16>,
+    parent_bus: Weak<Mutex<PciBus>>,
+    mem_region: Region,
+}
+
+impl NvmePciDevice {
+    pub fn new(
+        config: &NvmeConfig,
+        devfn: u8,
+        parent_bus: Weak<Mutex<PciBus>>,
+        mem_space: &Arc<AddressSpace>,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    ) -> Self {
+        Self {
+            pci_config: PciConfig::new(PCI_CONFIG_SPACE_SIZE, 1),
+            devfn,
+            ctrl: Arc::new(Mutex::new(NvmeCtrl::new(config, mem_space, drive_files))),
+            config: config.clone(),
+            dev_id: Arc::new(AtomicU16::new(0)),
+            parent_bus,
+            mem_region: Region::init_container_region(NVME_BAR_SIZE),
+        }
+    }
+
+    /// Attach the namespace `config` to the controller.
+    pub fn attach_namespace(&self, config: &NvmeNsConfig) -> Result<()> {
+        self.ctrl
+            .lock()
+            .unwrap()
+            .attach_namespace(config)
+            .with_context(|| format!("Failed to attach namespace {}", config.id))
+    }
+
+    fn build_reg_ops(&self) -> RegionOps {
+        let ctrl = self.ctrl.clone();
+        let reg_read = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+            let value = if offset < NVME_DOORBELL_OFFSET {
+                ctrl.lock().unwrap().read_reg(offset)
+            } else {
+                // Doorbell registers are write only.
+                0
+            };
+            write_data_u32(data, value)
+        };
+
+        let ctrl = self.ctrl.clone();
+        let reg_write = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+            let mut value = 0;
+            if !read_data_u32(data, &mut value) {
+                return false;
+            }
+            let mut locked_ctrl = ctrl.lock().unwrap();
+            if offset < NVME_DOORBELL_OFFSET {
+                locked_ctrl.write_reg(offset, value);
+            } else {
+                locked_ctrl.write_doorbell(offset - NVME_DOORBELL_OFFSET, value);
+            }
+            true
+        };
+
+        RegionOps {
+            read: Arc::new(reg_read),
+            write: Arc::new(reg_write),
+        }
+    }
+}
+
+impl PciDevOps for NvmePciDevice {
+    fn init_write_mask(&mut self) -> Result<()> {
+        self.pci_config.init_common_write_mask()
+    }
+
+    fn init_write_clear_mask(&mut self) -> Result<()> {
+        self.pci_config.init_common_write_clear_mask()
+    }
+
+    fn realize(mut self) -> Result<()> {
+        self.init_write_mask()?;
+        self.init_write_clear_mask()?;
+        let config = &mut self.pci_config.config;
+        le_write_u16(config, VENDOR_ID as usize, PCI_VENDOR_ID_REDHAT)?;
+        le_write_u16(config, DEVICE_ID as usize, PCI_DEVICE_ID_REDHAT_NVME)?;
+        config[REVISION_ID] = 0x02;
+        le_write_u16(config, SUB_CLASS_CODE as usize, PCI_CLASS_STORAGE_EXPRESS)?;
+        config[PCI_CLASS_PI] = PCI_PROG_IF_NVME;
+        self.dev_id.store(self.devfn as u16, Ordering::SeqCst);
+
+        if let Some(ns_config) = self.config.namespace.as_ref() {
+            self.attach_namespace(ns_config)?;
+        }
+
+        let mut reg_region = Region::init_io_region(NVME_REG_SIZE, self.build_reg_ops());
+        reg_region.set_access_size(4);
+        self.mem_region
+            .add_subregion(reg_region, 0)
+            .with_context(|| "Failed to register nvme register region.")?;
+
+        // One vector for the admin completion queue and each IO completion queue.
+        init_msix(
+            0_usize,
+            self.config.num_queues as u32 + 1,
+            &mut self.pci_config,
+            self.dev_id.clone(),
+            &self.config.id,
+            Some(&self.mem_region),
+            Some((NVME_MSIX_TABLE_OFFSET, NVME_MSIX_PBA_OFFSET)),
+        )?;
+
+        let bar_size = max(NVME_BAR_SIZE, MINMUM_BAR_SIZE_FOR_MMIO as u64);
+        self.pci_config.register_bar(
+            0_usize,
+            self.mem_region.clone(),
+            RegionType::Mem64Bit,
+            false,
+            bar_size,
+        )?;
+
+        // It is safe to unwrap, because it is initialized in init_msix.
+        let cloned_msix = self.pci_config.msix.as_ref().unwrap().clone();
+        let cloned_dev_id = self.dev_id.clone();
+        self.ctrl.lock().unwrap().interrupt_cb = Some(Arc::new(move |vector: u16| {
+            cloned_msix
+                .lock()
+                .unwrap()
+                .notify(vector, cloned_dev_id.load(Ordering::Acquire));
+        }));
+
+        let devfn = self.devfn;
+        let dev = Arc::new(Mutex::new(self));
+        // Attach to the PCI bus.
+        let pci_bus = dev.lock().unwrap().parent_bus.upgrade().unwrap();
+        let mut locked_pci_bus = pci_bus.lock().unwrap();
+        if let Some(pci_device) = locked_pci_bus.devices.get(&devfn) {
+            bail!(
+                "Devfn {:?} has been used by {:?}",
+                &devfn,
+                pci_device.lock().unwrap().name()
+            );
+        }
+        locked_pci_bus.devices.insert(devfn, dev);
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> Result<()> {
+        let mut locked_ctrl = self.ctrl.lock().unwrap();
+        locked_ctrl.reset();
+        locked_ctrl.detach_namespaces()
+    }
+
+    fn devfn(&self) -> Option<u8> {
+        Some(self.devfn)
+    }
+
+    fn read_config(&mut self, offset: usize, data: &mut [u8]) {
+        self.pci_config.read(offset, data);
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        update_dev_id(&self.parent_bus, self.devfn, &self.dev_id);
+        let parent_bus = match self.parent_bus.upgrade() {
+            Some(bus) => bus,
+            None => {
+                warn!("Parent bus of nvme {} is dropped", self.config.id);
+                return;
+            }
+        };
+        let locked_parent_bus = parent_bus.lock().unwrap();
+
+        self.pci_config.write(
+            offset,
+            data,
+            self.dev_id.load(Ordering::Acquire),
+            #[cfg(target_arch = "x86_64")]
+            Some(&locked_parent_bus.io_region),
+            Some(&locked_parent_bus.mem_region),
+        );
+    }
+
+    fn name(&self) -> String {
+        self.config.id.clone()
+    }
+
+    fn reset(&mut self, _reset_child_device: bool) -> Result<()> {
+        self.ctrl.lock().unwrap().reset();
+        self.pci_config.reset()
+    }
+}
+
+/// Create the address space with 1M bytes of ram at address 0 for tests.
+#[cfg(test)]
+fn create_mem_space() -> Arc<AddressSpace> {
+    let root = Region::init_container_region(1 << 36);
+    let sys_space = AddressSpace::new(root).unwrap();
+    let host_mmap = Arc::new(
+        address_space::HostMemMapping::new(
+            GuestAddress(0),
+            None,
+            0x10_0000,
+            None,
+            false,
+            false,
+            false,
+        )
+        .unwrap(),
+    );
+    sys_space
+        .root()
+        .add_subregion(
+            Region::init_ram_region(host_mmap.clone()),
+            host_mmap.start_address().raw_value(),
+        )
+        .unwrap();
+    sys_space
+}
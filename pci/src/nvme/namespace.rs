@@ -0,0 +1,285 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use log::error;
+
+use super::queue::{map_prps, NvmeCmd, NvmeRequest};
+use super::{
+    NVME_IDENTIFY_DATA_SIZE, NVME_SC_DATA_TRANSFER_ERROR, NVME_SC_INTERNAL, NVME_SC_INVALID_FIELD,
+    NVME_SC_INVALID_OPCODE, NVME_SC_LBA_RANGE, NVME_SC_UNRECOVERED_READ, NVME_SC_WRITE_FAULT,
+    NVME_SC_WRITE_PROTECTED,
+};
+use crate::{le_read_u32, le_read_u64, le_write_u32, le_write_u64};
+use address_space::AddressSpace;
+use block_backend::{
+    create_block_backend, create_nbd_backend, BlockDriverOps, BlockIoErrorCallback, BlockProperty,
+};
+use machine_manager::config::{is_nbd_path, DriveFile, NbdUri, NvmeNsConfig, VmConfig};
+use util::aio::{iov_to_buf_direct, Aio, AioCb, OpCode};
+
+/// IO command set opcodes, NVMe spec 1.4 6.
+const NVME_CMD_FLUSH: u8 = 0x00;
+const NVME_CMD_WRITE: u8 = 0x01;
+const NVME_CMD_READ: u8 = 0x02;
+const NVME_CMD_WRITE_ZEROES: u8 = 0x08;
+const NVME_CMD_DSM: u8 = 0x09;
+
+/// Logical block size is 512 bytes.
+pub const NVME_LBA_SHIFT: u64 = 9;
+/// Deallocate bit of Write Zeroes command in cdw12.
+const NVME_WRITE_ZEROES_DEAC: u32 = 1 << 25;
+/// Attribute-Deallocate bit of Dataset Management command in cdw11.
+pub const NVME_DSM_AD: u32 = 1 << 2;
+/// Size of the range descriptor of Dataset Management command.
+const NVME_DSM_RANGE_SIZE: usize = 16;
+
+/// Called when the aio request of the namespace finishes.
+fn nvme_aio_complete(aiocb: &AioCb<NvmeRequest>, ret: i64) -> Result<()> {
+    let status = if ret < 0 {
+        match aiocb.opcode {
+            OpCode::Preadv => NVME_SC_UNRECOVERED_READ,
+            OpCode::Fdsync => NVME_SC_INTERNAL,
+            _ => NVME_SC_WRITE_FAULT,
+        }
+    } else {
+        0
+    };
+    aiocb.iocompletecb.finish(status);
+    Ok(())
+}
+
+pub struct NvmeNamespace {
+    pub nsid: u32,
+    pub config: NvmeNsConfig,
+    /// Size of the namespace in logical blocks.
+    pub nsze: u64,
+    backend: Arc<Mutex<dyn BlockDriverOps<NvmeRequest>>>,
+}
+
+impl NvmeNamespace {
+    pub fn new(
+        nsid: u32,
+        config: NvmeNsConfig,
+        drive_files: &Arc<Mutex<HashMap<String, DriveFile>>>,
+        broken: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let aio = Aio::new(Arc::new(nvme_aio_complete), config.aio)?;
+        let backend = if is_nbd_path(&config.path_on_host) {
+            let uri = config.path_on_host.parse::<NbdUri>()?;
+            let prop = BlockProperty {
+                id: config.id.clone(),
+                format: config.format,
+                iothread: None,
+                direct: false,
+                req_align: 1,
+                buf_align: 1,
+                copy_on_read: false,
+            };
+            create_nbd_backend(&uri, config.read_only, aio, prop)?
+        } else {
+            let (file, alignments) = {
+                let drive_files = drive_files.lock().unwrap();
+                (
+                    VmConfig::fetch_drive_file(&drive_files, &config.path_on_host)?,
+                    VmConfig::fetch_drive_align(&drive_files, &config.path_on_host)?,
+                )
+            };
+            let prop = BlockProperty {
+                id: config.id.clone(),
+                format: config.format,
+                iothread: None,
+                direct: config.direct,
+                req_align: alignments.0,
+                buf_align: alignments.1,
+                copy_on_read: false,
+            };
+            create_block_backend(file, aio, prop)?
+        };
+        let nsze = backend.lock().unwrap().disk_size()? >> NVME_LBA_SHIFT;
+
+        let id = config.id.clone();
+        let cloned_broken = broken.clone();
+        let error_cb: BlockIoErrorCallback = Arc::new(move || {
+            error!("Backend of nvme namespace {} is broken", id);
+            cloned_broken.store(true, Ordering::SeqCst);
+        });
+        backend
+            .lock()
+            .unwrap()
+            .register_io_event(broken, error_cb)
+            .with_context(|| format!("Failed to register io event of nvme namespace {}", nsid))?;
+
+        Ok(Self {
+            nsid,
+            config,
+            nsze,
+            backend,
+        })
+    }
+
+    /// Complete all the in-flight requests.
+    pub fn drain(&self) -> Result<()> {
+        self.backend.lock().unwrap().drain_request()
+    }
+
+    /// Submit the requests queued in the aio context.
+    pub fn flush_request(&self) -> Result<()> {
+        self.backend.lock().unwrap().flush_request()
+    }
+
+    pub fn unregister_io_event(&self) -> Result<()> {
+        self.backend.lock().unwrap().unregister_io_event()
+    }
+
+    /// Identify Namespace data structure, NVMe spec 1.4 5.15.2.
+    pub fn identify(&self) -> Vec<u8> {
+        let mut data = vec![0_u8; NVME_IDENTIFY_DATA_SIZE];
+        // NSZE, NCAP and NUSE.
+        le_write_u64(&mut data, 0, self.nsze).unwrap();
+        le_write_u64(&mut data, 8, self.nsze).unwrap();
+        le_write_u64(&mut data, 16, self.nsze).unwrap();
+        // DLFEAT: the deallocated blocks read as zeros, and Write Zeroes can deallocate.
+        data[33] = 0x09;
+        // NSATTR: the namespace is write protected.
+        data[99] = self.config.read_only as u8;
+        // NGUID, unique in the controller.
+        le_write_u32(&mut data, 104, self.nsid).unwrap();
+        // LBAF0: LBADS in bits 16~23.
+        le_write_u32(&mut data, 128, (NVME_LBA_SHIFT as u32) << 16).unwrap();
+        data
+    }
+
+    fn check_range(&self, slba: u64, nlb: u64) -> std::result::Result<(), u16> {
+        match slba.checked_add(nlb) {
+            Some(end) if end <= self.nsze => Ok(()),
+            _ => Err(NVME_SC_LBA_RANGE),
+        }
+    }
+
+    fn check_writable(&self) -> std::result::Result<(), u16> {
+        if self.config.read_only {
+            return Err(NVME_SC_WRITE_PROTECTED);
+        }
+        Ok(())
+    }
+
+    /// Submit the IO command to the backend, the parts of `req` are finished
+    /// when the aio requests complete.
+    pub fn execute(
+        &self,
+        cmd: &NvmeCmd,
+        req: &NvmeRequest,
+        mem_space: &AddressSpace,
+        page_size: u64,
+        max_transfer: u64,
+    ) -> std::result::Result<(), u16> {
+        let slba = (cmd.cdw11 as u64) << 32 | cmd.cdw10 as u64;
+        let nlb = (cmd.cdw12 & 0xffff) as u64 + 1;
+        let offset = (slba << NVME_LBA_SHIFT) as usize;
+        let (res, part) = match cmd.opcode {
+            NVME_CMD_FLUSH => {
+                let part = req.part();
+                (self.backend.lock().unwrap().datasync(part.clone()), part)
+            }
+            NVME_CMD_WRITE | NVME_CMD_READ => {
+                if cmd.opcode == NVME_CMD_WRITE {
+                    self.check_writable()?;
+                }
+                self.check_range(slba, nlb)?;
+                let len = nlb << NVME_LBA_SHIFT;
+                if len > max_transfer {
+                    return Err(NVME_SC_INVALID_FIELD);
+                }
+                let iovecs = map_prps(mem_space, cmd.prp1, cmd.prp2, len, page_size)?;
+                let part = req.part();
+                let mut locked_backend = self.backend.lock().unwrap();
+                let res = if cmd.opcode == NVME_CMD_WRITE {
+                    locked_backend.write_vectored(iovecs, offset, part.clone())
+                } else {
+                    locked_backend.read_vectored(iovecs, offset, part.clone())
+                };
+                (res, part)
+            }
+            NVME_CMD_WRITE_ZEROES => {
+                self.check_writable()?;
+                self.check_range(slba, nlb)?;
+                let unmap = cmd.cdw12 & NVME_WRITE_ZEROES_DEAC != 0;
+                let part = req.part();
+                (
+                    self.backend.lock().unwrap().write_zeroes(
+                        offset,
+                        nlb << NVME_LBA_SHIFT,
+                        part.clone(),
+                        unmap,
+                    ),
+                    part,
+                )
+            }
+            NVME_CMD_DSM => return self.dataset_management(cmd, req, mem_space, page_size),
+            _ => return Err(NVME_SC_INVALID_OPCODE),
+        };
+        if let Err(e) = res {
+            error!("Failed to submit nvme io command: {:?}", e);
+            part.finish(NVME_SC_INTERNAL);
+        }
+        Ok(())
+    }
+
+    /// Dataset Management command, only the deallocate attribute is handled and
+    /// the other hints are ignored.
+    fn dataset_management(
+        &self,
+        cmd: &NvmeCmd,
+        req: &NvmeRequest,
+        mem_space: &AddressSpace,
+        page_size: u64,
+    ) -> std::result::Result<(), u16> {
+        if cmd.cdw11 & NVME_DSM_AD == 0 {
+            return Ok(());
+        }
+        self.check_writable()?;
+        let nr = (cmd.cdw10 & 0xff) as usize + 1;
+        let mut ranges = vec![0_u8; nr * NVME_DSM_RANGE_SIZE];
+        let iovecs = map_prps(
+            mem_space,
+            cmd.prp1,
+            cmd.prp2,
+            ranges.len() as u64,
+            page_size,
+        )?;
+        iov_to_buf_direct(&iovecs, &mut ranges).map_err(|_| NVME_SC_DATA_TRANSFER_ERROR)?;
+
+        for range in ranges.chunks(NVME_DSM_RANGE_SIZE) {
+            let nlb = le_read_u32(range, 4).unwrap() as u64;
+            let slba = le_read_u64(range, 8).unwrap();
+            if nlb == 0 {
+                continue;
+            }
+            self.check_range(slba, nlb)?;
+            let part = req.part();
+            if let Err(e) = self.backend.lock().unwrap().discard(
+                (slba << NVME_LBA_SHIFT) as usize,
+                nlb << NVME_LBA_SHIFT,
+                part.clone(),
+            ) {
+                error!("Failed to submit nvme discard request: {:?}", e);
+                part.finish(NVME_SC_INTERNAL);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use log::error;
+
+use super::{NvmeInterruptCb, NVME_SC_DATA_TRANSFER_ERROR, NVME_SC_INVALID_FIELD};
+use address_space::{AddressSpace, GuestAddress};
+use util::aio::Iovec;
+use util::byte_code::ByteCode;
+
+/// Size of submission queue entry.
+pub const NVME_SQE_SIZE: u64 = 64;
+/// Size of completion queue entry.
+pub const NVME_CQE_SIZE: u64 = 16;
+
+/// Submission queue entry, NVMe spec 1.4 4.2.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeCmd {
+    pub opcode: u8,
+    pub flags: u8,
+    pub cid: u16,
+    pub nsid: u32,
+    pub cdw2: u32,
+    pub cdw3: u32,
+    pub mptr: u64,
+    pub prp1: u64,
+    pub prp2: u64,
+    pub cdw10: u32,
+    pub cdw11: u32,
+    pub cdw12: u32,
+    pub cdw13: u32,
+    pub cdw14: u32,
+    pub cdw15: u32,
+}
+
+impl ByteCode for NvmeCmd {}
+
+/// Completion queue entry, NVMe spec 1.4 4.6.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NvmeCqe {
+    pub dw0: u32,
+    pub dw1: u32,
+    pub sq_head: u16,
+    pub sq_id: u16,
+    pub cid: u16,
+    /// Phase tag in bit 0 and status field in bits 1~15.
+    pub status: u16,
+}
+
+impl ByteCode for NvmeCqe {}
+
+pub struct SubmissionQueue {
+    pub cqid: u16,
+    base: u64,
+    size: u16,
+    pub head: u16,
+    tail: u16,
+}
+
+impl SubmissionQueue {
+    pub fn new(cqid: u16, base: u64, size: u16) -> Self {
+        Self {
+            cqid,
+            base,
+            size,
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn set_tail(&mut self, tail: u16) {
+        self.tail = tail;
+    }
+
+    /// Fetch the next command posted by guest, none if the queue is empty.
+    pub fn pop(&mut self, mem_space: &AddressSpace) -> Option<Result<NvmeCmd>> {
+        if self.head == self.tail {
+            return None;
+        }
+        let addr = self.base + self.head as u64 * NVME_SQE_SIZE;
+        self.head = (self.head + 1) % self.size;
+        Some(
+            mem_space
+                .read_object::<NvmeCmd>(GuestAddress(addr))
+                .with_context(|| format!("Failed to read nvme command at {:#x}", addr)),
+        )
+    }
+}
+
+pub struct CompletionQueue {
+    mem_space: Arc<AddressSpace>,
+    base: u64,
+    size: u16,
+    head: u16,
+    tail: u16,
+    /// Phase tag of the entries posted in current round.
+    phase: bool,
+    vector: u16,
+    irq_enabled: bool,
+    interrupt_cb: Option<NvmeInterruptCb>,
+    /// Completions waiting for free entries of the queue.
+    pending: VecDeque<NvmeCqe>,
+    /// The queue is deleted by guest, the late completions are dropped.
+    pub deleted: bool,
+}
+
+impl CompletionQueue {
+    pub fn new(
+        mem_space: Arc<AddressSpace>,
+        base: u64,
+        size: u16,
+        vector: u16,
+        irq_enabled: bool,
+        interrupt_cb: Option<NvmeInterruptCb>,
+    ) -> Self {
+        Self {
+            mem_space,
+            base,
+            size,
+            head: 0,
+            tail: 0,
+            phase: true,
+            vector,
+            irq_enabled,
+            interrupt_cb,
+            pending: VecDeque::new(),
+            deleted: false,
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn is_full(&self) -> bool {
+        (self.tail + 1) % self.size == self.head
+    }
+
+    /// Post the completion to guest, it is queued if there is no free entry.
+    pub fn post(&mut self, cqe: NvmeCqe) {
+        if self.deleted {
+            return;
+        }
+        self.pending.push_back(cqe);
+        self.flush();
+    }
+
+    /// Guest has consumed the entries before `head`.
+    pub fn set_head(&mut self, head: u16) {
+        self.head = head;
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        let mut posted = false;
+        while !self.is_full() {
+            let mut cqe = match self.pending.pop_front() {
+                Some(cqe) => cqe,
+                None => break,
+            };
+            cqe.status = (cqe.status << 1) | self.phase as u16;
+            let addr = self.base + self.tail as u64 * NVME_CQE_SIZE;
+            if let Err(e) = self.mem_space.write_object(&cqe, GuestAddress(addr)) {
+                error!("Failed to post nvme completion: {:?}", e);
+            }
+            self.tail += 1;
+            if self.tail == self.size {
+                self.tail = 0;
+                self.phase = !self.phase;
+            }
+            posted = true;
+        }
+        if posted && self.irq_enabled {
+            if let Some(cb) = self.interrupt_cb.as_ref() {
+                cb(self.vector);
+            }
+        }
+    }
+}
+
+/// The handle to complete a command, it is also the completion callback of the
+/// aio requests of the command.
+#[derive(Clone)]
+pub struct NvmeRequest {
+    cq: Arc<Mutex<CompletionQueue>>,
+    sqid: u16,
+    sq_head: u16,
+    cid: u16,
+    /// Count of the unfinished parts of the command, including the submitter.
+    pending: Arc<AtomicU32>,
+    /// Status of the first failed part.
+    status: Arc<AtomicU16>,
+}
+
+impl NvmeRequest {
+    pub fn new(cq: Arc<Mutex<CompletionQueue>>, sqid: u16, sq_head: u16, cid: u16) -> Self {
+        Self {
+            cq,
+            sqid,
+            sq_head,
+            cid,
+            pending: Arc::new(AtomicU32::new(1)),
+            status: Arc::new(AtomicU16::new(0)),
+        }
+    }
+
+    /// Complete the command with `status` and command specific `dw0` at once.
+    pub fn complete(&self, status: u16, dw0: u32) {
+        let cqe = NvmeCqe {
+            dw0,
+            sq_head: self.sq_head,
+            sq_id: self.sqid,
+            cid: self.cid,
+            status,
+            ..Default::default()
+        };
+        self.cq.lock().unwrap().post(cqe);
+    }
+
+    /// Get a handle for one more part of the command which finishes separately.
+    pub fn part(&self) -> Self {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        self.clone()
+    }
+
+    /// Finish one part of the command, the command completes after all parts finish.
+    pub fn finish(&self, status: u16) {
+        if status != 0 {
+            let _ = self
+                .status
+                .compare_exchange(0, status, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        if self.pending.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.complete(self.status.load(Ordering::SeqCst), 0);
+        }
+    }
+}
+
+fn map_guest_range(
+    mem_space: &AddressSpace,
+    addr: u64,
+    len: u64,
+) -> std::result::Result<Iovec, u16> {
+    if !mem_space.address_in_memory(GuestAddress(addr), len) {
+        error!("Nvme data address {:#x} length {} is invalid", addr, len);
+        return Err(NVME_SC_DATA_TRANSFER_ERROR);
+    }
+    let hva = mem_space
+        .get_host_address(GuestAddress(addr))
+        .ok_or(NVME_SC_DATA_TRANSFER_ERROR)?;
+    Ok(Iovec::new(hva, len))
+}
+
+/// Map the data buffer of `len` bytes described by PRP entries to host iovecs,
+/// NVMe spec 1.4 4.3. The status code is returned on failure.
+pub fn map_prps(
+    mem_space: &AddressSpace,
+    prp1: u64,
+    prp2: u64,
+    len: u64,
+    page_size: u64,
+) -> std::result::Result<Vec<Iovec>, u16> {
+    let mut iovecs = Vec::new();
+    if len == 0 {
+        return Ok(iovecs);
+    }
+    if prp1 & 0x3 != 0 {
+        return Err(NVME_SC_INVALID_FIELD);
+    }
+    let first_len = std::cmp::min(len, page_size - (prp1 & (page_size - 1)));
+    iovecs.push(map_guest_range(mem_space, prp1, first_len)?);
+    let mut remain = len - first_len;
+    if remain == 0 {
+        return Ok(iovecs);
+    }
+    if remain <= page_size {
+        if prp2 & (page_size - 1) != 0 {
+            return Err(NVME_SC_INVALID_FIELD);
+        }
+        iovecs.push(map_guest_range(mem_space, prp2, remain)?);
+        return Ok(iovecs);
+    }
+
+    // Prp2 points to a list of PRP entries, the last entry of a full list page
+    // points to the next list page.
+    if prp2 & 0x7 != 0 {
+        return Err(NVME_SC_INVALID_FIELD);
+    }
+    let entry_size = size_of::<u64>() as u64;
+    let mut list_addr = prp2;
+    // The guest may chain the list pages in a loop, so the walk is bounded by
+    // the number of the data pages.
+    let max_walk = len / page_size + 1;
+    let mut list_pages = 1;
+    let mut entries = 0;
+    while remain > 0 {
+        let entry = mem_space
+            .read_object::<u64>(GuestAddress(list_addr))
+            .map_err(|_| NVME_SC_DATA_TRANSFER_ERROR)?;
+        let last_in_page = (list_addr + entry_size) & (page_size - 1) == 0;
+        if last_in_page && remain > page_size {
+            list_pages += 1;
+            if entry & 0x7 != 0 || list_pages > max_walk {
+                return Err(NVME_SC_INVALID_FIELD);
+            }
+            list_addr = entry;
+            continue;
+        }
+        entries += 1;
+        if entry & (page_size - 1) != 0 || entries > max_walk {
+            return Err(NVME_SC_INVALID_FIELD);
+        }
+        let chunk = std::cmp::min(remain, page_size);
+        iovecs.push(map_guest_range(mem_space, entry, chunk)?);
+        remain -= chunk;
+        list_addr += entry_size;
+    }
+    Ok(iovecs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nvme::create_mem_space;
+    use util::aio::get_iov_size;
+
+    #[test]
+    fn test_map_prps() {
+        let mem_space = create_mem_space();
+        let page_size = 4096;
+        let hva = mem_space.get_host_address(GuestAddress(0)).unwrap();
+
+        // Data in one page.
+        let iovecs = map_prps(&mem_space, 0x1100, 0, 0x100, page_size).unwrap();
+        assert_eq!(iovecs.len(), 1);
+        assert_eq!(iovecs[0].iov_base, hva + 0x1100);
+
+        // Data in two pages, prp2 is a data pointer.
+        let iovecs = map_prps(&mem_space, 0x1800, 0x5000, 0x1000, page_size).unwrap();
+        assert_eq!(iovecs.len(), 2);
+        assert_eq!(iovecs[0].iov_len, 0x800);
+        assert_eq!(iovecs[1].iov_base, hva + 0x5000);
+        assert_eq!(iovecs[1].iov_len, 0x800);
+        assert_eq!(
+            map_prps(&mem_space, 0x1800, 0x5008, 0x1000, page_size).err(),
+            Some(NVME_SC_INVALID_FIELD)
+        );
+
+        // Prp2 points to a list which chains to the next list page.
+        let list = 0x2ff0_u64;
+        mem_space
+            .write_object(&0x8000_u64, GuestAddress(list))
+            .unwrap();
+        mem_space
+            .write_object(&0x3000_u64, GuestAddress(list + 8))
+            .unwrap();
+        mem_space
+            .write_object(&0x9000_u64, GuestAddress(0x3000))
+            .unwrap();
+        mem_space
+            .write_object(&0xa000_u64, GuestAddress(0x3008))
+            .unwrap();
+        let iovecs = map_prps(&mem_space, 0x7000, list, 0x3800, page_size).unwrap();
+        assert_eq!(iovecs.len(), 4);
+        assert_eq!(get_iov_size(&iovecs), 0x3800);
+        assert_eq!(iovecs[2].iov_base, hva + 0x9000);
+        assert_eq!(iovecs[3].iov_base, hva + 0xa000);
+        assert_eq!(iovecs[3].iov_len, 0x800);
+
+        // The last entry of the list page points to itself.
+        let list = 0x4ff8_u64;
+        mem_space.write_object(&list, GuestAddress(list)).unwrap();
+        assert_eq!(
+            map_prps(&mem_space, 0x7000, list, 0x3800, page_size).err(),
+            Some(NVME_SC_INVALID_FIELD)
+        );
+
+        // Out of guest memory.
+        assert_eq!(
+            map_prps(&mem_space, 0x20_0000, 0, 0x200, page_size).err(),
+            Some(NVME_SC_DATA_TRANSFER_ERROR)
+        );
+    }
+
+    #[test]
+    fn test_completion_queue() {
+        let mem_space = create_mem_space();
+        let irqs = Arc::new(AtomicU32::new(0));
+        let cloned_irqs = irqs.clone();
+        let cb: NvmeInterruptCb = Arc::new(move |_| {
+            cloned_irqs.fetch_add(1, Ordering::SeqCst);
+        });
+        let cq = Arc::new(Mutex::new(CompletionQueue::new(
+            mem_space.clone(),
+            0x4000,
+            2,
+            0,
+            true,
+            Some(cb),
+        )));
+
+        let req = NvmeRequest::new(cq.clone(), 1, 5, 0x10);
+        let part = req.part();
+        req.finish(0);
+        assert_eq!(irqs.load(Ordering::SeqCst), 0);
+        part.finish(NVME_SC_INVALID_FIELD);
+        let cqe = mem_space
+            .read_object::<NvmeCqe>(GuestAddress(0x4000))
+            .unwrap();
+        assert_eq!(cqe.cid, 0x10);
+        assert_eq!(cqe.sq_id, 1);
+        assert_eq!(cqe.sq_head, 5);
+        assert_eq!(cqe.status, NVME_SC_INVALID_FIELD << 1 | 1);
+        assert_eq!(irqs.load(Ordering::SeqCst), 1);
+
+        // The queue of 2 entries is full with one entry posted.
+        NvmeRequest::new(cq.clone(), 1, 6, 0x11).complete(0, 0);
+        assert_eq!(irqs.load(Ordering::SeqCst), 1);
+        cq.lock().unwrap().set_head(1);
+        let cqe = mem_space
+            .read_object::<NvmeCqe>(GuestAddress(0x4000 + NVME_CQE_SIZE))
+            .unwrap();
+        assert_eq!(cqe.cid, 0x11);
+        assert_eq!(cqe.status, 1);
+        assert_eq!(irqs.load(Ordering::SeqCst), 2);
+
+        // The phase tag is inverted in the next round.
+        NvmeRequest::new(cq.clone(), 1, 7, 0x12).complete(0, 0);
+        cq.lock().unwrap().set_head(0);
+        let cqe = mem_space
+            .read_object::<NvmeCqe>(GuestAddress(0x4000))
+            .unwrap();
+        assert_eq!(cqe.cid, 0x12);
+        assert_eq!(cqe.status, 0);
+    }
+}
@@ -17,6 +17,7 @@ use std::slice::from_raw_parts;
 use std::{thread, time};
 
 use rand::Rng;
+use serde_json::{json, Value};
 use util::aio::{aio_probe, AioEngine};
 use util::byte_code::ByteCode;
 use util::offset_of;
@@ -59,6 +60,9 @@ const GET_CONFIGURATION: u8 = 0x46;
 const READ_DISC_INFORMATION: u8 = 0x51;
 const GET_EVENT_STATUS_NOTIFICATION: u8 = 0x4a;
 const READ_TOC: u8 = 0x43;
+const WRITE_SAME_10: u8 = 0x41;
+const UNMAP: u8 = 0x42;
+const ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
 
 const VIRTIO_SCSI_S_OK: u8 = 0;
 const VIRTIO_SCSI_S_BAD_TARGET: u8 = 3;
@@ -308,6 +312,44 @@ impl VirtioScsiTest {
         }
     }
 
+    // Check the state of cd-rom by TEST_UNIT_READY, `sense` is None if it's ready.
+    fn scsi_cd_test_unit_ready(&mut self, target: u8, lun: u16, sense: Option<ScsiSense>) {
+        let mut test_unit_ready_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+        test_unit_ready_cdb[0] = TEST_UNIT_READY;
+        let cdb_test_args = CdbTest {
+            cdb: test_unit_ready_cdb,
+            target,
+            lun,
+            data_out: None,
+            data_in_length: 0,
+            expect_response: VIRTIO_SCSI_S_OK,
+            expect_result_data: None,
+            expect_sense: sense.map(get_sense_bytes),
+        };
+        self.scsi_cdb_test(cdb_test_args);
+    }
+
+    // Check the media event of cd-rom by GET_EVENT_STATUS_NOTIFICATION.
+    // `event_code` is Byte4 and `media_status` is Byte5 of the returned data.
+    fn scsi_cd_media_event(&mut self, target: u8, lun: u16, event_code: u8, media_status: u8) {
+        let mut get_event_status_notification_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+        get_event_status_notification_cdb[0] = GET_EVENT_STATUS_NOTIFICATION;
+        get_event_status_notification_cdb[1] = 1;
+        get_event_status_notification_cdb[4] = 0x10;
+        get_event_status_notification_cdb[8] = GET_EVENT_STATUS_NOTIFICATION_DATA_LEN;
+        let cdb_test_args = CdbTest {
+            cdb: get_event_status_notification_cdb,
+            target,
+            lun,
+            data_out: None,
+            data_in_length: GET_EVENT_STATUS_NOTIFICATION_DATA_LEN as u32,
+            expect_response: VIRTIO_SCSI_S_OK,
+            expect_result_data: Some(vec![0, 6, 4, 0x10, event_code, media_status, 0, 0]),
+            expect_sense: None,
+        };
+        self.scsi_cdb_test(cdb_test_args);
+    }
+
     // Basic IO function test.
     fn scsi_try_io(&mut self, target: u8, lun: u16, scsi_type: ScsiDeviceType) {
         // Test: scsi command: WRITE_10.
@@ -397,6 +439,12 @@ const SCSI_SENSE_INVALID_FIELD: ScsiSense = ScsiSense {
     ascq: 0x00,
 };
 
+const SCSI_SENSE_LBA_OUT_OF_RANGE: ScsiSense = ScsiSense {
+    key: 0x05,
+    asc: 0x21,
+    ascq: 0x00,
+};
+
 const SCSI_SENSE_LUN_NOT_SUPPORTED: ScsiSense = ScsiSense {
     key: 0x05,
     asc: 0x25,
@@ -409,6 +457,24 @@ const SCSI_SENSE_NO_SENSE: ScsiSense = ScsiSense {
     ascq: 0,
 };
 
+const SCSI_SENSE_NO_MEDIUM: ScsiSense = ScsiSense {
+    key: 0x02,
+    asc: 0x3a,
+    ascq: 0x00,
+};
+
+const SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM: ScsiSense = ScsiSense {
+    key: 0x06,
+    asc: 0x3a,
+    ascq: 0x00,
+};
+
+const SCSI_SENSE_MEDIUM_CHANGED: ScsiSense = ScsiSense {
+    key: 0x06,
+    asc: 0x28,
+    ascq: 0x00,
+};
+
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
 struct TestVirtioScsiCmdReq {
@@ -560,6 +626,15 @@ fn get_sense_bytes(sense: ScsiSense) -> [u8; TEST_VIRTIO_SCSI_SENSE_SIZE] {
     bytes
 }
 
+// Check the event DEVICE_TRAY_MOVED which is sent before the response of qmp command.
+fn check_tray_moved(event: &Value, id: &str, tray_open: bool) {
+    assert_eq!(*event.get("event").unwrap(), json!("DEVICE_TRAY_MOVED"));
+    assert_eq!(
+        *event.get("data").unwrap(),
+        json!({"id": id, "tray-open": tray_open})
+    );
+}
+
 pub fn virtio_scsi_defalut_feature(cntlr: Rc<RefCell<TestVirtioPciDev>>) -> u64 {
     let mut features = cntlr.borrow().get_device_features();
     features &=
@@ -975,9 +1050,9 @@ fn scsi_hd_basic_test() {
     // Byte3: page length(length - 4).
     // Byte4: Threshold exponent.
     // Byte5: LBPU(bit 7) / LBPWS / LBPWS10 / LBPRZ / ANC_SUP / DP.
-    // Byte6: Threshold percentage / Provisioning Type.
+    // Byte6: Threshold percentage / Provisioning Type(2: thin provisioned).
     // Byte7: Threshold percentage.
-    let expect_result_vec = vec![0, 0xb2, 0, 0x4, 0, 0x60, 0x1, 0];
+    let expect_result_vec = vec![0, 0xb2, 0, 0x4, 0, 0xe0, 0x2, 0];
     let cdb_test_args = CdbTest {
         cdb: inquiry_cdb,
         target,
@@ -1016,6 +1091,128 @@ fn scsi_hd_basic_test() {
     vst.testcase_tear_down();
 }
 
+/// Virtio Scsi hard disk thin provisioning test. target 0, lun 0.
+/// TestStep:
+///   0. Init process.
+///   1. Write data, zero it by WRITE_SAME_10 with UNMAP bit, and read it.
+///   2. Write same data which is not zero.
+///   3. Unmap one block, and unmap the block out of the disk.
+///   4. Test ends. Destroy device.
+/// Expect:
+///   1/3: success, the data read is zero.
+///   2: Failure with INVALID FIELD sense.
+///   3: Unmapping the block out of the disk fails with LBA OUT OF RANGE sense.
+#[test]
+fn scsi_hd_provisioning_test() {
+    let target = 0;
+    let lun = 0;
+    let mut vst = VirtioScsiTest::general_testcase_run(ScsiDeviceType::ScsiHd, target, lun);
+
+    // Test 1: write data to LBA 0, then zero it by WRITE_SAME_10 with UNMAP bit.
+    let mut write_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    write_cdb[0] = WRITE_10;
+    write_cdb[8] = 0x1; // 1 sector.
+    let cdb_test_args = CdbTest {
+        cdb: write_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(vec![0x8; 512]).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    let mut write_same_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    write_same_cdb[0] = WRITE_SAME_10;
+    write_same_cdb[1] = 0x8; // UNMAP.
+    write_same_cdb[8] = 0x1; // 1 sector.
+    let cdb_test_args = CdbTest {
+        cdb: write_same_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(vec![0; 512]).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    let mut read_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    read_cdb[0] = READ_10;
+    read_cdb[8] = 0x1; // 1 sector.
+    let cdb_test_args = CdbTest {
+        cdb: read_cdb,
+        target,
+        lun,
+        data_out: None,
+        data_in_length: 512,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_result_data: Some(vec![0; 512]),
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Test 2: write same data which is not zero.
+    let cdb_test_args = CdbTest {
+        cdb: write_same_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(vec![0x8; 512]).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_result_data: None,
+        expect_sense: Some(get_sense_bytes(SCSI_SENSE_INVALID_FIELD)),
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Test 3: unmap one block.
+    // Parameter list header:
+    //   Bytes[0-1]: Unmap data length(n - 1).
+    //   Bytes[2-3]: Unmap block descriptor data length.
+    // Block descriptor:
+    //   Bytes[8-15]: Logical block address.
+    //   Bytes[16-19]: Number of logical blocks.
+    let unmap_param_len = 24;
+    let mut unmap_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    unmap_cdb[0] = UNMAP;
+    unmap_cdb[8] = unmap_param_len as u8;
+    let mut param = vec![0_u8; unmap_param_len];
+    param[1] = (unmap_param_len - 2) as u8;
+    param[3] = 16;
+    param[19] = 1;
+    let cdb_test_args = CdbTest {
+        cdb: unmap_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(param.clone()).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    // Unmap the block out of the disk.
+    let lba = TEST_IMAGE_SIZE / 512;
+    param[8..16].copy_from_slice(&lba.to_be_bytes());
+    let cdb_test_args = CdbTest {
+        cdb: unmap_cdb,
+        target,
+        lun,
+        data_out: Some(String::from_utf8(param).unwrap()),
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_result_data: None,
+        expect_sense: Some(get_sense_bytes(SCSI_SENSE_LBA_OUT_OF_RANGE)),
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    vst.testcase_tear_down();
+}
+
 /// Virtio Scsi CD-ROM basic function test. target 0, lun 7.
 /// TestStep:
 ///   0. Init process.
@@ -2364,3 +2561,139 @@ fn wrong_io_test() {
 
     vst.testcase_tear_down();
 }
+
+/// Scsi CD-ROM eject test.
+/// TestStep:
+///   1. Init process.
+///   2. Eject the medium by qmp command "eject".
+///   3. Check the state of CD-ROM by TEST_UNIT_READY twice.
+///   4. Check the media event by GET_EVENT_STATUS_NOTIFICATION.
+///   5. Destroy device.
+/// Expect:
+///   1/2/5: success.
+///   2: The event DEVICE_TRAY_MOVED(tray-open: true) is sent.
+///   3: UNIT ATTENTION/MEDIUM NOT PRESENT first, and then NOT READY/MEDIUM NOT PRESENT.
+///   4: Event Code is media removal(3), the tray is open and no medium is present.
+#[test]
+fn scsi_cd_eject_test() {
+    let target = 0;
+    let lun = 0;
+    let id = format!("scsi0-0-{}-{}", target, lun);
+    let mut vst = VirtioScsiTest::general_testcase_run(ScsiDeviceType::ScsiCd, target, lun);
+    vst.scsi_cd_test_unit_ready(target, lun, None);
+
+    let ret = vst.state.borrow().qmp(&format!(
+        "{{\"execute\": \"eject\", \"arguments\": {{\"device\": \"{}\"}}}}",
+        id
+    ));
+    check_tray_moved(&ret, &id, true);
+    let ret = vst.state.borrow().qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    vst.scsi_cd_test_unit_ready(target, lun, Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM));
+    vst.scsi_cd_test_unit_ready(target, lun, Some(SCSI_SENSE_NO_MEDIUM));
+    // Event Code: media removal(3). Media Status: tray open(bit 0).
+    vst.scsi_cd_media_event(target, lun, 3, 1);
+    // The media event is cleared once reported.
+    vst.scsi_cd_media_event(target, lun, 0, 1);
+
+    vst.testcase_tear_down();
+}
+
+/// Scsi CD-ROM change medium test.
+/// TestStep:
+///   1. Init process.
+///   2. Change the medium by qmp command "blockdev-change-medium".
+///   3. Check the state of CD-ROM by TEST_UNIT_READY twice.
+///   4. Check the media event by GET_EVENT_STATUS_NOTIFICATION.
+///   5. Basic IO test on the new medium.
+///   6. Destroy device.
+/// Expect:
+///   1/2/5/6: success.
+///   2: The event DEVICE_TRAY_MOVED is sent for opening and closing the tray.
+///   3: UNIT ATTENTION/MEDIUM MAY HAVE CHANGED first, and then ready.
+///   4: Event Code is new media(2), the tray is closed and the medium is present.
+#[test]
+fn scsi_cd_change_medium_test() {
+    let target = 0;
+    let lun = 0;
+    let id = format!("scsi0-0-{}-{}", target, lun);
+    let mut vst = VirtioScsiTest::general_testcase_run(ScsiDeviceType::ScsiCd, target, lun);
+    let new_image_path = create_img(TEST_IMAGE_SIZE, 1);
+
+    let ret = vst.state.borrow().qmp(&format!(
+        "{{\"execute\": \"blockdev-change-medium\", \"arguments\": {{\"device\": \"{}\", \"filename\": \"{}\"}}}}",
+        id, new_image_path
+    ));
+    check_tray_moved(&ret, &id, true);
+    check_tray_moved(&vst.state.borrow().qmp_read(), &id, false);
+    let ret = vst.state.borrow().qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+
+    vst.scsi_cd_test_unit_ready(target, lun, Some(SCSI_SENSE_MEDIUM_CHANGED));
+    vst.scsi_cd_test_unit_ready(target, lun, None);
+    // Event Code: new media(2). Media Status: media present(bit 1).
+    vst.scsi_cd_media_event(target, lun, 2, 2);
+    vst.scsi_try_io(target, lun, ScsiDeviceType::ScsiCd);
+
+    vst.testcase_tear_down();
+    cleanup_img(new_image_path);
+}
+
+/// Scsi CD-ROM prevent medium removal test.
+/// TestStep:
+///   1. Init process.
+///   2. Prevent medium removal by ALLOW_MEDIUM_REMOVAL.
+///   3. Eject the medium by qmp command "eject" without force.
+///   4. Check the media event by GET_EVENT_STATUS_NOTIFICATION.
+///   5. Eject the medium by qmp command "eject" with force.
+///   6. Check the state of CD-ROM by TEST_UNIT_READY.
+///   7. Destroy device.
+/// Expect:
+///   1/2/5/7: success.
+///   3: failure, the medium is still present.
+///   4: Event Code is eject request(1), the tray is closed and the medium is present.
+///   6: UNIT ATTENTION/MEDIUM NOT PRESENT.
+#[test]
+fn scsi_cd_prevent_removal_test() {
+    let target = 0;
+    let lun = 0;
+    let id = format!("scsi0-0-{}-{}", target, lun);
+    let mut vst = VirtioScsiTest::general_testcase_run(ScsiDeviceType::ScsiCd, target, lun);
+
+    let mut allow_medium_removal_cdb = [0_u8; TEST_VIRTIO_SCSI_CDB_SIZE];
+    allow_medium_removal_cdb[0] = ALLOW_MEDIUM_REMOVAL;
+    // Byte4: Bits[0-1]: Prevent.
+    allow_medium_removal_cdb[4] = 1;
+    let cdb_test_args = CdbTest {
+        cdb: allow_medium_removal_cdb,
+        target,
+        lun,
+        data_out: None,
+        data_in_length: 0,
+        expect_response: VIRTIO_SCSI_S_OK,
+        expect_result_data: None,
+        expect_sense: None,
+    };
+    vst.scsi_cdb_test(cdb_test_args);
+
+    let ret = vst.state.borrow().qmp(&format!(
+        "{{\"execute\": \"eject\", \"arguments\": {{\"device\": \"{}\"}}}}",
+        id
+    ));
+    assert!(ret.get("error").is_some());
+    vst.scsi_cd_test_unit_ready(target, lun, None);
+    // Event Code: eject request(1). Media Status: media present(bit 1).
+    vst.scsi_cd_media_event(target, lun, 1, 2);
+
+    let ret = vst.state.borrow().qmp(&format!(
+        "{{\"execute\": \"eject\", \"arguments\": {{\"device\": \"{}\", \"force\": true}}}}",
+        id
+    ));
+    check_tray_moved(&ret, &id, true);
+    let ret = vst.state.borrow().qmp_read();
+    assert_eq!(*ret.get("return").unwrap(), json!({}));
+    vst.scsi_cd_test_unit_ready(target, lun, Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM));
+
+    vst.testcase_tear_down();
+}
@@ -16,7 +16,8 @@ mod uring;
 
 use std::clone::Clone;
 use std::io::Write;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicI64, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::{cmp, str::FromStr};
 
@@ -44,6 +45,8 @@ const AIO_NATIVE: &str = "native";
 const AIO_IOURING: &str = "io_uring";
 /// Max bytes of bounce buffer for misaligned IO.
 const MAX_LEN_BOUNCE_BUFF: u64 = 1 << 20;
+/// Timeout of each poll when waiting for the in-flight requests.
+const DRAIN_POLL_TIMEOUT_MS: i32 = 10;
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
 pub enum AioEngine {
@@ -71,6 +74,15 @@ pub struct Iovec {
     pub iov_len: u64,
 }
 
+impl Iovec {
+    pub fn new(base: u64, len: u64) -> Self {
+        Iovec {
+            iov_base: base,
+            iov_len: len,
+        }
+    }
+}
+
 /// The trait for Asynchronous IO operation.
 trait AioContext<T: Clone> {
     /// Submit IO requests to the OS, the nr submitted is returned.
@@ -91,6 +103,22 @@ pub enum OpCode {
     Preadv = 1,
     Pwritev = 2,
     Fdsync = 3,
+    /// Deallocate the range of file.
+    Discard = 4,
+    /// Zero the range of file, the range keeps allocated.
+    WriteZeroes = 5,
+    /// Zero the range of file, the range may be deallocated.
+    WriteZeroesUnmap = 6,
+}
+
+impl OpCode {
+    /// The operation is done by fallocate, and the result is 0 on success.
+    pub fn is_fallocate(&self) -> bool {
+        matches!(
+            self,
+            OpCode::Discard | OpCode::WriteZeroes | OpCode::WriteZeroesUnmap
+        )
+    }
 }
 
 pub struct AioCb<T: Clone> {
@@ -104,8 +132,13 @@ pub struct AioCb<T: Clone> {
     pub nbytes: u64,
     pub user_data: u64,
     pub iocompletecb: T,
+    /// Shared by the pieces a request is split into: the count of pieces not yet
+    /// completed and the first error met. `iocompletecb` is called only once for all.
+    pub combine_req: Option<(Arc<AtomicU32>, Arc<AtomicI64>)>,
 }
 
+/// Complete function of request, the result is negative on failure, which is
+/// the negative errno if it's known.
 pub type AioCompleteFunc<T> = fn(&AioCb<T>, i64) -> Result<()>;
 
 pub struct Aio<T: Clone + 'static> {
@@ -118,6 +151,38 @@ pub struct Aio<T: Clone + 'static> {
     complete_func: Arc<AioCompleteFunc<T>>,
 }
 
+fn combine_complete<T: Clone>(
+    complete_func: &AioCompleteFunc<T>,
+    cb: &AioCb<T>,
+    res: i64,
+) -> Result<()> {
+    let mut res = res;
+    if let Some((pending, err)) = cb.combine_req.as_ref() {
+        if res < 0 {
+            // Keep the first error, the later ones are dropped.
+            let _ = err.compare_exchange(0, res, Ordering::SeqCst, Ordering::SeqCst);
+        }
+        if pending.fetch_sub(1, Ordering::SeqCst) != 1 {
+            return Ok(());
+        }
+        let first_err = err.load(Ordering::SeqCst);
+        if first_err < 0 {
+            res = first_err;
+        }
+    }
+    (complete_func)(cb, res)
+}
+
+/// Do the fallocate operation of request synchronously.
+fn fallocate_result<T: Clone>(cb: &AioCb<T>) -> i64 {
+    match cb.opcode {
+        OpCode::Discard => raw_discard(cb.file_fd, cb.offset, cb.nbytes),
+        OpCode::WriteZeroes => raw_write_zeroes(cb.file_fd, cb.offset, cb.nbytes, false),
+        OpCode::WriteZeroesUnmap => raw_write_zeroes(cb.file_fd, cb.offset, cb.nbytes, true),
+        _ => -1,
+    }
+}
+
 pub fn aio_probe(engine: AioEngine) -> Result<()> {
     match engine {
         AioEngine::Off => {}
@@ -158,6 +223,12 @@ impl<T: Clone + 'static> Aio<T> {
         self.engine
     }
 
+    /// Call the complete function of the request. For a request split into several
+    /// pieces, only the completion of the last piece is reported.
+    pub fn complete_cb(&self, cb: &AioCb<T>, res: i64) -> Result<()> {
+        combine_complete(&self.complete_func, cb, res)
+    }
+
     pub fn submit_request(&mut self, mut cb: AioCb<T>) -> Result<()> {
         if self.request_misaligned(&cb) {
             let max_len = round_down(cb.nbytes + cb.req_align as u64 * 2, cb.req_align as u64)
@@ -170,7 +241,7 @@ impl<T: Clone + 'static> Aio<T> {
                 unsafe { libc::memalign(host_page_size() as usize, buff_len as usize) };
             if bounce_buffer.is_null() {
                 error!("Failed to alloc memory for misaligned read/write.");
-                return (self.complete_func)(&cb, -1);
+                return self.complete_cb(&cb, -1);
             }
 
             let res = match self.handle_misaligned_rw(&mut cb, bounce_buffer, buff_len) {
@@ -183,7 +254,7 @@ impl<T: Clone + 'static> Aio<T> {
 
             // SAFETY: the memory is allocated by us and will not be used anymore.
             unsafe { libc::free(bounce_buffer) };
-            return (self.complete_func)(&cb, res);
+            return self.complete_cb(&cb, res);
         }
 
         match cb.opcode {
@@ -201,6 +272,14 @@ impl<T: Clone + 'static> Aio<T> {
                     self.flush_sync(cb)
                 }
             }
+            OpCode::Discard | OpCode::WriteZeroes | OpCode::WriteZeroesUnmap => {
+                // Linux native aio has no fallocate command.
+                if self.engine == AioEngine::IoUring {
+                    self.rw_async(cb)
+                } else {
+                    self.fallocate_sync(cb)
+                }
+            }
             OpCode::Noop => Err(anyhow!("Aio opcode is not specified.")),
         }
     }
@@ -219,22 +298,40 @@ impl<T: Clone + 'static> Aio<T> {
             warn!("Can not handle aio complete with invalid ctx.");
             return Ok(done);
         }
+        let complete_func = self.complete_func.clone();
         for evt in self.ctx.as_mut().unwrap().get_events() {
             // SAFETY: evt.data is specified by submit and not dropped at other place.
             unsafe {
                 let node = evt.user_data as *mut CbNode<T>;
-                let res = if (evt.status == 0) && (evt.res == (*node).value.nbytes as i64) {
+                let expected = match (*node).value.opcode {
+                    OpCode::Preadv | OpCode::Pwritev => (*node).value.nbytes as i64,
+                    _ => 0,
+                };
+                let res = if (evt.status == 0) && (evt.res == expected) {
                     done = true;
                     evt.res
+                } else if (evt.res == -(libc::EOPNOTSUPP as i64)
+                    || evt.res == -(libc::EINVAL as i64))
+                    && (*node).value.opcode.is_fallocate()
+                {
+                    // Fall back to the sync way which handles the file not
+                    // supporting the mode of fallocate.
+                    done = true;
+                    fallocate_result(&(*node).value)
                 } else {
                     error!(
                         "Async IO request failed, status {} res {}",
                         evt.status, evt.res
                     );
-                    -1
+                    // Keep the errno for the device to handle the error.
+                    if evt.res < 0 {
+                        evt.res
+                    } else {
+                        -1
+                    }
                 };
 
-                (self.complete_func)(&(*node).value, res)?;
+                combine_complete(&complete_func, &(*node).value, res)?;
                 self.aio_in_flight.unlink(&(*node));
                 // Construct Box to free mem automatically.
                 drop(Box::from_raw(node));
@@ -244,6 +341,36 @@ impl<T: Clone + 'static> Aio<T> {
         Ok(done)
     }
 
+    /// Wait until all the queued and in-flight requests are completed.
+    pub fn drain_request(&mut self) -> Result<()> {
+        if self.ctx.is_none() {
+            // Requests are completed synchronously.
+            return Ok(());
+        }
+        self.process_list()?;
+        while self.aio_in_queue.len > 0 || self.aio_in_flight.len > 0 {
+            let mut pollfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: pollfd is valid and only one fd is polled.
+            let ret = unsafe { libc::poll(&mut pollfd, 1, DRAIN_POLL_TIMEOUT_MS) };
+            if ret < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    bail!("Failed to poll aio events: {}", err);
+                }
+            }
+            if ret > 0 {
+                // Nothing to do if the counter has been read by others.
+                let _ = self.fd.read();
+            }
+            self.handle_complete()?;
+        }
+        Ok(())
+    }
+
     fn process_list(&mut self) -> Result<()> {
         if self.ctx.is_none() {
             warn!("Can not process aio list with invalid ctx.");
@@ -284,7 +411,7 @@ impl<T: Clone + 'static> Aio<T> {
             if is_err {
                 // Fail one request, retry the rest.
                 if let Some(node) = self.aio_in_queue.pop_tail() {
-                    (self.complete_func)(&(node).value, -1)?;
+                    self.complete_cb(&(node).value, -1)?;
                 }
             } else if nr == 0 {
                 // If can't submit any request, break the loop
@@ -319,7 +446,7 @@ impl<T: Clone + 'static> Aio<T> {
             error!("Incomplete sync read/write.");
             ret = -1;
         }
-        (self.complete_func)(&cb, ret)
+        self.complete_cb(&cb, ret)
     }
 
     fn request_misaligned(&self, cb: &AioCb<T>) -> bool {
@@ -481,12 +608,17 @@ impl<T: Clone + 'static> Aio<T> {
         self.rw_async(cb)
     }
 
+    fn fallocate_sync(&mut self, cb: AioCb<T>) -> Result<()> {
+        let ret = fallocate_result(&cb);
+        self.complete_cb(&cb, ret)
+    }
+
     fn flush_sync(&mut self, cb: AioCb<T>) -> Result<()> {
         let ret = raw_datasync(cb.file_fd);
         if ret < 0 {
             error!("Failed to do sync flush.");
         }
-        (self.complete_func)(&cb, ret)
+        self.complete_cb(&cb, ret)
     }
 }
 
@@ -539,6 +671,40 @@ pub fn iov_to_buf_direct(iovec: &[Iovec], buf: &mut [u8]) -> Result<usize> {
     Ok(end)
 }
 
+/// Fill all the buffers of iovec with zero.
+pub fn iovec_write_zero(iovec: &[Iovec]) {
+    for iov in iovec.iter() {
+        // SAFETY: all callers have valid hva address.
+        unsafe { std::ptr::write_bytes(iov.iov_base as *mut u8, 0, iov.iov_len as usize) };
+    }
+}
+
+/// Get the total length of iovec.
+pub fn get_iov_size(iovec: &[Iovec]) -> u64 {
+    iovec.iter().map(|iov| iov.iov_len).sum()
+}
+
+/// Split iovec into two parts, the first one holds "size" bytes.
+pub fn iovecs_split(iovec: Vec<Iovec>, mut size: u64) -> (Vec<Iovec>, Vec<Iovec>) {
+    let mut begin = Vec::new();
+    let mut end = Vec::new();
+    for iov in iovec {
+        if size == 0 {
+            end.push(iov);
+            continue;
+        }
+        if iov.iov_len > size {
+            begin.push(Iovec::new(iov.iov_base, size));
+            end.push(Iovec::new(iov.iov_base + size, iov.iov_len - size));
+            size = 0;
+        } else {
+            size -= iov.iov_len;
+            begin.push(iov);
+        }
+    }
+    (begin, end)
+}
+
 /// Discard "size" bytes of the front of iovec.
 pub fn iov_discard_front_direct(iovec: &mut [Iovec], mut size: u64) -> Option<&mut [Iovec]> {
     for (index, iov) in iovec.iter_mut().enumerate() {
@@ -11,10 +11,18 @@
 // See the Mulan PSL v2 for more details.
 
 use super::Iovec;
-use libc::{c_int, c_void, fdatasync, iovec, off_t, pread, preadv, pwrite, pwritev, size_t};
+use crate::unix::host_page_size;
+use libc::{
+    c_int, c_void, fallocate, fdatasync, iovec, off_t, pread, preadv, pwrite, pwritev, size_t,
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE,
+};
 use log::error;
+use std::cmp;
 use std::os::unix::io::RawFd;
 
+/// Max bytes of the zero buffer written when the file can't zero the range by fallocate.
+const MAX_LEN_ZERO_BUFF: u64 = 1 << 20;
+
 pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
     let mut ret;
     loop {
@@ -32,13 +40,12 @@ pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let err = errno::errno().0;
         error!(
             "Failed to pread: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0
+            buf, size, offset, err
         );
+        return -i64::from(err);
     }
     ret
 }
@@ -60,11 +67,9 @@ pub fn raw_readv(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to preadv: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let err = errno::errno().0;
+        error!("Failed to preadv: offset{}, errno{}.", offset, err);
+        return -i64::from(err);
     }
     ret
 }
@@ -86,13 +91,12 @@ pub fn raw_write(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let err = errno::errno().0;
         error!(
             "Failed to pwrite: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0,
+            buf, size, offset, err
         );
+        return -i64::from(err);
     }
     ret
 }
@@ -114,11 +118,9 @@ pub fn raw_writev(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to pwritev: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let err = errno::errno().0;
+        error!("Failed to pwritev: offset{}, errno{}.", offset, err);
+        return -i64::from(err);
     }
     ret
 }
@@ -127,7 +129,85 @@ pub fn raw_datasync(fd: RawFd) -> i64 {
     // SAFETY: fd is valid.
     let ret = unsafe { i64::from(fdatasync(fd)) };
     if ret < 0 {
-        error!("Failed to fdatasync: errno{}.", errno::errno().0);
+        let err = errno::errno().0;
+        error!("Failed to fdatasync: errno{}.", err);
+        return -i64::from(err);
+    }
+    ret
+}
+
+fn raw_fallocate(fd: RawFd, mode: c_int, offset: usize, size: u64) -> i64 {
+    let mut ret;
+    loop {
+        // SAFETY: fd is valid.
+        ret = unsafe { i64::from(fallocate(fd, mode, offset as off_t, size as off_t)) };
+        if !(ret < 0 && errno::errno().0 == libc::EINTR) {
+            break;
+        }
+    }
+    ret
+}
+
+/// Deallocate the range of file, which reads as zeros afterwards. It's ok if
+/// the file does not support it, as discard is only a hint.
+pub fn raw_discard(fd: RawFd, offset: usize, size: u64) -> i64 {
+    let ret = raw_fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, offset, size);
+    let err = errno::errno().0;
+    if ret < 0 && err != libc::EOPNOTSUPP {
+        error!(
+            "Failed to discard: offset{}, size{}, errno{}.",
+            offset, size, err
+        );
+        return -i64::from(err);
+    }
+    0
+}
+
+/// Zero the range of file, the range is deallocated if `unmap` is allowed. Zero
+/// buffer is written if the file does not support zeroing by fallocate.
+pub fn raw_write_zeroes(fd: RawFd, offset: usize, size: u64, unmap: bool) -> i64 {
+    if unmap && raw_fallocate(fd, FALLOC_FL_PUNCH_HOLE | FALLOC_FL_KEEP_SIZE, offset, size) == 0 {
+        return 0;
+    }
+    let ret = raw_fallocate(fd, FALLOC_FL_ZERO_RANGE, offset, size);
+    if ret == 0 {
+        return 0;
+    }
+    let err = errno::errno().0;
+    if err != libc::EOPNOTSUPP && err != libc::EINVAL {
+        error!(
+            "Failed to write zeroes: offset{}, size{}, errno{}.",
+            offset, size, err
+        );
+        return -i64::from(err);
+    }
+
+    let buff_len = cmp::min(size, MAX_LEN_ZERO_BUFF);
+    // SAFETY: we allocate aligned memory and free it later. The alignment meets
+    // the requirement of direct IO.
+    let buffer = unsafe { libc::memalign(host_page_size() as usize, buff_len as usize) };
+    if buffer.is_null() {
+        error!("Failed to alloc memory for writing zeroes.");
+        return -i64::from(libc::ENOMEM);
+    }
+    // SAFETY: the buffer is allocated with the length above.
+    unsafe { std::ptr::write_bytes(buffer as *mut u8, 0, buff_len as usize) };
+    let mut ret = 0;
+    let mut pos = 0;
+    while pos < size {
+        let len = cmp::min(size - pos, buff_len);
+        let written = raw_write(fd, buffer as u64, len as usize, offset + pos as usize);
+        if written < 0 || written as u64 != len {
+            ret = if written < 0 {
+                written
+            } else {
+                -i64::from(libc::EIO)
+            };
+            break;
+        }
+        pos += len;
     }
+    // SAFETY: the memory is allocated by us and will not be used anymore.
+    unsafe { libc::free(buffer) };
     ret
 }
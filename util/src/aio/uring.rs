@@ -71,6 +71,18 @@ impl<T: Clone> AioContext<T> for IoUringContext {
                     .build()
                     .flags(squeue::Flags::ASYNC)
                     .user_data(data),
+                OpCode::Discard | OpCode::WriteZeroes | OpCode::WriteZeroesUnmap => {
+                    let mode = match cb.opcode {
+                        OpCode::WriteZeroes => libc::FALLOC_FL_ZERO_RANGE,
+                        _ => libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                    };
+                    opcode::Fallocate64::new(fd, cb.nbytes as libc::off64_t)
+                        .offset64(offset as libc::off64_t)
+                        .mode(mode)
+                        .build()
+                        .flags(squeue::Flags::ASYNC)
+                        .user_data(data)
+                }
                 _ => {
                     bail!("Invalid entry code");
                 }
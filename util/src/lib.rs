@@ -27,16 +27,19 @@ pub mod logger;
 pub mod loop_context;
 pub mod num_ops;
 pub mod offsetof;
+pub mod pcap;
 #[cfg(not(target_env = "musl"))]
 pub mod pixman;
 pub mod reader;
 pub mod seccomp;
+pub mod socknet;
 pub mod syscall;
 pub mod tap;
 pub mod test_helper;
 pub mod time;
 pub mod trace;
 pub mod unix;
+pub mod usernet;
 pub use anyhow::Result;
 pub use error::UtilError;
 use libc::{tcgetattr, tcsetattr, termios, OPOST, TCSANOW};
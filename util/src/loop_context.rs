@@ -31,6 +31,10 @@ use std::fmt::Debug;
 
 const READY_EVENT_MAX: usize = 256;
 const AIO_PRFETCH_CYCLE_TIME: usize = 100;
+/// Initial time in nanoseconds to poll when the adaptive polling grows from zero.
+const POLL_NS_INIT: u64 = 4000;
+/// Default factor to grow the polling time.
+const POLL_GROW_DEFAULT: u64 = 2;
 
 #[derive(Debug)]
 pub enum NotifierOperation {
@@ -209,6 +213,14 @@ pub struct EventLoopContext {
     ready_events: Vec<EpollEvent>,
     /// Timer list
     timers: Arc<Mutex<Vec<Timer>>>,
+    /// Max time in nanoseconds to busy poll the handlers before epoll, zero
+    /// means the adaptive polling is disabled.
+    poll_max_ns: u64,
+    /// Factors to grow and shrink the polling time.
+    poll_grow: u64,
+    poll_shrink: u64,
+    /// Current time in nanoseconds to busy poll.
+    poll_ns: u64,
 }
 
 // SAFETY: The closure in EventNotifier and Timer doesn't impl Send, they're
@@ -228,6 +240,10 @@ impl EventLoopContext {
             gc: Arc::new(RwLock::new(Vec::new())),
             ready_events: vec![EpollEvent::default(); READY_EVENT_MAX],
             timers: Arc::new(Mutex::new(Vec::new())),
+            poll_max_ns: 0,
+            poll_grow: 0,
+            poll_shrink: 0,
+            poll_ns: 0,
         };
         ctx.init_kick();
         ctx
@@ -483,6 +499,10 @@ impl EventLoopContext {
             }
         }
 
+        if self.poll_max_ns != 0 {
+            return self.adaptive_poll_run();
+        }
+
         let timeout = self.timers_min_timeout_ms();
         if timeout == -1 {
             for _i in 0..AIO_PRFETCH_CYCLE_TIME {
@@ -502,6 +522,106 @@ impl EventLoopContext {
         self.epoll_wait_manager(timeout)
     }
 
+    /// Set the parameters of adaptive polling, the polling is disabled if
+    /// `max_ns` is zero. The polling time is doubled if `grow` is zero, and
+    /// reset to zero if `shrink` is zero.
+    pub fn set_poll_params(&mut self, max_ns: u64, grow: u64, shrink: u64) {
+        self.poll_max_ns = max_ns;
+        self.poll_grow = grow;
+        self.poll_shrink = shrink;
+        self.poll_ns = self.poll_ns.min(max_ns);
+    }
+
+    /// Get the max polling time, and the factors to grow and shrink it.
+    pub fn poll_params(&self) -> (u64, u64, u64) {
+        (self.poll_max_ns, self.poll_grow, self.poll_shrink)
+    }
+
+    /// Get the current time in nanoseconds to busy poll.
+    pub fn poll_ns(&self) -> u64 {
+        self.poll_ns
+    }
+
+    /// Busy poll the handlers for the current polling time, epoll is not blocked
+    /// if they make progress. Otherwise the polling time is adjusted by the time
+    /// spent to wait for the events.
+    fn adaptive_poll_run(&mut self) -> Result<bool> {
+        let start = Instant::now();
+        let mut poll_ns = self.poll_ns;
+        let timeout_ns = self.timers_min_timeout_ns();
+        if timeout_ns >= 0 {
+            poll_ns = poll_ns.min(timeout_ns as u64);
+        }
+        let progress = self.poll_handlers(poll_ns)?;
+
+        let timeout = match progress {
+            true => 0,
+            false => self.timers_min_timeout_ms(),
+        };
+        let ret = self.epoll_wait_manager(timeout)?;
+        if !progress {
+            self.adjust_poll_ns(start.elapsed().as_nanos() as u64);
+        }
+        Ok(ret)
+    }
+
+    /// Call the polling handlers until any of them makes progress, or `poll_ns`
+    /// nanoseconds elapse. The handlers are called once at least.
+    fn poll_handlers(&mut self, poll_ns: u64) -> Result<bool> {
+        let start = Instant::now();
+        loop {
+            let mut notifiers = Vec::new();
+            let mut progress = false;
+            for notifier in self.events.read().unwrap().values() {
+                let status_locked = notifier.status.lock().unwrap();
+                if *status_locked != EventStatus::Alive {
+                    continue;
+                }
+                if let Some(handler_poll) = notifier.handler_poll.as_ref() {
+                    if let Some(mut new_notifiers) =
+                        handler_poll(EventSet::empty(), notifier.raw_fd)
+                    {
+                        notifiers.append(&mut new_notifiers);
+                        progress = true;
+                    }
+                }
+            }
+            self.update_events(notifiers)?;
+            if progress {
+                return Ok(true);
+            }
+            if start.elapsed().as_nanos() as u64 >= poll_ns {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Adjust the polling time by the time `block_ns` taken to get the events.
+    /// The polling time shrinks if it can't catch the events within the max
+    /// polling time, and grows if it can.
+    fn adjust_poll_ns(&mut self, block_ns: u64) {
+        if block_ns <= self.poll_ns {
+            // The polling time is long enough.
+            return;
+        }
+        if block_ns > self.poll_max_ns {
+            self.poll_ns = match self.poll_shrink {
+                0 => 0,
+                shrink => self.poll_ns / shrink,
+            };
+        } else if self.poll_ns < self.poll_max_ns {
+            let grow = match self.poll_grow {
+                0 => POLL_GROW_DEFAULT,
+                grow => grow,
+            };
+            self.poll_ns = match self.poll_ns {
+                0 => POLL_NS_INIT,
+                ns => ns.saturating_mul(grow),
+            }
+            .min(self.poll_max_ns);
+        }
+    }
+
     /// Call the function given by `func` after `nsec` nanoseconds.
     ///
     /// # Arguments
@@ -912,4 +1032,48 @@ mod test {
 
         assert!(mainloop.update_events(vec![event]).is_ok());
     }
+
+    #[test]
+    fn adaptive_poll_test() {
+        let mut mainloop = EventLoopContext::new();
+        mainloop.set_poll_params(32000, 0, 0);
+
+        // The polling time grows from the initial time, up to the max polling time.
+        mainloop.adjust_poll_ns(10000);
+        assert_eq!(mainloop.poll_ns(), POLL_NS_INIT);
+        mainloop.adjust_poll_ns(10000);
+        assert_eq!(mainloop.poll_ns(), 8000);
+        mainloop.adjust_poll_ns(20000);
+        assert_eq!(mainloop.poll_ns(), 16000);
+        mainloop.adjust_poll_ns(20000);
+        assert_eq!(mainloop.poll_ns(), 32000);
+        // The events are caught within the polling time.
+        mainloop.adjust_poll_ns(1000);
+        assert_eq!(mainloop.poll_ns(), 32000);
+        // The polling time is reset if events come later than the max polling time.
+        mainloop.adjust_poll_ns(100000);
+        assert_eq!(mainloop.poll_ns(), 0);
+
+        mainloop.set_poll_params(32000, 4, 2);
+        mainloop.adjust_poll_ns(10000);
+        mainloop.adjust_poll_ns(10000);
+        assert_eq!(mainloop.poll_ns(), 16000);
+        mainloop.adjust_poll_ns(100000);
+        assert_eq!(mainloop.poll_ns(), 8000);
+
+        // The handlers making progress stop the polling.
+        let fd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mut notifier = EventNotifier::new(
+            NotifierOperation::AddShared,
+            fd.as_raw_fd(),
+            None,
+            EventSet::IN,
+            Vec::new(),
+        );
+        notifier.handler_poll = Some(Box::new(|_, _| Some(Vec::new())));
+        mainloop.update_events(vec![notifier]).unwrap();
+        assert!(mainloop.poll_handlers(u64::MAX).unwrap());
+        assert!(mainloop.iothread_run().unwrap());
+        assert_eq!(mainloop.poll_ns(), 8000);
+    }
 }
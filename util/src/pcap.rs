@@ -0,0 +1,161 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Writer of the pcap capture files, which can be read by tcpdump or wireshark.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_GLOBAL_HDR_LEN: u64 = 24;
+const PCAP_RECORD_HDR_LEN: u64 = 16;
+
+pub struct PcapWriter {
+    path: String,
+    file: File,
+    /// Max number of bytes saved for each packet.
+    snaplen: u32,
+    /// The file is rotated once it exceeds this size, 0 means no rotation.
+    max_size: u64,
+    /// Max number of the rotated files kept besides the current one.
+    max_files: u32,
+    /// Size of the current file.
+    size: u64,
+}
+
+impl PcapWriter {
+    pub fn new(path: &str, snaplen: u32, max_size: u64, max_files: u32) -> Result<Self> {
+        Ok(PcapWriter {
+            path: path.to_string(),
+            file: Self::create_file(path, snaplen)?,
+            snaplen,
+            max_size,
+            max_files,
+            size: PCAP_GLOBAL_HDR_LEN,
+        })
+    }
+
+    fn create_file(path: &str, snaplen: u32) -> Result<File> {
+        let mut file =
+            File::create(path).with_context(|| format!("Failed to create pcap file {}", path))?;
+        let mut hdr = Vec::with_capacity(PCAP_GLOBAL_HDR_LEN as usize);
+        hdr.extend_from_slice(&PCAP_MAGIC.to_le_bytes());
+        hdr.extend_from_slice(&PCAP_VERSION_MAJOR.to_le_bytes());
+        hdr.extend_from_slice(&PCAP_VERSION_MINOR.to_le_bytes());
+        // Timezone offset and accuracy of timestamps.
+        hdr.extend_from_slice(&0_i32.to_le_bytes());
+        hdr.extend_from_slice(&0_u32.to_le_bytes());
+        hdr.extend_from_slice(&snaplen.to_le_bytes());
+        hdr.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        file.write_all(&hdr)
+            .with_context(|| format!("Failed to write pcap header to {}", path))?;
+        Ok(file)
+    }
+
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen
+    }
+
+    /// Move `file` to `file.1`, `file.1` to `file.2` and so on, dropping the oldest one.
+    fn rotate(&mut self) -> Result<()> {
+        for i in (1..self.max_files).rev() {
+            let from = format!("{}.{}", self.path, i);
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))
+                    .with_context(|| format!("Failed to rotate pcap file {}", from))?;
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))
+            .with_context(|| format!("Failed to rotate pcap file {}", self.path))?;
+        self.file = Self::create_file(&self.path, self.snaplen)?;
+        self.size = PCAP_GLOBAL_HDR_LEN;
+        Ok(())
+    }
+
+    /// Write one captured packet. `data` has been truncated to the snap length
+    /// by the caller, and `orig_len` is the length of the packet on the wire.
+    pub fn write_packet(&mut self, data: &[u8], orig_len: usize) -> Result<()> {
+        let record_len = PCAP_RECORD_HDR_LEN + data.len() as u64;
+        if self.max_size != 0
+            && self.size > PCAP_GLOBAL_HDR_LEN
+            && self.size + record_len > self.max_size
+        {
+            self.rotate()?;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut record = Vec::with_capacity(record_len as usize);
+        record.extend_from_slice(&(now.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&now.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(orig_len as u32).to_le_bytes());
+        record.extend_from_slice(data);
+        self.file
+            .write_all(&record)
+            .with_context(|| format!("Failed to write packet to {}", self.path))?;
+        self.size += record_len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcap_write_and_rotate() {
+        let path = format!("/tmp/stratovirt_test_{}.pcap", std::process::id());
+        // Room for the header and two records with 10 bytes data.
+        let mut writer = PcapWriter::new(&path, 10, 24 + 2 * 26, 2).unwrap();
+        assert_eq!(writer.snaplen(), 10);
+
+        let content = fs::read(&path).unwrap();
+        assert_eq!(content.len(), 24);
+        assert_eq!(content[0..4], PCAP_MAGIC.to_le_bytes());
+        assert_eq!(content[16..20], 10_u32.to_le_bytes());
+        assert_eq!(content[20..24], LINKTYPE_ETHERNET.to_le_bytes());
+
+        writer.write_packet(&[1; 10], 100).unwrap();
+        writer.write_packet(&[2; 10], 10).unwrap();
+        let content = fs::read(&path).unwrap();
+        assert_eq!(content.len(), 24 + 2 * 26);
+        assert_eq!(content[32..36], 10_u32.to_le_bytes());
+        assert_eq!(content[36..40], 100_u32.to_le_bytes());
+        assert_eq!(content[40..50], [1; 10]);
+
+        // Rotate three times, only two old files are kept.
+        for i in 3..6 {
+            writer.write_packet(&[i; 10], 10).unwrap();
+            writer.write_packet(&[i; 10], 10).unwrap();
+        }
+        let content = fs::read(format!("{}.1", path)).unwrap();
+        assert_eq!(content[40..50], [4; 10]);
+        let content = fs::read(format!("{}.2", path)).unwrap();
+        assert_eq!(content[40..50], [3; 10]);
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+        let content = fs::read(&path).unwrap();
+        assert_eq!(content.len(), 24 + 2 * 26);
+        assert_eq!(content[40..50], [5; 10]);
+
+        for file in [path.clone(), format!("{}.1", path), format!("{}.2", path)] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
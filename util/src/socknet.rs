@@ -0,0 +1,629 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Socket network backend.
+//!
+//! The backend cables the net device to a remote peer, e.g. the net device of
+//! another VM, without any host bridge or privilege. Ethernet frames are sent
+//! over a stream socket with a 4 bytes big-endian length before each frame, or
+//! over udp with one frame in each datagram. The backend relays them between
+//! the remote socket and a socket pair which behaves like a tap device.
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::epoll::EventSet;
+
+use crate::loop_context::{
+    gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use crate::tap::Tap;
+use crate::usernet::VNET_HDR_LEN;
+
+/// Size of the frame length before each frame of the stream.
+const FRAME_LEN_SIZE: usize = 4;
+const ETH_HDR_LEN: usize = 14;
+/// Max length of the frame from the remote.
+const MAX_FRAME_LEN: usize = 65536;
+/// Max bytes buffered for the stream, frames of the net device are dropped
+/// beyond it as a congested link does.
+const MAX_PENDING_LEN: usize = 1 << 20;
+
+/// Address of the socket network backend.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SocketNetAddr {
+    /// Stream over tcp.
+    Inet(SocketAddr),
+    /// Stream over unix socket.
+    Unix(String),
+    /// Datagrams sent to `remote` and received on `local`.
+    Udp {
+        local: SocketAddr,
+        remote: SocketAddr,
+    },
+}
+
+/// Configuration of the socket network backend.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SocketNetConfig {
+    pub addr: SocketNetAddr,
+    /// Listen on the address and wait for the remote to connect, only for the stream.
+    pub server: bool,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn accept(&self) -> std::io::Result<File> {
+        let fd = match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                stream.into_raw_fd()
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                stream.into_raw_fd()
+            }
+        };
+        // SAFETY: The fd is just accepted and owned by the file.
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+pub struct SocketNet {
+    /// Frames are sent over the stream with their length, or over udp.
+    stream: bool,
+    /// Socket of the backend side to exchange frames with the net device.
+    sock: File,
+    /// Socket of the net device side.
+    peer: File,
+    /// Length of the virtio net header, set by the net device through the tap.
+    hdr_len: Arc<AtomicUsize>,
+    listener: Option<Listener>,
+    /// Socket connected to the remote.
+    remote: Option<File>,
+    /// Bytes received from the stream which do not form a whole frame yet.
+    rx_buf: Vec<u8>,
+    /// Frame from the remote which the net device can not receive yet.
+    rx_pending: Option<Vec<u8>>,
+    /// Bytes to be sent to the stream.
+    tx_buf: Vec<u8>,
+    /// Sockets added or removed, to be updated to the event loop.
+    added_fds: Vec<RawFd>,
+    removed_fds: Vec<RawFd>,
+}
+
+fn into_file<T: IntoRawFd>(socket: T) -> File {
+    // SAFETY: The fd is taken from the socket and owned by the file.
+    unsafe { File::from_raw_fd(socket.into_raw_fd()) }
+}
+
+impl SocketNet {
+    pub fn new(config: &SocketNetConfig) -> Result<Self> {
+        let (sock, peer) =
+            Tap::socket_pair().with_context(|| "Failed to create socket pair for socket netdev")?;
+
+        let mut listener = None;
+        let mut remote = None;
+        match &config.addr {
+            SocketNetAddr::Inet(addr) if config.server => {
+                let tcp = TcpListener::bind(addr)
+                    .with_context(|| format!("Failed to listen socket netdev on {}", addr))?;
+                tcp.set_nonblocking(true)?;
+                listener = Some(Listener::Tcp(tcp));
+            }
+            SocketNetAddr::Inet(addr) => {
+                let stream = TcpStream::connect(addr)
+                    .with_context(|| format!("Failed to connect socket netdev to {}", addr))?;
+                stream.set_nonblocking(true)?;
+                stream.set_nodelay(true)?;
+                remote = Some(into_file(stream));
+            }
+            SocketNetAddr::Unix(path) if config.server => {
+                if Path::new(path).exists() {
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("Failed to remove stale socket {}", path))?;
+                }
+                let unix = UnixListener::bind(path)
+                    .with_context(|| format!("Failed to listen socket netdev on {}", path))?;
+                unix.set_nonblocking(true)?;
+                listener = Some(Listener::Unix(unix));
+            }
+            SocketNetAddr::Unix(path) => {
+                let stream = UnixStream::connect(path)
+                    .with_context(|| format!("Failed to connect socket netdev to {}", path))?;
+                stream.set_nonblocking(true)?;
+                remote = Some(into_file(stream));
+            }
+            SocketNetAddr::Udp {
+                local,
+                remote: addr,
+            } => {
+                let udp = UdpSocket::bind(local)
+                    .with_context(|| format!("Failed to bind socket netdev on {}", local))?;
+                udp.connect(addr)
+                    .with_context(|| format!("Failed to connect socket netdev to {}", addr))?;
+                udp.set_nonblocking(true)?;
+                remote = Some(into_file(udp));
+            }
+        }
+
+        Ok(SocketNet {
+            stream: !matches!(config.addr, SocketNetAddr::Udp { .. }),
+            sock,
+            peer,
+            hdr_len: Arc::new(AtomicUsize::new(VNET_HDR_LEN)),
+            listener,
+            remote,
+            rx_buf: Vec::new(),
+            rx_pending: None,
+            tx_buf: Vec::new(),
+            added_fds: Vec::new(),
+            removed_fds: Vec::new(),
+        })
+    }
+
+    /// Get the tap-like endpoint, which the net device reads and writes frames with.
+    pub fn tap(&self) -> Result<Tap> {
+        Ok(Tap::from_socket(
+            self.peer.try_clone()?,
+            self.hdr_len.clone(),
+        ))
+    }
+
+    /// Get all the fds registered to the event loop.
+    pub fn notifier_fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.sock.as_raw_fd()];
+        fds.extend(self.listener.as_ref().map(|l| l.as_raw_fd()));
+        fds.extend(self.remote.as_ref().map(|r| r.as_raw_fd()));
+        fds
+    }
+
+    fn events(&self, fd: RawFd) -> EventSet {
+        if self.listener.as_ref().map(|l| l.as_raw_fd()) == Some(fd) {
+            EventSet::IN
+        } else {
+            EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED
+        }
+    }
+
+    fn handle_event(&mut self, fd: RawFd, event: EventSet) {
+        if fd == self.sock.as_raw_fd() {
+            if event.contains(EventSet::OUT) {
+                self.pump_remote();
+            }
+            if event.contains(EventSet::IN) {
+                self.handle_device();
+            }
+        } else if self.listener.as_ref().map(|l| l.as_raw_fd()) == Some(fd) {
+            self.handle_listener();
+        } else if self.remote.as_ref().map(|r| r.as_raw_fd()) == Some(fd) {
+            if event.contains(EventSet::OUT) {
+                self.flush_remote();
+            }
+            if event.intersects(EventSet::IN | EventSet::HANG_UP | EventSet::ERROR) {
+                self.pump_remote();
+            }
+        }
+    }
+
+    fn handle_listener(&mut self) {
+        loop {
+            let file = match self.listener.as_ref().unwrap().accept() {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to accept the remote of socket netdev: {:?}", e);
+                    break;
+                }
+            };
+            if self.remote.is_some() {
+                warn!("Socket netdev is connected already, refuse the new remote");
+                continue;
+            }
+            info!("Socket netdev is connected");
+            self.added_fds.push(file.as_raw_fd());
+            self.remote = Some(file);
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if let Some(remote) = self.remote.take() {
+            // No need to delete the socket which is not registered yet.
+            let fd = remote.as_raw_fd();
+            let pending = self.added_fds.len();
+            self.added_fds.retain(|added| *added != fd);
+            if self.added_fds.len() == pending {
+                self.removed_fds.push(fd);
+            }
+        }
+        self.rx_buf.clear();
+        self.rx_pending = None;
+        self.tx_buf.clear();
+        if self.listener.is_some() {
+            info!("Remote of socket netdev is disconnected, wait for the new one");
+        } else {
+            warn!("Remote of socket netdev is disconnected, frames will be dropped");
+        }
+    }
+
+    /// Relay the frames of the net device to the remote.
+    fn handle_device(&mut self) {
+        let hdr_len = self.hdr_len.load(Ordering::SeqCst);
+        let mut buf = vec![0_u8; VNET_HDR_LEN + MAX_FRAME_LEN];
+        loop {
+            let len = match self.sock.read(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to receive frame from the net device: {:?}", e);
+                    break;
+                }
+            };
+            if len >= hdr_len + ETH_HDR_LEN {
+                self.send_remote(&buf[hdr_len..len]);
+            }
+        }
+        self.flush_remote();
+    }
+
+    fn send_remote(&mut self, frame: &[u8]) {
+        let remote = match self.remote.as_mut() {
+            Some(remote) => remote,
+            None => return,
+        };
+        if self.stream {
+            if self.tx_buf.len() + FRAME_LEN_SIZE + frame.len() <= MAX_PENDING_LEN {
+                self.tx_buf
+                    .extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                self.tx_buf.extend_from_slice(frame);
+            }
+            return;
+        }
+        // The datagram is dropped if the remote is not ready.
+        if let Err(e) = remote.write(frame) {
+            if !matches!(
+                e.kind(),
+                ErrorKind::WouldBlock | ErrorKind::ConnectionRefused
+            ) {
+                error!("Failed to send frame to the remote: {:?}", e);
+            }
+        }
+    }
+
+    fn flush_remote(&mut self) {
+        while !self.tx_buf.is_empty() {
+            let remote = match self.remote.as_mut() {
+                Some(remote) => remote,
+                None => return,
+            };
+            match remote.write(&self.tx_buf) {
+                Ok(len) => {
+                    self.tx_buf.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to send frames to the remote: {:?}", e);
+                    self.disconnect();
+                }
+            }
+        }
+    }
+
+    /// Send the frame to the net device, return false if it is kept to be sent
+    /// after the net device receives the previous ones.
+    fn send_device(&mut self, frame: Vec<u8>) -> bool {
+        match self.sock.write(&frame) {
+            Ok(_) => true,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                self.rx_pending = Some(frame);
+                false
+            }
+            Err(e) => {
+                error!("Failed to send frame to the net device: {:?}", e);
+                true
+            }
+        }
+    }
+
+    /// Take the next whole frame received from the stream.
+    fn next_stream_frame(&mut self) -> Result<Option<Vec<u8>>, usize> {
+        if self.rx_buf.len() < FRAME_LEN_SIZE {
+            return Ok(None);
+        }
+        let mut len_bytes = [0_u8; FRAME_LEN_SIZE];
+        len_bytes.copy_from_slice(&self.rx_buf[..FRAME_LEN_SIZE]);
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if !(ETH_HDR_LEN..=MAX_FRAME_LEN).contains(&len) {
+            return Err(len);
+        }
+        if self.rx_buf.len() < FRAME_LEN_SIZE + len {
+            return Ok(None);
+        }
+        let mut frame = vec![0_u8; self.hdr_len.load(Ordering::SeqCst)];
+        frame.extend(
+            self.rx_buf
+                .drain(..FRAME_LEN_SIZE + len)
+                .skip(FRAME_LEN_SIZE),
+        );
+        Ok(Some(frame))
+    }
+
+    /// Relay the frames of the remote to the net device until it can not receive more.
+    fn pump_remote(&mut self) {
+        let hdr_len = self.hdr_len.load(Ordering::SeqCst);
+        let mut buf = vec![0_u8; VNET_HDR_LEN + MAX_FRAME_LEN];
+        loop {
+            if let Some(frame) = self.rx_pending.take() {
+                if !self.send_device(frame) {
+                    return;
+                }
+            }
+            loop {
+                match self.next_stream_frame() {
+                    Ok(Some(frame)) => {
+                        if !self.send_device(frame) {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(len) => {
+                        error!("Invalid frame length {} from the remote", len);
+                        self.disconnect();
+                        return;
+                    }
+                }
+            }
+
+            let remote = match self.remote.as_mut() {
+                Some(remote) => remote,
+                None => return,
+            };
+            if self.stream {
+                match remote.read(&mut buf) {
+                    Ok(0) => {
+                        self.disconnect();
+                        return;
+                    }
+                    Ok(len) => self.rx_buf.extend_from_slice(&buf[..len]),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Failed to receive frames from the remote: {:?}", e);
+                        self.disconnect();
+                        return;
+                    }
+                }
+            } else {
+                match remote.read(&mut buf[hdr_len..]) {
+                    Ok(len) if len >= ETH_HDR_LEN => {
+                        self.rx_pending = Some(buf[..hdr_len + len].to_vec());
+                    }
+                    Ok(_) => {}
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            ErrorKind::Interrupted | ErrorKind::ConnectionRefused
+                        ) => {}
+                    Err(e) => {
+                        error!("Failed to receive frame from the remote: {:?}", e);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn take_notifiers(net: &Arc<Mutex<SocketNet>>) -> Option<Vec<EventNotifier>> {
+        let mut locked_net = net.lock().unwrap();
+        let removed = std::mem::take(&mut locked_net.removed_fds);
+        let added = std::mem::take(&mut locked_net.added_fds)
+            .into_iter()
+            .map(|fd| (fd, locked_net.events(fd)))
+            .collect::<Vec<(RawFd, EventSet)>>();
+        drop(locked_net);
+        if removed.is_empty() && added.is_empty() {
+            return None;
+        }
+
+        // Deleted fds go first, as the number may be reused by the added socket.
+        let mut notifiers = gen_delete_notifiers(&removed);
+        for (fd, event) in added {
+            notifiers.push(SocketNet::notifier(net, fd, event));
+        }
+        Some(notifiers)
+    }
+
+    fn notifier(net: &Arc<Mutex<SocketNet>>, fd: RawFd, event: EventSet) -> EventNotifier {
+        let cloned_net = net.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |event, fd| {
+            cloned_net.lock().unwrap().handle_event(fd, event);
+            SocketNet::take_notifiers(&cloned_net)
+        });
+        EventNotifier::new(NotifierOperation::AddShared, fd, None, event, vec![handler])
+    }
+}
+
+impl EventNotifierHelper for SocketNet {
+    fn internal_notifiers(net: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let locked_net = net.lock().unwrap();
+        let fds = locked_net
+            .notifier_fds()
+            .into_iter()
+            .map(|fd| (fd, locked_net.events(fd)))
+            .collect::<Vec<(RawFd, EventSet)>>();
+        drop(locked_net);
+        fds.into_iter()
+            .map(|(fd, event)| SocketNet::notifier(&net, fd, event))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(byte: u8, len: usize) -> Vec<u8> {
+        let mut frame = vec![0_u8; VNET_HDR_LEN];
+        frame.resize(VNET_HDR_LEN + len, byte);
+        frame
+    }
+
+    fn recv_frame(peer: &mut File) -> Vec<u8> {
+        let mut buf = vec![0_u8; VNET_HDR_LEN + MAX_FRAME_LEN];
+        let len = peer.read(&mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    /// Relay all the pending frames of the two backends.
+    fn relay(nets: &mut [&mut SocketNet]) {
+        for _ in 0..4 {
+            for net in nets.iter_mut() {
+                net.handle_event(net.sock.as_raw_fd(), EventSet::IN | EventSet::OUT);
+                if let Some(fd) = net.listener.as_ref().map(|l| l.as_raw_fd()) {
+                    net.handle_event(fd, EventSet::IN);
+                }
+                if let Some(fd) = net.remote.as_ref().map(|r| r.as_raw_fd()) {
+                    net.handle_event(fd, EventSet::IN | EventSet::OUT);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_socknet_unix_stream() {
+        let path = format!("/tmp/socknet_test_{}.sock", std::process::id());
+        let server_cfg = SocketNetConfig {
+            addr: SocketNetAddr::Unix(path.clone()),
+            server: true,
+        };
+        let mut server = SocketNet::new(&server_cfg).unwrap();
+        let client_cfg = SocketNetConfig {
+            addr: SocketNetAddr::Unix(path.clone()),
+            server: false,
+        };
+        let mut client = SocketNet::new(&client_cfg).unwrap();
+        assert!(client.remote.is_some());
+        relay(&mut [&mut server, &mut client]);
+        assert!(server.remote.is_some());
+        assert_eq!(server.added_fds.len(), 1);
+
+        let mut server_dev = server.tap().unwrap();
+        let mut client_dev = client.tap().unwrap();
+        assert!(!server_dev.has_offload());
+        assert!(!server_dev.has_ufo());
+
+        // Frames are passed in order with the virtio net header, and runts are dropped.
+        server_dev.write(&frame(1, 60)).unwrap();
+        server_dev.write(&frame(2, 4)).unwrap();
+        server_dev.write(&frame(3, 1514)).unwrap();
+        relay(&mut [&mut server, &mut client]);
+        assert_eq!(recv_frame(&mut client_dev.file), frame(1, 60));
+        assert_eq!(recv_frame(&mut client_dev.file), frame(3, 1514));
+
+        client_dev.write(&frame(4, 100)).unwrap();
+        relay(&mut [&mut server, &mut client]);
+        assert_eq!(recv_frame(&mut server_dev.file), frame(4, 100));
+
+        // The server waits for the new remote after the client goes away.
+        drop(client);
+        relay(&mut [&mut server]);
+        assert!(server.remote.is_none());
+        assert!(server.listener.is_some());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_socknet_udp() {
+        let addr1 = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+        let probe = UdpSocket::bind(addr1).unwrap();
+        let addr2 = probe.local_addr().unwrap();
+        drop(probe);
+        let probe = UdpSocket::bind(addr1).unwrap();
+        let addr1 = probe.local_addr().unwrap();
+        drop(probe);
+
+        let mut net1 = SocketNet::new(&SocketNetConfig {
+            addr: SocketNetAddr::Udp {
+                local: addr1,
+                remote: addr2,
+            },
+            server: false,
+        })
+        .unwrap();
+        let mut net2 = SocketNet::new(&SocketNetConfig {
+            addr: SocketNetAddr::Udp {
+                local: addr2,
+                remote: addr1,
+            },
+            server: false,
+        })
+        .unwrap();
+        let mut dev1 = net1.tap().unwrap();
+        let mut dev2 = net2.tap().unwrap();
+
+        dev1.write(&frame(5, 60)).unwrap();
+        dev2.write(&frame(6, 1514)).unwrap();
+        relay(&mut [&mut net1, &mut net2]);
+        assert_eq!(recv_frame(&mut dev2.file), frame(5, 60));
+        assert_eq!(recv_frame(&mut dev1.file), frame(6, 1514));
+    }
+
+    #[test]
+    fn test_socknet_invalid_frame_len() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut net = SocketNet::new(&SocketNetConfig {
+            addr: SocketNetAddr::Inet(addr),
+            server: false,
+        })
+        .unwrap();
+        let (mut remote, _) = listener.accept().unwrap();
+
+        let mut data = 64_u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&[7; 64]);
+        data.extend_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+        remote.write_all(&data).unwrap();
+        relay(&mut [&mut net]);
+        let mut dev = net.tap().unwrap();
+        assert_eq!(recv_frame(&mut dev.file), frame(7, 64));
+        assert!(net.remote.is_none());
+    }
+}
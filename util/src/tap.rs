@@ -15,6 +15,8 @@ use std::fs::{File, OpenOptions};
 use std::io::{Read, Result as IoResult, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr};
 
@@ -47,6 +49,9 @@ pub struct IfReq {
 
 pub struct Tap {
     pub file: File,
+    /// Length of the virtio net header shared with the in-process backend, if the
+    /// fd is a socket of the backend rather than a tap device.
+    socket_hdr_len: Option<Arc<AtomicUsize>>,
 }
 
 impl Tap {
@@ -112,10 +117,60 @@ impl Tap {
             bail!("Needs multiqueue, but no kernel support for IFF_MULTI_QUEUE available");
         }
 
-        Ok(Tap { file })
+        Ok(Tap {
+            file,
+            socket_hdr_len: None,
+        })
+    }
+
+    /// Create a socket pair for the in-process backend, the first socket is used
+    /// by the backend and the second one is the tap-like endpoint of the net device.
+    pub fn socket_pair() -> Result<(File, File)> {
+        let mut fds = [0; 2];
+        // SAFETY: The fds array is valid for the socket pair.
+        let ret = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        if ret < 0 {
+            return Err(anyhow!(
+                "Failed to create socket pair, error is {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        // SAFETY: The fds are just created and owned by the files.
+        Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+    }
+
+    /// Create the tap-like endpoint from the socket of the in-process backend, which
+    /// takes the same frames as the tap device but supports no offload.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - The socket of the net device side.
+    /// * `hdr_len` - Length of the virtio net header, which is set by the net device
+    ///   and used by the backend.
+    pub fn from_socket(file: File, hdr_len: Arc<AtomicUsize>) -> Self {
+        Tap {
+            file,
+            socket_hdr_len: Some(hdr_len),
+        }
     }
 
     pub fn set_offload(&self, flags: u32) -> Result<()> {
+        if self.socket_hdr_len.is_some() {
+            if flags != 0 {
+                bail!(
+                    "Offload {:#x} is not supported by the socket backend",
+                    flags
+                );
+            }
+            return Ok(());
+        }
         let ret = unsafe { ioctl_with_val(&self.file, TUNSETOFFLOAD(), flags as libc::c_ulong) };
         if ret < 0 {
             return Err(anyhow!("ioctl TUNSETOFFLOAD failed.".to_string()));
@@ -125,6 +180,10 @@ impl Tap {
     }
 
     pub fn set_hdr_size(&self, len: u32) -> Result<()> {
+        if let Some(hdr_len) = self.socket_hdr_len.as_ref() {
+            hdr_len.store(len as usize, Ordering::SeqCst);
+            return Ok(());
+        }
         let ret = unsafe { ioctl_with_ref(&self.file, TUNSETVNETHDRSZ(), &len) };
         if ret < 0 {
             return Err(anyhow!("ioctl TUNSETVNETHDRSZ failed.".to_string()));
@@ -133,7 +192,15 @@ impl Tap {
         Ok(())
     }
 
+    /// Whether the frames with partial checksum or segmentation offload are supported.
+    pub fn has_offload(&self) -> bool {
+        self.socket_hdr_len.is_none()
+    }
+
     pub fn has_ufo(&self) -> bool {
+        if self.socket_hdr_len.is_some() {
+            return false;
+        }
         let flags = TUN_F_CSUM | TUN_F_UFO;
         (unsafe { ioctl_with_val(&self.file, TUNSETOFFLOAD(), flags as libc::c_ulong) }) >= 0
     }
@@ -155,6 +222,7 @@ impl Clone for Tap {
     fn clone(&self) -> Self {
         Tap {
             file: self.file.try_clone().unwrap(),
+            socket_hdr_len: self.socket_hdr_len.clone(),
         }
     }
 }
@@ -0,0 +1,313 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! A minimal DHCP server which leases the addresses of the user-mode network.
+
+use std::net::Ipv4Addr;
+
+use super::packet::MacAddr;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTP_REQUEST: u8 = 1;
+const BOOTP_REPLY: u8 = 2;
+const BOOTP_HDR_LEN: usize = 236;
+const BOOTP_FLAG_BROADCAST: u16 = 0x8000;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+const DHCP_REQUEST: u8 = 3;
+const DHCP_ACK: u8 = 5;
+const DHCP_NAK: u8 = 6;
+const DHCP_RELEASE: u8 = 7;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MSG_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+/// Lease time in seconds.
+const LEASE_TIME: u32 = 86400;
+/// Max number of the addresses to lease.
+pub const MAX_LEASES: u32 = 16;
+
+pub struct DhcpReply {
+    /// Payload of the udp datagram.
+    pub data: Vec<u8>,
+    pub client_mac: MacAddr,
+    /// Address leased to the client, unspecified for the NAK.
+    pub client_addr: Ipv4Addr,
+    /// Reply needs to be broadcasted as the client can not receive unicast yet.
+    pub broadcast: bool,
+}
+
+struct DhcpRequest {
+    msg_type: u8,
+    xid: [u8; 4],
+    flags: u16,
+    ciaddr: Ipv4Addr,
+    chaddr: MacAddr,
+    requested_ip: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+}
+
+fn parse_request(buf: &[u8]) -> Option<DhcpRequest> {
+    if buf.len() < BOOTP_HDR_LEN + DHCP_MAGIC.len()
+        || buf[0] != BOOTP_REQUEST
+        || buf[1] != 1
+        || buf[2] != 6
+        || buf[BOOTP_HDR_LEN..BOOTP_HDR_LEN + 4] != DHCP_MAGIC
+    {
+        return None;
+    }
+
+    let mut req = DhcpRequest {
+        msg_type: 0,
+        xid: [buf[4], buf[5], buf[6], buf[7]],
+        flags: u16::from_be_bytes([buf[10], buf[11]]),
+        ciaddr: Ipv4Addr::new(buf[12], buf[13], buf[14], buf[15]),
+        chaddr: [0; 6],
+        requested_ip: None,
+        server_id: None,
+    };
+    req.chaddr.copy_from_slice(&buf[28..34]);
+
+    let mut opts = &buf[BOOTP_HDR_LEN + 4..];
+    while let Some(&code) = opts.first() {
+        match code {
+            OPT_END => break,
+            OPT_PAD => opts = &opts[1..],
+            _ => {
+                if opts.len() < 2 || opts.len() < 2 + usize::from(opts[1]) {
+                    break;
+                }
+                let val = &opts[2..2 + usize::from(opts[1])];
+                match (code, val.len()) {
+                    (OPT_MSG_TYPE, 1) => req.msg_type = val[0],
+                    (OPT_REQUESTED_IP, 4) => {
+                        req.requested_ip = Some(Ipv4Addr::new(val[0], val[1], val[2], val[3]))
+                    }
+                    (OPT_SERVER_ID, 4) => {
+                        req.server_id = Some(Ipv4Addr::new(val[0], val[1], val[2], val[3]))
+                    }
+                    _ => {}
+                }
+                opts = &opts[2 + val.len()..];
+            }
+        }
+    }
+    if req.msg_type == 0 {
+        return None;
+    }
+
+    Some(req)
+}
+
+pub struct DhcpServer {
+    server: Ipv4Addr,
+    mask: Ipv4Addr,
+    dns: Ipv4Addr,
+    start: Ipv4Addr,
+    /// Client of each address from `start`.
+    leases: Vec<Option<MacAddr>>,
+}
+
+impl DhcpServer {
+    pub fn new(
+        server: Ipv4Addr,
+        mask: Ipv4Addr,
+        dns: Ipv4Addr,
+        start: Ipv4Addr,
+        pool_size: u32,
+    ) -> Self {
+        DhcpServer {
+            server,
+            mask,
+            dns,
+            start,
+            leases: vec![None; pool_size as usize],
+        }
+    }
+
+    fn lease_addr(&self, index: usize) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.start) + index as u32)
+    }
+
+    fn find_lease(&self, mac: &MacAddr) -> Option<Ipv4Addr> {
+        self.leases
+            .iter()
+            .position(|lease| lease.as_ref() == Some(mac))
+            .map(|index| self.lease_addr(index))
+    }
+
+    fn allocate(&mut self, mac: &MacAddr) -> Option<Ipv4Addr> {
+        if let Some(addr) = self.find_lease(mac) {
+            return Some(addr);
+        }
+        let index = self.leases.iter().position(|lease| lease.is_none())?;
+        self.leases[index] = Some(*mac);
+        Some(self.lease_addr(index))
+    }
+
+    fn release(&mut self, mac: &MacAddr) {
+        for lease in self.leases.iter_mut() {
+            if lease.as_ref() == Some(mac) {
+                *lease = None;
+            }
+        }
+    }
+
+    /// Handle the DHCP message from the client, return the reply if needed.
+    pub fn handle(&mut self, buf: &[u8]) -> Option<DhcpReply> {
+        let req = parse_request(buf)?;
+        match req.msg_type {
+            DHCP_DISCOVER => {
+                let addr = self.allocate(&req.chaddr)?;
+                Some(self.reply(&req, DHCP_OFFER, addr))
+            }
+            DHCP_REQUEST => {
+                // The client has selected another server.
+                if matches!(req.server_id, Some(id) if id != self.server) {
+                    return None;
+                }
+                let requested = req
+                    .requested_ip
+                    .or_else(|| (!req.ciaddr.is_unspecified()).then_some(req.ciaddr));
+                match self.allocate(&req.chaddr) {
+                    Some(addr) if requested.is_none() || requested == Some(addr) => {
+                        Some(self.reply(&req, DHCP_ACK, addr))
+                    }
+                    _ => Some(self.reply(&req, DHCP_NAK, Ipv4Addr::UNSPECIFIED)),
+                }
+            }
+            DHCP_RELEASE => {
+                self.release(&req.chaddr);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn reply(&self, req: &DhcpRequest, msg_type: u8, addr: Ipv4Addr) -> DhcpReply {
+        let mut data = vec![0_u8; BOOTP_HDR_LEN];
+        data[0] = BOOTP_REPLY;
+        data[1] = 1;
+        data[2] = 6;
+        data[4..8].copy_from_slice(&req.xid);
+        data[10..12].copy_from_slice(&req.flags.to_be_bytes());
+        data[16..20].copy_from_slice(&addr.octets());
+        data[20..24].copy_from_slice(&self.server.octets());
+        data[28..34].copy_from_slice(&req.chaddr);
+        data.extend_from_slice(&DHCP_MAGIC);
+
+        data.extend_from_slice(&[OPT_MSG_TYPE, 1, msg_type]);
+        data.extend_from_slice(&[OPT_SERVER_ID, 4]);
+        data.extend_from_slice(&self.server.octets());
+        if msg_type != DHCP_NAK {
+            data.extend_from_slice(&[OPT_LEASE_TIME, 4]);
+            data.extend_from_slice(&LEASE_TIME.to_be_bytes());
+            data.extend_from_slice(&[OPT_SUBNET_MASK, 4]);
+            data.extend_from_slice(&self.mask.octets());
+            data.extend_from_slice(&[OPT_ROUTER, 4]);
+            data.extend_from_slice(&self.server.octets());
+            data.extend_from_slice(&[OPT_DNS, 4]);
+            data.extend_from_slice(&self.dns.octets());
+        }
+        data.push(OPT_END);
+
+        DhcpReply {
+            data,
+            client_mac: req.chaddr,
+            client_addr: addr,
+            broadcast: msg_type == DHCP_NAK || req.flags & BOOTP_FLAG_BROADCAST != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(msg_type: u8, mac: MacAddr, requested_ip: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut buf = vec![0_u8; BOOTP_HDR_LEN];
+        buf[0] = BOOTP_REQUEST;
+        buf[1] = 1;
+        buf[2] = 6;
+        buf[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        buf[28..34].copy_from_slice(&mac);
+        buf.extend_from_slice(&DHCP_MAGIC);
+        buf.extend_from_slice(&[OPT_MSG_TYPE, 1, msg_type]);
+        if let Some(ip) = requested_ip {
+            buf.extend_from_slice(&[OPT_REQUESTED_IP, 4]);
+            buf.extend_from_slice(&ip.octets());
+        }
+        buf.push(OPT_END);
+        buf
+    }
+
+    fn reply_type(reply: &DhcpReply) -> u8 {
+        assert_eq!(reply.data[BOOTP_HDR_LEN + 4], OPT_MSG_TYPE);
+        reply.data[BOOTP_HDR_LEN + 6]
+    }
+
+    #[test]
+    fn test_dhcp_lease() {
+        let mut server = DhcpServer::new(
+            Ipv4Addr::new(10, 0, 2, 2),
+            Ipv4Addr::new(255, 255, 255, 0),
+            Ipv4Addr::new(10, 0, 2, 3),
+            Ipv4Addr::new(10, 0, 2, 15),
+            2,
+        );
+        let mac1 = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+        let mac2 = [0x52, 0x54, 0, 0x12, 0x34, 0x57];
+        let mac3 = [0x52, 0x54, 0, 0x12, 0x34, 0x58];
+
+        let offer = server.handle(&request(DHCP_DISCOVER, mac1, None)).unwrap();
+        assert_eq!(reply_type(&offer), DHCP_OFFER);
+        assert_eq!(offer.client_addr, Ipv4Addr::new(10, 0, 2, 15));
+        assert_eq!(offer.client_mac, mac1);
+        assert!(!offer.broadcast);
+
+        let ack = server
+            .handle(&request(DHCP_REQUEST, mac1, Some(offer.client_addr)))
+            .unwrap();
+        assert_eq!(reply_type(&ack), DHCP_ACK);
+        assert_eq!(ack.client_addr, offer.client_addr);
+
+        // The second client gets the next address, and a wrong request is refused.
+        let nak = server
+            .handle(&request(
+                DHCP_REQUEST,
+                mac2,
+                Some(Ipv4Addr::new(10, 0, 2, 15)),
+            ))
+            .unwrap();
+        assert_eq!(reply_type(&nak), DHCP_NAK);
+        assert!(nak.broadcast);
+        let ack = server.handle(&request(DHCP_REQUEST, mac2, None)).unwrap();
+        assert_eq!(ack.client_addr, Ipv4Addr::new(10, 0, 2, 16));
+
+        // The pool is exhausted until a lease is released.
+        assert!(server.handle(&request(DHCP_DISCOVER, mac3, None)).is_none());
+        assert!(server.handle(&request(DHCP_RELEASE, mac1, None)).is_none());
+        let offer = server.handle(&request(DHCP_DISCOVER, mac3, None)).unwrap();
+        assert_eq!(offer.client_addr, Ipv4Addr::new(10, 0, 2, 15));
+    }
+}
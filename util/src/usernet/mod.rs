@@ -0,0 +1,1237 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! User-mode network stack.
+//!
+//! The stack emulates a NAT gateway for the guest without any tap device or
+//! privilege. Frames of the guest are exchanged with the net device through a
+//! socket pair which behaves like a tap device, the gateway answers ARP, DHCP
+//! and ICMP echo itself and proxies the TCP and UDP flows through host sockets.
+
+mod dhcp;
+mod packet;
+mod tcp;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::mem::size_of;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::timerfd::TimerFd;
+
+use crate::loop_context::{
+    gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use crate::tap::Tap;
+use dhcp::{DhcpServer, DHCP_CLIENT_PORT, DHCP_SERVER_PORT, MAX_LEASES};
+use packet::{
+    build_arp, build_eth, build_icmp_echo_reply, build_ipv4, build_tcp, build_udp, parse_arp,
+    parse_eth, parse_ipv4, parse_tcp, parse_udp, Ipv4Packet, MacAddr, TcpHeader, ARP_OP_REPLY,
+    ARP_OP_REQUEST, BROADCAST_MAC, ETH_P_ARP, ETH_P_IP, IPPROTO_ICMP, IPPROTO_TCP, IPPROTO_UDP,
+    TCP_ACK, TCP_RST, TCP_SYN,
+};
+use tcp::TcpConn;
+
+/// Max length of the virtio net header prepended to each frame. The net device
+/// sets the length negotiated with the guest, which is 10 for the legacy guest
+/// without `VIRTIO_NET_F_MRG_RXBUF`.
+pub const VNET_HDR_LEN: usize = 12;
+/// Max length of the frame with the virtio net header.
+const MAX_FRAME_LEN: usize = VNET_HDR_LEN + 14 + 65535;
+/// Max frames handled for one event of the guest, to avoid starving others.
+const MAX_FRAMES_PER_EVENT: usize = 256;
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// Interval of the timer to retransmit tcp segments and expire udp sessions.
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
+const UDP_TIMEOUT: Duration = Duration::from_secs(60);
+const UDP_DNS_TIMEOUT: Duration = Duration::from_secs(10);
+/// First port of the gateway to forward the host connections from.
+const FWD_PORT_BASE: u16 = 1024;
+
+/// Port forwarding rule from the host to the guest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HostFwd {
+    pub udp: bool,
+    /// Host address to listen on, unspecified for any address.
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    /// Guest address to forward to, unspecified for the first dhcp address.
+    pub guest_addr: Ipv4Addr,
+    pub guest_port: u16,
+}
+
+fn parse_fwd_addr(addr: &str) -> Result<Ipv4Addr> {
+    if addr.is_empty() {
+        return Ok(Ipv4Addr::UNSPECIFIED);
+    }
+    addr.parse::<Ipv4Addr>()
+        .map_err(|_| anyhow!("Invalid address {:?} of hostfwd", addr))
+}
+
+fn parse_fwd_port(port: &str) -> Result<u16> {
+    port.parse::<u16>()
+        .ok()
+        .filter(|port| *port != 0)
+        .ok_or_else(|| anyhow!("Invalid port {:?} of hostfwd", port))
+}
+
+impl FromStr for HostFwd {
+    type Err = anyhow::Error;
+
+    /// Parse the rule in the format of `[tcp|udp]:[hostaddr]:hostport-[guestaddr]:guestport`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (host, guest) = s
+            .split_once('-')
+            .ok_or_else(|| anyhow!("Invalid hostfwd {:?}, missing guest part", s))?;
+        let host_parts = host.split(':').collect::<Vec<&str>>();
+        if host_parts.len() != 3 {
+            bail!(
+                "Invalid hostfwd {:?}, expected [tcp|udp]:[hostaddr]:hostport",
+                s
+            );
+        }
+        let udp = match host_parts[0] {
+            "" | "tcp" => false,
+            "udp" => true,
+            proto => bail!("Invalid protocol {:?} of hostfwd", proto),
+        };
+        let (guest_addr, guest_port) = guest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid hostfwd {:?}, expected [guestaddr]:guestport", s))?;
+
+        Ok(HostFwd {
+            udp,
+            host_addr: parse_fwd_addr(host_parts[1])?,
+            host_port: parse_fwd_port(host_parts[2])?,
+            guest_addr: parse_fwd_addr(guest_addr)?,
+            guest_port: parse_fwd_port(guest_port)?,
+        })
+    }
+}
+
+/// Configuration of the user-mode network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserNetConfig {
+    /// Network address of the virtual network.
+    pub net: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Address of the gateway, which is also the host seen from the guest.
+    pub host: Ipv4Addr,
+    /// First address leased to the guest by dhcp.
+    pub dhcp_start: Ipv4Addr,
+    /// Address of the virtual dns server.
+    pub dns: Ipv4Addr,
+    pub hostfwd: Vec<HostFwd>,
+}
+
+impl Default for UserNetConfig {
+    fn default() -> Self {
+        UserNetConfig {
+            net: Ipv4Addr::new(10, 0, 2, 0),
+            prefix_len: 24,
+            host: Ipv4Addr::new(10, 0, 2, 2),
+            dhcp_start: Ipv4Addr::new(10, 0, 2, 15),
+            dns: Ipv4Addr::new(10, 0, 2, 3),
+            hostfwd: Vec::new(),
+        }
+    }
+}
+
+impl UserNetConfig {
+    pub fn mask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32 - u32::from(self.prefix_len))
+                .unwrap_or(0),
+        )
+    }
+
+    fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.net) | !u32::from(self.mask()))
+    }
+
+    /// Whether the address is a host address of the virtual network.
+    fn is_host_addr(&self, addr: Ipv4Addr) -> bool {
+        addr != self.net
+            && addr != self.broadcast()
+            && u32::from(addr) & u32::from(self.mask()) == u32::from(self.net)
+    }
+
+    fn dhcp_pool_size(&self) -> u32 {
+        (u32::from(self.broadcast()) - u32::from(self.dhcp_start)).min(MAX_LEASES)
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if !(8..=30).contains(&self.prefix_len) {
+            bail!("Invalid prefix length {} of user network", self.prefix_len);
+        }
+        if u32::from(self.net) & !u32::from(self.mask()) != 0 {
+            bail!("Invalid network address {}/{}", self.net, self.prefix_len);
+        }
+        for (name, addr) in [
+            ("host", self.host),
+            ("dhcpstart", self.dhcp_start),
+            ("dns", self.dns),
+        ] {
+            if !self.is_host_addr(addr) {
+                bail!(
+                    "Address {} of {} is not in network {}/{}",
+                    addr,
+                    name,
+                    self.net,
+                    self.prefix_len
+                );
+            }
+        }
+        if self.host == self.dns {
+            bail!("The host and dns addresses of user network are the same");
+        }
+        let pool = u32::from(self.dhcp_start)..u32::from(self.dhcp_start) + self.dhcp_pool_size();
+        if pool.contains(&u32::from(self.host)) || pool.contains(&u32::from(self.dns)) {
+            bail!("The dhcp addresses of user network overlap the host or dns address");
+        }
+        for fwd in self.hostfwd.iter() {
+            if !fwd.guest_addr.is_unspecified() && !self.is_host_addr(fwd.guest_addr) {
+                bail!(
+                    "Guest address {} of hostfwd is not in user network",
+                    fwd.guest_addr
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Flow seen from the guest, both directions of it are handled by one host socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Flow {
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+struct UdpSession {
+    /// Socket connected to the remote, None for the forwarded session which
+    /// replies through the listener socket.
+    socket: Option<UdpSocket>,
+    /// Index of the listener and the host peer of the forwarded session.
+    peer: Option<(usize, SocketAddr)>,
+    last_active: Instant,
+}
+
+#[derive(Clone, Copy)]
+enum SocketKind {
+    Guest,
+    Timer,
+    TcpListener(usize),
+    UdpListener(usize),
+    Tcp(Flow),
+    Udp(Flow),
+}
+
+pub struct UserNet {
+    config: UserNetConfig,
+    /// Mac address of the gateway.
+    mac: MacAddr,
+    /// Socket of the stack side to exchange frames with the net device.
+    sock: File,
+    /// Socket of the net device side.
+    peer: File,
+    /// Length of the virtio net header, set by the net device through the tap.
+    hdr_len: Arc<AtomicUsize>,
+    timer: TimerFd,
+    timer_armed: bool,
+    dhcp: DhcpServer,
+    arp_table: HashMap<Ipv4Addr, MacAddr>,
+    /// Dns server of the host which the virtual dns server forwards to.
+    dns_server: Option<Ipv4Addr>,
+    tcp_listeners: Vec<(HostFwd, TcpListener)>,
+    udp_listeners: Vec<(HostFwd, UdpSocket)>,
+    tcp_conns: HashMap<Flow, TcpConn>,
+    udp_sessions: HashMap<Flow, UdpSession>,
+    sockets: HashMap<RawFd, SocketKind>,
+    next_port: u16,
+    /// Host sockets added or removed, to be updated to the event loop.
+    added_fds: Vec<(RawFd, EventSet)>,
+    removed_fds: Vec<RawFd>,
+}
+
+fn host_dns_server() -> Option<Ipv4Addr> {
+    let conf = std::fs::read_to_string(RESOLV_CONF).ok()?;
+    conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => addr.parse::<Ipv4Addr>().ok(),
+            _ => None,
+        }
+    })
+}
+
+/// Connect to the address without blocking, the result is reported by the
+/// writable event of the socket.
+fn tcp_connect_nonblocking(addr: SocketAddrV4) -> Result<TcpStream> {
+    // SAFETY: The arguments are valid, and the returned fd is checked.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(anyhow!(std::io::Error::last_os_error()));
+    }
+    // SAFETY: The fd is just created and owned by the stream.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from_ne_bytes(addr.ip().octets()),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: The sockaddr is valid and its size is correct.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(anyhow!(err));
+        }
+    }
+    Ok(stream)
+}
+
+fn initial_seq() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() ^ (d.as_secs() as u32).rotate_left(16))
+        .unwrap_or(0)
+}
+
+impl UserNet {
+    pub fn new(config: &UserNetConfig) -> Result<Self> {
+        config.check()?;
+
+        let (sock, peer) =
+            Tap::socket_pair().with_context(|| "Failed to create socket pair for user network")?;
+
+        let mut tcp_listeners = Vec::new();
+        let mut udp_listeners = Vec::new();
+        for fwd in config.hostfwd.iter() {
+            let addr = SocketAddrV4::new(fwd.host_addr, fwd.host_port);
+            if fwd.udp {
+                let socket = UdpSocket::bind(addr)
+                    .with_context(|| format!("Failed to bind udp hostfwd on {}", addr))?;
+                socket.set_nonblocking(true)?;
+                udp_listeners.push((fwd.clone(), socket));
+            } else {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("Failed to listen tcp hostfwd on {}", addr))?;
+                listener.set_nonblocking(true)?;
+                tcp_listeners.push((fwd.clone(), listener));
+            }
+        }
+
+        let dns_server = host_dns_server();
+        if dns_server.is_none() {
+            warn!(
+                "No dns server found in {}, dns of user network is disabled",
+                RESOLV_CONF
+            );
+        }
+
+        let host = config.host.octets();
+        let mut usernet = UserNet {
+            config: config.clone(),
+            mac: [0x52, 0x55, host[0], host[1], host[2], host[3]],
+            sock,
+            peer,
+            hdr_len: Arc::new(AtomicUsize::new(VNET_HDR_LEN)),
+            timer: TimerFd::new().with_context(|| "Failed to create timer for user network")?,
+            timer_armed: false,
+            dhcp: DhcpServer::new(
+                config.host,
+                config.mask(),
+                config.dns,
+                config.dhcp_start,
+                config.dhcp_pool_size(),
+            ),
+            arp_table: HashMap::new(),
+            dns_server,
+            tcp_listeners,
+            udp_listeners,
+            tcp_conns: HashMap::new(),
+            udp_sessions: HashMap::new(),
+            sockets: HashMap::new(),
+            next_port: FWD_PORT_BASE,
+            added_fds: Vec::new(),
+            removed_fds: Vec::new(),
+        };
+        usernet
+            .sockets
+            .insert(usernet.sock.as_raw_fd(), SocketKind::Guest);
+        usernet
+            .sockets
+            .insert(usernet.timer.as_raw_fd(), SocketKind::Timer);
+        for (index, (_, listener)) in usernet.tcp_listeners.iter().enumerate() {
+            usernet
+                .sockets
+                .insert(listener.as_raw_fd(), SocketKind::TcpListener(index));
+        }
+        for (index, (_, socket)) in usernet.udp_listeners.iter().enumerate() {
+            usernet
+                .sockets
+                .insert(socket.as_raw_fd(), SocketKind::UdpListener(index));
+        }
+
+        Ok(usernet)
+    }
+
+    /// Get the tap-like endpoint, which the net device reads and writes frames with.
+    pub fn tap(&self) -> Result<Tap> {
+        Ok(Tap::from_socket(
+            self.peer.try_clone()?,
+            self.hdr_len.clone(),
+        ))
+    }
+
+    /// Get all the fds registered to the event loop.
+    pub fn notifier_fds(&self) -> Vec<RawFd> {
+        self.sockets.keys().copied().collect()
+    }
+
+    fn handle_event(&mut self, fd: RawFd, event: EventSet) {
+        match self.sockets.get(&fd).copied() {
+            Some(SocketKind::Guest) => self.handle_guest(),
+            Some(SocketKind::Timer) => self.handle_timer(),
+            Some(SocketKind::TcpListener(index)) => self.handle_tcp_listener(index),
+            Some(SocketKind::UdpListener(index)) => self.handle_udp_listener(index),
+            Some(SocketKind::Tcp(flow)) => {
+                if let Some(conn) = self.tcp_conns.get_mut(&flow) {
+                    conn.host_event(event);
+                }
+                self.tcp_flush(&flow);
+            }
+            Some(SocketKind::Udp(flow)) => self.handle_udp_remote(&flow),
+            None => {}
+        }
+        self.update_timer();
+    }
+
+    fn update_timer(&mut self) {
+        let busy = !self.tcp_conns.is_empty() || !self.udp_sessions.is_empty();
+        if busy == self.timer_armed {
+            return;
+        }
+        let ret = if busy {
+            self.timer.reset(TIMER_INTERVAL, Some(TIMER_INTERVAL))
+        } else {
+            self.timer.clear()
+        };
+        match ret {
+            Ok(()) => self.timer_armed = busy,
+            Err(e) => error!("Failed to update the timer of user network: {:?}", e),
+        }
+    }
+
+    fn handle_timer(&mut self) {
+        if let Err(e) = self.timer.wait() {
+            if e.errno() != libc::EAGAIN {
+                error!("Failed to read the timer of user network: {:?}", e);
+            }
+        }
+
+        let now = Instant::now();
+        let flows = self.tcp_conns.keys().copied().collect::<Vec<Flow>>();
+        for flow in flows.iter() {
+            if let Some(conn) = self.tcp_conns.get_mut(flow) {
+                conn.timer(now);
+            }
+            self.tcp_flush(flow);
+        }
+
+        let expired = self
+            .udp_sessions
+            .iter()
+            .filter(|(flow, session)| {
+                let timeout = if flow.remote.port() == DNS_PORT {
+                    UDP_DNS_TIMEOUT
+                } else {
+                    UDP_TIMEOUT
+                };
+                now.duration_since(session.last_active) >= timeout
+            })
+            .map(|(flow, _)| *flow)
+            .collect::<Vec<Flow>>();
+        for flow in expired.iter() {
+            if let Some(session) = self.udp_sessions.remove(flow) {
+                if let Some(socket) = session.socket {
+                    self.remove_socket(socket.as_raw_fd());
+                }
+            }
+        }
+    }
+
+    fn add_socket(&mut self, fd: RawFd, kind: SocketKind, event: EventSet) {
+        self.sockets.insert(fd, kind);
+        self.added_fds.push((fd, event));
+    }
+
+    fn remove_socket(&mut self, fd: RawFd) {
+        self.sockets.remove(&fd);
+        // No need to delete the socket which is not registered yet.
+        let pending = self.added_fds.len();
+        self.added_fds.retain(|(added, _)| *added != fd);
+        if self.added_fds.len() == pending {
+            self.removed_fds.push(fd);
+        }
+    }
+
+    /// Send the frame to the guest, the frame is dropped if the net device can not
+    /// receive it in time, which is recovered by the retransmission of tcp.
+    fn send_frame(&mut self, frame: &[u8]) {
+        if let Err(e) = self.sock.write(frame) {
+            if e.kind() != ErrorKind::WouldBlock {
+                error!("Failed to send frame to the guest: {:?}", e);
+            }
+        }
+    }
+
+    fn build_eth(&self, dst: MacAddr, ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        build_eth(
+            self.hdr_len.load(Ordering::SeqCst),
+            dst,
+            self.mac,
+            ether_type,
+            payload,
+        )
+    }
+
+    fn send_ipv4(&mut self, src: Ipv4Addr, dst: Ipv4Addr, proto: u8, payload: &[u8]) {
+        let dst_mac = match self.arp_table.get(&dst) {
+            Some(mac) => *mac,
+            None => {
+                // Resolve the guest address, the packet is dropped and resent later.
+                let arp = build_arp(ARP_OP_REQUEST, self.mac, self.config.host, [0; 6], dst);
+                let frame = self.build_eth(BROADCAST_MAC, ETH_P_ARP, &arp);
+                self.send_frame(&frame);
+                return;
+            }
+        };
+        let ip = build_ipv4(src, dst, proto, payload);
+        let frame = self.build_eth(dst_mac, ETH_P_IP, &ip);
+        self.send_frame(&frame);
+    }
+
+    fn handle_guest(&mut self) {
+        let mut buf = vec![0_u8; MAX_FRAME_LEN];
+        for _ in 0..MAX_FRAMES_PER_EVENT {
+            match self.sock.read(&mut buf) {
+                Ok(len) => self.handle_frame(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to receive frame from the guest: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, buf: &[u8]) {
+        let eth = match parse_eth(buf, self.hdr_len.load(Ordering::SeqCst)) {
+            Some(eth) => eth,
+            None => return,
+        };
+        match eth.ether_type {
+            ETH_P_ARP => self.handle_arp(eth.payload),
+            ETH_P_IP => {
+                if let Some(ip) = parse_ipv4(eth.payload) {
+                    if self.config.is_host_addr(ip.src) {
+                        self.arp_table.insert(ip.src, eth.src);
+                    }
+                    self.handle_ipv4(&ip);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_arp(&mut self, buf: &[u8]) {
+        let arp = match parse_arp(buf) {
+            Some(arp) => arp,
+            None => return,
+        };
+        if self.config.is_host_addr(arp.spa) {
+            self.arp_table.insert(arp.spa, arp.sha);
+        }
+        if arp.op == ARP_OP_REQUEST && (arp.tpa == self.config.host || arp.tpa == self.config.dns) {
+            let reply = build_arp(ARP_OP_REPLY, self.mac, arp.tpa, arp.sha, arp.spa);
+            let frame = self.build_eth(arp.sha, ETH_P_ARP, &reply);
+            self.send_frame(&frame);
+        }
+    }
+
+    fn handle_ipv4(&mut self, ip: &Ipv4Packet) {
+        match ip.proto {
+            IPPROTO_UDP => self.handle_udp(ip),
+            IPPROTO_TCP if self.config.is_host_addr(ip.src) => self.handle_tcp(ip),
+            IPPROTO_ICMP => {
+                if ip.dst != self.config.host && ip.dst != self.config.dns {
+                    return;
+                }
+                if let Some(reply) = build_icmp_echo_reply(ip.payload) {
+                    self.send_ipv4(ip.dst, ip.src, IPPROTO_ICMP, &reply);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Get the host address to proxy the flow to.
+    fn remote_host_addr(&self, remote: SocketAddrV4) -> Option<SocketAddrV4> {
+        let addr = *remote.ip();
+        if addr == self.config.host {
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, remote.port()))
+        } else if addr == self.config.dns {
+            if remote.port() != DNS_PORT {
+                return None;
+            }
+            self.dns_server
+                .map(|server| SocketAddrV4::new(server, DNS_PORT))
+        } else if u32::from(addr) & u32::from(self.config.mask()) == u32::from(self.config.net)
+            || addr.is_broadcast()
+            || addr.is_multicast()
+            || addr.is_unspecified()
+        {
+            None
+        } else {
+            Some(remote)
+        }
+    }
+
+    fn handle_udp(&mut self, ip: &Ipv4Packet) {
+        let udp = match parse_udp(ip.payload) {
+            Some(udp) => udp,
+            None => return,
+        };
+        if udp.dst_port == DHCP_SERVER_PORT && (ip.dst == self.config.host || ip.dst.is_broadcast())
+        {
+            self.handle_dhcp(udp.payload);
+            return;
+        }
+        if !self.config.is_host_addr(ip.src) {
+            return;
+        }
+
+        let flow = Flow {
+            guest: SocketAddrV4::new(ip.src, udp.src_port),
+            remote: SocketAddrV4::new(ip.dst, udp.dst_port),
+        };
+        if !self.udp_sessions.contains_key(&flow) {
+            let target = match self.remote_host_addr(flow.remote) {
+                Some(target) => target,
+                None => return,
+            };
+            let socket = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+                .and_then(|socket| socket.connect(target).map(|_| socket))
+            {
+                Ok(socket) => socket,
+                Err(e) => {
+                    warn!("Failed to create udp socket to {}: {:?}", target, e);
+                    return;
+                }
+            };
+            self.add_socket(socket.as_raw_fd(), SocketKind::Udp(flow), EventSet::IN);
+            self.udp_sessions.insert(
+                flow,
+                UdpSession {
+                    socket: Some(socket),
+                    peer: None,
+                    last_active: Instant::now(),
+                },
+            );
+        }
+
+        let session = self.udp_sessions.get_mut(&flow).unwrap();
+        session.last_active = Instant::now();
+        let ret = match (&session.socket, session.peer) {
+            (Some(socket), _) => socket.send(udp.payload),
+            (None, Some((index, peer))) => self.udp_listeners[index].1.send_to(udp.payload, peer),
+            _ => return,
+        };
+        if let Err(e) = ret {
+            if e.kind() != ErrorKind::WouldBlock {
+                warn!("Failed to send udp datagram of the guest: {:?}", e);
+            }
+        }
+    }
+
+    fn handle_dhcp(&mut self, buf: &[u8]) {
+        let reply = match self.dhcp.handle(buf) {
+            Some(reply) => reply,
+            None => return,
+        };
+        let (dst_mac, dst_addr) = if reply.broadcast {
+            (BROADCAST_MAC, Ipv4Addr::BROADCAST)
+        } else {
+            (reply.client_mac, reply.client_addr)
+        };
+        if !reply.client_addr.is_unspecified() {
+            self.arp_table.insert(reply.client_addr, reply.client_mac);
+        }
+        let udp = build_udp(
+            self.config.host,
+            dst_addr,
+            DHCP_SERVER_PORT,
+            DHCP_CLIENT_PORT,
+            &reply.data,
+        );
+        let ip = build_ipv4(self.config.host, dst_addr, IPPROTO_UDP, &udp);
+        let frame = self.build_eth(dst_mac, ETH_P_IP, &ip);
+        self.send_frame(&frame);
+    }
+
+    fn handle_udp_remote(&mut self, flow: &Flow) {
+        let mut buf = vec![0_u8; 65536];
+        loop {
+            let session = match self.udp_sessions.get_mut(flow) {
+                Some(session) => session,
+                None => return,
+            };
+            let len = match session.socket.as_ref().map(|socket| socket.recv(&mut buf)) {
+                Some(Ok(len)) => len,
+                Some(Err(e)) if e.kind() == ErrorKind::Interrupted => continue,
+                Some(Err(e)) if e.kind() == ErrorKind::WouldBlock => return,
+                Some(Err(e)) => {
+                    // Errors such as the unreachable port are reported by icmp of the
+                    // remote, just ignore them and wait for the session to expire.
+                    warn!(
+                        "Failed to receive udp datagram from {}: {:?}",
+                        flow.remote, e
+                    );
+                    return;
+                }
+                None => return,
+            };
+            session.last_active = Instant::now();
+            self.send_udp_to_guest(flow, &buf[..len]);
+        }
+    }
+
+    fn send_udp_to_guest(&mut self, flow: &Flow, payload: &[u8]) {
+        let udp = build_udp(
+            *flow.remote.ip(),
+            *flow.guest.ip(),
+            flow.remote.port(),
+            flow.guest.port(),
+            payload,
+        );
+        self.send_ipv4(*flow.remote.ip(), *flow.guest.ip(), IPPROTO_UDP, &udp);
+    }
+
+    /// Allocate a port of the gateway for the forwarded host connection.
+    fn alloc_fwd_port(&mut self, guest: SocketAddrV4) -> Option<u16> {
+        for _ in FWD_PORT_BASE..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = if port == u16::MAX {
+                FWD_PORT_BASE
+            } else {
+                port + 1
+            };
+            let flow = Flow {
+                guest,
+                remote: SocketAddrV4::new(self.config.host, port),
+            };
+            if !self.tcp_conns.contains_key(&flow) && !self.udp_sessions.contains_key(&flow) {
+                return Some(port);
+            }
+        }
+        None
+    }
+
+    fn fwd_guest_addr(&self, fwd: &HostFwd) -> SocketAddrV4 {
+        let addr = if fwd.guest_addr.is_unspecified() {
+            self.config.dhcp_start
+        } else {
+            fwd.guest_addr
+        };
+        SocketAddrV4::new(addr, fwd.guest_port)
+    }
+
+    fn handle_udp_listener(&mut self, index: usize) {
+        let mut buf = vec![0_u8; 65536];
+        loop {
+            let (len, peer) = match self.udp_listeners[index].1.recv_from(&mut buf) {
+                Ok(ret) => ret,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed to receive udp datagram of hostfwd: {:?}", e);
+                    return;
+                }
+            };
+
+            let existing = self
+                .udp_sessions
+                .iter()
+                .find(|(_, session)| session.peer == Some((index, peer)))
+                .map(|(flow, _)| *flow);
+            let flow = match existing {
+                Some(flow) => flow,
+                None => {
+                    let guest = self.fwd_guest_addr(&self.udp_listeners[index].0);
+                    let port = match self.alloc_fwd_port(guest) {
+                        Some(port) => port,
+                        None => continue,
+                    };
+                    let flow = Flow {
+                        guest,
+                        remote: SocketAddrV4::new(self.config.host, port),
+                    };
+                    self.udp_sessions.insert(
+                        flow,
+                        UdpSession {
+                            socket: None,
+                            peer: Some((index, peer)),
+                            last_active: Instant::now(),
+                        },
+                    );
+                    flow
+                }
+            };
+            if let Some(session) = self.udp_sessions.get_mut(&flow) {
+                session.last_active = Instant::now();
+            }
+            self.send_udp_to_guest(&flow, &buf[..len]);
+        }
+    }
+
+    fn handle_tcp_listener(&mut self, index: usize) {
+        loop {
+            let stream = match self.tcp_listeners[index].1.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed to accept tcp connection of hostfwd: {:?}", e);
+                    return;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!(
+                    "Failed to set tcp connection of hostfwd nonblocking: {:?}",
+                    e
+                );
+                continue;
+            }
+            let guest = self.fwd_guest_addr(&self.tcp_listeners[index].0);
+            let port = match self.alloc_fwd_port(guest) {
+                Some(port) => port,
+                None => continue,
+            };
+            let flow = Flow {
+                guest,
+                remote: SocketAddrV4::new(self.config.host, port),
+            };
+            self.add_socket(
+                stream.as_raw_fd(),
+                SocketKind::Tcp(flow),
+                EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED,
+            );
+            self.tcp_conns
+                .insert(flow, TcpConn::new_inbound(stream, initial_seq()));
+            self.tcp_flush(&flow);
+        }
+    }
+
+    fn handle_tcp(&mut self, ip: &Ipv4Packet) {
+        let seg = match parse_tcp(ip.payload) {
+            Some(seg) => seg,
+            None => return,
+        };
+        let flow = Flow {
+            guest: SocketAddrV4::new(ip.src, seg.src_port),
+            remote: SocketAddrV4::new(ip.dst, seg.dst_port),
+        };
+
+        if let Some(conn) = self.tcp_conns.get_mut(&flow) {
+            conn.input(&seg);
+            self.tcp_flush(&flow);
+            return;
+        }
+        if seg.flags & TCP_RST != 0 {
+            return;
+        }
+
+        if seg.flags & (TCP_SYN | TCP_ACK) == TCP_SYN {
+            let stream = match self.remote_host_addr(flow.remote) {
+                Some(target) => tcp_connect_nonblocking(target).ok(),
+                None => None,
+            };
+            if let Some(stream) = stream {
+                self.add_socket(
+                    stream.as_raw_fd(),
+                    SocketKind::Tcp(flow),
+                    EventSet::IN | EventSet::OUT | EventSet::EDGE_TRIGGERED,
+                );
+                self.tcp_conns
+                    .insert(flow, TcpConn::new_outbound(stream, initial_seq(), &seg));
+                return;
+            }
+        }
+
+        // Reset the segment not belonging to any connection.
+        let (seq, ack, flags) = if seg.flags & TCP_ACK != 0 {
+            (seg.ack, 0, TCP_RST)
+        } else {
+            (0, seg.seq.wrapping_add(seg.seq_len()), TCP_RST | TCP_ACK)
+        };
+        let hdr = TcpHeader {
+            src_port: flow.remote.port(),
+            dst_port: flow.guest.port(),
+            seq,
+            ack,
+            flags,
+            window: 0,
+            mss: None,
+        };
+        let tcp = build_tcp(*flow.remote.ip(), *flow.guest.ip(), &hdr, &[]);
+        self.send_ipv4(*flow.remote.ip(), *flow.guest.ip(), IPPROTO_TCP, &tcp);
+    }
+
+    /// Send the output segments of the connection, and remove it if closed.
+    fn tcp_flush(&mut self, flow: &Flow) {
+        let conn = match self.tcp_conns.get_mut(flow) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let output = conn.take_output();
+        let closed = conn.is_closed();
+        for seg in output.iter() {
+            let hdr = TcpHeader {
+                src_port: flow.remote.port(),
+                dst_port: flow.guest.port(),
+                seq: seg.seq,
+                ack: seg.ack,
+                flags: seg.flags,
+                window: seg.window,
+                mss: seg.mss,
+            };
+            let tcp = build_tcp(*flow.remote.ip(), *flow.guest.ip(), &hdr, &seg.payload);
+            self.send_ipv4(*flow.remote.ip(), *flow.guest.ip(), IPPROTO_TCP, &tcp);
+        }
+        if closed {
+            if let Some(conn) = self.tcp_conns.remove(flow) {
+                self.remove_socket(conn.stream.as_raw_fd());
+            }
+        }
+    }
+
+    fn take_notifiers(net: &Arc<Mutex<UserNet>>) -> Option<Vec<EventNotifier>> {
+        let mut locked_net = net.lock().unwrap();
+        let removed = std::mem::take(&mut locked_net.removed_fds);
+        let added = std::mem::take(&mut locked_net.added_fds);
+        drop(locked_net);
+        if removed.is_empty() && added.is_empty() {
+            return None;
+        }
+
+        // Deleted fds go first, as the number may be reused by the added sockets.
+        let mut notifiers = gen_delete_notifiers(&removed);
+        for (fd, event) in added {
+            notifiers.push(UserNet::notifier(net, fd, event));
+        }
+        Some(notifiers)
+    }
+
+    fn notifier(net: &Arc<Mutex<UserNet>>, fd: RawFd, event: EventSet) -> EventNotifier {
+        let cloned_net = net.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |event, fd| {
+            cloned_net.lock().unwrap().handle_event(fd, event);
+            UserNet::take_notifiers(&cloned_net)
+        });
+        EventNotifier::new(NotifierOperation::AddShared, fd, None, event, vec![handler])
+    }
+}
+
+impl EventNotifierHelper for UserNet {
+    fn internal_notifiers(net: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let fds = net.lock().unwrap().notifier_fds();
+        fds.iter()
+            .map(|fd| UserNet::notifier(&net, *fd, EventSet::IN))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUEST_MAC: MacAddr = [0x52, 0x54, 0, 0x12, 0x34, 0x56];
+
+    fn guest_addr() -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 2, 15)
+    }
+
+    fn send_guest_ipv4(peer: &mut File, gw_mac: MacAddr, dst: Ipv4Addr, proto: u8, l4: &[u8]) {
+        let ip = build_ipv4(guest_addr(), dst, proto, l4);
+        peer.write_all(&build_eth(VNET_HDR_LEN, gw_mac, GUEST_MAC, ETH_P_IP, &ip))
+            .unwrap();
+    }
+
+    fn recv_guest_frame(peer: &mut File) -> Vec<u8> {
+        let mut buf = vec![0_u8; MAX_FRAME_LEN];
+        let len = peer.read(&mut buf).unwrap();
+        buf.truncate(len);
+        buf
+    }
+
+    #[test]
+    fn test_hostfwd_parse() {
+        let fwd = HostFwd::from_str("tcp::2222-:22").unwrap();
+        assert!(!fwd.udp);
+        assert!(fwd.host_addr.is_unspecified());
+        assert_eq!(fwd.host_port, 2222);
+        assert!(fwd.guest_addr.is_unspecified());
+        assert_eq!(fwd.guest_port, 22);
+
+        let fwd = HostFwd::from_str("udp:127.0.0.1:5353-10.0.2.16:53").unwrap();
+        assert!(fwd.udp);
+        assert_eq!(fwd.host_addr, Ipv4Addr::LOCALHOST);
+        assert_eq!(fwd.guest_addr, Ipv4Addr::new(10, 0, 2, 16));
+        assert_eq!(fwd.guest_port, 53);
+
+        assert!(HostFwd::from_str(":2222-:22").is_err());
+        assert!(HostFwd::from_str("sctp::2222-:22").is_err());
+        assert!(HostFwd::from_str("tcp::2222").is_err());
+        assert!(HostFwd::from_str("tcp::0-:22").is_err());
+        assert!(HostFwd::from_str("tcp:localhost:2222-:22").is_err());
+    }
+
+    #[test]
+    fn test_usernet_config_check() {
+        let mut config = UserNetConfig::default();
+        assert!(config.check().is_ok());
+        assert_eq!(config.mask(), Ipv4Addr::new(255, 255, 255, 0));
+
+        config.dns = config.host;
+        assert!(config.check().is_err());
+        config.dns = Ipv4Addr::new(10, 0, 3, 3);
+        assert!(config.check().is_err());
+        config.dns = Ipv4Addr::new(10, 0, 2, 20);
+        assert!(config.check().is_err());
+        config.dns = Ipv4Addr::new(10, 0, 2, 3);
+        config.prefix_len = 31;
+        assert!(config.check().is_err());
+        config.prefix_len = 24;
+        config.net = Ipv4Addr::new(10, 0, 2, 1);
+        assert!(config.check().is_err());
+        config.net = Ipv4Addr::new(10, 0, 2, 0);
+        config.hostfwd = vec![HostFwd::from_str("tcp::2222-10.0.3.15:22").unwrap()];
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn test_usernet_arp_icmp_udp() {
+        let mut net = UserNet::new(&UserNetConfig::default()).unwrap();
+        let mut peer = net.tap().unwrap().file;
+        let gw_mac = net.mac;
+        let host = net.config.host;
+
+        // Arp of the gateway.
+        let arp = build_arp(ARP_OP_REQUEST, GUEST_MAC, guest_addr(), [0; 6], host);
+        peer.write_all(&build_eth(
+            VNET_HDR_LEN,
+            BROADCAST_MAC,
+            GUEST_MAC,
+            ETH_P_ARP,
+            &arp,
+        ))
+        .unwrap();
+        net.handle_guest();
+        let frame = recv_guest_frame(&mut peer);
+        assert_eq!(frame[VNET_HDR_LEN..VNET_HDR_LEN + 6], GUEST_MAC);
+        let eth = parse_eth(&frame, VNET_HDR_LEN).unwrap();
+        let reply = parse_arp(eth.payload).unwrap();
+        assert_eq!(reply.op, ARP_OP_REPLY);
+        assert_eq!(reply.sha, gw_mac);
+        assert_eq!(reply.spa, host);
+
+        // Ping the gateway.
+        let echo = [8, 0, 0xf7, 0xfd, 0, 1, 0, 1];
+        send_guest_ipv4(&mut peer, gw_mac, host, IPPROTO_ICMP, &echo);
+        net.handle_guest();
+        let frame = recv_guest_frame(&mut peer);
+        let ip = parse_ipv4(parse_eth(&frame, VNET_HDR_LEN).unwrap().payload).unwrap();
+        assert_eq!(ip.proto, IPPROTO_ICMP);
+        assert_eq!(ip.src, host);
+        assert_eq!(ip.payload[0], 0);
+
+        // Udp to the gateway is proxied to the localhost.
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let udp = build_udp(guest_addr(), host, 40000, port, b"ping");
+        send_guest_ipv4(&mut peer, gw_mac, host, IPPROTO_UDP, &udp);
+        net.handle_guest();
+        let mut buf = [0_u8; 16];
+        let (len, client) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert_eq!(net.udp_sessions.len(), 1);
+        assert_eq!(net.added_fds.len(), 1);
+
+        server.send_to(b"pong", client).unwrap();
+        let fd = net.added_fds[0].0;
+        std::thread::sleep(Duration::from_millis(50));
+        net.handle_event(fd, EventSet::IN);
+        let frame = recv_guest_frame(&mut peer);
+        let ip = parse_ipv4(parse_eth(&frame, VNET_HDR_LEN).unwrap().payload).unwrap();
+        assert_eq!(ip.src, host);
+        assert_eq!(ip.dst, guest_addr());
+        let udp = parse_udp(ip.payload).unwrap();
+        assert_eq!((udp.src_port, udp.dst_port), (port, 40000));
+        assert_eq!(udp.payload, b"pong");
+    }
+
+    #[test]
+    fn test_usernet_vnet_hdr() {
+        let mut net = UserNet::new(&UserNetConfig::default()).unwrap();
+        let tap = net.tap().unwrap();
+        assert!(!tap.has_offload());
+        assert!(!tap.has_ufo());
+        assert!(tap.set_offload(0).is_ok());
+        assert!(tap.set_offload(crate::tap::TUN_F_CSUM).is_err());
+
+        // The frames follow the header length of the legacy guest set by the net device.
+        tap.set_hdr_size(10).unwrap();
+        let mut peer = tap.file;
+        let host = net.config.host;
+        let arp = build_arp(ARP_OP_REQUEST, GUEST_MAC, guest_addr(), [0; 6], host);
+        peer.write_all(&build_eth(10, BROADCAST_MAC, GUEST_MAC, ETH_P_ARP, &arp))
+            .unwrap();
+        net.handle_guest();
+        let frame = recv_guest_frame(&mut peer);
+        assert_eq!(frame[10..16], GUEST_MAC);
+        let reply = parse_arp(parse_eth(&frame, 10).unwrap().payload).unwrap();
+        assert_eq!(reply.op, ARP_OP_REPLY);
+        assert_eq!(reply.spa, host);
+    }
+
+    #[test]
+    fn test_usernet_tcp() {
+        let mut net = UserNet::new(&UserNetConfig::default()).unwrap();
+        let mut peer = net.tap().unwrap().file;
+        let gw_mac = net.mac;
+        let host = net.config.host;
+        net.arp_table.insert(guest_addr(), GUEST_MAC);
+
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let tcp_hdr = |seq, ack, flags| TcpHeader {
+            src_port: 40000,
+            dst_port: port,
+            seq,
+            ack,
+            flags,
+            window: 65535,
+            mss: None,
+        };
+        let recv_seg = |peer: &mut File| {
+            let frame = recv_guest_frame(peer);
+            let ip = parse_ipv4(parse_eth(&frame, VNET_HDR_LEN).unwrap().payload).unwrap();
+            let seg = parse_tcp(ip.payload).unwrap();
+            (seg.seq, seg.ack, seg.flags, seg.payload.to_vec())
+        };
+
+        // Guest connects to the gateway, which is proxied to the localhost.
+        let syn = build_tcp(guest_addr(), host, &tcp_hdr(1000, 0, TCP_SYN), &[]);
+        send_guest_ipv4(&mut peer, gw_mac, host, IPPROTO_TCP, &syn);
+        net.handle_guest();
+        let (mut accepted, _) = server.accept().unwrap();
+        let fd = net.added_fds[0].0;
+        net.added_fds.clear();
+        net.handle_event(fd, EventSet::OUT);
+        let (iss, ack, flags, _) = recv_seg(&mut peer);
+        assert_eq!(flags, TCP_SYN | TCP_ACK);
+        assert_eq!(ack, 1001);
+
+        // Guest sends data with the ACK of SYN.
+        let data = build_tcp(
+            guest_addr(),
+            host,
+            &tcp_hdr(1001, iss + 1, TCP_ACK),
+            b"hello",
+        );
+        send_guest_ipv4(&mut peer, gw_mac, host, IPPROTO_TCP, &data);
+        net.handle_guest();
+        let mut buf = [0_u8; 16];
+        let len = accepted.read(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"hello");
+        let (_, ack, flags, _) = recv_seg(&mut peer);
+        assert_eq!(flags, TCP_ACK);
+        assert_eq!(ack, 1006);
+
+        // Host replies and closes the connection.
+        accepted.write_all(b"world").unwrap();
+        drop(accepted);
+        std::thread::sleep(Duration::from_millis(50));
+        net.handle_event(fd, EventSet::IN);
+        let (seq, _, _, payload) = recv_seg(&mut peer);
+        assert_eq!(seq, iss + 1);
+        assert_eq!(payload, b"world");
+        let (seq, _, flags, _) = recv_seg(&mut peer);
+        assert_eq!(seq, iss + 6);
+        assert_ne!(flags & packet::TCP_FIN, 0);
+
+        // Guest acks and closes its side, the connection is removed.
+        let fin = build_tcp(
+            guest_addr(),
+            host,
+            &tcp_hdr(1006, iss + 7, TCP_ACK | packet::TCP_FIN),
+            &[],
+        );
+        send_guest_ipv4(&mut peer, gw_mac, host, IPPROTO_TCP, &fin);
+        net.handle_guest();
+        let (_, ack, flags, _) = recv_seg(&mut peer);
+        assert_eq!(flags, TCP_ACK);
+        assert_eq!(ack, 1007);
+        assert!(net.tcp_conns.is_empty());
+        assert_eq!(net.removed_fds, vec![fd]);
+
+        // Segment of unknown connection is reset.
+        send_guest_ipv4(&mut peer, gw_mac, host, IPPROTO_TCP, &data);
+        net.handle_guest();
+        let (seq, _, flags, _) = recv_seg(&mut peer);
+        assert_eq!(seq, iss + 1);
+        assert_eq!(flags, TCP_RST);
+    }
+}
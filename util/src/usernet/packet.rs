@@ -0,0 +1,448 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Parsing and building of the ethernet, ARP, IPv4, ICMP, UDP and TCP headers
+//! used by the user-mode network stack.
+
+use std::net::Ipv4Addr;
+
+pub type MacAddr = [u8; 6];
+
+pub const BROADCAST_MAC: MacAddr = [0xff; 6];
+
+pub const ETH_HDR_LEN: usize = 14;
+pub const ETH_P_IP: u16 = 0x0800;
+pub const ETH_P_ARP: u16 = 0x0806;
+
+const ARP_HDR_LEN: usize = 28;
+const ARP_HTYPE_ETHER: u16 = 1;
+pub const ARP_OP_REQUEST: u16 = 1;
+pub const ARP_OP_REPLY: u16 = 2;
+
+const IPV4_HDR_LEN: usize = 20;
+const IPV4_FLAG_DF: u16 = 0x4000;
+const IPV4_FLAG_MF: u16 = 0x2000;
+const IPV4_FRAG_OFFSET_MASK: u16 = 0x1fff;
+const IPV4_DEFAULT_TTL: u8 = 64;
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+
+const ICMP_HDR_LEN: usize = 8;
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+const UDP_HDR_LEN: usize = 8;
+
+const TCP_HDR_LEN: usize = 20;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+/// Ethernet frame received from the guest, the virtio net header is stripped.
+pub struct EthFrame<'a> {
+    pub src: MacAddr,
+    pub ether_type: u16,
+    pub payload: &'a [u8],
+}
+
+pub struct ArpPacket {
+    pub op: u16,
+    pub sha: MacAddr,
+    pub spa: Ipv4Addr,
+    pub tpa: Ipv4Addr,
+}
+
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub proto: u8,
+    pub payload: &'a [u8],
+}
+
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    /// Length in sequence space, SYN and FIN take one sequence number each.
+    pub fn seq_len(&self) -> u32 {
+        let mut len = self.payload.len() as u32;
+        if self.flags & TCP_SYN != 0 {
+            len += 1;
+        }
+        if self.flags & TCP_FIN != 0 {
+            len += 1;
+        }
+        len
+    }
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn read_ipv4(buf: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    )
+}
+
+fn read_mac(buf: &[u8], offset: usize) -> MacAddr {
+    let mut mac = [0_u8; 6];
+    mac.copy_from_slice(&buf[offset..offset + 6]);
+    mac
+}
+
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+fn checksum_fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Internet checksum of RFC 1071.
+pub fn inet_checksum(data: &[u8]) -> u16 {
+    checksum_fold(checksum_add(0, data))
+}
+
+fn l4_checksum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, data: &[u8]) -> u16 {
+    let mut sum = checksum_add(0, &src.octets());
+    sum = checksum_add(sum, &dst.octets());
+    sum += u32::from(proto);
+    sum += data.len() as u32;
+    checksum_fold(checksum_add(sum, data))
+}
+
+/// Parse the frame written by the net device, which starts with the virtio net
+/// header of `hdr_len` bytes.
+pub fn parse_eth(buf: &[u8], hdr_len: usize) -> Option<EthFrame<'_>> {
+    if buf.len() < hdr_len + ETH_HDR_LEN {
+        return None;
+    }
+    let frame = &buf[hdr_len..];
+    Some(EthFrame {
+        src: read_mac(frame, 6),
+        ether_type: read_u16(frame, 12),
+        payload: &frame[ETH_HDR_LEN..],
+    })
+}
+
+pub fn parse_arp(buf: &[u8]) -> Option<ArpPacket> {
+    if buf.len() < ARP_HDR_LEN
+        || read_u16(buf, 0) != ARP_HTYPE_ETHER
+        || read_u16(buf, 2) != ETH_P_IP
+        || buf[4] != 6
+        || buf[5] != 4
+    {
+        return None;
+    }
+    Some(ArpPacket {
+        op: read_u16(buf, 6),
+        sha: read_mac(buf, 8),
+        spa: read_ipv4(buf, 14),
+        tpa: read_ipv4(buf, 24),
+    })
+}
+
+/// Parse the IPv4 packet, fragments are not supported and will be dropped.
+pub fn parse_ipv4(buf: &[u8]) -> Option<Ipv4Packet<'_>> {
+    if buf.len() < IPV4_HDR_LEN || buf[0] >> 4 != 4 {
+        return None;
+    }
+    let hdr_len = usize::from(buf[0] & 0xf) * 4;
+    if hdr_len < IPV4_HDR_LEN || hdr_len > buf.len() {
+        return None;
+    }
+    let frag = read_u16(buf, 6);
+    if frag & IPV4_FLAG_MF != 0 || frag & IPV4_FRAG_OFFSET_MASK != 0 {
+        return None;
+    }
+    // The total length may be zero or inaccurate for the segmentation offloaded
+    // packets of the guest, fall back to the length of the frame.
+    let mut total_len = usize::from(read_u16(buf, 2));
+    if total_len < hdr_len || total_len > buf.len() {
+        total_len = buf.len();
+    }
+    Some(Ipv4Packet {
+        src: read_ipv4(buf, 12),
+        dst: read_ipv4(buf, 16),
+        proto: buf[9],
+        payload: &buf[hdr_len..total_len],
+    })
+}
+
+pub fn parse_udp(buf: &[u8]) -> Option<UdpDatagram<'_>> {
+    if buf.len() < UDP_HDR_LEN {
+        return None;
+    }
+    let mut len = usize::from(read_u16(buf, 4));
+    if len < UDP_HDR_LEN || len > buf.len() {
+        len = buf.len();
+    }
+    Some(UdpDatagram {
+        src_port: read_u16(buf, 0),
+        dst_port: read_u16(buf, 2),
+        payload: &buf[UDP_HDR_LEN..len],
+    })
+}
+
+pub fn parse_tcp(buf: &[u8]) -> Option<TcpSegment<'_>> {
+    if buf.len() < TCP_HDR_LEN {
+        return None;
+    }
+    let hdr_len = usize::from(buf[12] >> 4) * 4;
+    if hdr_len < TCP_HDR_LEN || hdr_len > buf.len() {
+        return None;
+    }
+
+    let mut mss = None;
+    let mut opts = &buf[TCP_HDR_LEN..hdr_len];
+    while let Some(&kind) = opts.first() {
+        match kind {
+            TCP_OPT_END => break,
+            TCP_OPT_NOP => opts = &opts[1..],
+            _ => {
+                if opts.len() < 2 || usize::from(opts[1]) < 2 || usize::from(opts[1]) > opts.len() {
+                    break;
+                }
+                if kind == TCP_OPT_MSS && opts[1] == 4 {
+                    mss = Some(read_u16(opts, 2));
+                }
+                opts = &opts[usize::from(opts[1])..];
+            }
+        }
+    }
+
+    Some(TcpSegment {
+        src_port: read_u16(buf, 0),
+        dst_port: read_u16(buf, 2),
+        seq: read_u32(buf, 4),
+        ack: read_u32(buf, 8),
+        flags: buf[13],
+        window: read_u16(buf, 14),
+        mss,
+        payload: &buf[hdr_len..],
+    })
+}
+
+/// Build the frame to be read by the net device, a zeroed virtio net header of
+/// `hdr_len` bytes is prepended as the packet is complete and needs no offloading.
+pub fn build_eth(
+    hdr_len: usize,
+    dst: MacAddr,
+    src: MacAddr,
+    ether_type: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(hdr_len + ETH_HDR_LEN + payload.len());
+    frame.resize(hdr_len, 0);
+    frame.extend_from_slice(&dst);
+    frame.extend_from_slice(&src);
+    frame.extend_from_slice(&ether_type.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub fn build_arp(op: u16, sha: MacAddr, spa: Ipv4Addr, tha: MacAddr, tpa: Ipv4Addr) -> Vec<u8> {
+    let mut arp = Vec::with_capacity(ARP_HDR_LEN);
+    arp.extend_from_slice(&ARP_HTYPE_ETHER.to_be_bytes());
+    arp.extend_from_slice(&ETH_P_IP.to_be_bytes());
+    arp.extend_from_slice(&[6, 4]);
+    arp.extend_from_slice(&op.to_be_bytes());
+    arp.extend_from_slice(&sha);
+    arp.extend_from_slice(&spa.octets());
+    arp.extend_from_slice(&tha);
+    arp.extend_from_slice(&tpa.octets());
+    arp
+}
+
+pub fn build_ipv4(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, payload: &[u8]) -> Vec<u8> {
+    let mut ip = Vec::with_capacity(IPV4_HDR_LEN + payload.len());
+    ip.extend_from_slice(&[0x45, 0]);
+    ip.extend_from_slice(&((IPV4_HDR_LEN + payload.len()) as u16).to_be_bytes());
+    ip.extend_from_slice(&[0, 0]);
+    ip.extend_from_slice(&IPV4_FLAG_DF.to_be_bytes());
+    ip.extend_from_slice(&[IPV4_DEFAULT_TTL, proto, 0, 0]);
+    ip.extend_from_slice(&src.octets());
+    ip.extend_from_slice(&dst.octets());
+    let csum = inet_checksum(&ip);
+    ip[10..12].copy_from_slice(&csum.to_be_bytes());
+    ip.extend_from_slice(payload);
+    ip
+}
+
+pub fn build_icmp_echo_reply(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < ICMP_HDR_LEN || request[0] != ICMP_ECHO_REQUEST {
+        return None;
+    }
+    let mut reply = request.to_vec();
+    reply[0] = ICMP_ECHO_REPLY;
+    reply[2..4].copy_from_slice(&[0, 0]);
+    let csum = inet_checksum(&reply);
+    reply[2..4].copy_from_slice(&csum.to_be_bytes());
+    Some(reply)
+}
+
+pub fn build_udp(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    src_port: u16,
+    dst_port: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut udp = Vec::with_capacity(UDP_HDR_LEN + payload.len());
+    udp.extend_from_slice(&src_port.to_be_bytes());
+    udp.extend_from_slice(&dst_port.to_be_bytes());
+    udp.extend_from_slice(&((UDP_HDR_LEN + payload.len()) as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    let mut csum = l4_checksum(src, dst, IPPROTO_UDP, &udp);
+    if csum == 0 {
+        csum = 0xffff;
+    }
+    udp[6..8].copy_from_slice(&csum.to_be_bytes());
+    udp
+}
+
+/// Fields of the tcp segment to be sent to the guest.
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+}
+
+pub fn build_tcp(src: Ipv4Addr, dst: Ipv4Addr, hdr: &TcpHeader, payload: &[u8]) -> Vec<u8> {
+    let hdr_len = if hdr.mss.is_some() {
+        TCP_HDR_LEN + 4
+    } else {
+        TCP_HDR_LEN
+    };
+    let mut tcp = Vec::with_capacity(hdr_len + payload.len());
+    tcp.extend_from_slice(&hdr.src_port.to_be_bytes());
+    tcp.extend_from_slice(&hdr.dst_port.to_be_bytes());
+    tcp.extend_from_slice(&hdr.seq.to_be_bytes());
+    tcp.extend_from_slice(&hdr.ack.to_be_bytes());
+    tcp.extend_from_slice(&[(hdr_len as u8 / 4) << 4, hdr.flags]);
+    tcp.extend_from_slice(&hdr.window.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = hdr.mss {
+        tcp.extend_from_slice(&[TCP_OPT_MSS, 4]);
+        tcp.extend_from_slice(&mss.to_be_bytes());
+    }
+    tcp.extend_from_slice(payload);
+    let csum = l4_checksum(src, dst, IPPROTO_TCP, &tcp);
+    tcp[16..18].copy_from_slice(&csum.to_be_bytes());
+    tcp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packet_build_and_parse() {
+        let src = Ipv4Addr::new(10, 0, 2, 2);
+        let dst = Ipv4Addr::new(10, 0, 2, 15);
+
+        let udp = build_udp(src, dst, 67, 68, b"hello");
+        assert_eq!(l4_checksum(src, dst, IPPROTO_UDP, &udp), 0);
+        let ip = build_ipv4(src, dst, IPPROTO_UDP, &udp);
+        assert_eq!(inet_checksum(&ip[..IPV4_HDR_LEN]), 0);
+        let frame = build_eth(10, BROADCAST_MAC, [0x52, 0x55, 10, 0, 2, 2], ETH_P_IP, &ip);
+        assert_eq!(frame.len(), 10 + ETH_HDR_LEN + ip.len());
+
+        let eth = parse_eth(&frame, 10).unwrap();
+        assert_eq!(eth.ether_type, ETH_P_IP);
+        assert_eq!(eth.src, [0x52, 0x55, 10, 0, 2, 2]);
+        let ip = parse_ipv4(eth.payload).unwrap();
+        assert_eq!(ip.src, src);
+        assert_eq!(ip.dst, dst);
+        assert_eq!(ip.proto, IPPROTO_UDP);
+        let udp = parse_udp(ip.payload).unwrap();
+        assert_eq!((udp.src_port, udp.dst_port), (67, 68));
+        assert_eq!(udp.payload, b"hello");
+
+        let hdr = TcpHeader {
+            src_port: 80,
+            dst_port: 40000,
+            seq: 100,
+            ack: 200,
+            flags: TCP_SYN | TCP_ACK,
+            window: 65535,
+            mss: Some(1460),
+        };
+        let tcp = build_tcp(src, dst, &hdr, b"");
+        assert_eq!(l4_checksum(src, dst, IPPROTO_TCP, &tcp), 0);
+        let seg = parse_tcp(&tcp).unwrap();
+        assert_eq!((seg.src_port, seg.dst_port), (80, 40000));
+        assert_eq!((seg.seq, seg.ack), (100, 200));
+        assert_eq!(seg.mss, Some(1460));
+        assert_eq!(seg.seq_len(), 1);
+
+        let arp = build_arp(ARP_OP_REQUEST, [1; 6], dst, [0; 6], src);
+        let arp = parse_arp(&arp).unwrap();
+        assert_eq!(arp.op, ARP_OP_REQUEST);
+        assert_eq!(arp.spa, dst);
+        assert_eq!(arp.tpa, src);
+
+        // Fragments are dropped.
+        let mut ip = build_ipv4(src, dst, IPPROTO_UDP, b"12345678");
+        ip[6] |= 0x20;
+        assert!(parse_ipv4(&ip).is_none());
+    }
+}
@@ -0,0 +1,603 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Tcp connection which terminates the guest tcp stream and proxies the data
+//! through a host socket.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Result as IoResult, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+use vmm_sys_util::epoll::EventSet;
+
+use super::packet::{TcpSegment, TCP_ACK, TCP_FIN, TCP_PSH, TCP_RST, TCP_SYN};
+
+/// Max segment size of the data sent to the guest, fits in the 1500 bytes MTU.
+const TCP_MSS: u16 = 1460;
+/// Max segment size assumed if the guest does not specify it.
+const TCP_DEFAULT_MSS: u16 = 536;
+/// Size of the buffers in each direction, which is also the window advertised to
+/// the guest as the window scale option is not used.
+const TCP_BUF_SIZE: usize = 65535;
+const TCP_RTO_INIT: Duration = Duration::from_millis(200);
+const TCP_RTO_MAX: Duration = Duration::from_secs(8);
+const TCP_MAX_RETRIES: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TcpState {
+    /// Guest initiated connection, waiting for the host socket to be connected.
+    Connecting,
+    /// Host initiated connection, SYN is sent to the guest.
+    SynSent,
+    /// SYN-ACK is sent to the guest.
+    SynReceived,
+    Established,
+    Closed,
+}
+
+/// Segment to be sent to the guest.
+pub struct TcpOutput {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: Vec<u8>,
+}
+
+fn seq_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+pub struct TcpConn {
+    pub stream: TcpStream,
+    state: TcpState,
+    /// Initial sequence number of our side.
+    iss: u32,
+    /// Oldest sequence number not acked by the guest.
+    snd_una: u32,
+    /// Next sequence number to send.
+    snd_nxt: u32,
+    /// Window advertised by the guest.
+    snd_wnd: u32,
+    /// Max segment size of the guest.
+    mss: usize,
+    /// Next sequence number expected from the guest.
+    rcv_nxt: u32,
+    /// Data read from the host socket starting from `snd_una`.
+    to_guest: VecDeque<u8>,
+    /// Data received from the guest but not written to the host socket yet.
+    to_host: VecDeque<u8>,
+    /// The host socket is closed for reading, FIN follows the data to the guest.
+    host_eof: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    /// FIN is received from the guest.
+    guest_fin: bool,
+    /// The host socket is shut down for writing after the guest FIN.
+    host_shut: bool,
+    /// Time of the last transmission of unacked data.
+    last_xmit: Instant,
+    retries: u32,
+    output: Vec<TcpOutput>,
+}
+
+impl TcpConn {
+    fn new(stream: TcpStream, iss: u32, state: TcpState) -> Self {
+        TcpConn {
+            stream,
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_wnd: 0,
+            mss: usize::from(TCP_DEFAULT_MSS),
+            rcv_nxt: 0,
+            to_guest: VecDeque::new(),
+            to_host: VecDeque::new(),
+            host_eof: false,
+            fin_sent: false,
+            fin_acked: false,
+            guest_fin: false,
+            host_shut: false,
+            last_xmit: Instant::now(),
+            retries: 0,
+            output: Vec::new(),
+        }
+    }
+
+    /// Create the connection for the SYN of the guest, `stream` is connecting.
+    pub fn new_outbound(stream: TcpStream, iss: u32, syn: &TcpSegment) -> Self {
+        let mut conn = TcpConn::new(stream, iss, TcpState::Connecting);
+        conn.rcv_nxt = syn.seq.wrapping_add(1);
+        conn.update_peer(syn);
+        conn
+    }
+
+    /// Create the connection for the accepted host socket, SYN is sent to the guest.
+    pub fn new_inbound(stream: TcpStream, iss: u32) -> Self {
+        let mut conn = TcpConn::new(stream, iss, TcpState::SynSent);
+        conn.send_syn();
+        conn
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state == TcpState::Closed
+    }
+
+    pub fn take_output(&mut self) -> Vec<TcpOutput> {
+        std::mem::take(&mut self.output)
+    }
+
+    fn update_peer(&mut self, seg: &TcpSegment) {
+        self.snd_wnd = u32::from(seg.window);
+        if let Some(mss) = seg.mss {
+            self.mss = usize::from(mss.clamp(TCP_DEFAULT_MSS, TCP_MSS));
+        }
+    }
+
+    fn rcv_wnd(&self) -> u16 {
+        (TCP_BUF_SIZE - self.to_host.len()) as u16
+    }
+
+    fn push(&mut self, seq: u32, flags: u8, mss: Option<u16>, payload: Vec<u8>) {
+        self.output.push(TcpOutput {
+            seq,
+            ack: self.rcv_nxt,
+            flags,
+            window: self.rcv_wnd(),
+            mss,
+            payload,
+        });
+    }
+
+    fn send_ack(&mut self) {
+        self.push(self.snd_nxt, TCP_ACK, None, Vec::new());
+    }
+
+    fn send_syn(&mut self) {
+        let flags = if self.state == TcpState::SynSent {
+            TCP_SYN
+        } else {
+            TCP_SYN | TCP_ACK
+        };
+        self.push(self.iss, flags, Some(TCP_MSS), Vec::new());
+        self.snd_nxt = self.iss.wrapping_add(1);
+        self.last_xmit = Instant::now();
+    }
+
+    /// Abort the connection and reset the guest side.
+    pub fn reset(&mut self) {
+        if self.state != TcpState::Closed {
+            self.push(self.snd_nxt, TCP_RST | TCP_ACK, None, Vec::new());
+            self.state = TcpState::Closed;
+        }
+    }
+
+    fn check_finished(&mut self) {
+        if self.guest_fin && self.host_shut && self.fin_acked {
+            self.state = TcpState::Closed;
+        }
+    }
+
+    /// Handle the segment from the guest.
+    pub fn input(&mut self, seg: &TcpSegment) {
+        if seg.flags & TCP_RST != 0 {
+            self.state = TcpState::Closed;
+            return;
+        }
+
+        match self.state {
+            TcpState::Connecting | TcpState::Closed => return,
+            TcpState::SynSent => {
+                if seg.flags & (TCP_SYN | TCP_ACK) != TCP_SYN | TCP_ACK || seg.ack != self.snd_nxt {
+                    return;
+                }
+                self.rcv_nxt = seg.seq.wrapping_add(1);
+                self.snd_una = seg.ack;
+                self.update_peer(seg);
+                self.state = TcpState::Established;
+                self.send_ack();
+                self.pump();
+                return;
+            }
+            TcpState::SynReceived => {
+                if seg.flags & TCP_SYN != 0 {
+                    // SYN-ACK is lost, the guest retransmits SYN.
+                    self.send_syn();
+                    return;
+                }
+                if seg.flags & TCP_ACK == 0 || seg.ack != self.snd_nxt {
+                    return;
+                }
+                self.snd_una = seg.ack;
+                self.state = TcpState::Established;
+                self.retries = 0;
+            }
+            TcpState::Established => {}
+        }
+
+        if seg.flags & TCP_ACK != 0 {
+            self.handle_ack(seg);
+        }
+
+        let mut need_ack = false;
+        if !seg.payload.is_empty() || seg.flags & TCP_FIN != 0 {
+            // Out of order segments are dropped and acked with the expected
+            // sequence number, the guest will retransmit them.
+            if seg.seq == self.rcv_nxt && !self.guest_fin {
+                let len = seg.payload.len().min(TCP_BUF_SIZE - self.to_host.len());
+                self.to_host.extend(&seg.payload[..len]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+                if seg.flags & TCP_FIN != 0 && len == seg.payload.len() {
+                    self.guest_fin = true;
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                }
+            }
+            need_ack = true;
+        }
+
+        if self.write_host().is_err() {
+            self.reset();
+            return;
+        }
+        let sent = self.output.len();
+        self.pump();
+        if need_ack && self.output.len() == sent && self.state != TcpState::Closed {
+            self.send_ack();
+        }
+        self.check_finished();
+    }
+
+    fn handle_ack(&mut self, seg: &TcpSegment) {
+        if seq_after(seg.ack, self.snd_una) && !seq_after(seg.ack, self.snd_nxt) {
+            let mut acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+            if self.fin_sent && seg.ack == self.snd_nxt {
+                self.fin_acked = true;
+                acked -= 1;
+            }
+            self.to_guest.drain(..acked.min(self.to_guest.len()));
+            self.snd_una = seg.ack;
+            self.retries = 0;
+            self.last_xmit = Instant::now();
+        }
+        self.snd_wnd = u32::from(seg.window);
+    }
+
+    /// Handle the event of the host socket.
+    pub fn host_event(&mut self, event: EventSet) {
+        if self.state == TcpState::Connecting {
+            if !event.intersects(EventSet::OUT | EventSet::ERROR | EventSet::HANG_UP) {
+                return;
+            }
+            match self.stream.take_error() {
+                Ok(None) => {
+                    self.state = TcpState::SynReceived;
+                    self.send_syn();
+                }
+                _ => self.reset(),
+            }
+            return;
+        }
+        if self.state != TcpState::Established {
+            return;
+        }
+
+        if event.contains(EventSet::OUT) {
+            let window = self.rcv_wnd();
+            if self.write_host().is_err() {
+                self.reset();
+                return;
+            }
+            // Update the window if the guest may be blocked by it.
+            if window == 0 && self.rcv_wnd() != 0 {
+                self.send_ack();
+            }
+        }
+        if event.intersects(EventSet::IN | EventSet::HANG_UP | EventSet::ERROR) {
+            self.pump();
+        }
+        self.check_finished();
+    }
+
+    /// Retransmit the unacked segments if the retransmission timeout expires.
+    pub fn timer(&mut self, now: Instant) {
+        let unacked = match self.state {
+            TcpState::SynSent | TcpState::SynReceived => true,
+            TcpState::Established => self.snd_nxt != self.snd_una,
+            _ => false,
+        };
+        if !unacked {
+            return;
+        }
+
+        let rto = TCP_RTO_INIT
+            .checked_mul(1 << self.retries.min(6))
+            .unwrap_or(TCP_RTO_MAX)
+            .min(TCP_RTO_MAX);
+        if now.duration_since(self.last_xmit) < rto {
+            return;
+        }
+        self.retries += 1;
+        if self.retries > TCP_MAX_RETRIES {
+            self.reset();
+            return;
+        }
+        if self.state == TcpState::Established {
+            // Go back to the oldest unacked data.
+            self.snd_nxt = self.snd_una;
+            self.fin_sent = false;
+            self.transmit();
+        } else {
+            self.send_syn();
+        }
+        self.last_xmit = now;
+    }
+
+    /// Read the host socket and send the data to the guest as the window allows.
+    fn pump(&mut self) {
+        if self.state != TcpState::Established {
+            return;
+        }
+        if self.read_host().is_err() {
+            self.reset();
+            return;
+        }
+        self.transmit();
+    }
+
+    fn read_host(&mut self) -> IoResult<()> {
+        let mut buf = [0_u8; 16384];
+        while !self.host_eof && self.to_guest.len() < TCP_BUF_SIZE {
+            let len = buf.len().min(TCP_BUF_SIZE - self.to_guest.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => self.host_eof = true,
+                Ok(n) => self.to_guest.extend(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_host(&mut self) -> IoResult<()> {
+        while !self.to_host.is_empty() {
+            let (data, _) = self.to_host.as_slices();
+            match self.stream.write(data) {
+                Ok(n) => {
+                    self.to_host.drain(..n);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if self.guest_fin && !self.host_shut {
+            self.host_shut = true;
+            // The peer may have closed the socket already.
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self) {
+        let idle = self.snd_nxt == self.snd_una;
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let pending = self.to_guest.len().saturating_sub(in_flight);
+            let window = (self.snd_wnd as usize).saturating_sub(in_flight);
+            let len = pending.min(window).min(self.mss);
+            if len == 0 {
+                if self.host_eof && !self.fin_sent && pending == 0 {
+                    self.push(self.snd_nxt, TCP_FIN | TCP_ACK, None, Vec::new());
+                    self.snd_nxt = self.snd_nxt.wrapping_add(1);
+                    self.fin_sent = true;
+                }
+                break;
+            }
+            let payload = self
+                .to_guest
+                .range(in_flight..in_flight + len)
+                .copied()
+                .collect();
+            self.push(self.snd_nxt, TCP_ACK | TCP_PSH, None, payload);
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+        if idle && self.snd_nxt != self.snd_una {
+            self.last_xmit = Instant::now();
+            self.retries = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Get the connected host sockets, the first one is used by the connection.
+    fn stream_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (accepted, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        (stream, accepted)
+    }
+
+    fn seg(seq: u32, ack: u32, flags: u8, payload: &[u8]) -> TcpSegment<'_> {
+        TcpSegment {
+            src_port: 40000,
+            dst_port: 80,
+            seq,
+            ack,
+            flags,
+            window: 65535,
+            mss: if flags & TCP_SYN != 0 {
+                Some(1460)
+            } else {
+                None
+            },
+            payload,
+        }
+    }
+
+    fn output(conn: &mut TcpConn) -> Vec<(u32, u32, u8, Vec<u8>)> {
+        conn.take_output()
+            .into_iter()
+            .map(|out| (out.seq, out.ack, out.flags, out.payload))
+            .collect()
+    }
+
+    /// Get the connection established by the guest, the guest ISN is 100 and ours is 1000.
+    fn established() -> (TcpConn, TcpStream) {
+        let (stream, host) = stream_pair();
+        let mut conn = TcpConn::new_outbound(stream, 1000, &seg(100, 0, TCP_SYN, &[]));
+        conn.host_event(EventSet::OUT);
+        conn.input(&seg(101, 1001, TCP_ACK, &[]));
+        conn.take_output();
+        (conn, host)
+    }
+
+    #[test]
+    fn test_tcp_handshake() {
+        // The guest connects, SYN-ACK is sent after the host socket is connected.
+        let (stream, _host) = stream_pair();
+        let mut conn = TcpConn::new_outbound(stream, 1000, &seg(100, 0, TCP_SYN, &[]));
+        conn.input(&seg(101, 1001, TCP_ACK, &[]));
+        assert!(output(&mut conn).is_empty());
+        conn.host_event(EventSet::OUT);
+        assert_eq!(
+            output(&mut conn),
+            vec![(1000, 101, TCP_SYN | TCP_ACK, vec![])]
+        );
+
+        // SYN-ACK is resent for the retransmitted SYN and after the timeout.
+        conn.input(&seg(100, 0, TCP_SYN, &[]));
+        assert_eq!(
+            output(&mut conn),
+            vec![(1000, 101, TCP_SYN | TCP_ACK, vec![])]
+        );
+        conn.timer(Instant::now() + TCP_RTO_MAX);
+        assert_eq!(
+            output(&mut conn),
+            vec![(1000, 101, TCP_SYN | TCP_ACK, vec![])]
+        );
+
+        // The ACK of wrong sequence is ignored.
+        conn.input(&seg(101, 1002, TCP_ACK, &[]));
+        conn.input(&seg(101, 1001, TCP_ACK, &[]));
+        assert_eq!(conn.state, TcpState::Established);
+        assert!(output(&mut conn).is_empty());
+
+        // The host connects, the guest answers SYN with SYN-ACK.
+        let (stream, _host) = stream_pair();
+        let mut conn = TcpConn::new_inbound(stream, 2000);
+        assert_eq!(output(&mut conn), vec![(2000, 0, TCP_SYN, vec![])]);
+        conn.input(&seg(300, 2000, TCP_SYN | TCP_ACK, &[]));
+        assert_eq!(conn.state, TcpState::SynSent);
+        conn.input(&seg(300, 2001, TCP_SYN | TCP_ACK, &[]));
+        assert_eq!(conn.state, TcpState::Established);
+        assert_eq!(output(&mut conn), vec![(2001, 301, TCP_ACK, vec![])]);
+    }
+
+    #[test]
+    fn test_tcp_data() {
+        let (mut conn, mut host) = established();
+
+        // Data of the guest is written to the host socket and acked.
+        conn.input(&seg(101, 1001, TCP_ACK | TCP_PSH, b"hello"));
+        assert_eq!(output(&mut conn), vec![(1001, 106, TCP_ACK, vec![])]);
+        let mut buf = [0_u8; 16];
+        assert_eq!(host.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // Out of order data is dropped and the expected sequence is acked.
+        conn.input(&seg(111, 1001, TCP_ACK | TCP_PSH, b"world"));
+        assert_eq!(output(&mut conn), vec![(1001, 106, TCP_ACK, vec![])]);
+
+        // Data of the host is sent to the guest, and retransmitted until acked.
+        host.write_all(b"world").unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        conn.host_event(EventSet::IN);
+        let data = (1001, 106, TCP_ACK | TCP_PSH, b"world".to_vec());
+        assert_eq!(output(&mut conn), vec![data.clone()]);
+        conn.timer(Instant::now() + TCP_RTO_MAX);
+        assert_eq!(output(&mut conn), vec![data]);
+        conn.input(&seg(106, 1006, TCP_ACK, &[]));
+        conn.timer(Instant::now() + TCP_RTO_MAX);
+        assert!(output(&mut conn).is_empty());
+        assert!(conn.to_guest.is_empty());
+    }
+
+    #[test]
+    fn test_tcp_fin() {
+        let (mut conn, mut host) = established();
+
+        // FIN of the guest shuts down the host socket for writing.
+        conn.input(&seg(101, 1001, TCP_ACK | TCP_FIN, &[]));
+        assert_eq!(output(&mut conn), vec![(1001, 102, TCP_ACK, vec![])]);
+        let mut buf = [0_u8; 16];
+        assert_eq!(host.read(&mut buf).unwrap(), 0);
+        assert!(!conn.is_closed());
+
+        // FIN follows the data of the host, and the connection is closed when it is acked.
+        host.write_all(b"bye").unwrap();
+        drop(host);
+        std::thread::sleep(Duration::from_millis(50));
+        conn.host_event(EventSet::IN);
+        assert_eq!(
+            output(&mut conn),
+            vec![
+                (1001, 102, TCP_ACK | TCP_PSH, b"bye".to_vec()),
+                (1004, 102, TCP_ACK | TCP_FIN, vec![]),
+            ]
+        );
+        conn.input(&seg(102, 1004, TCP_ACK, &[]));
+        assert!(!conn.is_closed());
+        conn.input(&seg(102, 1005, TCP_ACK, &[]));
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn test_tcp_rst() {
+        // RST of the guest closes the connection silently.
+        let (mut conn, _host) = established();
+        conn.input(&seg(101, 1001, TCP_RST, &[]));
+        assert!(conn.is_closed());
+        assert!(output(&mut conn).is_empty());
+
+        // The guest is reset once when the connection is aborted.
+        let (mut conn, _host) = established();
+        conn.reset();
+        conn.reset();
+        assert!(conn.is_closed());
+        assert_eq!(
+            output(&mut conn),
+            vec![(1001, 101, TCP_RST | TCP_ACK, vec![])]
+        );
+        conn.input(&seg(101, 1001, TCP_ACK | TCP_PSH, b"hello"));
+        assert!(output(&mut conn).is_empty());
+
+        // The guest is reset if the data can not be written to the host socket.
+        let (stream, _host) = stream_pair();
+        stream.shutdown(Shutdown::Both).unwrap();
+        let mut conn = TcpConn::new_outbound(stream, 1000, &seg(100, 0, TCP_SYN, &[]));
+        conn.host_event(EventSet::OUT);
+        conn.input(&seg(101, 1001, TCP_ACK, &[]));
+        conn.input(&seg(101, 1001, TCP_ACK | TCP_PSH, b"hello"));
+        assert!(conn.is_closed());
+        assert_eq!(output(&mut conn).last().unwrap().2, TCP_RST | TCP_ACK);
+    }
+}
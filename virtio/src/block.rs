@@ -13,38 +13,65 @@
 use std::cmp;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::Write;
 use std::mem::size_of;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Instant;
 
 use super::{
     iov_discard_back, iov_discard_front, iov_to_buf, report_virtio_error, virtio_has_feature,
     Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioTrace,
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX,
-    VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO,
+    VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_F_ZONED, VIRTIO_BLK_ID_BYTES,
+    VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP, VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+    VIRTIO_BLK_S_ZONE_INVALID_CMD, VIRTIO_BLK_S_ZONE_OPEN_RESOURCE, VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_T_ZONE_APPEND, VIRTIO_BLK_T_ZONE_CLOSE,
+    VIRTIO_BLK_T_ZONE_FINISH, VIRTIO_BLK_T_ZONE_OPEN, VIRTIO_BLK_T_ZONE_REPORT,
+    VIRTIO_BLK_T_ZONE_RESET, VIRTIO_BLK_T_ZONE_RESET_ALL, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
     VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
 };
 use crate::VirtioError;
 use address_space::{AddressSpace, GuestAddress};
 use anyhow::{anyhow, bail, Context, Result};
+use block_backend::commit::{CommitJob, CommitPivotFn, CommitReleaseFn};
+use block_backend::dirty_bitmap::{
+    add_named_bitmap, remove_named_bitmap, restore_persistent_bitmaps, store_persistent_bitmaps,
+    DirtyBitmap,
+};
+use block_backend::mirror::{MirrorJob, MirrorPivotFn, MirrorReleaseFn, MirrorSync};
+use block_backend::nbd::server::NbdExport;
+use block_backend::stats::{BlockAcctCookie, BlockAcctStats, BlockAcctType};
+use block_backend::stream::StreamJob;
+use block_backend::throttle::{get_throttle_group, Throttle, ThrottleGroup};
+use block_backend::zoned::{ZoneError, ZoneMap, ZoneOp};
+use block_backend::{
+    create_block_backend, create_nbd_backend, BlockDriverOps, BlockIoErrorCallback, BlockProperty,
+    SnapshotInfo,
+};
 use byteorder::{ByteOrder, LittleEndian};
 use log::{error, warn};
-use machine_manager::config::{BlkDevConfig, ConfigCheck, DriveFile, VmConfig};
+use machine_manager::config::{
+    is_nbd_path, BlkDevConfig, BlockErrorAction, ConfigCheck, DiskFormat, DriveFile, NbdUri,
+    ThrottleConfig, VmConfig,
+};
+use machine_manager::event;
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper, EventLoop};
+use machine_manager::machine::request_vm_stop;
+use machine_manager::qmp::qmp_schema::{BlockDeviceStats, BlockIoError};
+use machine_manager::qmp::QmpChannel;
 use migration::{
     migration::Migratable, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
     StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
-use util::aio::{iov_from_buf_direct, raw_datasync, Aio, AioCb, AioEngine, Iovec, OpCode};
+use util::aio::{iov_from_buf_direct, raw_datasync, Aio, AioCb, Iovec, OpCode};
 use util::byte_code::ByteCode;
-use util::leak_bucket::LeakBucket;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
@@ -67,15 +94,17 @@ const MAX_NUM_MERGE_IOVS: usize = 1024;
 const MAX_NUM_MERGE_BYTES: u64 = i32::MAX as u64;
 /// Max time for every round of process queue.
 const MAX_MILLIS_TIME_PROCESS_QUEUE: u16 = 100;
+/// Max number sectors of a discard or write zeroes segment.
+const MAX_DISCARD_WRITE_ZEROES_SECTORS: u32 = (i32::MAX as u32) >> SECTOR_SHIFT;
+/// Size of the header and the descriptor of zone report.
+const ZONE_REPORT_HEADER_SIZE: usize = 64;
+const ZONE_DESCRIPTOR_SIZE: usize = 64;
 
 type SenderConfig = (
-    Option<Arc<File>>,
-    u32,
-    u32,
+    Option<Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>>,
     u64,
     Option<String>,
-    bool,
-    AioEngine,
+    Option<Arc<Mutex<ZoneMap>>>,
 );
 
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
@@ -97,6 +126,73 @@ struct RequestOutHeader {
 
 impl ByteCode for RequestOutHeader {}
 
+/// The segment of discard and write zeroes request.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DiscardWriteZeroesSeg {
+    /// The start sector of the segment.
+    sector: u64,
+    /// The number of sectors of the segment.
+    num_sectors: u32,
+    /// Only the unmap flag is valid for write zeroes command.
+    flags: u32,
+}
+
+impl ByteCode for DiscardWriteZeroesSeg {}
+
+/// Error policy of the block device, shared by the IO handler and the requests.
+struct BlockErrorPolicy {
+    /// Id of the block device.
+    id: String,
+    /// Action on write error.
+    werror: BlockErrorAction,
+    /// Action on read error.
+    rerror: BlockErrorAction,
+    /// The failed requests kept to retry after VM resumes.
+    failed_reqs: Mutex<Vec<Request>>,
+}
+
+impl BlockErrorPolicy {
+    fn new(blk_cfg: &BlkDevConfig) -> Self {
+        BlockErrorPolicy {
+            id: blk_cfg.id.clone(),
+            werror: blk_cfg.werror,
+            rerror: blk_cfg.rerror,
+            failed_reqs: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Handle the failed request according to the error action. Returns the status
+    /// to complete the request with, or None if the request is kept to retry.
+    fn handle_error(&self, req: &Request, is_read: bool, ret: i64) -> Option<u8> {
+        let mut action = if is_read { self.rerror } else { self.werror };
+        if action == BlockErrorAction::Stop {
+            self.failed_reqs.lock().unwrap().push(req.clone());
+            if !request_vm_stop() {
+                warn!("VM can't be stopped on block error, report it to guest");
+                self.failed_reqs.lock().unwrap().pop();
+                action = BlockErrorAction::Report;
+            }
+        }
+
+        let err = -ret as i32;
+        let io_error = BlockIoError {
+            device: self.id.clone(),
+            operation: if is_read { "read" } else { "write" }.to_string(),
+            action: action.to_string(),
+            nospace: err == libc::ENOSPC,
+            reason: std::io::Error::from_raw_os_error(err).to_string(),
+        };
+        event!(BlockIoError; io_error);
+
+        match action {
+            BlockErrorAction::Report => Some(VIRTIO_BLK_S_IOERR),
+            BlockErrorAction::Ignore => Some(VIRTIO_BLK_S_OK),
+            BlockErrorAction::Stop => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AioCompleteCb {
     queue: Arc<Mutex<Queue>>,
@@ -105,6 +201,8 @@ pub struct AioCompleteCb {
     req: Rc<Request>,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
+    /// Error policy of the block device.
+    err_policy: Arc<BlockErrorPolicy>,
 }
 
 impl AioCompleteCb {
@@ -114,6 +212,7 @@ impl AioCompleteCb {
         req: Rc<Request>,
         interrupt_cb: Arc<VirtioInterrupt>,
         driver_features: u64,
+        err_policy: Arc<BlockErrorPolicy>,
     ) -> Self {
         AioCompleteCb {
             queue,
@@ -121,6 +220,7 @@ impl AioCompleteCb {
             req,
             interrupt_cb,
             driver_features,
+            err_policy,
         }
     }
 
@@ -134,6 +234,9 @@ impl AioCompleteCb {
     }
 
     fn complete_one_request(&self, req: &Request, status: u8) -> Result<()> {
+        if let Some(acct) = req.acct.as_ref() {
+            acct.done(status != VIRTIO_BLK_S_OK);
+        }
         if let Err(ref e) = self.mem_space.write_object(&status, req.in_header) {
             bail!("Failed to write the status (blk io completion) {:?}", e);
         }
@@ -174,6 +277,10 @@ struct Request {
     data_len: u64,
     in_len: u32,
     in_header: GuestAddress,
+    /// The area of write zeroes request may be deallocated.
+    unmap: bool,
+    /// Used to account the IO of request.
+    acct: Option<BlockAcctCookie>,
     /// Point to the next merged Request.
     next: Box<Option<Request>>,
 }
@@ -221,6 +328,8 @@ impl Request {
             data_len: 0,
             in_len: 0,
             in_header,
+            unmap: false,
+            acct: None,
             next: Box::new(None),
         };
 
@@ -232,95 +341,218 @@ impl Request {
             request.in_len += in_iov.len;
         }
 
+        let zoned = handler.zones.is_some();
         match out_header.request_type {
             VIRTIO_BLK_T_IN | VIRTIO_BLK_T_GET_ID | VIRTIO_BLK_T_OUT => {
-                let data_iovec = match out_header.request_type {
-                    VIRTIO_BLK_T_OUT => {
-                        iov_discard_front(&mut elem.out_iovec, size_of::<RequestOutHeader>() as u64)
-                    }
-                    // Otherwise discard the last "status" byte.
-                    _ => iov_discard_back(&mut elem.in_iovec, 1),
-                };
-                if data_iovec.is_none() {
-                    bail!("Empty data for block request");
-                }
-                for elem_iov in data_iovec.unwrap() {
-                    if let Some(hva) = handler.mem_space.get_host_address(elem_iov.addr) {
-                        let iov = Iovec {
-                            iov_base: hva,
-                            iov_len: u64::from(elem_iov.len),
-                        };
-                        request.iovec.push(iov);
-                        // Note: elem_iov total len is no more than 1<<32.
-                        request.data_len += u64::from(elem_iov.len);
-                    } else {
-                        bail!("Map desc base {:?} failed", elem_iov.addr);
-                    }
+                request.parse_data(handler, elem)?;
+            }
+            VIRTIO_BLK_T_ZONE_REPORT if zoned => request.parse_data(handler, elem)?,
+            VIRTIO_BLK_T_ZONE_APPEND if zoned => {
+                // The appended sector is returned before the "status" byte.
+                if in_iov_elem.len < 1 + size_of::<u64>() as u32 {
+                    error!(
+                        "Invalid in header for zone append: length {}",
+                        in_iov_elem.len
+                    );
+                    *status = VIRTIO_BLK_S_IOERR;
+                } else {
+                    request.parse_data(handler, elem)?;
                 }
             }
             VIRTIO_BLK_T_FLUSH => (),
+            // Discard and write zeroes are not offered for zoned device.
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES if !zoned => {
+                *status = request.parse_discard_write_zeroes(handler, elem)?;
+            }
+            VIRTIO_BLK_T_ZONE_OPEN
+            | VIRTIO_BLK_T_ZONE_CLOSE
+            | VIRTIO_BLK_T_ZONE_FINISH
+            | VIRTIO_BLK_T_ZONE_RESET
+            | VIRTIO_BLK_T_ZONE_RESET_ALL
+                if zoned => {}
             others => {
                 error!("Request type {} is not supported for block", others);
                 *status = VIRTIO_BLK_S_UNSUPP;
             }
         }
 
-        if !request.io_range_valid(handler.disk_sectors) {
+        if *status == VIRTIO_BLK_S_OK && !request.io_range_valid(handler.disk_sectors) {
             *status = VIRTIO_BLK_S_IOERR;
         }
 
+        let acct_type = match out_header.request_type {
+            VIRTIO_BLK_T_IN => Some(BlockAcctType::Read),
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_WRITE_ZEROES | VIRTIO_BLK_T_ZONE_APPEND => {
+                Some(BlockAcctType::Write)
+            }
+            VIRTIO_BLK_T_FLUSH => Some(BlockAcctType::Flush),
+            VIRTIO_BLK_T_DISCARD => Some(BlockAcctType::Unmap),
+            _ => None,
+        };
+        request.acct = acct_type
+            .map(|acct_type| BlockAcctCookie::new(&handler.stats, acct_type, request.data_len));
+
         Ok(request)
     }
 
+    /// Map the data buffers of request, which follow the out header for writes,
+    /// or precede the in header otherwise.
+    fn parse_data(&mut self, handler: &BlockIoHandler, elem: &mut Element) -> Result<()> {
+        let data_iovec = match self.out_header.request_type {
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_ZONE_APPEND => {
+                iov_discard_front(&mut elem.out_iovec, size_of::<RequestOutHeader>() as u64)
+            }
+            // Otherwise discard the last "status" byte.
+            _ => iov_discard_back(&mut elem.in_iovec, 1),
+        };
+        if data_iovec.is_none() {
+            bail!("Empty data for block request");
+        }
+        for elem_iov in data_iovec.unwrap() {
+            if let Some(hva) = handler.mem_space.get_host_address(elem_iov.addr) {
+                let iov = Iovec {
+                    iov_base: hva,
+                    iov_len: u64::from(elem_iov.len),
+                };
+                self.iovec.push(iov);
+                // Note: elem_iov total len is no more than 1<<32.
+                self.data_len += u64::from(elem_iov.len);
+            } else {
+                bail!("Map desc base {:?} failed", elem_iov.addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse the segment of discard or write zeroes request, only one segment
+    /// is supported as `max_discard_seg` and `max_write_zeroes_seg` are 1.
+    fn parse_discard_write_zeroes(
+        &mut self,
+        handler: &BlockIoHandler,
+        elem: &mut Element,
+    ) -> Result<u8> {
+        let request_type = self.out_header.request_type;
+        let data_iovec =
+            iov_discard_front(&mut elem.out_iovec, size_of::<RequestOutHeader>() as u64)
+                .with_context(|| "Empty data for block request")?;
+        let data_len: u64 = data_iovec.iter().map(|iov| u64::from(iov.len)).sum();
+        if data_len != size_of::<DiscardWriteZeroesSeg>() as u64 {
+            error!(
+                "Invalid segments length {} of block request type {}",
+                data_len, request_type
+            );
+            return Ok(VIRTIO_BLK_S_UNSUPP);
+        }
+
+        let mut seg = DiscardWriteZeroesSeg::default();
+        iov_to_buf(&handler.mem_space, data_iovec, seg.as_mut_bytes())?;
+        let sector = LittleEndian::read_u64(seg.sector.as_bytes());
+        let num_sectors = LittleEndian::read_u32(seg.num_sectors.as_bytes());
+        let flags = LittleEndian::read_u32(seg.flags.as_bytes());
+
+        let valid_flags = match request_type {
+            VIRTIO_BLK_T_WRITE_ZEROES => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            _ => 0,
+        };
+        if flags & !valid_flags != 0 {
+            error!(
+                "Invalid flags {:#x} of block request type {}",
+                flags, request_type
+            );
+            return Ok(VIRTIO_BLK_S_UNSUPP);
+        }
+        if num_sectors > MAX_DISCARD_WRITE_ZEROES_SECTORS {
+            error!(
+                "Too many sectors {} of block request type {}",
+                num_sectors, request_type
+            );
+            return Ok(VIRTIO_BLK_S_IOERR);
+        }
+
+        self.out_header.sector = sector;
+        self.data_len = u64::from(num_sectors) << SECTOR_SHIFT;
+        self.unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+        Ok(VIRTIO_BLK_S_OK)
+    }
+
     fn execute(
         &self,
         iohandler: &mut BlockIoHandler,
-        mut aiocb: AioCb<AioCompleteCb>,
+        block_backend: Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>,
+        aiocompletecb: AioCompleteCb,
     ) -> Result<()> {
         let mut req = Some(self);
+        let mut iovecs = Vec::new();
         while let Some(req_raw) = req {
             for iov in req_raw.iovec.iter() {
                 let iovec = Iovec {
                     iov_base: iov.iov_base,
                     iov_len: iov.iov_len,
                 };
-                aiocb.iovec.push(iovec);
-                // Note: total len of each req is no more than 1<<32,
-                // and reqs count is no more than 1024.
-                aiocb.nbytes += iov.iov_len;
+                iovecs.push(iovec);
             }
             req = req_raw.next.as_ref().as_ref();
         }
+        let offset = (self.out_header.sector << SECTOR_SHIFT) as usize;
 
         let request_type = self.out_header.request_type;
         if MigrationManager::is_active()
-            && (request_type == VIRTIO_BLK_T_IN || request_type == VIRTIO_BLK_T_GET_ID)
+            && (request_type == VIRTIO_BLK_T_IN
+                || request_type == VIRTIO_BLK_T_GET_ID
+                || request_type == VIRTIO_BLK_T_ZONE_REPORT)
         {
             // FIXME: mark dirty page needs to be managed by `AddressSpace` crate.
-            for iov in aiocb.iovec.iter() {
+            for iov in iovecs.iter() {
                 // Mark vmm dirty page manually if live migration is active.
                 MigrationManager::mark_dirty_log(iov.iov_base, iov.iov_len);
             }
         }
 
-        let aio = &mut iohandler.aio;
         let serial_num = &iohandler.serial_num;
+        let mut locked_backend = block_backend.lock().unwrap();
         match request_type {
             VIRTIO_BLK_T_IN => {
-                aiocb.opcode = OpCode::Preadv;
-                aio.submit_request(aiocb)
+                locked_backend
+                    .read_vectored(iovecs, offset, aiocompletecb)
                     .with_context(|| "Failed to process block request for reading")?;
             }
             VIRTIO_BLK_T_OUT => {
-                aiocb.opcode = OpCode::Pwritev;
-                aio.submit_request(aiocb)
+                locked_backend
+                    .write_vectored(iovecs, offset, aiocompletecb)
                     .with_context(|| "Failed to process block request for writing")?;
             }
+            VIRTIO_BLK_T_ZONE_APPEND => {
+                // The request has been moved to the write pointer of zone.
+                let sector = self.out_header.sector.to_le();
+                let addr = GuestAddress(self.in_header.0 - size_of::<u64>() as u64);
+                aiocompletecb
+                    .mem_space
+                    .write_object(&sector, addr)
+                    .with_context(|| "Failed to write the sector of zone append")?;
+                locked_backend
+                    .write_vectored(iovecs, offset, aiocompletecb)
+                    .with_context(|| "Failed to process block request for zone append")?;
+            }
             VIRTIO_BLK_T_FLUSH => {
-                aiocb.opcode = OpCode::Fdsync;
-                aio.submit_request(aiocb)
+                // The write pointers of emulated zones are persisted with data.
+                if let Some(Err(e)) = iohandler.zones.as_ref().map(|z| z.lock().unwrap().store()) {
+                    error!("Failed to store zones of block device: {:?}", e);
+                    return aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR);
+                }
+                locked_backend
+                    .datasync(aiocompletecb)
                     .with_context(|| "Failed to process block request for flushing")?;
             }
+            VIRTIO_BLK_T_DISCARD => {
+                locked_backend
+                    .discard(offset, self.data_len, aiocompletecb)
+                    .with_context(|| "Failed to process block request for discarding")?;
+            }
+            VIRTIO_BLK_T_WRITE_ZEROES => {
+                locked_backend
+                    .write_zeroes(offset, self.data_len, aiocompletecb, self.unmap)
+                    .with_context(|| "Failed to process block request for writing zeroes")?;
+            }
             VIRTIO_BLK_T_GET_ID => {
                 let serial = serial_num.clone().unwrap_or_else(|| String::from(""));
                 let serial_vec = get_serial_num_config(&serial);
@@ -331,7 +563,39 @@ impl Request {
                     },
                     |_| VIRTIO_BLK_S_OK,
                 );
-                aiocb.iocompletecb.complete_request(status)?;
+                aiocompletecb.complete_request(status)?;
+            }
+            VIRTIO_BLK_T_ZONE_REPORT => {
+                let status = iohandler.report_zones(self);
+                aiocompletecb.complete_request(status)?;
+            }
+            VIRTIO_BLK_T_ZONE_OPEN
+            | VIRTIO_BLK_T_ZONE_CLOSE
+            | VIRTIO_BLK_T_ZONE_FINISH
+            | VIRTIO_BLK_T_ZONE_RESET
+            | VIRTIO_BLK_T_ZONE_RESET_ALL => {
+                let op = match request_type {
+                    VIRTIO_BLK_T_ZONE_OPEN => ZoneOp::Open,
+                    VIRTIO_BLK_T_ZONE_CLOSE => ZoneOp::Close,
+                    VIRTIO_BLK_T_ZONE_FINISH => ZoneOp::Finish,
+                    VIRTIO_BLK_T_ZONE_RESET => ZoneOp::Reset,
+                    _ => ZoneOp::ResetAll,
+                };
+                match iohandler.manage_zone(op, self.out_header.sector) {
+                    // The data of emulated zones reset reads as zeros.
+                    Ok(Some((sector, nr_sectors))) => {
+                        locked_backend
+                            .write_zeroes(
+                                (sector << SECTOR_SHIFT) as usize,
+                                nr_sectors << SECTOR_SHIFT,
+                                aiocompletecb,
+                                true,
+                            )
+                            .with_context(|| "Failed to process block request for zone reset")?;
+                    }
+                    Ok(None) => aiocompletecb.complete_request(VIRTIO_BLK_S_OK)?,
+                    Err(status) => aiocompletecb.complete_request(status)?,
+                }
             }
             // The illegal request type has been handled in method new().
             _ => {}
@@ -341,7 +605,11 @@ impl Request {
 
     fn io_range_valid(&self, disk_sectors: u64) -> bool {
         match self.out_header.request_type {
-            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+            VIRTIO_BLK_T_IN
+            | VIRTIO_BLK_T_OUT
+            | VIRTIO_BLK_T_DISCARD
+            | VIRTIO_BLK_T_WRITE_ZEROES
+            | VIRTIO_BLK_T_ZONE_APPEND => {
                 if self.data_len % SECTOR_SIZE != 0 {
                     error!("Failed to process block request with size not aligned to 512B");
                     return false;
@@ -377,20 +645,12 @@ struct BlockIoHandler {
     queue_evt: Arc<EventFd>,
     /// The address space to which the block device belongs.
     mem_space: Arc<AddressSpace>,
-    /// The image file opened by the block device.
-    disk_image: Option<Arc<File>>,
-    /// The align requirement of request(offset/len).
-    pub req_align: u32,
-    /// The align requirement of buffer(iova_base).
-    pub buf_align: u32,
+    /// The block backend opened by the block device.
+    block_backend: Option<Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>>,
     /// The number of sectors of the disk image.
     disk_sectors: u64,
     /// Serial number of the block device.
     serial_num: Option<String>,
-    /// If use direct access io.
-    direct: bool,
-    /// Aio context.
-    aio: Box<Aio<AioCompleteCb>>,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
     /// The receiving half of Rust's channel to receive the image file.
@@ -403,11 +663,117 @@ struct BlockIoHandler {
     interrupt_cb: Arc<VirtioInterrupt>,
     /// thread name of io handler
     iothread: Option<String>,
-    /// Using the leak bucket to implement IO limits
-    leak_bucket: Option<LeakBucket>,
+    /// Limits of the read and write IO.
+    throttle: Throttle,
+    /// Statistics of the IO of device.
+    stats: Arc<BlockAcctStats>,
+    /// Error policy of the block device.
+    err_policy: Arc<BlockErrorPolicy>,
+    /// Eventfd to retry the failed requests.
+    retry_evt: Arc<EventFd>,
+    /// Zones of the zoned block device, shared by the queues.
+    zones: Option<Arc<Mutex<ZoneMap>>>,
+}
+
+/// Get the status of the zone command failed.
+fn zone_error_status(err: ZoneError) -> u8 {
+    match err {
+        ZoneError::InvalidCmd => VIRTIO_BLK_S_ZONE_INVALID_CMD,
+        ZoneError::UnalignedWp => VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+        ZoneError::OpenResource => VIRTIO_BLK_S_ZONE_OPEN_RESOURCE,
+        ZoneError::ActiveResource => VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+        ZoneError::Io => VIRTIO_BLK_S_IOERR,
+    }
 }
 
 impl BlockIoHandler {
+    /// Check the write to zoned device and advance the write pointer of zone,
+    /// the zone append is moved to the write pointer.
+    fn check_zoned_write(&self, req: &mut Request) -> u8 {
+        let append = match req.out_header.request_type {
+            VIRTIO_BLK_T_OUT => false,
+            VIRTIO_BLK_T_ZONE_APPEND => true,
+            _ => return VIRTIO_BLK_S_OK,
+        };
+        let zones = match self.zones.as_ref() {
+            Some(zones) => zones,
+            None => return VIRTIO_BLK_S_OK,
+        };
+        let sector = req.out_header.sector;
+        match zones
+            .lock()
+            .unwrap()
+            .write(sector, req.get_req_sector_num(), append)
+        {
+            Ok(sector) => {
+                req.out_header.sector = sector;
+                VIRTIO_BLK_S_OK
+            }
+            Err(e) => {
+                warn!("Zoned write at sector {} failed: {:?}", sector, e);
+                zone_error_status(e)
+            }
+        }
+    }
+
+    /// Fill the zone report of request, as many zones as the buffer holds are
+    /// reported from the zone containing the sector.
+    fn report_zones(&self, req: &Request) -> u8 {
+        let zones = match self.zones.as_ref() {
+            Some(zones) => zones,
+            None => return VIRTIO_BLK_S_UNSUPP,
+        };
+        let data_len = req.data_len as usize;
+        if data_len < ZONE_REPORT_HEADER_SIZE {
+            error!("Invalid buffer length {} of zone report", data_len);
+            return VIRTIO_BLK_S_ZONE_INVALID_CMD;
+        }
+        let max_zones = (data_len - ZONE_REPORT_HEADER_SIZE) / ZONE_DESCRIPTOR_SIZE;
+        let zones = match zones
+            .lock()
+            .unwrap()
+            .report(req.out_header.sector, max_zones)
+        {
+            Ok(zones) => zones,
+            Err(e) => return zone_error_status(e),
+        };
+
+        let mut buf = vec![0_u8; ZONE_REPORT_HEADER_SIZE + zones.len() * ZONE_DESCRIPTOR_SIZE];
+        LittleEndian::write_u64(&mut buf[0..8], zones.len() as u64);
+        for (i, zone) in zones.iter().enumerate() {
+            let desc = &mut buf[ZONE_REPORT_HEADER_SIZE + i * ZONE_DESCRIPTOR_SIZE..];
+            LittleEndian::write_u64(&mut desc[0..8], zone.cap);
+            LittleEndian::write_u64(&mut desc[8..16], zone.start);
+            LittleEndian::write_u64(&mut desc[16..24], zone.wp);
+            desc[24] = zone.zone_type;
+            desc[25] = zone.cond;
+        }
+        match iov_from_buf_direct(&req.iovec, &buf) {
+            Ok(_) => VIRTIO_BLK_S_OK,
+            Err(e) => {
+                error!("Failed to process block request for zone report, {:?}", e);
+                VIRTIO_BLK_S_IOERR
+            }
+        }
+    }
+
+    /// Execute the zone management operation, returns the range of sectors to
+    /// be zeroed for the emulated zones reset.
+    fn manage_zone(&self, op: ZoneOp, sector: u64) -> std::result::Result<Option<(u64, u64)>, u8> {
+        let zones = self.zones.as_ref().ok_or(VIRTIO_BLK_S_UNSUPP)?;
+        let mut locked_zones = zones.lock().unwrap();
+        let range = locked_zones.manage(op, sector).map_err(|e| {
+            warn!("Zone {:?} at sector {} failed: {:?}", op, sector, e);
+            zone_error_status(e)
+        })?;
+        // Zone management is persisted at once, as it is not done frequently.
+        if let Err(e) = locked_zones.store() {
+            error!("Failed to store zones of block device: {:?}", e);
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        Ok(range)
+    }
+
     fn merge_req_queue(&self, mut req_queue: Vec<Request>) -> Vec<Request> {
         req_queue.sort_by(|a, b| a.out_header.sector.cmp(&b.out_header.sector));
 
@@ -466,19 +832,26 @@ impl BlockIoHandler {
                 break;
             }
 
-            // limit io operations if iops is configured
-            if let Some(lb) = self.leak_bucket.as_mut() {
+            // Init and put valid request into request queue.
+            let mut status = VIRTIO_BLK_S_OK;
+            let mut req = Request::new(self, &mut elem, &mut status)?;
+            // Limit the read and write IO if throttling is configured.
+            let request_type = req.out_header.request_type;
+            if status == VIRTIO_BLK_S_OK
+                && (request_type == VIRTIO_BLK_T_IN || request_type == VIRTIO_BLK_T_OUT)
+            {
                 if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
-                    if lb.throttled(ctx, 1_u64) {
+                    let is_write = request_type == VIRTIO_BLK_T_OUT;
+                    if self.throttle.throttled(ctx, is_write, req.data_len) {
                         queue.vring.push_back();
                         break;
                     }
-                };
+                }
+            }
+            // The write pointer is advanced once the request is not pushed back.
+            if status == VIRTIO_BLK_S_OK {
+                status = self.check_zoned_write(&mut req);
             }
-
-            // Init and put valid request into request queue.
-            let mut status = VIRTIO_BLK_S_OK;
-            let req = Request::new(self, &mut elem, &mut status)?;
             if status != VIRTIO_BLK_S_OK {
                 let aiocompletecb = AioCompleteCb::new(
                     self.queue.clone(),
@@ -486,6 +859,7 @@ impl BlockIoHandler {
                     Rc::new(req),
                     self.interrupt_cb.clone(),
                     self.driver_features,
+                    self.err_policy.clone(),
                 );
                 // unlock queue, because it will be hold below.
                 drop(queue);
@@ -505,7 +879,14 @@ impl BlockIoHandler {
         }
 
         let merge_req_queue = self.merge_req_queue(req_queue);
-        for req in merge_req_queue.into_iter() {
+        self.submit_requests(merge_req_queue)?;
+
+        Ok(done)
+    }
+
+    /// Submit the (merged) requests to the block backend.
+    fn submit_requests(&mut self, reqs: Vec<Request>) -> Result<()> {
+        for req in reqs.into_iter() {
             let req_rc = Rc::new(req);
             let aiocompletecb = AioCompleteCb::new(
                 self.queue.clone(),
@@ -513,29 +894,29 @@ impl BlockIoHandler {
                 req_rc.clone(),
                 self.interrupt_cb.clone(),
                 self.driver_features,
+                self.err_policy.clone(),
             );
-            if let Some(disk_img) = self.disk_image.as_ref() {
-                let aiocb = AioCb {
-                    direct: self.direct,
-                    req_align: self.req_align,
-                    buf_align: self.buf_align,
-                    file_fd: disk_img.as_raw_fd(),
-                    opcode: OpCode::Noop,
-                    iovec: Vec::new(),
-                    offset: (req_rc.out_header.sector << SECTOR_SHIFT) as usize,
-                    nbytes: 0,
-                    user_data: 0,
-                    iocompletecb: aiocompletecb,
-                };
-                req_rc.execute(self, aiocb)?;
+            if let Some(block_backend) = self.block_backend.clone() {
+                req_rc.execute(self, block_backend, aiocompletecb)?;
             } else {
-                warn!("Failed to execute block request, disk_img not specified");
+                warn!("Failed to execute block request, block backend not specified");
                 aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR)?;
             }
         }
-        self.aio.flush_request()?;
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            block_backend.lock().unwrap().flush_request()?;
+        }
+        Ok(())
+    }
 
-        Ok(done)
+    /// Resubmit the requests which failed with the stop error action.
+    fn retry_failed_requests(&mut self) -> Result<()> {
+        let failed_reqs = std::mem::take(&mut *self.err_policy.failed_reqs.lock().unwrap());
+        if !failed_reqs.is_empty() {
+            self.submit_requests(failed_reqs)?;
+        }
+        self.process_queue()?;
+        Ok(())
     }
 
     fn process_queue_suppress_notify(&mut self) -> Result<bool> {
@@ -577,12 +958,8 @@ impl BlockIoHandler {
             )?;
 
             // See whether we have been throttled.
-            if let Some(lb) = self.leak_bucket.as_mut() {
-                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
-                    if lb.throttled(ctx, 0) {
-                        break;
-                    }
-                }
+            if self.throttle.timer_started() {
+                break;
             }
         }
         Ok(done)
@@ -602,18 +979,27 @@ impl BlockIoHandler {
     }
 
     fn complete_func(aiocb: &AioCb<AioCompleteCb>, ret: i64) -> Result<()> {
-        let mut status = if ret < 0 {
-            VIRTIO_BLK_S_IOERR
-        } else {
-            VIRTIO_BLK_S_OK
-        };
-
         let complete_cb = &aiocb.iocompletecb;
+        let mut status = VIRTIO_BLK_S_OK;
+        if ret < 0 {
+            let is_read = aiocb.opcode == OpCode::Preadv;
+            match complete_cb
+                .err_policy
+                .handle_error(&complete_cb.req, is_read, ret)
+            {
+                Some(err_status) => status = err_status,
+                // The request will be retried.
+                None => return Ok(()),
+            }
+        }
+
         // When driver does not accept FLUSH feature, the device must be of
         // writethrough cache type, so flush data before updating used ring.
         if !virtio_has_feature(complete_cb.driver_features, VIRTIO_BLK_F_FLUSH)
-            && aiocb.opcode == OpCode::Pwritev
+            && (aiocb.opcode == OpCode::Pwritev || aiocb.opcode.is_fallocate())
             && ret >= 0
+            // There is no host file to sync for the export of NBD server.
+            && aiocb.file_fd >= 0
             && raw_datasync(aiocb.file_fd) < 0
         {
             error!("Failed to flush data before send response to guest.");
@@ -623,58 +1009,23 @@ impl BlockIoHandler {
         complete_cb.complete_request(status)
     }
 
-    fn aio_complete_handler(&mut self) -> Result<bool> {
-        self.aio.handle_complete().map_err(|e| {
-            report_virtio_error(
-                self.interrupt_cb.clone(),
-                self.driver_features,
-                &self.device_broken,
-            );
-            e
-        })
-    }
-
     fn update_evt_handler(&mut self) {
-        let aio_engine;
         match self.receiver.recv() {
-            Ok((image, req_align, buf_align, disk_sectors, serial_num, direct, aio)) => {
+            Ok((block_backend, disk_sectors, serial_num, zones)) => {
                 self.disk_sectors = disk_sectors;
-                self.disk_image = image;
-                self.req_align = req_align;
-                self.buf_align = buf_align;
+                self.block_backend = block_backend;
                 self.serial_num = serial_num;
-                self.direct = direct;
-                aio_engine = aio;
+                self.zones = zones;
             }
             Err(e) => {
                 error!("Failed to receive config in updating handler {:?}", e);
                 self.disk_sectors = 0;
-                self.disk_image = None;
-                self.req_align = 1;
-                self.buf_align = 1;
+                self.block_backend = None;
                 self.serial_num = None;
-                self.direct = true;
-                aio_engine = AioEngine::Native;
+                self.zones = None;
             }
         };
 
-        if self.aio.get_engine() != aio_engine {
-            match Aio::new(Arc::new(Self::complete_func), aio_engine) {
-                Ok(aio) => {
-                    self.aio = Box::new(aio);
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                    report_virtio_error(
-                        self.interrupt_cb.clone(),
-                        self.driver_features,
-                        &self.device_broken,
-                    );
-                    return;
-                }
-            }
-        }
-
         if let Err(e) = (self.interrupt_cb)(&VirtioInterruptType::Config, None, false) {
             error!(
                 "{:?}. {:?}",
@@ -772,27 +1123,8 @@ impl EventNotifierHelper for BlockIoHandler {
             Some(handler_iopoll),
         ));
 
-        // Register timer event notifier for IO limits
-        if let Some(lb) = handler_raw.leak_bucket.as_ref() {
-            let h_clone = handler.clone();
-            let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
-                read_fd(fd);
-                let mut h_lock = h_clone.lock().unwrap();
-                if h_lock.device_broken.load(Ordering::SeqCst) {
-                    return None;
-                }
-                if let Some(lb) = h_lock.leak_bucket.as_mut() {
-                    lb.clear_timer();
-                }
-                if let Err(ref e) = h_lock.process_queue() {
-                    error!("Failed to handle block IO {:?}", e);
-                }
-                None
-            });
-            notifiers.push(build_event_notifier(lb.as_raw_fd(), vec![h], None));
-        }
-
-        // Register event notifier for aio.
+        // Register timer event notifier for IO limits, the limits may be
+        // enabled at runtime.
         let h_clone = handler.clone();
         let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
             read_fd(fd);
@@ -800,38 +1132,35 @@ impl EventNotifierHelper for BlockIoHandler {
             if h_lock.device_broken.load(Ordering::SeqCst) {
                 return None;
             }
-            if let Err(ref e) = h_lock.aio_complete_handler() {
-                error!("Failed to handle aio {:?}", e);
+            h_lock.throttle.clear_timer();
+            if let Err(ref e) = h_lock.process_queue() {
+                error!("Failed to handle block IO {:?}", e);
             }
             None
         });
+        notifiers.push(build_event_notifier(
+            handler_raw.throttle.as_raw_fd(),
+            vec![h],
+            None,
+        ));
+
+        // Register event notifier for retry_evt.
         let h_clone = handler.clone();
-        let handler_iopoll: Box<NotifierCallback> = Box::new(move |_, _fd: RawFd| {
+        let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
             let mut h_lock = h_clone.lock().unwrap();
             if h_lock.device_broken.load(Ordering::SeqCst) {
                 return None;
             }
-            if h_lock.aio.get_engine() == AioEngine::Off {
-                return None;
-            }
-            match h_lock.aio_complete_handler() {
-                Ok(done) => {
-                    if done {
-                        Some(Vec::new())
-                    } else {
-                        None
-                    }
-                }
-                Err(e) => {
-                    error!("Failed to handle aio {:?}", e);
-                    None
-                }
+            if let Err(ref e) = h_lock.retry_failed_requests() {
+                error!("Failed to retry block IO {:?}", e);
             }
+            None
         });
         notifiers.push(build_event_notifier(
-            handler_raw.aio.fd.as_raw_fd(),
+            handler_raw.retry_evt.as_raw_fd(),
             vec![h],
-            Some(handler_iopoll),
+            None,
         ));
 
         notifiers
@@ -893,6 +1222,32 @@ pub struct VirtioBlkConfig {
 
 impl ByteCode for VirtioBlkConfig {}
 
+/// The fields of config space following `VirtioBlkConfig`, which exist only
+/// when the device is zoned. Secure erase is not supported, so its fields are
+/// always zero.
+#[repr(C, packed)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioBlkZonedConfig {
+    /// Reserved fields of secure erase.
+    secure_erase: [u32; 3],
+    /// The number of sectors of zone.
+    zone_sectors: u32,
+    /// The maximum number of open zones, zero means no limit.
+    max_open_zones: u32,
+    /// The maximum number of active zones, zero means no limit.
+    max_active_zones: u32,
+    /// The maximum number of sectors of zone append request.
+    max_append_sectors: u32,
+    /// The write granularity in bytes.
+    write_granularity: u32,
+    /// The zoned model of device.
+    model: u8,
+    /// Reserved data.
+    unused2: [u8; 3],
+}
+
+impl ByteCode for VirtioBlkZonedConfig {}
+
 /// State of block device.
 #[repr(C)]
 #[derive(Clone, Copy, Desc, ByteCode)]
@@ -908,16 +1263,15 @@ pub struct BlockState {
     broken: bool,
 }
 
+/// The key is the id of block device, the value is the virtio block device.
+pub type BlockMap = Arc<Mutex<HashMap<String, Arc<Mutex<Block>>>>>;
+
 /// Block device structure.
 pub struct Block {
     /// Configuration of the block device.
     blk_cfg: BlkDevConfig,
-    /// Image file opened.
-    disk_image: Option<Arc<File>>,
-    /// The align requirement of request(offset/len).
-    pub req_align: u32,
-    /// The align requirement of buffer(iova_base).
-    pub buf_align: u32,
+    /// The block backend opened by the block device.
+    block_backend: Option<Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>>,
     /// Number of sectors of the image file.
     disk_sectors: u64,
     /// Status of block device.
@@ -928,12 +1282,24 @@ pub struct Block {
     senders: Vec<Sender<SenderConfig>>,
     /// Eventfd for config space update.
     update_evts: Vec<Arc<EventFd>>,
+    /// Eventfd to retry the failed requests.
+    retry_evts: Vec<Arc<EventFd>>,
+    /// IO handlers of the queues, locked to quiesce the IO of device.
+    handlers: Vec<Arc<Mutex<BlockIoHandler>>>,
     /// Eventfd for device deactivate.
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// IO limits of the device, which may be shared with other devices.
+    throttle_group: Arc<Mutex<ThrottleGroup>>,
+    /// Statistics of the IO of device.
+    stats: Arc<BlockAcctStats>,
+    /// Zones of the zoned block device.
+    zones: Option<Arc<Mutex<ZoneMap>>>,
+    /// Config space of the zoned block device.
+    zoned_config: VirtioBlkZonedConfig,
 }
 
 impl Block {
@@ -941,93 +1307,609 @@ impl Block {
         blk_cfg: BlkDevConfig,
         drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
     ) -> Block {
+        let throttle_group = Arc::new(Mutex::new(ThrottleGroup::new(
+            &blk_cfg.id,
+            ThrottleConfig::default(),
+        )));
         Self {
             blk_cfg,
-            disk_image: None,
-            req_align: 1,
-            buf_align: 1,
+            block_backend: None,
             disk_sectors: 0,
             state: BlockState::default(),
             interrupt_cb: None,
             senders: Vec::new(),
             update_evts: Vec::new(),
+            retry_evts: Vec::new(),
+            handlers: Vec::new(),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             drive_files,
+            throttle_group,
+            stats: Arc::new(BlockAcctStats::default()),
+            zones: None,
+            zoned_config: VirtioBlkZonedConfig::default(),
         }
     }
 
-    fn build_device_config_space(&mut self) {
-        // capacity: 64bits
-        let num_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
-        self.state.config_space.capacity = num_sectors;
-        // seg_max = queue_size - 2: 32bits
-        self.state.config_space.seg_max = self.queue_size() as u32 - 2;
+    /// Check whether any request failed with the stop error action is waiting to be retried.
+    fn has_failed_requests(&self) -> bool {
+        self.handlers.iter().any(|handler| {
+            !handler
+                .lock()
+                .unwrap()
+                .err_policy
+                .failed_reqs
+                .lock()
+                .unwrap()
+                .is_empty()
+        })
     }
-}
 
-impl VirtioDevice for Block {
-    /// Realize virtio block device.
-    fn realize(&mut self) -> Result<()> {
-        // if iothread not found, return err
-        if self.blk_cfg.iothread.is_some()
-            && EventLoop::get_ctx(self.blk_cfg.iothread.as_ref()).is_none()
-        {
-            bail!(
-                "IOThread {:?} of Block is not configured in params.",
-                self.blk_cfg.iothread,
-            );
-        }
+    fn gen_error_cb(&self, interrupt_cb: Arc<VirtioInterrupt>) -> BlockIoErrorCallback {
+        let cloned_features = self.state.driver_features;
+        let clone_broken = self.broken.clone();
+        Arc::new(move || {
+            report_virtio_error(interrupt_cb.clone(), cloned_features, &clone_broken);
+        })
+    }
 
-        self.state.device_features = (1_u64 << VIRTIO_F_VERSION_1) | (1_u64 << VIRTIO_BLK_F_FLUSH);
-        if self.blk_cfg.read_only {
-            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_RO;
+    /// Get the image file at `path` from drive files, and the properties used
+    /// to open it for the device.
+    fn fetch_image_file(&self, path: &str, format: DiskFormat) -> Result<(File, BlockProperty)> {
+        let drive_files = self.drive_files.lock().unwrap();
+        let file = VmConfig::fetch_drive_file(&drive_files, path)?;
+        let alignments = VmConfig::fetch_drive_align(&drive_files, path)?;
+        let conf = BlockProperty {
+            id: self.blk_cfg.id.clone(),
+            format,
+            iothread: self.blk_cfg.iothread.clone(),
+            direct: self.blk_cfg.direct,
+            req_align: alignments.0,
+            buf_align: alignments.1,
+            copy_on_read: self.blk_cfg.copy_on_read,
         };
-        self.state.device_features |= 1_u64 << VIRTIO_F_RING_INDIRECT_DESC;
-        self.state.device_features |= 1_u64 << VIRTIO_BLK_F_SEG_MAX;
-        self.state.device_features |= 1_u64 << VIRTIO_F_RING_EVENT_IDX;
-
-        self.build_device_config_space();
+        Ok((file, conf))
+    }
 
-        if self.blk_cfg.queues > 1 {
-            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_MQ;
-            self.state.config_space.num_queues = self.blk_cfg.queues;
+    fn build_block_backend(
+        &self,
+        blk_cfg: &BlkDevConfig,
+    ) -> Result<Arc<Mutex<dyn BlockDriverOps<AioCompleteCb>>>> {
+        let aio = Aio::new(Arc::new(BlockIoHandler::complete_func), blk_cfg.aio)?;
+        if is_nbd_path(&blk_cfg.path_on_host) {
+            let uri = blk_cfg.path_on_host.parse::<NbdUri>()?;
+            let conf = BlockProperty {
+                id: blk_cfg.id.clone(),
+                format: blk_cfg.format,
+                iothread: blk_cfg.iothread.clone(),
+                direct: false,
+                req_align: 1,
+                buf_align: 1,
+                copy_on_read: false,
+            };
+            return create_nbd_backend(&uri, blk_cfg.read_only, aio, conf);
         }
+        let (file, conf) = self.fetch_image_file(&blk_cfg.path_on_host, blk_cfg.format)?;
+        create_block_backend(file, aio, conf)
+    }
 
-        self.disk_image = None;
-        self.disk_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
-        self.req_align = 1;
-        self.buf_align = 1;
-        if !self.blk_cfg.path_on_host.is_empty() {
+    /// Build the zones of device, which are emulated over the raw image if the
+    /// zone size is configured, or those of the host zoned block device.
+    fn build_zone_map(&self, disk_size: u64) -> Result<Option<ZoneMap>> {
+        let blk_cfg = &self.blk_cfg;
+        if is_nbd_path(&blk_cfg.path_on_host) {
+            if blk_cfg.zone_size.is_some() {
+                bail!("Zones can't be emulated over NBD export");
+            }
+            return Ok(None);
+        }
+        let file = {
             let drive_files = self.drive_files.lock().unwrap();
-            let mut file = VmConfig::fetch_drive_file(&drive_files, &self.blk_cfg.path_on_host)?;
-            let alignments = VmConfig::fetch_drive_align(&drive_files, &self.blk_cfg.path_on_host)?;
-            let disk_size = file
-                .seek(SeekFrom::End(0))
-                .with_context(|| "Failed to seek the end for block")?;
-
-            self.disk_image = Some(Arc::new(file));
-            self.disk_sectors = disk_size >> SECTOR_SHIFT;
-            self.req_align = alignments.0;
-            self.buf_align = alignments.1;
+            VmConfig::fetch_drive_file(&drive_files, &blk_cfg.path_on_host)?
+        };
+        let zone_map = match blk_cfg.zone_size {
+            Some(zone_size) => {
+                if file.metadata()?.file_type().is_block_device() {
+                    bail!("Zones can't be emulated over block device");
+                }
+                ZoneMap::new_emulated(
+                    &blk_cfg.path_on_host,
+                    disk_size,
+                    zone_size,
+                    blk_cfg.conventional_zones,
+                    blk_cfg.max_open_zones,
+                    blk_cfg.max_active_zones,
+                )?
+            }
+            None => match ZoneMap::new_host(file)? {
+                Some(zone_map) => zone_map,
+                None => return Ok(None),
+            },
+        };
+        if blk_cfg.format != DiskFormat::Raw {
+            bail!(
+                "Zoned block device {} must be of raw format",
+                blk_cfg.path_on_host
+            );
         }
-        self.state.config_space.capacity = self.disk_sectors;
+        if blk_cfg.read_only {
+            bail!(
+                "Zoned block device {} can't be read only",
+                blk_cfg.path_on_host
+            );
+        }
+        Ok(Some(zone_map))
+    }
 
-        Ok(())
+    /// Offer the zoned model of device instead of discard and write zeroes.
+    fn build_zoned_config(&mut self, zone_map: &ZoneMap) {
+        self.state.device_features &= !(1_u64 << VIRTIO_BLK_F_DISCARD);
+        self.state.device_features &= !(1_u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        self.state.device_features |= 1_u64 << VIRTIO_BLK_F_ZONED;
+        self.zoned_config = VirtioBlkZonedConfig {
+            zone_sectors: zone_map.zone_sectors() as u32,
+            max_open_zones: zone_map.max_open(),
+            max_active_zones: zone_map.max_active(),
+            max_append_sectors: zone_map.max_append_sectors(),
+            write_granularity: SECTOR_SIZE as u32,
+            model: zone_map.model(),
+            ..Default::default()
+        };
     }
 
-    fn unrealize(&mut self) -> Result<()> {
-        MigrationManager::unregister_device_instance(BlockState::descriptor(), &self.blk_cfg.id);
-        Ok(())
+    /// Get the configuration of the block device.
+    pub fn blk_config(&self) -> &BlkDevConfig {
+        &self.blk_cfg
     }
 
-    /// Get the virtio device type, refer to Virtio Spec.
-    fn device_type(&self) -> u32 {
-        VIRTIO_TYPE_BLOCK
+    /// Get the statistics of the IO of device.
+    pub fn io_stats(&self) -> BlockDeviceStats {
+        self.stats.query()
     }
 
-    /// Get the count of virtio device queues.
-    fn queue_num(&self) -> usize {
+    /// Get the virtual size of disk in bytes.
+    pub fn disk_size(&self) -> u64 {
+        self.disk_sectors << SECTOR_SHIFT
+    }
+
+    /// Retry the requests which stopped the VM on IO error, called after VM resumes.
+    pub fn retry_failed_requests(&self) {
+        for retry_evt in &self.retry_evts {
+            if let Err(e) = retry_evt.write(1) {
+                error!(
+                    "Failed to retry requests of block device {}: {:?}",
+                    self.blk_cfg.id, e
+                );
+            }
+        }
+    }
+
+    /// Run `f` on the block backend with the IO of device quiesced, i.e. the
+    /// queues are not processed and the in-flight requests are completed.
+    fn with_quiesced_backend<R>(
+        &self,
+        f: impl FnOnce(&mut dyn BlockDriverOps<AioCompleteCb>) -> Result<R>,
+    ) -> Result<R> {
+        let block_backend = self
+            .block_backend
+            .as_ref()
+            .with_context(|| format!("No image is opened by block device {}", self.blk_cfg.id))?;
+        let _locked_handlers: Vec<MutexGuard<BlockIoHandler>> =
+            self.handlers.iter().map(|h| h.lock().unwrap()).collect();
+        let mut locked_backend = block_backend.lock().unwrap();
+        locked_backend.drain_request()?;
+        f(&mut *locked_backend)
+    }
+
+    /// Create an internal snapshot of the disk.
+    pub fn create_snapshot(&self, name: &str) -> Result<()> {
+        self.with_quiesced_backend(|backend| backend.create_snapshot(name))
+    }
+
+    /// Delete an internal snapshot of the disk.
+    pub fn delete_snapshot(&self, id: Option<&str>, name: Option<&str>) -> Result<SnapshotInfo> {
+        self.with_quiesced_backend(|backend| backend.delete_snapshot(id, name))
+    }
+
+    /// Get the path and format of the backing file of the active image.
+    pub fn backing_file(&self) -> Option<(String, DiskFormat)> {
+        self.block_backend.as_ref()?.lock().unwrap().backing_file()
+    }
+
+    /// Add a dirty bitmap to record the areas of disk written by guest.
+    pub fn add_dirty_bitmap(
+        &self,
+        name: &str,
+        granularity: Option<u64>,
+        persistent: bool,
+    ) -> Result<()> {
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read only", self.blk_cfg.id);
+        }
+        let path = self.blk_cfg.path_on_host.clone();
+        // Writes in flight are recorded before the bitmap is added.
+        self.with_quiesced_backend(|backend| {
+            add_named_bitmap(backend, &path, name, granularity, persistent)
+        })
+    }
+
+    pub fn remove_dirty_bitmap(&self, name: &str) -> Result<()> {
+        let path = self.blk_cfg.path_on_host.clone();
+        self.with_quiesced_backend(|backend| remove_named_bitmap(backend, &path, name))
+    }
+
+    pub fn get_dirty_bitmap(&self, name: &str) -> Result<Arc<Mutex<DirtyBitmap>>> {
+        self.block_backend
+            .as_ref()
+            .and_then(|backend| backend.lock().unwrap().get_dirty_bitmap(name))
+            .with_context(|| format!("Dirty bitmap {} not found", name))
+    }
+
+    /// Store the persistent dirty bitmaps alongside the image when VM exits.
+    pub fn store_dirty_bitmaps(&self) -> Result<()> {
+        if let Some(backend) = self.block_backend.as_ref() {
+            let bitmaps = backend.lock().unwrap().named_dirty_bitmaps();
+            store_persistent_bitmaps(&self.blk_cfg.path_on_host, &bitmaps, false)?;
+        }
+        Ok(())
+    }
+
+    /// Create the job to commit the active image to its backing file at `base`,
+    /// which has been registered to drive files in read-write mode.
+    pub fn commit_job(
+        &self,
+        base: &str,
+        base_format: DiskFormat,
+        pivot: CommitPivotFn,
+        release: CommitReleaseFn,
+    ) -> Result<CommitJob<AioCompleteCb>> {
+        let block_backend = self
+            .block_backend
+            .clone()
+            .with_context(|| format!("No image is opened by block device {}", self.blk_cfg.id))?;
+        let (file, conf) = self.fetch_image_file(base, base_format)?;
+        CommitJob::new(block_backend, file, conf, pivot, release)
+    }
+
+    /// Create the job to mirror the image of disk to the image at `target`,
+    /// which has been registered to drive files in read-write mode.
+    pub fn mirror_job(
+        &self,
+        target: &str,
+        target_format: DiskFormat,
+        sync: MirrorSync,
+        target_zeroed: bool,
+        pivot: MirrorPivotFn,
+        release: MirrorReleaseFn,
+    ) -> Result<MirrorJob<AioCompleteCb>> {
+        let block_backend = self
+            .block_backend
+            .clone()
+            .with_context(|| format!("No image is opened by block device {}", self.blk_cfg.id))?;
+        let (file, conf) = self.fetch_image_file(target, target_format)?;
+        MirrorJob::new(
+            block_backend,
+            file,
+            conf,
+            sync,
+            target_zeroed,
+            pivot,
+            release,
+        )
+    }
+
+    /// Create the job to copy the data of backing file into the image of disk.
+    pub fn stream_job(&self) -> Result<StreamJob<AioCompleteCb>> {
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read only", self.blk_cfg.id);
+        }
+        let block_backend = self
+            .block_backend
+            .clone()
+            .with_context(|| format!("No image is opened by block device {}", self.blk_cfg.id))?;
+        StreamJob::new(block_backend)
+    }
+
+    /// Export the disk by the NBD server with the name `export`, `bitmap` is
+    /// the dirty bitmap exposed to clients.
+    pub fn nbd_export(
+        &self,
+        export: &str,
+        writable: bool,
+        bitmap: Option<&str>,
+    ) -> Result<NbdExport> {
+        if writable && self.blk_cfg.read_only {
+            bail!("Block device {} is read only", self.blk_cfg.id);
+        }
+        let block_backend = self
+            .block_backend
+            .clone()
+            .with_context(|| format!("No image is opened by block device {}", self.blk_cfg.id))?;
+        let bitmap = match bitmap {
+            Some(name) => Some((name.to_string(), self.get_dirty_bitmap(name)?)),
+            None => None,
+        };
+        Ok(NbdExport::new(
+            export,
+            &self.blk_cfg.id,
+            block_backend,
+            writable,
+            bitmap,
+        ))
+    }
+
+    /// Switch the disk to the image file at `path` which has been registered to
+    /// drive files, e.g. the overlay of external snapshot. The virtual size of disk
+    /// must not be changed. The IO of device is quiesced during the switch, so the
+    /// guest keeps running.
+    pub fn switch_image(&mut self, path: &str, format: DiskFormat) -> Result<()> {
+        if self.zones.is_some() {
+            bail!(
+                "Image of zoned block device {} can't be switched",
+                self.blk_cfg.id
+            );
+        }
+        let mut blk_cfg = self.blk_cfg.clone();
+        blk_cfg.path_on_host = path.to_string();
+        blk_cfg.format = format;
+        let block_backend = self.build_block_backend(&blk_cfg)?;
+        let disk_size = block_backend.lock().unwrap().disk_size()?;
+        if disk_size != self.disk_size() {
+            bail!(
+                "Size {} of image {} is different from the size {} of disk",
+                disk_size,
+                path,
+                self.disk_size()
+            );
+        }
+
+        let handlers = self.handlers.clone();
+        let mut locked_handlers: Vec<MutexGuard<BlockIoHandler>> =
+            handlers.iter().map(|h| h.lock().unwrap()).collect();
+        let activated = !self.senders.is_empty();
+        if let (true, Some(interrupt_cb)) = (activated, self.interrupt_cb.clone()) {
+            let err_cb = self.gen_error_cb(interrupt_cb);
+            block_backend
+                .lock()
+                .unwrap()
+                .register_io_event(self.broken.clone(), err_cb)?;
+        }
+        let mut bitmaps = Vec::new();
+        if let Some(old_backend) = self.block_backend.as_ref() {
+            let mut locked_backend = old_backend.lock().unwrap();
+            locked_backend.drain_request()?;
+            if activated {
+                locked_backend.unregister_io_event()?;
+            }
+            // The dirty bitmaps record the writes of disk rather than image.
+            bitmaps = locked_backend.named_dirty_bitmaps();
+            for bitmap in bitmaps.iter() {
+                locked_backend.remove_dirty_bitmap(bitmap);
+            }
+        }
+        let mut locked_backend = block_backend.lock().unwrap();
+        for bitmap in bitmaps.iter() {
+            locked_backend.add_dirty_bitmap(bitmap.clone())?;
+        }
+        drop(locked_backend);
+        let moved = store_persistent_bitmaps(&self.blk_cfg.path_on_host, &[], false)
+            .and_then(|_| store_persistent_bitmaps(path, &bitmaps, true));
+        if let Err(e) = moved {
+            error!(
+                "Failed to move the persistent dirty bitmaps to {}: {:?}",
+                path, e
+            );
+        }
+        for handler in locked_handlers.iter_mut() {
+            handler.block_backend = Some(block_backend.clone());
+        }
+        self.block_backend = Some(block_backend);
+        self.blk_cfg = blk_cfg;
+        Ok(())
+    }
+
+    /// Grow the disk to `size` bytes online. The capacity in config space is
+    /// updated, and the guest is notified by the config change interrupt.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if self.blk_cfg.read_only {
+            bail!("Block device {} is read only", self.blk_cfg.id);
+        }
+        if size & (SECTOR_SIZE - 1) != 0 {
+            bail!("Size {} is not aligned to sector size", size);
+        }
+        if self.zones.is_some() {
+            bail!("Zoned block device {} can't be resized", self.blk_cfg.id);
+        }
+        let block_backend = self
+            .block_backend
+            .clone()
+            .with_context(|| format!("No image is opened by block device {}", self.blk_cfg.id))?;
+        let handlers = self.handlers.clone();
+        let mut locked_handlers: Vec<MutexGuard<BlockIoHandler>> =
+            handlers.iter().map(|h| h.lock().unwrap()).collect();
+        let mut locked_backend = block_backend.lock().unwrap();
+        locked_backend.drain_request()?;
+        locked_backend.resize(size)?;
+        drop(locked_backend);
+
+        self.disk_sectors = size >> SECTOR_SHIFT;
+        self.state.config_space.capacity = self.disk_sectors;
+        for handler in locked_handlers.iter_mut() {
+            handler.disk_sectors = self.disk_sectors;
+        }
+        drop(locked_handlers);
+
+        if let Some(interrupt_cb) = self.interrupt_cb.as_ref() {
+            interrupt_cb(&VirtioInterruptType::Config, None, false).with_context(|| {
+                VirtioError::InterruptTrigger("block", VirtioInterruptType::Config)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Build the throttle group of device. The named group is shared with other
+    /// devices, its limits are not changed if the device does not configure any.
+    fn build_throttle_group(
+        &self,
+        throttle: ThrottleConfig,
+        group: Option<&String>,
+    ) -> Arc<Mutex<ThrottleGroup>> {
+        match group {
+            Some(name) => get_throttle_group(name, throttle.is_enabled().then_some(throttle)),
+            None => Arc::new(Mutex::new(ThrottleGroup::new(&self.blk_cfg.id, throttle))),
+        }
+    }
+
+    /// Apply the throttle group to the IO handlers of queues.
+    fn update_handlers_throttle(&self) {
+        for handler in self.handlers.iter() {
+            handler
+                .lock()
+                .unwrap()
+                .throttle
+                .set_group(self.throttle_group.clone());
+        }
+    }
+
+    /// Get the name of the throttle group used by device and its limits.
+    pub fn io_throttle(&self) -> (String, ThrottleConfig) {
+        let locked_group = self.throttle_group.lock().unwrap();
+        (locked_group.name().to_string(), locked_group.config())
+    }
+
+    /// Change the IO limits of device at runtime. The limits are applied to
+    /// all the devices in the throttle group, the device joins the group
+    /// `group` if it is specified. Throttling is disabled for the device alone
+    /// if all the limits are zero.
+    pub fn set_io_throttle(
+        &mut self,
+        throttle: ThrottleConfig,
+        group: Option<String>,
+    ) -> Result<()> {
+        throttle.check()?;
+        let group = match throttle.is_enabled() {
+            true => group.or_else(|| self.blk_cfg.throttle_group.clone()),
+            false => None,
+        };
+        self.throttle_group = match group.as_ref() {
+            Some(name) => get_throttle_group(name, Some(throttle)),
+            None => self.build_throttle_group(throttle, None),
+        };
+        self.blk_cfg.throttle = throttle;
+        self.blk_cfg.throttle_group = group;
+        self.update_handlers_throttle();
+        Ok(())
+    }
+
+    fn build_device_config_space(&mut self) {
+        // capacity: 64bits
+        let num_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
+        self.state.config_space.capacity = num_sectors;
+        // seg_max = queue_size - 2: 32bits
+        self.state.config_space.seg_max = self.queue_size() as u32 - 2;
+
+        if !self.blk_cfg.read_only {
+            // Only one segment is supported for discard and write zeroes command.
+            self.state.config_space.max_discard_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+            self.state.config_space.max_discard_seg = 1;
+            self.state.config_space.discard_sector_alignment = 1;
+            self.state.config_space.max_write_zeroes_sectors = MAX_DISCARD_WRITE_ZEROES_SECTORS;
+            self.state.config_space.max_write_zeroes_seg = 1;
+            self.state.config_space.write_zeroes_may_unmap = 1;
+        }
+    }
+
+    /// Get the length of config space, the fields of discard and write zeroes
+    /// exist only when the features are offered, and so do the zoned fields.
+    fn config_len(&self) -> u64 {
+        if virtio_has_feature(self.state.device_features, VIRTIO_BLK_F_ZONED) {
+            (size_of::<VirtioBlkConfig>() + size_of::<VirtioBlkZonedConfig>()) as u64
+        } else if virtio_has_feature(self.state.device_features, VIRTIO_BLK_F_DISCARD)
+            || virtio_has_feature(self.state.device_features, VIRTIO_BLK_F_WRITE_ZEROES)
+        {
+            size_of::<VirtioBlkConfig>() as u64
+        } else {
+            offset_of!(VirtioBlkConfig, max_discard_sectors) as u64
+        }
+    }
+}
+
+impl VirtioDevice for Block {
+    /// Realize virtio block device.
+    fn realize(&mut self) -> Result<()> {
+        // if iothread not found, return err
+        if self.blk_cfg.iothread.is_some()
+            && EventLoop::get_ctx(self.blk_cfg.iothread.as_ref()).is_none()
+        {
+            bail!(
+                "IOThread {:?} of Block is not configured in params.",
+                self.blk_cfg.iothread,
+            );
+        }
+
+        self.state.device_features = (1_u64 << VIRTIO_F_VERSION_1) | (1_u64 << VIRTIO_BLK_F_FLUSH);
+        if self.blk_cfg.read_only {
+            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_RO;
+        } else {
+            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_DISCARD;
+            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_WRITE_ZEROES;
+        };
+        self.state.device_features |= 1_u64 << VIRTIO_F_RING_INDIRECT_DESC;
+        self.state.device_features |= 1_u64 << VIRTIO_BLK_F_SEG_MAX;
+        self.state.device_features |= 1_u64 << VIRTIO_F_RING_EVENT_IDX;
+
+        self.build_device_config_space();
+
+        if self.blk_cfg.queues > 1 {
+            self.state.device_features |= 1_u64 << VIRTIO_BLK_F_MQ;
+            self.state.config_space.num_queues = self.blk_cfg.queues;
+        }
+
+        self.block_backend = None;
+        // The zones may be restored from the stored zone map below.
+        if let Some(zones) = self.zones.take() {
+            zones.lock().unwrap().store()?;
+        }
+        self.disk_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
+        if !self.blk_cfg.path_on_host.is_empty() {
+            let block_backend = self.build_block_backend(&self.blk_cfg)?;
+            let disk_size = block_backend.lock().unwrap().disk_size()?;
+            if let Some(zone_map) = self.build_zone_map(disk_size)? {
+                self.build_zoned_config(&zone_map);
+                self.zones = Some(Arc::new(Mutex::new(zone_map)));
+            }
+            if !self.blk_cfg.read_only {
+                restore_persistent_bitmaps(
+                    &mut *block_backend.lock().unwrap(),
+                    &self.blk_cfg.path_on_host,
+                )?;
+            }
+
+            self.block_backend = Some(block_backend);
+            self.disk_sectors = disk_size >> SECTOR_SHIFT;
+        }
+        self.state.config_space.capacity = self.disk_sectors;
+        self.throttle_group =
+            self.build_throttle_group(self.blk_cfg.throttle, self.blk_cfg.throttle_group.as_ref());
+
+        Ok(())
+    }
+
+    fn unrealize(&mut self) -> Result<()> {
+        MigrationManager::unregister_device_instance(BlockState::descriptor(), &self.blk_cfg.id);
+        if let Some(zones) = self.zones.as_ref() {
+            zones.lock().unwrap().store()?;
+        }
+        self.store_dirty_bitmaps()
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_BLOCK
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
         self.blk_cfg.queues as usize
     }
 
@@ -1053,8 +1935,7 @@ impl VirtioDevice for Block {
 
     /// Read data of config from guest.
     fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
-        // F_DISCARD is not supported for now, so related config does not exist.
-        let config_len = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let config_len = self.config_len();
         let read_end = offset as usize + data.len();
         if offset
             .checked_add(data.len() as u64)
@@ -1064,7 +1945,8 @@ impl VirtioDevice for Block {
             return Err(anyhow!(VirtioError::DevConfigOverflow(offset, config_len)));
         }
 
-        let config_slice = self.state.config_space.as_bytes();
+        let mut config_slice = self.state.config_space.as_bytes().to_vec();
+        config_slice.extend_from_slice(self.zoned_config.as_bytes());
         data.write_all(&config_slice[(offset as usize)..read_end])?;
 
         Ok(())
@@ -1072,8 +1954,7 @@ impl VirtioDevice for Block {
 
     /// Write data to config from guest.
     fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
-        // F_DISCARD is not supported for now, so related config does not exist.
-        let config_len = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let config_len = self.config_len();
         if offset
             .checked_add(data.len() as u64)
             .filter(|&end| end <= config_len)
@@ -1104,41 +1985,45 @@ impl VirtioDevice for Block {
             }
             let (sender, receiver) = channel();
             let update_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
-            let aio = Box::new(Aio::new(
-                Arc::new(BlockIoHandler::complete_func),
-                self.blk_cfg.aio,
-            )?);
+            let retry_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
             let handler = BlockIoHandler {
                 queue: queue.clone(),
                 queue_evt,
                 mem_space: mem_space.clone(),
-                disk_image: self.disk_image.clone(),
-                req_align: self.req_align,
-                buf_align: self.buf_align,
+                block_backend: self.block_backend.clone(),
                 disk_sectors: self.disk_sectors,
-                direct: self.blk_cfg.direct,
                 serial_num: self.blk_cfg.serial_num.clone(),
-                aio,
                 driver_features: self.state.driver_features,
                 receiver,
                 update_evt: update_evt.clone(),
                 device_broken: self.broken.clone(),
                 interrupt_cb: interrupt_cb.clone(),
                 iothread: self.blk_cfg.iothread.clone(),
-                leak_bucket: match self.blk_cfg.iops {
-                    Some(iops) => Some(LeakBucket::new(iops)?),
-                    None => None,
-                },
+                throttle: Throttle::new(self.throttle_group.clone())?,
+                stats: self.stats.clone(),
+                err_policy: Arc::new(BlockErrorPolicy::new(&self.blk_cfg)),
+                retry_evt: retry_evt.clone(),
+                zones: self.zones.clone(),
             };
 
-            let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+            let handler = Arc::new(Mutex::new(handler));
+            let notifiers = EventNotifierHelper::internal_notifiers(handler.clone());
             register_event_helper(
                 notifiers,
                 self.blk_cfg.iothread.as_ref(),
                 &mut self.deactivate_evts,
             )?;
             self.update_evts.push(update_evt);
+            self.retry_evts.push(retry_evt);
             self.senders.push(sender);
+            self.handlers.push(handler);
+        }
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            let err_cb = self.gen_error_cb(interrupt_cb);
+            block_backend
+                .lock()
+                .unwrap()
+                .register_io_event(self.broken.clone(), err_cb)?;
         }
         self.broken.store(false, Ordering::SeqCst);
 
@@ -1147,8 +2032,13 @@ impl VirtioDevice for Block {
 
     fn deactivate(&mut self) -> Result<()> {
         unregister_event_helper(self.blk_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
+        if let Some(block_backend) = self.block_backend.as_ref() {
+            block_backend.lock().unwrap().unregister_io_event()?;
+        }
         self.update_evts.clear();
+        self.retry_evts.clear();
         self.senders.clear();
+        self.handlers.clear();
         Ok(())
     }
 
@@ -1165,18 +2055,35 @@ impl VirtioDevice for Block {
             self.blk_cfg = Default::default();
         }
 
+        let activated = !self.senders.is_empty();
+        if activated {
+            if let Some(block_backend) = self.block_backend.as_ref() {
+                block_backend.lock().unwrap().unregister_io_event()?;
+            }
+        }
+
         self.realize()?;
+        self.update_handlers_throttle();
+
+        if activated {
+            if let (Some(block_backend), Some(interrupt_cb)) =
+                (self.block_backend.as_ref(), self.interrupt_cb.clone())
+            {
+                let err_cb = self.gen_error_cb(interrupt_cb);
+                block_backend
+                    .lock()
+                    .unwrap()
+                    .register_io_event(self.broken.clone(), err_cb)?;
+            }
+        }
 
         for sender in &self.senders {
             sender
                 .send((
-                    self.disk_image.clone(),
-                    self.req_align,
-                    self.buf_align,
+                    self.block_backend.clone(),
                     self.disk_sectors,
                     self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
-                    self.blk_cfg.aio,
+                    self.zones.clone(),
                 ))
                 .with_context(|| anyhow!(VirtioError::ChannelSend("image fd".to_string())))?;
         }
@@ -1197,6 +2104,13 @@ unsafe impl Sync for Block {}
 
 impl StateTransfer for Block {
     fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        // The requests kept by the stop error action are not part of the state.
+        if self.has_failed_requests() {
+            bail!(
+                "Block device {} has failed requests to retry, migration is not allowed",
+                self.blk_cfg.id
+            );
+        }
         let mut state = self.state;
         state.broken = self.broken.load(Ordering::SeqCst);
         Ok(state.as_bytes().to_vec())
@@ -1228,9 +2142,13 @@ mod tests {
     use super::super::*;
     use super::*;
     use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+    use block_backend::qcow2::{create_qcow2_image, CreateOptions};
     use machine_manager::config::{IothreadConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE};
+    use machine_manager::machine::register_vm_stop_req;
+    use std::io::Read;
     use std::sync::atomic::{AtomicU32, Ordering};
     use std::{thread, time::Duration};
+    use util::aio::AioEngine;
     use vmm_sys_util::tempfile::TempFile;
 
     const QUEUE_NUM_BLK: usize = 1;
@@ -1243,17 +2161,24 @@ mod tests {
         fn default() -> Self {
             Block {
                 blk_cfg: Default::default(),
-                disk_image: None,
-                req_align: 1,
-                buf_align: 1,
+                block_backend: None,
                 disk_sectors: 0,
                 state: BlockState::default(),
                 interrupt_cb: None,
                 senders: Vec::new(),
                 update_evts: Vec::new(),
+                retry_evts: Vec::new(),
+                handlers: Vec::new(),
                 deactivate_evts: Vec::new(),
                 broken: Arc::new(AtomicBool::new(false)),
                 drive_files: Arc::new(Mutex::new(HashMap::new())),
+                throttle_group: Arc::new(Mutex::new(ThrottleGroup::new(
+                    "",
+                    ThrottleConfig::default(),
+                ))),
+                stats: Arc::new(BlockAcctStats::default()),
+                zones: None,
+                zoned_config: VirtioBlkZonedConfig::default(),
             }
         }
     }
@@ -1284,6 +2209,55 @@ mod tests {
         sys_space
     }
 
+    // build the virtqueue of block device in the dummy address space
+    fn queue_config_init(mem_space: &Arc<AddressSpace>) -> QueueConfig {
+        let mut queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.addr_cache.desc_table_host =
+            mem_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.avail_ring = GuestAddress(16 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.avail_ring_host =
+            mem_space.get_host_address(queue_config.avail_ring).unwrap();
+        queue_config.used_ring = GuestAddress(32 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.used_ring_host =
+            mem_space.get_host_address(queue_config.used_ring).unwrap();
+        queue_config.size = DEFAULT_VIRTQUEUE_SIZE;
+        queue_config.ready = true;
+        queue_config
+    }
+
+    // make the descriptor chain of (addr, len, flags) and put it to the avail ring
+    // as the first request
+    fn add_request(
+        mem_space: &Arc<AddressSpace>,
+        queue_config: &QueueConfig,
+        descs: &[(u64, u32, u16)],
+    ) {
+        for (i, (addr, len, flags)) in descs.iter().enumerate() {
+            let desc = SplitVringDesc {
+                addr: GuestAddress(*addr),
+                len: *len,
+                flags: *flags,
+                next: i as u16 + 1,
+            };
+            mem_space
+                .write_object::<SplitVringDesc>(
+                    &desc,
+                    GuestAddress(queue_config.desc_table.0 + 16 * i as u64),
+                )
+                .unwrap();
+        }
+
+        // write avail_ring id
+        mem_space
+            .write_object::<u16>(&0, GuestAddress(queue_config.avail_ring.0 + 4))
+            .unwrap();
+        // write avail_ring idx
+        mem_space
+            .write_object::<u16>(&1, GuestAddress(queue_config.avail_ring.0 + 2))
+            .unwrap();
+    }
+
     // Use different input parameters to verify block `new()` and `realize()` functionality.
     #[test]
     fn test_block_init() {
@@ -1293,7 +2267,7 @@ mod tests {
         assert_eq!(block.state.device_features, 0);
         assert_eq!(block.state.driver_features, 0);
         assert_eq!(block.state.config_space.as_bytes().len(), CONFIG_SPACE_SIZE);
-        assert!(block.disk_image.is_none());
+        assert!(block.block_backend.is_none());
         assert!(block.interrupt_cb.is_none());
         assert!(block.senders.is_empty());
 
@@ -1310,6 +2284,15 @@ mod tests {
         )
         .unwrap();
         assert!(block.realize().is_ok());
+        // Read-only device does not offer discard and write zeroes.
+        assert!(!virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_DISCARD
+        ));
+        assert!(!virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_WRITE_ZEROES
+        ));
 
         assert_eq!(block.device_type(), VIRTIO_TYPE_BLOCK);
         assert_eq!(block.queue_num(), QUEUE_NUM_BLK);
@@ -1428,6 +2411,7 @@ mod tests {
         // spawn io thread
         let io_conf = IothreadConfig {
             id: thread_name.clone(),
+            ..Default::default()
         };
         EventLoop::object_init(&Some(vec![io_conf])).unwrap();
 
@@ -1438,7 +2422,7 @@ mod tests {
 
         // config iothread and iops
         block.blk_cfg.iothread = Some(thread_name);
-        block.blk_cfg.iops = Some(100);
+        block.blk_cfg.throttle.iops_total.avg = 100;
 
         VmConfig::add_drive_file(
             &mut block.drive_files.lock().unwrap(),
@@ -1466,19 +2450,7 @@ mod tests {
             },
         ) as VirtioInterrupt);
 
-        let mut queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
-        queue_config.desc_table = GuestAddress(0);
-        queue_config.addr_cache.desc_table_host =
-            mem_space.get_host_address(queue_config.desc_table).unwrap();
-        queue_config.avail_ring = GuestAddress(16 * DEFAULT_VIRTQUEUE_SIZE as u64);
-        queue_config.addr_cache.avail_ring_host =
-            mem_space.get_host_address(queue_config.avail_ring).unwrap();
-        queue_config.used_ring = GuestAddress(32 * DEFAULT_VIRTQUEUE_SIZE as u64);
-        queue_config.addr_cache.used_ring_host =
-            mem_space.get_host_address(queue_config.used_ring).unwrap();
-        queue_config.size = DEFAULT_VIRTQUEUE_SIZE;
-        queue_config.ready = true;
-
+        let queue_config = queue_config_init(&mem_space);
         let queues: Vec<Arc<Mutex<Queue>>> =
             vec![Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap()))];
         let event = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
@@ -1493,17 +2465,6 @@ mod tests {
             )
             .unwrap();
 
-        // make first descriptor entry
-        let desc = SplitVringDesc {
-            addr: GuestAddress(0x100),
-            len: 16,
-            flags: VIRTQ_DESC_F_NEXT,
-            next: 1,
-        };
-        mem_space
-            .write_object::<SplitVringDesc>(&desc, GuestAddress(queue_config.desc_table.0))
-            .unwrap();
-
         // write RequestOutHeader to first desc
         let req_head = RequestOutHeader {
             request_type: 0, // read
@@ -1514,29 +2475,15 @@ mod tests {
             .write_object::<RequestOutHeader>(&req_head, GuestAddress(0x100))
             .unwrap();
 
-        // making the second descriptor entry to receive data from device
-        let desc = SplitVringDesc {
-            addr: GuestAddress(0x200),
-            len: 16,
-            flags: VIRTQ_DESC_F_WRITE,
-            next: 2,
-        };
-        mem_space
-            .write_object::<SplitVringDesc>(
-                &desc,
-                GuestAddress(queue_config.desc_table.0 + 16 as u64),
-            )
-            .unwrap();
-
-        // write avail_ring idx
-        mem_space
-            .write_object::<u16>(&0, GuestAddress(queue_config.avail_ring.0 + 4 as u64))
-            .unwrap();
-
-        // write avail_ring id
-        mem_space
-            .write_object::<u16>(&1, GuestAddress(queue_config.avail_ring.0 + 2 as u64))
-            .unwrap();
+        // the second descriptor entry is to receive data from device
+        add_request(
+            &mem_space,
+            &queue_config,
+            &[
+                (0x100, 16, VIRTQ_DESC_F_NEXT),
+                (0x200, 16, VIRTQ_DESC_F_WRITE),
+            ],
+        );
 
         // imitating guest OS to send notification.
         event.write(1).unwrap();
@@ -1559,5 +2506,347 @@ mod tests {
                 break;
             }
         }
+
+        // The read request whose size is not aligned to sector is failed.
+        let stats = block.io_stats();
+        assert_eq!(stats.failed_rd_operations, 1);
+        assert_eq!(stats.rd_operations, 0);
+        assert!(stats.idle_time_ns.is_some());
+    }
+
+    // Test `set_io_throttle`: the devices in the same group share the limits, and
+    // the device leaves the group when all the limits are zero.
+    #[test]
+    fn test_block_set_io_throttle() {
+        let mut block = Block::default();
+        block.blk_cfg.id = "blk0".to_string();
+        let mut block1 = Block::default();
+        block1.blk_cfg.id = "blk1".to_string();
+
+        let mut throttle = ThrottleConfig::default();
+        throttle.bps_total.avg = 1 << 20;
+        throttle.iops_read.avg = 100;
+        block
+            .set_io_throttle(throttle, Some("group0".to_string()))
+            .unwrap();
+        assert_eq!(block.io_throttle(), ("group0".to_string(), throttle));
+        assert_eq!(block.blk_cfg.throttle_group, Some("group0".to_string()));
+
+        // Limits of the group are changed for all the devices in it.
+        throttle.iops_read.avg = 200;
+        block1
+            .set_io_throttle(throttle, Some("group0".to_string()))
+            .unwrap();
+        assert!(Arc::ptr_eq(&block.throttle_group, &block1.throttle_group));
+        assert_eq!(block.io_throttle().1, throttle);
+
+        // Invalid limits are refused.
+        let mut invalid = throttle;
+        invalid.iops_total.avg = 100;
+        assert!(block.set_io_throttle(invalid, None).is_err());
+        assert_eq!(block.io_throttle().1, throttle);
+
+        block
+            .set_io_throttle(ThrottleConfig::default(), None)
+            .unwrap();
+        assert_eq!(
+            block.io_throttle(),
+            ("blk0".to_string(), ThrottleConfig::default())
+        );
+        assert_eq!(block.blk_cfg.throttle_group, None);
+        assert_eq!(block1.io_throttle().1, throttle);
+    }
+
+    // Test `switch_image`: the disk switches to the overlay of the same size, and the
+    // image of different size is refused.
+    #[test]
+    fn test_block_switch_image() {
+        let mut block = Block::default();
+        block.blk_cfg.direct = false;
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(1 << 20).unwrap();
+        block.blk_cfg.path_on_host = base.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            false,
+            false,
+        )
+        .unwrap();
+        block.realize().unwrap();
+        assert_eq!(block.disk_size(), 1 << 20);
+
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+        let mut opts = CreateOptions::new(&overlay_path, 1 << 20);
+        opts.backing_file = Some(block.blk_cfg.path_on_host.clone());
+        opts.backing_fmt = Some(DiskFormat::Raw);
+        create_qcow2_image(&opts).unwrap();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &overlay_path,
+            false,
+            false,
+        )
+        .unwrap();
+        block
+            .switch_image(&overlay_path, DiskFormat::Qcow2)
+            .unwrap();
+        assert_eq!(block.blk_config().path_on_host, overlay_path);
+        assert_eq!(block.blk_config().format, DiskFormat::Qcow2);
+        assert_eq!(block.disk_size(), 1 << 20);
+
+        let other = TempFile::new().unwrap();
+        other.as_file().set_len(2 << 20).unwrap();
+        let other_path = other.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &other_path,
+            false,
+            false,
+        )
+        .unwrap();
+        assert!(block.switch_image(&other_path, DiskFormat::Raw).is_err());
+        assert_eq!(block.blk_config().path_on_host, overlay_path);
+    }
+
+    // Test `resize`: the disk grows with the capacity in config space updated and
+    // the config change interrupt triggered, and shrinking is refused.
+    #[test]
+    fn test_block_resize() {
+        let mut block = Block::default();
+        block.blk_cfg.direct = false;
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(1 << 20).unwrap();
+        block.blk_cfg.path_on_host = image.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            false,
+            false,
+        )
+        .unwrap();
+        block.realize().unwrap();
+        let config_interrupts = Arc::new(AtomicU32::new(0));
+        let cloned_interrupts = config_interrupts.clone();
+        block.interrupt_cb = Some(Arc::new(Box::new(
+            move |int_type: &VirtioInterruptType, _queue: Option<&Queue>, _needs_reset: bool| {
+                if let VirtioInterruptType::Config = int_type {
+                    cloned_interrupts.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(())
+            },
+        ) as VirtioInterrupt));
+
+        assert!(block.resize((2 << 20) + 1).is_err());
+        block.resize(2 << 20).unwrap();
+        assert_eq!(block.disk_size(), 2 << 20);
+        let capacity = block.state.config_space.capacity;
+        assert_eq!(capacity, (2 << 20) >> SECTOR_SHIFT);
+        assert_eq!(image.as_file().metadata().unwrap().len(), 2 << 20);
+        assert_eq!(config_interrupts.load(Ordering::SeqCst), 1);
+
+        assert!(block.resize(1 << 20).is_err());
+        assert_eq!(block.disk_size(), 2 << 20);
+        assert_eq!(config_interrupts.load(Ordering::SeqCst), 1);
+    }
+    // Test the zoned block device emulated over raw image: the zoned feature is offered
+    // instead of discard and write zeroes, and the zoned fields follow the config space.
+    #[test]
+    fn test_block_zoned() {
+        let mut block = Block::default();
+        block.blk_cfg.direct = false;
+        block.blk_cfg.zone_size = Some(1 << 16);
+        block.blk_cfg.max_open_zones = 4;
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(1 << 20).unwrap();
+        block.blk_cfg.path_on_host = image.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            false,
+            false,
+        )
+        .unwrap();
+        block.realize().unwrap();
+
+        assert!(virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_ZONED
+        ));
+        assert!(!virtio_has_feature(
+            block.state.device_features,
+            VIRTIO_BLK_F_DISCARD
+        ));
+        assert_eq!(block.config_len(), 96);
+        let mut zoned_config = [0_u8; 24];
+        block.read_config(72, &mut zoned_config).unwrap();
+        assert_eq!(LittleEndian::read_u32(&zoned_config[0..4]), 128);
+        assert_eq!(LittleEndian::read_u32(&zoned_config[4..8]), 4);
+        assert_eq!(LittleEndian::read_u32(&zoned_config[12..16]), 128);
+        assert_eq!(zoned_config[20], 1);
+        assert!(block.resize(2 << 20).is_err());
+
+        let zones_file = block_backend::zoned::zones_file_path(&block.blk_cfg.path_on_host);
+        block
+            .zones
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .store()
+            .unwrap();
+        assert!(std::path::Path::new(&zones_file).exists());
+        std::fs::remove_file(zones_file).unwrap();
+    }
+
+    // Activate the block device with one queue, and put a request to write one
+    // sector at sector 0 to the queue.
+    fn error_policy_request(block: &mut Block, mem_space: &Arc<AddressSpace>) -> QueueConfig {
+        let queue_config = queue_config_init(mem_space);
+        let queues = vec![Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap()))];
+        let interrupt_cb = Arc::new(Box::new(
+            |_int_type: &VirtioInterruptType, _queue: Option<&Queue>, _needs_reset: bool| Ok(()),
+        ) as VirtioInterrupt);
+        let event = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        block
+            .activate(mem_space.clone(), interrupt_cb, &queues, vec![event])
+            .unwrap();
+
+        let req_head = RequestOutHeader {
+            request_type: VIRTIO_BLK_T_OUT,
+            io_prio: 0,
+            sector: 0,
+        };
+        mem_space
+            .write_object::<RequestOutHeader>(&req_head, GuestAddress(0x20000))
+            .unwrap();
+        mem_space
+            .write(
+                &mut [0xa5_u8; SECTOR_SIZE as usize].as_ref(),
+                GuestAddress(0x21000),
+                SECTOR_SIZE,
+            )
+            .unwrap();
+        mem_space
+            .write_object::<u8>(&0xff, GuestAddress(0x22000))
+            .unwrap();
+        add_request(
+            mem_space,
+            &queue_config,
+            &[
+                (
+                    0x20000,
+                    size_of::<RequestOutHeader>() as u32,
+                    VIRTQ_DESC_F_NEXT,
+                ),
+                (0x21000, SECTOR_SIZE as u32, VIRTQ_DESC_F_NEXT),
+                (0x22000, 1, VIRTQ_DESC_F_WRITE),
+            ],
+        );
+        queue_config
+    }
+
+    // Get the used idx of the queue and the status of the request.
+    fn error_policy_result(mem_space: &Arc<AddressSpace>, queue_config: &QueueConfig) -> (u16, u8) {
+        let used_idx = mem_space
+            .read_object::<u16>(GuestAddress(queue_config.used_ring.0 + 2))
+            .unwrap();
+        let status = mem_space.read_object::<u8>(GuestAddress(0x22000)).unwrap();
+        (used_idx, status)
+    }
+
+    // Create an image of 1M whose drive file is opened read only, so that the
+    // writes to it fail.
+    fn error_policy_block(werror: BlockErrorAction) -> (Block, TempFile) {
+        let mut block = Block::default();
+        block.blk_cfg.id = "blk0".to_string();
+        block.blk_cfg.direct = false;
+        block.blk_cfg.aio = AioEngine::Off;
+        block.blk_cfg.werror = werror;
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(1 << 20).unwrap();
+        block.blk_cfg.path_on_host = image.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            true,
+            false,
+        )
+        .unwrap();
+        block.realize().unwrap();
+        (block, image)
+    }
+
+    // Test the error policy of write: the failed request is completed with OK for
+    // `ignore` and with IOERR for `report`. For `stop` it is kept with the VM stop
+    // requested and the migration refused, and resubmitted when the VM resumes.
+    #[test]
+    fn test_block_error_policy() {
+        // The event loop is checked for the IO throttling, share it with `test_iothread`.
+        let io_conf = IothreadConfig {
+            id: "io1".to_string(),
+            ..Default::default()
+        };
+        EventLoop::object_init(&Some(vec![io_conf])).unwrap();
+        QmpChannel::object_init();
+        let stop_req = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        register_vm_stop_req(stop_req.clone());
+
+        for (werror, status) in [
+            (BlockErrorAction::Ignore, VIRTIO_BLK_S_OK),
+            (BlockErrorAction::Report, VIRTIO_BLK_S_IOERR),
+        ] {
+            let mem_space = address_space_init();
+            let (mut block, _image) = error_policy_block(werror);
+            let queue_config = error_policy_request(&mut block, &mem_space);
+            block.handlers[0].lock().unwrap().process_queue().unwrap();
+            assert_eq!(error_policy_result(&mem_space, &queue_config), (1, status));
+            assert!(!block.has_failed_requests());
+            assert!(stop_req.read().is_err());
+        }
+
+        let mem_space = address_space_init();
+        let (mut block, _image) = error_policy_block(BlockErrorAction::Stop);
+        let queue_config = error_policy_request(&mut block, &mem_space);
+        let handler = block.handlers[0].clone();
+        handler.lock().unwrap().process_queue().unwrap();
+        assert_eq!(stop_req.read().unwrap(), 1);
+        assert_eq!(error_policy_result(&mem_space, &queue_config), (0, 0xff));
+        assert!(block.has_failed_requests());
+        assert!(block.get_state_vec().is_err());
+
+        // The request fails again if the error is not fixed when VM resumes.
+        handler.lock().unwrap().retry_failed_requests().unwrap();
+        assert_eq!(stop_req.read().unwrap(), 1);
+        assert_eq!(error_policy_result(&mem_space, &queue_config), (0, 0xff));
+
+        // The request succeeds on the writable image after VM resumes.
+        let writable = TempFile::new().unwrap();
+        writable.as_file().set_len(1 << 20).unwrap();
+        let mut writable_block = Block::default();
+        writable_block.blk_cfg.direct = false;
+        writable_block.blk_cfg.aio = AioEngine::Off;
+        writable_block.blk_cfg.path_on_host = writable.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut writable_block.drive_files.lock().unwrap(),
+            &writable_block.blk_cfg.path_on_host,
+            false,
+            false,
+        )
+        .unwrap();
+        writable_block.realize().unwrap();
+        handler.lock().unwrap().block_backend = writable_block.block_backend.clone();
+        handler.lock().unwrap().retry_failed_requests().unwrap();
+        assert!(stop_req.read().is_err());
+        assert_eq!(
+            error_policy_result(&mem_space, &queue_config),
+            (1, VIRTIO_BLK_S_OK)
+        );
+        assert!(!block.has_failed_requests());
+        assert!(block.get_state_vec().is_ok());
+        let mut data = [0_u8; SECTOR_SIZE as usize];
+        writable.as_file().read_exact(&mut data).unwrap();
+        assert_eq!(data, [0xa5_u8; SECTOR_SIZE as usize]);
     }
 }
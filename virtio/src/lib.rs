@@ -40,7 +40,7 @@ mod virtio_pci;
 mod virtqueue;
 pub use anyhow::Result;
 pub use balloon::*;
-pub use block::{Block, BlockState};
+pub use block::{Block, BlockMap, BlockState};
 pub use console::{Console, VirtioConsoleState};
 pub use error::VirtioError;
 pub use error::*;
@@ -52,6 +52,8 @@ pub use rng::{Rng, RngState};
 pub use scsi::bus as ScsiBus;
 pub use scsi::controller as ScsiCntlr;
 pub use scsi::disk as ScsiDisk;
+pub use scsi::generic as ScsiGeneric;
+pub use scsi::reservation as ScsiReservation;
 pub use vhost::kernel as VhostKern;
 pub use vhost::user as VhostUser;
 pub use virtio_mmio::{VirtioMmioDevice, VirtioMmioState};
@@ -130,6 +132,8 @@ pub const VIRTIO_NET_F_HOST_TSO6: u32 = 12;
 pub const VIRTIO_NET_F_HOST_UFO: u32 = 14;
 /// Device can merge receive buffers.
 pub const VIRTIO_NET_F_MRG_RXBUF: u32 = 15;
+/// Configuration status field is available.
+pub const VIRTIO_NET_F_STATUS: u32 = 16;
 /// Control channel is available.
 pub const VIRTIO_NET_F_CTRL_VQ: u32 = 17;
 /// Control channel RX mode support.
@@ -138,6 +142,8 @@ pub const VIRTIO_NET_F_CTRL_RX: u32 = 18;
 pub const VIRTIO_NET_F_CTRL_VLAN: u32 = 19;
 /// Extra RX mode control support.
 pub const VIRTIO_NET_F_CTRL_RX_EXTRA: u32 = 20;
+/// Driver can send gratuitous packets.
+pub const VIRTIO_NET_F_GUEST_ANNOUNCE: u32 = 21;
 /// Device supports multi queue with automatic receive steering.
 pub const VIRTIO_NET_F_MQ: u32 = 22;
 /// Set Mac Address through control channel.
@@ -162,9 +168,16 @@ pub const VIRTIO_BLK_F_TOPOLOGY: u32 = 10;
 pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
 /// WRITE ZEROES is supported.
 pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+/// Zoned block device is supported.
+pub const VIRTIO_BLK_F_ZONED: u32 = 17;
 /// GPU EDID feature is supported.
 pub const VIRTIO_GPU_F_EDID: u32 = 1;
 
+/// Link is up.
+pub const VIRTIO_NET_S_LINK_UP: u16 = 1;
+/// Driver is asked to send gratuitous packets.
+pub const VIRTIO_NET_S_ANNOUNCE: u16 = 2;
+
 /// The device sets control ok status to driver.
 pub const VIRTIO_NET_OK: u8 = 0;
 /// The device sets control err status to driver.
@@ -199,6 +212,11 @@ pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
 /// The driver adds a vlan id from the vlan filtering table.
 pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
 
+/// The driver can send control commands for announcement.
+pub const VIRTIO_NET_CTRL_ANNOUNCE: u8 = 3;
+/// The driver acknowledges the announcement request.
+pub const VIRTIO_NET_CTRL_ANNOUNCE_ACK: u8 = 0;
+
 /// Driver configure the class before enabling virtqueue.
 pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
 /// Driver configure the command before enabling virtqueue.
@@ -230,6 +248,26 @@ pub const VIRTIO_BLK_T_OUT: u32 = 1;
 pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 /// Device id
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+/// Discard command.
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
+/// Write zeroes command.
+pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+/// Zone append command.
+pub const VIRTIO_BLK_T_ZONE_APPEND: u32 = 15;
+/// Zone report command.
+pub const VIRTIO_BLK_T_ZONE_REPORT: u32 = 16;
+/// Zone open command.
+pub const VIRTIO_BLK_T_ZONE_OPEN: u32 = 18;
+/// Zone close command.
+pub const VIRTIO_BLK_T_ZONE_CLOSE: u32 = 20;
+/// Zone finish command.
+pub const VIRTIO_BLK_T_ZONE_FINISH: u32 = 22;
+/// Zone reset command.
+pub const VIRTIO_BLK_T_ZONE_RESET: u32 = 24;
+/// Zone reset all command.
+pub const VIRTIO_BLK_T_ZONE_RESET_ALL: u32 = 26;
+/// Unmap flag for write zeroes command.
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 1;
 /// Device id length
 pub const VIRTIO_BLK_ID_BYTES: u32 = 20;
 /// Success
@@ -238,6 +276,14 @@ pub const VIRTIO_BLK_S_OK: u8 = 0;
 pub const VIRTIO_BLK_S_IOERR: u8 = 1;
 /// Unsupport.
 pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// Invalid zone command, or zone in invalid condition.
+pub const VIRTIO_BLK_S_ZONE_INVALID_CMD: u8 = 3;
+/// Write not at the write pointer of zone.
+pub const VIRTIO_BLK_S_ZONE_UNALIGNED_WP: u8 = 4;
+/// Max open zones limit is exceeded.
+pub const VIRTIO_BLK_S_ZONE_OPEN_RESOURCE: u8 = 5;
+/// Max active zones limit is exceeded.
+pub const VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE: u8 = 6;
 
 /// The Type of virtio gpu, refer to Virtio Spec.
 /// 2D commands:
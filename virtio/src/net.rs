@@ -23,7 +23,8 @@ use std::{cmp, fs, mem};
 
 use super::{
     Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioNetHdr, VirtioTrace,
-    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MAC,
+    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
+    VIRTIO_NET_CTRL_ANNOUNCE, VIRTIO_NET_CTRL_ANNOUNCE_ACK, VIRTIO_NET_CTRL_MAC,
     VIRTIO_NET_CTRL_MAC_ADDR_SET, VIRTIO_NET_CTRL_MAC_TABLE_SET, VIRTIO_NET_CTRL_MQ,
     VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
     VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI,
@@ -31,10 +32,12 @@ use super::{
     VIRTIO_NET_CTRL_RX_NOUNI, VIRTIO_NET_CTRL_RX_PROMISC, VIRTIO_NET_CTRL_VLAN,
     VIRTIO_NET_CTRL_VLAN_ADD, VIRTIO_NET_CTRL_VLAN_DEL, VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM,
     VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX, VIRTIO_NET_F_CTRL_RX_EXTRA,
-    VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO,
-    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
-    VIRTIO_NET_F_MQ, VIRTIO_NET_OK, VIRTIO_TYPE_NET,
+    VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
+    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_STATUS, VIRTIO_NET_OK, VIRTIO_NET_S_ANNOUNCE,
+    VIRTIO_NET_S_LINK_UP, VIRTIO_TYPE_NET,
 };
 use crate::{
     iov_discard_front, iov_to_buf, mem_to_buf, report_virtio_error, virtio_has_feature, ElemIovec,
@@ -42,10 +45,11 @@ use crate::{
 };
 use address_space::{AddressSpace, RegionCache};
 use anyhow::{anyhow, bail, Context, Result};
+use block_backend::throttle::{Throttle, ThrottleGroup};
 use log::{error, warn};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use machine_manager::{
-    config::{ConfigCheck, NetworkInterfaceConfig},
+    config::{ConfigCheck, FilterDumpConfig, NetRateLimit, NetworkInterfaceConfig},
     event_loop::EventLoop,
 };
 use migration::{
@@ -59,9 +63,12 @@ use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
 use util::num_ops::{read_u32, str_to_usize};
+use util::pcap::PcapWriter;
+use util::socknet::SocketNet;
 use util::tap::{
     Tap, IFF_MULTI_QUEUE, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_TSO_ECN, TUN_F_UFO,
 };
+use util::usernet::UserNet;
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 /// Number of virtqueues(rx/tx/ctrl).
 const QUEUE_NUM_NET: usize = 3;
@@ -396,6 +403,18 @@ impl CtrlInfo {
         ack
     }
 
+    fn handle_announce(&mut self, cmd: u8) -> u8 {
+        let mut locked_state = self.state.lock().unwrap();
+        if cmd != VIRTIO_NET_CTRL_ANNOUNCE_ACK
+            || !virtio_has_feature(locked_state.driver_features, VIRTIO_NET_F_GUEST_ANNOUNCE)
+        {
+            error!("Invalid cmd {} when handling control announce", cmd);
+            return VIRTIO_NET_ERR;
+        }
+        locked_state.config_space.status &= !VIRTIO_NET_S_ANNOUNCE;
+        VIRTIO_NET_OK
+    }
+
     fn filter_packets(&mut self, buf: &[u8]) -> bool {
         // Broadcast address: 0xff:0xff:0xff:0xff:0xff:0xff.
         let bcast = [0xff; MAC_ADDR_LEN];
@@ -585,6 +604,14 @@ impl NetCtrlHandler {
                         &mut data_iovec,
                     );
                 }
+                VIRTIO_NET_CTRL_ANNOUNCE => {
+                    ack = self
+                        .ctrl
+                        .ctrl_info
+                        .lock()
+                        .unwrap()
+                        .handle_announce(ctrl_hdr.cmd);
+                }
                 VIRTIO_NET_CTRL_MQ => {
                     ack = self.ctrl.ctrl_info.lock().unwrap().handle_mq(
                         &self.mem_space,
@@ -705,6 +732,16 @@ struct NetIoHandler {
     is_listening: bool,
     ctrl_info: Arc<Mutex<CtrlInfo>>,
     queue_size: u16,
+    dumps: Arc<Mutex<Vec<NetDump>>>,
+    iothread: Option<String>,
+    /// Rate limit of the packets received by the guest.
+    rx_throttle: Throttle,
+    /// Rate limit of the packets sent by the guest.
+    tx_throttle: Throttle,
+    /// The packets are dropped while the link is set down.
+    link_up: Arc<AtomicBool>,
+    /// Length of the virtio net header negotiated with the guest.
+    hdr_len: usize,
 }
 
 impl NetIoHandler {
@@ -769,6 +806,11 @@ impl NetIoHandler {
 
         let mut rx_packets = 0;
         while let Some(tap) = self.tap.as_mut() {
+            if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                if self.rx_throttle.wait(ctx, false) {
+                    break;
+                }
+            }
             let elem = queue
                 .vring
                 .pop_avail(&self.mem_space, self.driver_features)
@@ -795,12 +837,19 @@ impl NetIoHandler {
 
             // Read the data from the tap device.
             let size = NetIoHandler::read_from_tap(&iovecs, tap);
-            if size < (NET_HDR_LENGTH + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH) as i32 {
+            if size < (self.hdr_len + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH) as i32 {
                 queue.vring.push_back();
                 break;
             }
+            if !self.link_up.load(Ordering::SeqCst) {
+                queue.vring.push_back();
+                continue;
+            }
+            dump_packet(&self.dumps, &iovecs, size as usize, self.hdr_len, true);
+            self.rx_throttle
+                .account(false, (size as usize - self.hdr_len) as u64);
 
-            let mut buf = vec![0_u8; NET_HDR_LENGTH + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH];
+            let mut buf = vec![0_u8; self.hdr_len + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH];
             get_net_header(&iovecs, &mut buf).and_then(|size| {
                 if size != buf.len() {
                     bail!(
@@ -815,7 +864,7 @@ impl NetIoHandler {
                 .ctrl_info
                 .lock()
                 .unwrap()
-                .filter_packets(&buf[NET_HDR_LENGTH..])
+                .filter_packets(&buf[self.hdr_len..])
             {
                 queue.vring.push_back();
                 continue;
@@ -905,11 +954,20 @@ impl NetIoHandler {
                 queue.vring.get_cache(),
                 &elem.out_iovec,
             );
-            let tap_fd = if let Some(tap) = self.tap.as_mut() {
-                tap.as_raw_fd() as libc::c_int
-            } else {
-                -1_i32
+            let link_up = self.link_up.load(Ordering::SeqCst);
+            let tap_fd = match self.tap.as_mut() {
+                Some(tap) if link_up => tap.as_raw_fd() as libc::c_int,
+                _ => -1_i32,
             };
+            if tap_fd != -1 {
+                // The handler is woken up by the timer when the limit allows.
+                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                    if self.tx_throttle.wait(ctx, true) {
+                        queue.vring.push_back();
+                        return Ok(());
+                    }
+                }
+            }
             if tap_fd != -1 && self.send_packets(tap_fd, &iovecs) == -1 {
                 queue.vring.push_back();
                 self.tx.queue_evt.write(1).with_context(|| {
@@ -917,6 +975,12 @@ impl NetIoHandler {
                 })?;
                 return Ok(());
             }
+            if link_up {
+                let len = iovecs.iter().map(|iov| iov.iov_len).sum::<usize>();
+                self.tx_throttle
+                    .account(true, len.saturating_sub(self.hdr_len) as u64);
+                dump_packet(&self.dumps, &iovecs, len, self.hdr_len, false);
+            }
 
             queue
                 .vring
@@ -949,6 +1013,39 @@ impl NetIoHandler {
         Ok(())
     }
 
+    /// Receive the packets from tap, and stop listening to the tap if the rx queue is full.
+    fn handle_tap_event(&mut self) -> Option<Vec<EventNotifier>> {
+        if let Err(ref e) = self.handle_rx() {
+            error!("Failed to handle rx(tap event), {:?}", e);
+            report_virtio_error(
+                self.interrupt_cb.clone(),
+                self.driver_features,
+                &self.device_broken,
+            );
+            return None;
+        }
+
+        if let Some(tap) = self.tap.as_ref() {
+            if self.rx.queue_full {
+                self.rx.queue_full = false;
+                // The tap may have been parked when woken up by the rate limit timer.
+                if !self.is_listening {
+                    return None;
+                }
+                let notifier = vec![EventNotifier::new(
+                    NotifierOperation::Park,
+                    tap.as_raw_fd(),
+                    None,
+                    EventSet::IN | EventSet::EDGE_TRIGGERED,
+                    Vec::new(),
+                )];
+                self.is_listening = false;
+                return Some(notifier);
+            }
+        }
+        None
+    }
+
     fn update_evt_handler(net_io: &Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut locked_net_io = net_io.lock().unwrap();
         locked_net_io.tap = match locked_net_io.receiver.recv() {
@@ -968,6 +1065,8 @@ impl NetIoHandler {
             locked_net_io.update_evt.as_raw_fd(),
             locked_net_io.rx.queue_evt.as_raw_fd(),
             locked_net_io.tx.queue_evt.as_raw_fd(),
+            locked_net_io.rx_throttle.as_raw_fd(),
+            locked_net_io.tx_throttle.as_raw_fd(),
         ];
         if old_tap_fd != -1 {
             notifiers_fds.push(old_tap_fd);
@@ -980,6 +1079,66 @@ impl NetIoHandler {
     }
 }
 
+/// Packet capture of the net device, added by the `filter-dump` object.
+struct NetDump {
+    id: String,
+    rx: bool,
+    tx: bool,
+    writer: PcapWriter,
+}
+
+/// Write the packet to the pcap files capturing its direction.
+///
+/// # Arguments
+///
+/// * `iovecs` - The packet, beginning with the virtio net header.
+/// * `len` - Length of the packet, including the virtio net header.
+/// * `hdr_len` - Length of the virtio net header.
+/// * `is_rx` - The packet is received by the guest or sent by the guest.
+fn dump_packet(
+    dumps: &Mutex<Vec<NetDump>>,
+    iovecs: &[libc::iovec],
+    len: usize,
+    hdr_len: usize,
+    is_rx: bool,
+) {
+    let mut locked_dumps = dumps.lock().unwrap();
+    let snaplen = locked_dumps
+        .iter()
+        .filter(|dump| if is_rx { dump.rx } else { dump.tx })
+        .map(|dump| dump.writer.snaplen() as usize)
+        .max();
+    let snaplen = match snaplen {
+        Some(snaplen) if len > hdr_len => snaplen,
+        _ => return,
+    };
+
+    let mut buf = vec![0_u8; cmp::min(len, hdr_len + snaplen)];
+    let size = match get_net_header(iovecs, &mut buf) {
+        Ok(size) if size > hdr_len => size,
+        Ok(_) => return,
+        Err(e) => {
+            error!("Failed to get the packet to dump: {:?}", e);
+            return;
+        }
+    };
+    let packet = &buf[hdr_len..size];
+    locked_dumps.retain_mut(|dump| {
+        if (is_rx && !dump.rx) || (!is_rx && !dump.tx) {
+            return true;
+        }
+        let data = &packet[..cmp::min(packet.len(), dump.writer.snaplen() as usize)];
+        if let Err(e) = dump.writer.write_packet(data, len - hdr_len) {
+            error!(
+                "Failed to dump packet, filter-dump {} stopped: {:?}",
+                dump.id, e
+            );
+            return false;
+        }
+        true
+    });
+}
+
 fn get_net_header(iovec: &[libc::iovec], buf: &mut [u8]) -> Result<usize> {
     let mut start: usize = 0;
     let mut end: usize = 0;
@@ -1100,32 +1259,7 @@ impl EventNotifierHelper for NetIoHandler {
                 if locked_net_io.device_broken.load(Ordering::SeqCst) {
                     return None;
                 }
-
-                if let Err(ref e) = locked_net_io.handle_rx() {
-                    error!("Failed to handle rx(tap event), {:?}", e);
-                    report_virtio_error(
-                        locked_net_io.interrupt_cb.clone(),
-                        locked_net_io.driver_features,
-                        &locked_net_io.device_broken,
-                    );
-                    return None;
-                }
-
-                if let Some(tap) = locked_net_io.tap.as_ref() {
-                    if locked_net_io.rx.queue_full {
-                        let notifier = vec![EventNotifier::new(
-                            NotifierOperation::Park,
-                            tap.as_raw_fd(),
-                            None,
-                            EventSet::IN | EventSet::EDGE_TRIGGERED,
-                            Vec::new(),
-                        )];
-                        locked_net_io.is_listening = false;
-                        locked_net_io.rx.queue_full = false;
-                        return Some(notifier);
-                    }
-                }
-                None
+                locked_net_io.handle_tap_event()
             });
             let tap_fd = tap.as_raw_fd();
             notifiers.push(build_event_notifier(
@@ -1136,6 +1270,50 @@ impl EventNotifierHelper for NetIoHandler {
             ));
         }
 
+        // Register timer event notifiers for rate limits, the limits may be
+        // enabled at runtime.
+        let cloned_net_io = net_io.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut locked_net_io = cloned_net_io.lock().unwrap();
+            if locked_net_io.device_broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            locked_net_io.rx_throttle.clear_timer();
+            locked_net_io.handle_tap_event()
+        });
+        notifiers.push(build_event_notifier(
+            locked_net_io.rx_throttle.as_raw_fd(),
+            Some(handler),
+            NotifierOperation::AddShared,
+            EventSet::IN,
+        ));
+
+        let cloned_net_io = net_io.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            let mut locked_net_io = cloned_net_io.lock().unwrap();
+            if locked_net_io.device_broken.load(Ordering::SeqCst) {
+                return None;
+            }
+            locked_net_io.tx_throttle.clear_timer();
+            if let Err(ref e) = locked_net_io.handle_tx() {
+                error!("Failed to handle tx(rate limit timer) for net, {:?}", e);
+                report_virtio_error(
+                    locked_net_io.interrupt_cb.clone(),
+                    locked_net_io.driver_features,
+                    &locked_net_io.device_broken,
+                );
+            }
+            None
+        });
+        notifiers.push(build_event_notifier(
+            locked_net_io.tx_throttle.as_raw_fd(),
+            Some(handler),
+            NotifierOperation::AddShared,
+            EventSet::IN,
+        ));
+
         notifiers
     }
 }
@@ -1152,9 +1330,12 @@ pub struct VirtioNetState {
     /// Virtio net configurations.
     pub config_space: VirtioNetConfig,
     /// Device broken status.
-    broken: bool,
+    pub broken: bool,
 }
 
+/// The key is the id of net device, the value is the virtio net device.
+pub type NetMap = Arc<Mutex<HashMap<String, Arc<Mutex<Net>>>>>;
+
 /// Network device structure.
 pub struct Net {
     /// Configuration of the network device.
@@ -1173,6 +1354,18 @@ pub struct Net {
     broken: Arc<AtomicBool>,
     /// The information about control command.
     ctrl_info: Option<Arc<Mutex<CtrlInfo>>>,
+    /// User-mode network backend.
+    usernet: Option<Arc<Mutex<UserNet>>>,
+    /// Socket network backend.
+    socknet: Option<Arc<Mutex<SocketNet>>>,
+    /// Packet captures of the device.
+    dumps: Arc<Mutex<Vec<NetDump>>>,
+    /// Rate limits shared by the queues of the device.
+    throttle_group: Arc<Mutex<ThrottleGroup>>,
+    /// Link status set by the user.
+    link_up: Arc<AtomicBool>,
+    /// Callback to trigger the config interrupt when the device is activated.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
 }
 
 impl Default for Net {
@@ -1186,12 +1379,22 @@ impl Default for Net {
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             ctrl_info: None,
+            usernet: None,
+            socknet: None,
+            dumps: Arc::new(Mutex::new(Vec::new())),
+            throttle_group: Arc::new(Mutex::new(ThrottleGroup::new("", Default::default()))),
+            link_up: Arc::new(AtomicBool::new(true)),
+            interrupt_cb: None,
         }
     }
 }
 
 impl Net {
     pub fn new(net_cfg: NetworkInterfaceConfig) -> Self {
+        let throttle_group = Arc::new(Mutex::new(ThrottleGroup::new(
+            &net_cfg.id,
+            net_cfg.rate_limit.throttle_config(),
+        )));
         Self {
             net_cfg,
             taps: None,
@@ -1201,10 +1404,150 @@ impl Net {
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             ctrl_info: None,
+            usernet: None,
+            socknet: None,
+            dumps: Arc::new(Mutex::new(Vec::new())),
+            throttle_group,
+            link_up: Arc::new(AtomicBool::new(true)),
+            interrupt_cb: None,
+        }
+    }
+
+    pub fn rate_limit(&self) -> NetRateLimit {
+        self.net_cfg.rate_limit
+    }
+
+    /// Change the rate limits at runtime, rate limiting is disabled if all the
+    /// limits are zero.
+    pub fn set_rate_limit(&mut self, rate_limit: NetRateLimit) -> Result<()> {
+        rate_limit.check()?;
+        self.net_cfg.rate_limit = rate_limit;
+        self.throttle_group
+            .lock()
+            .unwrap()
+            .set_config(rate_limit.throttle_config());
+        Ok(())
+    }
+
+    pub fn link_up(&self) -> bool {
+        self.link_up.load(Ordering::SeqCst)
+    }
+
+    /// Set the link status, which is reported to the guest by the config interrupt.
+    /// The packets are dropped while the link is down, as if the cable is unplugged.
+    pub fn set_link(&mut self, up: bool) -> Result<()> {
+        self.link_up.store(up, Ordering::SeqCst);
+        let mut locked_state = self.state.lock().unwrap();
+        let old_status = locked_state.config_space.status;
+        let status = if up {
+            old_status | VIRTIO_NET_S_LINK_UP
+        } else {
+            old_status & !VIRTIO_NET_S_LINK_UP
+        };
+        if status == old_status {
+            return Ok(());
         }
+        locked_state.config_space.status = status;
+        drop(locked_state);
+
+        notify_config_change(self.interrupt_cb.as_ref())
+    }
+
+    /// Ask the guest to send gratuitous packets, e.g. after it is migrated to another host.
+    pub fn announce(&mut self) -> Result<()> {
+        guest_announce(&self.state, self.interrupt_cb.as_ref())?;
+        Ok(())
+    }
+
+    /// Id of the netdev used by the device.
+    pub fn netdev_id(&self) -> &str {
+        &self.net_cfg.netdev
+    }
+
+    pub fn has_dump(&self, id: &str) -> bool {
+        self.dumps.lock().unwrap().iter().any(|dump| dump.id == id)
+    }
+
+    /// Start capturing the packets of the device to a pcap file.
+    pub fn add_dump(&self, config: &FilterDumpConfig) -> Result<()> {
+        if self.has_dump(&config.id) {
+            bail!("filter-dump {} has been added", config.id);
+        }
+        let writer = PcapWriter::new(&config.file, config.maxlen, config.maxsize, config.maxfiles)?;
+        self.dumps.lock().unwrap().push(NetDump {
+            id: config.id.clone(),
+            rx: config.rx,
+            tx: config.tx,
+            writer,
+        });
+        Ok(())
+    }
+
+    /// Stop the packet capture, return false if it is not found.
+    pub fn del_dump(&self, id: &str) -> bool {
+        let mut locked_dumps = self.dumps.lock().unwrap();
+        let len = locked_dumps.len();
+        locked_dumps.retain(|dump| dump.id != id);
+        locked_dumps.len() != len
+    }
+
+    /// Stop the in-process backends and remove their sockets from the main loop.
+    ///
+    /// # Arguments
+    ///
+    /// * `all` - Stop all the backends, or only those not configured any more.
+    fn release_backends(&mut self, all: bool) -> Result<()> {
+        let mut fds = Vec::new();
+        if all || self.net_cfg.user.is_none() {
+            if let Some(usernet) = self.usernet.take() {
+                fds.append(&mut usernet.lock().unwrap().notifier_fds());
+            }
+        }
+        if all || self.net_cfg.socket.is_none() {
+            if let Some(socknet) = self.socknet.take() {
+                fds.append(&mut socknet.lock().unwrap().notifier_fds());
+            }
+        }
+        if !fds.is_empty() {
+            EventLoop::update_event(gen_delete_notifiers(&fds), None)?;
+        }
+        Ok(())
     }
 }
 
+/// Trigger the config interrupt to notify the guest that the config space is changed.
+fn notify_config_change(interrupt_cb: Option<&Arc<VirtioInterrupt>>) -> Result<()> {
+    if let Some(interrupt_cb) = interrupt_cb {
+        interrupt_cb(&VirtioInterruptType::Config, None, false)
+            .with_context(|| VirtioError::InterruptTrigger("net", VirtioInterruptType::Config))?;
+    }
+    Ok(())
+}
+
+/// Set VIRTIO_NET_S_ANNOUNCE to ask the guest to send gratuitous packets, return false
+/// if the device is not activated or the driver does not support VIRTIO_NET_F_GUEST_ANNOUNCE.
+///
+/// # Arguments
+///
+/// * `state` - The status of net device.
+/// * `interrupt_cb` - The interrupt callback of the activated device.
+pub fn guest_announce(
+    state: &Mutex<VirtioNetState>,
+    interrupt_cb: Option<&Arc<VirtioInterrupt>>,
+) -> Result<bool> {
+    let mut locked_state = state.lock().unwrap();
+    if interrupt_cb.is_none()
+        || !virtio_has_feature(locked_state.driver_features, VIRTIO_NET_F_GUEST_ANNOUNCE)
+    {
+        return Ok(false);
+    }
+    locked_state.config_space.status |= VIRTIO_NET_S_ANNOUNCE;
+    drop(locked_state);
+
+    notify_config_change(interrupt_cb)?;
+    Ok(true)
+}
+
 /// Set Mac address configured into the virtio configuration, and return features mask with
 /// VIRTIO_NET_F_MAC set.
 ///
@@ -1338,6 +1681,22 @@ pub fn create_tap(
     Ok(Some(taps))
 }
 
+/// Get the length of the virtio net header from driver features, the `num_buffers`
+/// field is absent for the legacy driver without `VIRTIO_NET_F_MRG_RXBUF`.
+///
+/// # Arguments
+///
+/// * `features` - The driver features.
+fn get_net_hdr_len(features: u64) -> usize {
+    if virtio_has_feature(features, VIRTIO_F_VERSION_1)
+        || virtio_has_feature(features, VIRTIO_NET_F_MRG_RXBUF)
+    {
+        NET_HDR_LENGTH
+    } else {
+        NET_HDR_LENGTH - mem::size_of::<u16>()
+    }
+}
+
 /// Get the tap offload flags from driver features.
 ///
 /// # Arguments
@@ -1376,6 +1735,8 @@ impl VirtioDevice for Net {
             );
         }
 
+        self.release_backends(false)?;
+
         let mut locked_state = self.state.lock().unwrap();
         locked_state.device_features = 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_NET_F_CSUM
@@ -1386,13 +1747,20 @@ impl VirtioDevice for Net {
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_TSO6
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
             | 1 << VIRTIO_NET_F_CTRL_RX
             | 1 << VIRTIO_NET_F_CTRL_VLAN
             | 1 << VIRTIO_NET_F_CTRL_RX_EXTRA
+            | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE
             | 1 << VIRTIO_NET_F_CTRL_MAC_ADDR
             | 1 << VIRTIO_NET_F_CTRL_VQ
             | 1 << VIRTIO_F_RING_INDIRECT_DESC
             | 1 << VIRTIO_F_RING_EVENT_IDX;
+        locked_state.config_space.status = if self.link_up.load(Ordering::SeqCst) {
+            VIRTIO_NET_S_LINK_UP
+        } else {
+            0
+        };
 
         let queue_pairs = self.net_cfg.queues / 2;
         if self.net_cfg.mq
@@ -1403,7 +1771,34 @@ impl VirtioDevice for Net {
             locked_state.config_space.max_virtqueue_pairs = queue_pairs;
         }
 
-        if !self.net_cfg.host_dev_name.is_empty() {
+        if let Some(user_cfg) = &self.net_cfg.user {
+            // For microvm which will call realize() twice, keep the running backend.
+            if self.usernet.is_none() {
+                let usernet = Arc::new(Mutex::new(
+                    UserNet::new(user_cfg).with_context(|| "Failed to create user netdev")?,
+                ));
+                EventLoop::update_event(
+                    EventNotifierHelper::internal_notifiers(usernet.clone()),
+                    None,
+                )?;
+                self.usernet = Some(usernet);
+            }
+            let tap = self.usernet.as_ref().unwrap().lock().unwrap().tap()?;
+            self.taps = Some(vec![tap]);
+        } else if let Some(socket_cfg) = &self.net_cfg.socket {
+            if self.socknet.is_none() {
+                let socknet = Arc::new(Mutex::new(
+                    SocketNet::new(socket_cfg).with_context(|| "Failed to create socket netdev")?,
+                ));
+                EventLoop::update_event(
+                    EventNotifierHelper::internal_notifiers(socknet.clone()),
+                    None,
+                )?;
+                self.socknet = Some(socknet);
+            }
+            let tap = self.socknet.as_ref().unwrap().lock().unwrap().tap()?;
+            self.taps = Some(vec![tap]);
+        } else if !self.net_cfg.host_dev_name.is_empty() {
             self.taps = None;
             self.taps = create_tap(None, Some(&self.net_cfg.host_dev_name), queue_pairs)
                 .with_context(|| "Failed to open tap with file path")?;
@@ -1427,7 +1822,16 @@ impl VirtioDevice for Net {
 
         // Using the first tap to test if all the taps have ufo.
         if let Some(tap) = self.taps.as_ref().map(|t| &t[0]) {
-            if !tap.has_ufo() {
+            if !tap.has_offload() {
+                locked_state.device_features &= !(1 << VIRTIO_NET_F_CSUM
+                    | 1 << VIRTIO_NET_F_GUEST_CSUM
+                    | 1 << VIRTIO_NET_F_GUEST_TSO4
+                    | 1 << VIRTIO_NET_F_GUEST_TSO6
+                    | 1 << VIRTIO_NET_F_GUEST_UFO
+                    | 1 << VIRTIO_NET_F_HOST_TSO4
+                    | 1 << VIRTIO_NET_F_HOST_TSO6
+                    | 1 << VIRTIO_NET_F_HOST_UFO);
+            } else if !tap.has_ufo() {
                 locked_state.device_features &=
                     !(1 << VIRTIO_NET_F_GUEST_UFO | 1 << VIRTIO_NET_F_HOST_UFO);
             }
@@ -1451,6 +1855,7 @@ impl VirtioDevice for Net {
     }
 
     fn unrealize(&mut self) -> Result<()> {
+        self.release_backends(true)?;
         mark_mac_table(&self.state.lock().unwrap().config_space.mac, false);
         MigrationManager::unregister_device_instance(
             VirtioNetState::descriptor(),
@@ -1575,6 +1980,7 @@ impl VirtioDevice for Net {
         // The features about offload is included in bits 0 to 31.
         let features = self.get_driver_features(0_u32);
         let flags = get_tap_offload_flags(features as u64);
+        let hdr_len = get_net_hdr_len(driver_features);
 
         let mut senders = Vec::new();
         let queue_pairs = queue_num / 2;
@@ -1590,6 +1996,8 @@ impl VirtioDevice for Net {
             if let Some(tap) = self.taps.as_ref().map(|t| t[index].clone()) {
                 tap.set_offload(flags)
                     .with_context(|| "Failed to set tap offload")?;
+                tap.set_hdr_size(hdr_len as u32)
+                    .with_context(|| "Failed to set tap hdr size")?;
             }
 
             let update_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
@@ -1607,6 +2015,12 @@ impl VirtioDevice for Net {
                 is_listening: true,
                 ctrl_info: ctrl_info.clone(),
                 queue_size: self.queue_size(),
+                dumps: self.dumps.clone(),
+                iothread: self.net_cfg.iothread.clone(),
+                rx_throttle: Throttle::new(self.throttle_group.clone())?,
+                tx_throttle: Throttle::new(self.throttle_group.clone())?,
+                link_up: self.link_up.clone(),
+                hdr_len,
             };
             if let Some(tap) = &handler.tap {
                 handler.tap_fd = tap.as_raw_fd();
@@ -1621,6 +2035,7 @@ impl VirtioDevice for Net {
             self.update_evts.push(update_evt);
         }
         self.senders = Some(senders);
+        self.interrupt_cb = Some(interrupt_cb);
         self.broken.store(false, Ordering::SeqCst);
 
         Ok(())
@@ -1647,10 +2062,15 @@ impl VirtioDevice for Net {
         } else {
             self.net_cfg = Default::default();
         }
+        self.throttle_group
+            .lock()
+            .unwrap()
+            .set_config(self.net_cfg.rate_limit.throttle_config());
 
         self.realize()?;
 
         if let Some(senders) = &self.senders {
+            let hdr_len = get_net_hdr_len(self.state.lock().unwrap().driver_features);
             for (index, sender) in senders.iter().enumerate() {
                 match self.taps.take() {
                     Some(taps) => {
@@ -1658,6 +2078,8 @@ impl VirtioDevice for Net {
                             .get(index)
                             .cloned()
                             .with_context(|| format!("Failed to get index {} tap", index))?;
+                        tap.set_hdr_size(hdr_len as u32)
+                            .with_context(|| "Failed to set tap hdr size")?;
                         sender.send(Some(tap)).with_context(|| {
                             anyhow!(VirtioError::ChannelSend("tap fd".to_string()))
                         })?;
@@ -1682,6 +2104,8 @@ impl VirtioDevice for Net {
         unregister_event_helper(self.net_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
         self.update_evts.clear();
         self.ctrl_info = None;
+        self.interrupt_cb = None;
+        self.state.lock().unwrap().config_space.status &= !VIRTIO_NET_S_ANNOUNCE;
         Ok(())
     }
 }
@@ -1705,6 +2129,10 @@ impl StateTransfer for Net {
         let mut locked_state = self.state.lock().unwrap();
         locked_state.as_mut_bytes().copy_from_slice(state);
         self.broken.store(locked_state.broken, Ordering::SeqCst);
+        self.link_up.store(
+            locked_state.config_space.status & VIRTIO_NET_S_LINK_UP != 0,
+            Ordering::SeqCst,
+        );
 
         Ok(())
     }
@@ -1718,7 +2146,13 @@ impl StateTransfer for Net {
     }
 }
 
-impl MigrationHook for Net {}
+impl MigrationHook for Net {
+    fn resume(&mut self) -> migration::Result<()> {
+        // Update the switches with the new location of the guest.
+        self.announce()
+            .with_context(|| "Failed to announce virtio net device")
+    }
+}
 
 impl VirtioTrace for NetIoHandler {}
 
@@ -1726,6 +2160,7 @@ impl VirtioTrace for NetIoHandler {}
 mod tests {
     pub use super::super::*;
     pub use super::*;
+    use std::sync::atomic::AtomicU32;
 
     #[test]
     fn test_net_init() {
@@ -1840,6 +2275,65 @@ mod tests {
         assert_eq!(ctrl_info.filter_packets(&buf), false);
     }
 
+    #[test]
+    fn test_net_hdr_len() {
+        assert_eq!(get_net_hdr_len(0), 10);
+        assert_eq!(get_net_hdr_len(1 << VIRTIO_NET_F_MRG_RXBUF), 12);
+        assert_eq!(get_net_hdr_len(1 << VIRTIO_F_VERSION_1), 12);
+    }
+
+    #[test]
+    fn test_net_link_and_announce() {
+        let mut net = Net::default();
+        net.net_cfg.mac = Some("52:54:00:ab:cd:ef".to_string());
+        net.realize().unwrap();
+        let config_status = |net: &Net| net.state.lock().unwrap().config_space.status;
+        assert_eq!(config_status(&net), VIRTIO_NET_S_LINK_UP);
+
+        let config_irqs = Arc::new(AtomicU32::new(0));
+        let cloned_irqs = config_irqs.clone();
+        let interrupt_cb: VirtioInterrupt = Box::new(move |int_type, _, _| {
+            if matches!(int_type, VirtioInterruptType::Config) {
+                cloned_irqs.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        });
+        net.interrupt_cb = Some(Arc::new(interrupt_cb));
+
+        // The guest is notified only when the link status is changed.
+        net.set_link(false).unwrap();
+        net.set_link(false).unwrap();
+        assert!(!net.link_up());
+        assert_eq!(config_status(&net), 0);
+        assert_eq!(config_irqs.load(Ordering::SeqCst), 1);
+        net.set_link(true).unwrap();
+        assert!(net.link_up());
+        assert_eq!(config_irqs.load(Ordering::SeqCst), 2);
+
+        // The driver does not support VIRTIO_NET_F_GUEST_ANNOUNCE.
+        net.announce().unwrap();
+        assert_eq!(config_status(&net), VIRTIO_NET_S_LINK_UP);
+        assert_eq!(config_irqs.load(Ordering::SeqCst), 2);
+
+        net.state.lock().unwrap().driver_features =
+            1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE;
+        net.announce().unwrap();
+        assert_eq!(
+            config_status(&net),
+            VIRTIO_NET_S_LINK_UP | VIRTIO_NET_S_ANNOUNCE
+        );
+        assert_eq!(config_irqs.load(Ordering::SeqCst), 3);
+
+        // The driver acknowledges the announcement by the control queue.
+        let mut ctrl_info = CtrlInfo::new(net.state.clone());
+        assert_eq!(ctrl_info.handle_announce(1), VIRTIO_NET_ERR);
+        assert_eq!(
+            ctrl_info.handle_announce(VIRTIO_NET_CTRL_ANNOUNCE_ACK),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(config_status(&net), VIRTIO_NET_S_LINK_UP);
+    }
+
     #[test]
     fn test_net_config_space() {
         let mut net_config = VirtioNetConfig::default();
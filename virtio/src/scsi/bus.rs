@@ -19,17 +19,18 @@ use anyhow::{anyhow, bail, Context, Result};
 
 use crate::ScsiCntlr::{
     ScsiCntlr, ScsiCompleteCb, ScsiXferMode, VirtioScsiCmdReq, VirtioScsiCmdResp,
-    VirtioScsiRequest, VIRTIO_SCSI_CDB_DEFAULT_SIZE, VIRTIO_SCSI_S_OK,
+    VirtioScsiRequest, VIRTIO_SCSI_CDB_DEFAULT_SIZE, VIRTIO_SCSI_SENSE_DEFAULT_SIZE,
+    VIRTIO_SCSI_S_FAILURE, VIRTIO_SCSI_S_OK,
 };
 use crate::ScsiDisk::{
-    ScsiDevice, DEFAULT_SECTOR_SIZE, SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-    SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT, SCSI_DISK_F_DPOFUA, SCSI_DISK_F_REMOVABLE, SCSI_TYPE_DISK,
-    SCSI_TYPE_ROM, SECTOR_SHIFT,
+    ScsiDevKind, ScsiDevice, DEFAULT_SECTOR_SIZE, SCSI_DISK_F_DPOFUA, SCSI_DISK_F_REMOVABLE,
+    SCSI_TYPE_DISK, SCSI_TYPE_ROM, SECTOR_SHIFT,
 };
 use address_space::AddressSpace;
+use block_backend::stats::{BlockAcctCookie, BlockAcctType};
 use byteorder::{BigEndian, ByteOrder};
 use log::{debug, error, info};
-use util::aio::{Aio, AioCb, Iovec, OpCode};
+use util::aio::{iov_to_buf_direct, Iovec};
 
 /// Scsi Operation code.
 pub const TEST_UNIT_READY: u8 = 0x00;
@@ -177,6 +178,15 @@ pub const SCSI_SENSE_BUF_SIZE: usize = 252;
 /// SERVICE ACTION IN subcodes.
 pub const SUBCODE_READ_CAPACITY_16: u8 = 0x10;
 
+/// Max number of blocks unmapped or written by one UNMAP or WRITE SAME command.
+const SCSI_MAX_PROVISIONING_BLOCKS: u32 = u32::MAX / 512;
+/// Length of the header of UNMAP parameter list.
+const UNMAP_PARAM_HEADER_LEN: usize = 8;
+/// Length of the block descriptor of UNMAP parameter list.
+const UNMAP_BLOCK_DESC_LEN: usize = 16;
+/// Max length of UNMAP parameter list with one block descriptor.
+const UNMAP_PARAM_MAX_LEN: usize = UNMAP_PARAM_HEADER_LEN + UNMAP_BLOCK_DESC_LEN;
+
 /// Sense Keys.
 pub const NO_SENSE: u8 = 0x00;
 pub const RECOVERED_ERROR: u8 = 0x01;
@@ -214,6 +224,7 @@ pub const SCSI_SENSE_INVALID_FIELD: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x24
 pub const SCSI_SENSE_INVALID_PARAM: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x00);
 pub const SCSI_SENSE_INVALID_PARAM_VALUE: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x01);
 pub const SCSI_SENSE_INVALID_PARAM_LEN: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x1a, 0x00);
+pub const SCSI_SENSE_INVALID_RELEASE: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x04);
 pub const SCSI_SENSE_LUN_NOT_SUPPORTED: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x25, 0x00);
 pub const SCSI_SENSE_SAVING_PARAMS_NOT_SUPPORTED: ScsiSense =
     scsisense!(ILLEGAL_REQUEST, 0x39, 0x00);
@@ -241,7 +252,7 @@ pub const SCSI_SENSE_DEVICE_INTERNAL_RESET: ScsiSense = scsisense!(UNIT_ATTENTIO
 pub const SCSI_SENSE_WRITE_PROTECTED: ScsiSense = scsisense!(DATA_PROTECT, 0x27, 0x00);
 pub const SCSI_SENSE_SPACE_ALLOC_FAILED: ScsiSense = scsisense!(DATA_PROTECT, 0x27, 0x07);
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct ScsiSense {
     /// Sense key.
     pub key: u8,
@@ -491,18 +502,14 @@ impl ScsiRequest {
             .scsi_bus_parse_req_cdb(cdb, scsidevice.clone())
         {
             let ops = cmd.command;
-            let opstype = scsi_operation_type(ops);
+            let opstype = scsi_operation_type(ops, scsidevice.lock().unwrap().kind);
             let _resid = cmd.xfer;
 
-            if ops == WRITE_10 || ops == READ_10 {
+            if (ops == WRITE_10 || ops == READ_10) && opstype == NON_EMULATE_SCSI_OPS {
                 let dev_lock = scsidevice.lock().unwrap();
                 let disk_size = dev_lock.disk_sectors << SECTOR_SHIFT;
-                let disk_type = dev_lock.scsi_type;
+                let offset_shift = dev_lock.block_size.trailing_zeros();
                 drop(dev_lock);
-                let offset_shift = match disk_type {
-                    SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-                    _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-                };
                 let offset = if let Some(off) = cmd.lba.checked_shl(offset_shift) {
                     off
                 } else {
@@ -535,53 +542,294 @@ impl ScsiRequest {
         }
     }
 
-    pub fn execute(
-        &self,
-        aio: &mut Box<Aio<ScsiCompleteCb>>,
-        mut aiocb: AioCb<ScsiCompleteCb>,
-    ) -> Result<u32> {
+    pub fn execute(&self, mut iocompletecb: ScsiCompleteCb) -> Result<u32> {
         let dev_lock = self.dev.lock().unwrap();
-        let offset = match dev_lock.scsi_type {
-            SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-            _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-        };
-        aiocb.offset = (self.cmd.lba << offset) as usize;
+        let offset_shift = dev_lock.block_size.trailing_zeros();
+        let offset = (self.cmd.lba << offset_shift) as usize;
+        let mut locked_backend = dev_lock
+            .block_backend
+            .as_ref()
+            .with_context(|| "No block backend for scsi device")?
+            .lock()
+            .unwrap();
 
+        let mut iovecs = Vec::new();
         for iov in self.virtioscsireq.lock().unwrap().iovec.iter() {
             let iovec = Iovec {
                 iov_base: iov.iov_base,
                 iov_len: iov.iov_len,
             };
-            aiocb.iovec.push(iovec);
-            // Note: total len of each req is no more than DESC_CHAIN_MAX_TOTAL_LEN (1 << 32).
-            aiocb.nbytes += iov.iov_len;
+            iovecs.push(iovec);
         }
 
         if self.cmd.command == SYNCHRONIZE_CACHE {
-            aiocb.opcode = OpCode::Fdsync;
-            aio.submit_request(aiocb)
+            iocompletecb.acct = Some(BlockAcctCookie::new(
+                &dev_lock.stats,
+                BlockAcctType::Flush,
+                0,
+            ));
+            locked_backend
+                .datasync(iocompletecb)
                 .with_context(|| "Failed to process scsi request for flushing")?;
+            locked_backend.flush_request()?;
+            return Ok(0);
+        }
+
+        if matches!(self.cmd.command, UNMAP | WRITE_SAME_10 | WRITE_SAME_16) {
+            let disk_blocks = (dev_lock.disk_sectors << SECTOR_SHIFT) >> offset_shift;
+            let range = match dev_lock.is_writable() {
+                true => provisioning_range(&self.cmd, &iovecs, disk_blocks),
+                false => Err(SCSI_SENSE_WRITE_PROTECTED),
+            };
+            let (lba, nb_blocks, unmap) = match range {
+                Ok(range) => range,
+                Err(sense) => {
+                    debug!(
+                        "Invalid scsi command {:#x} for provisioning",
+                        self.cmd.command
+                    );
+                    self.cmd_complete(
+                        &iocompletecb.mem_space,
+                        VIRTIO_SCSI_S_OK,
+                        CHECK_CONDITION,
+                        Some(sense),
+                        &Vec::new(),
+                    )?;
+                    return Ok(0);
+                }
+            };
+            if nb_blocks == 0 {
+                self.cmd_complete(
+                    &iocompletecb.mem_space,
+                    VIRTIO_SCSI_S_OK,
+                    GOOD,
+                    None,
+                    &Vec::new(),
+                )?;
+                return Ok(0);
+            }
+
+            let offset = (lba << offset_shift) as usize;
+            let nbytes = nb_blocks << offset_shift;
+            // Writing same zeros is accounted as write like virtio-blk.
+            let acct_type = match self.cmd.command {
+                UNMAP => BlockAcctType::Unmap,
+                _ => BlockAcctType::Write,
+            };
+            iocompletecb.acct = Some(BlockAcctCookie::new(&dev_lock.stats, acct_type, nbytes));
+            if self.cmd.command == UNMAP {
+                locked_backend
+                    .discard(offset, nbytes, iocompletecb)
+                    .with_context(|| "Failed to process scsi request for unmapping")?;
+            } else {
+                locked_backend
+                    .write_zeroes(offset, nbytes, iocompletecb, unmap)
+                    .with_context(|| "Failed to process scsi request for writing same")?;
+            }
+            locked_backend.flush_request()?;
             return Ok(0);
         }
 
+        let nbytes = iovecs.iter().map(|iov| iov.iov_len).sum();
         match self.cmd.mode {
             ScsiXferMode::ScsiXferFromDev => {
-                aiocb.opcode = OpCode::Preadv;
-                aio.submit_request(aiocb)
+                iocompletecb.acct = Some(BlockAcctCookie::new(
+                    &dev_lock.stats,
+                    BlockAcctType::Read,
+                    nbytes,
+                ));
+                locked_backend
+                    .read_vectored(iovecs, offset, iocompletecb)
                     .with_context(|| "Failed to process scsi request for reading")?;
             }
             ScsiXferMode::ScsiXferToDev => {
-                aiocb.opcode = OpCode::Pwritev;
-                aio.submit_request(aiocb)
+                iocompletecb.acct = Some(BlockAcctCookie::new(
+                    &dev_lock.stats,
+                    BlockAcctType::Write,
+                    nbytes,
+                ));
+                locked_backend
+                    .write_vectored(iovecs, offset, iocompletecb)
                     .with_context(|| "Failed to process block request for writing")?;
             }
             _ => {
                 info!("xfer none");
             }
         }
+        locked_backend.flush_request()?;
         Ok(0)
     }
 
+    /// Complete the request with the unit attention condition of device if there
+    /// is, which is cleared once reported. INQUIRY, REPORT LUNS and REQUEST SENSE
+    /// are not affected, REQUEST SENSE reports the condition as its sense data.
+    pub fn report_unit_attention(&self, mem_space: &Arc<AddressSpace>) -> Result<bool> {
+        if matches!(self.cmd.command, INQUIRY | REPORT_LUNS | REQUEST_SENSE) {
+            return Ok(false);
+        }
+        let sense = match self.dev.lock().unwrap().unit_attention.take() {
+            Some(sense) => sense,
+            None => return Ok(false),
+        };
+        self.cmd_complete(
+            mem_space,
+            VIRTIO_SCSI_S_OK,
+            CHECK_CONDITION,
+            Some(sense),
+            &Vec::new(),
+        )?;
+        Ok(true)
+    }
+
+    /// Complete the request with the sense of MEDIUM NOT PRESENT if it accesses
+    /// the medium of cd-rom which is ejected or whose tray is open.
+    pub fn report_no_medium(&self, mem_space: &Arc<AddressSpace>) -> Result<bool> {
+        let dev_lock = self.dev.lock().unwrap();
+        if dev_lock.scsi_type != SCSI_TYPE_ROM
+            || dev_lock.kind != ScsiDevKind::Emulated
+            || dev_lock.has_medium()
+        {
+            return Ok(false);
+        }
+        drop(dev_lock);
+        if self.opstype == EMULATE_SCSI_OPS
+            && !matches!(
+                self.cmd.command,
+                TEST_UNIT_READY
+                    | READ_CAPACITY_10
+                    | SERVICE_ACTION_IN_16
+                    | READ_TOC
+                    | READ_DISC_INFORMATION
+            )
+        {
+            return Ok(false);
+        }
+        self.cmd_complete(
+            mem_space,
+            VIRTIO_SCSI_S_OK,
+            CHECK_CONDITION,
+            Some(SCSI_SENSE_NO_MEDIUM),
+            &Vec::new(),
+        )?;
+        Ok(true)
+    }
+
+    /// Complete the request with RESERVATION CONFLICT if it accesses the medium
+    /// which is reserved by other initiators.
+    pub fn report_reservation_conflict(&self, mem_space: &Arc<AddressSpace>) -> Result<bool> {
+        if self.opstype != NON_EMULATE_SCSI_OPS {
+            return Ok(false);
+        }
+        let pr_manager = match self.dev.lock().unwrap().pr_manager.clone() {
+            Some(pr_manager) => pr_manager,
+            None => return Ok(false),
+        };
+        let write = !matches!(self.cmd.command, READ_6 | READ_10 | READ_12 | READ_16);
+        let (status, sense) = match pr_manager.check_access(write) {
+            Ok(true) => return Ok(false),
+            Ok(false) => (RESERVATION_CONFLICT, None),
+            Err(e) => {
+                error!("Failed to check reservation: {:?}", e);
+                (CHECK_CONDITION, Some(SCSI_SENSE_LUN_COMM_FAILURE))
+            }
+        };
+        self.cmd_complete(mem_space, VIRTIO_SCSI_S_OK, status, sense, &Vec::new())?;
+        Ok(true)
+    }
+
+    /// Execute PERSISTENT RESERVE IN and OUT by the manager of persistent
+    /// reservations, if the device has one.
+    pub fn persistent_reserve_execute(&self, mem_space: &Arc<AddressSpace>) -> Result<bool> {
+        if !matches!(
+            self.cmd.command,
+            PERSISTENT_RESERVE_IN | PERSISTENT_RESERVE_OUT
+        ) {
+            return Ok(false);
+        }
+        let pr_manager = match self.dev.lock().unwrap().pr_manager.clone() {
+            Some(pr_manager) => pr_manager,
+            None => return Ok(false),
+        };
+        let req = self.virtioscsireq.lock().unwrap();
+        let cdb = req.req.cdb[..self.cmd.len as usize].to_vec();
+        let mut param = Vec::new();
+        if self.cmd.command == PERSISTENT_RESERVE_OUT {
+            param = vec![0_u8; self.cmd.xfer as usize];
+            match iov_to_buf_direct(&req.iovec, &mut param) {
+                Ok(size) if size == param.len() => {}
+                _ => {
+                    drop(req);
+                    self.cmd_complete(
+                        mem_space,
+                        VIRTIO_SCSI_S_OK,
+                        CHECK_CONDITION,
+                        Some(SCSI_SENSE_INVALID_PARAM_LEN),
+                        &Vec::new(),
+                    )?;
+                    return Ok(true);
+                }
+            }
+        }
+        drop(req);
+
+        match pr_manager.execute(&cdb, &param) {
+            Ok(mut resp) => {
+                // The data is truncated to the allocation length.
+                resp.data.truncate(self.cmd.xfer as usize);
+                self.cmd_complete(
+                    mem_space,
+                    VIRTIO_SCSI_S_OK,
+                    resp.status,
+                    resp.sense,
+                    &resp.data,
+                )?;
+            }
+            Err(e) => {
+                error!("Failed to execute persistent reservation: {:?}", e);
+                self.cmd_complete(
+                    mem_space,
+                    VIRTIO_SCSI_S_OK,
+                    CHECK_CONDITION,
+                    Some(SCSI_SENSE_LUN_COMM_FAILURE),
+                    &Vec::new(),
+                )?;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Pass through the command to host device, the status and sense data
+    /// returned by host device are reported to guest.
+    pub fn passthrough_execute(&self, mem_space: &Arc<AddressSpace>) -> Result<()> {
+        let sg_dev = self
+            .dev
+            .lock()
+            .unwrap()
+            .sg_dev
+            .clone()
+            .with_context(|| "No host device for scsi passthrough")?;
+        let mut req = self.virtioscsireq.lock().unwrap();
+        let cdb = &req.req.cdb[..self.cmd.len as usize];
+        let mut sense = [0_u8; VIRTIO_SCSI_SENSE_DEFAULT_SIZE];
+        match sg_dev.execute(cdb, req.mode.clone(), &req.iovec, &mut sense) {
+            Ok(result) => {
+                req.resp.response = match result.host_status {
+                    0 => VIRTIO_SCSI_S_OK,
+                    _ => VIRTIO_SCSI_S_FAILURE,
+                };
+                req.resp.status = result.status;
+                req.resp.resid = result.resid;
+                req.resp.sense = sense;
+                req.resp.sense_len = result.sense_len;
+            }
+            Err(e) => {
+                error!("{:?}", e);
+                req.resp.response = VIRTIO_SCSI_S_FAILURE;
+            }
+        }
+        req.complete(mem_space)
+    }
+
     pub fn emulate_execute(
         &self,
         iocompletecb: ScsiCompleteCb,
@@ -616,12 +864,13 @@ impl ScsiRequest {
             // It's not a target request.
             match self.cmd.command {
                 REQUEST_SENSE => {
-                    sense = Some(SCSI_SENSE_NO_SENSE);
+                    let unit_attention = self.dev.lock().unwrap().unit_attention.take();
+                    sense = Some(unit_attention.unwrap_or(SCSI_SENSE_NO_SENSE));
                     Ok(Vec::new())
                 }
                 TEST_UNIT_READY => {
                     let dev_lock = self.dev.lock().unwrap();
-                    if dev_lock.disk_image.is_none() {
+                    if dev_lock.block_backend.is_none() {
                         Err(anyhow!("No scsi backend!"))
                     } else {
                         Ok(Vec::new())
@@ -641,6 +890,13 @@ impl ScsiRequest {
                 }
                 READ_TOC => scsi_command_emulate_read_toc(&self.cmd, &self.dev),
                 GET_CONFIGURATION => scsi_command_emulate_get_configuration(&self.cmd, &self.dev),
+                ALLOW_MEDIUM_REMOVAL => {
+                    scsi_command_emulate_allow_medium_removal(&self.cmd, &self.dev)
+                }
+                START_STOP => scsi_command_emulate_start_stop(&self.cmd, &self.dev).map_err(|e| {
+                    sense = Some(e);
+                    anyhow!("Medium removal is prevented")
+                }),
                 _ => {
                     not_supported_flag = true;
                     Err(anyhow!("Emulation scsi command is not supported now!"))
@@ -680,7 +936,7 @@ impl ScsiRequest {
                         &iocompletecb.mem_space,
                         VIRTIO_SCSI_S_OK,
                         CHECK_CONDITION,
-                        Some(SCSI_SENSE_INVALID_FIELD),
+                        Some(sense.unwrap_or(SCSI_SENSE_INVALID_FIELD)),
                         &Vec::new(),
                     )?;
                 }
@@ -745,14 +1001,26 @@ fn write_buf_mem(buf: &[u8], max: u64, hva: u64) -> Result<()> {
 pub const EMULATE_SCSI_OPS: u32 = 0;
 // Scsi Commands which will do something(eg: read and write) to the backend.
 pub const NON_EMULATE_SCSI_OPS: u32 = 1;
-
-fn scsi_operation_type(op: u8) -> u32 {
-    match op {
-        READ_6 | READ_10 | READ_12 | READ_16 | WRITE_6 | WRITE_10 | WRITE_12 | WRITE_16
-        | WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16 | SYNCHRONIZE_CACHE => {
-            NON_EMULATE_SCSI_OPS
-        }
-        _ => EMULATE_SCSI_OPS,
+// Scsi Commands which are passed through to the host device.
+pub const PASSTHROUGH_SCSI_OPS: u32 = 2;
+
+fn scsi_operation_type(op: u8, kind: ScsiDevKind) -> u32 {
+    match kind {
+        ScsiDevKind::Emulated => match op {
+            READ_6 | READ_10 | READ_12 | READ_16 | WRITE_6 | WRITE_10 | WRITE_12 | WRITE_16
+            | WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16 | SYNCHRONIZE_CACHE | UNMAP
+            | WRITE_SAME_10 | WRITE_SAME_16 => NON_EMULATE_SCSI_OPS,
+            _ => EMULATE_SCSI_OPS,
+        },
+        // The luns of host are not exposed to guest.
+        _ if op == REPORT_LUNS => EMULATE_SCSI_OPS,
+        ScsiDevKind::Block => match op {
+            READ_6 | READ_10 | READ_12 | READ_16 | WRITE_6 | WRITE_10 | WRITE_12 | WRITE_16 => {
+                NON_EMULATE_SCSI_OPS
+            }
+            _ => PASSTHROUGH_SCSI_OPS,
+        },
+        ScsiDevKind::Generic => PASSTHROUGH_SCSI_OPS,
     }
 }
 
@@ -821,6 +1089,13 @@ fn scsi_cdb_xfer(cdb: &[u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE], dev: Arc<Mutex<ScsiDe
         INQUIRY => {
             xfer = i32::from(cdb[4]) | i32::from(cdb[3]) << 8;
         }
+        WRITE_SAME_10 | WRITE_SAME_16 => {
+            // Byte[1] bit0 of WRITE SAME(16): NDOB(No Data-Out Buffer).
+            xfer = match cdb[0] == WRITE_SAME_16 && cdb[1] & 0x1 != 0 {
+                true => 0,
+                false => block_size,
+            };
+        }
         _ => {}
     }
     xfer
@@ -893,6 +1168,105 @@ fn scsi_cdb_xfer_mode(cdb: &[u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE]) -> ScsiXferMode
     }
 }
 
+/// Get the lba, number of blocks and the unmap flag of UNMAP or WRITE SAME
+/// command. Only one block descriptor is supported for UNMAP, and only the
+/// data of zeros is supported for WRITE SAME.
+fn provisioning_range(
+    cmd: &ScsiCommand,
+    iovecs: &[Iovec],
+    disk_blocks: u64,
+) -> std::result::Result<(u64, u64, bool), ScsiSense> {
+    let (lba, nb_blocks) = match cmd.command {
+        UNMAP => {
+            // Byte[1] bit0: ANCHOR.
+            if cmd.buf[1] & 0x1 != 0 {
+                return Err(SCSI_SENSE_INVALID_FIELD);
+            }
+            let len = cmd.xfer as usize;
+            if len == 0 {
+                return Ok((0, 0, true));
+            }
+            if len < UNMAP_PARAM_HEADER_LEN {
+                return Err(SCSI_SENSE_INVALID_PARAM_LEN);
+            }
+            let mut param = vec![0_u8; cmp::min(len, UNMAP_PARAM_MAX_LEN)];
+            match iov_to_buf_direct(iovecs, &mut param) {
+                Ok(size) if size == param.len() => {}
+                _ => return Err(SCSI_SENSE_INVALID_PARAM_LEN),
+            }
+            // Parameter list header:
+            // Byte[0-1]: Unmap data length (n - 1).
+            // Byte[2-3]: Unmap block descriptor data length (n - 7).
+            // Byte[4-7]: Reserved.
+            let data_len = BigEndian::read_u16(&param[0..2]) as usize;
+            let desc_len = BigEndian::read_u16(&param[2..4]) as usize;
+            let desc_num = desc_len / UNMAP_BLOCK_DESC_LEN;
+            if len < data_len + 2
+                || len < desc_len + UNMAP_PARAM_HEADER_LEN
+                || desc_num * UNMAP_BLOCK_DESC_LEN != desc_len
+            {
+                return Err(SCSI_SENSE_INVALID_PARAM_LEN);
+            }
+            match desc_num {
+                0 => return Ok((0, 0, true)),
+                1 => {}
+                _ => return Err(SCSI_SENSE_INVALID_PARAM),
+            }
+            // Block descriptor:
+            // Byte[0-7]: Unmap logical block address.
+            // Byte[8-11]: Number of logical blocks.
+            // Byte[12-15]: Reserved.
+            let desc = &param[UNMAP_PARAM_HEADER_LEN..];
+            (
+                BigEndian::read_u64(&desc[0..8]),
+                BigEndian::read_u32(&desc[8..12]) as u64,
+            )
+        }
+        _ => {
+            // Byte[1] bit3: UNMAP, bit4: ANCHOR.
+            if cmd.buf[1] & 0x10 != 0 {
+                return Err(SCSI_SENSE_INVALID_FIELD);
+            }
+            let nb_blocks = match cmd.command {
+                WRITE_SAME_10 => BigEndian::read_u16(&cmd.buf[7..9]) as u64,
+                _ => BigEndian::read_u32(&cmd.buf[10..14]) as u64,
+            };
+            // WSNZ is set in Block Limits VPD page, zero blocks is invalid.
+            if nb_blocks == 0 {
+                return Err(SCSI_SENSE_INVALID_FIELD);
+            }
+            if cmd.xfer != 0 {
+                let mut data = vec![0_u8; cmd.xfer as usize];
+                match iov_to_buf_direct(iovecs, &mut data) {
+                    Ok(size) if size == data.len() => {}
+                    _ => return Err(SCSI_SENSE_INVALID_PARAM_LEN),
+                }
+                if data.iter().any(|b| *b != 0) {
+                    info!("Writing same data which is not zero is not supported");
+                    return Err(SCSI_SENSE_INVALID_FIELD);
+                }
+            }
+            (cmd.lba, nb_blocks)
+        }
+    };
+
+    if nb_blocks > SCSI_MAX_PROVISIONING_BLOCKS as u64 {
+        return Err(match cmd.command {
+            UNMAP => SCSI_SENSE_INVALID_PARAM,
+            _ => SCSI_SENSE_INVALID_FIELD,
+        });
+    }
+    if lba
+        .checked_add(nb_blocks)
+        .filter(|&end| end <= disk_blocks)
+        .is_none()
+    {
+        return Err(SCSI_SENSE_LBA_OUT_OF_RANGE);
+    }
+    let unmap = cmd.command == UNMAP || cmd.buf[1] & 0x8 != 0;
+    Ok((lba, nb_blocks, unmap))
+}
+
 /// VPD: Vital Product Data.
 fn scsi_command_emulate_vpd_page(
     cmd: &ScsiCommand,
@@ -986,7 +1360,12 @@ fn scsi_command_emulate_vpd_page(
             outbuf[4] = 1;
             let max_xfer_length: u32 = u32::MAX / 512;
             BigEndian::write_u32(&mut outbuf[8..12], max_xfer_length);
-            BigEndian::write_u64(&mut outbuf[36..44], max_xfer_length as u64);
+            if dev_lock.is_writable() {
+                BigEndian::write_u32(&mut outbuf[20..24], SCSI_MAX_PROVISIONING_BLOCKS);
+                BigEndian::write_u32(&mut outbuf[24..28], 1);
+                BigEndian::write_u32(&mut outbuf[28..32], 1);
+            }
+            BigEndian::write_u64(&mut outbuf[36..44], SCSI_MAX_PROVISIONING_BLOCKS as u64);
             buflen = outbuf.len();
         }
         0xb1 => {
@@ -1001,10 +1380,15 @@ fn scsi_command_emulate_vpd_page(
         0xb2 => {
             // Logical Block Provisioning.
             // 0: Threshold exponent.
-            // 0xe0: LBPU(bit 7) | LBPWS | LBPWS10 | LBPRZ | ANC_SUP | DP.
-            // 0: Threshold percentage | Provisioning Type.
+            // 0xe0: LBPU(bit 7) | LBPWS(bit 6) | LBPWS10(bit 5).
+            // 2: Minimum percentage | Provisioning Type(thin provisioned).
             // 0: Threshold percentage.
-            outbuf.append(&mut [0_u8, 0x60_u8, 1_u8, 0_u8].to_vec());
+            // Read-only device is fully provisioned and does not support unmapping.
+            let (lbp, provisioning_type) = match dev_lock.is_writable() {
+                true => (0xe0_u8, 2_u8),
+                false => (0_u8, 0_u8),
+            };
+            outbuf.append(&mut [0_u8, lbp, provisioning_type, 0_u8].to_vec());
             buflen = 8;
         }
         _ => {
@@ -1430,13 +1814,18 @@ fn scsi_command_emulate_service_action_in_16(
         let mut nb_sectors = dev_lock.disk_sectors;
         nb_sectors /= (block_size / DEFAULT_SECTOR_SIZE) as u64;
         nb_sectors -= 1;
+        let writable = dev_lock.is_writable();
 
         drop(dev_lock);
 
         // Byte[0-7]: Returned Logical BLock Address(the logical block address of the last logical block).
         // Byte[8-11]: Logical Block Length in Bytes.
+        // Byte[14]: bit7: LBPME(Logical Block Provisioning Management Enabled).
         BigEndian::write_u64(&mut outbuf[0..8], nb_sectors);
         BigEndian::write_u32(&mut outbuf[8..12], block_size);
+        if writable {
+            outbuf[14] |= 0x80;
+        }
 
         return Ok(outbuf);
     }
@@ -1627,7 +2016,7 @@ fn scsi_command_emulate_get_event_status_notification(
 ) -> Result<Vec<u8>> {
     // Byte4: Notification Class Request.
     let notification_class_request = cmd.buf[4];
-    let dev_lock = dev.lock().unwrap();
+    let mut dev_lock = dev.lock().unwrap();
 
     if dev_lock.scsi_type != SCSI_TYPE_ROM {
         bail!("Invalid scsi tye {}", dev_lock.scsi_type);
@@ -1661,10 +2050,14 @@ fn scsi_command_emulate_get_event_status_notification(
         // Byte6: Start Slot.
         // Byte7: End Slot.
 
-        // Do not support hot-plug/hot-unplug scsi cd which will be present all the time once vm starts.
-        // To do: this outbuf event code and media status should be changed after allowing hot-plug.
-        outbuf[4] = GESN_EC_NOCHG;
-        outbuf[5] = 1 << GESN_MS_MEDIA_PRESENT_BIT;
+        // The media event is cleared once reported.
+        outbuf[4] = std::mem::replace(&mut dev_lock.media_event, GESN_EC_NOCHG);
+        if dev_lock.tray_open {
+            outbuf[5] |= 1 << GESN_MS_DOOR_OR_TRAY_OPEN_BIT;
+        }
+        if dev_lock.block_backend.is_some() {
+            outbuf[5] |= 1 << GESN_MS_MEDIA_PRESENT_BIT;
+        }
     } else {
         // NCE = 1.
         outbuf[2] = 0x80;
@@ -1676,6 +2069,39 @@ fn scsi_command_emulate_get_event_status_notification(
     Ok(outbuf)
 }
 
+/// PREVENT ALLOW MEDIUM REMOVAL locks or unlocks the medium of device.
+fn scsi_command_emulate_allow_medium_removal(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<Vec<u8>> {
+    // Byte4: Bits[0-1]: Prevent.
+    dev.lock().unwrap().locked = cmd.buf[4] & 0x3 != 0;
+    Ok(Vec::new())
+}
+
+/// START STOP UNIT loads or ejects the medium of cd-rom, which fails with the
+/// sense returned if the removal of medium is prevented.
+fn scsi_command_emulate_start_stop(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> std::result::Result<Vec<u8>, ScsiSense> {
+    // Byte4: Bit 1: LOEJ(Load Eject). Bit 0: Start.
+    let loej = cmd.buf[4] & 0x2 != 0;
+    let start = cmd.buf[4] & 0x1 != 0;
+    let mut dev_lock = dev.lock().unwrap();
+    if dev_lock.scsi_type != SCSI_TYPE_ROM || !loej {
+        return Ok(Vec::new());
+    }
+    if !start && dev_lock.locked {
+        return Err(match dev_lock.has_medium() {
+            true => SCSI_SENSE_NOT_READY_REMOVAL_PREVENTED,
+            false => SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED,
+        });
+    }
+    dev_lock.move_tray(!start);
+    Ok(Vec::new())
+}
+
 /// LBA to MSF translation is defined in MMC6 Table 647.
 /// MSF values are converted to LBA values via such formula:
 /// lba = ((m * CD_SECS) + s) * CD_FRAMES + f) - CD_MSF_OFFSET.
@@ -1742,3 +2168,341 @@ fn cdrom_read_formatted_toc(nb_sectors: u32, msf: u8, track_number: u8) -> Resul
 
     Ok(outbuf)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+    use crate::ScsiCntlr::aio_complete_cb;
+    use block_backend::{create_block_backend, BlockProperty};
+    use machine_manager::config::{DiskFormat, ScsiDevConfig};
+    use machine_manager::qmp::QmpChannel;
+    use util::aio::{Aio, AioEngine};
+
+    fn scsi_cmd(cdb: &[u8]) -> ScsiCommand {
+        let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+        buf[..cdb.len()].copy_from_slice(cdb);
+        ScsiCommand {
+            buf,
+            command: cdb[0],
+            len: cdb.len() as u32,
+            xfer: 0,
+            lba: 0,
+            mode: ScsiXferMode::ScsiXferNone,
+        }
+    }
+
+    /// Create a scsi-cd whose medium is the image `file`.
+    fn scsi_cd(file: &TempFile) -> Arc<Mutex<ScsiDevice>> {
+        let config = ScsiDevConfig {
+            id: "scsi-cd0".to_string(),
+            path_on_host: file.as_path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let mut dev = ScsiDevice::new(config, SCSI_TYPE_ROM, Arc::new(Mutex::new(HashMap::new())));
+        file.as_file().set_len(1 << 20).unwrap();
+        let image = File::open(file.as_path()).unwrap();
+        let aio = Aio::new(Arc::new(aio_complete_cb), AioEngine::Off).unwrap();
+        let prop = BlockProperty {
+            id: "scsi-cd0".to_string(),
+            format: DiskFormat::Raw,
+            iothread: None,
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            copy_on_read: false,
+        };
+        dev.block_backend = Some(create_block_backend(image, aio, prop).unwrap());
+        Arc::new(Mutex::new(dev))
+    }
+
+    #[test]
+    fn test_scsi_cd_start_stop() {
+        QmpChannel::object_init();
+        let file = TempFile::new().unwrap();
+        let dev = scsi_cd(&file);
+        let prevent = scsi_cmd(&[ALLOW_MEDIUM_REMOVAL, 0, 0, 0, 1, 0]);
+        let allow = scsi_cmd(&[ALLOW_MEDIUM_REMOVAL, 0, 0, 0, 0, 0]);
+        // LOEJ = 1, Start = 0.
+        let eject = scsi_cmd(&[START_STOP, 0, 0, 0, 0x2, 0]);
+        // LOEJ = 1, Start = 1.
+        let load = scsi_cmd(&[START_STOP, 0, 0, 0, 0x3, 0]);
+
+        // The medium locked by guest can't be ejected.
+        scsi_command_emulate_allow_medium_removal(&prevent, &dev).unwrap();
+        assert!(dev.lock().unwrap().locked);
+        assert_eq!(
+            scsi_command_emulate_start_stop(&eject, &dev).unwrap_err(),
+            SCSI_SENSE_NOT_READY_REMOVAL_PREVENTED
+        );
+        assert!(dev.lock().unwrap().has_medium());
+
+        scsi_command_emulate_allow_medium_removal(&allow, &dev).unwrap();
+        assert!(!dev.lock().unwrap().locked);
+        scsi_command_emulate_start_stop(&eject, &dev).unwrap();
+        assert!(dev.lock().unwrap().tray_open);
+        assert!(!dev.lock().unwrap().has_medium());
+
+        // The tray can be closed even if the medium is locked.
+        scsi_command_emulate_allow_medium_removal(&prevent, &dev).unwrap();
+        assert_eq!(
+            scsi_command_emulate_start_stop(&eject, &dev).unwrap_err(),
+            SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED
+        );
+        scsi_command_emulate_start_stop(&load, &dev).unwrap();
+        assert!(!dev.lock().unwrap().tray_open);
+        assert!(dev.lock().unwrap().has_medium());
+    }
+
+    #[test]
+    fn test_scsi_cd_media_event() {
+        QmpChannel::object_init();
+        let file = TempFile::new().unwrap();
+        let dev = scsi_cd(&file);
+        // Polled, notification class request of media.
+        let gesn = scsi_cmd(&[
+            GET_EVENT_STATUS_NOTIFICATION,
+            1,
+            0,
+            0,
+            1 << GESN_MEDIA,
+            0,
+            0,
+            0,
+            8,
+            0,
+        ]);
+
+        dev.lock().unwrap().media_event = GESN_EC_NEWMEDIA;
+        let outbuf = scsi_command_emulate_get_event_status_notification(&gesn, &dev).unwrap();
+        assert_eq!(outbuf[2], GESN_MEDIA);
+        assert_eq!(outbuf[4], GESN_EC_NEWMEDIA);
+        assert_eq!(outbuf[5], 1 << GESN_MS_MEDIA_PRESENT_BIT);
+        // The media event is cleared after it is read.
+        let outbuf = scsi_command_emulate_get_event_status_notification(&gesn, &dev).unwrap();
+        assert_eq!(outbuf[4], GESN_EC_NOCHG);
+        assert_eq!(dev.lock().unwrap().media_event, GESN_EC_NOCHG);
+
+        dev.lock().unwrap().move_tray(true);
+        let outbuf = scsi_command_emulate_get_event_status_notification(&gesn, &dev).unwrap();
+        assert_eq!(
+            outbuf[5],
+            1 << GESN_MS_MEDIA_PRESENT_BIT | 1 << GESN_MS_DOOR_OR_TRAY_OPEN_BIT
+        );
+    }
+
+    /// Build the UNMAP command with the parameter list of block descriptors.
+    fn unmap_cmd(descs: &[(u64, u32)]) -> (ScsiCommand, Vec<u8>) {
+        let desc_len = descs.len() * UNMAP_BLOCK_DESC_LEN;
+        let len = UNMAP_PARAM_HEADER_LEN + desc_len;
+        let mut param = vec![0_u8; len];
+        BigEndian::write_u16(&mut param[0..2], (len - 2) as u16);
+        BigEndian::write_u16(&mut param[2..4], desc_len as u16);
+        for (i, (lba, nb_blocks)) in descs.iter().enumerate() {
+            let desc = &mut param[UNMAP_PARAM_HEADER_LEN + i * UNMAP_BLOCK_DESC_LEN..];
+            BigEndian::write_u64(&mut desc[0..8], *lba);
+            BigEndian::write_u32(&mut desc[8..12], *nb_blocks);
+        }
+        let mut cmd = scsi_cmd(&[UNMAP, 0, 0, 0, 0, 0, 0, 0, len as u8, 0]);
+        cmd.xfer = len as u32;
+        (cmd, param)
+    }
+
+    fn param_iovecs(param: &[u8]) -> Vec<Iovec> {
+        vec![Iovec::new(param.as_ptr() as u64, param.len() as u64)]
+    }
+
+    #[test]
+    fn test_scsi_unmap_range() {
+        let disk_blocks = 100;
+        let (cmd, param) = unmap_cmd(&[(8, 16)]);
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+            Ok((8, 16, true))
+        );
+
+        // Empty parameter list or no block descriptor unmaps nothing.
+        let mut empty = cmd.clone();
+        empty.xfer = 0;
+        assert_eq!(
+            provisioning_range(&empty, &[], disk_blocks),
+            Ok((0, 0, true))
+        );
+        let (cmd, param) = unmap_cmd(&[]);
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+            Ok((0, 0, true))
+        );
+
+        // The range exceeds the disk, or overflows.
+        for (lba, nb_blocks) in [(90, 16), (100, 1), (u64::MAX, 2)] {
+            let (cmd, param) = unmap_cmd(&[(lba, nb_blocks)]);
+            assert_eq!(
+                provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+                Err(SCSI_SENSE_LBA_OUT_OF_RANGE)
+            );
+        }
+        // Too many blocks, or too many block descriptors.
+        let (cmd, param) = unmap_cmd(&[(0, SCSI_MAX_PROVISIONING_BLOCKS + 1)]);
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), u64::MAX),
+            Err(SCSI_SENSE_INVALID_PARAM)
+        );
+        let (cmd, param) = unmap_cmd(&[(0, 1), (8, 1)]);
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+            Err(SCSI_SENSE_INVALID_PARAM)
+        );
+
+        // The lengths in header exceed the parameter list.
+        let (cmd, mut param) = unmap_cmd(&[(8, 16)]);
+        BigEndian::write_u16(&mut param[0..2], 0x100);
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+            Err(SCSI_SENSE_INVALID_PARAM_LEN)
+        );
+        let (cmd, mut param) = unmap_cmd(&[(8, 16)]);
+        BigEndian::write_u16(&mut param[2..4], 2 * UNMAP_BLOCK_DESC_LEN as u16);
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+            Err(SCSI_SENSE_INVALID_PARAM_LEN)
+        );
+        // The parameter list is shorter than the length of command.
+        let (mut cmd, param) = unmap_cmd(&[(8, 16)]);
+        cmd.xfer = UNMAP_PARAM_HEADER_LEN as u32 - 1;
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+            Err(SCSI_SENSE_INVALID_PARAM_LEN)
+        );
+        cmd.xfer = param.len() as u32;
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param[..12]), disk_blocks),
+            Err(SCSI_SENSE_INVALID_PARAM_LEN)
+        );
+        // ANCHOR is not supported.
+        let (mut cmd, param) = unmap_cmd(&[(8, 16)]);
+        cmd.buf[1] = 0x1;
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&param), disk_blocks),
+            Err(SCSI_SENSE_INVALID_FIELD)
+        );
+    }
+
+    #[test]
+    fn test_scsi_write_same_range() {
+        let disk_blocks = 100;
+        let zeros = vec![0_u8; 512];
+        let iovecs = param_iovecs(&zeros);
+        // WRITE SAME(16) of 16 blocks at lba 8.
+        let mut cdb = [0_u8; 16];
+        cdb[0] = WRITE_SAME_16;
+        BigEndian::write_u64(&mut cdb[2..10], 8);
+        BigEndian::write_u32(&mut cdb[10..14], 16);
+        let mut cmd = scsi_cmd(&cdb);
+        cmd.lba = 8;
+        cmd.xfer = 512;
+        assert_eq!(
+            provisioning_range(&cmd, &iovecs, disk_blocks),
+            Ok((8, 16, false))
+        );
+        // Byte[1] bit3: UNMAP.
+        cmd.buf[1] = 0x8;
+        assert_eq!(
+            provisioning_range(&cmd, &iovecs, disk_blocks),
+            Ok((8, 16, true))
+        );
+        // Byte[1] bit0: NDOB, no data is transferred.
+        cmd.buf[1] = 0x9;
+        cmd.xfer = 0;
+        assert_eq!(
+            provisioning_range(&cmd, &[], disk_blocks),
+            Ok((8, 16, true))
+        );
+
+        // Only the data of zeros is supported.
+        let data = vec![0x5a_u8; 512];
+        cmd.buf[1] = 0;
+        cmd.xfer = 512;
+        assert_eq!(
+            provisioning_range(&cmd, &param_iovecs(&data), disk_blocks),
+            Err(SCSI_SENSE_INVALID_FIELD)
+        );
+        // ANCHOR is not supported.
+        cmd.buf[1] = 0x10;
+        assert_eq!(
+            provisioning_range(&cmd, &iovecs, disk_blocks),
+            Err(SCSI_SENSE_INVALID_FIELD)
+        );
+        cmd.buf[1] = 0;
+        cmd.lba = 90;
+        assert_eq!(
+            provisioning_range(&cmd, &iovecs, disk_blocks),
+            Err(SCSI_SENSE_LBA_OUT_OF_RANGE)
+        );
+
+        // WRITE SAME(10) of zero blocks is invalid as WSNZ is set.
+        let mut cmd = scsi_cmd(&[WRITE_SAME_10, 0x8, 0, 0, 0, 8, 0, 0, 0, 0]);
+        cmd.lba = 8;
+        assert_eq!(
+            provisioning_range(&cmd, &[], disk_blocks),
+            Err(SCSI_SENSE_INVALID_FIELD)
+        );
+        BigEndian::write_u16(&mut cmd.buf[7..9], 4);
+        assert_eq!(provisioning_range(&cmd, &[], disk_blocks), Ok((8, 4, true)));
+    }
+
+    fn scsi_hd(read_only: bool) -> Arc<Mutex<ScsiDevice>> {
+        let config = ScsiDevConfig {
+            id: "scsi-hd0".to_string(),
+            read_only,
+            ..Default::default()
+        };
+        let dev = ScsiDevice::new(config, SCSI_TYPE_DISK, Arc::new(Mutex::new(HashMap::new())));
+        Arc::new(Mutex::new(dev))
+    }
+
+    #[test]
+    fn test_scsi_provisioning_vpd() {
+        let supported = scsi_cmd(&[INQUIRY, 1, 0, 0, 0xff, 0]);
+        let block_limits = scsi_cmd(&[INQUIRY, 1, 0xb0, 0, 0xff, 0]);
+        let provisioning = scsi_cmd(&[INQUIRY, 1, 0xb2, 0, 0xff, 0]);
+
+        let dev = scsi_hd(false);
+        let outbuf = scsi_command_emulate_vpd_page(&supported, &dev).unwrap();
+        assert!(outbuf[4..].contains(&0xb0));
+        assert!(outbuf[4..].contains(&0xb2));
+
+        let outbuf = scsi_command_emulate_vpd_page(&block_limits, &dev).unwrap();
+        assert_eq!(outbuf.len(), 64);
+        assert_eq!(outbuf[3], 60);
+        // WSNZ.
+        assert_eq!(outbuf[4], 1);
+        assert_eq!(
+            BigEndian::read_u32(&outbuf[20..24]),
+            SCSI_MAX_PROVISIONING_BLOCKS
+        );
+        assert_eq!(BigEndian::read_u32(&outbuf[24..28]), 1);
+        assert_eq!(BigEndian::read_u32(&outbuf[28..32]), 1);
+        assert_eq!(
+            BigEndian::read_u64(&outbuf[36..44]),
+            SCSI_MAX_PROVISIONING_BLOCKS as u64
+        );
+
+        let outbuf = scsi_command_emulate_vpd_page(&provisioning, &dev).unwrap();
+        assert_eq!(outbuf, vec![0, 0xb2, 0, 4, 0, 0xe0, 2, 0]);
+
+        // Read-only disk does not support unmapping.
+        let dev = scsi_hd(true);
+        let outbuf = scsi_command_emulate_vpd_page(&block_limits, &dev).unwrap();
+        assert_eq!(&outbuf[20..32], &[0_u8; 12]);
+        assert_eq!(
+            BigEndian::read_u64(&outbuf[36..44]),
+            SCSI_MAX_PROVISIONING_BLOCKS as u64
+        );
+        let outbuf = scsi_command_emulate_vpd_page(&provisioning, &dev).unwrap();
+        assert_eq!(outbuf, vec![0, 0xb2, 0, 4, 0, 0, 0, 0]);
+    }
+}
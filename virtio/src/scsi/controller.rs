@@ -28,17 +28,18 @@ use super::super::{
 };
 use crate::ScsiBus::{
     virtio_scsi_get_lun, ScsiBus, ScsiRequest, ScsiSense, CHECK_CONDITION, EMULATE_SCSI_OPS, GOOD,
-    SCSI_SENSE_INVALID_OPCODE,
+    PASSTHROUGH_SCSI_OPS, RESERVATION_CONFLICT, SCSI_SENSE_INVALID_OPCODE,
 };
 use crate::VirtioError;
 use address_space::{AddressSpace, GuestAddress};
+use block_backend::stats::BlockAcctCookie;
 use log::{debug, error, info};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use machine_manager::{
     config::{ScsiCntlrConfig, VIRTIO_SCSI_MAX_LUN, VIRTIO_SCSI_MAX_TARGET},
     event_loop::EventLoop,
 };
-use util::aio::{Aio, AioCb, AioEngine, Iovec, OpCode};
+use util::aio::{AioCb, Iovec};
 use util::byte_code::ByteCode;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
@@ -314,8 +315,7 @@ impl VirtioDevice for ScsiCntlr {
         let queues_num = queues.len();
         for cmd_queue in queues.iter().take(queues_num).skip(2) {
             if let Some(bus) = &self.bus {
-                let mut cmd_handler = ScsiCmdHandler {
-                    aio: None,
+                let cmd_handler = ScsiCmdHandler {
                     scsibus: bus.clone(),
                     queue: cmd_queue.clone(),
                     queue_evt: queue_evts.remove(0),
@@ -325,8 +325,6 @@ impl VirtioDevice for ScsiCntlr {
                     device_broken: self.broken.clone(),
                 };
 
-                cmd_handler.aio = Some(cmd_handler.build_aio()?);
-
                 let notifiers =
                     EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(cmd_handler)));
                 register_event_helper(
@@ -465,7 +463,7 @@ pub struct VirtioScsiRequest<T: Clone + ByteCode, U: Clone + ByteCode> {
     pub data_len: u32,
     _cdb_size: u32,
     _sense_size: u32,
-    mode: ScsiXferMode,
+    pub mode: ScsiXferMode,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
     /// resp GPA.
@@ -791,8 +789,6 @@ pub struct ScsiCmdHandler {
     interrupt_cb: Arc<VirtioInterrupt>,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
-    /// Aio context.
-    aio: Option<Box<Aio<ScsiCompleteCb>>>,
     /// Device is broken or not.
     device_broken: Arc<AtomicBool>,
 }
@@ -817,30 +813,6 @@ impl EventNotifierHelper for ScsiCmdHandler {
         });
         notifiers.push(build_event_notifier(h_locked.queue_evt.as_raw_fd(), h));
 
-        // Register event notifier for aio.
-        if let Some(ref aio) = h_locked.aio {
-            let h_clone = handler.clone();
-            let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
-                read_fd(fd);
-                let mut h_lock = h_clone.lock().unwrap();
-                if h_lock.device_broken.load(Ordering::SeqCst) {
-                    return None;
-                }
-                if let Some(aio) = &mut h_lock.aio {
-                    if let Err(ref e) = aio.handle_complete() {
-                        error!("Failed to handle aio, {:?}", e);
-                        report_virtio_error(
-                            h_lock.interrupt_cb.clone(),
-                            h_lock.driver_features,
-                            &h_lock.device_broken,
-                        );
-                    }
-                }
-                None
-            });
-            notifiers.push(build_event_notifier(aio.fd.as_raw_fd(), h));
-        }
-
         notifiers
     }
 }
@@ -919,10 +891,26 @@ impl ScsiCmdHandler {
                 continue;
             };
 
-            let scsi_device_lock = scsidevice.lock().unwrap();
-            if scsi_req.opstype == EMULATE_SCSI_OPS {
-                let lun = scsi_device_lock.config.lun;
-                drop(scsi_device_lock);
+            let lun = scsidevice.lock().unwrap().config.lun;
+            // The unit attention of device is not reported to target request.
+            if req_lun_id == lun && scsi_req.report_unit_attention(&self.mem_space)? {
+                continue;
+            }
+            if req_lun_id == lun && scsi_req.report_no_medium(&self.mem_space)? {
+                continue;
+            }
+            if req_lun_id == lun && scsi_req.report_reservation_conflict(&self.mem_space)? {
+                continue;
+            }
+            if req_lun_id == lun && scsi_req.persistent_reserve_execute(&self.mem_space)? {
+                continue;
+            }
+
+            if scsi_req.opstype == PASSTHROUGH_SCSI_OPS && req_lun_id == lun {
+                scsi_req.passthrough_execute(&self.mem_space)?;
+            } else if scsi_req.opstype == EMULATE_SCSI_OPS
+                || scsi_req.opstype == PASSTHROUGH_SCSI_OPS
+            {
                 let scsicompletecb = ScsiCompleteCb::new(
                     self.mem_space.clone(),
                     Arc::new(Mutex::new(scsi_req.clone())),
@@ -930,71 +918,57 @@ impl ScsiCmdHandler {
                 // If found device's lun id is not equal to request lun id, this request is a target request.
                 scsi_req.emulate_execute(scsicompletecb, req_lun_id, lun)?;
             } else {
-                let direct = scsi_device_lock.config.direct;
-                let disk_img = scsi_device_lock.disk_image.as_ref().unwrap().clone();
-                let req_align = scsi_device_lock.req_align;
-                let buf_align = scsi_device_lock.buf_align;
-                drop(scsi_device_lock);
-
                 let scsicompletecb = ScsiCompleteCb::new(
                     self.mem_space.clone(),
                     Arc::new(Mutex::new(scsi_req.clone())),
                 );
-                if let Some(ref mut aio) = self.aio {
-                    let aiocb = AioCb {
-                        direct,
-                        req_align,
-                        buf_align,
-                        file_fd: disk_img.as_raw_fd(),
-                        opcode: OpCode::Noop,
-                        iovec: Vec::new(),
-                        offset: 0,
-                        nbytes: 0,
-                        user_data: 0,
-                        iocompletecb: scsicompletecb,
-                    };
-                    scsi_req.execute(aio, aiocb)?;
-                    aio.flush_request()?;
-                }
+                scsi_req.execute(scsicompletecb)?;
             }
         }
 
         Ok(())
     }
+}
 
-    fn complete_func(aiocb: &AioCb<ScsiCompleteCb>, ret: i64) -> Result<()> {
-        let complete_cb = &aiocb.iocompletecb;
-        let request = &aiocb.iocompletecb.req.lock().unwrap();
-        let mut virtio_scsi_req = request.virtioscsireq.lock().unwrap();
-
-        virtio_scsi_req.resp.response = if ret < 0 {
-            VIRTIO_SCSI_S_FAILURE
-        } else {
-            VIRTIO_SCSI_S_OK
-        };
-
-        virtio_scsi_req.resp.status = GOOD;
-        virtio_scsi_req.resp.resid = 0;
-        virtio_scsi_req.resp.sense_len = 0;
-        virtio_scsi_req.complete(&complete_cb.mem_space)
-    }
-
-    fn build_aio(&self) -> Result<Box<Aio<ScsiCompleteCb>>> {
-        Ok(Box::new(Aio::new(
-            Arc::new(Self::complete_func),
-            AioEngine::Off,
-        )?))
+/// Complete function of the aio of scsi device.
+pub fn aio_complete_cb(aiocb: &AioCb<ScsiCompleteCb>, ret: i64) -> Result<()> {
+    let complete_cb = &aiocb.iocompletecb;
+    if let Some(acct) = complete_cb.acct.as_ref() {
+        acct.done(ret < 0);
     }
+    let request = &aiocb.iocompletecb.req.lock().unwrap();
+    let mut virtio_scsi_req = request.virtioscsireq.lock().unwrap();
+
+    virtio_scsi_req.resp.response = if ret < 0 && ret != -(libc::EBADE as i64) {
+        VIRTIO_SCSI_S_FAILURE
+    } else {
+        VIRTIO_SCSI_S_OK
+    };
+
+    // The host device reports EBADE for reservation conflict.
+    virtio_scsi_req.resp.status = match ret == -(libc::EBADE as i64) {
+        true => RESERVATION_CONFLICT,
+        false => GOOD,
+    };
+    virtio_scsi_req.resp.resid = 0;
+    virtio_scsi_req.resp.sense_len = 0;
+    virtio_scsi_req.complete(&complete_cb.mem_space)
 }
 
 #[derive(Clone)]
 pub struct ScsiCompleteCb {
     pub mem_space: Arc<AddressSpace>,
     req: Arc<Mutex<ScsiRequest>>,
+    /// Used to account the IO of request.
+    pub acct: Option<BlockAcctCookie>,
 }
 
 impl ScsiCompleteCb {
     fn new(mem_space: Arc<AddressSpace>, req: Arc<Mutex<ScsiRequest>>) -> Self {
-        ScsiCompleteCb { mem_space, req }
+        ScsiCompleteCb {
+            mem_space,
+            req,
+            acct: None,
+        }
     }
 }
@@ -12,13 +12,33 @@
 
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
 use std::sync::{Arc, Mutex, Weak};
 
 use anyhow::{bail, Context, Result};
 
-use crate::ScsiBus::ScsiBus;
-use machine_manager::config::{DriveFile, ScsiDevConfig, VmConfig};
+use crate::ScsiBus::{
+    ScsiBus, ScsiSense, GESN_EC_EJECTREQUEST, GESN_EC_MEDIAREMOVAL, GESN_EC_NEWMEDIA,
+    GESN_EC_NOCHG, SCSI_SENSE_CAPACITY_CHANGED, SCSI_SENSE_MEDIUM_CHANGED,
+    SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM,
+};
+use crate::ScsiCntlr::{aio_complete_cb, ScsiCompleteCb};
+use crate::ScsiGeneric::SgDevice;
+use crate::ScsiReservation::{PrFileManager, PrHelperManager, PrManager};
+use block_backend::dirty_bitmap::{
+    add_named_bitmap, remove_named_bitmap, restore_persistent_bitmaps, store_persistent_bitmaps,
+    DirtyBitmap,
+};
+use block_backend::mirror::{MirrorJob, MirrorPivotFn, MirrorReleaseFn, MirrorSync};
+use block_backend::nbd::server::NbdExport;
+use block_backend::stats::BlockAcctStats;
+use block_backend::{create_block_backend, create_nbd_backend, BlockDriverOps, BlockProperty};
+use machine_manager::config::{
+    is_nbd_path, DiskFormat, DriveFile, NbdUri, ScsiDevConfig, VmConfig,
+};
+use machine_manager::event;
+use machine_manager::qmp::qmp_schema::DeviceTrayMoved;
+use machine_manager::qmp::QmpChannel;
+use util::aio::{Aio, AioEngine};
 
 /// SCSI DEVICE TYPES.
 pub const SCSI_TYPE_DISK: u32 = 0x00;
@@ -57,6 +77,18 @@ pub const SCSI_DISK_DEFAULT_BLOCK_SIZE: u32 = 1 << SCSI_DISK_DEFAULT_BLOCK_SIZE_
 pub const SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT: u32 = 11;
 pub const SCSI_CDROM_DEFAULT_BLOCK_SIZE: u32 = 1 << SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT;
 
+/// How the commands of scsi device are processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScsiDevKind {
+    /// scsi-hd and scsi-cd, the commands are emulated on the image file.
+    Emulated,
+    /// scsi-generic, the commands are passed through to the host sg device.
+    Generic,
+    /// scsi-block, READ and WRITE commands are processed on the host block
+    /// device as image file, and the others are passed through to it.
+    Block,
+}
+
 #[derive(Clone, Default)]
 pub struct ScsiDevState {
     /// Features which the scsi device supports.
@@ -92,22 +124,34 @@ pub struct ScsiDevice {
     pub config: ScsiDevConfig,
     /// State of the scsi device.
     pub state: ScsiDevState,
-    /// Image file opened.
-    pub disk_image: Option<Arc<File>>,
-    /// The align requirement of request(offset/len).
-    pub req_align: u32,
-    /// The align requirement of buffer(iova_base).
-    pub buf_align: u32,
+    /// The block backend opened by the scsi device.
+    pub block_backend: Option<Arc<Mutex<dyn BlockDriverOps<ScsiCompleteCb>>>>,
     /// Number of sectors of the image file.
     pub disk_sectors: u64,
     /// Scsi Device block size.
     pub block_size: u32,
     /// Scsi device type.
     pub scsi_type: u32,
+    /// Scsi device is emulated or passes through the commands to host.
+    pub kind: ScsiDevKind,
+    /// The host device which the commands are passed through to.
+    pub sg_dev: Option<Arc<SgDevice>>,
     /// Scsi Bus attached to.
     pub parent_bus: Weak<Mutex<ScsiBus>>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// Statistics of the IO of device.
+    pub stats: Arc<BlockAcctStats>,
+    /// Unit attention condition reported to the next command of device.
+    pub unit_attention: Option<ScsiSense>,
+    /// The tray of cd-rom is open.
+    pub tray_open: bool,
+    /// The removal of medium is prevented by guest.
+    pub locked: bool,
+    /// Media event code reported to the next GET EVENT STATUS NOTIFICATION.
+    pub media_event: u8,
+    /// Manager of the persistent reservations of device.
+    pub pr_manager: Option<Arc<dyn PrManager>>,
 }
 
 impl ScsiDevice {
@@ -119,18 +163,39 @@ impl ScsiDevice {
         ScsiDevice {
             config,
             state: ScsiDevState::new(),
-            disk_image: None,
-            req_align: 1,
-            buf_align: 1,
+            block_backend: None,
             disk_sectors: 0,
             block_size: 0,
             scsi_type,
+            kind: ScsiDevKind::Emulated,
+            sg_dev: None,
             parent_bus: Weak::new(),
             drive_files,
+            stats: Arc::new(BlockAcctStats::default()),
+            unit_attention: None,
+            tray_open: false,
+            locked: false,
+            media_event: GESN_EC_NOCHG,
+            pr_manager: None,
         }
     }
 
+    /// Create the scsi device passing through the commands to host device, the
+    /// device type is the type of host device.
+    pub fn new_passthrough(
+        config: ScsiDevConfig,
+        kind: ScsiDevKind,
+        drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    ) -> ScsiDevice {
+        let mut dev = ScsiDevice::new(config, SCSI_TYPE_NOT_PRESENT, drive_files);
+        dev.kind = kind;
+        dev
+    }
+
     pub fn realize(&mut self) -> Result<()> {
+        if self.kind != ScsiDevKind::Emulated {
+            return self.realize_passthrough();
+        }
         match self.scsi_type {
             SCSI_TYPE_DISK => {
                 self.block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE;
@@ -150,27 +215,371 @@ impl ScsiDevice {
         }
         let mut disk_size = DUMMY_IMG_SIZE;
 
+        self.block_backend = None;
         if !self.config.path_on_host.is_empty() {
-            self.disk_image = None;
-
-            let drive_files = self.drive_files.lock().unwrap();
-            let mut file = VmConfig::fetch_drive_file(&drive_files, &self.config.path_on_host)?;
-            disk_size = file
-                .seek(SeekFrom::End(0))
-                .with_context(|| "Failed to seek the end for scsi device")?;
-            self.disk_image = Some(Arc::new(file));
-
-            let alignments = VmConfig::fetch_drive_align(&drive_files, &self.config.path_on_host)?;
-            self.req_align = alignments.0;
-            self.buf_align = alignments.1;
-        } else {
-            self.disk_image = None;
-            self.req_align = 1;
-            self.buf_align = 1;
+            let block_backend = self.open_image(&self.config.path_on_host, self.config.format)?;
+            disk_size = block_backend.lock().unwrap().disk_size()?;
+            if self.is_writable() {
+                restore_persistent_bitmaps(
+                    &mut *block_backend.lock().unwrap(),
+                    &self.config.path_on_host,
+                )?;
+            }
+            self.block_backend = Some(block_backend);
         }
 
         self.disk_sectors = disk_size >> SECTOR_SHIFT;
+        self.pr_manager = self.create_pr_manager()?;
+
+        Ok(())
+    }
+
+    fn realize_passthrough(&mut self) -> Result<()> {
+        let path = self.config.path_on_host.clone();
+        if is_nbd_path(&path) {
+            bail!(
+                "Scsi passthrough device {} can not use NBD export",
+                self.config.id
+            );
+        }
+        let file = VmConfig::fetch_drive_file(&self.drive_files.lock().unwrap(), &path)?;
+        let sg_dev = SgDevice::new(file, &path)?;
+        self.scsi_type = sg_dev.device_type()?;
+        if self.kind == ScsiDevKind::Block {
+            if self.scsi_type != SCSI_TYPE_DISK {
+                bail!(
+                    "Scsi-block only supports disk, use scsi-generic for device type {}",
+                    self.scsi_type
+                );
+            }
+            self.block_size = sg_dev.block_size()?;
+            let block_backend = self.open_image(&path, DiskFormat::Raw)?;
+            self.disk_sectors = block_backend.lock().unwrap().disk_size()? >> SECTOR_SHIFT;
+            self.block_backend = Some(block_backend);
+        }
+        self.sg_dev = Some(Arc::new(sg_dev));
+        self.pr_manager = self.create_pr_manager()?;
+        Ok(())
+    }
+
+    /// Create the manager of persistent reservations configured for device,
+    /// the reservations are passed through to host if there is none.
+    fn create_pr_manager(&self) -> Result<Option<Arc<dyn PrManager>>> {
+        if self.config.pr_manager.is_none() && self.config.pr_initiator.is_none() {
+            return Ok(None);
+        }
+        let path = &self.config.path_on_host;
+        if self.kind == ScsiDevKind::Emulated && self.scsi_type != SCSI_TYPE_DISK {
+            bail!(
+                "Persistent reservation is not supported by scsi device {}",
+                self.config.id
+            );
+        }
+        if path.is_empty() || is_nbd_path(path) {
+            bail!(
+                "Persistent reservation of scsi device {} needs a host file",
+                self.config.id
+            );
+        }
+        if let Some(socket) = &self.config.pr_manager {
+            let file = VmConfig::fetch_drive_file(&self.drive_files.lock().unwrap(), path)?;
+            let pr_manager = PrHelperManager::new(socket, file)?;
+            return Ok(Some(Arc::new(pr_manager)));
+        }
+        if self.kind != ScsiDevKind::Emulated {
+            bail!(
+                "Scsi passthrough device {} does not support pr-initiator, use pr-manager",
+                self.config.id
+            );
+        }
+        let initiator = self.config.pr_initiator.as_ref().unwrap();
+        Ok(Some(Arc::new(PrFileManager::new(path, initiator)?)))
+    }
+
+    /// Open the image at `path`, which is the export of NBD server or the file
+    /// registered to drive files.
+    fn open_image(
+        &self,
+        path: &str,
+        format: DiskFormat,
+    ) -> Result<Arc<Mutex<dyn BlockDriverOps<ScsiCompleteCb>>>> {
+        let aio = Aio::new(Arc::new(aio_complete_cb), AioEngine::Off)?;
+        if is_nbd_path(path) {
+            let uri = path.parse::<NbdUri>()?;
+            let conf = BlockProperty {
+                id: self.config.id.clone(),
+                format,
+                iothread: None,
+                direct: false,
+                req_align: 1,
+                buf_align: 1,
+                copy_on_read: false,
+            };
+            create_nbd_backend(&uri, !self.is_writable(), aio, conf)
+        } else {
+            let (file, conf) = self.fetch_image_file(path, format)?;
+            create_block_backend(file, aio, conf)
+        }
+    }
+
+    /// Get the image file at `path` from drive files, and the properties used
+    /// to open it for the device.
+    fn fetch_image_file(&self, path: &str, format: DiskFormat) -> Result<(File, BlockProperty)> {
+        let drive_files = self.drive_files.lock().unwrap();
+        let file = VmConfig::fetch_drive_file(&drive_files, path)?;
+        let alignments = VmConfig::fetch_drive_align(&drive_files, path)?;
+        let conf = BlockProperty {
+            id: self.config.id.clone(),
+            format,
+            iothread: None,
+            direct: self.config.direct,
+            req_align: alignments.0,
+            buf_align: alignments.1,
+            copy_on_read: false,
+        };
+        Ok((file, conf))
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.scsi_type == SCSI_TYPE_DISK && !self.config.read_only
+    }
+
+    fn backend(&self) -> Result<&Arc<Mutex<dyn BlockDriverOps<ScsiCompleteCb>>>> {
+        // The writes passed through to host are not tracked by the block backend.
+        if self.kind != ScsiDevKind::Emulated {
+            bail!(
+                "Image of scsi passthrough device {} is not managed",
+                self.config.id
+            );
+        }
+        self.block_backend
+            .as_ref()
+            .with_context(|| format!("No image is opened by scsi device {}", self.config.id))
+    }
+
+    /// Add a dirty bitmap to record the areas of disk written by guest.
+    pub fn add_dirty_bitmap(
+        &self,
+        name: &str,
+        granularity: Option<u64>,
+        persistent: bool,
+    ) -> Result<()> {
+        if !self.is_writable() {
+            bail!("Scsi device {} is read only", self.config.id);
+        }
+        add_named_bitmap(
+            &mut *self.backend()?.lock().unwrap(),
+            &self.config.path_on_host,
+            name,
+            granularity,
+            persistent,
+        )
+    }
 
+    pub fn remove_dirty_bitmap(&self, name: &str) -> Result<()> {
+        remove_named_bitmap(
+            &mut *self.backend()?.lock().unwrap(),
+            &self.config.path_on_host,
+            name,
+        )
+    }
+
+    pub fn get_dirty_bitmap(&self, name: &str) -> Result<Arc<Mutex<DirtyBitmap>>> {
+        self.backend()?
+            .lock()
+            .unwrap()
+            .get_dirty_bitmap(name)
+            .with_context(|| format!("Dirty bitmap {} not found", name))
+    }
+
+    /// Store the persistent dirty bitmaps alongside the image when VM exits.
+    pub fn store_dirty_bitmaps(&self) -> Result<()> {
+        if let Some(backend) = self.block_backend.as_ref() {
+            let bitmaps = backend.lock().unwrap().named_dirty_bitmaps();
+            store_persistent_bitmaps(&self.config.path_on_host, &bitmaps, false)?;
+        }
+        Ok(())
+    }
+
+    /// Grow the disk to `size` bytes online. The guest is notified by the unit
+    /// attention of CAPACITY DATA HAS CHANGED reported to the next command.
+    pub fn resize(&mut self, size: u64) -> Result<()> {
+        if !self.is_writable() {
+            bail!("Scsi device {} is read only", self.config.id);
+        }
+        if size & (self.block_size as u64 - 1) != 0 {
+            bail!(
+                "Size {} is not aligned to block size {}",
+                size,
+                self.block_size
+            );
+        }
+        let mut locked_backend = self.backend()?.lock().unwrap();
+        locked_backend.drain_request()?;
+        locked_backend.resize(size)?;
+        drop(locked_backend);
+        self.disk_sectors = size >> SECTOR_SHIFT;
+        self.unit_attention = Some(SCSI_SENSE_CAPACITY_CHANGED);
+        Ok(())
+    }
+
+    /// The medium of cd-rom can be accessed by guest.
+    pub fn has_medium(&self) -> bool {
+        self.block_backend.is_some() && !self.tray_open
+    }
+
+    /// Open or close the tray of cd-rom, the management is notified by the
+    /// DEVICE_TRAY_MOVED event.
+    pub fn move_tray(&mut self, open: bool) {
+        if self.tray_open == open {
+            return;
+        }
+        self.tray_open = open;
+        let tray_moved = DeviceTrayMoved {
+            id: self.config.id.clone(),
+            tray_open: open,
+        };
+        event!(DeviceTrayMoved; tray_moved);
+    }
+
+    /// Check whether the medium of cd-rom can be removed by the management. If
+    /// it's locked by guest, the guest is requested to eject the medium.
+    fn check_removable(&mut self, force: bool) -> Result<()> {
+        if self.scsi_type != SCSI_TYPE_ROM || self.kind != ScsiDevKind::Emulated {
+            bail!("Scsi device {} is not removable", self.config.id);
+        }
+        if self.locked && !force {
+            self.media_event = GESN_EC_EJECTREQUEST;
+            bail!(
+                "Medium of scsi device {} is locked by guest",
+                self.config.id
+            );
+        }
         Ok(())
     }
+
+    /// Eject the medium of cd-rom and leave the tray open, the path of the image
+    /// closed is returned. The guest is notified by the media event and the unit
+    /// attention of MEDIUM NOT PRESENT.
+    pub fn eject(&mut self, force: bool) -> Result<String> {
+        self.check_removable(force)?;
+        if let Some(backend) = self.block_backend.as_ref() {
+            backend.lock().unwrap().drain_request()?;
+        }
+        self.block_backend = None;
+        self.disk_sectors = 0;
+        self.move_tray(true);
+        self.media_event = GESN_EC_MEDIAREMOVAL;
+        self.unit_attention = Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM);
+        Ok(std::mem::take(&mut self.config.path_on_host))
+    }
+
+    /// Replace the medium of cd-rom with the image at `path`, which has been
+    /// registered to drive files, and close the tray. The path of the image
+    /// replaced is returned. The guest is notified by the media event and the
+    /// unit attention of MEDIUM MAY HAVE CHANGED.
+    pub fn change_medium(&mut self, path: &str, format: DiskFormat, force: bool) -> Result<String> {
+        self.check_removable(force)?;
+        let block_backend = self.open_image(path, format)?;
+        let disk_size = block_backend.lock().unwrap().disk_size()?;
+        if let Some(backend) = self.block_backend.as_ref() {
+            backend.lock().unwrap().drain_request()?;
+        }
+        self.block_backend = Some(block_backend);
+        self.disk_sectors = disk_size >> SECTOR_SHIFT;
+        self.config.format = format;
+        let old_path = std::mem::replace(&mut self.config.path_on_host, path.to_string());
+        // The tray is opened to take out the old medium.
+        self.move_tray(true);
+        self.move_tray(false);
+        self.media_event = GESN_EC_NEWMEDIA;
+        self.unit_attention = Some(SCSI_SENSE_MEDIUM_CHANGED);
+        Ok(old_path)
+    }
+
+    /// Export the disk by the NBD server with the name `export`, `bitmap` is
+    /// the dirty bitmap exposed to clients.
+    pub fn nbd_export(
+        &self,
+        export: &str,
+        writable: bool,
+        bitmap: Option<&str>,
+    ) -> Result<NbdExport> {
+        if writable && !self.is_writable() {
+            bail!("Scsi device {} is read only", self.config.id);
+        }
+        let bitmap = match bitmap {
+            Some(name) => Some((name.to_string(), self.get_dirty_bitmap(name)?)),
+            None => None,
+        };
+        Ok(NbdExport::new(
+            export,
+            &self.config.id,
+            self.backend()?.clone(),
+            writable,
+            bitmap,
+        ))
+    }
+
+    /// Create the job to mirror the image of disk to the image at `target`,
+    /// which has been registered to drive files in read-write mode.
+    pub fn mirror_job(
+        &self,
+        target: &str,
+        target_format: DiskFormat,
+        sync: MirrorSync,
+        target_zeroed: bool,
+        pivot: MirrorPivotFn,
+        release: MirrorReleaseFn,
+    ) -> Result<MirrorJob<ScsiCompleteCb>> {
+        let block_backend = self.backend()?.clone();
+        let (file, conf) = self.fetch_image_file(target, target_format)?;
+        MirrorJob::new(
+            block_backend,
+            file,
+            conf,
+            sync,
+            target_zeroed,
+            pivot,
+            release,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine_manager::qmp::QmpChannel;
+
+    fn scsi_device(scsi_type: u32) -> ScsiDevice {
+        let config = ScsiDevConfig {
+            id: "scsi0".to_string(),
+            path_on_host: "/path/to/image".to_string(),
+            ..Default::default()
+        };
+        ScsiDevice::new(config, scsi_type, Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    #[test]
+    fn test_scsi_cd_eject() {
+        QmpChannel::object_init();
+        assert!(scsi_device(SCSI_TYPE_DISK).eject(true).is_err());
+
+        let mut dev = scsi_device(SCSI_TYPE_ROM);
+        dev.locked = true;
+        // The guest is requested to eject the medium locked.
+        assert!(dev.eject(false).is_err());
+        assert_eq!(dev.media_event, GESN_EC_EJECTREQUEST);
+        assert!(!dev.tray_open);
+        assert_eq!(dev.config.path_on_host, "/path/to/image");
+
+        assert_eq!(dev.eject(true).unwrap(), "/path/to/image");
+        assert!(dev.tray_open);
+        assert!(dev.config.path_on_host.is_empty());
+        assert_eq!(dev.media_event, GESN_EC_MEDIAREMOVAL);
+        assert_eq!(
+            dev.unit_attention,
+            Some(SCSI_SENSE_UNIT_ATTENTION_NO_MEDIUM)
+        );
+    }
 }
@@ -0,0 +1,240 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+
+use anyhow::{bail, Result};
+use byteorder::{BigEndian, ByteOrder};
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+use crate::ScsiBus::{GOOD, INQUIRY, READ_CAPACITY_10};
+use crate::ScsiCntlr::ScsiXferMode;
+use util::aio::Iovec;
+
+/// Ioctls of the sg driver, from <scsi/sg.h>.
+const SG_GET_VERSION_NUM: u64 = 0x2282;
+const SG_IO: u64 = 0x2285;
+
+/// The SG_IO v3 interface is supported since version 3.0.0 of sg driver.
+const SG_MIN_VERSION: i32 = 30000;
+
+/// Data transfer directions of SG_IO.
+const SG_DXFER_NONE: i32 = -1;
+const SG_DXFER_TO_DEV: i32 = -2;
+const SG_DXFER_FROM_DEV: i32 = -3;
+
+/// Timeout of the commands passed through, in milliseconds.
+const SG_IO_TIMEOUT: u32 = u32::MAX;
+
+/// Length of the standard INQUIRY data.
+const INQUIRY_DATA_LEN: usize = 36;
+/// Length of the READ CAPACITY(10) data.
+const READ_CAPACITY_10_DATA_LEN: usize = 8;
+
+/// Header of SG_IO request, `sg_io_hdr_t` in <scsi/sg.h>.
+#[repr(C)]
+struct SgIoHdr {
+    /// Always 'S' for SG_IO v3.
+    interface_id: i32,
+    dxfer_direction: i32,
+    cmd_len: u8,
+    /// Max length of sense buffer.
+    mx_sb_len: u8,
+    /// Number of entries of the iovec array `dxferp` points to, 0 means
+    /// `dxferp` is the data buffer.
+    iovec_count: u16,
+    dxfer_len: u32,
+    dxferp: u64,
+    cmdp: u64,
+    sbp: u64,
+    timeout: u32,
+    flags: u32,
+    pack_id: i32,
+    usr_ptr: u64,
+    /// Scsi status returned by device.
+    status: u8,
+    masked_status: u8,
+    msg_status: u8,
+    /// Length of sense data written.
+    sb_len_wr: u8,
+    host_status: u16,
+    driver_status: u16,
+    /// Number of bytes not transferred.
+    resid: i32,
+    duration: u32,
+    info: u32,
+}
+
+/// Result of the command passed through to host device.
+pub struct SgIoResult {
+    /// Scsi status of the command.
+    pub status: u8,
+    /// Length of sense data.
+    pub sense_len: u32,
+    /// Number of bytes not transferred.
+    pub resid: u32,
+    /// Error of the host adapter, 0 means no error.
+    pub host_status: u16,
+}
+
+/// Host scsi device to pass through the commands of guest by SG_IO, which is
+/// a sg character device or a block device of scsi disk.
+pub struct SgDevice {
+    file: File,
+    path: String,
+}
+
+impl SgDevice {
+    /// Check whether the host device at `path` supports SG_IO.
+    pub fn new(file: File, path: &str) -> Result<Self> {
+        let mut version: i32 = 0;
+        // SAFETY: file is valid and version is a valid i32.
+        let ret = unsafe { ioctl_with_mut_ref(&file, SG_GET_VERSION_NUM, &mut version) };
+        if ret < 0 {
+            bail!(
+                "Device {} does not support SG_IO: {}",
+                path,
+                std::io::Error::last_os_error()
+            );
+        }
+        if version < SG_MIN_VERSION {
+            bail!("Sg driver version {} of {} is too old", version, path);
+        }
+        Ok(Self {
+            file,
+            path: path.to_string(),
+        })
+    }
+
+    /// Execute the command `cdb` on host device, the data is transferred from
+    /// or to the buffers `iovecs` according to `mode`, and the sense data is
+    /// written to `sense`.
+    pub fn execute(
+        &self,
+        cdb: &[u8],
+        mode: ScsiXferMode,
+        iovecs: &[Iovec],
+        sense: &mut [u8],
+    ) -> Result<SgIoResult> {
+        let dxfer_len: u64 = iovecs.iter().map(|iov| iov.iov_len).sum();
+        let dxfer_direction = match mode {
+            _ if dxfer_len == 0 => SG_DXFER_NONE,
+            ScsiXferMode::ScsiXferToDev => SG_DXFER_TO_DEV,
+            ScsiXferMode::ScsiXferFromDev => SG_DXFER_FROM_DEV,
+            ScsiXferMode::ScsiXferNone => SG_DXFER_NONE,
+        };
+        let sg_iovecs: Vec<libc::iovec> = iovecs
+            .iter()
+            .map(|iov| libc::iovec {
+                iov_base: iov.iov_base as *mut libc::c_void,
+                iov_len: iov.iov_len as libc::size_t,
+            })
+            .collect();
+        let mut hdr = SgIoHdr {
+            interface_id: 'S' as i32,
+            dxfer_direction,
+            cmd_len: cdb.len() as u8,
+            mx_sb_len: sense.len().min(u8::MAX as usize) as u8,
+            iovec_count: sg_iovecs.len() as u16,
+            dxfer_len: dxfer_len as u32,
+            dxferp: sg_iovecs.as_ptr() as u64,
+            cmdp: cdb.as_ptr() as u64,
+            sbp: sense.as_mut_ptr() as u64,
+            timeout: SG_IO_TIMEOUT,
+            flags: 0,
+            pack_id: 0,
+            usr_ptr: 0,
+            status: 0,
+            masked_status: 0,
+            msg_status: 0,
+            sb_len_wr: 0,
+            host_status: 0,
+            driver_status: 0,
+            resid: 0,
+            duration: 0,
+            info: 0,
+        };
+        // SAFETY: file is valid, and the buffers referred by hdr live during the ioctl.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, SG_IO, &mut hdr) };
+        if ret < 0 {
+            bail!(
+                "Failed to pass through scsi command {:#x} to {}: {}",
+                cdb[0],
+                self.path,
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(SgIoResult {
+            status: hdr.status,
+            sense_len: hdr.sb_len_wr as u32,
+            resid: hdr.resid.max(0) as u32,
+            host_status: hdr.host_status,
+        })
+    }
+
+    /// Execute the command `cdb` which reads `len` bytes of data from device.
+    fn read_data(&self, cdb: &[u8], len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0_u8; len];
+        let iovecs = vec![Iovec::new(buf.as_mut_ptr() as u64, len as u64)];
+        let mut sense = [0_u8; 32];
+        let result = self.execute(cdb, ScsiXferMode::ScsiXferFromDev, &iovecs, &mut sense)?;
+        if result.status != GOOD || result.host_status != 0 {
+            bail!(
+                "Scsi command {:#x} failed on {}, status {:#x}, host status {:#x}, sense {:?}",
+                cdb[0],
+                self.path,
+                result.status,
+                result.host_status,
+                &sense[..result.sense_len as usize]
+            );
+        }
+        Ok(buf)
+    }
+
+    /// Get the peripheral device type of host device by INQUIRY.
+    pub fn device_type(&self) -> Result<u32> {
+        let cdb = [INQUIRY, 0, 0, 0, INQUIRY_DATA_LEN as u8, 0];
+        let data = self.read_data(&cdb, INQUIRY_DATA_LEN)?;
+        // Byte[0]: Bits[5-7]: Peripheral Qualifier. Bits[0-4]: Peripheral Device Type.
+        Ok((data[0] & 0x1f) as u32)
+    }
+
+    /// Get the logical block length of host device by READ CAPACITY(10).
+    pub fn block_size(&self) -> Result<u32> {
+        let mut cdb = [0_u8; 10];
+        cdb[0] = READ_CAPACITY_10;
+        let data = self.read_data(&cdb, READ_CAPACITY_10_DATA_LEN)?;
+        // Bytes[0-3]: Returned Logical Block Address. Bytes[4-7]: Logical Block Length In Bytes.
+        let block_size = BigEndian::read_u32(&data[4..8]);
+        if !block_size.is_power_of_two() || block_size < 512 {
+            bail!("Invalid block size {} of {}", block_size, self.path);
+        }
+        Ok(block_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use super::*;
+
+    #[test]
+    fn test_sg_device_check() {
+        // The regular file does not support SG_IO.
+        let path = "/tmp/test_sg_device_check.img";
+        let file = File::create(path).unwrap();
+        file.set_len(1 << 20).unwrap();
+        assert!(SgDevice::new(file, path).is_err());
+        remove_file(path).unwrap();
+    }
+}
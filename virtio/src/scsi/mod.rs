@@ -13,3 +13,5 @@
 pub mod bus;
 pub mod controller;
 pub mod disk;
+pub mod generic;
+pub mod reservation;
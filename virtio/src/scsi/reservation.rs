@@ -0,0 +1,791 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use log::error;
+
+use crate::ScsiBus::{
+    ScsiSense, CHECK_CONDITION, GOOD, PERSISTENT_RESERVE_IN, PERSISTENT_RESERVE_OUT,
+    RESERVATION_CONFLICT, SCSI_SENSE_INVALID_FIELD, SCSI_SENSE_INVALID_OPCODE,
+    SCSI_SENSE_INVALID_PARAM, SCSI_SENSE_INVALID_PARAM_LEN, SCSI_SENSE_INVALID_RELEASE,
+};
+use util::unix::UnixSock;
+
+/// Service actions of PERSISTENT RESERVE IN.
+const PR_IN_READ_KEYS: u8 = 0x00;
+const PR_IN_READ_RESERVATION: u8 = 0x01;
+const PR_IN_REPORT_CAPABILITIES: u8 = 0x02;
+
+/// Service actions of PERSISTENT RESERVE OUT.
+const PR_OUT_REGISTER: u8 = 0x00;
+const PR_OUT_RESERVE: u8 = 0x01;
+const PR_OUT_RELEASE: u8 = 0x02;
+const PR_OUT_CLEAR: u8 = 0x03;
+const PR_OUT_PREEMPT: u8 = 0x04;
+const PR_OUT_PREEMPT_AND_ABORT: u8 = 0x05;
+const PR_OUT_REGISTER_AND_IGNORE: u8 = 0x06;
+
+/// Types of persistent reservation.
+const PR_TYPE_WRITE_EXCLUSIVE: u8 = 0x01;
+const PR_TYPE_EXCLUSIVE_ACCESS: u8 = 0x03;
+const PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY: u8 = 0x05;
+const PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY: u8 = 0x06;
+const PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS: u8 = 0x07;
+const PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS: u8 = 0x08;
+
+/// Length of the parameter list of PERSISTENT RESERVE OUT.
+const PR_OUT_PARAM_LEN: usize = 24;
+/// Byte[20] bit3 of the parameter list: SPEC_I_PT.
+const PR_OUT_SPEC_I_PT: u8 = 0x08;
+/// Length of the data of REPORT CAPABILITIES.
+const PR_CAPABILITIES_LEN: usize = 8;
+
+/// Header of the file storing reservation state.
+const PR_FILE_MAGIC: u32 = 0x5250_5653;
+const PR_FILE_VERSION: u32 = 1;
+
+/// Sizes of the fields in the protocol of pr-helper.
+const PR_HELPER_CDB_SIZE: usize = 16;
+const PR_HELPER_SENSE_SIZE: usize = 96;
+const PR_HELPER_DATA_SIZE: usize = 8192;
+/// Response header of pr-helper: result, data size and sense data.
+const PR_HELPER_RESP_SIZE: usize = 8 + PR_HELPER_SENSE_SIZE;
+
+/// Get the path of the file storing the persistent reservation state of image,
+/// which is placed alongside the image.
+pub fn pr_file_path(image: &str) -> String {
+    format!("{}.pr", image)
+}
+
+/// Result of the command of persistent reservation.
+pub struct PrResponse {
+    /// Scsi status of the command.
+    pub status: u8,
+    pub sense: Option<ScsiSense>,
+    /// Data returned by PERSISTENT RESERVE IN.
+    pub data: Vec<u8>,
+}
+
+impl PrResponse {
+    fn good(data: Vec<u8>) -> Self {
+        PrResponse {
+            status: GOOD,
+            sense: None,
+            data,
+        }
+    }
+
+    fn conflict() -> Self {
+        PrResponse {
+            status: RESERVATION_CONFLICT,
+            sense: None,
+            data: Vec::new(),
+        }
+    }
+
+    fn check_condition(sense: ScsiSense) -> Self {
+        PrResponse {
+            status: CHECK_CONDITION,
+            sense: Some(sense),
+            data: Vec::new(),
+        }
+    }
+}
+
+/// Manager doing persistent reservations for scsi device.
+pub trait PrManager: Send + Sync {
+    /// Execute PERSISTENT RESERVE IN or OUT `cdb`, `param` is the parameter
+    /// list of PERSISTENT RESERVE OUT.
+    fn execute(&self, cdb: &[u8], param: &[u8]) -> Result<PrResponse>;
+
+    /// Check whether the medium can be accessed by the device, `write` is
+    /// true for the commands modifying the medium.
+    fn check_access(&self, _write: bool) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+fn is_all_registrants(pr_type: u8) -> bool {
+    matches!(
+        pr_type,
+        PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS | PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS
+    )
+}
+
+fn is_valid_type(pr_type: u8) -> bool {
+    matches!(
+        pr_type,
+        PR_TYPE_WRITE_EXCLUSIVE
+            | PR_TYPE_EXCLUSIVE_ACCESS
+            | PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY
+            | PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY
+            | PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS
+            | PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS
+    )
+}
+
+/// Persistent reservation state of logical unit shared by initiators.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct PrState {
+    /// Counter of the changes of registrations.
+    generation: u32,
+    /// Registered initiators and their reservation keys.
+    registrations: Vec<(String, u64)>,
+    /// Initiator holding the reservation and type of the reservation.
+    reservation: Option<(String, u8)>,
+}
+
+impl PrState {
+    fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let mut state = PrState::default();
+        if buf.is_empty() {
+            return Ok(state);
+        }
+        let mut pos = 0;
+        let mut take = |len: usize| -> Result<&[u8]> {
+            if pos + len > buf.len() {
+                bail!("Reservation state is truncated");
+            }
+            pos += len;
+            Ok(&buf[pos - len..pos])
+        };
+        if LittleEndian::read_u32(take(4)?) != PR_FILE_MAGIC
+            || LittleEndian::read_u32(take(4)?) != PR_FILE_VERSION
+        {
+            bail!("Invalid header of reservation state");
+        }
+        state.generation = LittleEndian::read_u32(take(4)?);
+        let nr_regs = LittleEndian::read_u32(take(4)?);
+        for _ in 0..nr_regs {
+            let key = LittleEndian::read_u64(take(8)?);
+            let len = LittleEndian::read_u16(take(2)?) as usize;
+            let initiator = String::from_utf8(take(len)?.to_vec())?;
+            state.registrations.push((initiator, key));
+        }
+        if take(1)?[0] != 0 {
+            let pr_type = take(1)?[0];
+            let len = LittleEndian::read_u16(take(2)?) as usize;
+            let holder = String::from_utf8(take(len)?.to_vec())?;
+            state.reservation = Some((holder, pr_type));
+        }
+        Ok(state)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let put_u32 = |buf: &mut Vec<u8>, val: u32| {
+            let mut bytes = [0_u8; 4];
+            LittleEndian::write_u32(&mut bytes, val);
+            buf.extend_from_slice(&bytes);
+        };
+        let put_str = |buf: &mut Vec<u8>, val: &str| {
+            let mut bytes = [0_u8; 2];
+            LittleEndian::write_u16(&mut bytes, val.len() as u16);
+            buf.extend_from_slice(&bytes);
+            buf.extend_from_slice(val.as_bytes());
+        };
+        put_u32(&mut buf, PR_FILE_MAGIC);
+        put_u32(&mut buf, PR_FILE_VERSION);
+        put_u32(&mut buf, self.generation);
+        put_u32(&mut buf, self.registrations.len() as u32);
+        for (initiator, key) in &self.registrations {
+            let mut bytes = [0_u8; 8];
+            LittleEndian::write_u64(&mut bytes, *key);
+            buf.extend_from_slice(&bytes);
+            put_str(&mut buf, initiator);
+        }
+        match &self.reservation {
+            Some((holder, pr_type)) => {
+                buf.push(1);
+                buf.push(*pr_type);
+                put_str(&mut buf, holder);
+            }
+            None => buf.push(0),
+        }
+        buf
+    }
+
+    fn key_of(&self, initiator: &str) -> Option<u64> {
+        self.registrations
+            .iter()
+            .find(|(name, _)| name == initiator)
+            .map(|(_, key)| *key)
+    }
+
+    /// Every registrant holds the reservation of all registrants type.
+    fn is_holder(&self, initiator: &str) -> bool {
+        match &self.reservation {
+            Some((_, pr_type)) if is_all_registrants(*pr_type) => self.key_of(initiator).is_some(),
+            Some((holder, _)) => holder == initiator,
+            None => false,
+        }
+    }
+
+    fn check_access(&self, initiator: &str, write: bool) -> bool {
+        let pr_type = match &self.reservation {
+            Some((_, pr_type)) => *pr_type,
+            None => return true,
+        };
+        if self.is_holder(initiator) {
+            return true;
+        }
+        let registered = self.key_of(initiator).is_some();
+        match pr_type {
+            PR_TYPE_WRITE_EXCLUSIVE => !write,
+            PR_TYPE_EXCLUSIVE_ACCESS => false,
+            PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY | PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS => {
+                !write || registered
+            }
+            _ => registered,
+        }
+    }
+
+    /// Remove the registrations with `key` except the one of `initiator`,
+    /// return the number of the removed ones.
+    fn remove_key(&mut self, key: u64, initiator: &str) -> usize {
+        let before = self.registrations.len();
+        self.registrations
+            .retain(|(name, k)| *k != key || name == initiator);
+        before - self.registrations.len()
+    }
+
+    /// The reservation is released when its holder is unregistered.
+    fn drop_stale_reservation(&mut self) {
+        let stale = match &self.reservation {
+            Some((_, pr_type)) if is_all_registrants(*pr_type) => self.registrations.is_empty(),
+            Some((holder, _)) => self.key_of(holder).is_none(),
+            None => false,
+        };
+        if stale {
+            self.reservation = None;
+        }
+    }
+
+    fn persistent_reserve_in(&self, cdb: &[u8]) -> PrResponse {
+        let mut data = vec![0_u8; 8];
+        // Byte[0-3]: PRgeneration.
+        BigEndian::write_u32(&mut data[0..4], self.generation);
+        // Byte[1] bits[0-4]: Service action.
+        match cdb[1] & 0x1f {
+            PR_IN_READ_KEYS => {
+                // Byte[4-7]: Additional length. Byte[8-n]: Reservation key list.
+                BigEndian::write_u32(&mut data[4..8], (self.registrations.len() * 8) as u32);
+                for (_, key) in &self.registrations {
+                    let mut bytes = [0_u8; 8];
+                    BigEndian::write_u64(&mut bytes, *key);
+                    data.extend_from_slice(&bytes);
+                }
+            }
+            PR_IN_READ_RESERVATION => {
+                if let Some((holder, pr_type)) = &self.reservation {
+                    BigEndian::write_u32(&mut data[4..8], 16);
+                    // Reservation descriptor:
+                    // Byte[0-7]: Reservation key, 0 for all registrants type.
+                    // Byte[13]: bits[4-7]: Scope. bits[0-3]: Type.
+                    let mut desc = [0_u8; 16];
+                    if !is_all_registrants(*pr_type) {
+                        BigEndian::write_u64(&mut desc[0..8], self.key_of(holder).unwrap_or(0));
+                    }
+                    desc[13] = *pr_type;
+                    data.extend_from_slice(&desc);
+                }
+            }
+            PR_IN_REPORT_CAPABILITIES => {
+                data = vec![0_u8; PR_CAPABILITIES_LEN];
+                // Byte[0-1]: Length.
+                BigEndian::write_u16(&mut data[0..2], PR_CAPABILITIES_LEN as u16);
+                // Byte[3] bit7: TMV, the type mask is valid.
+                data[3] = 0x80;
+                // Byte[4-5]: Persistent reservation type mask.
+                data[4] = 1 << PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS
+                    | 1 << PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY
+                    | 1 << PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY
+                    | 1 << PR_TYPE_EXCLUSIVE_ACCESS
+                    | 1 << PR_TYPE_WRITE_EXCLUSIVE;
+                data[5] = 1 << (PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS - 8);
+            }
+            _ => return PrResponse::check_condition(SCSI_SENSE_INVALID_FIELD),
+        }
+        PrResponse::good(data)
+    }
+
+    fn persistent_reserve_out(&mut self, initiator: &str, cdb: &[u8], param: &[u8]) -> PrResponse {
+        // Byte[1] bits[0-4]: Service action. Byte[2] bits[4-7]: Scope, bits[0-3]: Type.
+        let action = cdb[1] & 0x1f;
+        let pr_type = cdb[2] & 0xf;
+        // Only the logical unit scope is supported.
+        let valid_type = cdb[2] >> 4 == 0 && is_valid_type(pr_type);
+        if param.len() != PR_OUT_PARAM_LEN {
+            return PrResponse::check_condition(SCSI_SENSE_INVALID_PARAM_LEN);
+        }
+        // Parameter list:
+        // Byte[0-7]: Reservation key.
+        // Byte[8-15]: Service action reservation key.
+        // Byte[20]: bit3: SPEC_I_PT. bit2: ALL_TG_PT. bit0: APTPL.
+        let key = BigEndian::read_u64(&param[0..8]);
+        let sa_key = BigEndian::read_u64(&param[8..16]);
+        if param[20] & PR_OUT_SPEC_I_PT != 0 {
+            return PrResponse::check_condition(SCSI_SENSE_INVALID_PARAM);
+        }
+
+        let registered = self.key_of(initiator);
+        if action == PR_OUT_REGISTER || action == PR_OUT_REGISTER_AND_IGNORE {
+            // The key of unregistered initiator is zero.
+            if action == PR_OUT_REGISTER && registered.unwrap_or(0) != key {
+                return PrResponse::conflict();
+            }
+            match (registered, sa_key) {
+                (None, 0) => return PrResponse::good(Vec::new()),
+                (None, _) => self.registrations.push((initiator.to_string(), sa_key)),
+                (Some(_), 0) => {
+                    self.registrations.retain(|(name, _)| name != initiator);
+                    self.drop_stale_reservation();
+                }
+                (Some(_), _) => {
+                    for (name, k) in self.registrations.iter_mut() {
+                        if name == initiator {
+                            *k = sa_key;
+                        }
+                    }
+                }
+            }
+            self.generation = self.generation.wrapping_add(1);
+            return PrResponse::good(Vec::new());
+        }
+
+        if !matches!(
+            action,
+            PR_OUT_RESERVE
+                | PR_OUT_RELEASE
+                | PR_OUT_CLEAR
+                | PR_OUT_PREEMPT
+                | PR_OUT_PREEMPT_AND_ABORT
+        ) {
+            return PrResponse::check_condition(SCSI_SENSE_INVALID_FIELD);
+        }
+        if action != PR_OUT_CLEAR && !valid_type {
+            return PrResponse::check_condition(SCSI_SENSE_INVALID_FIELD);
+        }
+        if registered != Some(key) {
+            return PrResponse::conflict();
+        }
+        match action {
+            PR_OUT_RESERVE => match &self.reservation {
+                None => self.reservation = Some((initiator.to_string(), pr_type)),
+                Some((_, t)) if *t == pr_type && self.is_holder(initiator) => {}
+                Some(_) => return PrResponse::conflict(),
+            },
+            PR_OUT_RELEASE => match &self.reservation {
+                Some((_, t)) if self.is_holder(initiator) => {
+                    if *t != pr_type {
+                        return PrResponse::check_condition(SCSI_SENSE_INVALID_RELEASE);
+                    }
+                    self.reservation = None;
+                }
+                // Releasing the reservation held by others does nothing.
+                _ => {}
+            },
+            PR_OUT_CLEAR => {
+                self.registrations.clear();
+                self.reservation = None;
+                self.generation = self.generation.wrapping_add(1);
+            }
+            _ => {
+                // PREEMPT AND ABORT is the same as PREEMPT, as the commands
+                // are completed synchronously.
+                let preempt_holder = match &self.reservation {
+                    Some((_, t)) if is_all_registrants(*t) => sa_key == 0,
+                    Some((holder, _)) => self.key_of(holder) == Some(sa_key),
+                    None => false,
+                };
+                if preempt_holder {
+                    if sa_key == 0 {
+                        self.registrations.retain(|(name, _)| name == initiator);
+                    } else {
+                        self.remove_key(sa_key, initiator);
+                    }
+                    self.reservation = Some((initiator.to_string(), pr_type));
+                } else {
+                    if sa_key == 0 {
+                        return PrResponse::check_condition(SCSI_SENSE_INVALID_PARAM);
+                    }
+                    if self.remove_key(sa_key, initiator) == 0 {
+                        return PrResponse::conflict();
+                    }
+                    self.drop_stale_reservation();
+                }
+                self.generation = self.generation.wrapping_add(1);
+            }
+        }
+        PrResponse::good(Vec::new())
+    }
+}
+
+/// Persistent reservations emulated in process, the state is stored in the
+/// file alongside the image, so it's shared by the VMs using the image and
+/// kept across restarts of VM. The file is locked during each access.
+pub struct PrFileManager {
+    file: File,
+    path: String,
+    /// Name of the initiator of this device, which is unique among the VMs.
+    initiator: String,
+}
+
+impl PrFileManager {
+    pub fn new(image: &str, initiator: &str) -> Result<Self> {
+        let path = pr_file_path(image);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open reservation file {}", path))?;
+        Ok(Self {
+            file,
+            path,
+            initiator: initiator.to_string(),
+        })
+    }
+
+    fn flock(&self, op: i32) -> Result<()> {
+        loop {
+            // SAFETY: the file has a valid raw fd.
+            let ret = unsafe { libc::flock(self.file.as_raw_fd(), op) };
+            if ret == 0 {
+                return Ok(());
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                bail!("Failed to lock reservation file {}: {}", self.path, err);
+            }
+        }
+    }
+
+    fn load(&self) -> Result<PrState> {
+        let len = self.file.metadata()?.len() as usize;
+        let mut buf = vec![0_u8; len];
+        self.file.read_exact_at(&mut buf, 0)?;
+        PrState::from_bytes(&buf).with_context(|| format!("Invalid reservation file {}", self.path))
+    }
+
+    fn store(&self, state: &PrState) -> Result<()> {
+        let buf = state.to_bytes();
+        self.file.write_all_at(&buf, 0)?;
+        self.file.set_len(buf.len() as u64)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Access the state with the file locked, the state changed by `f` is
+    /// written back.
+    fn with_state<R>(&self, exclusive: bool, f: impl FnOnce(&mut PrState) -> R) -> Result<R> {
+        self.flock(if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        })?;
+        let result = self.load().and_then(|mut state| {
+            let old = state.clone();
+            let ret = f(&mut state);
+            if state != old {
+                self.store(&state)?;
+            }
+            Ok(ret)
+        });
+        self.flock(libc::LOCK_UN)?;
+        result
+    }
+}
+
+impl PrManager for PrFileManager {
+    fn execute(&self, cdb: &[u8], param: &[u8]) -> Result<PrResponse> {
+        match cdb[0] {
+            PERSISTENT_RESERVE_IN => {
+                self.with_state(false, |state| state.persistent_reserve_in(cdb))
+            }
+            PERSISTENT_RESERVE_OUT => self.with_state(true, |state| {
+                state.persistent_reserve_out(&self.initiator, cdb, param)
+            }),
+            _ => Ok(PrResponse::check_condition(SCSI_SENSE_INVALID_OPCODE)),
+        }
+    }
+
+    fn check_access(&self, write: bool) -> Result<bool> {
+        self.with_state(false, |state| state.check_access(&self.initiator, write))
+    }
+}
+
+/// Persistent reservations done by the privileged helper process, which is
+/// compatible with qemu-pr-helper. The fd of host device is passed to the
+/// helper with each command, and the reservation conflicts of IO are reported
+/// by host device.
+pub struct PrHelperManager {
+    path: String,
+    /// The connection is set up again when it's broken.
+    sock: Mutex<Option<UnixSock>>,
+    /// The host device of reservations.
+    file: File,
+}
+
+impl PrHelperManager {
+    pub fn new(path: &str, file: File) -> Result<Self> {
+        let sock = Self::connect(path)?;
+        Ok(Self {
+            path: path.to_string(),
+            sock: Mutex::new(Some(sock)),
+            file,
+        })
+    }
+
+    fn connect(path: &str) -> Result<UnixSock> {
+        let mut sock = UnixSock::new(path);
+        sock.connect()?;
+        // The helper reports its capabilities first, none is used now.
+        let mut flags = [0_u8; 4];
+        recv_all(&sock, &mut flags)
+            .with_context(|| format!("Failed to handshake with pr-helper {}", path))?;
+        Ok(sock)
+    }
+
+    fn run(&self, sock: &UnixSock, cdb: &[u8], param: &[u8]) -> Result<PrResponse> {
+        if cdb.len() > PR_HELPER_CDB_SIZE || param.len() > PR_HELPER_DATA_SIZE {
+            return Ok(PrResponse::check_condition(SCSI_SENSE_INVALID_FIELD));
+        }
+        let mut req = [0_u8; PR_HELPER_CDB_SIZE];
+        req[..cdb.len()].copy_from_slice(cdb);
+        send_all(sock, &req, &[self.file.as_raw_fd()])?;
+        if cdb[0] == PERSISTENT_RESERVE_OUT && !param.is_empty() {
+            send_all(sock, param, &[])?;
+        }
+
+        // Response: Byte[0-3]: Scsi status. Byte[4-7]: Data size. Byte[8-103]: Sense data.
+        let mut resp = [0_u8; PR_HELPER_RESP_SIZE];
+        recv_all(sock, &mut resp)?;
+        let status = BigEndian::read_u32(&resp[0..4]) as u8;
+        let size = BigEndian::read_u32(&resp[4..8]) as usize;
+        if size > PR_HELPER_DATA_SIZE {
+            bail!("Invalid data size {} from pr-helper", size);
+        }
+        let mut data = vec![0_u8; size];
+        if cdb[0] == PERSISTENT_RESERVE_IN && size != 0 {
+            recv_all(sock, &mut data)?;
+        }
+        let sense = match status {
+            CHECK_CONDITION => Some(parse_sense(&resp[8..])),
+            _ => None,
+        };
+        Ok(PrResponse {
+            status,
+            sense,
+            data,
+        })
+    }
+}
+
+impl PrManager for PrHelperManager {
+    fn execute(&self, cdb: &[u8], param: &[u8]) -> Result<PrResponse> {
+        let mut locked_sock = self.sock.lock().unwrap();
+        if locked_sock.is_none() {
+            *locked_sock = Some(Self::connect(&self.path)?);
+        }
+        let result = self.run(locked_sock.as_ref().unwrap(), cdb, param);
+        if result.is_err() {
+            // The state of the connection is unknown, connect again for the next one.
+            error!("Connection to pr-helper {} is broken", self.path);
+            *locked_sock = None;
+        }
+        result
+    }
+}
+
+fn send_all(sock: &UnixSock, buf: &[u8], fds: &[RawFd]) -> Result<()> {
+    let mut iov = [libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    }];
+    let len = sock.send_msg(&mut iov, fds)?;
+    if len != buf.len() {
+        bail!("Short write {} of {} bytes to pr-helper", len, buf.len());
+    }
+    Ok(())
+}
+
+fn recv_all(sock: &UnixSock, buf: &mut [u8]) -> Result<()> {
+    let mut iov = [libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    }];
+    let (len, _) = sock.recv_msg(&mut iov, &mut [])?;
+    if len != buf.len() {
+        bail!("Short read {} of {} bytes from pr-helper", len, buf.len());
+    }
+    Ok(())
+}
+
+/// Get the sense key and codes from the sense data in fixed or descriptor format.
+fn parse_sense(sense: &[u8]) -> ScsiSense {
+    // Byte[0] bits[0-6]: Response code, 0x72 and 0x73 are descriptor format.
+    match sense[0] & 0x7f {
+        0x72 | 0x73 => ScsiSense {
+            key: sense[1] & 0xf,
+            asc: sense[2],
+            ascq: sense[3],
+        },
+        _ => ScsiSense {
+            key: sense[2] & 0xf,
+            asc: sense[12],
+            ascq: sense[13],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+
+    use super::*;
+
+    fn pr_out(manager: &PrFileManager, action: u8, pr_type: u8, key: u64, sa_key: u64) -> u8 {
+        let cdb = [
+            PERSISTENT_RESERVE_OUT,
+            action,
+            pr_type,
+            0,
+            0,
+            0,
+            0,
+            0,
+            24,
+            0,
+        ];
+        let mut param = [0_u8; PR_OUT_PARAM_LEN];
+        BigEndian::write_u64(&mut param[0..8], key);
+        BigEndian::write_u64(&mut param[8..16], sa_key);
+        manager.execute(&cdb, &param).unwrap().status
+    }
+
+    fn pr_in(manager: &PrFileManager, action: u8) -> Vec<u8> {
+        let cdb = [PERSISTENT_RESERVE_IN, action, 0, 0, 0, 0, 0, 0x10, 0, 0];
+        let resp = manager.execute(&cdb, &[]).unwrap();
+        assert_eq!(resp.status, GOOD);
+        resp.data
+    }
+
+    #[test]
+    fn test_pr_file_manager() {
+        // Two VMs share the same image.
+        let image = "/tmp/test_pr_file_manager.img";
+        let vm1 = PrFileManager::new(image, "vm1").unwrap();
+        let vm2 = PrFileManager::new(image, "vm2").unwrap();
+
+        // Register with wrong key, then register the keys.
+        assert_eq!(
+            pr_out(&vm1, PR_OUT_REGISTER, 0, 1, 0x11),
+            RESERVATION_CONFLICT
+        );
+        assert_eq!(pr_out(&vm1, PR_OUT_REGISTER, 0, 0, 0x11), GOOD);
+        assert_eq!(pr_out(&vm2, PR_OUT_REGISTER, 0, 0, 0x22), GOOD);
+        let keys = pr_in(&vm2, PR_IN_READ_KEYS);
+        assert_eq!(BigEndian::read_u32(&keys[0..4]), 2);
+        assert_eq!(BigEndian::read_u32(&keys[4..8]), 16);
+        assert_eq!(BigEndian::read_u64(&keys[8..16]), 0x11);
+        assert_eq!(BigEndian::read_u64(&keys[16..24]), 0x22);
+
+        // vm1 reserves with write exclusive.
+        assert_eq!(
+            pr_out(&vm1, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, 0x11, 0),
+            GOOD
+        );
+        assert_eq!(
+            pr_out(&vm2, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, 0x22, 0),
+            RESERVATION_CONFLICT
+        );
+        let resv = pr_in(&vm2, PR_IN_READ_RESERVATION);
+        assert_eq!(BigEndian::read_u32(&resv[4..8]), 16);
+        assert_eq!(BigEndian::read_u64(&resv[8..16]), 0x11);
+        assert_eq!(resv[21], PR_TYPE_WRITE_EXCLUSIVE);
+        assert!(vm1.check_access(true).unwrap());
+        assert!(vm2.check_access(false).unwrap());
+        assert!(!vm2.check_access(true).unwrap());
+
+        // vm2 preempts the reservation of vm1, and vm1 is unregistered.
+        assert_eq!(
+            pr_out(&vm2, PR_OUT_PREEMPT, PR_TYPE_EXCLUSIVE_ACCESS, 0x22, 0x11),
+            GOOD
+        );
+        assert!(!vm1.check_access(false).unwrap());
+        assert_eq!(
+            pr_out(&vm1, PR_OUT_RELEASE, PR_TYPE_EXCLUSIVE_ACCESS, 0x11, 0),
+            RESERVATION_CONFLICT
+        );
+        let keys = pr_in(&vm1, PR_IN_READ_KEYS);
+        assert_eq!(BigEndian::read_u32(&keys[0..4]), 3);
+        assert_eq!(BigEndian::read_u32(&keys[4..8]), 8);
+
+        // The state is kept across restarts.
+        drop(vm2);
+        let vm2 = PrFileManager::new(image, "vm2").unwrap();
+        assert!(vm2.check_access(true).unwrap());
+        assert_eq!(
+            pr_out(&vm2, PR_OUT_RELEASE, PR_TYPE_WRITE_EXCLUSIVE, 0x22, 0),
+            CHECK_CONDITION
+        );
+        assert_eq!(
+            pr_out(&vm2, PR_OUT_RELEASE, PR_TYPE_EXCLUSIVE_ACCESS, 0x22, 0),
+            GOOD
+        );
+        assert!(vm1.check_access(true).unwrap());
+        assert_eq!(pr_out(&vm2, PR_OUT_CLEAR, 0, 0x22, 0), GOOD);
+        assert_eq!(BigEndian::read_u32(&pr_in(&vm1, PR_IN_READ_KEYS)[4..8]), 0);
+
+        remove_file(pr_file_path(image)).unwrap();
+    }
+
+    #[test]
+    fn test_pr_all_registrants() {
+        let mut state = PrState::default();
+        let cdb = |action: u8, pr_type: u8| [PERSISTENT_RESERVE_OUT, action, pr_type];
+        let param = |key: u64, sa_key: u64| {
+            let mut param = [0_u8; PR_OUT_PARAM_LEN];
+            BigEndian::write_u64(&mut param[0..8], key);
+            BigEndian::write_u64(&mut param[8..16], sa_key);
+            param
+        };
+        for (name, key) in [("a", 1), ("b", 2)] {
+            let resp = state.persistent_reserve_out(name, &cdb(PR_OUT_REGISTER, 0), &param(0, key));
+            assert_eq!(resp.status, GOOD);
+        }
+        let pr_type = PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS;
+        let resp = state.persistent_reserve_out("a", &cdb(PR_OUT_RESERVE, pr_type), &param(1, 0));
+        assert_eq!(resp.status, GOOD);
+        assert!(state.check_access("b", true));
+        assert!(!state.check_access("c", false));
+
+        // The reservation is kept until the last registrant is unregistered.
+        let resp = state.persistent_reserve_out("a", &cdb(PR_OUT_REGISTER, 0), &param(1, 0));
+        assert_eq!(resp.status, GOOD);
+        assert!(state.reservation.is_some());
+        let buf = state.to_bytes();
+        assert_eq!(PrState::from_bytes(&buf).unwrap(), state);
+        let resp = state.persistent_reserve_out("b", &cdb(PR_OUT_REGISTER, 0), &param(2, 0));
+        assert_eq!(resp.status, GOOD);
+        assert!(state.reservation.is_none());
+        assert_eq!(state.generation, 4);
+    }
+}
@@ -411,7 +411,7 @@ impl VirtioDevice for Net {
 mod tests {
     use super::*;
     use address_space::*;
-    use machine_manager::config::DEFAULT_VIRTQUEUE_SIZE;
+    use machine_manager::config::{NetRateLimit, DEFAULT_VIRTQUEUE_SIZE};
     use std::fs::File;
 
     const SYSTEM_SPACE_SIZE: u64 = (1024 * 1024) as u64;
@@ -455,6 +455,10 @@ mod tests {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            netdev: "".to_string(),
+            rate_limit: NetRateLimit::default(),
         };
         let conf = vec![net1];
         let confs = Some(conf);
@@ -477,6 +481,10 @@ mod tests {
             mq: false,
             socket_path: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            user: None,
+            socket: None,
+            netdev: "".to_string(),
+            rate_limit: NetRateLimit::default(),
         };
         let conf = vec![net1];
         let confs = Some(conf);
@@ -36,6 +36,7 @@ use super::message::{
 };
 use super::sock::VhostUserSock;
 use crate::block::VirtioBlkConfig;
+use crate::net::MAC_ADDR_LEN;
 use crate::virtio_has_feature;
 use crate::VhostUser::message::VhostUserConfig;
 use anyhow::{anyhow, bail, Context, Result};
@@ -43,6 +44,8 @@ use util::unix::do_mmap;
 
 /// Vhost supports multiple queue
 pub const VHOST_USER_PROTOCOL_F_MQ: u8 = 0;
+/// Vhost supports `VHOST_USER_SEND_RARP` msg.
+pub const VHOST_USER_PROTOCOL_F_RARP: u8 = 2;
 /// Vhost supports `VHOST_USER_SET_CONFIG` and `VHOST_USER_GET_CONFIG` msg.
 pub const VHOST_USER_PROTOCOL_F_CONFIG: u8 = 9;
 /// Vhost supports `VHOST_USER_SET_INFLIGHT_FD` and `VHOST_USER_GET_INFLIGHT_FD` msg.
@@ -622,6 +625,13 @@ impl VhostUserClient {
         self.set_value(VhostUserMsgReq::SetProtocolFeatures, features)
     }
 
+    /// Ask vhost to broadcast a RARP packet with the mac address of the guest.
+    pub fn send_rarp(&self, mac: &[u8; MAC_ADDR_LEN]) -> Result<()> {
+        let mut value = [0_u8; size_of::<u64>()];
+        value[..MAC_ADDR_LEN].copy_from_slice(mac);
+        self.set_value(VhostUserMsgReq::SendRarp, u64::from_le_bytes(value))
+    }
+
     /// Get virtio blk config from vhost.
     pub fn get_virtio_blk_config(&self) -> Result<VirtioBlkConfig> {
         let request = VhostUserMsgReq::GetConfig as u32;
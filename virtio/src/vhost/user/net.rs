@@ -20,26 +20,33 @@ use std::sync::{Arc, Mutex};
 use address_space::AddressSpace;
 use machine_manager::config::NetworkInterfaceConfig;
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
+use migration::{MigrationHook, MigrationManager, StateTransfer};
 use util::byte_code::ByteCode;
 use util::loop_context::EventNotifierHelper;
 use util::num_ops::read_u32;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::super::super::{
-    net::{build_device_config_space, CtrlInfo, VirtioNetState, MAC_ADDR_LEN},
+    net::{build_device_config_space, guest_announce, CtrlInfo, VirtioNetState, MAC_ADDR_LEN},
     CtrlVirtio, NetCtrlHandler, Queue, VirtioDevice, VirtioInterrupt, VIRTIO_F_RING_EVENT_IDX,
     VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
-    VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_MRG_RXBUF, VIRTIO_TYPE_NET,
+    VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_ANNOUNCE,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_STATUS, VIRTIO_NET_S_LINK_UP, VIRTIO_TYPE_NET,
 };
 use super::super::VhostOps;
-use super::{VhostBackendType, VhostUserClient};
+use super::{
+    VhostBackendType, VhostUserClient, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_MQ,
+    VHOST_USER_PROTOCOL_F_RARP,
+};
 use crate::error::VirtioError;
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 /// Number of virtqueues.
 const QUEUE_NUM_NET: usize = 2;
+/// Protocol features which vhost-user net acks to the backend.
+const SUPPORTED_PROTOCOL_FEATURES: [u8; 2] = [VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_RARP];
 
 /// Network device structure.
 pub struct Net {
@@ -57,6 +64,10 @@ pub struct Net {
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
+    /// Protocol features negotiated with vhost.
+    protocol_features: u64,
+    /// Callback to trigger the config interrupt when the device is activated.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
 }
 
 impl Net {
@@ -69,6 +80,24 @@ impl Net {
             call_events: Vec::<Arc<EventFd>>::new(),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
+            protocol_features: 0,
+            interrupt_cb: None,
+        }
+    }
+
+    /// Ask the guest to send gratuitous packets, or ask vhost to send a RARP packet
+    /// for the guest if the driver does not support VIRTIO_NET_F_GUEST_ANNOUNCE.
+    pub fn announce(&mut self) -> Result<()> {
+        if self.interrupt_cb.is_none() || guest_announce(&self.state, self.interrupt_cb.as_ref())? {
+            return Ok(());
+        }
+        if !virtio_has_feature(self.protocol_features, VHOST_USER_PROTOCOL_F_RARP as u32) {
+            return Ok(());
+        }
+        let mac = self.state.lock().unwrap().config_space.mac;
+        match &self.client {
+            Some(client) => client.lock().unwrap().send_rarp(&mac),
+            None => Err(anyhow!("Failed to get client for vhost-user net")),
         }
     }
 
@@ -99,6 +128,7 @@ impl Net {
             .as_mut_bytes()
             .copy_from_slice(&[0_u8; std::mem::size_of::<VirtioNetState>()]);
         self.client = None;
+        self.interrupt_cb = None;
 
         Ok(())
     }
@@ -132,6 +162,25 @@ impl VirtioDevice for Net {
             .get_features()
             .with_context(|| "Failed to get features for vhost-user net")?;
 
+        self.protocol_features = 0;
+        if virtio_has_feature(locked_state.device_features, VHOST_USER_F_PROTOCOL_FEATURES) {
+            let locked_client = client.lock().unwrap();
+            let protocol_features = locked_client
+                .get_protocol_features()
+                .with_context(|| "Failed to get protocol features for vhost-user net")?;
+            // Protocol features are only negotiated for RARP, the backends which
+            // don't support it keep working without the negotiation.
+            if virtio_has_feature(protocol_features, VHOST_USER_PROTOCOL_F_RARP as u32) {
+                let supported_protocol_features = SUPPORTED_PROTOCOL_FEATURES
+                    .iter()
+                    .fold(0_u64, |features, bit| features | 1 << bit);
+                self.protocol_features = protocol_features & supported_protocol_features;
+                locked_client
+                    .set_protocol_features(self.protocol_features)
+                    .with_context(|| "Failed to set protocol features for vhost-user net")?;
+            }
+        }
+
         let features = 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
@@ -141,6 +190,9 @@ impl VirtioDevice for Net {
             | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_F_RING_EVENT_IDX;
         locked_state.device_features &= features;
+        // The link status is emulated, it's always up for vhost-user net.
+        locked_state.device_features |= 1 << VIRTIO_NET_F_STATUS;
+        locked_state.config_space.status = VIRTIO_NET_S_LINK_UP;
 
         let queue_pairs = self.net_cfg.queues / 2;
         if self.net_cfg.mq
@@ -149,6 +201,7 @@ impl VirtioDevice for Net {
         {
             locked_state.device_features |= 1 << VIRTIO_NET_F_CTRL_VQ;
             locked_state.device_features |= 1 << VIRTIO_NET_F_MQ;
+            locked_state.device_features |= 1 << VIRTIO_NET_F_GUEST_ANNOUNCE;
             locked_state.config_space.max_virtqueue_pairs = queue_pairs;
         }
 
@@ -269,11 +322,17 @@ impl VirtioDevice for Net {
             None => return Err(anyhow!("Failed to get client for vhost-user net")),
         };
 
-        let features = driver_features & !(1 << VIRTIO_NET_F_MAC);
+        // The features emulated by the device are not passed to vhost.
+        let features = driver_features
+            & !(1 << VIRTIO_NET_F_MAC
+                | 1 << VIRTIO_NET_F_STATUS
+                | 1 << VIRTIO_NET_F_GUEST_ANNOUNCE);
         client.features = features;
         client.set_queues(queues);
         client.set_queue_evts(&queue_evts);
         client.activate_vhost_user()?;
+        drop(client);
+        self.interrupt_cb = Some(interrupt_cb);
         self.broken.store(false, Ordering::SeqCst);
 
         Ok(())
@@ -309,6 +368,10 @@ impl VirtioDevice for Net {
         self.delete_event()?;
         self.call_events.clear();
         self.client = None;
+        MigrationManager::unregister_device_instance(
+            VirtioNetState::descriptor(),
+            &self.net_cfg.id,
+        );
 
         Ok(())
     }
@@ -320,3 +383,34 @@ impl VirtioDevice for Net {
         )
     }
 }
+
+impl StateTransfer for Net {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        self.state.lock().unwrap().broken = self.broken.load(Ordering::SeqCst);
+        Ok(self.state.lock().unwrap().as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::Result<()> {
+        let s_len = std::mem::size_of::<VirtioNetState>();
+        if state.len() != s_len {
+            bail!("Invalid state length {}, expected {}", state.len(), s_len);
+        }
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state.as_mut_bytes().copy_from_slice(state);
+        self.broken.store(locked_state.broken, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        MigrationManager::get_desc_alias(&VirtioNetState::descriptor().name).unwrap_or(!0)
+    }
+}
+
+impl MigrationHook for Net {
+    fn resume(&mut self) -> migration::Result<()> {
+        // Update the switches with the new location of the guest.
+        self.announce()
+            .with_context(|| "Failed to announce vhost-user net device")
+    }
+}